@@ -0,0 +1,77 @@
+//! Compares sequential and parallel parsing on a corpus made of the
+//! given files, each repeated `-n` times (default 20). Every mode is
+//! run five times and the fastest run is reported.
+//!
+//!     cargo run --release --example parse_parallel -- [-n N] [FILE..]
+//!
+//! With `-n 10` of `src/minisat.ll` (5,057,060 bytes) on a single-core
+//! machine, two runs gave:
+//!
+//! | mode                 | run 1  | run 2  |
+//! |----------------------|--------|--------|
+//! | sequential           | 108 ms | 126 ms |
+//! | parallel, 1 thread   | 140 ms | 166 ms |
+//! | parallel, 2 threads  | 181 ms | 175 ms |
+//! | parallel, 4 threads  | 171 ms | 115 ms |
+//! | parallel, auto       | 137 ms | 133 ms |
+//!
+//! One core gives nothing to parallelise, so these only show the cost of
+//! splitting the input; numbers from a multi-core machine are still
+//! missing.
+extern crate llvm_ir;
+extern crate nom;
+
+use llvm_ir::stream::parse_parallel;
+use nom::IResult;
+use std::env;
+use std::fs;
+use std::time::{Duration,Instant};
+
+const RUNS: usize = 5;
+
+// The fastest of several runs of `f`, and its last result.
+fn best<T,F: FnMut() -> T>(mut f: F) -> (Duration,T) {
+    let mut res = None;
+    let mut time = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        res = Some(f());
+        time = time.min(start.elapsed());
+    }
+    (time,res.unwrap())
+}
+
+fn main() {
+    let mut copies = 20;
+    let mut files = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg=="-n" {
+            copies = args.next().and_then(|n| n.parse().ok()).expect("-n needs a number");
+        } else {
+            files.push(arg);
+        }
+    }
+    if files.is_empty() {
+        files.push("src/minisat.ll".to_string());
+    }
+    let mut corpus = Vec::new();
+    for _ in 0..copies {
+        for f in &files {
+            corpus.extend(fs::read(f).expect("cannot read input"));
+            corpus.push(b'\n');
+        }
+    }
+    println!("corpus: {} bytes",corpus.len());
+
+    let (time,seq) = best(|| match llvm_ir::module(&corpus) {
+        IResult::Done(_,m) => m,
+        _ => panic!("cannot parse the corpus")
+    });
+    println!("sequential: {:?}",time);
+    for threads in &[1,2,4,0] {
+        let (time,par) = best(|| parse_parallel(&corpus,*threads).expect("cannot parse the corpus"));
+        println!("parallel, {} threads: {:?}",threads,time);
+        assert!(par==seq,"results differ");
+    }
+}
@@ -0,0 +1,1236 @@
+//! Abstract interpretation over the control-flow graph of a function.
+//!
+//! `analyze` computes a fixpoint of an `Analysis` with a worklist over
+//! the blocks in reverse postorder. States are widened at the targets
+//! of retreating edges (loop headers) and the fixpoint is refined by a
+//! few descending iterations afterwards. The result keeps the state
+//! before every instruction of the reachable blocks.
+//!
+//! `ValueAnalysis` tracks an abstract value for every integer local and
+//! argument in a `ValueDomain`. Conditional branches and switches
+//! restrict the values of the compared operands, which also reveals
+//! edges that can never be taken. Two domains are provided, `Interval`
+//! and `KnownBits`.
+use num_bigint::{BigInt,BigUint,Sign};
+use num_traits::{One,Zero,ToPrimitive};
+use std::cmp::{max,min};
+use std::collections::{BTreeMap,BTreeSet};
+use std::fmt;
+use std::marker::PhantomData;
+use super::*;
+use builder::resolve_type;
+use cfg::{ControlFlowGraph,DominatorTree};
+use interp::{self,Val};
+use loops::{Edge,LoopInfo};
+
+/// The number of descending iterations after a fixpoint is reached.
+const NARROWING_ROUNDS: usize = 2;
+
+pub trait Lattice: Clone + PartialEq + fmt::Debug {
+    /// The least upper bound of two elements.
+    fn join(&self,other: &Self) -> Self;
+    /// An upper bound of both elements, used at loop headers. Every
+    /// chain `x0, x0.widen(x1), ...` has to become stable eventually.
+    /// The default is `join`, which is only enough for lattices of
+    /// finite height.
+    fn widen(&self,next: &Self) -> Self {
+        self.join(next)
+    }
+}
+
+/// A forward analysis. Unreachable program points have no state, so
+/// the lattice does not need a bottom element.
+pub trait Analysis {
+    type State: Lattice;
+    /// The state at the start of the entry block.
+    fn entry(&self,fun: &Function) -> Self::State;
+    /// Update the state for an instruction that is not a terminator.
+    /// Phi nodes are evaluated by `edge` and can be ignored here.
+    fn instruction(&self,instr: &Instruction,state: &mut Self::State);
+    /// The state at the start of `to` when coming from `from`, given
+    /// the state at the end of `from`, including the phi nodes of `to`.
+    /// `None` if the edge cannot be taken.
+    fn edge(&self,from: &BasicBlock,to: &BasicBlock,state: &Self::State) -> Option<Self::State>;
+}
+
+/// The result of `analyze`.
+#[derive(Debug,Clone)]
+pub struct Fixpoint<S> {
+    cfg: ControlFlowGraph,
+    // For every reachable block the state before each instruction
+    states: Vec<Option<Vec<S>>>,
+    feasible: BTreeSet<Edge>
+}
+
+impl<S> Fixpoint<S> {
+    pub fn cfg(&self) -> &ControlFlowGraph {
+        &self.cfg
+    }
+    /// Whether the analysis found an execution reaching a block.
+    pub fn is_reachable(&self,blk: usize) -> bool {
+        self.states[blk].is_some()
+    }
+    /// The state at the start of a block.
+    pub fn block_entry(&self,blk: usize) -> Option<&S> {
+        self.before(blk,0)
+    }
+    /// The state before an instruction, `None` if it is unreachable.
+    pub fn before(&self,blk: usize,instr: usize) -> Option<&S> {
+        self.states[blk].as_ref().and_then(|st| st.get(instr))
+    }
+    /// The state after an instruction. Terminators do not change the
+    /// state.
+    pub fn after(&self,blk: usize,instr: usize) -> Option<&S> {
+        self.states[blk].as_ref().and_then(|st| st.get(instr+1).or_else(|| st.get(instr)))
+    }
+    pub fn is_feasible(&self,from: usize,to: usize) -> bool {
+        self.feasible.contains(&(from,to))
+    }
+    /// The edges leaving reachable blocks that can never be taken,
+    /// i.e. the dead sides of branches.
+    pub fn dead_edges(&self) -> Vec<Edge> {
+        let mut res = Vec::new();
+        for b in 0..self.cfg.len() {
+            if !self.is_reachable(b) {
+                continue
+            }
+            for &s in self.cfg.successors(b) {
+                if !self.is_feasible(b,s) {
+                    res.push((b,s));
+                }
+            }
+        }
+        res
+    }
+}
+
+/// Run an analysis on a function body until the states are stable,
+/// `None` for declarations.
+pub fn analyze<A: Analysis>(analysis: &A,fun: &Function) -> Option<Fixpoint<A::State>> {
+    let blocks = match fun.body {
+        Some(ref blks) => &blks[..],
+        None => return None
+    };
+    let cfg = ControlFlowGraph::new(blocks);
+    if cfg.is_empty() {
+        return Some(Fixpoint { cfg, states: Vec::new(), feasible: BTreeSet::new() })
+    }
+    let li = LoopInfo::new(&cfg,&DominatorTree::new(&cfg));
+    let mut widen = vec![false; cfg.len()];
+    for &(_,h) in li.back_edges().iter().chain(li.irreducible_edges()) {
+        widen[h] = true;
+    }
+    let rpo = cfg.reverse_postorder();
+    let mut order = vec![usize::MAX; cfg.len()];
+    for (i,&b) in rpo.iter().enumerate() {
+        order[b] = i;
+    }
+    let init = analysis.entry(fun);
+    let mut entries: Vec<Option<A::State>> = vec![None; cfg.len()];
+    // The states along the edges that can be taken, by target and source
+    let mut incoming: Vec<BTreeMap<usize,A::State>> = vec![BTreeMap::new(); cfg.len()];
+    let join_incoming = |b: usize,incoming: &[BTreeMap<usize,A::State>]| {
+        let start = if b==cfg.entry() { Some(&init) } else { None };
+        start.into_iter()
+            .chain(incoming[b].values())
+            .fold(None,|acc: Option<A::State>,st| Some(match acc {
+                Some(acc) => acc.join(st),
+                None => st.clone()
+            }))
+    };
+    let update_edges = |b: usize,entry: Option<&A::State>,incoming: &mut [BTreeMap<usize,A::State>]| {
+        let exit = entry.map(|st| transfer(analysis,&blocks[b],st.clone()));
+        for &s in cfg.successors(b) {
+            match exit.as_ref().and_then(|st| analysis.edge(&blocks[b],&blocks[s],st)) {
+                Some(st) => { incoming[s].insert(b,st); },
+                None => { incoming[s].remove(&b); }
+            }
+        }
+    };
+
+    // Ascending iterations, always processing the first block in
+    // reverse postorder
+    entries[cfg.entry()] = Some(init.clone());
+    let mut worklist = BTreeSet::new();
+    worklist.insert(order[cfg.entry()]);
+    while let Some(pos) = worklist.iter().next().cloned() {
+        worklist.remove(&pos);
+        let b = rpo[pos];
+        update_edges(b,entries[b].as_ref(),&mut incoming);
+        for &s in cfg.successors(b) {
+            let joined = join_incoming(s,&incoming);
+            let next = match (entries[s].as_ref(),joined) {
+                (Some(old),Some(new)) if widen[s] => Some(old.widen(&new)),
+                (_,new) => new
+            };
+            if next!=entries[s] {
+                entries[s] = next;
+                worklist.insert(order[s]);
+            }
+        }
+    }
+
+    // Descending iterations recover some of the precision lost by
+    // widening
+    for _ in 0..NARROWING_ROUNDS {
+        let mut changed = false;
+        for &b in rpo.iter() {
+            let next = join_incoming(b,&incoming);
+            if next!=entries[b] {
+                entries[b] = next;
+                changed = true;
+            }
+            update_edges(b,entries[b].as_ref(),&mut incoming);
+        }
+        if !changed {
+            break
+        }
+    }
+
+    let mut feasible = BTreeSet::new();
+    for (s,inc) in incoming.iter().enumerate() {
+        for &b in inc.keys() {
+            if entries[b].is_some() {
+                feasible.insert((b,s));
+            }
+        }
+    }
+    let states = entries.into_iter().enumerate().map(|(b,entry)| entry.map(|mut st| {
+        let mut res = Vec::with_capacity(blocks[b].instrs.len());
+        for instr in blocks[b].instrs.iter() {
+            res.push(st.clone());
+            if let InstructionC::Term(_) = instr.content {
+                continue
+            }
+            analysis.instruction(instr,&mut st);
+        }
+        res
+    })).collect();
+    Some(Fixpoint { cfg, states, feasible })
+}
+
+/// The state at the end of a block.
+fn transfer<A: Analysis>(analysis: &A,blk: &BasicBlock,mut st: A::State) -> A::State {
+    for instr in blk.instrs.iter() {
+        if let InstructionC::Term(_) = instr.content {
+            break
+        }
+        analysis.instruction(instr,&mut st);
+    }
+    st
+}
+
+/// An abstract domain for integers of a fixed bit width.
+pub trait ValueDomain: Lattice {
+    /// All integers of a width.
+    fn top(width: u64) -> Self;
+    /// A single integer, truncated to the width.
+    fn constant(width: u64,v: &BigInt) -> Self;
+    fn width(&self) -> u64;
+    /// The smallest and largest possible value, interpreted as signed
+    /// or unsigned integers.
+    fn range(&self,signed: bool) -> (BigInt,BigInt);
+    /// The common values of both elements, `None` if there are none.
+    fn meet(&self,other: &Self) -> Option<Self>;
+    fn binary(op: &BinOp,l: &Self,r: &Self) -> Self;
+    /// Truncate or extend a value to a width. Other casts give `top`.
+    fn cast(op: CastInst,v: &Self,width: u64) -> Self;
+    /// Restrict the operands of a comparison to the values for which
+    /// it has the given outcome, `None` if there are none.
+    fn assume(op: &CmpOp,outcome: bool,l: &Self,r: &Self) -> Option<(Self,Self)>;
+
+    /// The only possible value as unsigned integer, if there is one.
+    fn as_constant(&self) -> Option<BigInt> {
+        let (lo,hi) = self.range(false);
+        if lo==hi {
+            Some(lo)
+        } else {
+            None
+        }
+    }
+    /// The possible outcomes of a comparison as a 1 bit value.
+    fn compare(op: &CmpOp,l: &Self,r: &Self) -> Self {
+        match (Self::assume(op,true,l,r).is_some(),Self::assume(op,false,l,r).is_some()) {
+            (true,false) => Self::constant(1,&BigInt::one()),
+            (false,true) => Self::constant(1,&BigInt::zero()),
+            _ => Self::top(1)
+        }
+    }
+}
+
+fn pow2(n: u64) -> BigInt {
+    BigInt::one() << (n as usize)
+}
+
+fn signed_min(width: u64) -> BigInt {
+    -pow2(width-1)
+}
+
+fn signed_max(width: u64) -> BigInt {
+    pow2(width-1) - BigInt::one()
+}
+
+fn unsigned_max(width: u64) -> BigInt {
+    pow2(width) - BigInt::one()
+}
+
+fn to_unsigned(width: u64,v: &BigInt) -> BigInt {
+    let m = pow2(width);
+    let r = v % &m;
+    if r.sign()==Sign::Minus {
+        r + m
+    } else {
+        r
+    }
+}
+
+fn to_signed(width: u64,v: &BigInt) -> BigInt {
+    let u = to_unsigned(width,v);
+    if u > signed_max(width) {
+        u - pow2(width)
+    } else {
+        u
+    }
+}
+
+/// Evaluate a binary operation on two unsigned constants, `None` if
+/// the result is undefined.
+fn fold(op: &BinOp,width: u64,l: &BigInt,r: &BigInt) -> Option<BigInt> {
+    match interp::binary(op,&Val::Int(width,l.clone()),&Val::Int(width,r.clone())) {
+        Ok(Val::Int(_,v)) => Some(v),
+        _ => None
+    }
+}
+
+fn is_signed(op: &CmpOp) -> bool {
+    matches!(*op,CmpOp::SGt | CmpOp::SGe | CmpOp::SLt | CmpOp::SLe)
+}
+
+/// The comparison with the opposite outcome.
+fn negate(op: &CmpOp) -> CmpOp {
+    match *op {
+        CmpOp::Eq => CmpOp::Ne,
+        CmpOp::Ne => CmpOp::Eq,
+        CmpOp::UGt => CmpOp::ULe,
+        CmpOp::UGe => CmpOp::ULt,
+        CmpOp::ULt => CmpOp::UGe,
+        CmpOp::ULe => CmpOp::UGt,
+        CmpOp::SGt => CmpOp::SLe,
+        CmpOp::SGe => CmpOp::SLt,
+        CmpOp::SLt => CmpOp::SGe,
+        CmpOp::SLe => CmpOp::SGt
+    }
+}
+
+/// The comparison with swapped operands.
+fn swap(op: &CmpOp) -> CmpOp {
+    match *op {
+        CmpOp::UGt => CmpOp::ULt,
+        CmpOp::UGe => CmpOp::ULe,
+        CmpOp::ULt => CmpOp::UGt,
+        CmpOp::ULe => CmpOp::UGe,
+        CmpOp::SGt => CmpOp::SLt,
+        CmpOp::SGe => CmpOp::SLe,
+        CmpOp::SLt => CmpOp::SGt,
+        CmpOp::SLe => CmpOp::SGe,
+        ref op => op.clone()
+    }
+}
+
+/// Whether a comparison can hold for some values in the given ranges,
+/// which have to match the signedness of the comparison.
+fn may_hold(op: &CmpOp,l: &(BigInt,BigInt),r: &(BigInt,BigInt)) -> bool {
+    match *op {
+        CmpOp::Eq => l.0<=r.1 && r.0<=l.1,
+        CmpOp::Ne => !(l.0==l.1 && r.0==r.1 && l.0==r.0),
+        CmpOp::ULt | CmpOp::SLt => l.0<r.1,
+        CmpOp::ULe | CmpOp::SLe => l.0<=r.1,
+        CmpOp::UGt | CmpOp::SGt => l.1>r.0,
+        CmpOp::UGe | CmpOp::SGe => l.1>=r.0
+    }
+}
+
+/// Integer division rounding towards negative infinity, `d` has to be
+/// positive.
+fn floor_div(v: &BigInt,d: &BigInt) -> BigInt {
+    let q = v / d;
+    if (v % d).sign()==Sign::Minus {
+        q - BigInt::one()
+    } else {
+        q
+    }
+}
+
+/// The integers between two bounds, interpreted as signed values.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Interval {
+    width: u64,
+    lo: BigInt,
+    hi: BigInt
+}
+
+impl Interval {
+    /// The signed integers from `lo` to `hi`, both inclusive.
+    ///
+    /// Panics if the bounds are empty or not representable.
+    pub fn new(width: u64,lo: BigInt,hi: BigInt) -> Interval {
+        assert!(signed_min(width)<=lo && lo<=hi && hi<=signed_max(width),
+                "invalid interval [{}, {}] for width {}",lo,hi,width);
+        Interval { width, lo, hi }
+    }
+    pub fn lo(&self) -> &BigInt {
+        &self.lo
+    }
+    pub fn hi(&self) -> &BigInt {
+        &self.hi
+    }
+    /// Whether a signed integer is in the interval.
+    pub fn contains(&self,v: &BigInt) -> bool {
+        self.lo<=*v && *v<=self.hi
+    }
+    /// The interval of the bounds, or all integers if a bound is not
+    /// representable.
+    fn bounded(width: u64,lo: BigInt,hi: BigInt) -> Interval {
+        if lo<signed_min(width) || hi>signed_max(width) {
+            Interval::top(width)
+        } else {
+            Interval { width, lo, hi }
+        }
+    }
+    /// The values between two signed bounds.
+    fn restrict(&self,lo: &BigInt,hi: &BigInt) -> Option<Interval> {
+        let lo = max(&self.lo,lo);
+        let hi = min(&self.hi,hi);
+        if lo<=hi {
+            Some(Interval { width: self.width, lo: lo.clone(), hi: hi.clone() })
+        } else {
+            None
+        }
+    }
+    /// The values without `v`. Only the bounds can be excluded.
+    fn exclude(&self,v: &BigInt) -> Option<Interval> {
+        if *v==self.lo {
+            self.restrict(&(v+BigInt::one()),&self.hi)
+        } else if *v==self.hi {
+            self.restrict(&self.lo,&(v-BigInt::one()))
+        } else {
+            Some(self.clone())
+        }
+    }
+    fn is_non_negative(&self) -> bool {
+        self.lo.sign()!=Sign::Minus
+    }
+}
+
+impl Lattice for Interval {
+    fn join(&self,other: &Interval) -> Interval {
+        Interval { width: self.width,
+                   lo: min(&self.lo,&other.lo).clone(),
+                   hi: max(&self.hi,&other.hi).clone() }
+    }
+    fn widen(&self,next: &Interval) -> Interval {
+        Interval { width: self.width,
+                   lo: if next.lo<self.lo { signed_min(self.width) } else { self.lo.clone() },
+                   hi: if next.hi>self.hi { signed_max(self.width) } else { self.hi.clone() } }
+    }
+}
+
+impl ValueDomain for Interval {
+    fn top(width: u64) -> Interval {
+        Interval { width, lo: signed_min(width), hi: signed_max(width) }
+    }
+    fn constant(width: u64,v: &BigInt) -> Interval {
+        let v = to_signed(width,v);
+        Interval { width, lo: v.clone(), hi: v }
+    }
+    fn width(&self) -> u64 {
+        self.width
+    }
+    fn range(&self,signed: bool) -> (BigInt,BigInt) {
+        if signed || self.is_non_negative() {
+            (self.lo.clone(),self.hi.clone())
+        } else if self.hi.sign()==Sign::Minus {
+            (&self.lo+pow2(self.width),&self.hi+pow2(self.width))
+        } else {
+            (BigInt::zero(),unsigned_max(self.width))
+        }
+    }
+    fn meet(&self,other: &Interval) -> Option<Interval> {
+        self.restrict(&other.lo,&other.hi)
+    }
+    fn binary(op: &BinOp,l: &Interval,r: &Interval) -> Interval {
+        let w = l.width;
+        if let (Some(a),Some(b)) = (l.as_constant(),r.as_constant()) {
+            return match fold(op,w,&a,&b) {
+                Some(v) => Interval::constant(w,&v),
+                None => Interval::top(w)
+            }
+        }
+        let zero = BigInt::zero();
+        let shift = r.as_constant().and_then(|s| s.to_u64()).filter(|&s| s < w);
+        match *op {
+            BinOp::Add(..) => Interval::bounded(w,&l.lo+&r.lo,&l.hi+&r.hi),
+            BinOp::Sub(..) => Interval::bounded(w,&l.lo-&r.hi,&l.hi-&r.lo),
+            BinOp::Mul(..) => {
+                let ps = [&l.lo*&r.lo,&l.lo*&r.hi,&l.hi*&r.lo,&l.hi*&r.hi];
+                Interval::bounded(w,ps.iter().min().unwrap().clone(),ps.iter().max().unwrap().clone())
+            },
+            BinOp::And => match (l.is_non_negative(),r.is_non_negative()) {
+                (true,true) => Interval::new(w,zero,min(&l.hi,&r.hi).clone()),
+                (true,false) => Interval::new(w,zero,l.hi.clone()),
+                (false,true) => Interval::new(w,zero,r.hi.clone()),
+                (false,false) => Interval::top(w)
+            },
+            BinOp::Or | BinOp::XOr if l.is_non_negative() && r.is_non_negative() => {
+                let bits = max(&l.hi,&r.hi).bits() as u64;
+                let lo = if *op==BinOp::Or { max(&l.lo,&r.lo).clone() } else { zero };
+                Interval::new(w,lo,pow2(bits)-BigInt::one())
+            },
+            BinOp::Shl => match shift {
+                Some(s) => Interval::bounded(w,&l.lo*pow2(s),&l.hi*pow2(s)),
+                None => Interval::top(w)
+            },
+            BinOp::AShr => match shift {
+                Some(s) => Interval::new(w,floor_div(&l.lo,&pow2(s)),floor_div(&l.hi,&pow2(s))),
+                None => Interval::new(w,min(&l.lo,&zero).clone(),max(&l.hi,&zero).clone())
+            },
+            BinOp::LShr => match shift {
+                Some(s) if l.is_non_negative() => Interval::new(w,&l.lo/pow2(s),&l.hi/pow2(s)),
+                None if l.is_non_negative() => Interval::new(w,zero,l.hi.clone()),
+                Some(s) if s > 0 => Interval::new(w,zero,unsigned_max(w)/pow2(s)),
+                _ => Interval::top(w)
+            },
+            BinOp::SDiv(_) if r.lo.sign()==Sign::Plus || r.hi.sign()==Sign::Minus => {
+                let qs = [&l.lo/&r.lo,&l.lo/&r.hi,&l.hi/&r.lo,&l.hi/&r.hi];
+                Interval::bounded(w,qs.iter().min().unwrap().clone(),qs.iter().max().unwrap().clone())
+            },
+            _ => Interval::top(w)
+        }
+    }
+    fn cast(op: CastInst,v: &Interval,width: u64) -> Interval {
+        match op {
+            CastInst::Trunc => Interval::bounded(width,v.lo.clone(),v.hi.clone()),
+            CastInst::ZExt => {
+                let (lo,hi) = v.range(false);
+                Interval::new(width,lo,hi)
+            },
+            CastInst::SExt => Interval::new(width,v.lo.clone(),v.hi.clone()),
+            _ => Interval::top(width)
+        }
+    }
+    fn assume(op: &CmpOp,outcome: bool,l: &Interval,r: &Interval) -> Option<(Interval,Interval)> {
+        let op = if outcome { op.clone() } else { negate(op) };
+        let one = BigInt::one();
+        match op {
+            CmpOp::Eq => {
+                let m = l.meet(r)?;
+                Some((m.clone(),m))
+            },
+            CmpOp::Ne => {
+                let l = if r.lo==r.hi { l.exclude(&r.lo)? } else { l.clone() };
+                let r = if l.lo==l.hi { r.exclude(&l.lo)? } else { r.clone() };
+                Some((l,r))
+            },
+            // Only restrict "less than" comparisons, by swapping the
+            // operands of the others
+            CmpOp::UGt | CmpOp::UGe | CmpOp::SGt | CmpOp::SGe =>
+                Interval::assume(&swap(&op),true,r,l).map(|(r,l)| (l,r)),
+            CmpOp::ULt | CmpOp::ULe | CmpOp::SLt | CmpOp::SLe => {
+                let strict = if op==CmpOp::ULt || op==CmpOp::SLt { one.clone() } else { BigInt::zero() };
+                let mut l = l.clone();
+                if !is_signed(&op) {
+                    if !may_hold(&op,&l.range(false),&r.range(false)) {
+                        return None
+                    }
+                    // Values below a non-negative bound are non-negative
+                    if r.is_non_negative() {
+                        l = l.restrict(&BigInt::zero(),&(&r.hi-&strict))?;
+                    }
+                    if !(l.is_non_negative() && r.is_non_negative()) {
+                        return Some((l,r.clone()))
+                    }
+                }
+                let l = l.restrict(&signed_min(l.width),&(&r.hi-&strict))?;
+                let r = r.restrict(&(&l.lo+&strict),&signed_max(r.width))?;
+                Some((l,r))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,"[{}, {}]",self.lo,self.hi)
+    }
+}
+
+/// Integers of which some bits are known to be zero or one.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct KnownBits {
+    width: u64,
+    zeros: BigUint,
+    ones: BigUint
+}
+
+impl KnownBits {
+    /// The integers with the bits set in `zeros` cleared and the bits
+    /// set in `ones` set.
+    ///
+    /// Panics if a bit is in both masks or exceeds the width.
+    pub fn new(width: u64,zeros: BigUint,ones: BigUint) -> KnownBits {
+        assert!((&zeros | &ones) <= mask(width) && (&zeros & &ones).is_zero(),
+                "invalid known bits for width {}",width);
+        KnownBits { width, zeros, ones }
+    }
+    /// The bits known to be zero.
+    pub fn zeros(&self) -> &BigUint {
+        &self.zeros
+    }
+    /// The bits known to be one.
+    pub fn ones(&self) -> &BigUint {
+        &self.ones
+    }
+    fn sign_bit(&self) -> BigUint {
+        BigUint::one() << (self.width as usize - 1)
+    }
+    /// The number of trailing bits known to be zero.
+    fn trailing_zeros(&self) -> u64 {
+        (0..self.width).take_while(|&i| bit(&self.zeros,i)).count() as u64
+    }
+    /// Extend the known bits to a larger width, with the new high bits
+    /// known to be like `fill` (unknown for `None`).
+    fn extend(&self,width: u64,fill: Option<bool>) -> KnownBits {
+        let high = mask(width) ^ mask(self.width);
+        match fill {
+            Some(true) => KnownBits { width, zeros: self.zeros.clone(), ones: &self.ones | &high },
+            Some(false) => KnownBits { width, zeros: &self.zeros | &high, ones: self.ones.clone() },
+            None => KnownBits { width, zeros: self.zeros.clone(), ones: self.ones.clone() }
+        }
+    }
+    /// The state of the sign bit, `None` if it is unknown.
+    fn sign(&self) -> Option<bool> {
+        let s = self.sign_bit();
+        if !(&self.ones & &s).is_zero() {
+            Some(true)
+        } else if !(&self.zeros & &s).is_zero() {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+fn mask(width: u64) -> BigUint {
+    (BigUint::one() << (width as usize)) - BigUint::one()
+}
+
+fn bit(v: &BigUint,i: u64) -> bool {
+    !((v >> (i as usize)) & BigUint::one()).is_zero()
+}
+
+fn to_bigint(v: BigUint) -> BigInt {
+    BigInt::from_biguint(Sign::Plus,v)
+}
+
+/// The known bits of `l + r + carry` (see LLVM's `KnownBits::computeForAddCarry`).
+fn add_known(width: u64,l: &KnownBits,r: &KnownBits,carry: bool) -> KnownBits {
+    let m = mask(width);
+    let c = if carry { BigUint::one() } else { BigUint::zero() };
+    let possible_zero = ((&l.zeros ^ &m) + (&r.zeros ^ &m) + &c) & &m;
+    let possible_one = (&l.ones + &r.ones + &c) & &m;
+    let carry_zero = (&possible_zero ^ &l.zeros ^ &r.zeros) ^ &m;
+    let carry_one = &possible_one ^ &l.ones ^ &r.ones;
+    let known = (&l.zeros | &l.ones) & (&r.zeros | &r.ones) & (carry_zero | carry_one);
+    KnownBits { width,
+                zeros: (possible_zero ^ &m) & &known,
+                ones: possible_one & known }
+}
+
+impl Lattice for KnownBits {
+    fn join(&self,other: &KnownBits) -> KnownBits {
+        KnownBits { width: self.width,
+                    zeros: &self.zeros & &other.zeros,
+                    ones: &self.ones & &other.ones }
+    }
+}
+
+impl ValueDomain for KnownBits {
+    fn top(width: u64) -> KnownBits {
+        KnownBits { width, zeros: BigUint::zero(), ones: BigUint::zero() }
+    }
+    fn constant(width: u64,v: &BigInt) -> KnownBits {
+        let ones = to_unsigned(width,v).to_biguint().unwrap();
+        KnownBits { width, zeros: &ones ^ mask(width), ones }
+    }
+    fn width(&self) -> u64 {
+        self.width
+    }
+    fn range(&self,signed: bool) -> (BigInt,BigInt) {
+        let m = mask(self.width);
+        let (lo,hi) = if signed && self.sign()!=Some(false) {
+            if self.sign()==Some(true) {
+                (self.ones.clone(),&self.zeros ^ &m)
+            } else {
+                let s = self.sign_bit();
+                (&self.ones | &s,(&self.zeros ^ &m) ^ s)
+            }
+        } else {
+            (self.ones.clone(),&self.zeros ^ &m)
+        };
+        if signed {
+            (to_signed(self.width,&to_bigint(lo)),to_signed(self.width,&to_bigint(hi)))
+        } else {
+            (to_bigint(lo),to_bigint(hi))
+        }
+    }
+    fn meet(&self,other: &KnownBits) -> Option<KnownBits> {
+        let zeros = &self.zeros | &other.zeros;
+        let ones = &self.ones | &other.ones;
+        if (&zeros & &ones).is_zero() {
+            Some(KnownBits { width: self.width, zeros, ones })
+        } else {
+            None
+        }
+    }
+    fn binary(op: &BinOp,l: &KnownBits,r: &KnownBits) -> KnownBits {
+        let w = l.width;
+        if let (Some(a),Some(b)) = (l.as_constant(),r.as_constant()) {
+            return match fold(op,w,&a,&b) {
+                Some(v) => KnownBits::constant(w,&v),
+                None => KnownBits::top(w)
+            }
+        }
+        let m = mask(w);
+        let shift = r.as_constant().and_then(|s| s.to_usize()).filter(|&s| (s as u64) < w);
+        match *op {
+            BinOp::And => KnownBits { width: w, zeros: &l.zeros | &r.zeros, ones: &l.ones & &r.ones },
+            BinOp::Or => KnownBits { width: w, zeros: &l.zeros & &r.zeros, ones: &l.ones | &r.ones },
+            BinOp::XOr => KnownBits { width: w,
+                                      zeros: (&l.zeros & &r.zeros) | (&l.ones & &r.ones),
+                                      ones: (&l.ones & &r.zeros) | (&l.zeros & &r.ones) },
+            BinOp::Add(..) => add_known(w,l,r,false),
+            // l - r = l + ~r + 1
+            BinOp::Sub(..) => {
+                let not_r = KnownBits { width: w, zeros: r.ones.clone(), ones: r.zeros.clone() };
+                add_known(w,l,&not_r,true)
+            },
+            BinOp::Mul(..) => {
+                let tz = min(l.trailing_zeros()+r.trailing_zeros(),w);
+                KnownBits { width: w, zeros: mask(tz), ones: BigUint::zero() }
+            },
+            BinOp::Shl => match shift {
+                Some(s) => KnownBits { width: w,
+                                       zeros: ((&l.zeros << s) | mask(s as u64)) & &m,
+                                       ones: (&l.ones << s) & &m },
+                None => KnownBits::top(w)
+            },
+            BinOp::LShr | BinOp::AShr => match shift {
+                Some(s) => {
+                    let shifted = KnownBits { width: w-s as u64, zeros: &l.zeros >> s, ones: &l.ones >> s };
+                    let fill = if *op==BinOp::LShr { Some(false) } else { l.sign() };
+                    shifted.extend(w,fill)
+                },
+                None => KnownBits::top(w)
+            },
+            BinOp::SDiv(_) => KnownBits::top(w)
+        }
+    }
+    fn cast(op: CastInst,v: &KnownBits,width: u64) -> KnownBits {
+        match op {
+            CastInst::Trunc => KnownBits { width, zeros: &v.zeros & mask(width), ones: &v.ones & mask(width) },
+            CastInst::ZExt => v.extend(width,Some(false)),
+            CastInst::SExt => v.extend(width,v.sign()),
+            _ => KnownBits::top(width)
+        }
+    }
+    fn assume(op: &CmpOp,outcome: bool,l: &KnownBits,r: &KnownBits) -> Option<(KnownBits,KnownBits)> {
+        let op = if outcome { op.clone() } else { negate(op) };
+        if op==CmpOp::Eq {
+            let m = l.meet(r)?;
+            return Some((m.clone(),m))
+        }
+        let signed = is_signed(&op);
+        if may_hold(&op,&l.range(signed),&r.range(signed)) {
+            Some((l.clone(),r.clone()))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for KnownBits {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        for i in (0..self.width).rev() {
+            let c = if bit(&self.ones,i) {
+                '1'
+            } else if bit(&self.zeros,i) {
+                '0'
+            } else {
+                '?'
+            };
+            write!(f,"{}",c)?;
+        }
+        Ok(())
+    }
+}
+
+/// The abstract values of the integer locals and arguments of a
+/// function. Values that are not tracked can be anything.
+#[derive(Debug,PartialEq,Clone)]
+pub struct ValueState<D> {
+    values: BTreeMap<Value,D>
+}
+
+impl<D: ValueDomain> ValueState<D> {
+    pub fn new() -> ValueState<D> {
+        ValueState { values: BTreeMap::new() }
+    }
+    /// The tracked value of a local or an argument.
+    pub fn get(&self,val: &Value) -> Option<&D> {
+        self.values.get(val)
+    }
+    /// Set the value of a local or an argument, other values are
+    /// ignored.
+    pub fn set(&mut self,val: &Value,d: D) {
+        match *val {
+            Value::Local(_) | Value::Argument(_) => { self.values.insert(val.clone(),d); },
+            _ => {}
+        }
+    }
+    /// The abstract value of an operand, `None` if its type is not an
+    /// integer type.
+    pub fn value(&self,tp: &Type,val: &Value) -> Option<D> {
+        let w = match *tp {
+            Type::Int(w) => w,
+            _ => return None
+        };
+        Some(match *val {
+            Value::Constant(Constant::Int(ref i)) => D::constant(w,i),
+            _ => self.values.get(val).cloned().unwrap_or_else(|| D::top(w))
+        })
+    }
+    fn update(&mut self,name: &str,d: Option<D>) {
+        let key = Value::Local(name.to_string());
+        match d {
+            Some(d) => { self.values.insert(key,d); },
+            None => { self.values.remove(&key); }
+        }
+    }
+}
+
+impl<D: ValueDomain> Default for ValueState<D> {
+    fn default() -> ValueState<D> {
+        ValueState::new()
+    }
+}
+
+impl<D: ValueDomain> Lattice for ValueState<D> {
+    fn join(&self,other: &ValueState<D>) -> ValueState<D> {
+        ValueState { values: self.values.iter()
+                     .filter_map(|(k,v)| other.values.get(k).map(|w| (k.clone(),v.join(w))))
+                     .collect() }
+    }
+    fn widen(&self,next: &ValueState<D>) -> ValueState<D> {
+        ValueState { values: self.values.iter()
+                     .filter_map(|(k,v)| next.values.get(k).map(|w| (k.clone(),v.widen(w))))
+                     .collect() }
+    }
+}
+
+/// Tracks the values of the integer locals and arguments of a function
+/// in a `ValueDomain`.
+pub struct ValueAnalysis<D> {
+    domain: PhantomData<D>
+}
+
+impl<D: ValueDomain> ValueAnalysis<D> {
+    pub fn new() -> ValueAnalysis<D> {
+        ValueAnalysis { domain: PhantomData }
+    }
+}
+
+impl<D: ValueDomain> Default for ValueAnalysis<D> {
+    fn default() -> ValueAnalysis<D> {
+        ValueAnalysis::new()
+    }
+}
+
+impl<D: ValueDomain> Analysis for ValueAnalysis<D> {
+    type State = ValueState<D>;
+    fn entry(&self,_: &Function) -> ValueState<D> {
+        ValueState::new()
+    }
+    fn instruction(&self,instr: &Instruction,st: &mut ValueState<D>) {
+        let (name,res) = match instr.content {
+            InstructionC::Bin(ref name,ref op,ref tp,ref l,ref r) =>
+                (name,st.value(tp,l).and_then(|l| st.value(tp,r).map(|r| D::binary(op,&l,&r)))),
+            InstructionC::ICmp(ref name,ref op,ref tp,ref l,ref r) =>
+                (name,Some(match (st.value(tp,l),st.value(tp,r)) {
+                    (Some(l),Some(r)) => D::compare(op,&l,&r),
+                    _ => D::top(1)
+                })),
+            InstructionC::Unary(ref name,ref arg,UnaryInst::Cast(Type::Int(w),op)) =>
+                (name,Some(match st.value(&arg.tp,&arg.val) {
+                    Some(v) => D::cast(op,&v,w),
+                    None => D::top(w)
+                })),
+            InstructionC::Select(ref name,ref c,ref tp,ref l,ref r) =>
+                (name,match st.value(&Type::Int(1),c).and_then(|c| c.as_constant()) {
+                    Some(ref c) if c.is_zero() => st.value(tp,r),
+                    Some(_) => st.value(tp,l),
+                    None => st.value(tp,l).and_then(|l| st.value(tp,r).map(|r| l.join(&r)))
+                }),
+            InstructionC::Phi(..) | InstructionC::Term(_) => return,
+            ref c => match c.name() {
+                Some(name) => {
+                    st.update(name,None);
+                    return
+                },
+                None => return
+            }
+        };
+        st.update(name,res);
+    }
+    fn edge(&self,from: &BasicBlock,to: &BasicBlock,st: &ValueState<D>) -> Option<ValueState<D>> {
+        let mut res: Option<ValueState<D>> = None;
+        {
+            let mut add = |cur: Option<ValueState<D>>| if let Some(cur) = cur {
+                res = Some(match res.take() {
+                    Some(prev) => prev.join(&cur),
+                    None => cur
+                });
+            };
+            match *from.terminator()? {
+                Terminator::Br(_) => add(Some(st.clone())),
+                Terminator::BrC(ref c,ref t,ref f) => {
+                    if *t==to.name {
+                        add(assume_condition(from,c,true,st.clone()));
+                    }
+                    if *f==to.name {
+                        add(assume_condition(from,c,false,st.clone()));
+                    }
+                },
+                Terminator::Switch(ref tp,ref v,ref def,ref cases) => {
+                    if *def==to.name {
+                        add(cases.iter().try_fold(st.clone(),|cur,case| {
+                            assume_compare(&CmpOp::Ne,tp,v,&Value::Constant(case.0.clone()),true,cur)
+                        }));
+                    }
+                    for case in cases.iter().filter(|case| case.1==to.name) {
+                        add(assume_compare(&CmpOp::Eq,tp,v,&Value::Constant(case.0.clone()),true,st.clone()));
+                    }
+                },
+                _ => {}
+            }
+        }
+        let mut res = res?;
+        // Evaluate the phi nodes in parallel
+        let mut phis = Vec::new();
+        for instr in to.instrs.iter() {
+            match instr.content {
+                InstructionC::Phi(ref name,ref tp,ref incoming) => {
+                    let v = incoming.iter()
+                        .find(|inc| inc.1==from.name)
+                        .and_then(|inc| res.value(tp,&inc.0));
+                    phis.push((name,v));
+                },
+                _ => break
+            }
+        }
+        for (name,v) in phis {
+            res.update(name,v);
+        }
+        Some(res)
+    }
+}
+
+/// Restrict a state to the executions in which a branch condition has
+/// the given outcome. Comparisons and conjunctions defined in the
+/// branching block are taken into account.
+fn assume_condition<D: ValueDomain>(blk: &BasicBlock,cond: &Value,outcome: bool,mut st: ValueState<D>)
+                                    -> Option<ValueState<D>> {
+    let val = D::constant(1,&BigInt::from(outcome as u8));
+    let cur = st.value(&Type::Int(1),cond)?;
+    cur.meet(&val)?;
+    let name = match *cond {
+        Value::Local(ref name) => name,
+        _ => return Some(st)
+    };
+    st.set(cond,val);
+    for instr in blk.instrs.iter() {
+        match instr.content {
+            InstructionC::ICmp(ref n,ref op,ref tp,ref l,ref r) if n==name =>
+                return assume_compare(op,tp,l,r,outcome,st),
+            InstructionC::Bin(ref n,BinOp::And,_,ref l,ref r) if n==name && outcome =>
+                return assume_condition(blk,l,true,st).and_then(|st| assume_condition(blk,r,true,st)),
+            InstructionC::Bin(ref n,BinOp::Or,_,ref l,ref r) if n==name && !outcome =>
+                return assume_condition(blk,l,false,st).and_then(|st| assume_condition(blk,r,false,st)),
+            _ => {}
+        }
+    }
+    Some(st)
+}
+
+fn assume_compare<D: ValueDomain>(op: &CmpOp,tp: &Type,l: &Value,r: &Value,outcome: bool,mut st: ValueState<D>)
+                                  -> Option<ValueState<D>> {
+    if let (Some(lv),Some(rv)) = (st.value(tp,l),st.value(tp,r)) {
+        let (lv,rv) = D::assume(op,outcome,&lv,&rv)?;
+        st.set(l,lv);
+        st.set(r,rv);
+    }
+    Some(st)
+}
+
+/// The interval analysis of a function, `None` for declarations.
+pub fn intervals(fun: &Function) -> Option<Fixpoint<ValueState<Interval>>> {
+    analyze(&ValueAnalysis::new(),fun)
+}
+
+/// The known bits analysis of a function, `None` for declarations.
+pub fn known_bits(fun: &Function) -> Option<Fixpoint<ValueState<KnownBits>>> {
+    analyze(&ValueAnalysis::new(),fun)
+}
+
+/// An index into an array type by a `getelementptr` instruction.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct ArrayIndex {
+    pub block: usize,
+    pub instr: usize,
+    /// The position of the index in the instruction's index list.
+    pub operand: usize,
+    /// The number of elements of the array.
+    pub length: u64,
+    /// `Some(true)` if the index is always in bounds, `Some(false)` if
+    /// it never is. An index equal to the length only forms a pointer
+    /// past the end and counts as out of bounds.
+    pub in_bounds: Option<bool>
+}
+
+/// Check the array indices of all reachable `getelementptr`
+/// instructions of a function against the values of a value analysis.
+/// The first index of an instruction steps over the pointer and is
+/// not checked, neither are indices into arrays of length 0.
+pub fn array_indices<D: ValueDomain>(m: &Module,fun: &Function,res: &Fixpoint<ValueState<D>>) -> Vec<ArrayIndex> {
+    let mut indices = Vec::new();
+    let blocks = match fun.body {
+        Some(ref blks) => blks,
+        None => return indices
+    };
+    for (b,blk) in blocks.iter().enumerate() {
+        for (i,instr) in blk.instrs.iter().enumerate() {
+            let gep = match instr.content {
+                InstructionC::GEP(_,ref gep) => gep,
+                _ => continue
+            };
+            let st = match res.before(b,i) {
+                Some(st) => st,
+                None => continue
+            };
+            let mut cur = match *resolve_type(&m.types,&gep.ptr.tp) {
+                Type::Pointer(ref el,_) => &**el,
+                _ => continue
+            };
+            for (n,idx) in gep.indices.iter().map(|idx| &idx.0).enumerate().skip(1) {
+                cur = match *resolve_type(&m.types,cur) {
+                    // Flexible array members have no useful length
+                    Type::Array(0,ref el) => el,
+                    Type::Array(len,ref el) => {
+                        let in_bounds = st.value(&idx.tp,&idx.val).and_then(|v| {
+                            let (lo,hi) = v.range(true);
+                            let len = BigInt::from(len);
+                            if lo.sign()!=Sign::Minus && hi<len {
+                                Some(true)
+                            } else if hi.sign()==Sign::Minus || lo>=len {
+                                Some(false)
+                            } else {
+                                None
+                            }
+                        });
+                        indices.push(ArrayIndex { block: b, instr: i, operand: n, length: len, in_bounds });
+                        el
+                    },
+                    Type::Struct(ref els) => {
+                        let field = match idx.val {
+                            Value::Constant(Constant::Int(ref k)) => k.to_usize(),
+                            _ => None
+                        };
+                        match field.and_then(|k| els.get(k)) {
+                            Some(el) => el,
+                            None => break
+                        }
+                    },
+                    _ => break
+                };
+            }
+        }
+    }
+    indices
+}
+
+#[cfg(test)]
+fn interval(width: u64,lo: i64,hi: i64) -> Interval {
+    Interval::new(width,BigInt::from(lo),BigInt::from(hi))
+}
+
+#[test]
+fn test_interval_ops() {
+    let c = |v: i64| Interval::constant(8,&BigInt::from(v));
+    let add = BinOp::Add(false,false);
+    assert_eq!(Interval::binary(&add,&interval(8,0,10),&interval(8,-5,5)),interval(8,-5,15));
+    assert_eq!(Interval::binary(&add,&interval(8,0,100),&interval(8,0,100)),Interval::top(8));
+    // Constants wrap around
+    assert_eq!(Interval::binary(&add,&c(127),&c(1)),c(-128));
+    assert_eq!(Interval::binary(&BinOp::Mul(false,false),&interval(8,-2,3),&interval(8,-4,5)),interval(8,-12,15));
+    assert_eq!(Interval::binary(&BinOp::And,&interval(8,-100,100),&interval(8,0,15)),interval(8,0,15));
+    assert_eq!(Interval::binary(&BinOp::AShr,&interval(8,-9,9),&c(2)),interval(8,-3,2));
+    assert_eq!(Interval::binary(&BinOp::SDiv(false),&interval(8,-9,9),&interval(8,2,3)),interval(8,-4,4));
+    assert_eq!(Interval::cast(CastInst::ZExt,&Interval::top(1),8),interval(8,0,1));
+    assert_eq!(Interval::cast(CastInst::Trunc,&interval(32,0,300),8),Interval::top(8));
+    assert_eq!(interval(8,0,1).widen(&interval(8,0,2)),interval(8,0,127));
+    // i < 10 and i >= 0
+    assert_eq!(Interval::assume(&CmpOp::SLt,true,&Interval::top(8),&c(10)),
+               Some((interval(8,-128,9),c(10))));
+    assert_eq!(Interval::assume(&CmpOp::ULt,true,&Interval::top(8),&c(10)),
+               Some((interval(8,0,9),c(10))));
+    assert_eq!(Interval::assume(&CmpOp::SGt,false,&interval(8,5,20),&interval(8,0,10)),
+               Some((interval(8,5,10),interval(8,5,10))));
+    assert_eq!(Interval::assume(&CmpOp::Eq,true,&interval(8,0,5),&interval(8,6,9)),None);
+    assert_eq!(Interval::assume(&CmpOp::Ne,true,&interval(8,0,5),&c(0)),Some((interval(8,1,5),c(0))));
+    assert_eq!(Interval::compare(&CmpOp::UGt,&interval(8,-1,-1),&interval(8,0,100)),
+               Interval::constant(1,&BigInt::one()));
+    assert_eq!(Interval::constant(1,&BigInt::one()).as_constant(),Some(BigInt::one()));
+}
+
+#[test]
+fn test_known_bits_ops() {
+    let c = |v: i64| KnownBits::constant(8,&BigInt::from(v));
+    let x = KnownBits::binary(&BinOp::Or,&KnownBits::binary(&BinOp::Shl,&KnownBits::top(8),&c(2)),&c(1));
+    assert_eq!(format!("{}",x),"??????01");
+    assert_eq!(format!("{}",KnownBits::binary(&BinOp::Add(false,false),&x,&c(3))),"??????00");
+    assert_eq!(format!("{}",KnownBits::binary(&BinOp::Sub(false,false),&x,&c(1))),"??????00");
+    assert_eq!(format!("{}",KnownBits::binary(&BinOp::AShr,&x,&c(1))),"???????0");
+    assert_eq!(format!("{}",KnownBits::binary(&BinOp::LShr,&x,&c(4))),"0000????");
+    assert_eq!(format!("{}",KnownBits::cast(CastInst::SExt,&c(-2),12)),"111111111110");
+    assert_eq!(x.range(false),(BigInt::from(1),BigInt::from(253)));
+    assert_eq!(x.range(true),(BigInt::from(-127),BigInt::from(125)));
+    assert_eq!(KnownBits::compare(&CmpOp::Eq,&x,&c(0)),KnownBits::constant(1,&BigInt::zero()));
+    assert_eq!(KnownBits::compare(&CmpOp::SLt,&x,&c(0)),KnownBits::top(1));
+    assert_eq!(c(5).join(&c(7)),KnownBits::new(8,BigUint::from(0xf8u32),BigUint::from(5u32)));
+}
+
+#[test]
+fn test_intervals_loop() {
+    let fun = parse_test_function(b"define i32 @f(i64 %n) {
+entry:
+  %a = alloca [10 x i32], align 4
+  br label %loop
+
+loop:
+  %i = phi i32 [ 0, %entry ], [ %i2, %body ]
+  %c = icmp slt i32 %i, 10
+  br i1 %c, label %body, label %exit
+
+body:
+  %idx = sext i32 %i to i64
+  %p = getelementptr inbounds [10 x i32]* %a, i64 0, i64 %idx
+  store i32 %i, i32* %p, align 4
+  %i2 = add nsw i32 %i, 1
+  br label %loop
+
+exit:
+  %idx2 = sext i32 %i to i64
+  %q = getelementptr inbounds [10 x i32]* %a, i64 0, i64 %idx2
+  %big = icmp sgt i32 %i, 10
+  br i1 %big, label %dead, label %done
+
+dead:
+  unreachable
+
+done:
+  %r = getelementptr inbounds [10 x i32]* %a, i64 0, i64 %n
+  ret i32 %i
+}");
+    let res = intervals(&fun).unwrap();
+    let i = Value::Local("i".to_string());
+    let at = |b: usize,n: usize| res.before(b,n).unwrap().get(&i).cloned();
+    // Widening loses the upper bound, narrowing recovers it
+    assert_eq!(at(1,1),Some(interval(32,0,10)));
+    assert_eq!(at(2,0),Some(interval(32,0,9)));
+    assert_eq!(at(3,0),Some(interval(32,10,10)));
+    assert_eq!(res.after(2,3).unwrap().get(&Value::Local("i2".to_string())),Some(&interval(32,1,10)));
+    assert!(!res.is_reachable(4));
+    assert!(res.is_feasible(3,5));
+    assert_eq!(res.dead_edges(),vec![(3,4)]);
+    let m = Module::new();
+    let checks: Vec<(usize,Option<bool>)> = array_indices(&m,&fun,&res).iter()
+        .map(|idx| (idx.block,idx.in_bounds))
+        .collect();
+    assert_eq!(checks,vec![(2,Some(true)),(3,Some(false)),(5,None)]);
+}
+
+#[test]
+fn test_known_bits_branch() {
+    let fun = parse_test_function(b"define i32 @g(i32 %x, i8 %y) {
+entry:
+  %a = shl i32 %x, 2
+  %b = or i32 %a, 1
+  %m = and i32 %b, 3
+  %c = icmp eq i32 %m, 0
+  br i1 %c, label %dead, label %ok
+
+dead:
+  unreachable
+
+ok:
+  %s = add i32 %b, 3
+  switch i8 %y, label %other [ i8 0, label %zero
+                               i8 1, label %one ]
+
+zero:
+  %z = zext i8 %y to i32
+  ret i32 %z
+
+one:
+  ret i32 %s
+
+other:
+  ret i32 0
+}");
+    let res = known_bits(&fun).unwrap();
+    assert_eq!(res.dead_edges(),vec![(0,1)]);
+    let st = res.block_entry(2).unwrap();
+    assert_eq!(format!("{}",st.get(&Value::Local("b".to_string())).unwrap()),
+               format!("{}01","?".repeat(30)));
+    assert_eq!(format!("{}",res.after(2,0).unwrap().get(&Value::Local("s".to_string())).unwrap()),
+               format!("{}00","?".repeat(30)));
+    // Switch cases fix the value
+    assert_eq!(res.after(3,0).unwrap().get(&Value::Local("z".to_string())),
+               Some(&KnownBits::constant(32,&BigInt::zero())));
+    // Intervals do not see that the first branch is dead
+    let res = intervals(&fun).unwrap();
+    assert!(res.dead_edges().is_empty());
+    assert_eq!(res.block_entry(2).unwrap().get(&Value::Local("m".to_string())),Some(&interval(32,1,3)));
+    assert_eq!(res.after(3,0).unwrap().get(&Value::Local("z".to_string())),Some(&interval(32,0,0)));
+    assert_eq!(res.block_entry(5).unwrap().get(&Value::Argument(1)),Some(&Interval::top(8)));
+}
+
+#[test]
+fn test_absint_minisat() {
+    let m = minisat();
+    for fun in m.functions.values() {
+        let res = match intervals(fun) {
+            Some(res) => res,
+            None => continue
+        };
+        let reach = res.cfg().reachable();
+        assert!(res.is_reachable(0),"{}",fun.name);
+        for (b,&r) in reach.iter().enumerate() {
+            assert!(!res.is_reachable(b) || r);
+        }
+        let kb = known_bits(fun).unwrap();
+        assert!(kb.is_reachable(0),"{}",fun.name);
+        for idx in array_indices(&m,fun,&res) {
+            assert!(idx.in_bounds!=Some(false),"{} indexes out of bounds",fun.name);
+        }
+    }
+}
@@ -0,0 +1,822 @@
+//! Alias analysis for the pointers of a function and an Andersen-style
+//! points-to analysis for a whole module.
+//!
+//! `AliasAnalysis` decides whether two pointers may refer to the same
+//! memory by stripping bitcasts and constant-offset `getelementptr`s
+//! down to an underlying object. Distinct allocas, globals, `noalias`
+//! arguments and the results of `noalias` calls never overlap, and
+//! different offsets into the same object are compared with the access
+//! sizes given by the `DataLayout`.
+//!
+//! `PointsTo` computes, for every pointer of the module, the set of
+//! memory objects it may point to. It is inclusion based, flow- and
+//! context-insensitive and does not distinguish fields. Calls through
+//! function pointers are resolved while the constraints are solved.
+//! Declared functions are assumed not to store pointers into memory
+//! reachable from their arguments; the pointers they return are
+//! `Unknown`, except for `noalias` results which are fresh heap
+//! objects.
+use num_traits::ToPrimitive;
+use std::collections::{BTreeSet,HashMap,VecDeque};
+use super::*;
+use builder::{self,resolve_type,instruction_type};
+
+/// How two memory accesses relate to each other.
+#[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Hash,Clone,Copy)]
+pub enum AliasResult {
+    /// The accesses never overlap.
+    NoAlias,
+    /// Nothing is known.
+    MayAlias,
+    /// The accesses overlap but do not start at the same address.
+    PartialAlias,
+    /// The accesses start at the same address.
+    MustAlias
+}
+
+// What a pointer is based on after stripping casts and offsets.
+#[derive(Debug,PartialEq,Eq,Clone)]
+enum Base {
+    Null,
+    Alloca(String),
+    Global(String),
+    // The result of a call returning a `noalias` pointer
+    NoAliasCall(String),
+    Argument(usize),
+    Other(Value)
+}
+
+// How many casts and offsets are stripped from a pointer at most.
+const MAX_LOOKUP: usize = 32;
+
+pub struct AliasAnalysis<'m> {
+    module: &'m Module,
+    function: &'m Function,
+    defs: HashMap<&'m str,&'m InstructionC>,
+    points_to: Option<&'m PointsTo>
+}
+
+impl<'m> AliasAnalysis<'m> {
+    pub fn new(module: &'m Module,function: &'m Function) -> AliasAnalysis<'m> {
+        let mut defs = HashMap::new();
+        if let Some(ref blks) = function.body {
+            for instr in blks.iter().flat_map(|blk| blk.instrs.iter()) {
+                if let Some(name) = instr.content.name() {
+                    defs.insert(name,&instr.content);
+                }
+            }
+        }
+        AliasAnalysis { module, function, defs, points_to: None }
+    }
+    /// Use the result of a points-to analysis of the module for pairs
+    /// of pointers that the local rules cannot separate.
+    pub fn set_points_to(&mut self,pt: &'m PointsTo) {
+        self.points_to = Some(pt);
+    }
+    /// Relate accesses through two pointers, each of the size of the
+    /// type it points to.
+    pub fn alias(&self,a: &Value,b: &Value) -> AliasResult {
+        self.alias_sized(a,self.access_size(a),b,self.access_size(b))
+    }
+    /// Relate an access of `size_a` bytes through `a` to one of
+    /// `size_b` bytes through `b`. `None` stands for an unknown size.
+    pub fn alias_sized(&self,a: &Value,size_a: Option<u64>,b: &Value,size_b: Option<u64>) -> AliasResult {
+        if a==b {
+            return AliasResult::MustAlias
+        }
+        let (base_a,off_a) = self.decompose(a);
+        let (base_b,off_b) = self.decompose(b);
+        if base_a==Base::Null || base_b==Base::Null {
+            return AliasResult::NoAlias
+        }
+        if base_a==base_b {
+            if let (Some(oa),Some(ob)) = (off_a,off_b) {
+                return compare_offsets(oa,size_a,ob,size_b)
+            }
+        } else if (self.is_identified(&base_a) && self.is_identified(&base_b)) ||
+            self.separate_from_arguments(&base_a,&base_b) ||
+            self.separate_from_arguments(&base_b,&base_a) {
+            return AliasResult::NoAlias
+        }
+        match self.points_to {
+            Some(pt) if !pt.may_alias(self.function,a,b) => AliasResult::NoAlias,
+            _ => AliasResult::MayAlias
+        }
+    }
+    /// The number of bytes accessed through a pointer by a load or
+    /// store of the type it points to.
+    pub fn access_size(&self,ptr: &Value) -> Option<u64> {
+        let tp = self.type_of(ptr)?;
+        match *resolve_type(&self.module.types,&tp) {
+            Type::Pointer(ref el,_) => store_size(self.module,el),
+            _ => None
+        }
+    }
+    fn type_of(&self,v: &Value) -> Option<Type> {
+        match *v {
+            Value::Argument(n) => self.function.arguments.get(n).map(|a| a.1.clone()),
+            Value::Local(ref name) => self.defs.get(&name[..])
+                .and_then(|instr| instruction_type(&self.module.types,instr)),
+            Value::Constant(ref c) => constant_type(self.module,c),
+            Value::Metadata(_) => None
+        }
+    }
+    // Strip casts and offsets from a pointer. The offset is in bytes,
+    // `None` if some index is not a constant.
+    fn decompose(&self,v: &Value) -> (Base,Option<i64>) {
+        let mut cur = v.clone();
+        let mut offset = Some(0);
+        for _ in 0..MAX_LOOKUP {
+            let next = match cur {
+                Value::Constant(Constant::NullPtr) => return (Base::Null,offset),
+                Value::Constant(Constant::Global(ref g)) => return (Base::Global(g.clone()),offset),
+                Value::Constant(Constant::GEP(ref g)) => {
+                    offset = add_offset(offset,gep_offset(self.module,g,constant_index));
+                    Value::Constant(g.ptr.val.clone())
+                },
+                Value::Constant(Constant::Cast(CastInst::Bitcast,ref c,_)) => Value::Constant(c.val.clone()),
+                Value::Argument(n) => return (Base::Argument(n),offset),
+                Value::Local(ref name) => match self.defs.get(&name[..]) {
+                    Some(&&InstructionC::Alloca(..)) => return (Base::Alloca(name.clone()),offset),
+                    Some(&&InstructionC::Unary(_,ref v,UnaryInst::Cast(_,CastInst::Bitcast))) => v.val.clone(),
+                    Some(&InstructionC::GEP(_,g)) => {
+                        offset = add_offset(offset,gep_offset(self.module,g,value_index));
+                        g.ptr.val.clone()
+                    },
+                    Some(&instr) if returns_noalias(self.module,instr) =>
+                        return (Base::NoAliasCall(name.clone()),offset),
+                    _ => return (Base::Other(cur),offset)
+                },
+                _ => return (Base::Other(cur),offset)
+            };
+            cur = next;
+        }
+        (Base::Other(cur),offset)
+    }
+    fn is_noalias_argument(&self,n: usize) -> bool {
+        self.function.argument_attrs.get(n).is_some_and(|a| a.noalias)
+    }
+    // Objects that are distinct from every other identified object.
+    fn is_identified(&self,base: &Base) -> bool {
+        match *base {
+            Base::Alloca(_) | Base::Global(_) | Base::NoAliasCall(_) => true,
+            Base::Argument(n) => self.is_noalias_argument(n),
+            _ => false
+        }
+    }
+    // Objects created by the function cannot be reached through its
+    // arguments, and `noalias` arguments do not overlap other ones.
+    fn separate_from_arguments(&self,a: &Base,b: &Base) -> bool {
+        match (a,b) {
+            (&Base::Alloca(_),&Base::Argument(_)) |
+            (&Base::NoAliasCall(_),&Base::Argument(_)) => true,
+            (&Base::Argument(n),&Base::Argument(_)) => self.is_noalias_argument(n),
+            _ => false
+        }
+    }
+}
+
+fn compare_offsets(oa: i64,size_a: Option<u64>,ob: i64,size_b: Option<u64>) -> AliasResult {
+    if oa==ob {
+        return AliasResult::MustAlias
+    }
+    let (lo,size_lo,size_hi,hi) = if oa<ob {
+        (oa,size_a,size_b,ob)
+    } else {
+        (ob,size_b,size_a,oa)
+    };
+    match size_lo {
+        Some(sz) if lo.checked_add(sz as i64).is_some_and(|end| end<=hi) => AliasResult::NoAlias,
+        Some(_) if size_hi.is_some() => AliasResult::PartialAlias,
+        _ => AliasResult::MayAlias
+    }
+}
+
+fn add_offset(offset: Option<i64>,delta: Option<i64>) -> Option<i64> {
+    offset?.checked_add(delta?)
+}
+
+fn value_index(v: &Value) -> Option<i64> {
+    match *v {
+        Value::Constant(ref c) => constant_index(c),
+        _ => None
+    }
+}
+
+fn constant_index(c: &Constant) -> Option<i64> {
+    match *c {
+        Constant::Int(ref i) => i.to_i64(),
+        _ => None
+    }
+}
+
+// Whether the type has a size, without panicking on undefined named
+// types.
+fn is_sized(m: &Module,tp: &Type) -> bool {
+    sized(m,tp,&mut Vec::new())
+}
+
+// Named types that contain themselves are not sized, `seen` holds the
+// ones that are being looked at.
+fn sized<'a>(m: &'a Module,tp: &'a Type,seen: &mut Vec<&'a str>) -> bool {
+    match *tp {
+        Type::Named(ref n) => match m.types.get(n) {
+            Some(t) if !seen.contains(&&n[..]) => {
+                seen.push(n);
+                let res = sized(m,t,seen);
+                seen.pop();
+                res
+            },
+            _ => false
+        },
+        Type::Opaque | Type::Label | Type::Function(..) | Type::Metadata => false,
+        Type::Struct(ref els) => els.iter().all(|el| sized(m,el,seen)),
+        Type::Array(_,ref el) => sized(m,el,seen),
+        _ => true
+    }
+}
+
+fn store_size(m: &Module,tp: &Type) -> Option<u64> {
+    if !is_sized(m,tp) {
+        return None
+    }
+    match m.datalayout.type_store_size(tp,&m.types) {
+        Ok(0) | Err(_) => None,
+        Ok(sz) => Some(sz)
+    }
+}
+
+/// The byte offset computed by a `getelementptr`, `None` if some index
+/// is not a constant.
+pub fn gep_offset<T>(m: &Module,g: &GEP<T>,index: fn(&T) -> Option<i64>) -> Option<i64> {
+    let mut cur = match *resolve_type(&m.types,&g.ptr.tp) {
+        Type::Pointer(ref el,_) => &**el,
+        _ => return None
+    };
+    let mut offset: i64 = 0;
+    for (n,idx) in g.indices.iter().enumerate() {
+        let i = index(&idx.0.val)?;
+        let delta = if n==0 {
+            element_distance(m,cur,i)?
+        } else {
+            match *resolve_type(&m.types,cur) {
+                Type::Struct(ref els) => {
+                    let k = i.to_usize().filter(|k| *k<els.len())?;
+                    if !is_sized(m,cur) {
+                        return None
+                    }
+                    let off = m.datalayout.struct_layout(els,&m.types).ok()?.element_offset(k);
+                    cur = &els[k];
+                    off as i64
+                },
+                Type::Array(_,ref el) => {
+                    cur = el;
+                    element_distance(m,el,i)?
+                },
+                _ => return None
+            }
+        };
+        offset = offset.checked_add(delta)?;
+    }
+    Some(offset)
+}
+
+fn element_distance(m: &Module,tp: &Type,i: i64) -> Option<i64> {
+    if !is_sized(m,tp) {
+        return None
+    }
+    (m.datalayout.type_alloc_size(tp,&m.types).ok()? as i64).checked_mul(i)
+}
+
+fn constant_type(m: &Module,c: &Constant) -> Option<Type> {
+    match *c {
+        Constant::Global(ref g) => match m.globals.get(g) {
+            Some(gv) => Some(Type::ptr(gv.types.clone())),
+            None => m.functions.get(g).map(|f| Type::ptr(builder::function_type(f)))
+        },
+        Constant::GEP(ref g) => {
+            let indices: Vec<Option<u64>> = g.indices.iter()
+                .map(|idx| constant_index(&idx.0.val).and_then(|i| i.to_u64()))
+                .collect();
+            builder::gep_type(&m.types,&g.ptr.tp,&indices).ok()
+        },
+        Constant::Cast(_,_,ref tp) => Some(tp.clone()),
+        _ => None
+    }
+}
+
+// Whether the instruction is a call returning a fresh pointer, either
+// by its own return attributes or by those of the called declaration.
+fn returns_noalias(m: &Module,instr: &InstructionC) -> bool {
+    match *instr {
+        InstructionC::Call(Some(_),_,ref ret,ref callee,_,_) => {
+            ret.as_ref().is_some_and(|r| r.1.noalias) || match *callee {
+                Value::Constant(Constant::Global(ref f)) => m.functions.get(f)
+                    .and_then(|f| f.return_type.as_ref())
+                    .is_some_and(|r| r.0.noalias),
+                _ => false
+            }
+        },
+        _ => false
+    }
+}
+
+fn is_pointer(m: &Module,tp: &Type) -> bool {
+    matches!(*resolve_type(&m.types,tp),Type::Pointer(..))
+}
+
+/// An abstract memory location of the points-to analysis.
+#[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Hash,Clone)]
+pub enum MemoryObject {
+    Global(String),
+    Function(String),
+    /// An alloca, given by the function and the name of the local.
+    Alloca(String,String),
+    /// The memory returned by a `noalias` call, given by the calling
+    /// function and the name of the result.
+    Heap(String,String),
+    /// Memory the analysis does not know about, for example pointers
+    /// passed in from outside the module.
+    Unknown
+}
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+enum Node {
+    Value(String,Value),
+    Constant(Constant),
+    Return(String),
+    Contents(MemoryObject)
+}
+
+// A call whose callee is only known after solving.
+#[derive(Debug,Clone)]
+struct IndirectCall {
+    args: Vec<Option<usize>>,
+    result: Option<usize>
+}
+
+struct Constraints<'m> {
+    module: &'m Module,
+    nodes: HashMap<Node,usize>,
+    sets: Vec<BTreeSet<MemoryObject>>,
+    copies: Vec<BTreeSet<usize>>,
+    // Indexed by the pointer that is loaded from
+    loads: Vec<Vec<usize>>,
+    // Indexed by the pointer that is stored to
+    stores: Vec<Vec<usize>>,
+    // Indexed by the callee
+    calls: Vec<Vec<IndirectCall>>
+}
+
+impl<'m> Constraints<'m> {
+    fn node(&mut self,n: Node) -> usize {
+        if let Some(idx) = self.nodes.get(&n) {
+            return *idx
+        }
+        let idx = self.sets.len();
+        let mut set = BTreeSet::new();
+        match n {
+            Node::Contents(MemoryObject::Unknown) => {
+                set.insert(MemoryObject::Unknown);
+            },
+            Node::Constant(ref c) => set.extend(constant_object(self.module,c)),
+            _ => {}
+        }
+        self.nodes.insert(n,idx);
+        self.sets.push(set);
+        self.copies.push(BTreeSet::new());
+        self.loads.push(Vec::new());
+        self.stores.push(Vec::new());
+        self.calls.push(Vec::new());
+        idx
+    }
+    fn value(&mut self,fun: &str,v: &Value) -> Option<usize> {
+        match *v {
+            Value::Local(_) | Value::Argument(_) => Some(self.node(Node::Value(fun.to_string(),v.clone()))),
+            Value::Constant(ref c) => Some(self.node(Node::Constant(c.clone()))),
+            Value::Metadata(_) => None
+        }
+    }
+    fn local(&mut self,fun: &str,name: &str) -> usize {
+        self.node(Node::Value(fun.to_string(),Value::Local(name.to_string())))
+    }
+    fn copy(&mut self,from: Option<usize>,to: usize) {
+        if let Some(from) = from {
+            self.copies[from].insert(to);
+        }
+    }
+    fn function(&mut self,fun: &Function) {
+        let name = &fun.name[..];
+        let blks = match fun.body {
+            Some(ref blks) => blks,
+            None => return
+        };
+        let internal = matches!(fun.linkage,Some(Linkage::Internal) | Some(Linkage::Private));
+        if !internal {
+            for (n,arg) in fun.arguments.iter().enumerate() {
+                if is_pointer(self.module,&arg.1) {
+                    let idx = self.node(Node::Value(name.to_string(),Value::Argument(n)));
+                    self.sets[idx].insert(MemoryObject::Unknown);
+                }
+            }
+        }
+        for instr in blks.iter().flat_map(|blk| blk.instrs.iter()) {
+            match instr.content {
+                InstructionC::Alloca(ref res,..) => {
+                    let idx = self.local(name,res);
+                    self.sets[idx].insert(MemoryObject::Alloca(name.to_string(),res.clone()));
+                },
+                InstructionC::Unary(ref res,ref v,UnaryInst::Cast(_,CastInst::Bitcast)) => {
+                    let from = self.value(name,&v.val);
+                    let to = self.local(name,res);
+                    self.copy(from,to);
+                },
+                InstructionC::Unary(ref res,_,UnaryInst::Cast(_,CastInst::IntToPtr)) => {
+                    let idx = self.local(name,res);
+                    self.sets[idx].insert(MemoryObject::Unknown);
+                },
+                InstructionC::Unary(ref res,ref ptr,UnaryInst::Load(..))
+                    if instruction_type(&self.module.types,&instr.content).is_some_and(|tp| is_pointer(self.module,&tp)) => {
+                    let to = self.local(name,res);
+                    if let Some(from) = self.value(name,&ptr.val) {
+                        self.loads[from].push(to);
+                    }
+                },
+                InstructionC::GEP(ref res,ref g) => {
+                    let from = self.value(name,&g.ptr.val);
+                    let to = self.local(name,res);
+                    self.copy(from,to);
+                },
+                InstructionC::Select(ref res,_,_,ref v1,ref v2) => {
+                    let to = self.local(name,res);
+                    for v in [v1,v2].iter() {
+                        let from = self.value(name,v);
+                        self.copy(from,to);
+                    }
+                },
+                InstructionC::Phi(ref res,_,ref incoming) => {
+                    let to = self.local(name,res);
+                    for inc in incoming.iter() {
+                        let from = self.value(name,&inc.0);
+                        self.copy(from,to);
+                    }
+                },
+                InstructionC::Store(_,ref val,ref ptr,_) if is_pointer(self.module,&val.tp) => {
+                    let from = self.value(name,&val.val);
+                    if let (Some(from),Some(to)) = (from,self.value(name,&ptr.val)) {
+                        self.stores[to].push(from);
+                    }
+                },
+                InstructionC::Call(ref res,_,_,ref callee,ref args,_) => {
+                    let call = IndirectCall {
+                        args: args.iter().map(|a| self.value(name,&a.val)).collect(),
+                        result: res.as_ref().map(|r| self.local(name,r))
+                    };
+                    match *callee {
+                        Value::Constant(Constant::Global(ref f)) if self.module.functions.contains_key(f) => {
+                            match (call.result,res) {
+                                (Some(idx),Some(r)) if returns_noalias(self.module,&instr.content) => {
+                                    self.sets[idx].insert(MemoryObject::Heap(name.to_string(),r.clone()));
+                                },
+                                _ => {
+                                    self.bind(f,&call);
+                                }
+                            }
+                        },
+                        _ => if let Some(c) = self.value(name,callee) {
+                            self.calls[c].push(call);
+                        }
+                    }
+                },
+                InstructionC::Term(Terminator::Ret(Some(ref v))) => {
+                    let from = self.value(name,&v.val);
+                    let to = self.node(Node::Return(name.to_string()));
+                    self.copy(from,to);
+                },
+                _ => {}
+            }
+        }
+    }
+    // Connect a call to a callee. Returns the nodes whose sets have to
+    // be propagated again.
+    fn bind(&mut self,callee: &str,call: &IndirectCall) -> Vec<usize> {
+        let mut changed = Vec::new();
+        let module = self.module;
+        let fun = match module.functions.get(callee) {
+            Some(f) => f,
+            None => return changed
+        };
+        if !fun.is_defined() {
+            if let Some(res) = call.result {
+                let tp = fun.return_type.as_ref().map(|r| &r.1);
+                if tp.is_some_and(|tp| is_pointer(module,tp)) && self.sets[res].insert(MemoryObject::Unknown) {
+                    changed.push(res);
+                }
+            }
+            return changed
+        }
+        for (n,arg) in call.args.iter().take(fun.arguments.len()).enumerate() {
+            if let Some(arg) = *arg {
+                let formal = self.node(Node::Value(callee.to_string(),Value::Argument(n)));
+                if self.copies[arg].insert(formal) {
+                    changed.push(arg);
+                }
+            }
+        }
+        if let Some(res) = call.result {
+            let ret = self.node(Node::Return(callee.to_string()));
+            if self.copies[ret].insert(res) {
+                changed.push(ret);
+            }
+        }
+        changed
+    }
+    fn solve(&mut self) {
+        let mut work: VecDeque<usize> = (0..self.sets.len()).collect();
+        let mut queued = vec![true; self.sets.len()];
+        while let Some(n) = work.pop_front() {
+            queued[n] = false;
+            let mut changed = Vec::new();
+            let objs: Vec<MemoryObject> = self.sets[n].iter().cloned().collect();
+            for obj in objs.into_iter() {
+                if let MemoryObject::Function(ref f) = obj {
+                    for call in self.calls[n].clone().iter() {
+                        changed.extend(self.bind(f,call));
+                    }
+                }
+                if obj==MemoryObject::Unknown {
+                    for call in self.calls[n].clone().iter() {
+                        if let Some(res) = call.result {
+                            if self.sets[res].insert(MemoryObject::Unknown) {
+                                changed.push(res);
+                            }
+                        }
+                    }
+                }
+                if self.loads[n].is_empty() && self.stores[n].is_empty() {
+                    continue
+                }
+                let contents = self.node(Node::Contents(obj));
+                for dst in self.loads[n].clone().into_iter() {
+                    if self.copies[contents].insert(dst) {
+                        changed.push(contents);
+                    }
+                }
+                for src in self.stores[n].clone().into_iter() {
+                    if self.copies[src].insert(contents) {
+                        changed.push(src);
+                    }
+                }
+            }
+            for succ in self.copies[n].clone().into_iter() {
+                if succ==n {
+                    continue
+                }
+                let before = self.sets[succ].len();
+                let new: Vec<MemoryObject> = self.sets[n].iter().cloned().collect();
+                self.sets[succ].extend(new);
+                if self.sets[succ].len()!=before {
+                    changed.push(succ);
+                }
+            }
+            queued.resize(self.sets.len(),false);
+            for c in changed.into_iter() {
+                if !queued[c] {
+                    queued[c] = true;
+                    work.push_back(c);
+                }
+            }
+        }
+    }
+}
+
+// The object whose address a constant is.
+fn constant_object(m: &Module,c: &Constant) -> Option<MemoryObject> {
+    match *c {
+        Constant::Global(ref g) => if m.functions.contains_key(g) {
+            Some(MemoryObject::Function(g.clone()))
+        } else {
+            Some(MemoryObject::Global(g.clone()))
+        },
+        Constant::GEP(ref g) => constant_object(m,&g.ptr.val),
+        Constant::Cast(CastInst::Bitcast,ref c,_) => constant_object(m,&c.val),
+        _ => None
+    }
+}
+
+fn initializer_objects(m: &Module,c: &Constant,objs: &mut BTreeSet<MemoryObject>) {
+    match *c {
+        Constant::Array(ref els) => for el in els.iter() {
+            initializer_objects(m,el,objs)
+        },
+        _ => objs.extend(constant_object(m,c))
+    }
+}
+
+/// The result of the points-to analysis of a module.
+#[derive(Debug,Clone)]
+pub struct PointsTo {
+    nodes: HashMap<Node,usize>,
+    sets: Vec<BTreeSet<MemoryObject>>
+}
+
+impl PointsTo {
+    /// Analyze all functions and global initializers of a module.
+    pub fn new(m: &Module) -> PointsTo {
+        let mut cons = Constraints { module: m,
+                                     nodes: HashMap::new(),
+                                     sets: Vec::new(),
+                                     copies: Vec::new(),
+                                     loads: Vec::new(),
+                                     stores: Vec::new(),
+                                     calls: Vec::new() };
+        for (name,gv) in m.globals.iter() {
+            let mut objs = BTreeSet::new();
+            match gv.initialization {
+                Some(ref c) => initializer_objects(m,c,&mut objs),
+                None => {
+                    objs.insert(MemoryObject::Unknown);
+                }
+            }
+            let idx = cons.node(Node::Contents(MemoryObject::Global(name.clone())));
+            cons.sets[idx].extend(objs);
+        }
+        for fun in m.functions.values() {
+            cons.function(fun);
+        }
+        cons.solve();
+        PointsTo { nodes: cons.nodes, sets: cons.sets }
+    }
+    /// The objects a pointer of a function may point to. The set is
+    /// empty for values that are not pointers.
+    pub fn points_to(&self,fun: &Function,v: &Value) -> BTreeSet<MemoryObject> {
+        let node = match *v {
+            Value::Local(_) | Value::Argument(_) => Node::Value(fun.name.clone(),v.clone()),
+            Value::Constant(ref c) => Node::Constant(c.clone()),
+            Value::Metadata(_) => return BTreeSet::new()
+        };
+        match self.nodes.get(&node) {
+            Some(idx) => self.sets[*idx].clone(),
+            None => match *v {
+                Value::Constant(ref c) => self.constant_objects(c),
+                _ => BTreeSet::new()
+            }
+        }
+    }
+    fn constant_objects(&self,c: &Constant) -> BTreeSet<MemoryObject> {
+        let obj = match *c {
+            Constant::Global(ref g) if self.nodes.contains_key(&Node::Return(g.clone())) =>
+                MemoryObject::Function(g.clone()),
+            Constant::Global(ref g) => MemoryObject::Global(g.clone()),
+            Constant::GEP(ref g) => return self.constant_objects(&g.ptr.val),
+            Constant::Cast(CastInst::Bitcast,ref c,_) => return self.constant_objects(&c.val),
+            _ => return BTreeSet::new()
+        };
+        vec![obj].into_iter().collect()
+    }
+    /// The pointers that may be stored in an object.
+    pub fn contents(&self,obj: &MemoryObject) -> BTreeSet<MemoryObject> {
+        if *obj==MemoryObject::Unknown {
+            return vec![MemoryObject::Unknown].into_iter().collect()
+        }
+        match self.nodes.get(&Node::Contents(obj.clone())) {
+            Some(idx) => self.sets[*idx].clone(),
+            None => BTreeSet::new()
+        }
+    }
+    /// Whether two pointers of a function may point to the same object.
+    pub fn may_alias(&self,fun: &Function,a: &Value,b: &Value) -> bool {
+        let pa = self.points_to(fun,a);
+        let pb = self.points_to(fun,b);
+        (pa.contains(&MemoryObject::Unknown) && !pb.is_empty()) ||
+            (pb.contains(&MemoryObject::Unknown) && !pa.is_empty()) ||
+            pa.intersection(&pb).next().is_some()
+    }
+    /// The possible targets of a call in a function: the functions the
+    /// callee may point to, and `Unknown` if it may be something else.
+    pub fn call_targets(&self,fun: &Function,callee: &Value) -> Vec<MemoryObject> {
+        self.points_to(fun,callee).into_iter()
+            .filter(|obj| matches!(*obj,MemoryObject::Function(_) | MemoryObject::Unknown))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+fn local(name: &str) -> Value {
+    Value::Local(name.to_string())
+}
+
+#[test]
+fn test_alias_basic() {
+    let m = parse_test_module(b"@g = global i32 0, align 4
+@h = global i32 0, align 4
+
+define void @f(i32* noalias %p, i32* %q, i32* %r, i64 %i) {
+entry:
+  %a = alloca [4 x i32], align 4
+  %b = alloca i32, align 4
+  %a0 = getelementptr inbounds [4 x i32]* %a, i64 0, i64 0
+  %a1 = getelementptr inbounds [4 x i32]* %a, i64 0, i64 1
+  %a1b = getelementptr inbounds [4 x i32]* %a, i64 0, i64 1
+  %ai = getelementptr inbounds [4 x i32]* %a, i64 0, i64 %i
+  %a8 = bitcast [4 x i32]* %a to i64*
+  %q1 = getelementptr inbounds i32* %q, i64 1
+  ret void
+}");
+    let fun = &m.functions["f"];
+    let aa = AliasAnalysis::new(&m,fun);
+    let g = Value::Constant(Constant::Global("g".to_string()));
+    let h = Value::Constant(Constant::Global("h".to_string()));
+    let null = Value::Constant(Constant::NullPtr);
+    assert_eq!(aa.access_size(&local("a8")),Some(8));
+    assert_eq!(aa.alias(&local("a0"),&local("a1")),AliasResult::NoAlias);
+    assert_eq!(aa.alias(&local("a1"),&local("a1b")),AliasResult::MustAlias);
+    assert_eq!(aa.alias(&local("a0"),&local("a")),AliasResult::MustAlias);
+    assert_eq!(aa.alias(&local("a0"),&local("ai")),AliasResult::MayAlias);
+    assert_eq!(aa.alias(&local("a8"),&local("a1")),AliasResult::PartialAlias);
+    assert_eq!(aa.alias_sized(&local("a0"),None,&local("a1"),Some(4)),AliasResult::MayAlias);
+    assert_eq!(aa.alias(&local("a0"),&local("b")),AliasResult::NoAlias);
+    assert_eq!(aa.alias(&local("b"),&Value::Argument(1)),AliasResult::NoAlias);
+    assert_eq!(aa.alias(&Value::Argument(0),&Value::Argument(1)),AliasResult::NoAlias);
+    assert_eq!(aa.alias(&Value::Argument(1),&Value::Argument(2)),AliasResult::MayAlias);
+    assert_eq!(aa.alias(&Value::Argument(1),&local("q1")),AliasResult::NoAlias);
+    assert_eq!(aa.alias(&Value::Argument(1),&null),AliasResult::NoAlias);
+    assert_eq!(aa.alias(&g,&h),AliasResult::NoAlias);
+    assert_eq!(aa.alias(&g,&local("b")),AliasResult::NoAlias);
+    assert_eq!(aa.alias(&g,&Value::Argument(1)),AliasResult::MayAlias);
+    assert_eq!(aa.alias(&g,&Value::Argument(0)),AliasResult::NoAlias);
+}
+
+#[test]
+fn test_points_to() {
+    let m = parse_test_module(b"@fp = internal global void (i32**, i32*)* @store_to, align 8
+
+declare noalias i8* @malloc(i64)
+
+define internal i32* @id(i32* %p) {
+entry:
+  ret i32* %p
+}
+
+define internal void @store_to(i32** %pp, i32* %v) {
+entry:
+  store i32* %v, i32** %pp, align 8
+  ret void
+}
+
+define i32 @main() {
+entry:
+  %x = alloca i32, align 4
+  %y = alloca i32, align 4
+  %slot = alloca i32*, align 8
+  %m = call i8* @malloc(i64 4)
+  %h = bitcast i8* %m to i32*
+  %px = call i32* @id(i32* %x)
+  %ph = call i32* @id(i32* %h)
+  %f = load void (i32**, i32*)** @fp, align 8
+  call void %f(i32** %slot, i32* %y)
+  %s = load i32** %slot, align 8
+  ret i32 0
+}");
+    let pt = PointsTo::new(&m);
+    let main = &m.functions["main"];
+    let set = |objs: Vec<MemoryObject>| objs.into_iter().collect::<BTreeSet<_>>();
+    let x = MemoryObject::Alloca("main".to_string(),"x".to_string());
+    let y = MemoryObject::Alloca("main".to_string(),"y".to_string());
+    let heap = MemoryObject::Heap("main".to_string(),"m".to_string());
+    let store_to = MemoryObject::Function("store_to".to_string());
+    assert_eq!(pt.points_to(main,&local("h")),set(vec![heap.clone()]));
+    assert_eq!(pt.points_to(main,&local("px")),set(vec![x.clone(),heap.clone()]));
+    assert_eq!(pt.contents(&MemoryObject::Global("fp".to_string())),set(vec![store_to.clone()]));
+    assert_eq!(pt.call_targets(main,&local("f")),vec![store_to]);
+    assert_eq!(pt.contents(&MemoryObject::Alloca("main".to_string(),"slot".to_string())),set(vec![y.clone()]));
+    assert_eq!(pt.points_to(main,&local("s")),set(vec![y]));
+    assert!(pt.may_alias(main,&local("px"),&local("h")));
+    assert!(!pt.may_alias(main,&local("s"),&local("x")));
+    let mut aa = AliasAnalysis::new(&m,main);
+    assert_eq!(aa.alias(&local("s"),&local("x")),AliasResult::MayAlias);
+    assert_eq!(aa.alias(&local("h"),&local("x")),AliasResult::NoAlias);
+    aa.set_points_to(&pt);
+    assert_eq!(aa.alias(&local("s"),&local("x")),AliasResult::NoAlias);
+    assert_eq!(aa.alias(&local("s"),&local("y")),AliasResult::MayAlias);
+}
+
+#[test]
+fn test_alias_minisat() {
+    let m = minisat();
+    let fun = &m.functions["solver_setnvars"];
+    let aa = AliasAnalysis::new(&m,fun);
+    // Fields of the solver struct pointed to by the argument
+    assert_eq!(aa.alias(&local("cap"),&local("cap1")),AliasResult::MustAlias);
+    assert_eq!(aa.alias(&local("cap"),&local("wlists")),AliasResult::NoAlias);
+    assert_eq!(aa.alias(&local("wlists"),&local("activity")),AliasResult::NoAlias);
+    assert_eq!(aa.alias(&local("cap"),&Value::Argument(0)),AliasResult::PartialAlias);
+    let pt = PointsTo::new(&m);
+    // The solver is allocated by solver_new, but the function is
+    // external and may also be called from outside the module
+    let s = pt.points_to(fun,&Value::Argument(0));
+    assert!(s.contains(&MemoryObject::Heap("solver_new".to_string(),"call".to_string())),"{:?}",s);
+    assert!(s.contains(&MemoryObject::Unknown));
+    // The watch lists come from realloc, which is not noalias
+    assert!(pt.points_to(fun,&local("tmp1")).contains(&MemoryObject::Unknown));
+}
@@ -0,0 +1,81 @@
+//! Reports the structural differences between two modules, textual or
+//! bitcode, ignoring the numbering of locals and blocks. Exits with 1 if
+//! the modules differ.
+//!
+//!     cargo run --bin ir-diff -- OLD NEW
+extern crate llvm_ir;
+
+use llvm_ir::diff::{diff_modules,BlockDiff,Difference,InstrDiff};
+use llvm_ir::printer::InstructionDisplay;
+use llvm_ir::{bitcode,BasicBlock,Function,Module};
+use std::env;
+use std::process;
+
+fn load(path: &str) -> Module {
+    let res = if path.ends_with(".bc") {
+        bitcode::parse_file(path).ok()
+    } else {
+        llvm_ir::parse_module(path)
+    };
+    let mut m = res.unwrap_or_else(|| {
+        eprintln!("cannot read module {}",path);
+        process::exit(2)
+    });
+    if let Err(e) = m.materialize() {
+        eprintln!("cannot read module {}: {}",path,e);
+        process::exit(2)
+    }
+    m
+}
+
+fn instruction(m: &Module,f: &Function,blk: &BasicBlock,i: usize) -> String {
+    InstructionDisplay { module: m, function: f, instruction: &blk.instrs[i].content }.to_string()
+}
+
+fn print_body(old: &Module,new: &Module,name: &str,blks: &[BlockDiff]) {
+    let (f1,f2) = (&old.functions[name],&new.functions[name]);
+    let (b1,b2) = match (&f1.body,&f2.body) {
+        (Some(b1),Some(b2)) => (b1,b2),
+        _ => return
+    };
+    for blk in blks {
+        match *blk {
+            BlockDiff::Removed(i) => println!("  - block %{}",b1[i].name),
+            BlockDiff::Added(j) => println!("  + block %{}",b2[j].name),
+            BlockDiff::Changed(i,j,ref instrs) => {
+                println!("  block %{} / %{}:",b1[i].name,b2[j].name);
+                for instr in instrs {
+                    match *instr {
+                        InstrDiff::Removed(k) => println!("  -   {}",instruction(old,f1,&b1[i],k)),
+                        InstrDiff::Added(l) => println!("  +   {}",instruction(new,f2,&b2[j],l))
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len()!=2 {
+        eprintln!("usage: ir-diff OLD NEW");
+        process::exit(2)
+    }
+    let old = load(&args[0]);
+    let new = load(&args[1]);
+    let diffs = diff_modules(&old,&new);
+    for d in &diffs {
+        println!("{}",d);
+        match *d {
+            Difference::TypeChanged(ref n) => {
+                println!("  - {}",old.types[n]);
+                println!("  + {}",new.types[n]);
+            },
+            Difference::BodyChanged(ref n,ref blks) => print_body(&old,&new,n,blks),
+            _ => {}
+        }
+    }
+    if !diffs.is_empty() {
+        process::exit(1)
+    }
+}
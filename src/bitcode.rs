@@ -0,0 +1,3731 @@
+//! Reading of LLVM bitcode files into the `Module` AST, and writing of
+//! modules as bitcode.
+//!
+//! The module, type, constants, function, metadata and symbol table
+//! blocks are decoded, the other blocks are skipped. Names of unnamed
+//! values, blocks and globals are numbered like `llvm-dis` does, so
+//! that a module read from bitcode looks like the textual one.
+//!
+//! Like the rest of the AST, the reader works with typed pointers;
+//! bitcode with opaque pointers is rejected. Information the AST has
+//! no room for, such as comdats, type attributes or metadata attached
+//! to functions and globals, is dropped. Constructs the AST cannot
+//! represent at all, like floating point constants or `invoke`,
+//! result in `BitcodeError::Unsupported`.
+//!
+//! Debug info nodes are read into `Metadata::Node` with the fields of
+//! their LLVM 14 records. Only the common kinds (files, compile units,
+//! subprograms, scopes, types, variables, labels and expressions) are
+//! supported, others such as `DIEnumerator` or `DINamespace` and
+//! records in older layouts result in `BitcodeError::Unsupported`.
+use nom::IResult;
+use std::collections::{HashMap,HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{Read,Write};
+use num_traits::{One,ToPrimitive,Zero};
+use super::*;
+use bitstream::{self,Abbrev,AbbrevOp,BitReader,BitWriter,BitstreamError,Cursor,Entry,Record,StreamWriter};
+
+const MODULE_BLOCK_ID: u32 = 8;
+const PARAMATTR_BLOCK_ID: u32 = 9;
+const PARAMATTR_GROUP_BLOCK_ID: u32 = 10;
+const CONSTANTS_BLOCK_ID: u32 = 11;
+const FUNCTION_BLOCK_ID: u32 = 12;
+const VALUE_SYMTAB_BLOCK_ID: u32 = 14;
+const METADATA_BLOCK_ID: u32 = 15;
+const METADATA_ATTACHMENT_ID: u32 = 16;
+const TYPE_BLOCK_ID: u32 = 17;
+const METADATA_KIND_BLOCK_ID: u32 = 22;
+const STRTAB_BLOCK_ID: u32 = 23;
+
+// The parameter index of function attributes in attribute groups.
+const FUNCTION_INDEX: u64 = 0xFFFF_FFFF;
+
+// The widest integer type LLVM allows.
+const MAX_INT_WIDTH: u64 = 1 << 23;
+
+// How a field of a specialized metadata node is encoded.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum FieldKind {
+    // A metadata id plus one, zero for null.
+    Md,
+    // Like `Md`, but left out of the record if null.
+    OptMd,
+    Int,
+    Bool,
+    // An integer plus one, zero if not set.
+    OptInt,
+    Checksum,
+    // All remaining operands.
+    Elements
+}
+
+// The record layout of a kind of specialized metadata node, as LLVM 14
+// writes it. `version` are the bits of the first operand besides the
+// distinct flag, `min` is the number of operands of the shortest record
+// with this version.
+struct NodeLayout {
+    code: u32,
+    kind: &'static str,
+    version: u64,
+    min: usize,
+    fields: &'static [(&'static str,FieldKind)]
+}
+
+const NODE_LAYOUTS: [NodeLayout; 15] = [
+    NodeLayout { code: 13, kind: "DISubrange", version: 4, min: 5,
+                 fields: &[("count",FieldKind::Md),("lowerBound",FieldKind::Md),("upperBound",FieldKind::Md),
+                           ("stride",FieldKind::Md)] },
+    NodeLayout { code: 15, kind: "DIBasicType", version: 0, min: 6,
+                 fields: &[("tag",FieldKind::Int),("name",FieldKind::Md),("size",FieldKind::Int),
+                           ("align",FieldKind::Int),("encoding",FieldKind::Int),("flags",FieldKind::Int)] },
+    NodeLayout { code: 16, kind: "DIFile", version: 0, min: 3,
+                 fields: &[("filename",FieldKind::Md),("directory",FieldKind::Md),
+                           ("checksumkind",FieldKind::Checksum),("checksum",FieldKind::Md),("source",FieldKind::OptMd)] },
+    NodeLayout { code: 17, kind: "DIDerivedType", version: 0, min: 12,
+                 fields: &[("tag",FieldKind::Int),("name",FieldKind::Md),("file",FieldKind::Md),
+                           ("line",FieldKind::Int),("scope",FieldKind::Md),("baseType",FieldKind::Md),
+                           ("size",FieldKind::Int),("align",FieldKind::Int),("offset",FieldKind::Int),
+                           ("flags",FieldKind::Int),("extraData",FieldKind::Md),
+                           ("dwarfAddressSpace",FieldKind::OptInt),("annotations",FieldKind::Md)] },
+    NodeLayout { code: 18, kind: "DICompositeType", version: 2, min: 16,
+                 fields: &[("tag",FieldKind::Int),("name",FieldKind::Md),("file",FieldKind::Md),
+                           ("line",FieldKind::Int),("scope",FieldKind::Md),("baseType",FieldKind::Md),
+                           ("size",FieldKind::Int),("align",FieldKind::Int),("offset",FieldKind::Int),
+                           ("flags",FieldKind::Int),("elements",FieldKind::Md),("runtimeLang",FieldKind::Int),
+                           ("vtableHolder",FieldKind::Md),("templateParams",FieldKind::Md),
+                           ("identifier",FieldKind::Md),("discriminator",FieldKind::Md),
+                           ("dataLocation",FieldKind::Md),("associated",FieldKind::Md),
+                           ("allocated",FieldKind::Md),("rank",FieldKind::Md),("annotations",FieldKind::Md)] },
+    NodeLayout { code: 19, kind: "DISubroutineType", version: 2, min: 3,
+                 fields: &[("flags",FieldKind::Int),("types",FieldKind::Md),("cc",FieldKind::Int)] },
+    NodeLayout { code: 20, kind: "DICompileUnit", version: 0, min: 14,
+                 fields: &[("language",FieldKind::Int),("file",FieldKind::Md),("producer",FieldKind::Md),
+                           ("isOptimized",FieldKind::Bool),("flags",FieldKind::Md),
+                           ("runtimeVersion",FieldKind::Int),("splitDebugFilename",FieldKind::Md),
+                           ("emissionKind",FieldKind::Int),("enums",FieldKind::Md),
+                           ("retainedTypes",FieldKind::Md),("subprograms",FieldKind::Md),
+                           ("globals",FieldKind::Md),("imports",FieldKind::Md),("dwoId",FieldKind::Int),
+                           ("macros",FieldKind::Md),("splitDebugInlining",FieldKind::Bool),
+                           ("debugInfoForProfiling",FieldKind::Bool),("nameTableKind",FieldKind::Int),
+                           ("rangesBaseAddress",FieldKind::Bool),("sysroot",FieldKind::Md),("sdk",FieldKind::Md)] },
+    NodeLayout { code: 21, kind: "DISubprogram", version: 6, min: 18,
+                 fields: &[("scope",FieldKind::Md),("name",FieldKind::Md),("linkageName",FieldKind::Md),
+                           ("file",FieldKind::Md),("line",FieldKind::Int),("type",FieldKind::Md),
+                           ("scopeLine",FieldKind::Int),("containingType",FieldKind::Md),
+                           ("spFlags",FieldKind::Int),("virtualIndex",FieldKind::Int),("flags",FieldKind::Int),
+                           ("unit",FieldKind::Md),("templateParams",FieldKind::Md),
+                           ("declaration",FieldKind::Md),("retainedNodes",FieldKind::Md),
+                           ("thisAdjustment",FieldKind::Int),("thrownTypes",FieldKind::Md),
+                           ("annotations",FieldKind::Md)] },
+    NodeLayout { code: 22, kind: "DILexicalBlock", version: 0, min: 5,
+                 fields: &[("scope",FieldKind::Md),("file",FieldKind::Md),("line",FieldKind::Int),
+                           ("column",FieldKind::Int)] },
+    NodeLayout { code: 23, kind: "DILexicalBlockFile", version: 0, min: 4,
+                 fields: &[("scope",FieldKind::Md),("file",FieldKind::Md),("discriminator",FieldKind::Int)] },
+    NodeLayout { code: 27, kind: "DIGlobalVariable", version: 4, min: 12,
+                 fields: &[("scope",FieldKind::Md),("name",FieldKind::Md),("linkageName",FieldKind::Md),
+                           ("file",FieldKind::Md),("line",FieldKind::Int),("type",FieldKind::Md),
+                           ("isLocal",FieldKind::Bool),("isDefinition",FieldKind::Bool),
+                           ("declaration",FieldKind::Md),("templateParams",FieldKind::Md),
+                           ("align",FieldKind::Int),("annotations",FieldKind::Md)] },
+    NodeLayout { code: 28, kind: "DILocalVariable", version: 2, min: 9,
+                 fields: &[("scope",FieldKind::Md),("name",FieldKind::Md),("file",FieldKind::Md),
+                           ("line",FieldKind::Int),("type",FieldKind::Md),("arg",FieldKind::Int),
+                           ("flags",FieldKind::Int),("align",FieldKind::Int),("annotations",FieldKind::Md)] },
+    NodeLayout { code: 29, kind: "DIExpression", version: 6, min: 1,
+                 fields: &[("elements",FieldKind::Elements)] },
+    NodeLayout { code: 37, kind: "DIGlobalVariableExpression", version: 0, min: 3,
+                 fields: &[("var",FieldKind::Md),("expr",FieldKind::Md)] },
+    NodeLayout { code: 40, kind: "DILabel", version: 0, min: 5,
+                 fields: &[("scope",FieldKind::Md),("name",FieldKind::Md),("file",FieldKind::Md),
+                           ("line",FieldKind::Int)] }
+];
+
+const CHECKSUM_KINDS: [&str; 3] = ["CSK_MD5","CSK_SHA1","CSK_SHA256"];
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum BitcodeError {
+    Io(String),
+    /// The bitstream container itself is broken.
+    Stream(BitstreamError),
+    /// The records do not describe a valid module.
+    Malformed(String),
+    /// The module uses something the AST cannot represent.
+    Unsupported(String)
+}
+
+impl fmt::Display for BitcodeError {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BitcodeError::Io(ref e) => write!(f,"cannot read bitcode: {}",e),
+            BitcodeError::Stream(ref e) => write!(f,"invalid bitstream: {}",e),
+            BitcodeError::Malformed(ref e) => write!(f,"malformed bitcode: {}",e),
+            BitcodeError::Unsupported(ref e) => write!(f,"unsupported bitcode: {}",e)
+        }
+    }
+}
+
+impl ::std::error::Error for BitcodeError {}
+
+impl From<BitstreamError> for BitcodeError {
+    fn from(err: BitstreamError) -> BitcodeError {
+        BitcodeError::Stream(err)
+    }
+}
+
+pub type BitcodeResult<T> = Result<T,BitcodeError>;
+
+fn malformed<T>(msg: &str) -> BitcodeResult<T> {
+    Err(BitcodeError::Malformed(msg.to_string()))
+}
+
+fn unsupported<T>(what: &str) -> BitcodeResult<T> {
+    Err(BitcodeError::Unsupported(what.to_string()))
+}
+
+/// Read a bitcode file. The module identifier is set to the path, as
+/// `llvm-dis` does.
+pub fn parse_file(path: &str) -> BitcodeResult<Module> {
+    let mut buf = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut buf))
+        .map_err(|e| BitcodeError::Io(e.to_string()))?;
+    let mut m = read_module(&buf)?;
+    m.id = Some(path.to_string());
+    Ok(m)
+}
+
+/// Decode the first module of a bitcode file, which may be wrapped.
+pub fn read_module(data: &[u8]) -> BitcodeResult<Module> {
+    let stream = bitstream::bitcode_stream(data)?;
+    let (strtab,names) = prescan(stream)?;
+    let mut reader = Reader::new(strtab,names);
+    let mut cur = Cursor::new(stream);
+    loop {
+        match cur.advance()? {
+            Some(Entry::SubBlock(MODULE_BLOCK_ID)) => {
+                cur.enter_block(MODULE_BLOCK_ID)?;
+                reader.read_module_block(&mut cur)?;
+                return Ok(reader.module)
+            },
+            Some(Entry::SubBlock(_)) => cur.skip_block()?,
+            Some(Entry::Record(_)) => {},
+            Some(Entry::EndBlock) | None => return malformed("no module block")
+        }
+    }
+}
+
+// Collects what has to be known before the module block is decoded:
+// the string table holding the symbol names, and for old bitcode
+// without string table, the names from the module's symbol table,
+// which comes after the function bodies.
+fn prescan(stream: &[u8]) -> BitcodeResult<(&[u8],HashMap<u64,String>)> {
+    let mut cur = Cursor::new(stream);
+    let mut strtab = &[][..];
+    let mut names = HashMap::new();
+    let mut seen_module = false;
+    while let Some(entry) = cur.advance()? {
+        match entry {
+            Entry::SubBlock(STRTAB_BLOCK_ID) if strtab.is_empty() => {
+                cur.enter_block(STRTAB_BLOCK_ID)?;
+                for r in cur.records()? {
+                    if let (1,Some(blob)) = (r.code,r.blob) {
+                        strtab = blob;
+                    }
+                }
+            },
+            Entry::SubBlock(MODULE_BLOCK_ID) if !seen_module => {
+                seen_module = true;
+                cur.enter_block(MODULE_BLOCK_ID)?;
+                loop {
+                    match cur.advance()? {
+                        Some(Entry::SubBlock(VALUE_SYMTAB_BLOCK_ID)) => {
+                            cur.enter_block(VALUE_SYMTAB_BLOCK_ID)?;
+                            for r in cur.records()? {
+                                match r.code {
+                                    1 if !r.ops.is_empty() => { names.insert(r.ops[0],r.string(1)); },
+                                    3 if r.ops.len() > 1 => { names.insert(r.ops[0],r.string(2)); },
+                                    _ => {}
+                                }
+                            }
+                        },
+                        Some(Entry::SubBlock(_)) => cur.skip_block()?,
+                        Some(Entry::Record(_)) => {},
+                        Some(Entry::EndBlock) => break,
+                        None => return Err(BitcodeError::Stream(BitstreamError::UnexpectedEof))
+                    }
+                }
+            },
+            Entry::SubBlock(_) => cur.skip_block()?,
+            _ => {}
+        }
+    }
+    Ok((strtab,names))
+}
+
+fn op(r: &Record,i: usize) -> BitcodeResult<u64> {
+    match r.ops.get(i) {
+        Some(&v) => Ok(v),
+        None => Err(BitcodeError::Malformed(format!("record {} has too few operands",r.code)))
+    }
+}
+
+fn decode_signed(v: u64) -> i64 {
+    if v & 1 == 0 {
+        (v >> 1) as i64
+    } else if v != 1 {
+        -((v >> 1) as i64)
+    } else {
+        i64::MIN
+    }
+}
+
+fn decode_alignment(v: u64) -> Option<Alignment> {
+    if v==0 || v > 64 { None } else { Some(1 << (v-1)) }
+}
+
+fn decode_linkage(v: u64) -> BitcodeResult<Linkage> {
+    Ok(match v {
+        0 | 5 | 6 => Linkage::External,
+        1 | 16 => Linkage::Weak,
+        2 => Linkage::Appending,
+        3 => Linkage::Internal,
+        4 | 18 => Linkage::LinkOnce,
+        7 => Linkage::ExternWeak,
+        8 => Linkage::Common,
+        9 | 13 | 14 => Linkage::Private,
+        10 | 17 => Linkage::WeakODR,
+        11 | 15 | 19 => Linkage::LinkOnceODR,
+        12 => Linkage::AvailableExternally,
+        _ => return malformed("unknown linkage")
+    })
+}
+
+fn decode_visibility(v: u64) -> Visibility {
+    match v {
+        1 => Visibility::Hidden,
+        2 => Visibility::Protected,
+        _ => Visibility::Default
+    }
+}
+
+fn decode_dll_storage_class(v: u64) -> DLLStorageClass {
+    match v {
+        1 => DLLStorageClass::DLLImport,
+        2 => DLLStorageClass::DLLExport,
+        _ => DLLStorageClass::Default
+    }
+}
+
+fn decode_calling_conv(v: u64) -> CallingConv {
+    match v {
+        0 => CallingConv::C,
+        8 => CallingConv::Fast,
+        9 => CallingConv::Cold,
+        12 => CallingConv::WebKitJS,
+        13 => CallingConv::AnyReg,
+        14 => CallingConv::PreserveMost,
+        15 => CallingConv::PreserveAll,
+        16 => CallingConv::Swift,
+        17 => CallingConv::CxxFastTLS,
+        n => CallingConv::Numbered(n)
+    }
+}
+
+fn decode_cmp_op(v: u64) -> BitcodeResult<CmpOp> {
+    Ok(match v {
+        32 => CmpOp::Eq,
+        33 => CmpOp::Ne,
+        34 => CmpOp::UGt,
+        35 => CmpOp::UGe,
+        36 => CmpOp::ULt,
+        37 => CmpOp::ULe,
+        38 => CmpOp::SGt,
+        39 => CmpOp::SGe,
+        40 => CmpOp::SLt,
+        41 => CmpOp::SLe,
+        _ if v < 16 => return unsupported("fcmp"),
+        _ => return malformed("unknown comparison predicate")
+    })
+}
+
+fn decode_cast(v: u64) -> BitcodeResult<CastInst> {
+    Ok(match v {
+        0 => CastInst::Trunc,
+        1 => CastInst::ZExt,
+        2 => CastInst::SExt,
+        9 => CastInst::PtrToInt,
+        10 => CastInst::IntToPtr,
+        11 => CastInst::Bitcast,
+        3..=8 => return unsupported("floating point cast"),
+        _ => return unsupported("address space cast")
+    })
+}
+
+fn decode_binop(v: u64,flags: u64) -> BitcodeResult<BinOp> {
+    let (nuw,nsw,exact) = (flags & 1 != 0,flags & 2 != 0,flags & 1 != 0);
+    Ok(match v {
+        0 => BinOp::Add(nuw,nsw),
+        1 => BinOp::Sub(nuw,nsw),
+        2 => BinOp::Mul(nuw,nsw),
+        4 => BinOp::SDiv(exact),
+        7 => BinOp::Shl,
+        8 => BinOp::LShr,
+        9 => BinOp::AShr,
+        10 => BinOp::And,
+        11 => BinOp::Or,
+        12 => BinOp::XOr,
+        3 => return unsupported("udiv"),
+        5 => return unsupported("urem"),
+        6 => return unsupported("srem"),
+        _ => return malformed("unknown binary operator")
+    })
+}
+
+// The names of the enum and integer attribute kinds, by kind - 1.
+const ATTRIBUTE_NAMES: [&str; 78] =
+    ["align","alwaysinline","byval","inlinehint","inreg","minsize",
+     "naked","nest","noalias","nobuiltin","nocapture","noduplicate",
+     "noimplicitfloat","noinline","nonlazybind","noredzone","noreturn",
+     "nounwind","optsize","readnone","readonly","returned",
+     "returns_twice","signext","alignstack","ssp","sspreq","sspstrong",
+     "sret","sanitize_address","sanitize_thread","sanitize_memory",
+     "uwtable","zeroext","builtin","cold","optnone","inalloca",
+     "nonnull","jumptable","dereferenceable","dereferenceable_or_null",
+     "convergent","safestack","argmemonly","swiftself","swifterror",
+     "norecurse","inaccessiblememonly","inaccessiblemem_or_argmemonly",
+     "allocsize","writeonly","speculatable","strictfp",
+     "sanitize_hwaddress","nocf_check","optforfuzzing","shadowcallstack",
+     "speculative_load_hardening","immarg","willreturn","nofree",
+     "nosync","sanitize_memtag","preallocated","nomerge",
+     "null_pointer_is_valid","noundef","byref","mustprogress",
+     "nocallback","hot","noprofile","vscale_range","swiftasync",
+     "nosanitize_coverage","elementtype",
+     "disable_sanitizer_instrumentation"];
+
+/// The name of an enum or integer attribute kind.
+fn attribute_name(kind: u64) -> Option<&'static str> {
+    if kind==0 {
+        None
+    } else {
+        ATTRIBUTE_NAMES.get(kind as usize - 1).cloned()
+    }
+}
+
+fn par_attrs(attrs: &[Attribute]) -> ParAttrs {
+    let mut res = ParAttrs::new();
+    for attr in attrs {
+        let value = attr.value.as_ref().and_then(|v| v.parse().ok());
+        match &attr.name[..] {
+            _ if attr.quoted => {},
+            "zeroext" => res.zeroext = true,
+            "signext" => res.signext = true,
+            "inreg" => res.inreg = true,
+            "byval" => res.byval = true,
+            "inalloca" => res.inalloca = true,
+            "sret" => res.sret = true,
+            "align" => res.align = value,
+            "noalias" => res.noalias = true,
+            "nocapture" => res.nocapture = true,
+            "nest" => res.nest = true,
+            "returned" => res.returned = true,
+            "nonnull" => res.nonnull = true,
+            "dereferenceable" => res.dereferenceable = value,
+            "dereferenceable_or_null" => res.dereferenceable_or_null = value,
+            "swiftself" => res.swiftself = true,
+            "swifterror" => res.swifterror = true,
+            _ => {}
+        }
+    }
+    res
+}
+
+// The value of an integer constant of the given type from its
+// two's complement bits.
+fn int_constant(tp: &Type,bits: u64) -> Constant {
+    let width = match *tp {
+        Type::Int(w) => w,
+        _ => 64
+    };
+    if width==1 {
+        return Constant::Int(BigInt::from(bits & 1))
+    }
+    if width < 64 && (bits >> (width-1)) & 1 == 1 {
+        Constant::Int(BigInt::from((bits | (!0 << width)) as i64))
+    } else {
+        Constant::Int(BigInt::from(bits as i64))
+    }
+}
+
+#[derive(Debug,Clone)]
+enum TypeEntry {
+    Void,
+    Type(Type),
+    Unsupported(&'static str)
+}
+
+// The raw type records, before references to other types are resolved.
+enum RawType {
+    Void,
+    Simple(Type),
+    Pointer(u64,u64),
+    Array(u64,u64),
+    Struct(Vec<u64>),
+    Named(String),
+    Function(bool,u64,Vec<u64>),
+    Unsupported(&'static str)
+}
+
+// The state of a constant of the constants block being decoded.
+#[derive(PartialEq,Eq,Clone,Copy)]
+enum ConstState {
+    Pending,
+    InProgress,
+    Done
+}
+
+struct ConstBlock<'r,'a: 'r> {
+    start: usize,
+    records: &'r [(Type,Record<'a>)],
+    state: Vec<ConstState>
+}
+
+fn fresh(slot: &mut u64) -> String {
+    *slot += 1;
+    (*slot-1).to_string()
+}
+
+// Names and blocks of the function being decoded.
+struct Locals {
+    /// Names of arguments and instructions by value id.
+    names: HashMap<usize,String>,
+    blocks: Vec<String>
+}
+
+struct Reader<'a> {
+    module: Module,
+    strtab: &'a [u8],
+    /// Names from the module-level symbol table, by value id.
+    vst_names: HashMap<u64,String>,
+    types: Vec<TypeEntry>,
+    /// All values that can currently be referenced, by value id.
+    values: Vec<Typed<Value>>,
+    /// Global variables and the value ids of their initializers, which
+    /// are only known after the constants block.
+    inits: Vec<(String,usize)>,
+    sections: Vec<String>,
+    /// Attribute groups by id, with their parameter index.
+    groups: HashMap<u64,(u64,Vec<Attribute>)>,
+    /// Attribute lists as lists of group ids.
+    attr_lists: Vec<Vec<u64>>,
+    /// Defined functions in the order of their bodies.
+    bodies: Vec<String>,
+    next_body: usize,
+    /// Metadata by metadata id. Nodes are references into `module.md`.
+    md: Vec<Metadata>,
+    md_kinds: HashMap<u64,String>,
+    next_md_node: u64,
+    /// Location nodes created for debug locations of instructions.
+    locations: HashMap<(u64,u64,u64,u64),u64>,
+    next_unnamed: u64,
+    /// Unnamed struct types are numbered separately from globals.
+    next_unnamed_type: u64
+}
+
+impl<'a> Reader<'a> {
+    fn new(strtab: &'a [u8],vst_names: HashMap<u64,String>) -> Reader<'a> {
+        Reader { module: Module::new(),
+                 strtab,
+                 vst_names,
+                 types: Vec::new(),
+                 values: Vec::new(),
+                 inits: Vec::new(),
+                 sections: Vec::new(),
+                 groups: HashMap::new(),
+                 attr_lists: Vec::new(),
+                 bodies: Vec::new(),
+                 next_body: 0,
+                 md: Vec::new(),
+                 md_kinds: HashMap::new(),
+                 next_md_node: 0,
+                 locations: HashMap::new(),
+                 next_unnamed: 0,
+                 next_unnamed_type: 0 }
+    }
+
+    fn read_module_block(&mut self,cur: &mut Cursor<'a>) -> BitcodeResult<()> {
+        let mut version = 0;
+        loop {
+            match cur.advance()? {
+                Some(Entry::Record(r)) => match r.code {
+                    1 => {
+                        version = op(&r,0)?;
+                        if version==0 {
+                            return unsupported("bitcode with absolute value ids")
+                        } else if version > 2 {
+                            return unsupported("bitcode version")
+                        }
+                    },
+                    2 => self.module.triple = Some(r.string(0)),
+                    3 => {
+                        let layout = r.string(0)+"\"";
+                        self.module.datalayout = match datalayout::datalayout_string(layout.as_bytes()) {
+                            IResult::Done(_,dl) => dl,
+                            _ => return malformed("invalid data layout")
+                        };
+                    },
+                    5 => self.sections.push(r.string(0)),
+                    7 => self.read_global(&r,version)?,
+                    8 => self.read_function(&r,version)?,
+                    9 | 14 => return unsupported("alias"),
+                    18 => return unsupported("ifunc"),
+                    _ => {}
+                },
+                Some(Entry::SubBlock(id)) => {
+                    match id {
+                        PARAMATTR_GROUP_BLOCK_ID => {
+                            cur.enter_block(id)?;
+                            self.read_attribute_groups(cur)?;
+                        },
+                        PARAMATTR_BLOCK_ID => {
+                            cur.enter_block(id)?;
+                            for r in cur.records()? {
+                                if r.code==2 {
+                                    self.attr_lists.push(r.ops);
+                                }
+                            }
+                        },
+                        TYPE_BLOCK_ID => {
+                            cur.enter_block(id)?;
+                            self.read_types(cur)?;
+                        },
+                        CONSTANTS_BLOCK_ID => {
+                            cur.enter_block(id)?;
+                            self.read_constants(cur)?;
+                        },
+                        METADATA_KIND_BLOCK_ID => {
+                            cur.enter_block(id)?;
+                            for r in cur.records()? {
+                                if r.code==6 {
+                                    self.md_kinds.insert(op(&r,0)?,r.string(1));
+                                }
+                            }
+                        },
+                        METADATA_BLOCK_ID => {
+                            cur.enter_block(id)?;
+                            let recs = cur.records()?;
+                            self.read_metadata(&recs,None)?;
+                        },
+                        FUNCTION_BLOCK_ID => {
+                            cur.enter_block(id)?;
+                            self.read_function_body(cur)?;
+                        },
+                        _ => cur.skip_block()?
+                    }
+                },
+                Some(Entry::EndBlock) => break,
+                None => return Err(BitcodeError::Stream(BitstreamError::UnexpectedEof))
+            }
+        }
+        for (name,id) in std::mem::take(&mut self.inits) {
+            let init = match self.values.get(id) {
+                Some(&Typed { val: Value::Constant(ref c),.. }) => c.clone(),
+                _ => return malformed("invalid initializer")
+            };
+            if let Some(g) = self.module.globals.get_mut(&name) {
+                g.initialization = Some(init);
+            }
+        }
+        Ok(())
+    }
+
+    fn type_entry(&self,id: u64) -> BitcodeResult<&TypeEntry> {
+        match self.types.get(id as usize) {
+            Some(e) => Ok(e),
+            None => malformed("unknown type id")
+        }
+    }
+
+    fn type_of(&self,id: u64) -> BitcodeResult<Type> {
+        match *self.type_entry(id)? {
+            TypeEntry::Type(ref tp) => Ok(tp.clone()),
+            TypeEntry::Void => malformed("unexpected void type"),
+            TypeEntry::Unsupported(what) => unsupported(what)
+        }
+    }
+
+    // The name of a global symbol, numbered if it is unnamed.
+    fn symbol_name(&mut self,r: &Record,version: u64) -> BitcodeResult<String> {
+        let name = if version >= 2 {
+            let (off,size) = (op(r,0)? as usize,op(r,1)? as usize);
+            match self.strtab.get(off..off+size) {
+                Some(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+                None => return malformed("symbol name outside of string table")
+            }
+        } else {
+            self.vst_names.get(&(self.values.len() as u64)).cloned().unwrap_or_default()
+        };
+        if name.is_empty() {
+            self.next_unnamed += 1;
+            Ok((self.next_unnamed-1).to_string())
+        } else {
+            Ok(name)
+        }
+    }
+
+    fn read_global(&mut self,r: &Record,version: u64) -> BitcodeResult<()> {
+        let name = self.symbol_name(r,version)?;
+        let base = if version >= 2 { 2 } else { 0 };
+        let field = |i: usize| r.ops.get(base+i).cloned().unwrap_or(0);
+        let flags = op(r,base+1)?;
+        let mut tp = self.type_of(op(r,base)?)?;
+        let addr_space = if flags & 2 != 0 {
+            flags >> 2
+        } else {
+            // Old records store the pointer type.
+            match tp {
+                Type::Pointer(el,sp) => {
+                    tp = *el;
+                    sp.unwrap_or(0)
+                },
+                _ => return malformed("global of non-pointer type")
+            }
+        };
+        let init = op(r,base+2)?;
+        let linkage = decode_linkage(op(r,base+3)?)?;
+        let section = match field(5) {
+            0 => None,
+            n => match self.sections.get(n as usize - 1) {
+                Some(s) => Some(s.clone()),
+                None => return malformed("unknown section")
+            }
+        };
+        let thread_local = match field(7) {
+            0 => None,
+            2 => Some(ThreadLocal::LocalDynamic),
+            3 => Some(ThreadLocal::InitialExec),
+            4 => Some(ThreadLocal::LocalExec),
+            _ => Some(ThreadLocal::ThreadLocal)
+        };
+        let unnamed_addr = match field(8) {
+            0 => None,
+            1 => Some(UnnamedAddr::UnnamedAddr),
+            _ => Some(UnnamedAddr::LocalUnnamedAddr)
+        };
+        if init != 0 {
+            self.inits.push((name.clone(),init as usize - 1));
+        }
+        let linkage = match linkage {
+            Linkage::External if init != 0 => None,
+            l => Some(l)
+        };
+        let addr_space = if addr_space==0 { None } else { Some(addr_space) };
+        self.values.push(Typed::new(Type::Pointer(Box::new(tp.clone()),addr_space),
+                                    Value::Constant(Constant::Global(name.clone()))));
+        self.module.globals.insert(name,GlobalVariable {
+            linkage,
+            visibility: decode_visibility(field(6)),
+            dll_storage_class: decode_dll_storage_class(field(10)),
+            thread_local,
+            unnamed_addr,
+            addr_space,
+            externally_initialized: field(9) != 0,
+            global_type: if flags & 1 != 0 { GlobalType::Constant } else { GlobalType::Global },
+            types: tp,
+            initialization: None,
+            section,
+            alignment: decode_alignment(field(4))
+        });
+        Ok(())
+    }
+
+    // The function attribute groups and the return and parameter
+    // attributes of a 1-based attribute list index.
+    fn attribute_list(&self,idx: u64,nparams: usize) -> BitcodeResult<(Vec<u64>,ParAttrs,Vec<ParAttrs>)> {
+        let mut fun = Vec::new();
+        let mut ret = Vec::new();
+        let mut params = vec![Vec::new(); nparams];
+        if idx != 0 {
+            let list = match self.attr_lists.get(idx as usize - 1) {
+                Some(l) => l,
+                None => return malformed("unknown attribute list")
+            };
+            for grp in list {
+                match self.groups.get(grp) {
+                    Some(&(FUNCTION_INDEX,_)) => fun.push(*grp),
+                    Some(&(0,ref attrs)) => ret.extend(attrs.iter().cloned()),
+                    Some(&(i,ref attrs)) => if let Some(p) = params.get_mut(i as usize - 1) {
+                        p.extend(attrs.iter().cloned());
+                    },
+                    None => return malformed("unknown attribute group")
+                }
+            }
+        }
+        Ok((fun,par_attrs(&ret),params.iter().map(|p| par_attrs(p)).collect()))
+    }
+
+    fn read_function(&mut self,r: &Record,version: u64) -> BitcodeResult<()> {
+        let name = self.symbol_name(r,version)?;
+        let base = if version >= 2 { 2 } else { 0 };
+        let field = |i: usize| r.ops.get(base+i).cloned().unwrap_or(0);
+        let fty = match self.type_of(op(r,base)?)? {
+            Type::Pointer(el,_) => *el,
+            tp => tp
+        };
+        let (ret,params,var_args) = match fty {
+            Type::Function(ref ret,ref params,va) => (ret.clone(),params.clone(),va),
+            _ => return malformed("function of non-function type")
+        };
+        let linkage = match decode_linkage(op(r,base+3)?)? {
+            Linkage::External => None,
+            l => Some(l)
+        };
+        let (groups,ret_attrs,arg_attrs) = self.attribute_list(field(4),params.len())?;
+        let defined = op(r,base+2)?==0;
+        if defined {
+            self.bodies.push(name.clone());
+        }
+        let addr_space = match field(16) {
+            0 => None,
+            n => Some(n)
+        };
+        self.values.push(Typed::new(Type::Pointer(Box::new(fty.clone()),addr_space),
+                                    Value::Constant(Constant::Global(name.clone()))));
+        self.module.functions.insert(name.clone(),Function {
+            name,
+            linkage,
+            visibility: decode_visibility(field(7)),
+            dll_storage_class: decode_dll_storage_class(field(11)),
+            cconv: decode_calling_conv(op(r,base+1)?),
+            return_type: ret.map(|tp| (ret_attrs,*tp)),
+            arguments: params.into_iter().map(|tp| (None,tp)).collect(),
+            argument_attrs: arg_attrs,
+            var_args,
+            attribute_groups: groups,
+            body: if defined { Some(Vec::new()) } else { None }
+        });
+        Ok(())
+    }
+
+    fn read_attribute_groups(&mut self,cur: &mut Cursor<'a>) -> BitcodeResult<()> {
+        for r in cur.records()? {
+            if r.code != 3 {
+                continue
+            }
+            let grp = op(&r,0)?;
+            let idx = op(&r,1)?;
+            let mut attrs = Vec::new();
+            let mut i = 2;
+            while i < r.ops.len() {
+                let kind = op(&r,i)?;
+                i += 1;
+                match kind {
+                    0 | 1 | 5 | 6 => {
+                        let code = op(&r,i)?;
+                        let name = match attribute_name(code) {
+                            Some(n) => n.to_string(),
+                            None => return Err(BitcodeError::Unsupported(format!("attribute kind {}",code)))
+                        };
+                        i += 1;
+                        let value = if kind==1 {
+                            i += 1;
+                            Some(op(&r,i-1)?.to_string())
+                        } else {
+                            if kind==6 {
+                                i += 1;
+                            }
+                            None
+                        };
+                        attrs.push(Attribute { name, quoted: false, value });
+                    },
+                    3 | 4 => {
+                        let mut strings = Vec::new();
+                        for _ in 0..kind-2 {
+                            let start = i;
+                            while op(&r,i)? != 0 {
+                                i += 1;
+                            }
+                            strings.push(String::from_utf8_lossy(&r.ops[start..i].iter().map(|&c| c as u8).collect::<Vec<u8>>()).into_owned());
+                            i += 1;
+                        }
+                        let value = if strings.len() > 1 { strings.pop() } else { None };
+                        attrs.push(Attribute { name: strings.pop().unwrap_or_default(),
+                                               quoted: true,
+                                               value });
+                    },
+                    _ => return malformed("unknown attribute encoding")
+                }
+            }
+            if idx==FUNCTION_INDEX {
+                self.module.attr_groups.insert(grp,attrs.clone());
+            }
+            self.groups.insert(grp,(idx,attrs));
+        }
+        Ok(())
+    }
+
+    fn read_types(&mut self,cur: &mut Cursor<'a>) -> BitcodeResult<()> {
+        let mut raw = Vec::new();
+        let mut bodies = Vec::new();
+        let mut name = None;
+        for r in cur.records()? {
+            let entry = match r.code {
+                1 => continue,
+                2 => RawType::Void,
+                3 => RawType::Simple(Type::Float),
+                4 => RawType::Simple(Type::Double),
+                5 => RawType::Simple(Type::Label),
+                6 | 20 => {
+                    let n = match name.take() {
+                        Some(n) => n,
+                        None => {
+                            self.next_unnamed_type += 1;
+                            (self.next_unnamed_type-1).to_string()
+                        }
+                    };
+                    if r.code==20 {
+                        if op(&r,0)? != 0 {
+                            return unsupported("packed struct")
+                        }
+                        bodies.push((n.clone(),r.ops[1..].to_vec()));
+                    } else {
+                        self.module.types.insert(n.clone(),Type::Opaque);
+                    }
+                    RawType::Named(n)
+                },
+                7 => {
+                    let width = op(&r,0)?;
+                    if width==0 || width > MAX_INT_WIDTH {
+                        return malformed("invalid integer width")
+                    }
+                    RawType::Simple(Type::Int(width))
+                },
+                8 => RawType::Pointer(op(&r,0)?,r.ops.get(1).cloned().unwrap_or(0)),
+                9 => RawType::Function(op(&r,0)? != 0,op(&r,2)?,r.ops[3..].to_vec()),
+                11 => RawType::Array(op(&r,0)?,op(&r,1)?),
+                13 => RawType::Simple(Type::X86_FP80),
+                14 => RawType::Simple(Type::FP128),
+                15 => RawType::Simple(Type::PPC_FP128),
+                16 => RawType::Simple(Type::Metadata),
+                18 => {
+                    if op(&r,0)? != 0 {
+                        return unsupported("packed struct")
+                    }
+                    RawType::Struct(r.ops[1..].to_vec())
+                },
+                19 => {
+                    name = Some(r.string(0));
+                    continue
+                },
+                21 => RawType::Function(op(&r,0)? != 0,op(&r,1)?,r.ops[2..].to_vec()),
+                10 => RawType::Unsupported("half type"),
+                12 => RawType::Unsupported("vector type"),
+                17 => RawType::Unsupported("x86_mmx type"),
+                22 => RawType::Unsupported("token type"),
+                23 => RawType::Unsupported("bfloat type"),
+                24 => RawType::Unsupported("x86_amx type"),
+                25 => RawType::Unsupported("opaque pointer"),
+                _ => return malformed("unknown type record")
+            };
+            raw.push(entry);
+        }
+        let mut resolved = vec![None; raw.len()];
+        for i in 0..raw.len() {
+            resolve_type(&raw,&mut resolved,i as u64,0)?;
+        }
+        self.types = match resolved.into_iter().collect() {
+            Some(tps) => tps,
+            None => return malformed("unresolved type")
+        };
+        let mut names = Vec::with_capacity(bodies.len());
+        for (n,elems) in bodies {
+            let mut tps = Vec::with_capacity(elems.len());
+            for e in elems {
+                tps.push(self.type_of(e)?);
+            }
+            self.module.types.insert(n.clone(),Type::Struct(tps));
+            names.push(n);
+        }
+        for n in names {
+            if contains_struct(&self.module.types,&n,&self.module.types[&n],&mut HashSet::new()) {
+                return Err(BitcodeError::Malformed(format!("struct %{} contains itself",n)))
+            }
+        }
+        Ok(())
+    }
+
+    fn null_value(&self,tp: &Type) -> BitcodeResult<Constant> {
+        match *tp {
+            Type::Int(_) => Ok(Constant::Int(BigInt::from(0))),
+            Type::Pointer(..) => Ok(Constant::NullPtr),
+            Type::Array(n,ref el) => Ok(Constant::Array(vec![self.null_value(el)?; n as usize])),
+            Type::Struct(ref els) => {
+                let mut res = Vec::with_capacity(els.len());
+                for el in els {
+                    res.push(self.null_value(el)?);
+                }
+                Ok(Constant::Array(res))
+            },
+            Type::Named(ref n) => match self.module.types.get(n) {
+                Some(tp @ &Type::Struct(_)) => self.null_value(tp),
+                _ => malformed("null value of opaque type")
+            },
+            _ => Err(BitcodeError::Unsupported(format!("null value of type {:?}",tp)))
+        }
+    }
+
+    fn read_constants(&mut self,cur: &mut Cursor<'a>) -> BitcodeResult<()> {
+        let mut records = Vec::new();
+        let mut tp = None;
+        for r in cur.records()? {
+            if r.code==1 {
+                tp = Some(self.type_of(op(&r,0)?)?);
+                continue
+            }
+            match tp {
+                Some(ref tp) => records.push((tp.clone(),r)),
+                None => return malformed("constant without type")
+            }
+        }
+        let mut block = ConstBlock { start: self.values.len(),
+                                     records: &records,
+                                     state: vec![ConstState::Pending; records.len()] };
+        for (tp,_) in &records {
+            self.values.push(Typed::new(tp.clone(),Value::Constant(Constant::Undef)));
+        }
+        for i in 0..records.len() {
+            self.resolve_constant(&mut block,i)?;
+        }
+        Ok(())
+    }
+
+    fn constant_operand(&mut self,block: &mut ConstBlock,id: u64) -> BitcodeResult<Constant> {
+        let id = id as usize;
+        if id >= block.start && id < block.start+block.records.len() {
+            self.resolve_constant(block,id-block.start)?;
+        }
+        match self.values.get(id) {
+            Some(&Typed { val: Value::Constant(ref c),.. }) => Ok(c.clone()),
+            Some(_) => malformed("constant refers to a non-constant"),
+            None => malformed("unknown value id in constant")
+        }
+    }
+
+    fn resolve_constant(&mut self,block: &mut ConstBlock,i: usize) -> BitcodeResult<()> {
+        match block.state[i] {
+            ConstState::Done => return Ok(()),
+            ConstState::InProgress => return malformed("cyclic constant"),
+            ConstState::Pending => {}
+        }
+        block.state[i] = ConstState::InProgress;
+        let records = block.records;
+        let (ref tp,ref r) = records[i];
+        let c = match r.code {
+            2 => self.null_value(tp)?,
+            3 | 26 => Constant::Undef,
+            4 => int_constant(tp,decode_signed(op(r,0)?) as u64),
+            5 => {
+                let width = match *tp {
+                    Type::Int(w) => w,
+                    _ => return malformed("wide integer of non-integer type")
+                };
+                if r.ops.len() as u64 > width.div_ceil(64) {
+                    return malformed("wide integer with too many words")
+                }
+                let mut val = BigInt::from(0);
+                for (i,&w) in r.ops.iter().enumerate() {
+                    val = val + (BigInt::from(decode_signed(w) as u64) << (64*i));
+                }
+                if val >= BigInt::one() << (width as usize - 1) {
+                    val = val - (BigInt::one() << width as usize);
+                }
+                Constant::Int(val)
+            },
+            6 => return unsupported("floating point constant"),
+            7 => {
+                let mut elems = Vec::with_capacity(r.ops.len());
+                for &id in &r.ops {
+                    elems.push(self.constant_operand(block,id)?);
+                }
+                Constant::Array(elems)
+            },
+            8 | 9 => {
+                let mut elems: Vec<Constant> = r.ops.iter()
+                    .map(|&c| Constant::Int(BigInt::from(c & 0xFF)))
+                    .collect();
+                if r.code==9 {
+                    elems.push(Constant::Int(BigInt::from(0)));
+                }
+                Constant::Array(elems)
+            },
+            22 => {
+                let el = match *tp {
+                    Type::Array(_,ref el) => (**el).clone(),
+                    _ => return unsupported("vector constant")
+                };
+                if !matches!(el,Type::Int(_)) {
+                    return unsupported("floating point constant")
+                }
+                Constant::Array(r.ops.iter().map(|&v| int_constant(&el,v)).collect())
+            },
+            11 => {
+                let cast = decode_cast(op(r,0)?)?;
+                let src = self.type_of(op(r,1)?)?;
+                let val = self.constant_operand(block,op(r,2)?)?;
+                Constant::Cast(cast,Box::new(Typed::new(src,val)),tp.clone())
+            },
+            12 | 20 | 24 => {
+                let mut pos = 0;
+                if r.code==24 || r.ops.len() % 2 == 1 {
+                    pos += 1;
+                }
+                let (inbounds,inrange) = if r.code==24 {
+                    pos += 1;
+                    let flags = op(r,1)?;
+                    (flags & 1 != 0,Some(flags >> 1))
+                } else {
+                    (r.code==20,None)
+                };
+                let mut ops = Vec::new();
+                while pos+1 < r.ops.len() {
+                    let tp = self.type_of(op(r,pos)?)?;
+                    let val = self.constant_operand(block,op(r,pos+1)?)?;
+                    ops.push(Typed::new(tp,val));
+                    pos += 2;
+                }
+                if ops.is_empty() {
+                    return malformed("constant getelementptr without pointer")
+                }
+                let ptr = ops.remove(0);
+                Constant::GEP(Box::new(GEP { ptr,
+                                             inbounds,
+                                             indices: ops.into_iter().enumerate()
+                                             .map(|(i,idx)| (idx,inrange==Some(i as u64)))
+                                             .collect() }))
+            },
+            10 => return unsupported("binary constant expression"),
+            13 => return unsupported("select constant expression"),
+            17 => return unsupported("compare constant expression"),
+            21 => return unsupported("blockaddress"),
+            _ => return Err(BitcodeError::Unsupported(format!("constant record {}",r.code)))
+        };
+        self.values[block.start+i].val = Value::Constant(c);
+        block.state[i] = ConstState::Done;
+        Ok(())
+    }
+
+    fn md_ref(&self,id: u64) -> BitcodeResult<Metadata> {
+        match self.md.get(id as usize) {
+            Some(md) => Ok(md.clone()),
+            None => malformed("unknown metadata id")
+        }
+    }
+
+    fn md_ref_opt(&self,id: u64) -> BitcodeResult<Metadata> {
+        if id==0 { Ok(Metadata::Null) } else { self.md_ref(id-1) }
+    }
+
+    // Decodes a specialized node, leaving out fields that are null or
+    // not set.
+    fn read_node(&self,r: &Record) -> BitcodeResult<Metadata> {
+        let layout = match NODE_LAYOUTS.iter().find(|l| l.code==r.code) {
+            Some(l) => l,
+            None => return Err(BitcodeError::Unsupported(format!("metadata record {}",r.code)))
+        };
+        let first = op(r,0)?;
+        if first & !1!=layout.version {
+            return Err(BitcodeError::Unsupported(format!("{} record version {}",layout.kind,first >> 1)))
+        }
+        let rest = matches!(layout.fields.last(),Some(&(_,FieldKind::Elements)));
+        if r.ops.len()<layout.min || (!rest && r.ops.len()>layout.fields.len()+1) {
+            return Err(BitcodeError::Malformed(format!("invalid {} record",layout.kind)))
+        }
+        let mut fields = Vec::new();
+        for (&(name,kind),&v) in layout.fields.iter().zip(&r.ops[1..]) {
+            let field = match kind {
+                FieldKind::Md | FieldKind::OptMd => match self.md_ref_opt(v)? {
+                    Metadata::Null => continue,
+                    md => MetadataField::Metadata(md)
+                },
+                FieldKind::Int => MetadataField::Int(v),
+                FieldKind::Bool => MetadataField::Bool(v!=0),
+                FieldKind::OptInt if v==0 => continue,
+                FieldKind::OptInt => MetadataField::Int(v-1),
+                FieldKind::Checksum if v==0 => continue,
+                FieldKind::Checksum => match CHECKSUM_KINDS.get(v as usize-1) {
+                    Some(c) => MetadataField::Constant(c.to_string()),
+                    None => return malformed("unknown checksum kind")
+                },
+                FieldKind::Elements => MetadataField::Elements(r.ops[1..].to_vec())
+            };
+            fields.push((name.to_string(),field));
+        }
+        Ok(Metadata::Node(layout.kind.to_string(),first & 1!=0,fields))
+    }
+
+    // Decodes the records of a metadata block. Nodes are numbered in
+    // order and stored in `module.md`.
+    fn read_metadata(&mut self,recs: &[Record],locals: Option<&Locals>) -> BitcodeResult<()> {
+        let base = self.md.len();
+        let mut nodes = Vec::new();
+        for r in recs {
+            match r.code {
+                1 => self.md.push(Metadata::Bytes(r.ops.iter().map(|&c| c as u8).collect())),
+                2 => self.md.push(Metadata::Null),
+                35 => {
+                    let count = op(r,0)?;
+                    let offset = op(r,1)? as usize;
+                    let blob = match r.blob {
+                        Some(b) if b.len() >= offset => b,
+                        _ => return malformed("invalid metadata strings")
+                    };
+                    let mut lengths = BitReader::new(&blob[..offset]);
+                    let mut pos = offset;
+                    for _ in 0..count {
+                        let len = lengths.read_vbr(6)? as usize;
+                        match blob.get(pos..pos+len) {
+                            Some(s) => self.md.push(Metadata::Bytes(s.to_vec())),
+                            None => return malformed("invalid metadata strings")
+                        }
+                        pos += len;
+                    }
+                },
+                3 | 5 | 7 | 12..=34 | 37 | 40 | 41 | 44..=47 => {
+                    nodes.push(self.next_md_node);
+                    self.md.push(Metadata::Ref(self.next_md_node));
+                    self.next_md_node += 1;
+                },
+                8 | 9 => return unsupported("old metadata format"),
+                _ => {}
+            }
+        }
+        // Values first, they are inlined into nodes.
+        let mut id = base;
+        for r in recs {
+            match r.code {
+                1 | 3 | 5 | 7 | 12..=34 | 37 | 40 | 41 | 44..=47 => id += 1,
+                35 => id += op(r,0)? as usize,
+                2 => {
+                    let tp = self.type_of(op(r,0)?)?;
+                    let vid = op(r,1)? as usize;
+                    let val = match (self.values.get(vid),locals) {
+                        (Some(v),_) => v.val.clone(),
+                        (None,Some(l)) => match l.names.get(&vid) {
+                            Some(n) => Value::Local(n.clone()),
+                            None => return malformed("unknown value in metadata")
+                        },
+                        (None,None) => return malformed("unknown value in metadata")
+                    };
+                    self.md[id] = Metadata::Value(Box::new(Typed::new(tp,val)));
+                    id += 1;
+                },
+                _ => {}
+            }
+        }
+        let mut nodes = nodes.into_iter();
+        let mut name = None;
+        for r in recs {
+            match r.code {
+                3 | 5 | 7 | 12..=34 | 37 | 40 | 41 | 44..=47 => {
+                    let node = match r.code {
+                        3 | 5 => {
+                            let mut elems = Vec::with_capacity(r.ops.len());
+                            for &e in &r.ops {
+                                elems.push(self.md_ref_opt(e)?);
+                            }
+                            Metadata::Struct(elems)
+                        },
+                        7 => {
+                            let scope = self.md_ref(op(r,3)?)?;
+                            Metadata::Location(op(r,1)?,op(r,2)?,Box::new(scope))
+                        },
+                        _ => self.read_node(r)?
+                    };
+                    match nodes.next() {
+                        Some(n) => { self.module.md.insert(n,node); },
+                        None => return malformed("metadata node without id")
+                    }
+                },
+                4 => name = Some(r.string(0)),
+                10 => {
+                    let mut elems = Vec::with_capacity(r.ops.len());
+                    for &e in &r.ops {
+                        elems.push(self.md_ref(e)?);
+                    }
+                    match name.take() {
+                        Some(n) => { self.module.named_md.insert(n,Metadata::Struct(elems)); },
+                        None => return malformed("named metadata without name")
+                    }
+                },
+                6 => { self.md_kinds.insert(op(r,0)?,r.string(1)); },
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn read_function_body(&mut self,cur: &mut Cursor<'a>) -> BitcodeResult<()> {
+        let name = match self.bodies.get(self.next_body) {
+            Some(n) => n.clone(),
+            None => return malformed("function body without definition")
+        };
+        self.next_body += 1;
+        let start = self.values.len();
+        let md_start = self.md.len();
+        let args: Vec<Type> = match self.module.functions.get(&name) {
+            Some(f) => f.arguments.iter().map(|(_,tp)| tp.clone()).collect(),
+            None => return malformed("function body without definition")
+        };
+        for (i,tp) in args.iter().enumerate() {
+            self.values.push(Typed::new(tp.clone(),Value::Argument(i)));
+        }
+        let mut nblocks = 0;
+        let mut insts = Vec::new();
+        let mut md_recs = Vec::new();
+        let mut attachments = Vec::new();
+        let mut names = HashMap::new();
+        let mut block_names = HashMap::new();
+        loop {
+            match cur.advance()? {
+                Some(Entry::Record(r)) => if r.code==1 {
+                    nblocks = op(&r,0)? as usize;
+                } else {
+                    insts.push(r)
+                },
+                Some(Entry::SubBlock(CONSTANTS_BLOCK_ID)) => {
+                    cur.enter_block(CONSTANTS_BLOCK_ID)?;
+                    self.read_constants(cur)?;
+                },
+                Some(Entry::SubBlock(METADATA_BLOCK_ID)) => {
+                    cur.enter_block(METADATA_BLOCK_ID)?;
+                    md_recs = cur.records()?;
+                },
+                Some(Entry::SubBlock(METADATA_ATTACHMENT_ID)) => {
+                    cur.enter_block(METADATA_ATTACHMENT_ID)?;
+                    attachments = cur.records()?;
+                },
+                Some(Entry::SubBlock(VALUE_SYMTAB_BLOCK_ID)) => {
+                    cur.enter_block(VALUE_SYMTAB_BLOCK_ID)?;
+                    for r in cur.records()? {
+                        match r.code {
+                            1 => { names.insert(op(&r,0)? as usize,r.string(1)); },
+                            2 => { block_names.insert(op(&r,0)? as usize,r.string(1)); },
+                            _ => {}
+                        }
+                    }
+                },
+                Some(Entry::SubBlock(_)) => cur.skip_block()?,
+                Some(Entry::EndBlock) => break,
+                None => return Err(BitcodeError::Stream(BitstreamError::UnexpectedEof))
+            }
+        }
+        // Number the unnamed values like the slot tracker of LLVM does.
+        let mut slot = 0;
+        let mut locals = Locals { names: HashMap::new(), blocks: Vec::with_capacity(nblocks) };
+        for i in 0..args.len() {
+            let n = match names.remove(&(start+i)) {
+                Some(n) => n,
+                None => fresh(&mut slot)
+            };
+            locals.names.insert(start+i,n);
+        }
+        let mut next_id = self.values.len();
+        let mut new_block = true;
+        for r in &insts {
+            if r.code==33 || r.code==35 {
+                continue
+            }
+            if new_block {
+                let bb = locals.blocks.len();
+                let n = match block_names.remove(&bb) {
+                    Some(n) => n,
+                    None => fresh(&mut slot)
+                };
+                locals.blocks.push(n);
+            }
+            if self.defines_value(r)? {
+                let n = match names.remove(&next_id) {
+                    Some(n) => n,
+                    None => fresh(&mut slot)
+                };
+                locals.names.insert(next_id,n);
+                next_id += 1;
+            }
+            new_block = matches!(r.code,10 | 11 | 12 | 15);
+        }
+        if locals.blocks.len() != nblocks {
+            return malformed("wrong number of basic blocks")
+        }
+        self.read_metadata(&md_recs,Some(&locals))?;
+        let mut blocks: Vec<BasicBlock> = locals.blocks.iter()
+            .map(|n| BasicBlock { name: n.clone(), instrs: Vec::new() })
+            .collect();
+        let mut positions: Vec<(usize,usize)> = Vec::new();
+        let mut cur_block = 0;
+        let mut last_loc = None;
+        for r in &insts {
+            match r.code {
+                33 | 35 => {
+                    let node = if r.code==35 {
+                        let key = (op(r,0)?,op(r,1)?,op(r,2)?,op(r,3)?);
+                        let node = match self.locations.get(&key) {
+                            Some(&n) => n,
+                            None => {
+                                let n = self.next_md_node;
+                                let scope = self.md_ref_opt(key.2)?;
+                                self.next_md_node += 1;
+                                self.module.md.insert(n,Metadata::Location(key.0,key.1,Box::new(scope)));
+                                self.locations.insert(key,n);
+                                n
+                            }
+                        };
+                        last_loc = Some(node);
+                        node
+                    } else {
+                        match last_loc {
+                            Some(n) => n,
+                            None => return malformed("debug location repeated without one")
+                        }
+                    };
+                    match positions.last() {
+                        Some(&(b,i)) => {
+                            blocks[b].instrs[i].metadata.insert("dbg".to_string(),node);
+                        },
+                        None => return malformed("debug location without instruction")
+                    }
+                },
+                _ => {
+                    let id = self.values.len();
+                    let (content,tp) = self.read_instruction(r,&locals)?;
+                    if let Some(tp) = tp {
+                        let n = match locals.names.get(&id) {
+                            Some(n) => n.clone(),
+                            None => return malformed("unnamed instruction result")
+                        };
+                        self.values.push(Typed::new(tp,Value::Local(n)));
+                    }
+                    let term = matches!(content,InstructionC::Term(_));
+                    let blk = match blocks.get_mut(cur_block) {
+                        Some(b) => b,
+                        None => return malformed("instruction after the last block")
+                    };
+                    positions.push((cur_block,blk.instrs.len()));
+                    blk.instrs.push(Instruction { content,
+                                                  metadata: OrderedMap::new() });
+                    if term {
+                        cur_block += 1;
+                    }
+                }
+            }
+        }
+        for r in &attachments {
+            if r.code != 11 || r.ops.len() % 2 == 0 {
+                continue
+            }
+            let (b,i) = match positions.get(r.ops[0] as usize) {
+                Some(&p) => p,
+                None => return malformed("attachment to unknown instruction")
+            };
+            for kv in r.ops[1..].chunks(2) {
+                let kind = match self.md_kinds.get(&kv[0]) {
+                    Some(k) => k.clone(),
+                    None => return malformed("unknown metadata kind")
+                };
+                if let Metadata::Ref(n) = self.md_ref(kv[1])? {
+                    blocks[b].instrs[i].metadata.insert(kind,n);
+                }
+            }
+        }
+        self.values.truncate(start);
+        self.md.truncate(md_start);
+        let fun = match self.module.functions.get_mut(&name) {
+            Some(f) => f,
+            None => return malformed("function body without definition")
+        };
+        for (i,arg) in fun.arguments.iter_mut().enumerate() {
+            arg.0 = locals.names.get(&(start+i)).cloned();
+        }
+        fun.body = Some(blocks);
+        Ok(())
+    }
+
+    // Whether an instruction record defines a value.
+    fn defines_value(&self,r: &Record) -> BitcodeResult<bool> {
+        match r.code {
+            2 | 3 | 5 | 16 | 19 | 20 | 28 | 29 | 43 => Ok(true),
+            10 | 11 | 12 | 15 | 44 => Ok(false),
+            34 => {
+                let flags = op(r,1)?;
+                let pos = if flags & (1 << 17) != 0 { 3 } else { 2 };
+                let fty = if flags & (1 << 15) != 0 {
+                    self.type_of(op(r,pos)?)?
+                } else {
+                    match self.values.get(self.values.len().wrapping_sub(op(r,pos)? as usize)) {
+                        Some(&Typed { tp: Type::Pointer(ref el,_),.. }) => (**el).clone(),
+                        _ => return unsupported("call without explicit function type")
+                    }
+                };
+                match fty {
+                    Type::Function(ref ret,..) => Ok(ret.is_some()),
+                    _ => malformed("call of a non-function")
+                }
+            },
+            _ => Err(BitcodeError::Unsupported(instruction_name(r.code).to_string()))
+        }
+    }
+
+    fn value_ref(&self,locals: &Locals,id: usize) -> BitcodeResult<Value> {
+        match self.values.get(id) {
+            Some(v) => Ok(v.val.clone()),
+            None => match locals.names.get(&id) {
+                Some(n) => Ok(Value::Local(n.clone())),
+                None => malformed("unknown value id")
+            }
+        }
+    }
+
+    fn block_ref(&self,locals: &Locals,id: u64) -> BitcodeResult<String> {
+        match locals.blocks.get(id as usize) {
+            Some(n) => Ok(n.clone()),
+            None => malformed("unknown basic block")
+        }
+    }
+
+    // A relative operand followed by its type if it is a forward reference.
+    fn typed_operand(&self,locals: &Locals,ops: &mut Operands) -> BitcodeResult<Typed<Value>> {
+        let id = ops.relative()?;
+        match self.values.get(id) {
+            Some(v) => Ok(v.clone()),
+            None => {
+                let tp = self.type_of(ops.next()?)?;
+                Ok(Typed::new(tp,self.value_ref(locals,id)?))
+            }
+        }
+    }
+
+    fn operand(&self,locals: &Locals,ops: &mut Operands,tp: &Type) -> BitcodeResult<Value> {
+        let id = ops.relative()?;
+        if *tp==Type::Metadata {
+            Ok(Value::Metadata(self.md_ref(id as u64)?))
+        } else {
+            self.value_ref(locals,id)
+        }
+    }
+
+    fn named_type(&self,tp: Type) -> Type {
+        match tp {
+            Type::Named(n) => match self.module.types.get(&n) {
+                Some(tp) => tp.clone(),
+                None => Type::Named(n)
+            },
+            tp => tp
+        }
+    }
+
+    // The instruction of a record and the type of the value it defines.
+    fn read_instruction(&self,r: &Record,locals: &Locals) -> BitcodeResult<(InstructionC,Option<Type>)> {
+        let mut ops = Operands { ops: &r.ops, pos: 0, inst_num: self.values.len() };
+        let name = || locals.names.get(&self.values.len()).cloned().unwrap_or_default();
+        Ok(match r.code {
+            2 => {
+                let lhs = self.typed_operand(locals,&mut ops)?;
+                let rhs = self.operand(locals,&mut ops,&lhs.tp)?;
+                if !matches!(lhs.tp,Type::Int(_)) {
+                    return unsupported("floating point arithmetic")
+                }
+                let opc = ops.next()?;
+                let flags = ops.next().unwrap_or(0);
+                (InstructionC::Bin(name(),decode_binop(opc,flags)?,lhs.tp.clone(),lhs.val,rhs),
+                 Some(lhs.tp))
+            },
+            3 => {
+                let val = self.typed_operand(locals,&mut ops)?;
+                let tp = self.type_of(ops.next()?)?;
+                let cast = decode_cast(ops.next()?)?;
+                (InstructionC::Unary(name(),val,UnaryInst::Cast(tp.clone(),cast)),Some(tp))
+            },
+            5 | 29 => {
+                let tv = self.typed_operand(locals,&mut ops)?;
+                let fv = self.operand(locals,&mut ops,&tv.tp)?;
+                let cond = if r.code==29 {
+                    self.typed_operand(locals,&mut ops)?.val
+                } else {
+                    self.operand(locals,&mut ops,&Type::Int(1))?
+                };
+                (InstructionC::Select(name(),cond,tv.tp.clone(),tv.val,fv),Some(tv.tp))
+            },
+            10 => {
+                let val = if ops.rest()==0 {
+                    None
+                } else {
+                    Some(self.typed_operand(locals,&mut ops)?)
+                };
+                if ops.rest() != 0 {
+                    return unsupported("return of multiple values")
+                }
+                (InstructionC::Term(Terminator::Ret(val)),None)
+            },
+            11 => {
+                let t = self.block_ref(locals,ops.next()?)?;
+                let term = if ops.rest()==0 {
+                    Terminator::Br(t)
+                } else {
+                    let f = self.block_ref(locals,ops.next()?)?;
+                    let cond = self.operand(locals,&mut ops,&Type::Int(1))?;
+                    Terminator::BrC(cond,t,f)
+                };
+                (InstructionC::Term(term),None)
+            },
+            12 => {
+                let tp_id = ops.next()?;
+                if tp_id >> 16 == 0x4B5 {
+                    return unsupported("switch with case ranges")
+                }
+                let tp = self.type_of(tp_id)?;
+                let cond = self.operand(locals,&mut ops,&tp)?;
+                let def = self.block_ref(locals,ops.next()?)?;
+                let mut cases = Vec::new();
+                while ops.rest() >= 2 {
+                    let c = match self.values.get(ops.next()? as usize) {
+                        Some(&Typed { val: Value::Constant(ref c),.. }) => c.clone(),
+                        _ => return malformed("switch case is not a constant")
+                    };
+                    cases.push((c,self.block_ref(locals,ops.next()?)?));
+                }
+                (InstructionC::Term(Terminator::Switch(tp,cond,def,cases)),None)
+            },
+            15 => (InstructionC::Term(Terminator::Unreachable),None),
+            16 => {
+                let tp = self.type_of(ops.next()?)?;
+                let mut incoming = Vec::new();
+                while ops.rest() >= 2 {
+                    let id = (ops.inst_num as i64 - decode_signed(ops.next()?)) as usize;
+                    let val = self.value_ref(locals,id)?;
+                    incoming.push((val,self.block_ref(locals,ops.next()?)?));
+                }
+                (InstructionC::Phi(name(),tp.clone(),incoming),Some(tp))
+            },
+            19 => {
+                let inst_tp = self.type_of(ops.next()?)?;
+                let size_tp = self.type_of(ops.next()?)?;
+                let size = ops.next()? as usize;
+                let align = ops.next()?;
+                let tp = if align & (1 << 6) != 0 {
+                    inst_tp
+                } else {
+                    match inst_tp {
+                        Type::Pointer(el,_) => *el,
+                        _ => return malformed("alloca of non-pointer type")
+                    }
+                };
+                let size = match self.values.get(size) {
+                    Some(&Typed { val: Value::Constant(Constant::Int(ref n)),.. })
+                        if size_tp==Type::Int(32) && n.is_one() => None,
+                    Some(v) => Some(Typed::new(size_tp,v.val.clone())),
+                    None => return malformed("unknown alloca size")
+                };
+                let align = decode_alignment((align & 0x1F) | ((align >> 8) & 0x7) << 5);
+                (InstructionC::Alloca(name(),tp.clone(),size,align),Some(Type::ptr(tp)))
+            },
+            20 => {
+                let ptr = self.typed_operand(locals,&mut ops)?;
+                let tp = if ops.rest()==3 {
+                    self.type_of(ops.next()?)?
+                } else {
+                    match ptr.tp {
+                        Type::Pointer(ref el,_) => (**el).clone(),
+                        _ => return malformed("load from non-pointer")
+                    }
+                };
+                let align = decode_alignment(ops.next()?);
+                let vol = ops.next()? != 0;
+                (InstructionC::Unary(name(),ptr,UnaryInst::Load(vol,align)),Some(tp))
+            },
+            28 => {
+                let lhs = self.typed_operand(locals,&mut ops)?;
+                let rhs = self.operand(locals,&mut ops,&lhs.tp)?;
+                let cmp = decode_cmp_op(ops.next()?)?;
+                (InstructionC::ICmp(name(),cmp,lhs.tp,lhs.val,rhs),Some(Type::Int(1)))
+            },
+            34 => {
+                let (fun,ret_attrs,_) = self.attribute_list(ops.next()?,0)?;
+                let flags = ops.next()?;
+                if flags & (1 << 17) != 0 {
+                    ops.next()?;
+                }
+                let explicit = if flags & (1 << 15) != 0 {
+                    Some(self.type_of(ops.next()?)?)
+                } else {
+                    None
+                };
+                let callee = self.typed_operand(locals,&mut ops)?;
+                let fty = match explicit {
+                    Some(tp) => tp,
+                    None => match callee.tp {
+                        Type::Pointer(ref el,_) => (**el).clone(),
+                        _ => return malformed("call of a non-pointer")
+                    }
+                };
+                let (ret,params,var_args) = match fty {
+                    Type::Function(ref ret,ref params,va) => (ret.clone(),params.clone(),va),
+                    _ => return malformed("call of a non-function")
+                };
+                let mut args = Vec::new();
+                for tp in params {
+                    if tp==Type::Label {
+                        return unsupported("label argument")
+                    }
+                    let val = self.operand(locals,&mut ops,&tp)?;
+                    args.push(Typed::new(tp,val));
+                }
+                if var_args {
+                    while ops.rest() > 0 {
+                        args.push(self.typed_operand(locals,&mut ops)?);
+                    }
+                }
+                let rtp = if var_args {
+                    Some((Type::ptr(fty.clone()),ret_attrs))
+                } else {
+                    ret.as_ref().map(|tp| ((**tp).clone(),ret_attrs))
+                };
+                let name = ret.as_ref().map(|_| name());
+                (InstructionC::Call(name,decode_calling_conv((flags >> 1) & 0x3FF),rtp,
+                                    callee.val,args,fun),
+                 ret.map(|tp| *tp))
+            },
+            43 => {
+                let inbounds = ops.next()? != 0;
+                let src = self.type_of(ops.next()?)?;
+                let ptr = self.typed_operand(locals,&mut ops)?;
+                let mut indices = Vec::new();
+                while ops.rest() > 0 {
+                    indices.push(self.typed_operand(locals,&mut ops)?);
+                }
+                let mut tp = src;
+                for idx in indices.iter().skip(1) {
+                    tp = match self.named_type(tp) {
+                        Type::Array(_,el) => *el,
+                        Type::Struct(mut els) => {
+                            let i = match idx.val {
+                                Value::Constant(Constant::Int(ref i)) => i.to_usize(),
+                                _ => None
+                            };
+                            match i {
+                                Some(i) if i < els.len() => els.swap_remove(i),
+                                _ => return malformed("invalid struct index")
+                            }
+                        },
+                        _ => return malformed("getelementptr into non-aggregate")
+                    };
+                }
+                let sp = match ptr.tp {
+                    Type::Pointer(_,sp) => sp,
+                    _ => return unsupported("vector getelementptr")
+                };
+                (InstructionC::GEP(name(),GEP { ptr,
+                                                inbounds,
+                                                indices: indices.into_iter().map(|i| (i,false)).collect() }),
+                 Some(Type::Pointer(Box::new(tp),sp)))
+            },
+            44 => {
+                let ptr = self.typed_operand(locals,&mut ops)?;
+                let val = self.typed_operand(locals,&mut ops)?;
+                let align = decode_alignment(ops.next()?);
+                let vol = ops.next()? != 0;
+                (InstructionC::Store(vol,val,ptr,align),None)
+            },
+            _ => return Err(BitcodeError::Unsupported(instruction_name(r.code).to_string()))
+        })
+    }
+}
+
+fn resolve_type(raw: &[RawType],resolved: &mut Vec<Option<TypeEntry>>,id: u64,depth: usize) -> BitcodeResult<TypeEntry> {
+    if let Some(Some(e)) = resolved.get(id as usize) {
+        return Ok(e.clone())
+    }
+    if depth > raw.len() {
+        return malformed("recursive type")
+    }
+    let children = match raw.get(id as usize) {
+        None => return malformed("unknown type id"),
+        Some(&RawType::Pointer(el,_)) | Some(&RawType::Array(_,el)) => vec![el],
+        Some(RawType::Struct(els)) => els.clone(),
+        Some(&RawType::Function(_,ret,ref params)) => {
+            let mut res = vec![ret];
+            res.extend(params.iter().cloned());
+            res
+        },
+        Some(_) => Vec::new()
+    };
+    // The resolved children, `None` stands for void.
+    let mut elems = Vec::with_capacity(children.len());
+    for child in children {
+        match resolve_type(raw,resolved,child,depth+1)? {
+            TypeEntry::Type(tp) => elems.push(Some(tp)),
+            TypeEntry::Void => elems.push(None),
+            TypeEntry::Unsupported(what) => {
+                resolved[id as usize] = Some(TypeEntry::Unsupported(what));
+                return Ok(TypeEntry::Unsupported(what))
+            }
+        }
+    }
+    let entry = match raw[id as usize] {
+        RawType::Void => TypeEntry::Void,
+        RawType::Simple(ref tp) => TypeEntry::Type(tp.clone()),
+        RawType::Named(ref n) => TypeEntry::Type(Type::Named(n.clone())),
+        RawType::Unsupported(what) => TypeEntry::Unsupported(what),
+        RawType::Pointer(_,sp) => match elems.pop() {
+            Some(Some(tp)) => TypeEntry::Type(Type::Pointer(Box::new(tp),if sp==0 { None } else { Some(sp) })),
+            _ => return malformed("pointer to void")
+        },
+        RawType::Array(n,_) => match elems.pop() {
+            Some(Some(tp)) => TypeEntry::Type(Type::Array(n,Box::new(tp))),
+            _ => return malformed("array of void")
+        },
+        RawType::Struct(_) => match elems.into_iter().collect() {
+            Some(tps) => TypeEntry::Type(Type::Struct(tps)),
+            None => return malformed("struct with void element")
+        },
+        RawType::Function(va,_,_) => {
+            let ret = elems.remove(0);
+            match elems.into_iter().collect() {
+                Some(params) => TypeEntry::Type(Type::Function(ret.map(Box::new),params,va)),
+                None => return malformed("void parameter")
+            }
+        }
+    };
+    resolved[id as usize] = Some(entry.clone());
+    Ok(entry)
+}
+
+// Whether a type contains the named struct by value, which would make
+// it infinitely large.
+fn contains_struct(types: &OrderedMap<String,Type>,name: &str,tp: &Type,seen: &mut HashSet<String>) -> bool {
+    match *tp {
+        Type::Named(ref n) if n==name => true,
+        Type::Named(ref n) => seen.insert(n.clone()) && match types.get(n) {
+            Some(tp) => contains_struct(types,name,tp,seen),
+            None => false
+        },
+        Type::Struct(ref els) => els.iter().any(|el| contains_struct(types,name,el,seen)),
+        Type::Array(_,ref el) => contains_struct(types,name,el,seen),
+        _ => false
+    }
+}
+
+fn instruction_name(code: u32) -> &'static str {
+    match code {
+        4 => "extractelement",
+        6 => "insertelement",
+        7 => "shufflevector",
+        8 | 9 => "fcmp",
+        13 => "invoke",
+        26 => "extractvalue",
+        27 => "insertvalue",
+        31 => "indirectbr",
+        36 | 37 => "fence",
+        38 | 46 => "cmpxchg",
+        39 | 59 => "atomicrmw",
+        40 => "resume",
+        41 | 45 => "atomic load",
+        42 | 47 => "atomic store",
+        48..=52 => "exception handling",
+        55 => "operand bundle",
+        56 => "fneg",
+        57 => "callbr",
+        58 => "freeze",
+        _ => "instruction"
+    }
+}
+
+// The operands of an instruction record.
+struct Operands<'r> {
+    ops: &'r [u64],
+    pos: usize,
+    /// The value id the instruction would define.
+    inst_num: usize
+}
+
+impl<'r> Operands<'r> {
+    fn next(&mut self) -> BitcodeResult<u64> {
+        match self.ops.get(self.pos) {
+            Some(&v) => {
+                self.pos += 1;
+                Ok(v)
+            },
+            None => malformed("instruction has too few operands")
+        }
+    }
+    fn rest(&self) -> usize {
+        self.ops.len()-self.pos
+    }
+    fn relative(&mut self) -> BitcodeResult<usize> {
+        let rel = self.next()? as u32;
+        Ok((self.inst_num as u32).wrapping_sub(rel) as usize)
+    }
+}
+
+const IDENTIFICATION_BLOCK_ID: u32 = 13;
+
+// Abbreviations of the BLOCKINFO block, with their ids in the
+// symbol table, constants and function blocks.
+const VST_ENTRY_8_ABBREV: u64 = 4;
+const VST_ENTRY_7_ABBREV: u64 = 5;
+const VST_ENTRY_6_ABBREV: u64 = 6;
+const VST_BBENTRY_6_ABBREV: u64 = 7;
+const CONSTANTS_SETTYPE_ABBREV: u64 = 4;
+const CONSTANTS_INTEGER_ABBREV: u64 = 5;
+const CONSTANTS_CE_CAST_ABBREV: u64 = 6;
+const CONSTANTS_NULL_ABBREV: u64 = 7;
+const FUNCTION_LOAD_ABBREV: u64 = 4;
+const FUNCTION_BINOP_ABBREV: u64 = 5;
+const FUNCTION_BINOP_FLAGS_ABBREV: u64 = 6;
+const FUNCTION_CAST_ABBREV: u64 = 7;
+const FUNCTION_RET_VOID_ABBREV: u64 = 8;
+const FUNCTION_RET_VAL_ABBREV: u64 = 9;
+const FUNCTION_UNREACHABLE_ABBREV: u64 = 10;
+const FUNCTION_GEP_ABBREV: u64 = 11;
+
+// Abbreviations defined at the start of the type, metadata and string
+// table blocks.
+const TYPE_POINTER_ABBREV: u64 = 4;
+const TYPE_FUNCTION_ABBREV: u64 = 5;
+const TYPE_STRUCT_ANON_ABBREV: u64 = 6;
+const TYPE_STRUCT_NAME_6_ABBREV: u64 = 7;
+const TYPE_STRUCT_NAME_8_ABBREV: u64 = 8;
+const TYPE_STRUCT_NAMED_ABBREV: u64 = 9;
+const TYPE_ARRAY_ABBREV: u64 = 10;
+const METADATA_STRINGS_ABBREV: u64 = 4;
+const METADATA_LOCATION_ABBREV: u64 = 5;
+const METADATA_NAME_ABBREV: u64 = 6;
+const STRTAB_BLOB_ABBREV: u64 = 4;
+
+// Attribute kinds that carry a type, written without one.
+const TYPE_ATTRIBUTES: [u64; 3] = [3,29,38];
+
+/// Write a module to a bitcode file.
+pub fn write_file(m: &Module,path: &str) -> BitcodeResult<()> {
+    let data = write_module(m)?;
+    File::create(path)
+        .and_then(|mut f| f.write_all(&data))
+        .map_err(|e| BitcodeError::Io(e.to_string()))
+}
+
+/// Encode a module as bitcode, in the format of LLVM 14 with typed
+/// pointers.
+///
+/// Unnamed values, blocks and types, whose names are numbers, are
+/// written without name; the numbering is left to the reader. Debug
+/// locations are written as such, other metadata as generic nodes, and
+/// null nodes, like the debug info the reader drops, as empty ones.
+/// Lazily parsed modules have to be materialized first.
+pub fn write_module(m: &Module) -> BitcodeResult<Vec<u8>> {
+    if !m.lazy_bodies.is_empty() {
+        return unsupported("function bodies that are not parsed yet")
+    }
+    let mut w = Writer::new(m);
+    let module = w.encode()?;
+    Ok(w.write(&module))
+}
+
+// A record with the abbreviations to try, in order, before falling
+// back to the unabbreviated form.
+struct Rec {
+    code: u32,
+    ops: Vec<u64>,
+    abbrevs: &'static [u64]
+}
+
+fn rec(code: u32,ops: Vec<u64>) -> Rec {
+    Rec { code, ops, abbrevs: &[] }
+}
+
+fn abbrev_rec(code: u32,ops: Vec<u64>,abbrevs: &'static [u64]) -> Rec {
+    Rec { code, ops, abbrevs }
+}
+
+fn emit(out: &mut StreamWriter,r: &Rec) {
+    for &a in r.abbrevs {
+        if out.write_abbrev_record(a,r.code,&r.ops,None).is_ok() {
+            return
+        }
+    }
+    out.write_record(r.code,&r.ops)
+}
+
+fn chars(s: &[u8]) -> Vec<u64> {
+    s.iter().map(|&c| c as u64).collect()
+}
+
+// Whether a name stands for an unnamed, numbered entity.
+fn is_unnamed(name: &str) -> bool {
+    name.bytes().all(|c| c.is_ascii_digit())
+}
+
+// Unnamed entities first, in the order of their numbers, then the
+// named ones.
+fn name_order(a: &&String,b: &&String) -> ::std::cmp::Ordering {
+    let key = |n: &str| if is_unnamed(n) { (0,n.len(),n.to_string()) } else { (1,0,n.to_string()) };
+    key(a).cmp(&key(b))
+}
+
+fn encode_signed(v: i64) -> u64 {
+    if v >= 0 {
+        (v as u64) << 1
+    } else if v != i64::MIN {
+        ((-v) as u64) << 1 | 1
+    } else {
+        1
+    }
+}
+
+fn encode_alignment(align: Option<Alignment>) -> BitcodeResult<u64> {
+    match align {
+        None => Ok(0),
+        Some(a) if a.is_power_of_two() => Ok(a.trailing_zeros() as u64 + 1),
+        Some(a) => Err(BitcodeError::Malformed(format!("alignment {} is not a power of two",a)))
+    }
+}
+
+fn encode_linkage(l: Option<Linkage>) -> u64 {
+    match l {
+        None | Some(Linkage::External) => 0,
+        Some(Linkage::Weak) => 16,
+        Some(Linkage::Appending) => 2,
+        Some(Linkage::Internal) => 3,
+        Some(Linkage::LinkOnce) => 18,
+        Some(Linkage::ExternWeak) => 7,
+        Some(Linkage::Common) => 8,
+        Some(Linkage::Private) => 9,
+        Some(Linkage::WeakODR) => 17,
+        Some(Linkage::LinkOnceODR) => 19,
+        Some(Linkage::AvailableExternally) => 12
+    }
+}
+
+fn encode_visibility(v: Visibility) -> u64 {
+    match v {
+        Visibility::Default => 0,
+        Visibility::Hidden => 1,
+        Visibility::Protected => 2
+    }
+}
+
+fn encode_dll_storage_class(c: DLLStorageClass) -> u64 {
+    match c {
+        DLLStorageClass::Default => 0,
+        DLLStorageClass::DLLImport => 1,
+        DLLStorageClass::DLLExport => 2
+    }
+}
+
+fn encode_calling_conv(cc: &CallingConv) -> u64 {
+    match *cc {
+        CallingConv::C => 0,
+        CallingConv::Fast => 8,
+        CallingConv::Cold => 9,
+        CallingConv::WebKitJS => 12,
+        CallingConv::AnyReg => 13,
+        CallingConv::PreserveMost => 14,
+        CallingConv::PreserveAll => 15,
+        CallingConv::Swift => 16,
+        CallingConv::CxxFastTLS => 17,
+        CallingConv::Numbered(n) => n
+    }
+}
+
+fn encode_cmp_op(op: &CmpOp) -> u64 {
+    match *op {
+        CmpOp::Eq => 32,
+        CmpOp::Ne => 33,
+        CmpOp::UGt => 34,
+        CmpOp::UGe => 35,
+        CmpOp::ULt => 36,
+        CmpOp::ULe => 37,
+        CmpOp::SGt => 38,
+        CmpOp::SGe => 39,
+        CmpOp::SLt => 40,
+        CmpOp::SLe => 41
+    }
+}
+
+fn encode_cast(c: CastInst) -> u64 {
+    match c {
+        CastInst::Trunc => 0,
+        CastInst::ZExt => 1,
+        CastInst::SExt => 2,
+        CastInst::PtrToInt => 9,
+        CastInst::IntToPtr => 10,
+        CastInst::Bitcast => 11
+    }
+}
+
+// The opcode and the flags of a binary operator.
+fn encode_binop(op: &BinOp) -> (u64,u64) {
+    let wrap = |nuw: bool,nsw: bool| (nuw as u64) | (nsw as u64) << 1;
+    match *op {
+        BinOp::Add(nuw,nsw) => (0,wrap(nuw,nsw)),
+        BinOp::Sub(nuw,nsw) => (1,wrap(nuw,nsw)),
+        BinOp::Mul(nuw,nsw) => (2,wrap(nuw,nsw)),
+        BinOp::SDiv(exact) => (4,exact as u64),
+        BinOp::Shl => (7,0),
+        BinOp::LShr => (8,0),
+        BinOp::AShr => (9,0),
+        BinOp::And => (10,0),
+        BinOp::Or => (11,0),
+        BinOp::XOr => (12,0)
+    }
+}
+
+fn attribute_kind(name: &str) -> Option<u64> {
+    ATTRIBUTE_NAMES.iter().position(|&n| n==name).map(|i| i as u64 + 1)
+}
+
+// The attribute group operands of a list of attributes.
+fn encode_attributes(attrs: &[Attribute]) -> BitcodeResult<Vec<u64>> {
+    let mut ops = Vec::new();
+    for attr in attrs {
+        if attr.quoted {
+            ops.push(if attr.value.is_some() { 4 } else { 3 });
+            ops.extend(chars(attr.name.as_bytes()));
+            ops.push(0);
+            if let Some(ref v) = attr.value {
+                ops.extend(chars(v.as_bytes()));
+                ops.push(0);
+            }
+            continue
+        }
+        let kind = match attribute_kind(&attr.name) {
+            Some(k) => k,
+            None => return Err(BitcodeError::Unsupported(format!("attribute {}",attr.name)))
+        };
+        match attr.value {
+            None if TYPE_ATTRIBUTES.contains(&kind) => ops.extend_from_slice(&[5,kind]),
+            None => ops.extend_from_slice(&[0,kind]),
+            Some(ref v) => match v.parse() {
+                Ok(n) => ops.extend_from_slice(&[1,kind,n]),
+                Err(_) => return Err(BitcodeError::Unsupported(format!("attribute {}({})",attr.name,v)))
+            }
+        }
+    }
+    Ok(ops)
+}
+
+// The attributes of a parameter or return value.
+fn par_attr_list(p: &ParAttrs) -> Vec<Attribute> {
+    let flags = [(p.zeroext,"zeroext"),(p.signext,"signext"),(p.inreg,"inreg"),
+                 (p.byval,"byval"),(p.inalloca,"inalloca"),(p.sret,"sret"),
+                 (p.noalias,"noalias"),(p.nocapture,"nocapture"),(p.nest,"nest"),
+                 (p.returned,"returned"),(p.nonnull,"nonnull"),
+                 (p.swiftself,"swiftself"),(p.swifterror,"swifterror")];
+    let ints = [(p.align,"align"),(p.dereferenceable,"dereferenceable"),
+                (p.dereferenceable_or_null,"dereferenceable_or_null")];
+    let mut res: Vec<Attribute> = flags.iter().filter(|f| f.0)
+        .map(|f| Attribute { name: f.1.to_string(), quoted: false, value: None })
+        .collect();
+    res.extend(ints.iter().filter_map(|&(v,n)| v.map(|v| Attribute { name: n.to_string(),
+                                                                       quoted: false,
+                                                                       value: Some(v.to_string()) })));
+    res
+}
+
+fn is_null(c: &Constant) -> bool {
+    match *c {
+        Constant::Int(ref v) => v.is_zero(),
+        Constant::NullPtr => true,
+        Constant::Array(ref els) => els.iter().all(is_null),
+        _ => false
+    }
+}
+
+// The two's complement of `v` in `width` bits, as 64 bit words.
+fn int_words(v: &BigInt,width: u64) -> Vec<u64> {
+    let modulus = BigInt::one() << width as usize;
+    let mut r = v % &modulus;
+    if r < BigInt::zero() {
+        r = r + &modulus;
+    }
+    let (_,bytes) = r.to_bytes_le();
+    let mut words = vec![0; width.div_ceil(64) as usize];
+    for (i,b) in bytes.iter().enumerate() {
+        words[i/8] |= (*b as u64) << (8*(i%8));
+    }
+    words
+}
+
+// The signed VBR operand of an integer constant of at most 64 bits.
+fn int_operand(v: &BigInt,width: u64) -> u64 {
+    let word = int_words(v,width)[0];
+    let shift = 64-width;
+    encode_signed(((word << shift) as i64) >> shift)
+}
+
+// The records of a function body.
+struct FunctionBody {
+    nblocks: u64,
+    constants: Vec<Rec>,
+    metadata: Vec<Rec>,
+    instrs: Vec<Rec>,
+    symbols: Vec<Rec>,
+    attachments: Vec<Rec>
+}
+
+// The records of the module block, as far as they are not written
+// straight from the writer's tables.
+struct ModuleRecords {
+    globals: Vec<Rec>,
+    constants: Vec<Rec>,
+    metadata: Vec<Rec>,
+    md_strings: Vec<Vec<u8>>,
+    bodies: Vec<FunctionBody>
+}
+
+// The arguments and local values of the function being encoded.
+struct FnValues<'m> {
+    args: &'m [(Option<String>,Type)],
+    first_arg: u64,
+    /// Value ids of named arguments and instructions.
+    ids: HashMap<&'m str,u64>,
+    types: HashMap<&'m str,Type>,
+    blocks: HashMap<&'m str,u64>,
+    /// Metadata ids of function-local metadata.
+    md: HashMap<&'m Typed<Value>,u64>
+}
+
+struct Writer<'m> {
+    module: &'m Module,
+    /// The type table, `None` is void.
+    types: Vec<Option<Type>>,
+    type_ids: HashMap<Type,u64>,
+    void_id: Option<u64>,
+    /// Named structs whose enumeration has started.
+    named_started: HashSet<String>,
+    global_ids: HashMap<&'m str,u64>,
+    globals: Vec<&'m str>,
+    functions: Vec<&'m str>,
+    strtab: Vec<u8>,
+    sections: Vec<&'m str>,
+    next_value: u64,
+    /// Constants with their value ids, those of the module and of the
+    /// function being encoded.
+    const_ids: HashMap<(Type,Constant),u64>,
+    /// Constants of the current constants block, in value id order.
+    consts: Vec<(Type,Constant)>,
+    /// Metadata in the order of the metadata ids to be assigned.
+    md_strings: Vec<Metadata>,
+    md_values: Vec<Metadata>,
+    md_nodes: Vec<Metadata>,
+    md_ids: HashMap<Metadata,u64>,
+    md_kinds: Vec<&'m str>,
+    /// Attribute group records and the ids of generated parameter groups.
+    groups: Vec<Rec>,
+    param_groups: HashMap<(u64,ParAttrs),u64>,
+    next_group: u64,
+    attr_lists: Vec<Vec<u64>>,
+    list_ids: HashMap<Vec<u64>,u64>
+}
+
+impl<'m> Writer<'m> {
+    fn new(m: &'m Module) -> Writer<'m> {
+        Writer { module: m,
+                 types: Vec::new(),
+                 type_ids: HashMap::new(),
+                 void_id: None,
+                 named_started: HashSet::new(),
+                 global_ids: HashMap::new(),
+                 globals: Vec::new(),
+                 functions: Vec::new(),
+                 strtab: Vec::new(),
+                 sections: Vec::new(),
+                 next_value: 0,
+                 const_ids: HashMap::new(),
+                 consts: Vec::new(),
+                 md_strings: Vec::new(),
+                 md_values: Vec::new(),
+                 md_nodes: Vec::new(),
+                 md_ids: HashMap::new(),
+                 md_kinds: Vec::new(),
+                 groups: Vec::new(),
+                 param_groups: HashMap::new(),
+                 next_group: m.attr_groups.keys().max().map_or(0,|g| g+1),
+                 attr_lists: Vec::new(),
+                 list_ids: HashMap::new() }
+    }
+
+    // Adds a type and the types it refers to to the type table, the
+    // latter first, as only named structs may be referenced before
+    // their definition.
+    fn enum_type(&mut self,tp: Option<&Type>) -> BitcodeResult<()> {
+        let tp = match tp {
+            None => {
+                if self.void_id.is_none() {
+                    self.void_id = Some(self.types.len() as u64);
+                    self.types.push(None);
+                }
+                return Ok(())
+            },
+            Some(tp) => tp
+        };
+        if self.type_ids.contains_key(tp) {
+            return Ok(())
+        }
+        match *tp {
+            Type::Named(ref n) => {
+                if !self.named_started.insert(n.clone()) {
+                    return Ok(())
+                }
+                match self.module.types.get(n) {
+                    Some(Type::Struct(els)) => for el in els {
+                        self.enum_type(Some(el))?;
+                    },
+                    Some(&Type::Opaque) => {},
+                    Some(_) => return Err(BitcodeError::Unsupported(format!("type alias %{}",n))),
+                    None => return Err(BitcodeError::Malformed(format!("unknown type %{}",n)))
+                }
+            },
+            Type::Pointer(ref el,_) | Type::Array(_,ref el) => self.enum_type(Some(el))?,
+            Type::Struct(ref els) => for el in els {
+                self.enum_type(Some(el))?;
+            },
+            Type::Function(ref ret,ref params,_) => {
+                self.enum_type(ret.as_ref().map(|r| &**r))?;
+                for p in params {
+                    self.enum_type(Some(p))?;
+                }
+            },
+            Type::Opaque => return unsupported("anonymous opaque type"),
+            _ => {}
+        }
+        if !self.type_ids.contains_key(tp) {
+            self.type_ids.insert(tp.clone(),self.types.len() as u64);
+            self.types.push(Some(tp.clone()));
+        }
+        Ok(())
+    }
+
+    fn type_id(&mut self,tp: &Type) -> BitcodeResult<u64> {
+        self.enum_type(Some(tp))?;
+        Ok(self.type_ids[tp])
+    }
+
+    fn global_type(&self,name: &str) -> BitcodeResult<Type> {
+        let m = self.module;
+        match (m.functions.get(name),m.globals.get(name)) {
+            (Some(f),_) => Ok(Type::ptr(builder::function_type(f))),
+            (None,Some(g)) => Ok(Type::Pointer(Box::new(g.types.clone()),g.addr_space)),
+            (None,None) => Err(BitcodeError::Malformed(format!("unknown global @{}",name)))
+        }
+    }
+
+    // Assigns value ids to a constant and the constants it is built
+    // from, which are added to the current constants block.
+    fn enum_constant(&mut self,tp: &Type,c: &Constant) -> BitcodeResult<()> {
+        if let Constant::Global(ref n) = *c {
+            return match self.global_ids.contains_key(&n[..]) {
+                true => Ok(()),
+                false => Err(BitcodeError::Malformed(format!("unknown global @{}",n)))
+            }
+        }
+        let key = (tp.clone(),c.clone());
+        if self.const_ids.contains_key(&key) {
+            return Ok(())
+        }
+        self.enum_type(Some(tp))?;
+        let m = self.module;
+        match *c {
+            _ if is_null(c) => {},
+            Constant::Array(ref els) if self.data_array(tp,els).is_none() => {
+                match *builder::resolve_type(&m.types,tp) {
+                    Type::Array(_,ref el) => for e in els {
+                        self.enum_constant(el,e)?;
+                    },
+                    Type::Struct(ref tps) if tps.len()==els.len() => for (t,e) in tps.iter().zip(els) {
+                        self.enum_constant(t,e)?;
+                    },
+                    _ => return Err(BitcodeError::Malformed(format!("aggregate constant of type {:?}",tp)))
+                }
+            },
+            Constant::GEP(ref g) => {
+                self.enum_constant(&g.ptr.tp,&g.ptr.val)?;
+                for (idx,_) in &g.indices {
+                    self.enum_constant(&idx.tp,&idx.val)?;
+                }
+            },
+            Constant::Cast(_,ref src,_) => {
+                self.enum_type(Some(&src.tp))?;
+                self.enum_constant(&src.tp,&src.val)?;
+            },
+            _ => {}
+        }
+        self.const_ids.insert(key.clone(),self.next_value);
+        self.next_value += 1;
+        self.consts.push(key);
+        Ok(())
+    }
+
+    // The element width and values of an integer array that can be
+    // written as a data record.
+    fn data_array(&self,tp: &Type,els: &[Constant]) -> Option<(u64,Vec<u64>)> {
+        let width = match *builder::resolve_type(&self.module.types,tp) {
+            Type::Array(_,ref el) => match **el {
+                Type::Int(w) if matches!(w,8 | 16 | 32 | 64) => w,
+                _ => return None
+            },
+            _ => return None
+        };
+        let mut vals = Vec::with_capacity(els.len());
+        for e in els {
+            match *e {
+                Constant::Int(ref v) => vals.push(int_words(v,width)[0]),
+                _ => return None
+            }
+        }
+        Some((width,vals))
+    }
+
+    fn constant_id(&self,tp: &Type,c: &Constant) -> BitcodeResult<u64> {
+        if let Constant::Global(ref n) = *c {
+            return match self.global_ids.get(&n[..]) {
+                Some(&id) => Ok(id),
+                None => Err(BitcodeError::Malformed(format!("unknown global @{}",n)))
+            }
+        }
+        match self.const_ids.get(&(tp.clone(),c.clone())) {
+            Some(&id) => Ok(id),
+            None => Err(BitcodeError::Malformed(format!("constant {:?} of type {:?} not enumerated",c,tp)))
+        }
+    }
+
+    // The records of the current constants block.
+    fn constant_records(&mut self) -> BitcodeResult<Vec<Rec>> {
+        let m = self.module;
+        let consts = std::mem::take(&mut self.consts);
+        let mut recs = Vec::with_capacity(consts.len());
+        let mut cur_tp = None;
+        for (tp,c) in &consts {
+            if cur_tp != Some(tp) {
+                recs.push(abbrev_rec(1,vec![self.type_id(tp)?],&[CONSTANTS_SETTYPE_ABBREV]));
+                cur_tp = Some(tp);
+            }
+            let r = match *c {
+                _ if is_null(c) => abbrev_rec(2,Vec::new(),&[CONSTANTS_NULL_ABBREV]),
+                Constant::Undef => rec(3,Vec::new()),
+                Constant::Int(ref v) => match *builder::resolve_type(&m.types,tp) {
+                    Type::Int(w) if w <= 64 => abbrev_rec(4,vec![int_operand(v,w)],&[CONSTANTS_INTEGER_ABBREV]),
+                    Type::Int(w) => rec(5,int_words(v,w).into_iter().map(|w| encode_signed(w as i64)).collect()),
+                    _ => return Err(BitcodeError::Malformed(format!("integer constant of type {:?}",tp)))
+                },
+                Constant::Array(ref els) => match self.data_array(tp,els) {
+                    Some((8,mut vals)) => {
+                        let cstring = vals.last()==Some(&0) && vals.iter().filter(|&&v| v==0).count()==1;
+                        if cstring {
+                            vals.pop();
+                            rec(9,vals)
+                        } else {
+                            rec(8,vals)
+                        }
+                    },
+                    Some((_,vals)) => rec(22,vals),
+                    None => {
+                        let tps: Vec<Type> = match *builder::resolve_type(&m.types,tp) {
+                            Type::Array(_,ref el) => vec![(**el).clone(); els.len()],
+                            Type::Struct(ref tps) => tps.clone(),
+                            _ => return Err(BitcodeError::Malformed(format!("aggregate constant of type {:?}",tp)))
+                        };
+                        let mut ops = Vec::with_capacity(els.len());
+                        for (t,e) in tps.iter().zip(els) {
+                            ops.push(self.constant_id(t,e)?);
+                        }
+                        rec(7,ops)
+                    }
+                },
+                Constant::GEP(ref g) => {
+                    let pointee = match g.ptr.tp {
+                        Type::Pointer(ref el,_) => (**el).clone(),
+                        _ => return malformed("constant getelementptr of a non-pointer")
+                    };
+                    let mut ops = vec![self.type_id(&pointee)?];
+                    let code = match g.indices.iter().position(|i| i.1) {
+                        Some(i) => {
+                            ops.push((i as u64) << 1 | g.inbounds as u64);
+                            24
+                        },
+                        None if g.inbounds => 20,
+                        None => 12
+                    };
+                    for tv in Some(&g.ptr).into_iter().chain(g.indices.iter().map(|i| &i.0)) {
+                        ops.push(self.type_id(&tv.tp)?);
+                        ops.push(self.constant_id(&tv.tp,&tv.val)?);
+                    }
+                    rec(code,ops)
+                },
+                Constant::Cast(op,ref src,_) => {
+                    let ops = vec![encode_cast(op),self.type_id(&src.tp)?,self.constant_id(&src.tp,&src.val)?];
+                    abbrev_rec(11,ops,&[CONSTANTS_CE_CAST_ABBREV])
+                },
+                Constant::NullPtr | Constant::Global(_) => return malformed("unexpected constant")
+            };
+            recs.push(r);
+        }
+        Ok(recs)
+    }
+
+    // Adds metadata and everything it refers to to the metadata to
+    // be written.
+    fn collect_md(&mut self,md: &Metadata) -> BitcodeResult<()> {
+        if *md==Metadata::Null || self.md_ids.contains_key(md) {
+            return Ok(())
+        }
+        // Only marks the metadata as seen, the ids are assigned later.
+        self.md_ids.insert(md.clone(),0);
+        match *md {
+            Metadata::Null => {},
+            Metadata::Bytes(_) => self.md_strings.push(md.clone()),
+            Metadata::Value(ref tv) => match tv.val {
+                Value::Constant(ref c) => {
+                    self.enum_type(Some(&tv.tp))?;
+                    self.enum_constant(&tv.tp,c)?;
+                    self.md_values.push(md.clone());
+                },
+                _ => return malformed("function-local metadata outside of a call")
+            },
+            Metadata::Ref(n) => {
+                let content = match self.module.md.get(&n) {
+                    Some(c) => c,
+                    None => return Err(BitcodeError::Malformed(format!("unknown metadata !{}",n)))
+                };
+                self.md_nodes.push(md.clone());
+                self.collect_md_operands(content)?;
+            },
+            Metadata::Struct(_) | Metadata::Location(..) | Metadata::Node(..) => {
+                self.md_nodes.push(md.clone());
+                self.collect_md_operands(md)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn collect_md_operands(&mut self,content: &Metadata) -> BitcodeResult<()> {
+        match *content {
+            Metadata::Struct(ref els) => for e in els {
+                self.collect_md(e)?;
+            },
+            Metadata::Location(_,_,ref scope) => self.collect_md(scope)?,
+            Metadata::Node(_,_,ref fields) => for field in fields {
+                if let MetadataField::Metadata(ref md) = field.1 {
+                    self.collect_md(md)?;
+                }
+            },
+            Metadata::Null => {},
+            _ => self.collect_md(content)?
+        }
+        Ok(())
+    }
+
+    fn md_id(&self,md: &Metadata) -> BitcodeResult<u64> {
+        match self.md_ids.get(md) {
+            Some(&id) => Ok(id),
+            None => malformed("metadata not enumerated")
+        }
+    }
+
+    // A node operand, 0 stands for null.
+    fn md_operand(&self,md: &Metadata) -> BitcodeResult<u64> {
+        if *md==Metadata::Null { Ok(0) } else { Ok(self.md_id(md)?+1) }
+    }
+
+    // The id of metadata that must be a node.
+    fn md_node_id(&self,md: &Metadata) -> BitcodeResult<u64> {
+        match *md {
+            Metadata::Ref(_) | Metadata::Struct(_) | Metadata::Location(..) | Metadata::Node(..) => self.md_id(md),
+            _ => Err(BitcodeError::Unsupported(format!("metadata {:?} where a node is expected",md)))
+        }
+    }
+
+    // The record of a specialized node, fields that are not set are
+    // written as zero.
+    fn node_record(&self,kind: &str,distinct: bool,fields: &[(String,MetadataField)]) -> BitcodeResult<Rec> {
+        let layout = match NODE_LAYOUTS.iter().find(|l| l.kind==kind) {
+            Some(l) => l,
+            None => return Err(BitcodeError::Unsupported(format!("metadata node !{}",kind)))
+        };
+        if let Some((name,_)) = fields.iter().find(|f| !layout.fields.iter().any(|&(n,_)| n==f.0)) {
+            return Err(BitcodeError::Malformed(format!("unknown field {} of !{}",name,kind)))
+        }
+        let mut ops = vec![layout.version | distinct as u64];
+        for &(name,enc) in layout.fields {
+            match (enc,fields.iter().find(|f| f.0==name).map(|f| &f.1)) {
+                (FieldKind::Elements,Some(MetadataField::Elements(els))) => ops.extend(els),
+                (FieldKind::Elements,None) | (FieldKind::OptMd,None) => {},
+                (_,None) => ops.push(0),
+                (FieldKind::Md,Some(MetadataField::Metadata(md))) | (FieldKind::OptMd,Some(MetadataField::Metadata(md))) => ops.push(self.md_operand(md)?),
+                (FieldKind::Int,Some(&MetadataField::Int(n))) => ops.push(n),
+                (FieldKind::Bool,Some(&MetadataField::Bool(b))) => ops.push(b as u64),
+                (FieldKind::OptInt,Some(&MetadataField::Int(n))) => ops.push(n+1),
+                (FieldKind::Checksum,Some(MetadataField::Constant(c))) => match CHECKSUM_KINDS.iter().position(|k| k==c) {
+                    Some(i) => ops.push(i as u64+1),
+                    None => return Err(BitcodeError::Unsupported(format!("checksum kind {}",c)))
+                },
+                _ => return Err(BitcodeError::Malformed(format!("invalid field {} of !{}",name,kind)))
+            }
+        }
+        Ok(rec(layout.code,ops))
+    }
+
+    // The location of a `!dbg` attachment.
+    fn debug_location(&self,node: u64) -> BitcodeResult<(u64,u64,&'m Metadata)> {
+        match self.module.md.get(&node) {
+            Some(Metadata::Location(l,c,scope)) => Ok((*l,*c,scope)),
+            Some(_) => Err(BitcodeError::Unsupported(format!("debug location !{} that is not a location",node))),
+            None => Err(BitcodeError::Malformed(format!("unknown metadata !{}",node)))
+        }
+    }
+
+    // Collects the metadata of the module and assigns the metadata ids:
+    // strings first, then values, then nodes.
+    fn enum_metadata(&mut self) -> BitcodeResult<()> {
+        let m = self.module;
+        let mut names: Vec<&String> = m.named_md.keys().collect();
+        names.sort();
+        for n in names {
+            self.collect_md(&m.named_md[n])?;
+        }
+        let mut kinds = HashSet::new();
+        for f in self.functions.clone() {
+            for blk in m.functions[f].body.iter().flatten() {
+                for instr in &blk.instrs {
+                    let mut attached: Vec<(&String,&u64)> = instr.metadata.iter().collect();
+                    attached.sort();
+                    for (kind,&node) in attached {
+                        if kind=="dbg" {
+                            let (_,_,scope) = self.debug_location(node)?;
+                            self.collect_md(scope)?;
+                        } else {
+                            kinds.insert(&kind[..]);
+                            self.collect_md(&Metadata::Ref(node))?;
+                        }
+                    }
+                    if let InstructionC::Call(_,_,_,_,ref args,_) = instr.content {
+                        for arg in args {
+                            match arg.val {
+                                Value::Metadata(Metadata::Value(ref tv)) if !matches!(tv.val,Value::Constant(_)) => {},
+                                Value::Metadata(ref md) => self.collect_md(md)?,
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        self.md_kinds = kinds.into_iter().collect();
+        self.md_kinds.sort();
+        let order: Vec<Metadata> = self.md_strings.iter()
+            .chain(self.md_values.iter())
+            .chain(self.md_nodes.iter())
+            .cloned().collect();
+        for (i,md) in order.into_iter().enumerate() {
+            self.md_ids.insert(md,i as u64);
+        }
+        Ok(())
+    }
+
+    fn metadata_records(&mut self) -> BitcodeResult<Vec<Rec>> {
+        let m = self.module;
+        let mut recs = Vec::new();
+        for md in self.md_values.clone() {
+            if let Metadata::Value(ref tv) = md {
+                if let Value::Constant(ref c) = tv.val {
+                    let ops = vec![self.type_id(&tv.tp)?,self.constant_id(&tv.tp,c)?];
+                    recs.push(rec(2,ops));
+                }
+            }
+        }
+        for md in &self.md_nodes {
+            let content = match *md {
+                Metadata::Ref(n) => &m.md[&n],
+                ref inline => inline
+            };
+            recs.push(match *content {
+                Metadata::Struct(ref els) => {
+                    let mut ops = Vec::with_capacity(els.len());
+                    for e in els {
+                        ops.push(self.md_operand(e)?);
+                    }
+                    rec(3,ops)
+                },
+                Metadata::Location(l,c,ref scope) => {
+                    let ops = vec![0,l,c,self.md_node_id(scope)?,0,0];
+                    abbrev_rec(7,ops,&[METADATA_LOCATION_ABBREV])
+                },
+                Metadata::Node(ref kind,distinct,ref fields) => self.node_record(kind,distinct,fields)?,
+                Metadata::Null => rec(3,Vec::new()),
+                ref other => rec(3,vec![self.md_operand(other)?])
+            });
+        }
+        let mut names: Vec<&String> = m.named_md.keys().collect();
+        names.sort();
+        for n in names {
+            recs.push(abbrev_rec(4,chars(n.as_bytes()),&[METADATA_NAME_ABBREV]));
+            let mut ops = Vec::new();
+            match m.named_md[n] {
+                Metadata::Struct(ref els) => for e in els {
+                    ops.push(self.md_node_id(e)?);
+                },
+                ref md => ops.push(self.md_node_id(md)?)
+            }
+            recs.push(rec(10,ops));
+        }
+        Ok(recs)
+    }
+
+    fn param_group(&mut self,idx: u64,attrs: &ParAttrs) -> BitcodeResult<u64> {
+        let key = (idx,attrs.clone());
+        if let Some(&g) = self.param_groups.get(&key) {
+            return Ok(g)
+        }
+        let g = self.next_group;
+        self.next_group += 1;
+        let mut ops = vec![g,idx];
+        ops.extend(encode_attributes(&par_attr_list(attrs))?);
+        self.groups.push(rec(3,ops));
+        self.param_groups.insert(key,g);
+        Ok(g)
+    }
+
+    // The 1-based index of an attribute list, 0 for no attributes.
+    fn attribute_list(&mut self,fun: &[AttributeGroup],ret: Option<&ParAttrs>,params: &[ParAttrs]) -> BitcodeResult<u64> {
+        let empty = ParAttrs::new();
+        let mut list = Vec::new();
+        for g in fun {
+            if !self.module.attr_groups.contains_key(g) {
+                return Err(BitcodeError::Malformed(format!("unknown attribute group #{}",g)))
+            }
+            list.push(*g);
+        }
+        if let Some(r) = ret.filter(|&r| *r != empty) {
+            list.push(self.param_group(0,r)?);
+        }
+        for (i,p) in params.iter().enumerate() {
+            if *p != empty {
+                list.push(self.param_group(i as u64+1,p)?);
+            }
+        }
+        if list.is_empty() {
+            return Ok(0)
+        }
+        if let Some(&idx) = self.list_ids.get(&list) {
+            return Ok(idx+1)
+        }
+        let idx = self.attr_lists.len() as u64;
+        self.list_ids.insert(list.clone(),idx);
+        self.attr_lists.push(list);
+        Ok(idx+1)
+    }
+
+    fn symbol(&mut self,name: &str) -> Vec<u64> {
+        if is_unnamed(name) {
+            return vec![0,0]
+        }
+        let off = self.strtab.len() as u64;
+        self.strtab.extend_from_slice(name.as_bytes());
+        vec![off,name.len() as u64]
+    }
+
+    // Enumerates the module and builds all records that refer to
+    // types, so that the type table is complete before it is written.
+    fn encode(&mut self) -> BitcodeResult<ModuleRecords> {
+        let m = self.module;
+        let mut gnames: Vec<&String> = m.globals.keys().collect();
+        gnames.sort_by(name_order);
+        let mut fnames: Vec<&String> = m.functions.keys().collect();
+        fnames.sort_by(name_order);
+        for n in gnames.iter().chain(fnames.iter()) {
+            self.global_ids.insert(&n[..],self.next_value);
+            self.next_value += 1;
+        }
+        self.globals = gnames.iter().map(|n| &n[..]).collect();
+        self.functions = fnames.iter().map(|n| &n[..]).collect();
+        let mut groups: Vec<(&u64,&Vec<Attribute>)> = m.attr_groups.iter().collect();
+        groups.sort();
+        for (g,attrs) in groups {
+            let mut ops = vec![*g,FUNCTION_INDEX];
+            ops.extend(encode_attributes(attrs)?);
+            self.groups.push(rec(3,ops));
+        }
+        // Named types in a fixed order, which also numbers the unnamed
+        // ones as before.
+        let mut tnames: Vec<&String> = m.types.keys().collect();
+        tnames.sort_by(name_order);
+        for n in tnames {
+            self.enum_type(Some(&Type::Named(n.clone())))?;
+        }
+
+        let mut globals = Vec::new();
+        for &n in &self.globals.clone() {
+            let g = &m.globals[n];
+            if let Some(ref c) = g.initialization {
+                self.enum_constant(&g.types,c)?;
+            }
+            if let Some(ref s) = g.section {
+                if !self.sections.contains(&&s[..]) {
+                    self.sections.push(s);
+                }
+            }
+        }
+        self.enum_metadata()?;
+        let module_values = self.next_value;
+        let constants = self.constant_records()?;
+        let metadata = self.metadata_records()?;
+        for &n in &self.globals.clone() {
+            let g = &m.globals[n];
+            let mut ops = self.symbol(n);
+            let flags = (g.global_type==GlobalType::Constant) as u64 | 2 | g.addr_space.unwrap_or(0) << 2;
+            let init = match g.initialization {
+                Some(ref c) => self.constant_id(&g.types,c)?+1,
+                None => 0
+            };
+            let section = match g.section {
+                Some(ref s) => self.sections.iter().position(|t| t==s).map_or(0,|i| i as u64+1),
+                None => 0
+            };
+            let tls = match g.thread_local {
+                None => 0,
+                Some(ThreadLocal::ThreadLocal) => 1,
+                Some(ThreadLocal::LocalDynamic) => 2,
+                Some(ThreadLocal::InitialExec) => 3,
+                Some(ThreadLocal::LocalExec) => 4
+            };
+            let unnamed_addr = match g.unnamed_addr {
+                None => 0,
+                Some(UnnamedAddr::UnnamedAddr) => 1,
+                Some(UnnamedAddr::LocalUnnamedAddr) => 2
+            };
+            ops.extend_from_slice(&[self.type_id(&g.types)?,flags,init,encode_linkage(g.linkage),
+                                    encode_alignment(g.alignment)?,section,encode_visibility(g.visibility),
+                                    tls,unnamed_addr,g.externally_initialized as u64,
+                                    encode_dll_storage_class(g.dll_storage_class)]);
+            globals.push(rec(7,ops));
+        }
+        for &n in &self.functions.clone() {
+            let f = &m.functions[n];
+            let mut ops = self.symbol(n);
+            let fty = self.type_id(&builder::function_type(f))?;
+            let attrs = self.attribute_list(&f.attribute_groups,f.return_type.as_ref().map(|r| &r.0),
+                                            &f.argument_attrs)?;
+            ops.extend_from_slice(&[fty,encode_calling_conv(&f.cconv),f.body.is_none() as u64,
+                                    encode_linkage(f.linkage),attrs,0,0,encode_visibility(f.visibility),
+                                    0,0,0,encode_dll_storage_class(f.dll_storage_class),0,0,0,0,0]);
+            globals.push(rec(8,ops));
+        }
+        let mut bodies = Vec::new();
+        for &n in &self.functions.clone() {
+            let f = &m.functions[n];
+            if let Some(ref body) = f.body {
+                bodies.push(self.encode_function(f,body)?);
+                self.next_value = module_values;
+            }
+        }
+        let md_strings = self.md_strings.iter().map(|md| match *md {
+            Metadata::Bytes(ref b) => b.clone(),
+            _ => Vec::new()
+        }).collect();
+        Ok(ModuleRecords { globals, constants, metadata, md_strings, bodies })
+    }
+
+    fn callee_type(&self,vals: &FnValues,callee: &Value) -> BitcodeResult<Type> {
+        match *callee {
+            Value::Constant(Constant::Global(ref n)) => self.global_type(n),
+            Value::Constant(Constant::Cast(_,_,ref tp)) => Ok(tp.clone()),
+            Value::Local(ref n) => match vals.types.get(&n[..]) {
+                Some(tp) => Ok(tp.clone()),
+                None => Err(BitcodeError::Malformed(format!("unknown value %{}",n)))
+            },
+            Value::Argument(i) => match vals.args.get(i) {
+                Some(a) => Ok(a.1.clone()),
+                None => malformed("unknown argument")
+            },
+            _ => unsupported("callee")
+        }
+    }
+
+    // The type of the function called by a call instruction.
+    fn call_type(&self,vals: &FnValues,callee: &Value) -> BitcodeResult<(Type,Option<Type>,Vec<Type>,bool)> {
+        match self.callee_type(vals,callee)? {
+            Type::Pointer(el,_) => match *el {
+                Type::Function(ref ret,ref params,va) =>
+                    Ok(((*el).clone(),ret.as_ref().map(|r| (**r).clone()),params.clone(),va)),
+                _ => malformed("call of a non-function")
+            },
+            _ => malformed("call of a non-pointer")
+        }
+    }
+
+    // The typed operands of an instruction that may be constants.
+    fn operand_values(&self,vals: &FnValues,instr: &InstructionC) -> BitcodeResult<Vec<(Type,Value)>> {
+        let mut res = Vec::new();
+        {
+            let mut push = |tp: &Type,v: &Value| res.push((tp.clone(),v.clone()));
+            match *instr {
+                InstructionC::Alloca(_,_,Some(ref size),_) => push(&size.tp,&size.val),
+                InstructionC::Alloca(_,_,None,_) =>
+                    push(&Type::Int(32),&Value::Constant(Constant::Int(BigInt::one()))),
+                InstructionC::Call(_,_,_,ref f,ref args,_) => {
+                    push(&self.callee_type(vals,f)?,f);
+                    for a in args {
+                        push(&a.tp,&a.val);
+                    }
+                },
+                InstructionC::ICmp(_,_,ref tp,ref v1,ref v2) |
+                InstructionC::Bin(_,_,ref tp,ref v1,ref v2) => {
+                    push(tp,v1);
+                    push(tp,v2);
+                },
+                InstructionC::Unary(_,ref v,_) => push(&v.tp,&v.val),
+                InstructionC::GEP(_,ref g) => {
+                    push(&g.ptr.tp,&g.ptr.val);
+                    for (idx,_) in &g.indices {
+                        push(&idx.tp,&idx.val);
+                    }
+                },
+                InstructionC::Store(_,ref v,ref p,_) => {
+                    push(&v.tp,&v.val);
+                    push(&p.tp,&p.val);
+                },
+                InstructionC::Select(_,ref c,ref tp,ref v1,ref v2) => {
+                    push(&Type::Int(1),c);
+                    push(tp,v1);
+                    push(tp,v2);
+                },
+                InstructionC::Phi(_,ref tp,ref incoming) => for (v,_) in incoming {
+                    push(tp,v);
+                },
+                InstructionC::Term(Terminator::BrC(ref c,_,_)) => push(&Type::Int(1),c),
+                InstructionC::Term(Terminator::Ret(Some(ref v))) => push(&v.tp,&v.val),
+                InstructionC::Term(Terminator::Switch(ref tp,ref v,_,ref cases)) => {
+                    push(tp,v);
+                    for (c,_) in cases {
+                        push(tp,&Value::Constant(c.clone()));
+                    }
+                },
+                InstructionC::Term(_) => {}
+            }
+        }
+        Ok(res)
+    }
+
+    fn value_id(&self,vals: &FnValues,tp: &Type,v: &Value) -> BitcodeResult<u64> {
+        match *v {
+            Value::Local(ref n) => match vals.ids.get(&n[..]) {
+                Some(&id) => Ok(id),
+                None => Err(BitcodeError::Malformed(format!("unknown value %{}",n)))
+            },
+            Value::Argument(i) if i < vals.args.len() => Ok(vals.first_arg+i as u64),
+            Value::Argument(_) => malformed("unknown argument"),
+            Value::Constant(ref c) => self.constant_id(tp,c),
+            Value::Metadata(_) => malformed("metadata used as a value")
+        }
+    }
+
+    // A value relative to the instruction number.
+    fn push_value(&self,vals: &FnValues,ops: &mut Vec<u64>,inst_num: u64,tp: &Type,v: &Value) -> BitcodeResult<()> {
+        let id = self.value_id(vals,tp,v)?;
+        ops.push((inst_num as u32).wrapping_sub(id as u32) as u64);
+        Ok(())
+    }
+
+    // A relative value followed by its type if it is a forward reference.
+    fn push_typed(&mut self,vals: &FnValues,ops: &mut Vec<u64>,inst_num: u64,tp: &Type,v: &Value) -> BitcodeResult<()> {
+        let id = self.value_id(vals,tp,v)?;
+        ops.push((inst_num as u32).wrapping_sub(id as u32) as u64);
+        if id >= inst_num {
+            ops.push(self.type_id(tp)?);
+        }
+        Ok(())
+    }
+
+    fn block_id(&self,vals: &FnValues,name: &str) -> BitcodeResult<u64> {
+        match vals.blocks.get(name) {
+            Some(&id) => Ok(id),
+            None => Err(BitcodeError::Malformed(format!("unknown block %{}",name)))
+        }
+    }
+
+    fn md_arg(&self,vals: &FnValues,md: &Metadata) -> BitcodeResult<u64> {
+        match *md {
+            Metadata::Value(ref tv) if !matches!(tv.val,Value::Constant(_)) => match vals.md.get(&**tv) {
+                Some(&id) => Ok(id),
+                None => malformed("function-local metadata not enumerated")
+            },
+            Metadata::Null => unsupported("null metadata argument"),
+            _ => self.md_id(md)
+        }
+    }
+
+    fn encode_instruction(&mut self,vals: &FnValues,instr: &InstructionC,inst_num: u64) -> BitcodeResult<Rec> {
+        let mut ops = Vec::new();
+        Ok(match *instr {
+            InstructionC::Alloca(_,ref tp,ref size,align) => {
+                let one = Typed::new(Type::Int(32),Value::Constant(Constant::Int(BigInt::one())));
+                let size = size.as_ref().unwrap_or(&one);
+                let align = encode_alignment(align)?;
+                ops.extend_from_slice(&[self.type_id(tp)?,self.type_id(&size.tp)?,
+                                        self.value_id(vals,&size.tp,&size.val)?,
+                                        (align & 0x1F) | 1 << 6 | (align >> 5) << 8]);
+                rec(19,ops)
+            },
+            InstructionC::Call(_,ref cc,ref rtp,ref f,ref args,ref groups) => {
+                let (fty,_,params,va) = self.call_type(vals,f)?;
+                let ftp = self.callee_type(vals,f)?;
+                ops.push(self.attribute_list(groups,rtp.as_ref().map(|r| &r.1),&[])?);
+                ops.push(encode_calling_conv(cc) << 1 | 1 << 15);
+                ops.push(self.type_id(&fty)?);
+                self.push_typed(vals,&mut ops,inst_num,&ftp,f)?;
+                if args.len() < params.len() || (!va && args.len() > params.len()) {
+                    return malformed("wrong number of call arguments")
+                }
+                for (i,a) in args.iter().enumerate() {
+                    match a.val {
+                        Value::Metadata(ref md) => {
+                            let id = self.md_arg(vals,md)?;
+                            ops.push((inst_num as u32).wrapping_sub(id as u32) as u64);
+                        },
+                        ref v if i < params.len() => self.push_value(vals,&mut ops,inst_num,&a.tp,v)?,
+                        ref v => self.push_typed(vals,&mut ops,inst_num,&a.tp,v)?
+                    }
+                }
+                rec(34,ops)
+            },
+            InstructionC::ICmp(_,ref op,ref tp,ref v1,ref v2) => {
+                self.push_typed(vals,&mut ops,inst_num,tp,v1)?;
+                self.push_value(vals,&mut ops,inst_num,tp,v2)?;
+                ops.push(encode_cmp_op(op));
+                rec(28,ops)
+            },
+            InstructionC::Unary(_,ref v,UnaryInst::Cast(ref tp,op)) => {
+                self.push_typed(vals,&mut ops,inst_num,&v.tp,&v.val)?;
+                ops.push(self.type_id(tp)?);
+                ops.push(encode_cast(op));
+                abbrev_rec(3,ops,&[FUNCTION_CAST_ABBREV])
+            },
+            InstructionC::Unary(_,ref v,UnaryInst::Load(vol,align)) => {
+                let tp = match v.tp {
+                    Type::Pointer(ref el,_) => (**el).clone(),
+                    _ => return malformed("load from a non-pointer")
+                };
+                self.push_typed(vals,&mut ops,inst_num,&v.tp,&v.val)?;
+                ops.extend_from_slice(&[self.type_id(&tp)?,encode_alignment(align)?,vol as u64]);
+                abbrev_rec(20,ops,&[FUNCTION_LOAD_ABBREV])
+            },
+            InstructionC::GEP(_,ref g) => {
+                let src = match g.ptr.tp {
+                    Type::Pointer(ref el,_) => (**el).clone(),
+                    _ => return unsupported("vector getelementptr")
+                };
+                ops.push(g.inbounds as u64);
+                ops.push(self.type_id(&src)?);
+                self.push_typed(vals,&mut ops,inst_num,&g.ptr.tp,&g.ptr.val)?;
+                for (idx,_) in &g.indices {
+                    self.push_typed(vals,&mut ops,inst_num,&idx.tp,&idx.val)?;
+                }
+                abbrev_rec(43,ops,&[FUNCTION_GEP_ABBREV])
+            },
+            InstructionC::Store(vol,ref v,ref p,align) => {
+                self.push_typed(vals,&mut ops,inst_num,&p.tp,&p.val)?;
+                self.push_typed(vals,&mut ops,inst_num,&v.tp,&v.val)?;
+                ops.extend_from_slice(&[encode_alignment(align)?,vol as u64]);
+                rec(44,ops)
+            },
+            InstructionC::Select(_,ref c,ref tp,ref v1,ref v2) => {
+                self.push_typed(vals,&mut ops,inst_num,tp,v1)?;
+                self.push_value(vals,&mut ops,inst_num,tp,v2)?;
+                self.push_typed(vals,&mut ops,inst_num,&Type::Int(1),c)?;
+                rec(29,ops)
+            },
+            InstructionC::Phi(_,ref tp,ref incoming) => {
+                ops.push(self.type_id(tp)?);
+                for (v,blk) in incoming {
+                    let id = self.value_id(vals,tp,v)?;
+                    ops.push(encode_signed(inst_num as i64 - id as i64));
+                    ops.push(self.block_id(vals,blk)?);
+                }
+                rec(16,ops)
+            },
+            InstructionC::Bin(_,ref op,ref tp,ref v1,ref v2) => {
+                let (opc,flags) = encode_binop(op);
+                self.push_typed(vals,&mut ops,inst_num,tp,v1)?;
+                self.push_value(vals,&mut ops,inst_num,tp,v2)?;
+                ops.push(opc);
+                if flags != 0 {
+                    ops.push(flags);
+                }
+                abbrev_rec(2,ops,&[FUNCTION_BINOP_ABBREV,FUNCTION_BINOP_FLAGS_ABBREV])
+            },
+            InstructionC::Term(Terminator::Br(ref t)) => rec(11,vec![self.block_id(vals,t)?]),
+            InstructionC::Term(Terminator::BrC(ref c,ref t,ref f)) => {
+                ops.push(self.block_id(vals,t)?);
+                ops.push(self.block_id(vals,f)?);
+                self.push_value(vals,&mut ops,inst_num,&Type::Int(1),c)?;
+                rec(11,ops)
+            },
+            InstructionC::Term(Terminator::Ret(None)) => abbrev_rec(10,ops,&[FUNCTION_RET_VOID_ABBREV]),
+            InstructionC::Term(Terminator::Ret(Some(ref v))) => {
+                self.push_typed(vals,&mut ops,inst_num,&v.tp,&v.val)?;
+                abbrev_rec(10,ops,&[FUNCTION_RET_VAL_ABBREV])
+            },
+            InstructionC::Term(Terminator::Switch(ref tp,ref v,ref def,ref cases)) => {
+                ops.push(self.type_id(tp)?);
+                self.push_value(vals,&mut ops,inst_num,tp,v)?;
+                ops.push(self.block_id(vals,def)?);
+                for (c,blk) in cases {
+                    ops.push(self.constant_id(tp,c)?);
+                    ops.push(self.block_id(vals,blk)?);
+                }
+                rec(12,ops)
+            },
+            InstructionC::Term(Terminator::Unreachable) => abbrev_rec(15,ops,&[FUNCTION_UNREACHABLE_ABBREV])
+        })
+    }
+
+    fn encode_function(&mut self,f: &'m Function,body: &'m [BasicBlock]) -> BitcodeResult<FunctionBody> {
+        let m = self.module;
+        let mut vals = FnValues { args: &f.arguments,
+                                  first_arg: self.next_value,
+                                  ids: HashMap::new(),
+                                  types: HashMap::new(),
+                                  blocks: HashMap::new(),
+                                  md: HashMap::new() };
+        let mut symbols = Vec::new();
+        for (i,(name,tp)) in f.arguments.iter().enumerate() {
+            if let Some(ref n) = *name {
+                vals.ids.insert(n,vals.first_arg+i as u64);
+                vals.types.insert(n,tp.clone());
+                if !is_unnamed(n) {
+                    let mut ops = vec![vals.first_arg+i as u64];
+                    ops.extend(chars(n.as_bytes()));
+                    symbols.push(abbrev_rec(1,ops,&[VST_ENTRY_6_ABBREV,VST_ENTRY_7_ABBREV,VST_ENTRY_8_ABBREV]));
+                }
+            }
+        }
+        for (i,blk) in body.iter().enumerate() {
+            vals.blocks.insert(&blk.name,i as u64);
+            if !is_unnamed(&blk.name) {
+                let mut ops = vec![i as u64];
+                ops.extend(chars(blk.name.as_bytes()));
+                symbols.push(abbrev_rec(2,ops,&[VST_BBENTRY_6_ABBREV,VST_ENTRY_8_ABBREV]));
+            }
+        }
+        // The types of the local values, calls are typed by their callee
+        // where possible.
+        for instr in body.iter().flat_map(|b| b.instrs.iter()) {
+            if let Some(n) = instr.content.name() {
+                let tp = match instr.content {
+                    InstructionC::Call(_,_,_,ref callee,_,_) => match self.call_type(&vals,callee) {
+                        Ok((_,ret,_,_)) => ret,
+                        Err(_) => builder::instruction_type(&m.types,&instr.content)
+                    },
+                    ref c => builder::instruction_type(&m.types,c)
+                };
+                match tp {
+                    Some(tp) => { vals.types.insert(n,tp); },
+                    None => return Err(BitcodeError::Malformed(format!("cannot determine the type of %{}",n)))
+                }
+            }
+        }
+        self.next_value += f.arguments.len() as u64;
+        for instr in body.iter().flat_map(|b| b.instrs.iter()) {
+            for (tp,v) in self.operand_values(&vals,&instr.content)? {
+                if let Value::Constant(ref c) = v {
+                    self.enum_constant(&tp,c)?;
+                }
+            }
+        }
+        let fn_consts = self.consts.clone();
+        let constants = self.constant_records()?;
+        // Value ids of the instructions.
+        let first_inst = self.next_value;
+        let mut next = first_inst;
+        let mut defines = Vec::new();
+        for instr in body.iter().flat_map(|b| b.instrs.iter()) {
+            let def = match instr.content {
+                InstructionC::Call(ref n,_,_,ref callee,_,_) => {
+                    let ret = self.call_type(&vals,callee)?.1;
+                    if n.is_some() && ret.is_none() {
+                        return malformed("named call of a void function")
+                    }
+                    ret.is_some()
+                },
+                ref c => c.name().is_some()
+            };
+            if def {
+                if let Some(n) = instr.content.name() {
+                    vals.ids.insert(n,next);
+                    if !is_unnamed(n) {
+                        let mut ops = vec![next];
+                        ops.extend(chars(n.as_bytes()));
+                        symbols.push(abbrev_rec(1,ops,&[VST_ENTRY_6_ABBREV,VST_ENTRY_7_ABBREV,VST_ENTRY_8_ABBREV]));
+                    }
+                }
+                next += 1;
+            }
+            defines.push(def);
+        }
+        // Function-local metadata, the arguments of debug intrinsics.
+        let mut metadata = Vec::new();
+        let mut next_md = self.md_ids.len() as u64;
+        for instr in body.iter().flat_map(|b| b.instrs.iter()) {
+            if let InstructionC::Call(_,_,_,_,ref args,_) = instr.content {
+                for arg in args {
+                    if let Value::Metadata(Metadata::Value(ref tv)) = arg.val {
+                        if matches!(tv.val,Value::Constant(_)) || vals.md.contains_key(&**tv) {
+                            continue
+                        }
+                        let ops = vec![self.type_id(&tv.tp)?,self.value_id(&vals,&tv.tp,&tv.val)?];
+                        metadata.push(rec(2,ops));
+                        vals.md.insert(tv,next_md);
+                        next_md += 1;
+                    }
+                }
+            }
+        }
+        let mut instrs = Vec::new();
+        let mut attachments = Vec::new();
+        let mut inst_num = first_inst;
+        let mut last_loc = None;
+        for (i,(instr,def)) in body.iter().flat_map(|b| b.instrs.iter()).zip(defines).enumerate() {
+            instrs.push(self.encode_instruction(&vals,&instr.content,inst_num)?);
+            if def {
+                inst_num += 1;
+            }
+            let mut kinds: Vec<(&String,&u64)> = instr.metadata.iter().collect();
+            kinds.sort();
+            let mut attached = vec![i as u64];
+            for (kind,&node) in kinds {
+                if kind=="dbg" {
+                    let (line,col,scope) = self.debug_location(node)?;
+                    let loc = (line,col,self.md_node_id(scope)?+1);
+                    if last_loc==Some(loc) {
+                        instrs.push(rec(33,Vec::new()));
+                    } else {
+                        instrs.push(rec(35,vec![loc.0,loc.1,loc.2,0,0]));
+                        last_loc = Some(loc);
+                    }
+                } else {
+                    let k = self.md_kinds.iter().position(|k| k==kind).expect("metadata kind");
+                    attached.push(k as u64);
+                    attached.push(self.md_id(&Metadata::Ref(node))?);
+                }
+            }
+            if attached.len() > 1 {
+                attachments.push(rec(11,attached));
+            }
+        }
+        for key in fn_consts {
+            self.const_ids.remove(&key);
+        }
+        Ok(FunctionBody { nblocks: body.len() as u64, constants, metadata, instrs, symbols, attachments })
+    }
+
+    fn type_record(&self,tp: &Option<Type>) -> Vec<Rec> {
+        let id = |tp: &Type| self.type_ids[tp];
+        let ret_id = |tp: &Option<Box<Type>>| match *tp {
+            Some(ref tp) => self.type_ids[&**tp],
+            None => self.void_id.expect("void type")
+        };
+        let tp = match *tp {
+            None => return vec![rec(2,Vec::new())],
+            Some(ref tp) => tp
+        };
+        vec![match *tp {
+            Type::Int(w) => rec(7,vec![w]),
+            Type::Float => rec(3,Vec::new()),
+            Type::Double => rec(4,Vec::new()),
+            Type::Label => rec(5,Vec::new()),
+            Type::X86_FP80 => rec(13,Vec::new()),
+            Type::FP128 => rec(14,Vec::new()),
+            Type::PPC_FP128 => rec(15,Vec::new()),
+            Type::Metadata => rec(16,Vec::new()),
+            Type::Pointer(ref el,sp) => abbrev_rec(8,vec![id(el),sp.unwrap_or(0)],&[TYPE_POINTER_ABBREV]),
+            Type::Array(n,ref el) => abbrev_rec(11,vec![n,id(el)],&[TYPE_ARRAY_ABBREV]),
+            Type::Struct(ref els) => {
+                let mut ops = vec![0];
+                ops.extend(els.iter().map(id));
+                abbrev_rec(18,ops,&[TYPE_STRUCT_ANON_ABBREV])
+            },
+            Type::Function(ref ret,ref params,va) => {
+                let mut ops = vec![va as u64,ret_id(ret)];
+                ops.extend(params.iter().map(id));
+                abbrev_rec(21,ops,&[TYPE_FUNCTION_ABBREV])
+            },
+            Type::Named(ref n) => {
+                let mut res = Vec::new();
+                if !is_unnamed(n) {
+                    res.push(abbrev_rec(19,chars(n.as_bytes()),
+                                        &[TYPE_STRUCT_NAME_6_ABBREV,TYPE_STRUCT_NAME_8_ABBREV]));
+                }
+                match self.module.types[n] {
+                    Type::Struct(ref els) => {
+                        let mut ops = vec![0];
+                        ops.extend(els.iter().map(id));
+                        res.push(abbrev_rec(20,ops,&[TYPE_STRUCT_NAMED_ABBREV]));
+                    },
+                    _ => res.push(rec(6,vec![0]))
+                }
+                return res
+            },
+            Type::Opaque => rec(6,vec![0])
+        }]
+    }
+
+    fn write(&self,module: &ModuleRecords) -> Vec<u8> {
+        use bitstream::AbbrevOp::*;
+        let m = self.module;
+        let abbrev = |ops: Vec<AbbrevOp>| Abbrev { ops };
+        let type_bits = (64-(self.types.len() as u64).leading_zeros()).max(1) as u8;
+        let mut out = StreamWriter::new();
+        out.write_bytes(b"BC\xC0\xDE");
+
+        out.enter_block(IDENTIFICATION_BLOCK_ID,5);
+        out.write_record(1,&chars(concat!("llvm-ir ",env!("CARGO_PKG_VERSION")).as_bytes()));
+        out.write_record(2,&[0]);
+        out.exit_block();
+
+        out.enter_block(MODULE_BLOCK_ID,3);
+        out.write_record(1,&[2]);
+        out.write_blockinfo(&[
+            (VALUE_SYMTAB_BLOCK_ID,abbrev(vec![Fixed(3),Vbr(8),Array,Fixed(8)])),
+            (VALUE_SYMTAB_BLOCK_ID,abbrev(vec![Literal(1),Vbr(8),Array,Fixed(7)])),
+            (VALUE_SYMTAB_BLOCK_ID,abbrev(vec![Literal(1),Vbr(8),Array,Char6])),
+            (VALUE_SYMTAB_BLOCK_ID,abbrev(vec![Literal(2),Vbr(8),Array,Char6])),
+            (CONSTANTS_BLOCK_ID,abbrev(vec![Literal(1),Fixed(type_bits)])),
+            (CONSTANTS_BLOCK_ID,abbrev(vec![Literal(4),Vbr(8)])),
+            (CONSTANTS_BLOCK_ID,abbrev(vec![Literal(11),Fixed(4),Fixed(type_bits),Vbr(8)])),
+            (CONSTANTS_BLOCK_ID,abbrev(vec![Literal(2)])),
+            (FUNCTION_BLOCK_ID,abbrev(vec![Literal(20),Vbr(6),Fixed(type_bits),Vbr(4),Fixed(1)])),
+            (FUNCTION_BLOCK_ID,abbrev(vec![Literal(2),Vbr(6),Vbr(6),Fixed(4)])),
+            (FUNCTION_BLOCK_ID,abbrev(vec![Literal(2),Vbr(6),Vbr(6),Fixed(4),Fixed(8)])),
+            (FUNCTION_BLOCK_ID,abbrev(vec![Literal(3),Vbr(6),Fixed(type_bits),Fixed(4)])),
+            (FUNCTION_BLOCK_ID,abbrev(vec![Literal(10)])),
+            (FUNCTION_BLOCK_ID,abbrev(vec![Literal(10),Vbr(6)])),
+            (FUNCTION_BLOCK_ID,abbrev(vec![Literal(15)])),
+            (FUNCTION_BLOCK_ID,abbrev(vec![Literal(43),Fixed(1),Fixed(type_bits),Array,Vbr(6)]))
+        ]);
+
+        out.enter_block(TYPE_BLOCK_ID,4);
+        out.define_abbrev(abbrev(vec![Literal(8),Fixed(type_bits),Literal(0)]));
+        out.define_abbrev(abbrev(vec![Literal(21),Fixed(1),Array,Fixed(type_bits)]));
+        out.define_abbrev(abbrev(vec![Literal(18),Fixed(1),Array,Fixed(type_bits)]));
+        out.define_abbrev(abbrev(vec![Literal(19),Array,Char6]));
+        out.define_abbrev(abbrev(vec![Literal(19),Array,Fixed(8)]));
+        out.define_abbrev(abbrev(vec![Literal(20),Fixed(1),Array,Fixed(type_bits)]));
+        out.define_abbrev(abbrev(vec![Literal(11),Vbr(8),Fixed(type_bits)]));
+        out.write_record(1,&[self.types.len() as u64]);
+        for tp in &self.types {
+            for r in self.type_record(tp) {
+                emit(&mut out,&r);
+            }
+        }
+        out.exit_block();
+
+        if !self.groups.is_empty() {
+            out.enter_block(PARAMATTR_GROUP_BLOCK_ID,3);
+            for r in &self.groups {
+                emit(&mut out,r);
+            }
+            out.exit_block();
+            out.enter_block(PARAMATTR_BLOCK_ID,3);
+            for list in &self.attr_lists {
+                out.write_record(2,list);
+            }
+            out.exit_block();
+        }
+
+        if let Some(ref triple) = m.triple {
+            out.write_record(2,&chars(triple.as_bytes()));
+        }
+        let layout = m.datalayout.to_string();
+        if !layout.is_empty() {
+            out.write_record(3,&chars(layout.as_bytes()));
+        }
+        for s in &self.sections {
+            out.write_record(5,&chars(s.as_bytes()));
+        }
+        for r in &module.globals {
+            emit(&mut out,r);
+        }
+        if !module.constants.is_empty() {
+            out.enter_block(CONSTANTS_BLOCK_ID,4);
+            for r in &module.constants {
+                emit(&mut out,r);
+            }
+            out.exit_block();
+        }
+        if !self.md_kinds.is_empty() {
+            out.enter_block(METADATA_KIND_BLOCK_ID,3);
+            for (i,k) in self.md_kinds.iter().enumerate() {
+                let mut ops = vec![i as u64];
+                ops.extend(chars(k.as_bytes()));
+                out.write_record(6,&ops);
+            }
+            out.exit_block();
+        }
+        if !module.metadata.is_empty() || !module.md_strings.is_empty() {
+            out.enter_block(METADATA_BLOCK_ID,3);
+            out.define_abbrev(abbrev(vec![Literal(35),Vbr(6),Vbr(6),Blob]));
+            out.define_abbrev(abbrev(vec![Literal(7),Fixed(1),Vbr(6),Vbr(8),Vbr(6),Vbr(6),Fixed(1)]));
+            out.define_abbrev(abbrev(vec![Literal(4),Array,Fixed(8)]));
+            if !module.md_strings.is_empty() {
+                let mut lengths = BitWriter::new();
+                for s in &module.md_strings {
+                    lengths.write_vbr(s.len() as u64,6);
+                }
+                lengths.align32();
+                let mut blob = lengths.into_bytes();
+                let offset = blob.len() as u64;
+                for s in &module.md_strings {
+                    blob.extend_from_slice(s);
+                }
+                out.write_abbrev_record(METADATA_STRINGS_ABBREV,35,&[module.md_strings.len() as u64,offset],Some(&blob))
+                    .expect("metadata strings abbreviation");
+            }
+            for r in &module.metadata {
+                emit(&mut out,r);
+            }
+            out.exit_block();
+        }
+
+        for body in &module.bodies {
+            out.enter_block(FUNCTION_BLOCK_ID,4);
+            out.write_record(1,&[body.nblocks]);
+            let blocks = [(CONSTANTS_BLOCK_ID,&body.constants),(METADATA_BLOCK_ID,&body.metadata)];
+            for &(id,recs) in &blocks {
+                if !recs.is_empty() {
+                    out.enter_block(id,4);
+                    for r in recs {
+                        emit(&mut out,r);
+                    }
+                    out.exit_block();
+                }
+            }
+            for r in &body.instrs {
+                emit(&mut out,r);
+            }
+            let blocks = [(VALUE_SYMTAB_BLOCK_ID,&body.symbols),(METADATA_ATTACHMENT_ID,&body.attachments)];
+            for &(id,recs) in &blocks {
+                if !recs.is_empty() {
+                    out.enter_block(id,4);
+                    for r in recs {
+                        emit(&mut out,r);
+                    }
+                    out.exit_block();
+                }
+            }
+            out.exit_block();
+        }
+        out.exit_block();
+
+        out.enter_block(STRTAB_BLOCK_ID,3);
+        out.define_abbrev(abbrev(vec![Literal(1),Blob]));
+        out.write_abbrev_record(STRTAB_BLOB_ABBREV,1,&[],Some(&self.strtab)).expect("string table abbreviation");
+        out.exit_block();
+        out.finish()
+    }
+}
+
+// The instructions of a block without debug intrinsics and attribute
+// groups of calls, which differ between minisat.ll and its bitcode.
+#[cfg(test)]
+fn comparable_instrs(blk: &BasicBlock) -> Vec<InstructionC> {
+    blk.instrs.iter().filter_map(|i| match i.content {
+        InstructionC::Call(_,_,_,Value::Constant(Constant::Global(ref f)),_,_)
+            if f.starts_with("llvm.dbg.") => None,
+        InstructionC::Call(ref n,ref cc,ref rtp,ref f,ref args,_) =>
+            Some(InstructionC::Call(n.clone(),cc.clone(),rtp.clone(),f.clone(),args.clone(),Vec::new())),
+        ref c => Some(c.clone())
+    }).collect()
+}
+
+#[test]
+fn test_read_minisat() {
+    let m = read_module(include_bytes!("minisat.bc")).unwrap();
+    let orig = minisat();
+    assert_eq!(m.triple,orig.triple);
+    assert_eq!(m.types,orig.types);
+    assert_eq!(m.globals,orig.globals);
+    assert_eq!(m.functions.len(),orig.functions.len());
+    for (name,f) in orig.functions.iter() {
+        let g = &m.functions[name];
+        if name.starts_with("llvm.dbg.") {
+            continue
+        }
+        assert_eq!((&f.linkage,&f.cconv,&f.return_type,&f.arguments,&f.argument_attrs,f.var_args),
+                   (&g.linkage,&g.cconv,&g.return_type,&g.arguments,&g.argument_attrs,g.var_args),
+                   "{}",name);
+        let blks1 = f.body.as_ref().map(|b| b.iter().map(|blk| (&blk.name,comparable_instrs(blk))).collect::<Vec<_>>());
+        let blks2 = g.body.as_ref().map(|b| b.iter().map(|blk| (&blk.name,comparable_instrs(blk))).collect::<Vec<_>>());
+        assert_eq!(blks1,blks2,"{}",name);
+    }
+    let main = &m.functions["main"];
+    assert!(main.attribute_groups.iter().any(|g| m.attr_groups[g].iter().any(|a| a.name=="nounwind")));
+    let mut interp = interp::Interpreter::new(&m).unwrap();
+    match interp.run_main(&["minisat","sample.cnf"]) {
+        Err(interp::ExecError::AssertionFailed(ref msg)) => assert!(msg.starts_with("solver.c:721:"),"{}",msg),
+        res => panic!("unexpected result {:?}",res)
+    }
+}
+
+#[test]
+fn test_read_sample() {
+    let m = read_module(include_bytes!("sample.bc")).unwrap();
+    let int = |n: i64| Constant::Int(BigInt::from(n));
+    assert_eq!(m.triple.as_ref().map(|t| &t[..]),Some("x86_64-unknown-linux-gnu"));
+    assert_eq!(m.datalayout.integer_alignment(64),Some((64,64)));
+    assert_eq!(m.types["struct.opaque"],Type::Opaque);
+    assert_eq!(m.globals["table"].initialization,Some(Constant::Array(vec![int(1),int(-2),int(3)])));
+    assert_eq!(m.globals["big"].initialization,Some(Constant::Int(-(BigInt::one() << 127))));
+    assert_eq!(m.globals["pairs"].initialization,
+               Some(Constant::Array(vec![Constant::Array(vec![int(0),Constant::NullPtr]); 2])));
+    assert_eq!(m.globals["0"].initialization,
+               Some(Constant::Array(vec![int(97),int(98),int(99),int(0)])));
+    assert_eq!(m.globals["tls"].thread_local,Some(ThreadLocal::InitialExec));
+    assert_eq!(m.globals["ext"].linkage,Some(Linkage::ExternWeak));
+    assert_eq!(m.globals["sec"].section.as_ref().map(|s| &s[..]),Some("mysec"));
+    match m.globals["ptr"].initialization {
+        Some(Constant::GEP(ref gep)) => {
+            assert_eq!(gep.ptr.val,Constant::Global("pairs".to_string()));
+            assert!(gep.inbounds);
+            assert_eq!(gep.indices.len(),3);
+        },
+        ref c => panic!("unexpected initializer {:?}",c)
+    }
+
+    let classify = &m.functions["classify"];
+    assert_eq!(classify.arguments.iter().map(|a| a.0.clone()).collect::<Vec<_>>(),
+               vec![Some("x".to_string()),Some("y".to_string())]);
+    assert!(classify.argument_attrs[1].signext);
+    let attrs = &m.attr_groups[&classify.attribute_groups[0]];
+    assert!(attrs.contains(&Attribute { name: "frame-pointer".to_string(),
+                                        quoted: true,
+                                        value: Some("all".to_string()) }));
+    let body = classify.body.as_ref().unwrap();
+    assert_eq!(body.iter().map(|b| &b.name[..]).collect::<Vec<_>>(),
+               vec!["entry","neg","sw","one","done"]);
+    assert_eq!(body[0].instrs[1].content,
+               InstructionC::Alloca("n".to_string(),Type::Int(32),
+                                    Some(Typed::new(Type::Int(32),Value::Argument(1))),Some(4)));
+    match body[0].instrs[2].content {
+        InstructionC::Call(None,_,None,_,ref args,_) =>
+            assert_eq!(args[0].val,Value::Metadata(Metadata::Value(Box::new(Typed::new(Type::Int(32),Value::Argument(0)))))),
+        ref c => panic!("unexpected instruction {:?}",c)
+    }
+    let cmp = &body[0].instrs[3];
+    match m.md[&cmp.metadata["dbg"]] {
+        Metadata::Location(4,7,_) => {},
+        ref md => panic!("unexpected location {:?}",md)
+    }
+    assert_eq!(ub::debug_location(&m,cmp).map(|l| l.to_string()),Some("sample.c:4:7".to_string()));
+    // Debug info nodes keep their fields.
+    let sp = m.md.values().find(|md| match **md {
+        Metadata::Node(ref kind,_,_) => kind=="DISubprogram",
+        _ => false
+    });
+    match sp {
+        Some(&Metadata::Node(_,true,ref fields)) => {
+            assert!(fields.contains(&("name".to_string(),MetadataField::Metadata(Metadata::Bytes(b"classify".to_vec())))));
+            assert!(fields.contains(&("spFlags".to_string(),MetadataField::Int(8))));
+        },
+        md => panic!("unexpected subprogram {:?}",md)
+    }
+    match m.md[&body[0].instrs[4].metadata["prof"]] {
+        Metadata::Struct(ref els) => assert_eq!(els[0],Metadata::Bytes(b"branch_weights".to_vec())),
+        ref md => panic!("unexpected metadata {:?}",md)
+    }
+    assert_eq!(body[2].instrs[0].content,
+               InstructionC::Term(Terminator::Switch(Type::Int(32),Value::Argument(0),"done".to_string(),
+                                                     vec![(int(1),"one".to_string()),
+                                                          (int(2),"one".to_string())])));
+    assert_eq!(body[4].instrs[0].content,
+               InstructionC::Phi("r".to_string(),Type::Int(32),
+                                 vec![(Value::Local("0".to_string()),"neg".to_string()),
+                                      (Value::Local("sh".to_string()),"one".to_string()),
+                                      (Value::Argument(0),"sw".to_string())]));
+    match m.named_md["llvm.module.flags"] {
+        Metadata::Struct(ref flags) => assert_eq!(flags.len(),2),
+        ref md => panic!("unexpected metadata {:?}",md)
+    }
+
+    // A use before its definition in block order.
+    let fwd = m.functions["fwd"].body.as_ref().unwrap();
+    assert_eq!(fwd[1].instrs[0].content,
+               InstructionC::Bin("u".to_string(),BinOp::Add(false,false),Type::Int(64),
+                                 Value::Local("d".to_string()),Value::Constant(int(1))));
+    // Unnamed arguments, blocks and instructions are numbered.
+    let anon = &m.functions["anon"];
+    assert_eq!(anon.arguments[0].0.as_ref().map(|n| &n[..]),Some("0"));
+    let body = anon.body.as_ref().unwrap();
+    assert_eq!(body.iter().map(|b| &b.name[..]).collect::<Vec<_>>(),vec!["1","3"]);
+    assert_eq!(body[0].instrs[0].content.name(),Some("2"));
+    // Variadic calls carry the function pointer type.
+    let exit = &m.functions["loop"].body.as_ref().unwrap()[2];
+    match exit.instrs[2].content {
+        InstructionC::Call(Some(ref n),_,Some((ref tp,_)),_,ref args,_) => {
+            assert_eq!(n,"r");
+            assert_eq!(builder::call_return_type(tp),Some(Type::Int(64)));
+            assert_eq!(args[1],Typed::new(Type::Int(32),Value::Constant(int(3))));
+        },
+        ref c => panic!("unexpected instruction {:?}",c)
+    }
+}
+
+#[test]
+fn test_read_errors() {
+    assert_eq!(read_module(b"not bitcode").err(),
+               Some(BitcodeError::Stream(BitstreamError::BadMagic)));
+    let data = include_bytes!("sample.bc");
+    assert!(read_module(&data[..data.len()/2]).is_err());
+    match parse_file("does/not/exist.bc") {
+        Err(BitcodeError::Io(_)) => {},
+        res => panic!("unexpected result {:?}",res)
+    }
+}
+
+// Truncated or corrupted files result in errors, not in panics.
+#[test]
+fn test_read_corrupted() {
+    let data = include_bytes!("sample.bc");
+    for len in 0..data.len() {
+        let _ = read_module(&data[..len]);
+    }
+    let flipped = |pos: usize,mask: u8| {
+        let mut d = data.to_vec();
+        d[pos] ^= mask;
+        read_module(&d).err()
+    };
+    assert_eq!(flipped(149,60),Some(BitcodeError::Malformed("invalid integer width".to_string())));
+    let mut m = read_module(data).unwrap();
+    let counter = m.globals.get_mut("counter").unwrap();
+    counter.types = Type::Int(1 << 30);
+    counter.initialization = None;
+    assert_eq!(read_module(&write_module(&m).unwrap()).err(),
+               Some(BitcodeError::Malformed("invalid integer width".to_string())));
+    assert_eq!(flipped(173,119),
+               Some(BitcodeError::Malformed("struct %struct.pair contains itself".to_string())));
+}
+
+// Metadata with references replaced by the nodes they refer to, as
+// metadata ids are not preserved by writing. References back to an
+// enclosing node become its depth.
+#[cfg(test)]
+fn resolved_md(m: &Module,md: &Metadata,path: &mut Vec<u64>) -> Metadata {
+    match *md {
+        Metadata::Ref(n) => match path.iter().position(|&p| p==n) {
+            Some(depth) => Metadata::Ref(depth as u64),
+            None => {
+                path.push(n);
+                let res = resolved_md(m,&m.md[&n],path);
+                path.pop();
+                res
+            }
+        },
+        Metadata::Struct(ref els) => Metadata::Struct(els.iter().map(|e| resolved_md(m,e,path)).collect()),
+        Metadata::Location(l,c,ref scope) => Metadata::Location(l,c,Box::new(resolved_md(m,scope,path))),
+        Metadata::Node(ref kind,distinct,ref fields) => Metadata::Node(kind.clone(),distinct,fields.iter().map(|(n,f)| {
+            (n.clone(),match *f {
+                MetadataField::Metadata(ref md) => MetadataField::Metadata(resolved_md(m,md,path)),
+                ref other => other.clone()
+            })
+        }).collect()),
+        ref other => other.clone()
+    }
+}
+
+// The metadata attached to each instruction of a function.
+#[cfg(test)]
+type Attached = Vec<Vec<(String,Metadata)>>;
+
+// The functions of a module, with the metadata of instructions and call
+// arguments resolved.
+#[cfg(test)]
+fn comparable_functions(m: &Module) -> Vec<(Function,Attached)> {
+    let mut names: Vec<&String> = m.functions.keys().collect();
+    names.sort();
+    names.into_iter().map(|n| {
+        let mut f = m.functions[n].clone();
+        let mut attached = Vec::new();
+        for instr in f.body.iter_mut().flat_map(|b| b.iter_mut()).flat_map(|b| b.instrs.iter_mut()) {
+            let mut md: Vec<(String,Metadata)> = instr.metadata.drain()
+                .map(|(k,n)| (k,resolved_md(m,&Metadata::Ref(n),&mut Vec::new())))
+                .collect();
+            md.sort();
+            attached.push(md);
+            if let InstructionC::Call(_,_,_,_,ref mut args,_) = instr.content {
+                for arg in args.iter_mut() {
+                    if let Value::Metadata(ref mut md) = arg.val {
+                        *md = resolved_md(m,md,&mut Vec::new());
+                    }
+                }
+            }
+        }
+        (f,attached)
+    }).collect()
+}
+
+#[cfg(test)]
+fn assert_roundtrip(m: &Module) {
+    let m2 = read_module(&write_module(m).unwrap()).unwrap();
+    assert_eq!(m2.triple,m.triple);
+    assert_eq!(m2.datalayout,m.datalayout);
+    assert_eq!(m2.types,m.types);
+    assert_eq!(m2.globals,m.globals);
+    assert_eq!(m2.attr_groups,m.attr_groups);
+    let named = |m: &Module| m.named_md.iter()
+        .map(|(k,v)| (k.clone(),resolved_md(m,v,&mut Vec::new())))
+        .collect::<HashMap<_,_>>();
+    assert_eq!(named(&m2),named(m));
+    assert_eq!(comparable_functions(&m2),comparable_functions(m));
+}
+
+#[test]
+fn test_write_roundtrip() {
+    assert_roundtrip(&read_module(include_bytes!("sample.bc")).unwrap());
+    assert_roundtrip(&read_module(include_bytes!("minisat.bc")).unwrap());
+    assert_roundtrip(&minisat());
+}
+
+// Written bitcode must be accepted by LLVM itself, checked with
+// `llvm-dis`. Run with `cargo test -- --ignored` where it is installed.
+#[test]
+#[ignore = "needs llvm-dis on PATH"]
+fn test_write_llvm_dis() {
+    use std::process::{Command,Stdio};
+    let inputs: [&[u8]; 2] = [include_bytes!("sample.bc"),include_bytes!("minisat.bc")];
+    for data in inputs.iter() {
+        let out = write_module(&read_module(data).unwrap()).unwrap();
+        let mut child = match Command::new("llvm-dis").args(["-o","/dev/null","-"])
+            .stdin(Stdio::piped()).stderr(Stdio::piped()).spawn() {
+            Ok(c) => c,
+            Err(e) => panic!("cannot run llvm-dis: {}",e)
+        };
+        child.stdin.take().unwrap().write_all(&out).unwrap();
+        let res = child.wait_with_output().unwrap();
+        let err = String::from_utf8_lossy(&res.stderr);
+        assert!(res.status.success() && err.is_empty(),"llvm-dis failed: {}",err);
+    }
+}
+
+#[test]
+fn test_write_errors() {
+    let mut m = read_module(include_bytes!("sample.bc")).unwrap();
+    m.globals.get_mut("counter").unwrap().alignment = Some(3);
+    assert_eq!(write_module(&m).err(),
+               Some(BitcodeError::Malformed("alignment 3 is not a power of two".to_string())));
+    m.globals.get_mut("counter").unwrap().alignment = None;
+    m.globals.get_mut("fptr").unwrap().initialization = Some(Constant::Global("missing".to_string()));
+    assert_eq!(write_module(&m).err(),
+               Some(BitcodeError::Malformed("unknown global @missing".to_string())));
+    m.globals.get_mut("fptr").unwrap().initialization = None;
+    m.types.insert("alias".to_string(),Type::Int(8));
+    m.globals.get_mut("counter").unwrap().types = Type::Named("alias".to_string());
+    assert_eq!(write_module(&m).err(),Some(BitcodeError::Unsupported("type alias %alias".to_string())));
+}
@@ -0,0 +1,866 @@
+//! Reader and writer for the LLVM bitstream container format.
+//!
+//! A bitstream is a sequence of nested blocks holding records. Records
+//! are either written unabbreviated, as a code and a list of VBR6
+//! operands, or through an abbreviation that fixes the encoding of
+//! every operand. Abbreviations are defined inside a block or, for all
+//! blocks with a given id, in the BLOCKINFO block; the `Cursor` keeps
+//! track of both and hands out decoded records, so the users of this
+//! module never see abbreviations. `StreamWriter` is the other
+//! direction: the caller picks the abbreviation for each record.
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fmt;
+
+/// The id of the BLOCKINFO block.
+pub const BLOCKINFO_BLOCK_ID: u32 = 0;
+
+const END_BLOCK: u64 = 0;
+const ENTER_SUBBLOCK: u64 = 1;
+const DEFINE_ABBREV: u64 = 2;
+const UNABBREV_RECORD: u64 = 3;
+
+const BLOCKINFO_CODE_SETBID: u32 = 1;
+
+const WRAPPER_MAGIC: u32 = 0x0B17_C0DE;
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum BitstreamError {
+    /// The data does not start with the `BC 0xC0DE` magic number.
+    BadMagic,
+    UnexpectedEof,
+    /// An abbreviation id that is not defined in the current block.
+    UnknownAbbrev(u64),
+    Malformed(&'static str)
+}
+
+impl fmt::Display for BitstreamError {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BitstreamError::BadMagic => write!(f,"not a bitcode file"),
+            BitstreamError::UnexpectedEof => write!(f,"unexpected end of bitstream"),
+            BitstreamError::UnknownAbbrev(id) => write!(f,"unknown abbreviation id {}",id),
+            BitstreamError::Malformed(ref msg) => write!(f,"malformed bitstream: {}",msg)
+        }
+    }
+}
+
+impl ::std::error::Error for BitstreamError {}
+
+pub type StreamResult<T> = Result<T,BitstreamError>;
+
+/// Reads fixed-width and variable-width integers from a byte buffer,
+/// least significant bit first.
+#[derive(Debug,Clone)]
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, pos: 0 }
+    }
+    /// The current position in bits.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+    pub fn at_end(&self) -> bool {
+        self.pos >= self.data.len()*8
+    }
+    pub fn seek(&mut self,pos: usize) -> StreamResult<()> {
+        if pos > self.data.len()*8 {
+            return Err(BitstreamError::UnexpectedEof)
+        }
+        self.pos = pos;
+        Ok(())
+    }
+    pub fn read(&mut self,width: u8) -> StreamResult<u64> {
+        if width > 64 {
+            return Err(BitstreamError::Malformed("fixed field wider than 64 bits"))
+        }
+        if self.pos + width as usize > self.data.len()*8 {
+            return Err(BitstreamError::UnexpectedEof)
+        }
+        let mut res = 0;
+        let mut done = 0;
+        while done < width {
+            let byte = self.data[self.pos/8] as u64;
+            let off = (self.pos%8) as u8;
+            let take = min(8-off,width-done);
+            let bits = (byte >> off) & ((1 << take)-1);
+            res |= bits << done;
+            done += take;
+            self.pos += take as usize;
+        }
+        Ok(res)
+    }
+    pub fn read_vbr(&mut self,width: u8) -> StreamResult<u64> {
+        if !(2..=32).contains(&width) {
+            return Err(BitstreamError::Malformed("invalid VBR width"))
+        }
+        let hi = 1 << (width-1);
+        let mut res = 0;
+        let mut shift = 0;
+        loop {
+            let chunk = self.read(width)?;
+            if shift < 64 {
+                res |= (chunk & (hi-1)) << shift;
+            }
+            if chunk & hi == 0 {
+                return Ok(res)
+            }
+            shift += width-1;
+            if shift >= 64+width {
+                return Err(BitstreamError::Malformed("VBR value too large"))
+            }
+        }
+    }
+    /// Skips to the next multiple of 32 bits.
+    pub fn align32(&mut self) -> StreamResult<()> {
+        let npos = self.pos.div_ceil(32)*32;
+        self.seek(npos.min(self.data.len()*8))
+    }
+    fn bytes(&mut self,len: usize) -> StreamResult<&'a [u8]> {
+        let start = self.pos/8;
+        if start+len > self.data.len() {
+            return Err(BitstreamError::UnexpectedEof)
+        }
+        self.pos += len*8;
+        Ok(&self.data[start..start+len])
+    }
+}
+
+/// Decodes a character of the 6-bit character set `[a-zA-Z0-9._]`.
+pub fn decode_char6(v: u64) -> u8 {
+    match v {
+        0..=25 => b'a'+v as u8,
+        26..=51 => b'A'+(v-26) as u8,
+        52..=61 => b'0'+(v-52) as u8,
+        62 => b'.',
+        _ => b'_'
+    }
+}
+
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum AbbrevOp {
+    Literal(u64),
+    Fixed(u8),
+    Vbr(u8),
+    /// An array of elements encoded as the following operand.
+    Array,
+    Char6,
+    Blob
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Abbrev {
+    pub ops: Vec<AbbrevOp>
+}
+
+/// A decoded record. For abbreviated records the blob operand, if
+/// any, is returned separately and not included in `ops`.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Record<'a> {
+    pub code: u32,
+    pub ops: Vec<u64>,
+    pub blob: Option<&'a [u8]>
+}
+
+impl<'a> Record<'a> {
+    /// The operands from `start` on, read as one byte each.
+    pub fn string(&self,start: usize) -> String {
+        let bytes: Vec<u8> = self.ops.iter().skip(start).map(|&c| c as u8).collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum Entry<'a> {
+    /// The start of a nested block. It has to be entered with
+    /// `Cursor::enter_block` or skipped with `Cursor::skip_block`
+    /// before the next call to `Cursor::advance`.
+    SubBlock(u32),
+    Record(Record<'a>),
+    /// The end of the current block; the cursor is back in the parent.
+    EndBlock
+}
+
+struct Scope {
+    block_id: Option<u32>,
+    abbrev_width: u8,
+    abbrevs: Vec<Abbrev>,
+    /// The end of the block in bits, `None` at the top level.
+    end: Option<usize>
+}
+
+/// Walks through the blocks and records of a bitstream.
+pub struct Cursor<'a> {
+    reader: BitReader<'a>,
+    scopes: Vec<Scope>,
+    blockinfo: HashMap<u32,Vec<Abbrev>>
+}
+
+/// Strips the optional wrapper header from bitcode and checks the
+/// magic number. Returns the bitstream following the magic.
+pub fn bitcode_stream(data: &[u8]) -> StreamResult<&[u8]> {
+    let mut data = data;
+    if data.len() >= 20 && read_u32(data,0)==WRAPPER_MAGIC {
+        let off = read_u32(data,8) as usize;
+        let size = read_u32(data,12) as usize;
+        if off.checked_add(size).is_none_or(|end| end > data.len()) {
+            return Err(BitstreamError::UnexpectedEof)
+        }
+        data = &data[off..off+size];
+    }
+    if data.len() < 4 || data[0..4]!=[b'B',b'C',0xC0,0xDE] {
+        return Err(BitstreamError::BadMagic)
+    }
+    Ok(&data[4..])
+}
+
+fn read_u32(data: &[u8],off: usize) -> u32 {
+    (data[off] as u32) | (data[off+1] as u32) << 8 |
+    (data[off+2] as u32) << 16 | (data[off+3] as u32) << 24
+}
+
+impl<'a> Cursor<'a> {
+    /// A cursor at the top level of a bitstream, without magic number.
+    pub fn new(stream: &'a [u8]) -> Cursor<'a> {
+        Cursor { reader: BitReader::new(stream),
+                 scopes: vec![Scope { block_id: None,
+                                      abbrev_width: 2,
+                                      abbrevs: Vec::new(),
+                                      end: None }],
+                 blockinfo: HashMap::new() }
+    }
+    /// A cursor for LLVM bitcode, which may be wrapped.
+    pub fn for_bitcode(data: &'a [u8]) -> StreamResult<Cursor<'a>> {
+        Ok(Cursor::new(bitcode_stream(data)?))
+    }
+    /// The id of the block the cursor is in, `None` at the top level.
+    pub fn block_id(&self) -> Option<u32> {
+        self.scopes.last().and_then(|s| s.block_id)
+    }
+    /// The current position in bits.
+    pub fn position(&self) -> usize {
+        self.reader.position()
+    }
+    /// Moves the cursor to a position returned by `position`, which
+    /// must lie in the current block.
+    pub fn seek(&mut self,pos: usize) -> StreamResult<()> {
+        self.reader.seek(pos)
+    }
+    /// The next entry of the current block, or `None` at the end of
+    /// the stream. BLOCKINFO blocks are read transparently.
+    pub fn advance(&mut self) -> StreamResult<Option<Entry<'a>>> {
+        loop {
+            let top_level = self.scopes.len()==1;
+            if top_level && self.reader.data.len()*8 - self.reader.position() < 32 {
+                // Trailing padding after the last block.
+                return Ok(None)
+            }
+            let width = self.scopes.last().expect("no scope").abbrev_width;
+            let id = self.reader.read(width)?;
+            match id {
+                END_BLOCK => {
+                    if top_level {
+                        return Err(BitstreamError::Malformed("end of block at top level"))
+                    }
+                    self.reader.align32()?;
+                    self.scopes.pop();
+                    return Ok(Some(Entry::EndBlock))
+                },
+                ENTER_SUBBLOCK => {
+                    let block_id = self.reader.read_vbr(8)? as u32;
+                    if block_id==BLOCKINFO_BLOCK_ID {
+                        self.read_blockinfo()?;
+                        continue
+                    }
+                    return Ok(Some(Entry::SubBlock(block_id)))
+                },
+                DEFINE_ABBREV => {
+                    let abbrev = self.read_abbrev()?;
+                    self.scopes.last_mut().expect("no scope").abbrevs.push(abbrev);
+                },
+                _ => return Ok(Some(Entry::Record(self.read_record(id)?)))
+            }
+        }
+    }
+    /// Enters the block announced by `Entry::SubBlock`.
+    pub fn enter_block(&mut self,block_id: u32) -> StreamResult<()> {
+        let width = self.reader.read_vbr(4)?;
+        if width==0 || width > 32 {
+            return Err(BitstreamError::Malformed("invalid abbreviation width"))
+        }
+        self.reader.align32()?;
+        let words = self.reader.read(32)? as usize;
+        let end = self.reader.position() + words*32;
+        if end > self.reader.data.len()*8 {
+            return Err(BitstreamError::UnexpectedEof)
+        }
+        let abbrevs = self.blockinfo.get(&block_id).cloned().unwrap_or_default();
+        self.scopes.push(Scope { block_id: Some(block_id),
+                                 abbrev_width: width as u8,
+                                 abbrevs,
+                                 end: Some(end) });
+        Ok(())
+    }
+    /// Skips the block announced by `Entry::SubBlock`.
+    pub fn skip_block(&mut self) -> StreamResult<()> {
+        self.reader.read_vbr(4)?;
+        self.reader.align32()?;
+        let words = self.reader.read(32)? as usize;
+        let end = self.reader.position() + words*32;
+        self.reader.seek(end)
+    }
+    /// Skips the rest of the current block.
+    pub fn exit_block(&mut self) -> StreamResult<()> {
+        match self.scopes.pop() {
+            Some(Scope { end: Some(end), .. }) => self.reader.seek(end),
+            _ => Err(BitstreamError::Malformed("no block to exit"))
+        }
+    }
+    /// Reads all records of the current block, skipping nested blocks,
+    /// up to and including its end.
+    pub fn records(&mut self) -> StreamResult<Vec<Record<'a>>> {
+        let mut res = Vec::new();
+        loop {
+            match self.advance()? {
+                Some(Entry::Record(r)) => res.push(r),
+                Some(Entry::SubBlock(_)) => self.skip_block()?,
+                Some(Entry::EndBlock) => return Ok(res),
+                None => return Err(BitstreamError::UnexpectedEof)
+            }
+        }
+    }
+    fn read_abbrev(&mut self) -> StreamResult<Abbrev> {
+        let num = self.reader.read_vbr(5)?;
+        let mut ops = Vec::new();
+        let mut i = 0;
+        while i < num {
+            if self.reader.read(1)?==1 {
+                ops.push(AbbrevOp::Literal(self.reader.read_vbr(8)?));
+            } else {
+                ops.push(match self.reader.read(3)? {
+                    1 => AbbrevOp::Fixed(self.reader.read_vbr(5)? as u8),
+                    2 => AbbrevOp::Vbr(self.reader.read_vbr(5)? as u8),
+                    3 => AbbrevOp::Array,
+                    4 => AbbrevOp::Char6,
+                    5 => AbbrevOp::Blob,
+                    _ => return Err(BitstreamError::Malformed("unknown operand encoding"))
+                });
+            }
+            i += 1;
+        }
+        for (i,op) in ops.iter().enumerate() {
+            match *op {
+                AbbrevOp::Array if i+2!=ops.len() =>
+                    return Err(BitstreamError::Malformed("array must be the second to last operand")),
+                AbbrevOp::Blob if i+1!=ops.len() =>
+                    return Err(BitstreamError::Malformed("blob must be the last operand")),
+                AbbrevOp::Vbr(w) if w < 2 && w!=0 =>
+                    return Err(BitstreamError::Malformed("invalid VBR width")),
+                _ => {}
+            }
+        }
+        if ops.is_empty() {
+            return Err(BitstreamError::Malformed("empty abbreviation"))
+        }
+        Ok(Abbrev { ops })
+    }
+    fn read_scalar(&mut self,op: AbbrevOp) -> StreamResult<u64> {
+        match op {
+            AbbrevOp::Literal(v) => Ok(v),
+            AbbrevOp::Fixed(w) => self.reader.read(w),
+            AbbrevOp::Vbr(0) => Ok(0),
+            AbbrevOp::Vbr(w) => self.reader.read_vbr(w),
+            AbbrevOp::Char6 => self.reader.read(6).map(|v| decode_char6(v) as u64),
+            AbbrevOp::Array | AbbrevOp::Blob =>
+                Err(BitstreamError::Malformed("invalid array element encoding"))
+        }
+    }
+    fn read_record(&mut self,id: u64) -> StreamResult<Record<'a>> {
+        if id==UNABBREV_RECORD {
+            let code = self.reader.read_vbr(6)? as u32;
+            let num = self.reader.read_vbr(6)?;
+            let mut ops = Vec::new();
+            for _ in 0..num {
+                ops.push(self.reader.read_vbr(6)?);
+            }
+            return Ok(Record { code, ops, blob: None })
+        }
+        let abbrev = {
+            let scope = self.scopes.last().expect("no scope");
+            match scope.abbrevs.get((id-4) as usize) {
+                Some(a) => a.clone(),
+                None => return Err(BitstreamError::UnknownAbbrev(id))
+            }
+        };
+        let mut vals = Vec::new();
+        let mut blob = None;
+        let mut i = 0;
+        while i < abbrev.ops.len() {
+            match abbrev.ops[i] {
+                AbbrevOp::Array => {
+                    let len = self.reader.read_vbr(6)?;
+                    let elem = abbrev.ops[i+1];
+                    for _ in 0..len {
+                        vals.push(self.read_scalar(elem)?);
+                    }
+                    i += 2;
+                    continue
+                },
+                AbbrevOp::Blob => {
+                    let len = self.reader.read_vbr(6)? as usize;
+                    self.reader.align32()?;
+                    blob = Some(self.reader.bytes(len)?);
+                    self.reader.align32()?;
+                },
+                op => vals.push(self.read_scalar(op)?)
+            }
+            i += 1;
+        }
+        if vals.is_empty() {
+            return Err(BitstreamError::Malformed("abbreviated record without code"))
+        }
+        let code = vals.remove(0) as u32;
+        Ok(Record { code, ops: vals, blob })
+    }
+    fn read_blockinfo(&mut self) -> StreamResult<()> {
+        self.enter_block(BLOCKINFO_BLOCK_ID)?;
+        let mut current = None;
+        loop {
+            let width = self.scopes.last().expect("no scope").abbrev_width;
+            match self.reader.read(width)? {
+                END_BLOCK => {
+                    self.reader.align32()?;
+                    self.scopes.pop();
+                    return Ok(())
+                },
+                ENTER_SUBBLOCK => {
+                    self.reader.read_vbr(8)?;
+                    self.skip_block()?;
+                },
+                DEFINE_ABBREV => {
+                    let abbrev = self.read_abbrev()?;
+                    match current {
+                        Some(bid) => self.blockinfo.entry(bid).or_default().push(abbrev),
+                        None => return Err(BitstreamError::Malformed("abbreviation before SETBID"))
+                    }
+                },
+                id => {
+                    let rec = self.read_record(id)?;
+                    if rec.code==BLOCKINFO_CODE_SETBID {
+                        match rec.ops.first() {
+                            Some(&bid) => current = Some(bid as u32),
+                            None => return Err(BitstreamError::Malformed("SETBID without block id"))
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Encodes a character of the 6-bit character set `[a-zA-Z0-9._]`.
+pub fn encode_char6(c: u8) -> Option<u64> {
+    match c {
+        b'a'..=b'z' => Some((c-b'a') as u64),
+        b'A'..=b'Z' => Some((c-b'A') as u64 + 26),
+        b'0'..=b'9' => Some((c-b'0') as u64 + 52),
+        b'.' => Some(62),
+        b'_' => Some(63),
+        _ => None
+    }
+}
+
+/// Writes fixed-width and variable-width integers to a byte buffer,
+/// least significant bit first.
+#[derive(Debug,Clone,Default)]
+pub struct BitWriter {
+    data: Vec<u8>,
+    pos: usize
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter { data: Vec::new(), pos: 0 }
+    }
+    /// The current position in bits.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+    pub fn write(&mut self,val: u64,width: u8) {
+        let mut done = 0;
+        while done < width {
+            if self.pos.is_multiple_of(8) {
+                self.data.push(0);
+            }
+            let off = (self.pos%8) as u8;
+            let take = min(8-off,width-done);
+            let bits = (val >> done) & ((1 << take)-1);
+            *self.data.last_mut().expect("no byte") |= (bits << off) as u8;
+            done += take;
+            self.pos += take as usize;
+        }
+    }
+    pub fn write_vbr(&mut self,val: u64,width: u8) {
+        let hi = 1 << (width-1);
+        let mut v = val;
+        while v >= hi {
+            self.write((v & (hi-1)) | hi,width);
+            v >>= width-1;
+        }
+        self.write(v,width);
+    }
+    /// Pads with zeros up to the next multiple of 32 bits.
+    pub fn align32(&mut self) {
+        let npos = self.pos.div_ceil(32)*32;
+        self.data.resize(npos/8,0);
+        self.pos = npos;
+    }
+    /// Appends bytes at a byte boundary.
+    pub fn write_bytes(&mut self,bytes: &[u8]) {
+        let npos = self.pos.div_ceil(8)*8;
+        self.data.resize(npos/8,0);
+        self.data.extend_from_slice(bytes);
+        self.pos = npos + bytes.len()*8;
+    }
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+struct WriteScope {
+    abbrev_width: u8,
+    abbrevs: Vec<Abbrev>,
+    /// Byte offset of the length word of the block.
+    len_pos: usize
+}
+
+/// Writes blocks and records of a bitstream.
+pub struct StreamWriter {
+    writer: BitWriter,
+    scopes: Vec<WriteScope>,
+    blockinfo: HashMap<u32,Vec<Abbrev>>
+}
+
+impl Default for StreamWriter {
+    fn default() -> StreamWriter {
+        StreamWriter::new()
+    }
+}
+
+impl StreamWriter {
+    /// A writer at the top level of an empty stream.
+    pub fn new() -> StreamWriter {
+        StreamWriter { writer: BitWriter::new(),
+                       scopes: vec![WriteScope { abbrev_width: 2,
+                                                 abbrevs: Vec::new(),
+                                                 len_pos: 0 }],
+                       blockinfo: HashMap::new() }
+    }
+    /// Writes raw bytes, such as a magic number, at the top level.
+    pub fn write_bytes(&mut self,bytes: &[u8]) {
+        self.writer.write_bytes(bytes)
+    }
+    fn abbrev_width(&self) -> u8 {
+        self.scopes.last().expect("no scope").abbrev_width
+    }
+    /// Starts a block whose abbreviation ids are `abbrev_width` bits
+    /// wide. The abbreviations of the BLOCKINFO block for `block_id`
+    /// are available in it.
+    pub fn enter_block(&mut self,block_id: u32,abbrev_width: u8) {
+        let width = self.abbrev_width();
+        self.writer.write(ENTER_SUBBLOCK,width);
+        self.writer.write_vbr(block_id as u64,8);
+        self.writer.write_vbr(abbrev_width as u64,4);
+        self.writer.align32();
+        let len_pos = self.writer.position()/8;
+        self.writer.write(0,32);
+        let abbrevs = self.blockinfo.get(&block_id).cloned().unwrap_or_default();
+        self.scopes.push(WriteScope { abbrev_width, abbrevs, len_pos });
+    }
+    /// Ends the current block.
+    pub fn exit_block(&mut self) {
+        let width = self.abbrev_width();
+        self.writer.write(END_BLOCK,width);
+        self.writer.align32();
+        let scope = self.scopes.pop().expect("no block to exit");
+        let words = ((self.writer.position()/8 - scope.len_pos - 4)/4) as u32;
+        self.writer.data[scope.len_pos..scope.len_pos+4].copy_from_slice(&words.to_le_bytes());
+    }
+    fn write_abbrev_def(&mut self,abbrev: &Abbrev) {
+        self.writer.write_vbr(abbrev.ops.len() as u64,5);
+        for op in &abbrev.ops {
+            match *op {
+                AbbrevOp::Literal(v) => {
+                    self.writer.write(1,1);
+                    self.writer.write_vbr(v,8);
+                },
+                AbbrevOp::Fixed(w) => {
+                    self.writer.write(1 << 1,4);
+                    self.writer.write_vbr(w as u64,5);
+                },
+                AbbrevOp::Vbr(w) => {
+                    self.writer.write(2 << 1,4);
+                    self.writer.write_vbr(w as u64,5);
+                },
+                AbbrevOp::Array => self.writer.write(3 << 1,4),
+                AbbrevOp::Char6 => self.writer.write(4 << 1,4),
+                AbbrevOp::Blob => self.writer.write(5 << 1,4)
+            }
+        }
+    }
+    /// Defines an abbreviation for the rest of the current block and
+    /// returns its id.
+    pub fn define_abbrev(&mut self,abbrev: Abbrev) -> u64 {
+        let width = self.abbrev_width();
+        self.writer.write(DEFINE_ABBREV,width);
+        self.write_abbrev_def(&abbrev);
+        let scope = self.scopes.last_mut().expect("no scope");
+        scope.abbrevs.push(abbrev);
+        (scope.abbrevs.len()+3) as u64
+    }
+    /// Writes a BLOCKINFO block defining abbreviations for other
+    /// blocks. Returns the ids the abbreviations will have in their
+    /// blocks, in the given order.
+    pub fn write_blockinfo(&mut self,abbrevs: &[(u32,Abbrev)]) -> Vec<u64> {
+        let mut ids = Vec::with_capacity(abbrevs.len());
+        self.enter_block(BLOCKINFO_BLOCK_ID,2);
+        let mut current = None;
+        for &(block_id,ref abbrev) in abbrevs {
+            if current != Some(block_id) {
+                self.write_record(BLOCKINFO_CODE_SETBID,&[block_id as u64]);
+                current = Some(block_id);
+            }
+            self.writer.write(DEFINE_ABBREV,2);
+            self.write_abbrev_def(abbrev);
+            let defs = self.blockinfo.entry(block_id).or_default();
+            defs.push(abbrev.clone());
+            ids.push((defs.len()+3) as u64);
+        }
+        self.exit_block();
+        ids
+    }
+    /// Writes an unabbreviated record.
+    pub fn write_record(&mut self,code: u32,ops: &[u64]) {
+        let width = self.abbrev_width();
+        self.writer.write(UNABBREV_RECORD,width);
+        self.writer.write_vbr(code as u64,6);
+        self.writer.write_vbr(ops.len() as u64,6);
+        for &op in ops {
+            self.writer.write_vbr(op,6);
+        }
+    }
+    fn check_scalar(op: AbbrevOp,val: u64) -> StreamResult<()> {
+        match op {
+            AbbrevOp::Literal(v) if v!=val => Err(BitstreamError::Malformed("operand differs from literal")),
+            AbbrevOp::Fixed(w) if w < 64 && val >> w != 0 =>
+                Err(BitstreamError::Malformed("operand too wide for fixed field")),
+            AbbrevOp::Char6 if val > 255 || encode_char6(val as u8).is_none() =>
+                Err(BitstreamError::Malformed("operand is not a char6 character")),
+            AbbrevOp::Array | AbbrevOp::Blob => Err(BitstreamError::Malformed("invalid array element encoding")),
+            _ => Ok(())
+        }
+    }
+    fn write_scalar(&mut self,op: AbbrevOp,val: u64) {
+        match op {
+            AbbrevOp::Fixed(w) => self.writer.write(val,w),
+            AbbrevOp::Vbr(w) => self.writer.write_vbr(val,w),
+            AbbrevOp::Char6 => self.writer.write(encode_char6(val as u8).unwrap_or(0),6),
+            _ => {}
+        }
+    }
+    /// Writes a record with the abbreviation `id`. A blob operand of
+    /// the abbreviation takes `blob`. Nothing is written if the
+    /// operands do not fit the abbreviation.
+    pub fn write_abbrev_record(&mut self,id: u64,code: u32,ops: &[u64],blob: Option<&[u8]>) -> StreamResult<()> {
+        let abbrev = match self.scopes.last().expect("no scope").abbrevs.get((id as usize).wrapping_sub(4)) {
+            Some(a) => a.clone(),
+            None => return Err(BitstreamError::UnknownAbbrev(id))
+        };
+        let vals: Vec<u64> = Some(code as u64).into_iter().chain(ops.iter().cloned()).collect();
+        // Pair every scalar operand of the abbreviation with its value;
+        // an array takes all remaining values.
+        let mut fields = Vec::new();
+        let mut pos = 0;
+        for (i,&op) in abbrev.ops.iter().enumerate() {
+            match op {
+                AbbrevOp::Array => {
+                    let elem = abbrev.ops[i+1];
+                    for &v in &vals[pos..] {
+                        Self::check_scalar(elem,v)?;
+                    }
+                    fields.push((op,(vals.len()-pos) as u64));
+                    pos = vals.len();
+                    break
+                },
+                AbbrevOp::Blob => fields.push((op,0)),
+                _ => match vals.get(pos) {
+                    Some(&v) => {
+                        Self::check_scalar(op,v)?;
+                        fields.push((op,v));
+                        pos += 1;
+                    },
+                    None => return Err(BitstreamError::Malformed("too few operands for abbreviation"))
+                }
+            }
+        }
+        if pos < vals.len() {
+            return Err(BitstreamError::Malformed("too many operands for abbreviation"))
+        }
+        let width = self.abbrev_width();
+        self.writer.write(id,width);
+        for &(op,v) in &fields {
+            match op {
+                AbbrevOp::Array => {
+                    self.writer.write_vbr(v,6);
+                    let elem = abbrev.ops[abbrev.ops.len()-1];
+                    for &e in &vals[vals.len()-v as usize..] {
+                        self.write_scalar(elem,e);
+                    }
+                },
+                AbbrevOp::Blob => {
+                    let blob = blob.unwrap_or(&[]);
+                    self.writer.write_vbr(blob.len() as u64,6);
+                    self.writer.align32();
+                    self.writer.write_bytes(blob);
+                    self.writer.align32();
+                },
+                _ => self.write_scalar(op,v)
+            }
+        }
+        Ok(())
+    }
+    /// The written stream. All blocks must have been exited.
+    pub fn finish(self) -> Vec<u8> {
+        assert_eq!(self.scopes.len(),1,"unterminated block");
+        self.writer.into_bytes()
+    }
+}
+
+#[test]
+fn test_bit_reader() {
+    let data = [0b1010_1101,0xff,0x01];
+    let mut r = BitReader::new(&data);
+    assert_eq!(r.read(3),Ok(0b101));
+    assert_eq!(r.read(5),Ok(0b10101));
+    // 0xff,0x01 as VBR4: 0b1111 (7, more) 0b1111 (7, more) 0b0001
+    assert_eq!(r.read_vbr(4),Ok(7 | 7 << 3 | 1 << 6));
+    assert_eq!(r.read(4),Ok(0));
+    assert_eq!(r.read(1),Err(BitstreamError::UnexpectedEof));
+    assert_eq!(decode_char6(0),b'a');
+    assert_eq!(decode_char6(27),b'B');
+    assert_eq!(decode_char6(53),b'1');
+    assert_eq!(decode_char6(63),b'_');
+}
+
+#[test]
+fn test_cursor() {
+    let mut b = BitWriter::new();
+    // BLOCKINFO defining a char6 array abbreviation for block 8.
+    b.write(1,2); b.write_vbr(0,8); b.write_vbr(2,4); b.align32();
+    let len_pos = b.position()/8; b.write(0,32);
+    let start = b.position();
+    b.write(3,2); b.write_vbr(1,6); b.write_vbr(1,6); b.write_vbr(8,6);
+    b.write(2,2); b.write_vbr(3,5);
+    b.write(1,1); b.write_vbr(4,8);
+    b.write(0,1); b.write(3,3);
+    b.write(0,1); b.write(4,3);
+    b.write(0,2); b.align32();
+    let words = ((b.position()-start)/32) as u32;
+    b.data[len_pos..len_pos+4].copy_from_slice(&words.to_le_bytes());
+    // Block 8 with a local abbreviation, three records and an
+    // empty nested block.
+    b.write(1,2); b.write_vbr(8,8); b.write_vbr(3,4); b.align32();
+    let len_pos = b.position()/8; b.write(0,32);
+    let start = b.position();
+    b.write(2,3); b.write_vbr(3,5);
+    b.write(1,1); b.write_vbr(7,8);
+    b.write(0,1); b.write(1,3); b.write_vbr(3,5);
+    b.write(0,1); b.write(5,3);
+    b.write(3,3); b.write_vbr(1,6); b.write_vbr(2,6); b.write_vbr(100,6); b.write_vbr(5,6);
+    // abbrev 4 (from BLOCKINFO): "ab"
+    b.write(4,3); b.write_vbr(2,6); b.write(0,6); b.write(1,6);
+    // abbrev 5 (local): code 7, fixed 5, blob "xyz"
+    b.write(5,3); b.write(5,3); b.write_vbr(3,6); b.align32();
+    b.write(b'x' as u64,8); b.write(b'y' as u64,8); b.write(b'z' as u64,8); b.align32();
+    b.write(1,3); b.write_vbr(9,8); b.write_vbr(2,4); b.align32(); b.write(1,32); b.write(0,2); b.align32();
+    b.write(0,3); b.align32();
+    let words = ((b.position()-start)/32) as u32;
+    b.data[len_pos..len_pos+4].copy_from_slice(&words.to_le_bytes());
+
+    let mut c = Cursor::new(&b.data);
+    assert_eq!(c.advance(),Ok(Some(Entry::SubBlock(8))));
+    c.enter_block(8).unwrap();
+    assert_eq!(c.block_id(),Some(8));
+    assert_eq!(c.advance(),Ok(Some(Entry::Record(Record { code: 1,
+                                                           ops: vec![100,5],
+                                                           blob: None }))));
+    let r = match c.advance() {
+        Ok(Some(Entry::Record(r))) => r,
+        e => panic!("expected record, got {:?}",e)
+    };
+    assert_eq!((r.code,r.string(0)),(4,"ab".to_string()));
+    assert_eq!(c.advance(),Ok(Some(Entry::Record(Record { code: 7,
+                                                           ops: vec![5],
+                                                           blob: Some(&b"xyz"[..]) }))));
+    assert_eq!(c.advance(),Ok(Some(Entry::SubBlock(9))));
+    c.skip_block().unwrap();
+    assert_eq!(c.advance(),Ok(Some(Entry::EndBlock)));
+    assert_eq!(c.block_id(),None);
+    assert_eq!(c.advance(),Ok(None));
+}
+
+#[test]
+fn test_stream_writer() {
+    let mut w = StreamWriter::new();
+    w.write_bytes(b"BC\xc0\xde");
+    let ids = w.write_blockinfo(&[(8,Abbrev { ops: vec![AbbrevOp::Literal(4),AbbrevOp::Array,AbbrevOp::Char6] })]);
+    assert_eq!(ids,vec![4]);
+    w.enter_block(8,3);
+    w.write_record(1,&[100,5]);
+    w.write_abbrev_record(4,4,&[b'a' as u64,b'b' as u64],None).unwrap();
+    let blob = w.define_abbrev(Abbrev { ops: vec![AbbrevOp::Literal(7),AbbrevOp::Fixed(3),AbbrevOp::Blob] });
+    assert_eq!(blob,5);
+    w.write_abbrev_record(blob,7,&[5],Some(b"xyz")).unwrap();
+    assert!(w.write_abbrev_record(blob,7,&[8],None).is_err());
+    assert!(w.write_abbrev_record(4,4,&[b'-' as u64],None).is_err());
+    assert_eq!(w.write_abbrev_record(6,1,&[],None),Err(BitstreamError::UnknownAbbrev(6)));
+    w.enter_block(9,2);
+    w.exit_block();
+    w.exit_block();
+    let data = w.finish();
+
+    let mut c = Cursor::for_bitcode(&data).unwrap();
+    assert_eq!(c.advance(),Ok(Some(Entry::SubBlock(8))));
+    c.enter_block(8).unwrap();
+    assert_eq!(c.advance(),Ok(Some(Entry::Record(Record { code: 1,
+                                                           ops: vec![100,5],
+                                                           blob: None }))));
+    let r = match c.advance() {
+        Ok(Some(Entry::Record(r))) => r,
+        e => panic!("expected record, got {:?}",e)
+    };
+    assert_eq!((r.code,r.string(0)),(4,"ab".to_string()));
+    assert_eq!(c.advance(),Ok(Some(Entry::Record(Record { code: 7,
+                                                           ops: vec![5],
+                                                           blob: Some(&b"xyz"[..]) }))));
+    assert_eq!(c.advance(),Ok(Some(Entry::SubBlock(9))));
+    c.enter_block(9).unwrap();
+    assert_eq!(c.advance(),Ok(Some(Entry::EndBlock)));
+    assert_eq!(c.advance(),Ok(Some(Entry::EndBlock)));
+    assert_eq!(c.advance(),Ok(None));
+    assert_eq!(encode_char6(b'B').map(decode_char6),Some(b'B'));
+}
+
+#[test]
+fn test_magic() {
+    assert_eq!(bitcode_stream(b"BC\xc0\xde\x35\x14"),Ok(&b"\x35\x14"[..]));
+    assert_eq!(bitcode_stream(b"\x7fELF"),Err(BitstreamError::BadMagic));
+    let mut wrapped = vec![0xde,0xc0,0x17,0x0b,0,0,0,0,20,0,0,0,6,0,0,0,7,0,0,1];
+    wrapped.extend_from_slice(b"BC\xc0\xde\x35\x14");
+    assert_eq!(bitcode_stream(&wrapped),Ok(&b"\x35\x14"[..]));
+}
@@ -0,0 +1,508 @@
+//! Bounded model checking.
+//!
+//! A function is translated into a single formula. Loops are unrolled
+//! up to a bound and calls to defined functions are inlined up to a
+//! depth, which turns the control-flow graph into an acyclic graph of
+//! block instances. These are encoded in topological order: every
+//! instance gets a guard, the condition under which it is executed,
+//! and values and memory are merged with `ite` where control flow
+//! joins.
+//!
+//! The targets are calls to `__assert_fail` or `__VERIFIER_error` and
+//! `unreachable` terminators. The formula asserts that one of them is
+//! reached, it is satisfiable iff a target is reachable within the
+//! bounds. Values and memory are translated by `symex::Lowering`.
+use std::collections::{HashMap,HashSet};
+use std::io;
+use std::io::Write;
+use std::rc::Rc;
+use super::*;
+use cfg::{ControlFlowGraph,DominatorTree};
+use loops::{LoopInfo,LoopId};
+use smt::{self,Term,Solver,SatResult};
+use symex::{Lowering,Memory,Allocator,Effect,PathEnd,SymError,SymResult};
+use ub::{self,DebugLocation};
+
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum TargetKind {
+    /// A call to `__assert_fail`.
+    AssertFail,
+    /// A call to `__VERIFIER_error`.
+    VerifierError,
+    Unreachable
+}
+
+/// A reachable target instruction.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Target {
+    pub kind: TargetKind,
+    pub function: String,
+    pub block: String,
+    pub location: Option<DebugLocation>,
+    /// The condition under which the target is reached.
+    pub condition: Term
+}
+
+/// The encoding of a function.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Encoding {
+    /// All targets whose condition does not simplify to `false`.
+    pub targets: Vec<Target>,
+    /// Holds for executions that exceed the unwinding bound or call
+    /// functions deeper than the inlining depth. The former are not
+    /// covered by the encoding, the latter calls are treated like calls
+    /// to functions without a body.
+    pub unwound: Term
+}
+
+impl Encoding {
+    /// The condition that some target is reached.
+    pub fn reachable(&self) -> Term {
+        Term::or(self.targets.iter().map(|t| t.condition.clone()).collect())
+    }
+    /// An SMT-LIB2 script that is satisfiable iff a target is reachable.
+    pub fn smtlib(&self) -> String {
+        smt::script("QF_ABV",&[self.reachable()])
+    }
+    /// An SMT-LIB2 script for the reachability of a single target.
+    pub fn target_smtlib(&self,idx: usize) -> String {
+        smt::script("QF_ABV",&[self.targets[idx].condition.clone()])
+    }
+    pub fn write_smtlib<W: Write>(&self,out: &mut W) -> io::Result<()> {
+        out.write_all(self.smtlib().as_bytes())
+    }
+    pub fn check(&self,solver: &Solver) -> io::Result<SatResult> {
+        solver.check(&self.smtlib())
+    }
+}
+
+pub struct Bmc<'m> {
+    lowering: Lowering<'m>,
+    initial_memory: Memory,
+    unwind: usize,
+    max_depth: usize
+}
+
+impl<'m> Bmc<'m> {
+    pub fn new(module: &'m Module) -> SymResult<Bmc<'m>> {
+        let lowering = Lowering::new(module)?;
+        let initial_memory = lowering.initial_memory()?;
+        Ok(Bmc { lowering,
+                 initial_memory,
+                 unwind: 3,
+                 max_depth: 4 })
+    }
+    /// Execute the blocks of a loop at most `k` times per entry into
+    /// the loop, 3 by default.
+    pub fn set_unwind(&mut self,k: usize) {
+        self.unwind = k;
+    }
+    /// The maximal number of nested inlined calls, 4 by default.
+    pub fn set_max_depth(&mut self,n: usize) {
+        self.max_depth = n;
+    }
+    /// Encode a function, its arguments are the variables `arg.<name>`.
+    pub fn encode(&self,name: &str) -> SymResult<Encoding> {
+        let fun = self.lowering.module().functions.get(name)
+            .ok_or_else(|| SymError::UnknownFunction(name.to_string()))?;
+        let args = self.lowering.arguments(fun)?;
+        let mut enc = Encoder { bmc: self,
+                                alloc: self.lowering.allocator(),
+                                graphs: HashMap::new(),
+                                targets: Vec::new(),
+                                unwound: Vec::new() };
+        enc.function(fun,args,Term::bool(true),self.initial_memory.clone(),0)?;
+        Ok(Encoding { targets: enc.targets,
+                      unwound: Term::or(enc.unwound) })
+    }
+}
+
+/// The loop structure of a function body.
+struct Graph {
+    cfg: ControlFlowGraph,
+    loops: LoopInfo,
+    /// The loops containing each block, outermost first.
+    nesting: Vec<Vec<LoopId>>
+}
+
+/// A block instance: the block and the iteration of each loop
+/// containing it.
+type Instance = (usize,Vec<usize>);
+
+/// Control flowing into a block instance.
+struct Incoming<'m> {
+    from: usize,
+    guard: Term,
+    locals: HashMap<&'m str,Term>,
+    memory: Memory
+}
+
+struct Returned {
+    guard: Term,
+    value: Option<Term>,
+    memory: Memory
+}
+
+struct Encoder<'b,'m: 'b> {
+    bmc: &'b Bmc<'m>,
+    alloc: Allocator,
+    graphs: HashMap<&'m str,Rc<Graph>>,
+    targets: Vec<Target>,
+    unwound: Vec<Term>
+}
+
+impl<'b,'m> Encoder<'b,'m> {
+    fn graph(&mut self,fun: &'m Function) -> SymResult<Rc<Graph>> {
+        if let Some(g) = self.graphs.get(&fun.name[..]) {
+            return Ok(g.clone())
+        }
+        let cfg = match ControlFlowGraph::from_function(fun) {
+            Some(ref cfg) if !cfg.is_empty() => cfg.clone(),
+            _ => return Err(SymError::Unsupported(format!("@{} has no body",fun.name)))
+        };
+        let loops = LoopInfo::new(&cfg,&DominatorTree::new(&cfg));
+        if loops.is_irreducible() {
+            return Err(SymError::Unsupported(format!("irreducible control flow in @{}",fun.name)))
+        }
+        let nesting = (0..cfg.len()).map(|b| {
+            let mut chain = Vec::new();
+            let mut cur = loops.loop_for(b);
+            while let Some(l) = cur {
+                chain.push(l);
+                cur = loops.get(l).parent;
+            }
+            chain.reverse();
+            chain
+        }).collect();
+        let g = Rc::new(Graph { cfg, loops, nesting });
+        self.graphs.insert(&fun.name,g.clone());
+        Ok(g)
+    }
+    /// The instance reached by an edge, `None` if it exceeds the
+    /// unwinding bound.
+    fn successor(&self,g: &Graph,from: &Instance,to: usize) -> Option<Instance> {
+        let outer = &g.nesting[from.0];
+        let mut iters = Vec::with_capacity(g.nesting[to].len());
+        for (i,&l) in g.nesting[to].iter().enumerate() {
+            if i < outer.len() && outer[i]==l {
+                let mut n = from.1[i];
+                if g.loops.get(l).header==to {
+                    n += 1;
+                    if n >= self.bmc.unwind {
+                        return None
+                    }
+                }
+                iters.push(n);
+            } else {
+                iters.push(0);
+            }
+        }
+        Some((to,iters))
+    }
+    /// All instances of the unrolled function in topological order and
+    /// the position of each in it.
+    fn unroll(&self,g: &Graph) -> (Vec<Instance>,HashMap<Instance,usize>) {
+        let entry: Instance = (g.cfg.entry(),Vec::new());
+        let mut index = HashMap::new();
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        visited.insert(entry.clone());
+        let mut stack = vec![(entry,0)];
+        while let Some((inst,i)) = stack.pop() {
+            // Visit the successors backwards, so that the order follows
+            // the order of the blocks where possible
+            let succs = g.cfg.successors(inst.0);
+            if i < succs.len() {
+                let next = self.successor(g,&inst,succs[succs.len()-1-i]);
+                stack.push((inst,i+1));
+                if let Some(next) = next {
+                    if visited.insert(next.clone()) {
+                        stack.push((next,0));
+                    }
+                }
+            } else {
+                order.push(inst);
+            }
+        }
+        order.reverse();
+        for (i,inst) in order.iter().enumerate() {
+            index.insert(inst.clone(),i);
+        }
+        (order,index)
+    }
+    fn function(&mut self,fun: &'m Function,args: Vec<Term>,guard: Term,memory: Memory,depth: usize)
+                -> SymResult<Returned> {
+        let g = self.graph(fun)?;
+        let blocks = fun.body.as_ref().unwrap();
+        let (order,index) = self.unroll(&g);
+        let mut pending: Vec<Vec<Incoming<'m>>> = order.iter().map(|_| Vec::new()).collect();
+        pending[0].push(Incoming { from: g.cfg.entry(),
+                                   guard,
+                                   locals: HashMap::new(),
+                                   memory: memory.clone() });
+        let mut returns = Vec::new();
+        for (n,inst) in order.iter().enumerate() {
+            let incoming = ::std::mem::take(&mut pending[n]);
+            if incoming.is_empty() {
+                continue
+            }
+            let guard = Term::or(incoming.iter().map(|i| i.guard.clone()).collect());
+            if guard.as_bool()==Some(false) {
+                continue
+            }
+            let blk = &blocks[inst.0];
+            let (mut locals,mut mem,start) = self.join(blocks,blk,&args,&incoming)?;
+            let mut g_cur = guard;
+            for instr in blk.instrs[start..].iter() {
+                let effect = self.bmc.lowering.instruction(&locals,&args,&instr.content,&mut mem,&mut self.alloc)?;
+                match effect {
+                    Effect::Value(v) => {
+                        if let (Some(name),Some(v)) = (instr.content.name(),v) {
+                            locals.insert(name,v);
+                        }
+                        continue
+                    },
+                    Effect::Call(callee,call_args) => {
+                        if depth >= self.bmc.max_depth {
+                            // Too deep, treat it like a function without a body
+                            self.unwound.push(g_cur.clone());
+                            let ret_tp = match instr.content {
+                                InstructionC::Call(_,_,Some((ref tp,_)),..) => builder::call_return_type(tp),
+                                _ => None
+                            };
+                            let v = self.bmc.lowering.call_external(&mut mem,&mut self.alloc,&callee.name,
+                                                                    &call_args,ret_tp.as_ref())?;
+                            if let (Some(name),Ok(Some(v))) = (instr.content.name(),v) {
+                                locals.insert(name,v);
+                            }
+                            continue
+                        }
+                        let ret = self.function(callee,call_args,g_cur,mem,depth+1)?;
+                        g_cur = ret.guard;
+                        mem = ret.memory;
+                        if let (Some(name),Some(v)) = (instr.content.name(),ret.value) {
+                            locals.insert(name,v);
+                        }
+                        if g_cur.as_bool()==Some(false) {
+                            break
+                        }
+                        continue
+                    },
+                    Effect::End(PathEnd::AssertionFailed) => {
+                        let kind = match instr.content {
+                            InstructionC::Call(_,_,_,ref callee,_,_) =>
+                                match &self.bmc.lowering.callee(&locals,&args,callee)?.name[..] {
+                                    "__VERIFIER_error" => TargetKind::VerifierError,
+                                    _ => TargetKind::AssertFail
+                                },
+                            _ => TargetKind::AssertFail
+                        };
+                        self.target(kind,fun,blk,instr,g_cur);
+                    },
+                    Effect::End(_) => {},
+                    Effect::Terminator(term) => match *term {
+                        Terminator::Ret(ref val) => {
+                            let v = match *val {
+                                Some(ref v) => Some(self.bmc.lowering.value(&locals,&args,&v.tp,&v.val)?),
+                                None => None
+                            };
+                            returns.push((g_cur,v,mem));
+                        },
+                        Terminator::Unreachable => self.target(TargetKind::Unreachable,fun,blk,instr,g_cur),
+                        _ => {
+                            for (cond,lbl) in self.bmc.lowering.branches(&locals,&args,term)?.unwrap() {
+                                let edge = Term::and(vec![g_cur.clone(),cond]);
+                                if edge.as_bool()==Some(false) {
+                                    continue
+                                }
+                                let to = g.cfg.block_index(lbl)
+                                    .ok_or_else(|| SymError::UnknownBlock(lbl.to_string()))?;
+                                match self.successor(&g,inst,to) {
+                                    None => self.unwound.push(edge),
+                                    Some(next) => pending[index[&next]].push(Incoming { from: inst.0,
+                                                                                        guard: edge,
+                                                                                        locals: locals.clone(),
+                                                                                        memory: mem.clone() })
+                                }
+                            }
+                        }
+                    }
+                }
+                break
+            }
+        }
+        if returns.is_empty() {
+            return Ok(Returned { guard: Term::bool(false), value: None, memory })
+        }
+        let guard = Term::or(returns.iter().map(|r| r.0.clone()).collect());
+        let value = if returns.iter().all(|r| r.1.is_some()) {
+            Some(merge(returns.iter().map(|r| (r.0.clone(),r.1.clone().unwrap())).collect()))
+        } else {
+            None
+        };
+        let memory = Memory::merge(&returns.into_iter().map(|r| (r.0,r.2)).collect::<Vec<_>>());
+        Ok(Returned { guard, value, memory })
+    }
+    /// Merge the control flowing into a block: the locals, the memory
+    /// and the values of the phi nodes. Also returns the index of the
+    /// first instruction after the phis.
+    fn join(&self,blocks: &'m [BasicBlock],blk: &'m BasicBlock,args: &[Term],incoming: &[Incoming<'m>])
+            -> SymResult<(HashMap<&'m str,Term>,Memory,usize)> {
+        let (first,rest) = incoming.split_first().unwrap();
+        let mut locals = HashMap::with_capacity(first.locals.len());
+        for (name,v) in first.locals.iter() {
+            let mut cases = Vec::with_capacity(incoming.len());
+            for inc in rest.iter() {
+                match inc.locals.get(name) {
+                    Some(w) => cases.push((inc.guard.clone(),w.clone())),
+                    None => break
+                }
+            }
+            if cases.len()==rest.len() {
+                cases.push((first.guard.clone(),v.clone()));
+                locals.insert(*name,merge(cases));
+            }
+        }
+        let mut start = 0;
+        let mut phis = Vec::new();
+        for instr in blk.instrs.iter() {
+            match instr.content {
+                InstructionC::Phi(ref name,ref tp,ref values) => {
+                    let mut cases = Vec::with_capacity(incoming.len());
+                    for inc in incoming.iter().rev() {
+                        let from = &blocks[inc.from].name;
+                        let v = match values.iter().find(|(_,l)| l==from) {
+                            Some((v,_)) => self.bmc.lowering.value(&inc.locals,args,tp,v)?,
+                            None => return Err(SymError::UnknownBlock(from.clone()))
+                        };
+                        cases.push((inc.guard.clone(),v));
+                    }
+                    phis.push((&name[..],merge(cases)));
+                    start += 1;
+                },
+                _ => break
+            }
+        }
+        for (name,v) in phis {
+            locals.insert(name,v);
+        }
+        let memory = Memory::merge(&incoming.iter().map(|i| (i.guard.clone(),i.memory.clone())).collect::<Vec<_>>());
+        Ok((locals,memory,start))
+    }
+    fn target(&mut self,kind: TargetKind,fun: &Function,blk: &BasicBlock,instr: &Instruction,cond: Term) {
+        if cond.as_bool()==Some(false) {
+            return
+        }
+        self.targets.push(Target { kind,
+                                   function: fun.name.clone(),
+                                   block: blk.name.clone(),
+                                   location: ub::debug_location(self.bmc.lowering.module(),instr),
+                                   condition: cond });
+    }
+}
+
+/// The value of the first case whose condition holds, the last value
+/// if none holds.
+fn merge(cases: Vec<(Term,Term)>) -> Term {
+    let mut it = cases.into_iter().rev();
+    let (_,mut res) = it.next().expect("no value to merge");
+    for (c,v) in it {
+        res = Term::ite(c,v,res);
+    }
+    res
+}
+
+#[test]
+fn test_bmc_loop() {
+    let m = parse_test_module(b"declare void @__assert_fail(i8*, i8*, i32, i8*)
+
+define i32 @f(i32 %n, i32 %x) {
+entry:
+  %neg = icmp slt i32 %x, 0
+  br i1 %neg, label %flip, label %loop
+
+flip:
+  %m = sub i32 0, %x
+  br label %loop
+
+loop:
+  %a = phi i32 [ %x, %entry ], [ %m, %flip ], [ %a2, %latch ]
+  %i = phi i32 [ 0, %entry ], [ 0, %flip ], [ %i2, %latch ]
+  %c = icmp slt i32 %i, %n
+  br i1 %c, label %body, label %exit
+
+body:
+  %three = icmp eq i32 %i, 3
+  br i1 %three, label %fail, label %latch
+
+fail:
+  call void @__assert_fail(i8* null, i8* null, i32 0, i8* null)
+  unreachable
+
+latch:
+  %a2 = add i32 %a, 1
+  %i2 = add i32 %i, 1
+  br label %loop
+
+exit:
+  %neg2 = icmp slt i32 %a, 0
+  br i1 %neg2, label %dead, label %done
+
+dead:
+  unreachable
+
+done:
+  ret i32 %a
+}
+");
+    let mut bmc = Bmc::new(&m).unwrap();
+    // Three iterations never reach i == 3
+    bmc.set_unwind(3);
+    let enc = bmc.encode("f").unwrap();
+    assert_eq!(enc.targets.len(),1);
+    assert_eq!(enc.targets[0].kind,TargetKind::Unreachable);
+    assert_eq!(enc.targets[0].block,"dead");
+    assert!(enc.unwound.as_bool().is_none());
+    let vars: Vec<String> = enc.reachable().variables().into_keys().collect();
+    assert_eq!(vars,vec!["arg.n","arg.x"]);
+    bmc.set_unwind(4);
+    let enc = bmc.encode("f").unwrap();
+    let kinds: Vec<(TargetKind,&str)> = enc.targets.iter().map(|t| (t.kind,&t.block[..])).collect();
+    assert_eq!(kinds,vec![(TargetKind::AssertFail,"fail"),(TargetKind::Unreachable,"dead")]);
+    // The assertion fails in the fourth iteration, if n > 3
+    let n = Term::var("arg.n",smt::Sort::BitVec(32));
+    let in_loop = |i| Term::bin(smt::Op::BvSlt,Term::bv_i64(32,i),n.clone());
+    assert_eq!(enc.targets[0].condition,Term::and(vec![in_loop(0),in_loop(1),in_loop(2),in_loop(3)]));
+    let script = enc.smtlib();
+    assert!(script.contains("(declare-fun arg.n () (_ BitVec 32))\n"));
+    assert!(script.ends_with("(check-sat)\n(get-model)\n"));
+    let mut out = Vec::new();
+    enc.write_smtlib(&mut out).unwrap();
+    assert_eq!(out,script.into_bytes());
+    if let Some(solver) = Solver::find() {
+        assert!(matches!(enc.check(&solver).unwrap(),SatResult::Sat(_)));
+    }
+}
+
+#[test]
+fn test_bmc_minisat() {
+    let m = minisat();
+    let mut bmc = Bmc::new(&m).unwrap();
+    bmc.set_unwind(2);
+    bmc.set_max_depth(1);
+    let enc = bmc.encode("solver_record").unwrap();
+    // The assertions of the inlined clause_new are targets as well
+    let funs: HashSet<&str> = enc.targets.iter().map(|t| &t.function[..]).collect();
+    assert!(funs.contains("solver_record"));
+    assert!(funs.contains("clause_new"));
+    for t in enc.targets.iter().filter(|t| t.kind==TargetKind::AssertFail) {
+        assert_eq!(t.location.as_ref().and_then(|l| l.file.as_ref()).map(|f| &f[..]),Some("solver.c"));
+    }
+    assert!(enc.smtlib().contains("(check-sat)"));
+    bmc.set_max_depth(0);
+    let enc = bmc.encode("solver_record").unwrap();
+    assert!(enc.targets.iter().all(|t| t.function=="solver_record"));
+    assert!(enc.targets.iter().any(|t| t.kind==TargetKind::AssertFail));
+    // Every call is too deep now
+    assert_eq!(enc.unwound.as_bool(),Some(true));
+}
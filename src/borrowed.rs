@@ -0,0 +1,1276 @@
+//! A variant of the AST that borrows from the parsed text.
+//!
+//! Names are `&str` slices of the input and types are interned in a
+//! `TypeArena`, referred to by `TypeId` handles; equal types get the
+//! same handle. Metadata strings borrow unless they contain escapes,
+//! `c"..."` strings are kept as bytes and `zeroinitializer` is not
+//! expanded. `Module::to_module` converts to the owned AST, which is
+//! the same as the one `module` of the crate root parses.
+use nom::IResult;
+use nom::*;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Index;
+use std::str;
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+use super::{Alignment,AttributeGroup,BinOp,CallingConv,CastInst,CmpOp,DLLStorageClass,
+            GlobalType,Linkage,ParAttrs,ThreadLocal,UnnamedAddr,Visibility};
+use super::{alignment,calling_conv,cast_inst,cmp_op,comment,constant_char,dll_storage_class,
+            externally_initialized,global_type,linkage,module_id,par_attrs,thread_local,
+            triple,unnamed_addr,visibility};
+use datalayout::{datalayout,DataLayout};
+use helper::*;
+use types::Type;
+use ordered::OrderedMap;
+
+/// A handle of a type in a `TypeArena`.
+#[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Hash,Clone,Copy)]
+pub struct TypeId(u32);
+
+/// A type whose components are interned.
+#[allow(non_camel_case_types)]
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+pub enum TypeData<'src> {
+    Opaque,
+    Int(u64),
+    Float,
+    Double,
+    PPC_FP128,
+    FP128,
+    X86_FP80,
+    Label,
+    Pointer(TypeId,Option<AddressSpace>),
+    Struct(Vec<TypeId>),
+    Array(u64,TypeId),
+    Function(Option<TypeId>,Vec<TypeId>,bool),
+    Named(&'src str),
+    Metadata
+}
+
+#[derive(Debug,PartialEq,Eq,Clone,Default)]
+pub struct TypeArena<'src> {
+    types: Vec<TypeData<'src>>,
+    ids: HashMap<TypeData<'src>,TypeId>
+}
+
+impl<'src> TypeArena<'src> {
+    pub fn new() -> TypeArena<'src> {
+        TypeArena::default()
+    }
+
+    pub fn intern(&mut self,tp: TypeData<'src>) -> TypeId {
+        if let Some(&id) = self.ids.get(&tp) {
+            return id
+        }
+        let id = TypeId(self.types.len() as u32);
+        self.types.push(tp.clone());
+        self.ids.insert(tp,id);
+        id
+    }
+
+    /// The handle of a type, if it has been interned.
+    pub fn lookup(&self,tp: &TypeData<'src>) -> Option<TypeId> {
+        self.ids.get(tp).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.types.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty()
+    }
+
+    /// Intern an owned type and its components.
+    pub fn from_type(&mut self,tp: &'src Type) -> TypeId {
+        let data = match *tp {
+            Type::Opaque => TypeData::Opaque,
+            Type::Int(w) => TypeData::Int(w),
+            Type::Float => TypeData::Float,
+            Type::Double => TypeData::Double,
+            Type::PPC_FP128 => TypeData::PPC_FP128,
+            Type::FP128 => TypeData::FP128,
+            Type::X86_FP80 => TypeData::X86_FP80,
+            Type::Label => TypeData::Label,
+            Type::Pointer(ref el,sp) => TypeData::Pointer(self.from_type(el),sp),
+            Type::Struct(ref els) => TypeData::Struct(els.iter().map(|el| self.from_type(el)).collect()),
+            Type::Array(n,ref el) => TypeData::Array(n,self.from_type(el)),
+            Type::Function(ref ret,ref params,va) => {
+                let ret = ret.as_ref().map(|r| self.from_type(r));
+                TypeData::Function(ret,params.iter().map(|p| self.from_type(p)).collect(),va)
+            },
+            Type::Named(ref n) => TypeData::Named(n),
+            Type::Metadata => TypeData::Metadata
+        };
+        self.intern(data)
+    }
+
+    /// The owned type of a handle.
+    pub fn to_type(&self,id: TypeId) -> Type {
+        match self[id] {
+            TypeData::Opaque => Type::Opaque,
+            TypeData::Int(w) => Type::Int(w),
+            TypeData::Float => Type::Float,
+            TypeData::Double => Type::Double,
+            TypeData::PPC_FP128 => Type::PPC_FP128,
+            TypeData::FP128 => Type::FP128,
+            TypeData::X86_FP80 => Type::X86_FP80,
+            TypeData::Label => Type::Label,
+            TypeData::Pointer(el,sp) => Type::Pointer(Box::new(self.to_type(el)),sp),
+            TypeData::Struct(ref els) => Type::Struct(els.iter().map(|&el| self.to_type(el)).collect()),
+            TypeData::Array(n,el) => Type::Array(n,Box::new(self.to_type(el))),
+            TypeData::Function(ret,ref params,va) =>
+                Type::Function(ret.map(|r| Box::new(self.to_type(r))),
+                               params.iter().map(|&p| self.to_type(p)).collect(),va),
+            TypeData::Named(n) => Type::Named(n.to_string()),
+            TypeData::Metadata => Type::Metadata
+        }
+    }
+}
+
+impl<'src> Index<TypeId> for TypeArena<'src> {
+    type Output = TypeData<'src>;
+    fn index(&self,id: TypeId) -> &TypeData<'src> {
+        &self.types[id.0 as usize]
+    }
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Module<'src> {
+    pub id: Option<&'src str>,
+    pub datalayout: DataLayout,
+    pub triple: Option<&'src str>,
+    pub functions: OrderedMap<&'src str,Function<'src>>,
+    /// The named types.
+    pub types: OrderedMap<&'src str,TypeId>,
+    pub globals: OrderedMap<&'src str,GlobalVariable<'src>>,
+    pub attr_groups: OrderedMap<u64,Vec<Attribute<'src>>>,
+    pub named_md: OrderedMap<&'src str,Metadata<'src>>,
+    pub md: OrderedMap<u64,Metadata<'src>>,
+    /// See `::Module::duplicate_functions`.
+    pub duplicate_functions: Vec<Function<'src>>,
+    /// The types all `TypeId`s of the module refer to.
+    pub type_arena: TypeArena<'src>
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct GlobalVariable<'src> {
+    pub linkage: Option<Linkage>,
+    pub visibility: Visibility,
+    pub dll_storage_class: DLLStorageClass,
+    pub thread_local: Option<ThreadLocal>,
+    pub unnamed_addr: Option<UnnamedAddr>,
+    pub addr_space: Option<AddressSpace>,
+    pub externally_initialized: bool,
+    pub global_type: GlobalType,
+    pub types: TypeId,
+    pub initialization: Option<Constant<'src>>,
+    pub section: Option<&'src str>,
+    pub alignment: Option<Alignment>
+}
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+pub struct Attribute<'src> {
+    pub name: &'src str,
+    pub quoted: bool,
+    pub value: Option<&'src str>
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Function<'src> {
+    pub name: &'src str,
+    pub linkage: Option<Linkage>,
+    pub visibility: Visibility,
+    pub dll_storage_class: DLLStorageClass,
+    pub cconv: CallingConv,
+    pub return_type: Option<(ParAttrs,TypeId)>,
+    pub arguments: Vec<(Option<&'src str>,TypeId)>,
+    pub argument_attrs: Vec<ParAttrs>,
+    pub var_args: bool,
+    pub attribute_groups: Vec<AttributeGroup>,
+    pub body: Option<Vec<BasicBlock<'src>>>
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct BasicBlock<'src> {
+    pub name: &'src str,
+    pub instrs: Vec<Instruction<'src>>
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Instruction<'src> {
+    pub content: InstructionC<'src>,
+    /// The attached metadata in the order of the source.
+    pub metadata: Vec<(&'src str,u64)>
+}
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+pub enum Terminator<'src> {
+    Br(&'src str),
+    BrC(Value<'src>,&'src str,&'src str),
+    Ret(Option<Typed<Value<'src>>>),
+    Switch(TypeId,Value<'src>,&'src str,Vec<(Constant<'src>,&'src str)>),
+    Unreachable
+}
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+pub enum InstructionC<'src> {
+    Alloca(&'src str,TypeId,Option<Typed<Value<'src>>>,Option<Alignment>),
+    Call(Option<&'src str>,CallingConv,Option<(TypeId,ParAttrs)>,Value<'src>,Vec<Typed<Value<'src>>>,Vec<AttributeGroup>),
+    ICmp(&'src str,CmpOp,TypeId,Value<'src>,Value<'src>),
+    Unary(&'src str,Typed<Value<'src>>,UnaryInst),
+    GEP(&'src str,GEP<Value<'src>>),
+    Store(bool,Typed<Value<'src>>,Typed<Value<'src>>,Option<Alignment>),
+    Select(&'src str,Value<'src>,TypeId,Value<'src>,Value<'src>),
+    Phi(&'src str,TypeId,Vec<(Value<'src>,&'src str)>),
+    Bin(&'src str,BinOp,TypeId,Value<'src>,Value<'src>),
+    Term(Terminator<'src>)
+}
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+pub enum UnaryInst {
+    Cast(TypeId,CastInst),
+    Load(bool,Option<Alignment>)
+}
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+pub struct Typed<T> {
+    pub tp: TypeId,
+    pub val: T
+}
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+pub struct GEP<T> {
+    pub ptr: Typed<T>,
+    pub inbounds: bool,
+    pub indices: Vec<(Typed<T>,bool)>
+}
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+pub enum Value<'src> {
+    Constant(Constant<'src>),
+    Local(&'src str),
+    Argument(usize),
+    Metadata(Metadata<'src>)
+}
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+pub enum Constant<'src> {
+    Global(&'src str),
+    Int(BigInt),
+    Array(Vec<Constant<'src>>),
+    /// A `c"..."` string.
+    Bytes(Cow<'src,[u8]>),
+    GEP(Box<GEP<Constant<'src>>>),
+    Cast(CastInst,Box<Typed<Constant<'src>>>,TypeId),
+    NullPtr,
+    Undef,
+    /// `zeroinitializer` of a global of the given type.
+    ZeroInit(TypeId)
+}
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+pub enum Metadata<'src> {
+    Null,
+    Ref(u64),
+    Value(Box<Typed<Value<'src>>>),
+    Struct(Vec<Metadata<'src>>),
+    Bytes(Cow<'src,[u8]>),
+    Location(u64,u64,Box<Metadata<'src>>)
+}
+
+impl<'src> Module<'src> {
+    /// Convert to the owned AST.
+    pub fn to_module(&self) -> ::Module {
+        let tps = &self.type_arena;
+        let mut m = ::Module::new();
+        m.id = self.id.map(|id| id.to_string());
+        m.datalayout = self.datalayout.clone();
+        m.triple = self.triple.map(|t| t.to_string());
+        m.types = self.types.iter().map(|(n,&tp)| (n.to_string(),tps.to_type(tp))).collect();
+        m.globals = self.globals.iter().map(|(n,g)| (n.to_string(),owned_global(tps,g))).collect();
+        m.functions = self.functions.iter().map(|(n,f)| (n.to_string(),owned_function(tps,f))).collect();
+        m.attr_groups = self.attr_groups.iter()
+            .map(|(&n,attrs)| (n,attrs.iter().map(owned_attribute).collect()))
+            .collect();
+        m.named_md = self.named_md.iter().map(|(n,md)| (n.to_string(),owned_metadata(tps,md))).collect();
+        m.md = self.md.iter().map(|(&n,md)| (n,owned_metadata(tps,md))).collect();
+        m.duplicate_functions = self.duplicate_functions.iter().map(|f| owned_function(tps,f)).collect();
+        m
+    }
+}
+
+fn owned_global(tps: &TypeArena,g: &GlobalVariable) -> ::GlobalVariable {
+    ::GlobalVariable { linkage: g.linkage,
+                       visibility: g.visibility,
+                       dll_storage_class: g.dll_storage_class,
+                       thread_local: g.thread_local,
+                       unnamed_addr: g.unnamed_addr,
+                       addr_space: g.addr_space,
+                       externally_initialized: g.externally_initialized,
+                       global_type: g.global_type,
+                       types: tps.to_type(g.types),
+                       initialization: g.initialization.as_ref().map(|c| owned_constant(tps,c)),
+                       section: g.section.map(|s| s.to_string()),
+                       alignment: g.alignment }
+}
+
+fn owned_attribute(attr: &Attribute) -> ::Attribute {
+    ::Attribute { name: attr.name.to_string(),
+                  quoted: attr.quoted,
+                  value: attr.value.map(|v| v.to_string()) }
+}
+
+fn owned_function(tps: &TypeArena,f: &Function) -> ::Function {
+    ::Function { name: f.name.to_string(),
+                 linkage: f.linkage,
+                 visibility: f.visibility,
+                 dll_storage_class: f.dll_storage_class,
+                 cconv: f.cconv.clone(),
+                 return_type: f.return_type.as_ref().map(|&(ref attrs,tp)| (attrs.clone(),tps.to_type(tp))),
+                 arguments: f.arguments.iter().map(|&(n,tp)| (n.map(|n| n.to_string()),tps.to_type(tp))).collect(),
+                 argument_attrs: f.argument_attrs.clone(),
+                 var_args: f.var_args,
+                 attribute_groups: f.attribute_groups.clone(),
+                 body: f.body.as_ref().map(|blks| blks.iter().map(|blk| owned_block(tps,blk)).collect()) }
+}
+
+fn owned_block(tps: &TypeArena,blk: &BasicBlock) -> ::BasicBlock {
+    ::BasicBlock { name: blk.name.to_string(),
+                   instrs: blk.instrs.iter().map(|i| ::Instruction {
+                       content: owned_instruction(tps,&i.content),
+                       metadata: i.metadata.iter().map(|&(k,n)| (k.to_string(),n)).collect()
+                   }).collect() }
+}
+
+fn owned_typed_value(tps: &TypeArena,tv: &Typed<Value>) -> ::Typed<::Value> {
+    ::Typed::new(tps.to_type(tv.tp),owned_value(tps,&tv.val))
+}
+
+fn owned_instruction(tps: &TypeArena,instr: &InstructionC) -> ::InstructionC {
+    let tp = |id| tps.to_type(id);
+    let val = |v| owned_value(tps,v);
+    let tval = |tv| owned_typed_value(tps,tv);
+    match *instr {
+        InstructionC::Alloca(n,t,ref size,align) =>
+            ::InstructionC::Alloca(n.to_string(),tp(t),size.as_ref().map(tval),align),
+        InstructionC::Call(n,ref cc,ref rtp,ref f,ref args,ref attrs) =>
+            ::InstructionC::Call(n.map(|n| n.to_string()),cc.clone(),
+                                 rtp.as_ref().map(|&(t,ref pa)| (tp(t),pa.clone())),
+                                 val(f),args.iter().map(tval).collect(),attrs.clone()),
+        InstructionC::ICmp(n,ref op,t,ref v1,ref v2) =>
+            ::InstructionC::ICmp(n.to_string(),op.clone(),tp(t),val(v1),val(v2)),
+        InstructionC::Unary(n,ref v,ref op) => {
+            let op = match *op {
+                UnaryInst::Cast(t,c) => ::UnaryInst::Cast(tp(t),c),
+                UnaryInst::Load(vol,align) => ::UnaryInst::Load(vol,align)
+            };
+            ::InstructionC::Unary(n.to_string(),tval(v),op)
+        },
+        InstructionC::GEP(n,ref g) =>
+            ::InstructionC::GEP(n.to_string(),::GEP { ptr: tval(&g.ptr),
+                                                      inbounds: g.inbounds,
+                                                      indices: g.indices.iter().map(|&(ref i,ir)| (tval(i),ir)).collect() }),
+        InstructionC::Store(vol,ref v,ref p,align) => ::InstructionC::Store(vol,tval(v),tval(p),align),
+        InstructionC::Select(n,ref c,t,ref v1,ref v2) =>
+            ::InstructionC::Select(n.to_string(),val(c),tp(t),val(v1),val(v2)),
+        InstructionC::Phi(n,t,ref incoming) =>
+            ::InstructionC::Phi(n.to_string(),tp(t),incoming.iter().map(|&(ref v,l)| (val(v),l.to_string())).collect()),
+        InstructionC::Bin(n,ref op,t,ref v1,ref v2) =>
+            ::InstructionC::Bin(n.to_string(),op.clone(),tp(t),val(v1),val(v2)),
+        InstructionC::Term(ref term) => ::InstructionC::Term(match *term {
+            Terminator::Br(l) => ::Terminator::Br(l.to_string()),
+            Terminator::BrC(ref c,l1,l2) => ::Terminator::BrC(val(c),l1.to_string(),l2.to_string()),
+            Terminator::Ret(ref v) => ::Terminator::Ret(v.as_ref().map(tval)),
+            Terminator::Switch(t,ref v,def,ref cases) =>
+                ::Terminator::Switch(tp(t),val(v),def.to_string(),
+                                     cases.iter().map(|&(ref c,l)| (owned_constant(tps,c),l.to_string())).collect()),
+            Terminator::Unreachable => ::Terminator::Unreachable
+        })
+    }
+}
+
+fn owned_value(tps: &TypeArena,v: &Value) -> ::Value {
+    match *v {
+        Value::Constant(ref c) => ::Value::Constant(owned_constant(tps,c)),
+        Value::Local(n) => ::Value::Local(n.to_string()),
+        Value::Argument(i) => ::Value::Argument(i),
+        Value::Metadata(ref md) => ::Value::Metadata(owned_metadata(tps,md))
+    }
+}
+
+fn owned_constant(tps: &TypeArena,c: &Constant) -> ::Constant {
+    match *c {
+        Constant::Global(n) => ::Constant::Global(n.to_string()),
+        Constant::Int(ref i) => ::Constant::Int(i.clone()),
+        Constant::Array(ref els) => ::Constant::Array(els.iter().map(|el| owned_constant(tps,el)).collect()),
+        Constant::Bytes(ref bytes) => ::Constant::Array(bytes.iter().map(|&b| ::Constant::Int(BigInt::from(b))).collect()),
+        Constant::GEP(ref g) => {
+            let tc = |tc: &Typed<Constant>| ::Typed::new(tps.to_type(tc.tp),owned_constant(tps,&tc.val));
+            ::Constant::GEP(Box::new(::GEP { ptr: tc(&g.ptr),
+                                             inbounds: g.inbounds,
+                                             indices: g.indices.iter().map(|&(ref i,ir)| (tc(i),ir)).collect() }))
+        },
+        Constant::Cast(op,ref src,tp) =>
+            ::Constant::Cast(op,Box::new(::Typed::new(tps.to_type(src.tp),owned_constant(tps,&src.val))),
+                             tps.to_type(tp)),
+        Constant::NullPtr => ::Constant::NullPtr,
+        Constant::Undef => ::Constant::Undef,
+        Constant::ZeroInit(tp) => ::Constant::zero_init(&tps.to_type(tp))
+    }
+}
+
+fn owned_metadata(tps: &TypeArena,md: &Metadata) -> ::Metadata {
+    match *md {
+        Metadata::Null => ::Metadata::Null,
+        Metadata::Ref(n) => ::Metadata::Ref(n),
+        Metadata::Value(ref tv) => ::Metadata::Value(Box::new(owned_typed_value(tps,tv))),
+        Metadata::Struct(ref els) => ::Metadata::Struct(els.iter().map(|el| owned_metadata(tps,el)).collect()),
+        Metadata::Bytes(ref bytes) => ::Metadata::Bytes(bytes.to_vec()),
+        Metadata::Location(l,c,ref scope) => ::Metadata::Location(l,c,Box::new(owned_metadata(tps,scope)))
+    }
+}
+
+// The types interned while parsing.
+type Types<'src> = RefCell<TypeArena<'src>>;
+
+type Args<'a,'src> = &'a [(Option<&'src str>,TypeId)];
+
+fn intern<'src>(tps: &Types<'src>,tp: TypeData<'src>) -> TypeId {
+    tps.borrow_mut().intern(tp)
+}
+
+fn arg_list<'src>(i: &'src [u8],tps: &Types<'src>) -> IResult<&'src [u8],(Vec<TypeId>,bool)> {
+    do_parse!(i,
+              char!('(') >>
+              llvm_space >>
+              args: separated_list!(terminated!(char!(','),llvm_space),
+                                    terminated!(call!(types,tps),llvm_space)) >>
+              va: map!(opt!(preceded!(terminated!(char!(','),llvm_space),
+                                      terminated!(tag!("..."),llvm_space))),
+                       |c| c.is_some()) >>
+              char!(')') >>
+              llvm_space >>
+              (args,va))
+}
+
+enum TrailingType {
+    Pointer(Option<AddressSpace>),
+    Function(Vec<TypeId>,bool)
+}
+
+fn trailing_type<'src>(i: &'src [u8],tps: &Types<'src>) -> IResult<&'src [u8],TrailingType> {
+    alt!(i,
+         do_parse!( sp: address_space >>
+                    llvm_space >>
+                    char!('*') >>
+                    llvm_space >>
+                    (TrailingType::Pointer(Some(sp))) ) |
+         do_parse!( char!('*') >>
+                    llvm_space >>
+                    (TrailingType::Pointer(None)) ) |
+         do_parse!( l: call!(arg_list,tps) >>
+                    llvm_space >>
+                    (TrailingType::Function(l.0,l.1)) ))
+}
+
+pub fn types<'src>(i: &'src [u8],tps: &Types<'src>) -> IResult<&'src [u8],TypeId> {
+    alt!(i,
+         map!(tag!("metadata"),
+              |_| intern(tps,TypeData::Metadata)) |
+         do_parse!(base: alt!( map!( tag!("opaque"), |_| intern(tps,TypeData::Opaque)) |
+                               do_parse!(tag!("void") >>
+                                         llvm_space >>
+                                         args: call!(arg_list,tps) >>
+                                         (intern(tps,TypeData::Function(None,args.0,args.1)))) |
+                               map!( preceded!(char!('i'),
+                                               parse_u64),
+                                     |i| intern(tps,TypeData::Int(i))) |
+                               do_parse!(char!('{') >>
+                                         llvm_space >>
+                                         els: separated_list!(terminated!(char!(','),llvm_space),
+                                                              terminated!(call!(types,tps),llvm_space)) >>
+                                         char!('}') >>
+                                         (intern(tps,TypeData::Struct(els)))) |
+                               do_parse!( char!('[') >>
+                                          llvm_space >>
+                                          x: parse_u64 >>
+                                          llvm_space >>
+                                          char!('x') >>
+                                          llvm_space >>
+                                          y: call!(types,tps) >>
+                                          llvm_space >>
+                                          char!(']') >>
+                                          (intern(tps,TypeData::Array(x,y)))) |
+                               map!( local_name,
+                                     |name| intern(tps,TypeData::Named(name)))) >>
+                   llvm_space >>
+                   res: fold_many0!(call!(trailing_type,tps),
+                                    base,
+                                    |tp,tr| match tr {
+                                        TrailingType::Pointer(sp)
+                                            => intern(tps,TypeData::Pointer(tp,sp)),
+                                        TrailingType::Function(args,va)
+                                            => intern(tps,TypeData::Function(Some(tp),args,va))
+                                    }) >>
+                   (res)))
+}
+
+fn gep<'src,T,F>(i: &'src [u8],tps: &Types<'src>,parse: F,paren: bool) -> IResult<&'src [u8],GEP<T>>
+    where F: Fn(&'src [u8]) -> IResult<&'src [u8],T> {
+    do_parse!(i,
+              tag!("getelementptr") >>
+              llvm_space >>
+              inb: map!(opt!(terminated!(tag!("inbounds"),llvm_space)),
+                        |x| x.is_some()) >>
+              cond!(paren,terminated!(char!('('),
+                                      llvm_space)) >>
+              tp: call!(types,tps) >>
+              llvm_space >>
+              ptr: call!(parse) >>
+              llvm_space >>
+              idx: many0!(do_parse!(char!(',') >>
+                                    llvm_space >>
+                                    ir: map!(opt!(terminated!(tag!("inrange"),llvm_space)),
+                                             |x| x.is_some()) >>
+                                    tp: call!(types,tps) >>
+                                    v: call!(parse) >>
+                                    llvm_space >>
+                                    (Typed { tp, val: v },ir))) >>
+              cond!(paren,char!(')')) >>
+              (GEP { ptr: Typed { tp, val: ptr }, inbounds: inb, indices: idx }))
+}
+
+// The contents of a `c"..."` string, borrowed unless it has escapes.
+fn constant_bytes<'src>(i: &'src [u8]) -> IResult<&'src [u8],Cow<'src,[u8]>> {
+    map_opt!(i,take_while!(|c| c != b'"'),decode_constant_bytes)
+}
+
+fn decode_constant_bytes<'src>(raw: &'src [u8]) -> Option<Cow<'src,[u8]>> {
+    if !raw.contains(&b'\\') {
+        return Some(Cow::Borrowed(raw))
+    }
+    let mut inp = raw;
+    let mut res = Vec::with_capacity(raw.len());
+    while !inp.is_empty() {
+        match constant_char(inp) {
+            IResult::Done(rest,c) => {
+                res.push(c.to_u8()?);
+                inp = rest;
+            },
+            _ => return None
+        }
+    }
+    Some(Cow::Owned(res))
+}
+
+pub fn constant<'src>(i: &'src [u8],tps: &Types<'src>) -> IResult<&'src [u8],Constant<'src>> {
+    alt_complete!(i,
+                  map!(tag!("null"),
+                       |_| Constant::NullPtr) |
+                  map!(tag!("undef"),
+                       |_| Constant::Undef) |
+                  map!(tag!("false"),
+                       |_| Constant::Int(BigInt::from(0))) |
+                  map!(tag!("true"),
+                       |_| Constant::Int(BigInt::from(1))) |
+                  map!(global_name,
+                       Constant::Global) |
+                  do_parse!(char!('c') >>
+                            char!('\"') >>
+                            bytes: constant_bytes >>
+                            char!('\"') >>
+                            (Constant::Bytes(bytes))) |
+                  map!(map_opt!(digit,
+                                |s| { BigInt::parse_bytes(s,10) }),
+                       Constant::Int) |
+                  map!(map_opt!(preceded!(char!('-'),digit),
+                                |s| { BigInt::parse_bytes(s,10) }),
+                       |i| Constant::Int(-i)) |
+                  map!(call!(gep,tps,|i| constant(i,tps),true),
+                       |g| Constant::GEP(Box::new(g))) |
+                  map!(delimited!(terminated!(char!('['),llvm_space),
+                                  call!(constant_elements,tps),
+                                  char!(']')),
+                       Constant::Array) |
+                  map!(delimited!(terminated!(char!('{'),llvm_space),
+                                  call!(constant_elements,tps),
+                                  char!('}')),
+                       Constant::Array) |
+                  do_parse!(op: cast_inst >>
+                            llvm_space >>
+                            char!('(') >>
+                            llvm_space >>
+                            tp: call!(types,tps) >>
+                            llvm_space >>
+                            c: call!(constant,tps) >>
+                            llvm_space >>
+                            tag!("to") >>
+                            llvm_space >>
+                            trg: call!(types,tps) >>
+                            llvm_space >>
+                            char!(')') >>
+                            (Constant::Cast(op,Box::new(Typed { tp, val: c }),trg))))
+}
+
+fn constant_elements<'src>(i: &'src [u8],tps: &Types<'src>) -> IResult<&'src [u8],Vec<Constant<'src>>> {
+    terminated!(i,
+                separated_list!(terminated!(char!(','),llvm_space),
+                                do_parse!(call!(types,tps) >>
+                                          llvm_space >>
+                                          c: call!(constant,tps) >>
+                                          llvm_space >>
+                                          (c))),
+                llvm_space)
+}
+
+fn argument<'src>(i: &'src [u8],args: Args<'_,'src>) -> IResult<&'src [u8],usize> {
+    map_opt!(i,local_name,|name: &str| args.iter().position(|&(arg_name,_)| arg_name==Some(name)))
+}
+
+fn value<'src>(i: &'src [u8],tps: &Types<'src>,args: Args<'_,'src>) -> IResult<&'src [u8],Value<'src>> {
+    alt_complete!(i,
+                  map!(call!(argument,args),
+                       Value::Argument) |
+                  map!(local_name,
+                       Value::Local) |
+                  map!(call!(constant,tps),
+                       Value::Constant))
+}
+
+fn typed_value<'src>(i: &'src [u8],tps: &Types<'src>,args: Args<'_,'src>) -> IResult<&'src [u8],Typed<Value<'src>>> {
+    alt_complete!(i,
+                  do_parse!(tag!("metadata")>>
+                            llvm_space >>
+                            r: call!(metadata,tps,args) >>
+                            (Typed { tp: intern(tps,TypeData::Metadata), val: Value::Metadata(r) })) |
+                  do_parse!(tp: call!(types,tps) >>
+                            llvm_space >>
+                            v: call!(value,tps,args) >>
+                            (Typed { tp, val: v })))
+}
+
+// A metadata string, decoded like the owned parser does: escapes are
+// two hexadecimal digits, other backslashes are kept.
+fn metadata_bytes<'src>(i: &'src [u8]) -> IResult<&'src [u8],Cow<'src,[u8]>> {
+    map!(i,take_while!(|c| c != b'"'),decode_metadata_bytes)
+}
+
+fn decode_metadata_bytes<'src>(raw: &'src [u8]) -> Cow<'src,[u8]> {
+    if !raw.contains(&b'\\') {
+        return Cow::Borrowed(raw)
+    }
+    let mut res = Vec::with_capacity(raw.len());
+    let mut pos = 0;
+    while pos < raw.len() {
+        let esc = if raw[pos]==b'\\' && pos+3 <= raw.len() {
+            str::from_utf8(&raw[pos+1..pos+3]).ok().and_then(|s| u8::from_str_radix(s,16).ok())
+        } else {
+            None
+        };
+        match esc {
+            Some(c) => {
+                res.push(c);
+                pos += 3;
+            },
+            None => {
+                res.push(raw[pos]);
+                pos += 1;
+            }
+        }
+    }
+    Cow::Owned(res)
+}
+
+fn metadata<'src>(i: &'src [u8],tps: &Types<'src>,args: Args<'_,'src>) -> IResult<&'src [u8],Metadata<'src>> {
+    alt_complete!(i,
+                  map!(tag!("null"),|_| Metadata::Null) |
+                  preceded!(char!('!'),
+                            alt!(do_parse!(char!('{') >>
+                                           llvm_space >>
+                                           els: separated_list!(delimited!(llvm_space,char!(','),llvm_space),
+                                                                call!(metadata,tps,args)) >>
+                                           llvm_space >>
+                                           char!('}') >>
+                                           (Metadata::Struct(els))) |
+                                 map!(parse_u64,Metadata::Ref) |
+                                 map!(delimited!(char!('"'),
+                                                 metadata_bytes,
+                                                 char!('"')),
+                                      Metadata::Bytes) |
+                                 do_parse!(tag!("MDLocation") >>
+                                           llvm_space >>
+                                           char!('(') >>
+                                           llvm_space >>
+                                           tag!("line:") >>
+                                           llvm_space >>
+                                           l: parse_u64 >>
+                                           llvm_space >>
+                                           char!(',') >>
+                                           llvm_space >>
+                                           tag!("column:") >>
+                                           llvm_space >>
+                                           c: parse_u64 >>
+                                           llvm_space >>
+                                           char!(',') >>
+                                           llvm_space >>
+                                           tag!("scope:") >>
+                                           llvm_space >>
+                                           sc: call!(metadata,tps,args) >>
+                                           llvm_space >>
+                                           char!(')') >>
+                                           (Metadata::Location(l,c,Box::new(sc)))))) |
+                  map!(call!(typed_value,tps,args),
+                       |v| Metadata::Value(Box::new(v))))
+}
+
+// The calling convention, return type, callee, arguments and attribute
+// groups of a call.
+type Call<'src> = (CallingConv,Option<(TypeId,ParAttrs)>,Value<'src>,Vec<Typed<Value<'src>>>,Vec<AttributeGroup>);
+
+fn call<'src>(i: &'src [u8],tps: &Types<'src>,args: Args<'_,'src>) -> IResult<&'src [u8],Call<'src>> {
+    do_parse!(i,
+              tag!("call") >>
+              llvm_space >>
+              cc: alt!(terminated!(calling_conv,llvm_space) |
+                       value!(CallingConv::C)) >>
+              pattrs: par_attrs >>
+              rtp: alt!(map!(tag!("void"),
+                             |_| None) |
+                        map!(call!(types,tps),|t| Some((t,pattrs)))) >>
+              llvm_space >>
+              fun: call!(value,tps,args) >>
+              llvm_space >>
+              char!('(') >>
+              llvm_space >>
+              call_args: separated_list!(terminated!(char!(','),
+                                                     llvm_space),
+                                         terminated!(call!(typed_value,tps,args),
+                                                     llvm_space)) >>
+              char!(')') >>
+              attrs: many0!(do_parse!(llvm_space >> char!('#') >> r: parse_u64 >> (r))) >>
+              (cc,rtp,fun,call_args,attrs))
+}
+
+fn flag<'src>(i: &'src [u8],name: &str) -> IResult<&'src [u8],bool> {
+    map!(i,opt!(preceded!(llvm_space,tag!(name))),|x| x.is_some())
+}
+
+fn bin_op(i: &[u8]) -> IResult<&[u8],BinOp> {
+    alt!(i,
+         do_parse!(tag!("add") >>
+                   nuw: call!(flag,"nuw") >>
+                   nsw: call!(flag,"nsw") >>
+                   (BinOp::Add(nuw,nsw))) |
+         do_parse!(tag!("sub") >>
+                   nuw: call!(flag,"nuw") >>
+                   nsw: call!(flag,"nsw") >>
+                   (BinOp::Sub(nuw,nsw))) |
+         do_parse!(tag!("mul") >>
+                   nuw: call!(flag,"nuw") >>
+                   nsw: call!(flag,"nsw") >>
+                   (BinOp::Mul(nuw,nsw))) |
+         map!(tag!("and"),|_| BinOp::And) |
+         map!(tag!("or"),|_| BinOp::Or) |
+         map!(tag!("xor"),|_| BinOp::XOr) |
+         map!(tag!("ashr"),|_| BinOp::AShr) |
+         map!(tag!("lshr"),|_| BinOp::LShr) |
+         map!(tag!("shl"),|_| BinOp::Shl) |
+         do_parse!(tag!("sdiv") >>
+                   exact: call!(flag,"exact") >>
+                   (BinOp::SDiv(exact))))
+}
+
+// The instructions that define a value, after the name.
+fn named_instruction<'src>(i: &'src [u8],tps: &Types<'src>,args: Args<'_,'src>,name: &'src str)
+                           -> IResult<&'src [u8],InstructionC<'src>> {
+    alt!(i,
+         map!(call!(call,tps,args),
+              |c| InstructionC::Call(Some(name),c.0,c.1,c.2,c.3,c.4)) |
+         do_parse!(tag!("icmp") >>
+                   llvm_space >>
+                   op: cmp_op >>
+                   llvm_space >>
+                   tp: call!(types,tps) >>
+                   llvm_space >>
+                   v1: call!(value,tps,args) >>
+                   llvm_space >>
+                   char!(',') >>
+                   llvm_space >>
+                   v2: call!(value,tps,args) >>
+                   (InstructionC::ICmp(name,op,tp,v1,v2))) |
+         do_parse!(tag!("load") >>
+                   llvm_space >>
+                   vol: map!(opt!(terminated!(tag!("volatile"),
+                                              llvm_space)),
+                             |x| x.is_some()) >>
+                   ptr: call!(typed_value,tps,args) >>
+                   llvm_space >>
+                   align: alignment >>
+                   (InstructionC::Unary(name,ptr,UnaryInst::Load(vol,align)))) |
+         do_parse!(op: cast_inst >>
+                   llvm_space >>
+                   val: call!(typed_value,tps,args) >>
+                   llvm_space >>
+                   tag!("to") >>
+                   llvm_space >>
+                   trg: call!(types,tps) >>
+                   (InstructionC::Unary(name,val,UnaryInst::Cast(trg,op)))) |
+         map!(call!(gep,tps,|inp| value(inp,tps,args),false),
+              |g| InstructionC::GEP(name,g)) |
+         do_parse!(tag!("select") >>
+                   llvm_space >>
+                   tag!("i1") >>
+                   llvm_space >>
+                   cond: call!(value,tps,args) >>
+                   llvm_space >>
+                   char!(',') >>
+                   llvm_space >>
+                   tp1: call!(types,tps) >>
+                   llvm_space >>
+                   v1: call!(value,tps,args) >>
+                   llvm_space >>
+                   char!(',') >>
+                   llvm_space >>
+                   call!(types,tps) >>
+                   llvm_space >>
+                   v2: call!(value,tps,args) >>
+                   (InstructionC::Select(name,cond,tp1,v1,v2))) |
+         do_parse!(tag!("phi") >>
+                   llvm_space >>
+                   tp: call!(types,tps) >>
+                   llvm_space >>
+                   trgs: separated_list!(do_parse!(llvm_space >> char!(',') >> llvm_space >> ()),
+                                         do_parse!(char!('[') >>
+                                                   llvm_space >>
+                                                   v: call!(value,tps,args) >>
+                                                   llvm_space >>
+                                                   char!(',') >>
+                                                   llvm_space >>
+                                                   blk: local_name >>
+                                                   llvm_space >>
+                                                   char!(']') >>
+                                                   ((v,blk)))) >>
+                   (InstructionC::Phi(name,tp,trgs))) |
+         do_parse!(op: bin_op >>
+                   llvm_space >>
+                   tp: call!(types,tps) >>
+                   llvm_space >>
+                   v1: call!(value,tps,args) >>
+                   llvm_space >>
+                   char!(',') >>
+                   llvm_space >>
+                   v2: call!(value,tps,args) >>
+                   (InstructionC::Bin(name,op,tp,v1,v2))) |
+         do_parse!(tag!("alloca") >>
+                   llvm_space >>
+                   tp: call!(types,tps) >>
+                   llvm_space >>
+                   num: opt!(preceded!(terminated!(char!(','),llvm_space),
+                                       call!(typed_value,tps,args))) >>
+                   align: alignment >>
+                   (InstructionC::Alloca(name,tp,num,align))))
+}
+
+fn instruction_c<'src>(i: &'src [u8],tps: &Types<'src>,args: Args<'_,'src>) -> IResult<&'src [u8],InstructionC<'src>> {
+    alt_complete!(i,
+                  map!(call!(call,tps,args),
+                       |(cc,rtp,fun,call_args,attrs)| InstructionC::Call(None,cc,rtp,fun,call_args,attrs)) |
+                  do_parse!(tag!("br") >>
+                            llvm_space >>
+                            res: alt!( do_parse!(tag!("label") >>
+                                                 llvm_space >>
+                                                 name: local_name >>
+                                                 (InstructionC::Term(Terminator::Br(name)))) |
+                                       do_parse!(tag!("i1") >>
+                                                 llvm_space >>
+                                                 c: call!(value,tps,args) >>
+                                                 llvm_space >>
+                                                 char!(',') >>
+                                                 llvm_space >>
+                                                 tag!("label") >>
+                                                 llvm_space >>
+                                                 l1: local_name >>
+                                                 llvm_space >>
+                                                 char!(',') >>
+                                                 llvm_space >>
+                                                 tag!("label") >>
+                                                 llvm_space >>
+                                                 l2: local_name >>
+                                                 (InstructionC::Term(Terminator::BrC(c,l1,l2))))) >>
+                            (res)) |
+                  map!(tag!("unreachable"),
+                       |_| InstructionC::Term(Terminator::Unreachable)) |
+                  do_parse!(tag!("store") >>
+                            llvm_space >>
+                            vol: map!(opt!(terminated!(tag!("volatile"),
+                                                       llvm_space)),
+                                      |x| x.is_some()) >>
+                            obj: call!(typed_value,tps,args) >>
+                            llvm_space >>
+                            char!(',') >>
+                            llvm_space >>
+                            ptr: call!(typed_value,tps,args) >>
+                            llvm_space >>
+                            align: alignment >>
+                            (InstructionC::Store(vol,obj,ptr,align))) |
+                  do_parse!(tag!("ret") >>
+                            llvm_space >>
+                            rval: alt_complete!( map!(call!(typed_value,tps,args),Some) |
+                                                 map!(tag!("void"),|_| None)) >>
+                            (InstructionC::Term(Terminator::Ret(rval)))) |
+                  do_parse!(tag!("switch") >>
+                            llvm_space >>
+                            tp: call!(types,tps) >>
+                            llvm_space >>
+                            val: call!(value,tps,args) >>
+                            llvm_space >>
+                            char!(',') >>
+                            llvm_space >>
+                            tag!("label") >>
+                            llvm_space >>
+                            def: local_name >>
+                            llvm_space >>
+                            char!('[') >>
+                            jmps: many0!(do_parse!(llvm_ws >>
+                                                   call!(types,tps) >>
+                                                   llvm_space >>
+                                                   v: call!(constant,tps) >>
+                                                   llvm_space >>
+                                                   char!(',') >>
+                                                   llvm_space >>
+                                                   tag!("label") >>
+                                                   llvm_space >>
+                                                   lbl: local_name >>
+                                                   (v,lbl))) >>
+                            llvm_ws >>
+                            char!(']') >>
+                            (InstructionC::Term(Terminator::Switch(tp,val,def,jmps)))) |
+                  do_parse!(name: local_name >>
+                            llvm_space >>
+                            char!('=') >>
+                            llvm_space >>
+                            cont: call!(named_instruction,tps,args,name) >>
+                            (cont)))
+}
+
+fn instruction<'src>(i: &'src [u8],tps: &Types<'src>,args: Args<'_,'src>) -> IResult<&'src [u8],Instruction<'src>> {
+    do_parse!(i,
+              cont: call!(instruction_c,tps,args) >>
+              meta: many0!(do_parse!(llvm_space >>
+                                     char!(',') >>
+                                     llvm_space >>
+                                     char!('!') >>
+                                     name: map_res!(is_not!(" \t\r\n"),
+                                                    str::from_utf8) >>
+                                     llvm_space >>
+                                     char!('!') >>
+                                     id: parse_u64 >>
+                                     (name,id))) >>
+              (Instruction { content: cont,
+                             metadata: meta }))
+}
+
+fn basic_block<'src>(i: &'src [u8],tps: &Types<'src>,args: Args<'_,'src>) -> IResult<&'src [u8],BasicBlock<'src>> {
+    do_parse!(i,
+              name: map_res!(is_not!("} \t\n:"),
+                             str::from_utf8) >>
+              char!(':') >>
+              instrs: many0!(preceded!(llvm_nl,alt!(call!(instruction,tps,args) |
+                                                    preceded!(not!(alt!(char!('}') | preceded!(is_not!("} \t\n:"),char!(':')))),
+                                                              map!(map_res!(is_not!("\n"),str::from_utf8),
+                                                                   |s| { panic!("Cannot parse instruction: {}",s) }))))) >>
+              (BasicBlock { name,
+                            instrs }))
+}
+
+// A function with its name, and whether it is a definition.
+fn function_header<'src>(i: &'src [u8],tps: &Types<'src>) -> IResult<&'src [u8],(Function<'src>,bool)> {
+    do_parse!(i,
+              is_defined: alt!(map!(tag!("define"),|_| true) |
+                               map!(tag!("declare"),|_| false)) >>
+              llvm_space >>
+              lnk: opt!(terminated!(linkage,llvm_space)) >>
+              vis: alt!(terminated!(visibility,llvm_space) |
+                        value!(Visibility::Default)) >>
+              stcls: alt!(terminated!(dll_storage_class,llvm_space) |
+                          value!(DLLStorageClass::Default)) >>
+              cc: alt!(terminated!(calling_conv,llvm_space) |
+                       value!(CallingConv::C)) >>
+              ret: alt!(map!(tag!("void"),
+                             |_| None) |
+                        map!(pair!(par_attrs,call!(types,tps)),Some)) >>
+              llvm_space >>
+              name: global_name >>
+              llvm_space >>
+              char!('(') >>
+              llvm_space >>
+              args: map!(separated_list!(delimited!(llvm_space,char!(','),llvm_space),
+                                         do_parse!(tp: call!(types,tps) >>
+                                                   llvm_space >>
+                                                   pattrs: par_attrs >>
+                                                   n: opt!(local_name) >>
+                                                   ((n,tp),pattrs))),
+                         |args: Vec<_>| args.into_iter().unzip::<_,_,Vec<_>,Vec<_>>()) >>
+              va: map!( opt!(do_parse!( cond!(!args.0.is_empty(),
+                                              terminated!(char!(','),llvm_space)) >>
+                                        tag!("...") >>
+                                        ())),
+                        |x| x.is_some()) >>
+              llvm_space >>
+              char!(')') >>
+              llvm_space >>
+              attrs: many0!(delimited!(char!('#'),parse_u64,llvm_space)) >>
+              llvm_space >>
+              (Function { name,
+                          linkage: lnk,
+                          visibility: vis,
+                          dll_storage_class: stcls,
+                          cconv: cc,
+                          return_type: ret,
+                          arguments: args.0,
+                          argument_attrs: args.1,
+                          var_args: va,
+                          attribute_groups: attrs,
+                          body: None },is_defined))
+}
+
+fn function_definition<'src>(i: &'src [u8],tps: &Types<'src>) -> IResult<&'src [u8],Function<'src>> {
+    do_parse!(i,
+              hd: call!(function_header,tps) >>
+              blks: cond!(hd.1,do_parse!(char!('{') >>
+                                         llvm_nl >>
+                                         blks: many0!(terminated!(call!(basic_block,tps,&hd.0.arguments[..]),llvm_nl)) >>
+                                         char!('}') >>
+                                         (blks))) >>
+              (Function { body: blks, ..hd.0 }))
+}
+
+fn global_variable<'src>(i: &'src [u8],tps: &Types<'src>) -> IResult<&'src [u8],GlobalVariable<'src>> {
+    do_parse!(i,
+              l: opt!(terminated!(linkage,llvm_space)) >>
+              v: alt!(terminated!(visibility,llvm_space) |
+                      value!(Visibility::Default)) >>
+              dll: alt!(terminated!(dll_storage_class,llvm_space) |
+                        value!(DLLStorageClass::Default)) >>
+              loc: opt!(terminated!(thread_local,llvm_space)) >>
+              ua: opt!(terminated!(unnamed_addr,llvm_space)) >>
+              addrsp: opt!(terminated!(address_space,llvm_space)) >>
+              ext: map!(opt!(terminated!(externally_initialized,llvm_space)),
+                        |v| v.is_some()) >>
+              gtp: global_type >>
+              llvm_space >>
+              tp: call!(types,tps) >>
+              llvm_space >>
+              init: opt!(terminated!(
+                  alt!(do_parse!(tag!("zeroinitializer") >>
+                                 (Constant::ZeroInit(tp))) |
+                       call!(constant,tps)),
+                  llvm_space)) >>
+              sec: opt!(do_parse!(char!(',') >>
+                                  llvm_space >>
+                                  tag!("section") >>
+                                  llvm_space >>
+                                  char!('"') >>
+                                  name: map_res!(is_not!("\""),
+                                                 str::from_utf8) >>
+                                  char!('"') >>
+                                  llvm_space >>
+                                  (name))) >>
+              align: alignment >>
+              (GlobalVariable { linkage: l,
+                                visibility: v,
+                                dll_storage_class: dll,
+                                thread_local: loc,
+                                unnamed_addr: ua,
+                                addr_space: addrsp,
+                                externally_initialized: ext,
+                                global_type: gtp,
+                                types: tp,
+                                initialization: init,
+                                section: sec,
+                                alignment: align }))
+}
+
+fn attribute<'src>(i: &'src [u8]) -> IResult<&'src [u8],Attribute<'src>> {
+    do_parse!(i,
+              name: alt!(map!(map_res!(alpha,str::from_utf8),
+                              |s| (s,false)) |
+                         map!(delimited!(char!('\"'),
+                                         map_res!(is_not!("\""),
+                                                  str::from_utf8),
+                                         char!('\"')),
+                              |s| (s,true))) >>
+              val: opt!(do_parse!(char!('=') >>
+                                  s: delimited!(char!('\"'),
+                                                map_res!(is_not!("\""),
+                                                         str::from_utf8),
+                                                char!('\"')) >>
+                                  (s))) >>
+              (Attribute { name: name.0,
+                           quoted: name.1,
+                           value: val }))
+}
+
+fn attribute_group<'src>(i: &'src [u8]) -> IResult<&'src [u8],(u64,Vec<Attribute<'src>>)> {
+    do_parse!(i,
+              tag!("attributes") >>
+              llvm_space >>
+              char!('#') >>
+              n: parse_u64 >>
+              llvm_space >>
+              char!('=') >>
+              llvm_space >>
+              char!('{') >>
+              llvm_space >>
+              attrs: many0!(terminated!(attribute,llvm_space)) >>
+              char!('}') >>
+              (n,attrs))
+}
+
+enum Element<'src> {
+    ModuleId(&'src str),
+    DataLayout(DataLayout),
+    Triple(&'src str),
+    TypeDef(&'src str,TypeId),
+    Global(&'src str,GlobalVariable<'src>),
+    Function(Function<'src>),
+    AttributeGroup(u64,Vec<Attribute<'src>>),
+    Metadata(u64,Metadata<'src>),
+    NamedMetadata(&'src str,Metadata<'src>)
+}
+
+fn module_element<'src>(i: &'src [u8],tps: &Types<'src>) -> IResult<&'src [u8],Option<Element<'src>>> {
+    let no_args: Args = &[];
+    alt!(i,
+         map!(module_id,
+              |id| Some(Element::ModuleId(id))) |
+         map!(datalayout,
+              |dl| Some(Element::DataLayout(dl))) |
+         map!(triple,
+              |tr| Some(Element::Triple(tr))) |
+         ws!(do_parse!(name: local_name >>
+                       char!('=') >>
+                       tag!("type") >>
+                       tp: call!(types,tps) >>
+                       (Some(Element::TypeDef(name,tp))))) |
+         do_parse!(name: global_name >>
+                   llvm_space >>
+                   char!('=') >>
+                   llvm_space >>
+                   glob: call!(global_variable,tps) >>
+                   (Some(Element::Global(name,glob)))) |
+         map!(call!(function_definition,tps),
+              |f| Some(Element::Function(f))) |
+         map!(attribute_group,
+              |(n,attrs)| Some(Element::AttributeGroup(n,attrs))) |
+         do_parse!(char!('!') >>
+                   name: parse_u64 >>
+                   llvm_space >>
+                   char!('=') >>
+                   llvm_space >>
+                   def: call!(metadata,tps,no_args) >>
+                   (Some(Element::Metadata(name,def)))) |
+         do_parse!(char!('!') >>
+                   name: map_res!(is_not!(" =!,\n"),
+                                  str::from_utf8) >>
+                   llvm_space >>
+                   char!('=') >>
+                   llvm_space >>
+                   def: call!(metadata,tps,no_args) >>
+                   (Some(Element::NamedMetadata(name,def)))) |
+         map!(comment,
+              |_| None))
+}
+
+/// Parse a module without copying names, see the module documentation.
+pub fn module<'src>(input: &'src [u8]) -> IResult<&'src [u8],Module<'src>> {
+    let tps = RefCell::new(TypeArena::new());
+    let mut m = Module { id: None,
+                         datalayout: DataLayout::new(),
+                         triple: None,
+                         functions: OrderedMap::new(),
+                         types: OrderedMap::new(),
+                         globals: OrderedMap::new(),
+                         attr_groups: OrderedMap::new(),
+                         named_md: OrderedMap::new(),
+                         md: OrderedMap::new(),
+                         duplicate_functions: Vec::new(),
+                         type_arena: TypeArena::new() };
+    let mut inp = input;
+    while !inp.is_empty() {
+        match module_element(inp,&tps) {
+            IResult::Done(ninp,el) => {
+                match el {
+                    Some(Element::ModuleId(id)) => m.id = Some(id),
+                    Some(Element::DataLayout(dl)) => m.datalayout = dl,
+                    Some(Element::Triple(tr)) => m.triple = Some(tr),
+                    Some(Element::TypeDef(name,tp)) => { m.types.insert(name,tp); },
+                    Some(Element::Global(name,def)) => { m.globals.insert(name,def); },
+                    Some(Element::Function(f)) => match m.functions.get_mut(f.name) {
+                        Some(old) => if old.body.is_none() {
+                            *old = f;
+                        } else if f.body.is_some() {
+                            m.duplicate_functions.push(f);
+                        },
+                        None => { m.functions.insert(f.name,f); }
+                    },
+                    Some(Element::AttributeGroup(n,attrs)) => { m.attr_groups.insert(n,attrs); },
+                    Some(Element::Metadata(n,md)) => { m.md.insert(n,md); },
+                    Some(Element::NamedMetadata(name,md)) => { m.named_md.insert(name,md); },
+                    None => {}
+                }
+                inp = ninp;
+                while !inp.is_empty() && (inp[0]==b' ' || inp[0]==b'\t' || inp[0]==b'\n') {
+                    inp = &inp[1..];
+                }
+            },
+            _ => panic!("Not parsed: {:?}",
+                        str::from_utf8(&inp[..inp.len().min(120)]))
+        }
+    }
+    m.type_arena = tps.into_inner();
+    IResult::Done(&b""[..],m)
+}
+
+#[test]
+fn test_type_arena() {
+    let tps = RefCell::new(TypeArena::new());
+    let (_,t1) = types(b"{ i32, i8* }* ",&tps).unwrap();
+    let (_,t2) = types(b"{ i32, i8* }*",&tps).unwrap();
+    let (_,t3) = types(b"%struct.s* (i8*, ...)*",&tps).unwrap();
+    assert_eq!(t1,t2);
+    let mut tps = tps.into_inner();
+    assert_eq!(tps.to_type(t1),Type::ptr(Type::Struct(vec![Type::Int(32),Type::ptr(Type::Int(8))])));
+    let fun = Type::Function(Some(Box::new(Type::ptr(Type::Named("struct.s".to_string())))),
+                             vec![Type::ptr(Type::Int(8))],true);
+    assert_eq!(tps.to_type(t3),Type::ptr(fun.clone()));
+    let i8p = tps.lookup(&TypeData::Int(8)).and_then(|i8| tps.lookup(&TypeData::Pointer(i8,None)));
+    assert!(i8p.is_some());
+    let n = tps.len();
+    let ptr = Type::ptr(fun);
+    assert_eq!(tps.from_type(&ptr),t3);
+    assert_eq!(tps.len(),n);
+}
+
+#[test]
+fn test_borrowed_module() {
+    let src = include_bytes!("minisat.ll");
+    let m = match module(src) {
+        IResult::Done(_,m) => m,
+        _ => panic!("parse failure")
+    };
+    let owned = ::parse_test_module(src);
+    assert_eq!(m.to_module(),owned);
+    let range = src.as_ptr_range();
+    let main = &m.functions["main"];
+    assert!(range.contains(&main.name.as_ptr()));
+    let entry = &main.body.as_ref().unwrap()[0];
+    assert!(range.contains(&entry.name.as_ptr()));
+    match m.globals["sampleFile"].initialization {
+        Some(Constant::Bytes(Cow::Owned(ref bytes))) => assert!(bytes.starts_with(b"p cnf 10 22\n")),
+        ref init => panic!("unexpected initialization {:?}",init)
+    }
+}
@@ -0,0 +1,651 @@
+//! A programmatic builder for functions and instructions.
+//!
+//! The builder is positioned at a point in a basic block and inserts
+//! every new instruction there. Operands are `Typed<Value>`s, so the
+//! builder can check the types of operands and compute the type of
+//! every result.
+use num_bigint::BigInt;
+use std::collections::{HashMap,HashSet};
+use std::fmt;
+use super::*;
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum BuildError {
+    UnknownFunction(String),
+    DuplicateFunction(String),
+    NotDefined(String),
+    UnknownBlock(String),
+    UnknownValue(Value),
+    /// No insertion point has been set.
+    NotPositioned,
+    /// An instruction was inserted after the terminator of a block.
+    BlockTerminated(String),
+    TypeMismatch { expected: Type, found: Type },
+    NotAnInteger(Type),
+    NotAPointer(Type),
+    NotAFunction(Type),
+    ArgumentCount { expected: usize, found: usize },
+    /// A struct was indexed with a non-constant or out of range index.
+    InvalidIndex(Type)
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::UnknownFunction(ref n) => write!(f,"unknown function @{}",n),
+            BuildError::DuplicateFunction(ref n) => write!(f,"function @{} already exists",n),
+            BuildError::NotDefined(ref n) => write!(f,"function @{} has no body",n),
+            BuildError::UnknownBlock(ref n) => write!(f,"unknown block %{}",n),
+            BuildError::UnknownValue(ref v) => write!(f,"unknown value {:?}",v),
+            BuildError::NotPositioned => write!(f,"builder has no insertion point"),
+            BuildError::BlockTerminated(ref n) => write!(f,"block %{} is already terminated",n),
+            BuildError::TypeMismatch { ref expected, ref found } =>
+                write!(f,"type mismatch: expected {:?}, found {:?}",expected,found),
+            BuildError::NotAnInteger(ref t) => write!(f,"expected an integer type, found {:?}",t),
+            BuildError::NotAPointer(ref t) => write!(f,"expected a pointer type, found {:?}",t),
+            BuildError::NotAFunction(ref t) => write!(f,"expected a function type, found {:?}",t),
+            BuildError::ArgumentCount { expected, found } =>
+                write!(f,"expected {} arguments, found {}",expected,found),
+            BuildError::InvalidIndex(ref t) => write!(f,"invalid index into {:?}",t)
+        }
+    }
+}
+
+impl ::std::error::Error for BuildError {}
+
+pub type BuildResult<T> = Result<T,BuildError>;
+
+/// Resolve named types until a structural type is reached.
+pub fn resolve_type<'a>(types: &'a OrderedMap<String,Type>,tp: &'a Type) -> &'a Type {
+    let mut cur = tp;
+    while let Type::Named(ref n) = *cur {
+        match types.get(n) {
+            Some(t) => cur = t,
+            None => break
+        }
+    }
+    cur
+}
+
+/// The type of the value computed by a `getelementptr` on a pointer of
+/// type `ptr_tp`. Struct indices have to be constants, they are given
+/// as `Some(idx)`; other indices may be `None`.
+pub fn gep_type(types: &OrderedMap<String,Type>,ptr_tp: &Type,indices: &[Option<u64>]) -> BuildResult<Type> {
+    let (mut cur,sp) = match *resolve_type(types,ptr_tp) {
+        Type::Pointer(ref el,sp) => ((**el).clone(),sp),
+        ref t => return Err(BuildError::NotAPointer(t.clone()))
+    };
+    for idx in indices.iter().skip(1) {
+        cur = match *resolve_type(types,&cur) {
+            Type::Array(_,ref el) => (**el).clone(),
+            Type::Struct(ref els) => match *idx {
+                Some(i) if (i as usize) < els.len() => els[i as usize].clone(),
+                _ => return Err(BuildError::InvalidIndex(cur.clone()))
+            },
+            ref t => return Err(BuildError::InvalidIndex(t.clone()))
+        };
+    }
+    Ok(Type::Pointer(Box::new(cur),sp))
+}
+
+fn gep_indices<T>(g: &GEP<T>,as_const: fn(&T) -> Option<u64>) -> Vec<Option<u64>> {
+    g.indices.iter().map(|(i,_)| as_const(&i.val)).collect()
+}
+
+fn const_index(v: &Value) -> Option<u64> {
+    match *v {
+        Value::Constant(Constant::Int(ref i)) => ::num_traits::ToPrimitive::to_u64(i),
+        _ => None
+    }
+}
+
+/// The return type of a call whose callee has type `tp`, which is
+/// either a function pointer type or just the return type.
+pub fn call_return_type(tp: &Type) -> Option<Type> {
+    match *tp {
+        Type::Pointer(ref el,_) => match **el {
+            Type::Function(ref ret,_,_) => ret.as_ref().map(|r| (**r).clone()),
+            _ => Some(tp.clone())
+        },
+        Type::Function(ref ret,_,_) => ret.as_ref().map(|r| (**r).clone()),
+        _ => Some(tp.clone())
+    }
+}
+
+/// The type of the value defined by an instruction, `None` if it does
+/// not define one.
+pub fn instruction_type(types: &OrderedMap<String,Type>,instr: &InstructionC) -> Option<Type> {
+    match *instr {
+        InstructionC::Alloca(_,ref tp,_,_) => Some(Type::ptr(tp.clone())),
+        InstructionC::Call(Some(_),_,Some((ref tp,_)),_,_,_) => call_return_type(tp),
+        InstructionC::ICmp(..) => Some(Type::Int(1)),
+        InstructionC::Unary(_,ref v,UnaryInst::Load(..)) => match *resolve_type(types,&v.tp) {
+            Type::Pointer(ref el,_) => Some((**el).clone()),
+            _ => None
+        },
+        InstructionC::Unary(_,_,UnaryInst::Cast(ref tp,_)) => Some(tp.clone()),
+        InstructionC::GEP(_,ref g) => gep_type(types,&g.ptr.tp,&gep_indices(g,const_index)).ok(),
+        InstructionC::Select(_,_,ref tp,_,_) |
+        InstructionC::Phi(_,ref tp,_) |
+        InstructionC::Bin(_,_,ref tp,_,_) => Some(tp.clone()),
+        _ => None
+    }
+}
+
+pub struct IRBuilder<'m> {
+    module: &'m mut Module,
+    function: Option<String>,
+    block: usize,
+    insert_at: usize,
+    // Types of the locals of the current function
+    locals: HashMap<String,Type>,
+    // Names of locals, arguments and blocks of the current function,
+    // which share one namespace
+    taken: HashSet<String>,
+    // Names of the blocks of the current function
+    labels: HashSet<String>
+}
+
+impl<'m> IRBuilder<'m> {
+    pub fn new(module: &'m mut Module) -> IRBuilder<'m> {
+        IRBuilder { module,
+                    function: None,
+                    block: 0,
+                    insert_at: 0,
+                    locals: HashMap::new(),
+                    taken: HashSet::new(),
+                    labels: HashSet::new() }
+    }
+    pub fn module(&self) -> &Module {
+        self.module
+    }
+    /// Add an empty function definition to the module. Use
+    /// `append_block` to give it an entry block.
+    pub fn create_function(&mut self,name: &str,ret: Option<Type>,args: Vec<(Option<String>,Type)>,var_args: bool)
+                           -> BuildResult<()> {
+        self.add_function(name,ret,args,var_args,Some(Vec::new()))
+    }
+    /// Add a function declaration to the module.
+    pub fn declare_function(&mut self,name: &str,ret: Option<Type>,args: Vec<Type>,var_args: bool)
+                            -> BuildResult<()> {
+        self.add_function(name,ret,args.into_iter().map(|t| (None,t)).collect(),var_args,None)
+    }
+    fn add_function(&mut self,name: &str,ret: Option<Type>,args: Vec<(Option<String>,Type)>,var_args: bool,
+                    body: Option<Vec<BasicBlock>>) -> BuildResult<()> {
+        if self.module.functions.contains_key(name) {
+            return Err(BuildError::DuplicateFunction(name.to_string()))
+        }
+        self.module.functions.insert(name.to_string(),
+                                     Function { name: name.to_string(),
+                                                linkage: None,
+                                                visibility: Visibility::Default,
+                                                dll_storage_class: DLLStorageClass::Default,
+                                                cconv: CallingConv::C,
+                                                return_type: ret.map(|t| (ParAttrs::new(),t)),
+                                                argument_attrs: vec![ParAttrs::new(); args.len()],
+                                                arguments: args,
+                                                var_args,
+                                                attribute_groups: Vec::new(),
+                                                body });
+        Ok(())
+    }
+    fn current_function(&self) -> BuildResult<&Function> {
+        match self.function {
+            Some(ref f) => Ok(&self.module.functions[f]),
+            None => Err(BuildError::NotPositioned)
+        }
+    }
+    fn current_body(&mut self) -> BuildResult<&mut Vec<BasicBlock>> {
+        match self.function {
+            Some(ref f) => Ok(self.module.functions.get_mut(f).unwrap().body.as_mut().unwrap()),
+            None => Err(BuildError::NotPositioned)
+        }
+    }
+    fn enter_function(&mut self,name: &str) -> BuildResult<()> {
+        if self.function.as_ref().map(|f| &f[..])==Some(name) {
+            return Ok(())
+        }
+        let fun = match self.module.functions.get(name) {
+            Some(f) => f,
+            None => return Err(BuildError::UnknownFunction(name.to_string()))
+        };
+        let blks = match fun.body {
+            Some(ref b) => b,
+            None => return Err(BuildError::NotDefined(name.to_string()))
+        };
+        let mut locals = HashMap::new();
+        let mut labels = HashSet::new();
+        for blk in blks.iter() {
+            labels.insert(blk.name.clone());
+            for instr in blk.instrs.iter() {
+                if let Some(n) = instr.content.name() {
+                    let tp = instruction_type(&self.module.types,&instr.content)
+                        .unwrap_or(Type::Opaque);
+                    locals.insert(n.to_string(),tp);
+                }
+            }
+        }
+        let mut taken: HashSet<String> = locals.keys().cloned().collect();
+        taken.extend(fun.arguments.iter().filter_map(|(n,_)| n.clone()));
+        taken.extend(labels.iter().cloned());
+        self.function = Some(name.to_string());
+        self.locals = locals;
+        self.taken = taken;
+        self.labels = labels;
+        Ok(())
+    }
+    /// Append a new block to a function. If the name is already taken,
+    /// a number is appended to it. Returns the name of the new block.
+    pub fn append_block(&mut self,function: &str,name: &str) -> BuildResult<String> {
+        self.enter_function(function)?;
+        let name = fresh(|c| self.taken.contains(c),name);
+        self.taken.insert(name.clone());
+        self.labels.insert(name.clone());
+        self.current_body()?.push(BasicBlock { name: name.clone(), instrs: Vec::new() });
+        Ok(name)
+    }
+    /// Insert new instructions at the end of a block.
+    pub fn position_at_end(&mut self,function: &str,block: &str) -> BuildResult<()> {
+        self.enter_function(function)?;
+        let (idx,len) = {
+            let blks = self.current_body()?;
+            match blks.iter().position(|b| b.name==block) {
+                Some(i) => (i,blks[i].instrs.len()),
+                None => return Err(BuildError::UnknownBlock(block.to_string()))
+            }
+        };
+        self.block = idx;
+        self.insert_at = len;
+        Ok(())
+    }
+    /// Insert new instructions before the `index`-th instruction of a
+    /// block.
+    pub fn position_before(&mut self,function: &str,block: &str,index: usize) -> BuildResult<()> {
+        self.position_at_end(function,block)?;
+        self.insert_at = ::std::cmp::min(index,self.insert_at);
+        Ok(())
+    }
+    /// The name of the block new instructions are inserted into.
+    pub fn current_block(&self) -> BuildResult<&str> {
+        let f = self.current_function()?;
+        Ok(&f.body.as_ref().unwrap()[self.block].name)
+    }
+    /// The `n`-th argument of the current function.
+    pub fn argument(&self,n: usize) -> BuildResult<Typed<Value>> {
+        let f = self.current_function()?;
+        match f.arguments.get(n) {
+            Some((_,tp)) => Ok(Typed::new(tp.clone(),Value::Argument(n))),
+            None => Err(BuildError::UnknownValue(Value::Argument(n)))
+        }
+    }
+    /// An existing local or named argument of the current function.
+    pub fn local(&self,name: &str) -> BuildResult<Typed<Value>> {
+        let f = self.current_function()?;
+        if let Some(n) = f.arguments.iter().position(|(n,_)| n.as_ref().map(|n| &n[..])==Some(name)) {
+            return self.argument(n)
+        }
+        match self.locals.get(name) {
+            Some(tp) => Ok(Typed::new(tp.clone(),Value::Local(name.to_string()))),
+            None => Err(BuildError::UnknownValue(Value::Local(name.to_string())))
+        }
+    }
+    pub fn const_int(&self,bits: u64,val: i64) -> Typed<Value> {
+        Typed::new(Type::Int(bits),Value::Constant(Constant::Int(BigInt::from(val))))
+    }
+    pub fn const_null(&self,tp: Type) -> Typed<Value> {
+        Typed::new(tp,Value::Constant(Constant::NullPtr))
+    }
+    /// A pointer to a global variable or function of the module.
+    pub fn global(&self,name: &str) -> BuildResult<Typed<Value>> {
+        let tp = if let Some(g) = self.module.globals.get(name) {
+            Type::Pointer(Box::new(g.types.clone()),g.addr_space)
+        } else if let Some(f) = self.module.functions.get(name) {
+            Type::ptr(function_type(f))
+        } else {
+            return Err(BuildError::UnknownValue(Value::Constant(Constant::Global(name.to_string()))))
+        };
+        Ok(Typed::new(tp,Value::Constant(Constant::Global(name.to_string()))))
+    }
+
+    fn insert(&mut self,instr: InstructionC) -> BuildResult<()> {
+        let (blk,at) = (self.block,self.insert_at);
+        let is_term = matches!(instr,InstructionC::Term(_));
+        {
+            let body = self.current_body()?;
+            let b = &mut body[blk];
+            if at==b.instrs.len() && b.terminator().is_some() {
+                return Err(BuildError::BlockTerminated(b.name.clone()))
+            }
+            if is_term && at < b.instrs.len() {
+                return Err(BuildError::BlockTerminated(b.name.clone()))
+            }
+            b.instrs.insert(at,Instruction { content: instr, metadata: OrderedMap::new() });
+        }
+        self.insert_at += 1;
+        Ok(())
+    }
+    fn define(&mut self,hint: &str,tp: Type,mk: impl FnOnce(String) -> InstructionC) -> BuildResult<Typed<Value>> {
+        self.current_function()?;
+        let name = fresh(|c| self.taken.contains(c),hint);
+        self.insert(mk(name.clone()))?;
+        self.taken.insert(name.clone());
+        self.locals.insert(name.clone(),tp.clone());
+        Ok(Typed::new(tp,Value::Local(name)))
+    }
+    fn int_type(&self,v: &Typed<Value>) -> BuildResult<u64> {
+        match *resolve_type(&self.module.types,&v.tp) {
+            Type::Int(w) => Ok(w),
+            ref t => Err(BuildError::NotAnInteger(t.clone()))
+        }
+    }
+    fn pointee(&self,v: &Typed<Value>) -> BuildResult<Type> {
+        match *resolve_type(&self.module.types,&v.tp) {
+            Type::Pointer(ref el,_) => Ok((**el).clone()),
+            ref t => Err(BuildError::NotAPointer(t.clone()))
+        }
+    }
+
+    /// A binary integer operation. Both operands must have the same
+    /// integer type.
+    pub fn bin(&mut self,op: BinOp,lhs: Typed<Value>,rhs: Typed<Value>) -> BuildResult<Typed<Value>> {
+        self.int_type(&lhs)?;
+        expect_type(&lhs.tp,&rhs.tp)?;
+        let tp = lhs.tp.clone();
+        self.define("tmp",tp.clone(),|n| InstructionC::Bin(n,op,tp,lhs.val,rhs.val))
+    }
+    pub fn add(&mut self,lhs: Typed<Value>,rhs: Typed<Value>) -> BuildResult<Typed<Value>> {
+        self.bin(BinOp::Add(false,false),lhs,rhs)
+    }
+    pub fn sub(&mut self,lhs: Typed<Value>,rhs: Typed<Value>) -> BuildResult<Typed<Value>> {
+        self.bin(BinOp::Sub(false,false),lhs,rhs)
+    }
+    pub fn mul(&mut self,lhs: Typed<Value>,rhs: Typed<Value>) -> BuildResult<Typed<Value>> {
+        self.bin(BinOp::Mul(false,false),lhs,rhs)
+    }
+    /// An integer or pointer comparison, yielding an `i1`.
+    pub fn icmp(&mut self,op: CmpOp,lhs: Typed<Value>,rhs: Typed<Value>) -> BuildResult<Typed<Value>> {
+        if self.int_type(&lhs).is_err() {
+            self.pointee(&lhs)?;
+        }
+        expect_type(&lhs.tp,&rhs.tp)?;
+        let tp = lhs.tp.clone();
+        self.define("cmp",Type::Int(1),|n| InstructionC::ICmp(n,op,tp,lhs.val,rhs.val))
+    }
+    pub fn alloca(&mut self,tp: Type) -> BuildResult<Typed<Value>> {
+        self.define("alloca",Type::ptr(tp.clone()),|n| InstructionC::Alloca(n,tp,None,None))
+    }
+    pub fn load(&mut self,ptr: Typed<Value>) -> BuildResult<Typed<Value>> {
+        let tp = self.pointee(&ptr)?;
+        self.define("load",tp,|n| InstructionC::Unary(n,ptr,UnaryInst::Load(false,None)))
+    }
+    pub fn store(&mut self,val: Typed<Value>,ptr: Typed<Value>) -> BuildResult<()> {
+        let tp = self.pointee(&ptr)?;
+        expect_type(&tp,&val.tp)?;
+        self.insert(InstructionC::Store(false,val,ptr,None))
+    }
+    /// Compute the address of an element. Indices into structs must be
+    /// integer constants.
+    pub fn gep(&mut self,ptr: Typed<Value>,indices: Vec<Typed<Value>>,inbounds: bool) -> BuildResult<Typed<Value>> {
+        for idx in indices.iter() {
+            self.int_type(idx)?;
+        }
+        let consts: Vec<Option<u64>> = indices.iter().map(|i| const_index(&i.val)).collect();
+        let tp = gep_type(&self.module.types,&ptr.tp,&consts)?;
+        self.define("gep",tp,|n| InstructionC::GEP(n,GEP { ptr,
+                                                           inbounds,
+                                                           indices: indices.into_iter()
+                                                               .map(|i| (i,false))
+                                                               .collect() }))
+    }
+    pub fn cast(&mut self,op: CastInst,val: Typed<Value>,to: Type) -> BuildResult<Typed<Value>> {
+        match op {
+            CastInst::Trunc | CastInst::ZExt | CastInst::SExt => {
+                self.int_type(&val)?;
+                if let Type::Int(_) = *resolve_type(&self.module.types,&to) {} else {
+                    return Err(BuildError::NotAnInteger(to))
+                }
+            },
+            CastInst::PtrToInt => {
+                self.pointee(&val)?;
+            },
+            CastInst::IntToPtr => {
+                self.int_type(&val)?;
+            },
+            CastInst::Bitcast => {}
+        }
+        let tp = to.clone();
+        self.define("conv",to,|n| InstructionC::Unary(n,val,UnaryInst::Cast(tp,op)))
+    }
+    pub fn select(&mut self,cond: Typed<Value>,v1: Typed<Value>,v2: Typed<Value>) -> BuildResult<Typed<Value>> {
+        expect_type(&Type::Int(1),&cond.tp)?;
+        expect_type(&v1.tp,&v2.tp)?;
+        let tp = v1.tp.clone();
+        self.define("sel",tp.clone(),|n| InstructionC::Select(n,cond.val,tp,v1.val,v2.val))
+    }
+    /// Call a function of the module by name. Returns the result, or
+    /// `None` for `void` functions.
+    pub fn call(&mut self,function: &str,args: Vec<Typed<Value>>) -> BuildResult<Option<Typed<Value>>> {
+        let callee = self.global(function)?;
+        self.call_indirect(callee,args)
+    }
+    /// Call a function pointer.
+    pub fn call_indirect(&mut self,callee: Typed<Value>,args: Vec<Typed<Value>>) -> BuildResult<Option<Typed<Value>>> {
+        let (ret,params,va) = match self.pointee(&callee)? {
+            Type::Function(ret,params,va) => (ret,params,va),
+            t => return Err(BuildError::NotAFunction(t))
+        };
+        if args.len() < params.len() || (!va && args.len()!=params.len()) {
+            return Err(BuildError::ArgumentCount { expected: params.len(), found: args.len() })
+        }
+        for (p,a) in params.iter().zip(args.iter()) {
+            expect_type(p,&a.tp)?;
+        }
+        // Variadic callees are called with the full function pointer
+        // type, like the assembler does.
+        let call_tp = if va { Some(callee.tp.clone()) } else { ret.as_ref().map(|r| (**r).clone()) };
+        match ret {
+            None => {
+                self.insert(InstructionC::Call(None,CallingConv::C,None,callee.val,args,Vec::new()))?;
+                Ok(None)
+            },
+            Some(ret) => {
+                let ctp = call_tp.unwrap();
+                self.define("call",*ret,|n| InstructionC::Call(Some(n),CallingConv::C,
+                                                               Some((ctp,ParAttrs::new())),
+                                                               callee.val,args,Vec::new()))
+                    .map(Some)
+            }
+        }
+    }
+    /// A phi node. Further incoming values can be added with
+    /// `add_incoming`.
+    pub fn phi(&mut self,tp: Type,incoming: Vec<(Typed<Value>,String)>) -> BuildResult<Typed<Value>> {
+        for (v,_) in incoming.iter() {
+            expect_type(&tp,&v.tp)?;
+        }
+        let tp2 = tp.clone();
+        self.define("phi",tp,|n| InstructionC::Phi(n,tp2,incoming.into_iter()
+                                                     .map(|(v,l)| (v.val,l))
+                                                     .collect()))
+    }
+    /// Add an incoming value to a phi node of the current function.
+    pub fn add_incoming(&mut self,phi: &Typed<Value>,val: Typed<Value>,block: &str) -> BuildResult<()> {
+        expect_type(&phi.tp,&val.tp)?;
+        let name = match phi.val {
+            Value::Local(ref n) => n.clone(),
+            ref v => return Err(BuildError::UnknownValue(v.clone()))
+        };
+        for blk in self.current_body()?.iter_mut() {
+            for instr in blk.instrs.iter_mut() {
+                if let InstructionC::Phi(ref n,_,ref mut inc) = instr.content {
+                    if *n==name {
+                        inc.push((val.val,block.to_string()));
+                        return Ok(())
+                    }
+                }
+            }
+        }
+        Err(BuildError::UnknownValue(phi.val.clone()))
+    }
+    pub fn br(&mut self,target: &str) -> BuildResult<()> {
+        self.check_label(target)?;
+        self.insert(InstructionC::Term(Terminator::Br(target.to_string())))
+    }
+    pub fn cond_br(&mut self,cond: Typed<Value>,then_blk: &str,else_blk: &str) -> BuildResult<()> {
+        expect_type(&Type::Int(1),&cond.tp)?;
+        self.check_label(then_blk)?;
+        self.check_label(else_blk)?;
+        self.insert(InstructionC::Term(Terminator::BrC(cond.val,then_blk.to_string(),else_blk.to_string())))
+    }
+    pub fn switch(&mut self,val: Typed<Value>,default: &str,cases: Vec<(i64,String)>) -> BuildResult<()> {
+        self.int_type(&val)?;
+        self.check_label(default)?;
+        for (_,l) in cases.iter() {
+            self.check_label(l)?;
+        }
+        self.insert(InstructionC::Term(Terminator::Switch(val.tp,val.val,default.to_string(),
+                                                          cases.into_iter()
+                                                          .map(|(c,l)| (Constant::Int(BigInt::from(c)),l))
+                                                          .collect())))
+    }
+    pub fn ret(&mut self,val: Option<Typed<Value>>) -> BuildResult<()> {
+        let expected = self.current_function()?.return_type.as_ref().map(|(_,t)| t.clone());
+        match (expected,val.as_ref()) {
+            (None,None) => {},
+            (Some(e),Some(v)) => expect_type(&e,&v.tp)?,
+            (Some(e),None) => return Err(BuildError::TypeMismatch { expected: e, found: Type::Opaque }),
+            (None,Some(v)) => return Err(BuildError::TypeMismatch { expected: Type::Opaque, found: v.tp.clone() })
+        }
+        self.insert(InstructionC::Term(Terminator::Ret(val)))
+    }
+    pub fn unreachable(&mut self) -> BuildResult<()> {
+        self.insert(InstructionC::Term(Terminator::Unreachable))
+    }
+    fn check_label(&self,l: &str) -> BuildResult<()> {
+        if self.labels.contains(l) {
+            Ok(())
+        } else {
+            Err(BuildError::UnknownBlock(l.to_string()))
+        }
+    }
+}
+
+/// The type of a function, as used for pointers to it.
+pub fn function_type(f: &Function) -> Type {
+    Type::Function(f.return_type.as_ref().map(|(_,t)| Box::new(t.clone())),
+                   f.arguments.iter().map(|(_,t)| t.clone()).collect(),
+                   f.var_args)
+}
+
+fn expect_type(expected: &Type,found: &Type) -> BuildResult<()> {
+    if expected==found {
+        Ok(())
+    } else {
+        Err(BuildError::TypeMismatch { expected: expected.clone(), found: found.clone() })
+    }
+}
+
+/// The hint itself if it is not taken, otherwise the hint followed by
+/// the smallest number that gives a free name.
+fn fresh<F: Fn(&str) -> bool>(taken: F,hint: &str) -> String {
+    let mut cand = hint.to_string();
+    let mut n = 0;
+    while taken(&cand) {
+        cand = format!("{}{}",hint,n);
+        n += 1;
+    }
+    cand
+}
+
+#[test]
+fn test_build_loop() {
+    // sum(n) = 0 + 1 + ... + (n-1)
+    let mut m = Module::new();
+    {
+        let mut b = IRBuilder::new(&mut m);
+        b.create_function("sum",Some(Type::Int(32)),vec![(Some("n".to_string()),Type::Int(32))],false).unwrap();
+        let entry = b.append_block("sum","entry").unwrap();
+        let head = b.append_block("sum","loop").unwrap();
+        let body = b.append_block("sum","loop").unwrap();
+        let exit = b.append_block("sum","exit").unwrap();
+        assert_eq!(body,"loop0");
+        b.position_at_end("sum",&entry).unwrap();
+        b.br(&head).unwrap();
+        b.position_at_end("sum",&head).unwrap();
+        let zero = b.const_int(32,0);
+        let i = b.phi(Type::Int(32),vec![(zero.clone(),entry.clone())]).unwrap();
+        let acc = b.phi(Type::Int(32),vec![(zero,entry.clone())]).unwrap();
+        let n = b.argument(0).unwrap();
+        let c = b.icmp(CmpOp::SLt,i.clone(),n).unwrap();
+        b.cond_br(c,&body,&exit).unwrap();
+        b.position_at_end("sum",&body).unwrap();
+        let acc2 = b.add(acc.clone(),i.clone()).unwrap();
+        let one = b.const_int(32,1);
+        let i2 = b.add(i.clone(),one).unwrap();
+        b.add_incoming(&i,i2,&body).unwrap();
+        b.add_incoming(&acc,acc2,&body).unwrap();
+        b.br(&head).unwrap();
+        b.position_at_end("sum",&exit).unwrap();
+        b.ret(Some(acc)).unwrap();
+        assert_eq!(b.br(&head),Err(BuildError::BlockTerminated("exit".to_string())));
+    }
+    let f = &m.functions["sum"];
+    let blks = f.body.as_ref().unwrap();
+    assert_eq!(blks.len(),4);
+    assert_eq!(blks[1].instrs[0].content,
+               InstructionC::Phi("phi".to_string(),Type::Int(32),
+                                 vec![(Value::Constant(Constant::Int(BigInt::from(0))),"entry".to_string()),
+                                      (Value::Local("tmp0".to_string()),"loop0".to_string())]));
+    let li = ::loops::LoopInfo::for_function(f).unwrap();
+    assert_eq!(li.loops().len(),1);
+    let idx = ::ssa::SsaIndex::new(f);
+    assert_eq!(idx.uses(&Value::Local("phi0".to_string())).len(),2);
+}
+
+#[test]
+fn test_build_shared_namespace() {
+    // Blocks, arguments and locals share one namespace
+    let mut m = Module::new();
+    let mut b = IRBuilder::new(&mut m);
+    b.create_function("f",Some(Type::Int(32)),vec![(Some("x".to_string()),Type::Int(32))],false).unwrap();
+    assert_eq!(b.append_block("f","x"),Ok("x0".to_string()));
+    assert_eq!(b.append_block("f","tmp"),Ok("tmp".to_string()));
+    b.position_at_end("f","x0").unwrap();
+    let x = b.argument(0).unwrap();
+    let sum = b.add(x.clone(),x).unwrap();
+    assert_eq!(sum.val,Value::Local("tmp0".to_string()));
+}
+
+#[test]
+fn test_build_errors() {
+    let mut m = minisat();
+    let mut b = IRBuilder::new(&mut m);
+    let x = b.const_int(32,1);
+    assert_eq!(b.add(x.clone(),x.clone()),Err(BuildError::NotPositioned));
+    // Instrument the entry of main with a call to printf
+    b.position_before("main","entry",0).unwrap();
+    let argc = b.argument(0).unwrap();
+    let wide = b.const_int(64,1);
+    assert_eq!(b.add(argc.clone(),wide),
+               Err(BuildError::TypeMismatch { expected: Type::Int(32), found: Type::Int(64) }));
+    assert_eq!(b.load(argc.clone()),Err(BuildError::NotAPointer(Type::Int(32))));
+    let fmt = b.global(".str6").unwrap();
+    let zero = b.const_int(32,0);
+    let s = b.gep(fmt,vec![zero.clone(),zero.clone()],true).unwrap();
+    assert_eq!(s.tp,Type::ptr(Type::Int(8)));
+    assert!(b.call("printf",vec![s,argc.clone(),argc.clone()]).unwrap().is_some());
+    assert_eq!(b.call("printf",vec![]),Err(BuildError::ArgumentCount { expected: 1, found: 0 }));
+    assert_eq!(b.call("exit",vec![argc.clone()]),Ok(None));
+    // The solver struct is a named type
+    b.position_before("main","if.end10",0).unwrap();
+    let solver = b.local("call").unwrap();
+    assert_eq!(solver.tp,Type::ptr(Type::Named("struct.solver_t".to_string())));
+    assert_eq!(b.local("argc"),Ok(argc.clone()));
+    let verb = b.gep(solver.clone(),vec![zero.clone(),b.const_int(32,27)],true).unwrap();
+    assert_eq!(verb.tp,Type::ptr(Type::Int(32)));
+    assert_eq!(b.gep(solver,vec![zero,b.const_int(32,29)],true).map(|_| ()),
+               Err(BuildError::InvalidIndex(Type::Named("struct.solver_t".to_string()))));
+    assert_eq!(b.current_block(),Ok("if.end10"));
+    let main = &b.module().functions["main"];
+    assert_eq!(main.body.as_ref().unwrap()[0].instrs[1].content.name(),Some("call0"));
+}
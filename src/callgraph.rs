@@ -0,0 +1,671 @@
+//! The call graph of a module.
+//!
+//! Every function of the module, defined or declared, is a node,
+//! identified by its index in the graph. Nodes are numbered in the
+//! order of the function names. An additional external node stands
+//! for code outside the module: declarations call it, and so do
+//! indirect calls whose targets are not completely known. It has no
+//! outgoing edges; functions that outside code may call are listed by
+//! `roots` instead. Calls to intrinsics (`llvm.*`) are not edges.
+//!
+//! Targets of indirect calls are either found by following function
+//! pointers through casts, phis, selects, loads from globals or allocas
+//! that are only loaded and stored, and arguments of functions whose
+//! call sites are all known (`CallGraph::new`), or taken from a
+//! points-to analysis (`CallGraph::with_points_to`).
+use std::collections::{BTreeSet,HashMap,HashSet};
+use super::*;
+use alias::{MemoryObject,PointsTo};
+use ssa::Position;
+
+/// A call instruction and the nodes it may call.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct CallSite {
+    pub position: Position,
+    /// Whether the callee is not a function constant.
+    pub indirect: bool,
+    /// The possible callees in ascending order, including the external
+    /// node if some targets are unknown.
+    pub targets: Vec<usize>
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct CallGraph {
+    names: Vec<String>,
+    index: HashMap<String,usize>,
+    sites: Vec<Vec<CallSite>>,
+    succs: Vec<Vec<usize>>,
+    preds: Vec<Vec<usize>>,
+    defined: Vec<bool>,
+    roots: Vec<usize>
+}
+
+impl CallGraph {
+    /// Build the call graph, resolving indirect calls by tracking
+    /// function pointers within the module.
+    pub fn new(m: &Module) -> CallGraph {
+        let tracker = Tracker::new(m);
+        CallGraph::build(m,|fun,callee| tracker.call_targets(fun,callee))
+    }
+    /// Build the call graph, resolving indirect calls with the result of
+    /// a points-to analysis of the module.
+    pub fn with_points_to(m: &Module,pt: &PointsTo) -> CallGraph {
+        CallGraph::build(m,|fun,callee| {
+            let mut targets = BTreeSet::new();
+            let mut complete = true;
+            for obj in pt.call_targets(fun,callee) {
+                match obj {
+                    MemoryObject::Function(f) => {
+                        targets.insert(f);
+                    },
+                    _ => complete = false
+                }
+            }
+            (targets,complete)
+        })
+    }
+    fn build<F>(m: &Module,resolve: F) -> CallGraph
+        where F: Fn(&Function,&Value) -> (BTreeSet<String>,bool) {
+        let mut names: Vec<String> = m.functions.keys().cloned().collect();
+        names.sort();
+        let index: HashMap<String,usize> = names.iter()
+            .enumerate()
+            .map(|(i,n)| (n.clone(),i))
+            .collect();
+        let external = names.len();
+        let mut sites = vec![Vec::new(); names.len()];
+        let mut succs: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); names.len()+1];
+        let address_taken = address_taken_functions(m);
+        let mut roots = Vec::new();
+        for (i,name) in names.iter().enumerate() {
+            let fun = &m.functions[name];
+            if is_intrinsic(name) {
+                continue
+            }
+            if fun.is_defined() {
+                if !is_internal(fun.linkage) || address_taken.contains(&name[..]) {
+                    roots.push(i);
+                }
+            } else {
+                succs[i].insert(external);
+            }
+            let blks = match fun.body {
+                Some(ref blks) => blks,
+                None => continue
+            };
+            for (b,blk) in blks.iter().enumerate() {
+                for (n,instr) in blk.instrs.iter().enumerate() {
+                    let callee = match instr.content {
+                        InstructionC::Call(_,_,_,ref callee,_,_) => callee,
+                        _ => continue
+                    };
+                    let (indirect,targets) = match direct_callee(m,callee) {
+                        Some(f) if is_intrinsic(f) => continue,
+                        Some(f) => (false,vec![index[f]]),
+                        None => {
+                            let (funs,complete) = resolve(fun,callee);
+                            let mut targets: Vec<usize> = funs.iter()
+                                .filter_map(|f| index.get(f).cloned())
+                                .collect();
+                            if !complete {
+                                targets.push(external);
+                            }
+                            (true,targets)
+                        }
+                    };
+                    succs[i].extend(targets.iter().cloned());
+                    sites[i].push(CallSite { position: Position { block: b, instr: n },
+                                             indirect,
+                                             targets });
+                }
+            }
+        }
+        let mut preds = vec![Vec::new(); names.len()+1];
+        for (i,s) in succs.iter().enumerate() {
+            for j in s.iter() {
+                preds[*j].push(i);
+            }
+        }
+        let defined = names.iter().map(|n| m.functions[n].is_defined()).collect();
+        CallGraph { names,
+                    index,
+                    sites,
+                    succs: succs.into_iter().map(|s| s.into_iter().collect()).collect(),
+                    preds,
+                    defined,
+                    roots }
+    }
+    /// The number of nodes, including the external node.
+    pub fn len(&self) -> usize {
+        self.succs.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.succs.is_empty()
+    }
+    /// The node standing for code outside the module.
+    pub fn external(&self) -> usize {
+        self.names.len()
+    }
+    pub fn node(&self,name: &str) -> Option<usize> {
+        self.index.get(name).cloned()
+    }
+    /// The name of the function of a node, `None` for the external
+    /// node.
+    pub fn name(&self,node: usize) -> Option<&str> {
+        self.names.get(node).map(|n| &n[..])
+    }
+    /// The nodes called by a node, in ascending order.
+    pub fn callees(&self,node: usize) -> &[usize] {
+        &self.succs[node]
+    }
+    /// The nodes calling a node, in ascending order.
+    pub fn callers(&self,node: usize) -> &[usize] {
+        &self.preds[node]
+    }
+    /// The calls in the body of a function, in program order.
+    pub fn call_sites(&self,node: usize) -> &[CallSite] {
+        match self.sites.get(node) {
+            Some(s) => &s[..],
+            None => &[]
+        }
+    }
+    /// The defined functions that code outside the module may call:
+    /// those that are not internal and those whose address is taken.
+    pub fn roots(&self) -> &[usize] {
+        &self.roots
+    }
+    /// The strongly connected components of the graph, callees before
+    /// their callers. The nodes of a component are in ascending order.
+    pub fn sccs(&self) -> Vec<Vec<usize>> {
+        Tarjan::new(self).run()
+    }
+    /// All nodes in bottom-up order: every node comes after the nodes
+    /// it calls, except for calls within a strongly connected component.
+    pub fn bottom_up(&self) -> Vec<usize> {
+        self.sccs().into_iter().flatten().collect()
+    }
+    /// Whether a function can call itself, directly or through others.
+    pub fn is_recursive(&self,node: usize) -> bool {
+        self.reachable_from(node).contains(&node)
+    }
+    /// The nodes that can be called, transitively, by a node. The node
+    /// itself is only included if it is recursive.
+    pub fn reachable_from(&self,node: usize) -> BTreeSet<usize> {
+        closure(&self.succs,node)
+    }
+    /// The nodes that can, transitively, call a node. The node itself is
+    /// only included if it is recursive.
+    pub fn reaching(&self,node: usize) -> BTreeSet<usize> {
+        closure(&self.preds,node)
+    }
+    /// The defined functions that cannot be called from any root.
+    pub fn dead_functions(&self) -> Vec<usize> {
+        let mut live: BTreeSet<usize> = self.roots.iter().cloned().collect();
+        for r in self.roots.iter() {
+            live.extend(self.reachable_from(*r));
+        }
+        (0..self.names.len())
+            .filter(|n| self.defined[*n] && !live.contains(n))
+            .collect()
+    }
+}
+
+fn closure(edges: &[Vec<usize>],start: usize) -> BTreeSet<usize> {
+    let mut seen = BTreeSet::new();
+    let mut stack: Vec<usize> = edges[start].clone();
+    while let Some(n) = stack.pop() {
+        if seen.insert(n) {
+            stack.extend(edges[n].iter().cloned());
+        }
+    }
+    seen
+}
+
+// Tarjan's algorithm without recursion, which emits the components in
+// reverse topological order.
+struct Tarjan<'a> {
+    graph: &'a CallGraph,
+    next: usize,
+    index: Vec<Option<usize>>,
+    lowlink: Vec<usize>,
+    on_stack: Vec<bool>,
+    stack: Vec<usize>,
+    result: Vec<Vec<usize>>
+}
+
+impl<'a> Tarjan<'a> {
+    fn new(graph: &'a CallGraph) -> Tarjan<'a> {
+        let n = graph.len();
+        Tarjan { graph,
+                 next: 0,
+                 index: vec![None; n],
+                 lowlink: vec![0; n],
+                 on_stack: vec![false; n],
+                 stack: Vec::new(),
+                 result: Vec::new() }
+    }
+    fn run(mut self) -> Vec<Vec<usize>> {
+        for n in 0..self.graph.len() {
+            if self.index[n].is_none() {
+                self.visit(n);
+            }
+        }
+        self.result
+    }
+    fn enter(&mut self,n: usize) {
+        self.index[n] = Some(self.next);
+        self.lowlink[n] = self.next;
+        self.next += 1;
+        self.stack.push(n);
+        self.on_stack[n] = true;
+    }
+    fn visit(&mut self,root: usize) {
+        // The nodes being visited and the next successor to look at
+        let mut work = vec![(root,0)];
+        self.enter(root);
+        while let Some(&mut (n,ref mut succ)) = work.last_mut() {
+            if let Some(&m) = self.graph.succs[n].get(*succ) {
+                *succ += 1;
+                match self.index[m] {
+                    None => {
+                        self.enter(m);
+                        work.push((m,0));
+                    },
+                    Some(idx) => if self.on_stack[m] {
+                        self.lowlink[n] = self.lowlink[n].min(idx);
+                    }
+                }
+                continue
+            }
+            work.pop();
+            if let Some(&(parent,_)) = work.last() {
+                self.lowlink[parent] = self.lowlink[parent].min(self.lowlink[n]);
+            }
+            if Some(self.lowlink[n])==self.index[n] {
+                let mut comp = Vec::new();
+                while let Some(m) = self.stack.pop() {
+                    self.on_stack[m] = false;
+                    comp.push(m);
+                    if m==n {
+                        break
+                    }
+                }
+                comp.sort();
+                self.result.push(comp);
+            }
+        }
+    }
+}
+
+fn is_intrinsic(name: &str) -> bool {
+    name.starts_with("llvm.")
+}
+
+fn is_internal(linkage: Option<Linkage>) -> bool {
+    matches!(linkage,Some(Linkage::Internal) | Some(Linkage::Private))
+}
+
+// The function called by a callee operand, looking through bitcasts.
+fn direct_callee<'a>(m: &Module,callee: &'a Value) -> Option<&'a str> {
+    match *callee {
+        Value::Constant(ref c) => constant_function(m,c),
+        _ => None
+    }
+}
+
+fn constant_function<'a>(m: &Module,c: &'a Constant) -> Option<&'a str> {
+    match *c {
+        Constant::Global(ref g) if m.functions.contains_key(g) => Some(g),
+        Constant::Cast(CastInst::Bitcast,ref c,_) => constant_function(m,&c.val),
+        _ => None
+    }
+}
+
+fn constant_functions<'a>(m: &Module,c: &'a Constant,res: &mut HashSet<&'a str>) {
+    match *c {
+        Constant::Global(ref g) if m.functions.contains_key(g) => {
+            res.insert(g);
+        },
+        Constant::Array(ref els) => for el in els.iter() {
+            constant_functions(m,el,res)
+        },
+        Constant::GEP(ref g) => constant_functions(m,&g.ptr.val,res),
+        Constant::Cast(_,ref c,_) => constant_functions(m,&c.val,res),
+        _ => {}
+    }
+}
+
+// Functions that are used other than as the callee of a call, which
+// excludes metadata.
+fn address_taken_functions(m: &Module) -> HashSet<&str> {
+    let mut res = HashSet::new();
+    for gv in m.globals.values() {
+        if let Some(ref c) = gv.initialization {
+            constant_functions(m,c,&mut res);
+        }
+    }
+    for fun in m.functions.values() {
+        for blk in fun.body.iter().flat_map(|blks| blks.iter()) {
+            for instr in blk.instrs.iter() {
+                let is_call = matches!(instr.content,InstructionC::Call(..));
+                for (n,op) in instr.content.operands().enumerate() {
+                    match *op {
+                        Value::Constant(_) if is_call && n==0 => {},
+                        Value::Constant(ref c) => constant_functions(m,c,&mut res),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+    res
+}
+
+// A calling function and the arguments it passes.
+type DirectCall<'m> = (&'m Function,&'m [Typed<Value>]);
+
+// Follows function pointers through the module.
+struct Tracker<'m> {
+    module: &'m Module,
+    defs: HashMap<&'m str,HashMap<&'m str,&'m InstructionC>>,
+    // The direct calls of every function
+    direct_calls: HashMap<&'m str,Vec<DirectCall<'m>>>,
+    // The values stored to globals that are only loaded and stored
+    global_stores: HashMap<&'m str,Vec<(&'m Function,&'m Value)>>,
+    // Globals used in any other way
+    escaped: HashSet<&'m str>,
+    address_taken: HashSet<&'m str>
+}
+
+impl<'m> Tracker<'m> {
+    fn new(m: &'m Module) -> Tracker<'m> {
+        let mut tr = Tracker { module: m,
+                               defs: HashMap::new(),
+                               direct_calls: HashMap::new(),
+                               global_stores: HashMap::new(),
+                               escaped: HashSet::new(),
+                               address_taken: address_taken_functions(m) };
+        for gv in m.globals.values() {
+            if let Some(ref c) = gv.initialization {
+                tr.escape_constant(c);
+            }
+        }
+        for fun in m.functions.values() {
+            let mut defs = HashMap::new();
+            for blk in fun.body.iter().flat_map(|blks| blks.iter()) {
+                for instr in blk.instrs.iter() {
+                    if let Some(name) = instr.content.name() {
+                        defs.insert(name,&instr.content);
+                    }
+                    match instr.content {
+                        InstructionC::Call(_,_,_,ref callee,ref args,_) => {
+                            if let Some(f) = direct_callee(m,callee) {
+                                tr.direct_calls.entry(f).or_default().push((fun,&args[..]));
+                            }
+                            for arg in args.iter() {
+                                tr.escape(&arg.val);
+                            }
+                        },
+                        InstructionC::Unary(_,_,UnaryInst::Load(..)) => {},
+                        InstructionC::Store(_,ref val,ref ptr,_) => {
+                            tr.escape(&val.val);
+                            if let Value::Constant(Constant::Global(ref g)) = ptr.val {
+                                tr.global_stores.entry(&g[..]).or_default().push((fun,&val.val));
+                            }
+                        },
+                        ref c => for op in c.operands() {
+                            tr.escape(op)
+                        }
+                    }
+                }
+            }
+            tr.defs.insert(&fun.name[..],defs);
+        }
+        tr
+    }
+    fn escape(&mut self,v: &'m Value) {
+        if let Value::Constant(ref c) = *v {
+            self.escape_constant(c)
+        }
+    }
+    fn escape_constant(&mut self,c: &'m Constant) {
+        match *c {
+            Constant::Global(ref g) => {
+                self.escaped.insert(g);
+            },
+            Constant::Array(ref els) => for el in els.iter() {
+                self.escape_constant(el)
+            },
+            Constant::GEP(ref g) => self.escape_constant(&g.ptr.val),
+            Constant::Cast(_,ref c,_) => self.escape_constant(&c.val),
+            _ => {}
+        }
+    }
+    fn call_targets(&self,fun: &'m Function,callee: &'m Value) -> (BTreeSet<String>,bool) {
+        let mut res = BTreeSet::new();
+        let mut visited = HashSet::new();
+        let complete = self.targets(fun,callee,&mut visited,&mut res);
+        (res.into_iter().map(|f| f.to_string()).collect(),complete)
+    }
+    // Collect the functions a value may be, returns whether they are
+    // all known.
+    fn targets(&self,fun: &'m Function,v: &'m Value,
+               visited: &mut HashSet<(&'m str,&'m Value)>,res: &mut BTreeSet<&'m str>) -> bool {
+        if !visited.insert((&fun.name[..],v)) {
+            return true
+        }
+        match *v {
+            Value::Constant(ref c) => self.constant_targets(c,res),
+            Value::Argument(n) => {
+                let mut complete = is_internal(fun.linkage) && !self.address_taken.contains(&fun.name[..]);
+                for &(caller,args) in self.direct_calls.get(&fun.name[..]).iter().flat_map(|c| c.iter()) {
+                    match args.get(n) {
+                        Some(arg) => complete &= self.targets(caller,&arg.val,visited,res),
+                        None => complete = false
+                    }
+                }
+                complete
+            },
+            Value::Local(ref name) => {
+                let instr = match self.defs.get(&fun.name[..]).and_then(|d| d.get(&name[..])) {
+                    Some(instr) => *instr,
+                    None => return false
+                };
+                match *instr {
+                    InstructionC::Unary(_,ref v,UnaryInst::Cast(_,CastInst::Bitcast)) =>
+                        self.targets(fun,&v.val,visited,res),
+                    InstructionC::Phi(_,_,ref inc) => {
+                        let mut complete = true;
+                        for v in inc.iter() {
+                            complete &= self.targets(fun,&v.0,visited,res);
+                        }
+                        complete
+                    },
+                    InstructionC::Select(_,_,_,ref v1,ref v2) => {
+                        let c1 = self.targets(fun,v1,visited,res);
+                        self.targets(fun,v2,visited,res) && c1
+                    },
+                    InstructionC::Unary(_,ref ptr,UnaryInst::Load(..)) =>
+                        self.loaded_targets(fun,&ptr.val,visited,res),
+                    _ => false
+                }
+            },
+            Value::Metadata(_) => false
+        }
+    }
+    fn constant_targets(&self,c: &'m Constant,res: &mut BTreeSet<&'m str>) -> bool {
+        match *c {
+            Constant::NullPtr => true,
+            _ => match constant_function(self.module,c) {
+                Some(f) => {
+                    res.insert(f);
+                    true
+                },
+                None => false
+            }
+        }
+    }
+    // The functions that a load from `ptr` may return.
+    fn loaded_targets(&self,fun: &'m Function,ptr: &'m Value,
+                      visited: &mut HashSet<(&'m str,&'m Value)>,res: &mut BTreeSet<&'m str>) -> bool {
+        match *ptr {
+            Value::Constant(Constant::Global(ref g)) => {
+                let gv = match self.module.globals.get(g) {
+                    Some(gv) => gv,
+                    None => return false
+                };
+                let mut complete = !self.escaped.contains(&g[..]) &&
+                    (is_internal(gv.linkage) || gv.global_type==GlobalType::Constant);
+                match gv.initialization {
+                    Some(ref c) => complete &= self.constant_targets(c,res),
+                    None => complete = false
+                }
+                for &(f,v) in self.global_stores.get(&g[..]).iter().flat_map(|s| s.iter()) {
+                    complete &= self.targets(f,v,visited,res);
+                }
+                complete
+            },
+            Value::Local(ref name) => {
+                let defs = &self.defs[&fun.name[..]];
+                if !matches!(defs.get(&name[..]),Some(&&InstructionC::Alloca(..))) {
+                    return false
+                }
+                // The alloca may only be used as the address of loads and
+                // stores
+                let mut complete = true;
+                for instr in fun.body.iter().flat_map(|blks| blks.iter()).flat_map(|blk| blk.instrs.iter()) {
+                    match instr.content {
+                        InstructionC::Unary(_,_,UnaryInst::Load(..)) => {},
+                        InstructionC::Store(_,ref val,ref p,_) => {
+                            if val.val==*ptr {
+                                complete = false
+                            } else if p.val==*ptr {
+                                complete &= self.targets(fun,&val.val,visited,res);
+                            }
+                        },
+                        ref c => if c.operands().any(|op| op==ptr) {
+                            complete = false
+                        }
+                    }
+                }
+                complete
+            },
+            _ => false
+        }
+    }
+}
+
+#[cfg(test)]
+fn names(cg: &CallGraph,nodes: &[usize]) -> Vec<String> {
+    nodes.iter().map(|n| cg.name(*n).unwrap_or("<external>").to_string()).collect()
+}
+
+#[test]
+fn test_call_graph() {
+    let m = parse_test_module(b"@handler = internal global void ()* @on_error, align 8
+
+declare void @exit(i32)
+declare void @llvm.dbg.value(metadata, i64, metadata)
+
+define internal void @on_error() {
+entry:
+  call void @exit(i32 1)
+  ret void
+}
+
+define internal void @on_success() {
+entry:
+  ret void
+}
+
+define internal void @unused() {
+entry:
+  call void @on_success()
+  ret void
+}
+
+define internal i32 @even(i32 %n) {
+entry:
+  %c = icmp eq i32 %n, 0
+  br i1 %c, label %done, label %rec
+
+rec:
+  %m = sub i32 %n, 1
+  %r = call i32 @odd(i32 %m)
+  ret i32 %r
+
+done:
+  ret i32 1
+}
+
+define internal i32 @odd(i32 %n) {
+entry:
+  %m = sub i32 %n, 1
+  %r = call i32 @even(i32 %m)
+  ret i32 %r
+}
+
+define internal void @dispatch(void ()* %f) {
+entry:
+  call void %f()
+  ret void
+}
+
+define i32 @main(i1 %ok) {
+entry:
+  %h = load void ()** @handler, align 8
+  %f = select i1 %ok, void ()* bitcast (void ()* @on_success to void ()*), void ()* %h
+  call void @dispatch(void ()* %f)
+  %e = call i32 @even(i32 4)
+  call void bitcast (void (i32)* @exit to void (i64)*)(i64 0)
+  ret i32 %e
+}");
+    let cg = CallGraph::new(&m);
+    let node = |n: &str| cg.node(n).unwrap();
+    assert_eq!(cg.len(),m.functions.len()+1);
+    assert_eq!(names(&cg,cg.roots()),vec!["main","on_error","on_success"]);
+    assert_eq!(names(&cg,cg.callees(node("main"))),vec!["dispatch","even","exit"]);
+    assert_eq!(names(&cg,cg.callees(node("dispatch"))),vec!["on_error","on_success"]);
+    assert_eq!(names(&cg,cg.callees(node("exit"))),vec!["<external>"]);
+    assert!(cg.callees(node("llvm.dbg.value")).is_empty());
+    let sites = cg.call_sites(node("dispatch"));
+    assert_eq!(sites.len(),1);
+    assert!(sites[0].indirect);
+    // The call through the bitcast of @exit is direct
+    assert!(!cg.call_sites(node("main"))[2].indirect);
+    // Callees come before their callers
+    let sccs = cg.sccs();
+    let pos = |n: usize| sccs.iter().position(|c| c.contains(&n)).unwrap();
+    assert!(sccs.contains(&vec![node("even"),node("odd")]));
+    assert!(pos(node("even")) < pos(node("main")));
+    assert!(pos(node("on_error")) < pos(node("dispatch")));
+    assert!(pos(cg.external()) < pos(node("exit")));
+    assert_eq!(cg.bottom_up().len(),cg.len());
+    assert!(cg.is_recursive(node("odd")));
+    assert!(!cg.is_recursive(node("main")));
+    assert_eq!(names(&cg,&cg.reaching(node("exit")).into_iter().collect::<Vec<_>>()),
+               vec!["dispatch","main","on_error"]);
+    assert_eq!(names(&cg,&cg.dead_functions()),vec!["unused"]);
+}
+
+#[test]
+fn test_call_graph_minisat() {
+    let m = minisat();
+    let cg = CallGraph::new(&m);
+    let sortrnd = cg.node("sortrnd").unwrap();
+    let selectionsort = cg.node("selectionsort").unwrap();
+    let clause_cmp = cg.node("clause_cmp").unwrap();
+    // The comparison function is passed down from @sort, which may also
+    // be called from outside the module
+    let indirect: Vec<&CallSite> = cg.call_sites(sortrnd).iter().filter(|s| s.indirect).collect();
+    assert_eq!(indirect.len(),2);
+    assert!(indirect.iter().all(|s| s.targets==vec![clause_cmp,cg.external()]));
+    assert!(cg.is_recursive(sortrnd));
+    assert!(cg.reaching(clause_cmp).contains(&selectionsort));
+    assert!(cg.dead_functions().is_empty());
+    let pt = PointsTo::new(&m);
+    let cg = CallGraph::with_points_to(&m,&pt);
+    let indirect: Vec<&CallSite> = cg.call_sites(selectionsort).iter().filter(|s| s.indirect).collect();
+    assert_eq!(indirect.len(),1);
+    assert_eq!(indirect[0].targets,vec![clause_cmp,cg.external()]);
+}
@@ -0,0 +1,443 @@
+//! Control-flow graphs, dominator trees and dominance frontiers over
+//! the basic blocks of a `Function`.
+//!
+//! Blocks are identified by their index in `Function.body`, the entry
+//! block is always index `0`.
+use std::collections::HashMap;
+use super::{Function,BasicBlock};
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct ControlFlowGraph {
+    names: Vec<String>,
+    index: HashMap<String,usize>,
+    succs: Vec<Vec<usize>>,
+    preds: Vec<Vec<usize>>
+}
+
+impl ControlFlowGraph {
+    /// Build the graph of a list of basic blocks. Every successor of
+    /// a block is only listed once, even if the terminator mentions it
+    /// multiple times.
+    ///
+    /// A jump to a label that is not defined, which the parser accepts
+    /// but LLVM does not, gets no edge.
+    pub fn new(blocks: &[BasicBlock]) -> ControlFlowGraph {
+        let names: Vec<String> = blocks.iter().map(|b| b.name.clone()).collect();
+        let index: HashMap<String,usize> = names.iter()
+            .enumerate()
+            .map(|(i,n)| (n.clone(),i))
+            .collect();
+        let mut succs = vec![Vec::new(); blocks.len()];
+        let mut preds = vec![Vec::new(); blocks.len()];
+        for (i,blk) in blocks.iter().enumerate() {
+            if let Some(term) = blk.terminator() {
+                for trg in term.targets() {
+                    let j = match index.get(trg) {
+                        Some(j) => *j,
+                        None => continue
+                    };
+                    if !succs[i].contains(&j) {
+                        succs[i].push(j);
+                        preds[j].push(i);
+                    }
+                }
+            }
+        }
+        ControlFlowGraph { names,
+                           index,
+                           succs,
+                           preds }
+    }
+    /// Build the graph of a function body, `None` for declarations.
+    pub fn from_function(fun: &Function) -> Option<ControlFlowGraph> {
+        fun.body.as_ref().map(|blks| ControlFlowGraph::new(&blks[..]))
+    }
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+    pub fn entry(&self) -> usize {
+        0
+    }
+    pub fn block_name(&self,blk: usize) -> &str {
+        &self.names[blk]
+    }
+    pub fn block_index(&self,name: &str) -> Option<usize> {
+        self.index.get(name).cloned()
+    }
+    pub fn successors(&self,blk: usize) -> &[usize] {
+        &self.succs[blk]
+    }
+    pub fn predecessors(&self,blk: usize) -> &[usize] {
+        &self.preds[blk]
+    }
+    /// Blocks without successors (returns and `unreachable`).
+    pub fn exits(&self) -> Vec<usize> {
+        (0..self.len()).filter(|&b| self.succs[b].is_empty()).collect()
+    }
+    /// The reachable blocks in depth-first postorder, starting at the
+    /// entry block.
+    pub fn postorder(&self) -> Vec<usize> {
+        if self.is_empty() {
+            Vec::new()
+        } else {
+            postorder(&self.succs,self.entry())
+        }
+    }
+    /// The reachable blocks in reverse postorder. Every block comes
+    /// before its successors, except for the targets of back edges.
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut res = self.postorder();
+        res.reverse();
+        res
+    }
+    /// For every block, whether it can be reached from the entry.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut res = vec![false; self.len()];
+        for b in self.postorder() {
+            res[b] = true;
+        }
+        res
+    }
+    /// All blocks that cannot be reached from the entry block.
+    pub fn unreachable_blocks(&self) -> Vec<usize> {
+        self.reachable()
+            .iter()
+            .enumerate()
+            .filter(|&(_,r)| !*r)
+            .map(|(b,_)| b)
+            .collect()
+    }
+}
+
+fn postorder(succs: &[Vec<usize>],entry: usize) -> Vec<usize> {
+    let mut visited = vec![false; succs.len()];
+    let mut res = Vec::with_capacity(succs.len());
+    // Stack of (node, index of next successor to visit)
+    let mut stack = vec![(entry,0)];
+    visited[entry] = true;
+    while let Some(&mut (node,ref mut next)) = stack.last_mut() {
+        if *next < succs[node].len() {
+            let succ = succs[node][*next];
+            *next += 1;
+            if !visited[succ] {
+                visited[succ] = true;
+                stack.push((succ,0));
+            }
+        } else {
+            res.push(node);
+            stack.pop();
+        }
+    }
+    res
+}
+
+/// A dominator or post-dominator tree.
+///
+/// Post-dominators are computed relative to a virtual exit node that
+/// succeeds every block without successors, so a function may have
+/// several post-dominator roots. Blocks that cannot reach an exit
+/// (endless loops) are not part of the post-dominator tree.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct DominatorTree {
+    post: bool,
+    idom: Vec<Option<usize>>,
+    roots: Vec<usize>,
+    children: Vec<Vec<usize>>,
+    reachable: Vec<bool>,
+    // Pre- and postorder numbers of the tree, used for constant time
+    // dominance queries.
+    pre: Vec<usize>,
+    post_num: Vec<usize>
+}
+
+impl DominatorTree {
+    /// Compute the dominator tree using the algorithm by Cooper,
+    /// Harvey and Kennedy ("A Simple, Fast Dominance Algorithm").
+    pub fn new(cfg: &ControlFlowGraph) -> DominatorTree {
+        if cfg.is_empty() {
+            return DominatorTree::from_idoms(false,Vec::new(),Vec::new());
+        }
+        let (idom,reachable) = compute_idoms(&cfg.succs,&cfg.preds,cfg.entry());
+        DominatorTree::from_idoms(false,idom,reachable)
+    }
+    /// Compute the post-dominator tree, i.e. the dominator tree of the
+    /// reversed graph.
+    pub fn post_dominators(cfg: &ControlFlowGraph) -> DominatorTree {
+        let n = cfg.len();
+        let mut rsuccs: Vec<Vec<usize>> = cfg.preds.clone();
+        let mut rpreds: Vec<Vec<usize>> = cfg.succs.clone();
+        let exits = cfg.exits();
+        for &e in exits.iter() {
+            rpreds[e].push(n);
+        }
+        rsuccs.push(exits);
+        rpreds.push(Vec::new());
+        let (idom,mut reachable) = compute_idoms(&rsuccs,&rpreds,n);
+        reachable.pop();
+        let idom = idom.into_iter()
+            .take(n)
+            .map(|d| match d {
+                Some(d) if d==n => None,
+                d => d
+            })
+            .collect();
+        DominatorTree::from_idoms(true,idom,reachable)
+    }
+    fn from_idoms(post: bool,idom: Vec<Option<usize>>,reachable: Vec<bool>) -> DominatorTree {
+        let n = idom.len();
+        let mut children = vec![Vec::new(); n];
+        let mut roots = Vec::new();
+        for b in 0..n {
+            if !reachable[b] {
+                continue
+            }
+            match idom[b] {
+                Some(d) => children[d].push(b),
+                None => roots.push(b)
+            }
+        }
+        let mut pre = vec![usize::MAX; n];
+        let mut post_num = vec![usize::MAX; n];
+        let mut pre_c = 0;
+        let mut post_c = 0;
+        for &r in roots.iter() {
+            let mut stack = vec![(r,0)];
+            pre[r] = pre_c;
+            pre_c += 1;
+            while let Some(&mut (node,ref mut next)) = stack.last_mut() {
+                if *next < children[node].len() {
+                    let child = children[node][*next];
+                    *next += 1;
+                    pre[child] = pre_c;
+                    pre_c += 1;
+                    stack.push((child,0));
+                } else {
+                    post_num[node] = post_c;
+                    post_c += 1;
+                    stack.pop();
+                }
+            }
+        }
+        DominatorTree { post,
+                        idom,
+                        roots,
+                        children,
+                        reachable,
+                        pre,
+                        post_num }
+    }
+    /// Whether this is a post-dominator tree.
+    pub fn is_post_dominator_tree(&self) -> bool {
+        self.post
+    }
+    /// The immediate (post-)dominator of a block. `None` for the roots
+    /// of the tree and for blocks that are not part of it.
+    pub fn idom(&self,blk: usize) -> Option<usize> {
+        self.idom[blk]
+    }
+    /// The blocks without an immediate (post-)dominator. For dominator
+    /// trees this is only the entry block.
+    pub fn roots(&self) -> &[usize] {
+        &self.roots
+    }
+    /// The blocks immediately (post-)dominated by a block.
+    pub fn children(&self,blk: usize) -> &[usize] {
+        &self.children[blk]
+    }
+    /// Whether the block is part of the tree, i.e. reachable from the
+    /// entry (or, for post-dominators, can reach an exit).
+    pub fn contains(&self,blk: usize) -> bool {
+        self.reachable[blk]
+    }
+    /// Whether `a` (post-)dominates `b`. Every block dominates itself.
+    pub fn dominates(&self,a: usize,b: usize) -> bool {
+        self.reachable[a] && self.reachable[b] &&
+            self.pre[a] <= self.pre[b] && self.post_num[b] <= self.post_num[a]
+    }
+    pub fn strictly_dominates(&self,a: usize,b: usize) -> bool {
+        a!=b && self.dominates(a,b)
+    }
+    /// The (post-)dominance frontier of every block. For post-dominator
+    /// trees this is the reverse dominance frontier, i.e. the set of
+    /// blocks a block is control dependent on.
+    pub fn dominance_frontiers(&self,cfg: &ControlFlowGraph) -> Vec<Vec<usize>> {
+        let n = cfg.len();
+        let mut df: Vec<Vec<usize>> = vec![Vec::new(); n];
+        for b in 0..n {
+            if !self.reachable[b] {
+                continue
+            }
+            let preds = if self.post {
+                cfg.successors(b)
+            } else {
+                cfg.predecessors(b)
+            };
+            for &p in preds.iter() {
+                if !self.reachable[p] {
+                    continue
+                }
+                let mut runner = Some(p);
+                while let Some(r) = runner {
+                    if Some(r)==self.idom[b] {
+                        break
+                    }
+                    if !df[r].contains(&b) {
+                        df[r].push(b);
+                    }
+                    runner = self.idom[r];
+                }
+            }
+        }
+        df
+    }
+}
+
+/// Computes the immediate dominators of all nodes reachable from
+/// `entry`, together with the reachability information.
+fn compute_idoms(succs: &[Vec<usize>],preds: &[Vec<usize>],entry: usize)
+                 -> (Vec<Option<usize>>,Vec<bool>) {
+    let n = succs.len();
+    let po = postorder(succs,entry);
+    let mut po_num = vec![usize::MAX; n];
+    for (i,&b) in po.iter().enumerate() {
+        po_num[b] = i;
+    }
+    let mut idom: Vec<Option<usize>> = vec![None; n];
+    idom[entry] = Some(entry);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &b in po.iter().rev() {
+            if b==entry {
+                continue
+            }
+            let mut new_idom = None;
+            for &p in preds[b].iter() {
+                if idom[p].is_none() {
+                    continue
+                }
+                new_idom = Some(match new_idom {
+                    None => p,
+                    Some(cur) => intersect(&idom,&po_num,p,cur)
+                });
+            }
+            if new_idom.is_some() && idom[b]!=new_idom {
+                idom[b] = new_idom;
+                changed = true;
+            }
+        }
+    }
+    let reachable = po_num.iter().map(|&n| n!=usize::MAX).collect();
+    idom[entry] = None;
+    (idom,reachable)
+}
+
+fn intersect(idom: &[Option<usize>],po_num: &[usize],a: usize,b: usize) -> usize {
+    let mut f1 = a;
+    let mut f2 = b;
+    while f1!=f2 {
+        while po_num[f1] < po_num[f2] {
+            f1 = idom[f1].unwrap();
+        }
+        while po_num[f2] < po_num[f1] {
+            f2 = idom[f2].unwrap();
+        }
+    }
+    f1
+}
+
+#[test]
+fn test_cfg_main() {
+    let m = ::minisat();
+    let cfg = ControlFlowGraph::from_function(&m.functions["main"]).unwrap();
+    let blk = |n: &str| cfg.block_index(n).unwrap();
+    assert_eq!(cfg.block_name(cfg.entry()),"entry");
+    assert_eq!(cfg.successors(blk("entry")),&[blk("if.then"),blk("if.end")]);
+    assert_eq!(cfg.predecessors(blk("for.cond")),&[blk("if.then21"),blk("for.inc")]);
+    assert_eq!(cfg.successors(blk("if.then")),&[] as &[usize]);
+    let rpo = cfg.reverse_postorder();
+    assert_eq!(rpo[0],cfg.entry());
+    assert_eq!(rpo.len(),cfg.len());
+    assert!(cfg.unreachable_blocks().is_empty());
+
+    let dt = DominatorTree::new(&cfg);
+    assert_eq!(dt.roots(),&[cfg.entry()]);
+    assert_eq!(dt.idom(blk("for.body")),Some(blk("for.cond")));
+    assert_eq!(dt.idom(blk("if.end30")),Some(blk("if.end10")));
+    assert!(dt.dominates(blk("if.end"),blk("for.inc")));
+    assert!(!dt.dominates(blk("for.body"),blk("for.end")));
+
+    let df = dt.dominance_frontiers(&cfg);
+    assert_eq!(df[blk("for.inc")],vec![blk("for.cond")]);
+    assert_eq!(df[blk("for.cond")],vec![blk("for.cond"),blk("if.end30")]);
+
+    let pdt = DominatorTree::post_dominators(&cfg);
+    assert_eq!(pdt.idom(blk("for.body")),Some(blk("for.inc")));
+    assert_eq!(pdt.idom(blk("if.then21")),Some(blk("for.cond")));
+    assert!(pdt.dominates(blk("if.end30"),blk("if.end10")));
+    assert!(!pdt.dominates(blk("if.end30"),blk("entry")));
+}
+
+#[test]
+fn test_dominators_minisat() {
+    let m = ::minisat();
+    for fun in m.functions.values() {
+        let cfg = match ControlFlowGraph::from_function(fun) {
+            Some(cfg) => cfg,
+            None => continue
+        };
+        let dt = DominatorTree::new(&cfg);
+        let reach = cfg.reachable();
+        for b in 0..cfg.len() {
+            if !reach[b] {
+                assert!(!dt.contains(b));
+                continue
+            }
+            assert!(dt.dominates(cfg.entry(),b));
+            // The immediate dominator dominates all predecessors
+            if let Some(d) = dt.idom(b) {
+                for &p in cfg.predecessors(b) {
+                    assert!(!reach[p] || dt.dominates(d,p),
+                            "{}: idom of {} does not dominate predecessor",fun.name,cfg.block_name(b));
+                }
+            }
+        }
+        // Check the dominance frontier against its definition
+        let df = dt.dominance_frontiers(&cfg);
+        for a in 0..cfg.len() {
+            for b in 0..cfg.len() {
+                let expected = reach[a] && reach[b] &&
+                    !dt.strictly_dominates(a,b) &&
+                    cfg.predecessors(b).iter().any(|&p| reach[p] && dt.dominates(a,p));
+                assert_eq!(df[a].contains(&b),expected);
+            }
+        }
+        let pdt = DominatorTree::post_dominators(&cfg);
+        for &e in cfg.exits().iter() {
+            assert!(pdt.roots().contains(&e));
+        }
+    }
+}
+
+#[test]
+fn test_unreachable_blocks() {
+    let src = b"define void @f() {\nentry:\n  ret void\ndead:\n  br label %entry\n}";
+    let fun = ::parse_test_function(src);
+    let cfg = ControlFlowGraph::from_function(&fun).unwrap();
+    assert_eq!(cfg.unreachable_blocks(),vec![1]);
+    let dt = DominatorTree::new(&cfg);
+    assert!(!dt.contains(1));
+    assert_eq!(dt.dominance_frontiers(&cfg)[1],Vec::<usize>::new());
+}
+
+#[test]
+fn test_unknown_label() {
+    let src = b"define void @f(i1 %c) {\nentry:\n  br i1 %c, label %missing, label %exit\nexit:\n  ret void\n}";
+    let fun = ::parse_test_function(src);
+    let cfg = ControlFlowGraph::from_function(&fun).unwrap();
+    assert_eq!(cfg.successors(0),&[1]);
+    assert_eq!(cfg.block_index("missing"),None);
+}
@@ -0,0 +1,570 @@
+//! Classic dataflow analyses over sets of facts: liveness of SSA
+//! values, reaching stores to allocas and available expressions.
+//!
+//! A `Problem` describes the direction of the analysis, how the sets
+//! of the predecessors (or successors) are combined and how every
+//! instruction changes the set. `solve` iterates the problem to a
+//! fixpoint and returns a `DataflowResult`, which is queried in program
+//! order regardless of the direction of the analysis.
+use std::collections::{BTreeMap,BTreeSet,VecDeque};
+use super::*;
+use cfg::ControlFlowGraph;
+use ssa::Position;
+
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum Direction {
+    Forward,
+    Backward
+}
+
+/// How the sets of several control-flow edges are combined.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum Meet {
+    /// Facts that hold on some path.
+    Union,
+    /// Facts that hold on all paths.
+    Intersection
+}
+
+pub trait Problem {
+    type Fact: Ord + Clone;
+    fn direction(&self) -> Direction;
+    fn meet(&self) -> Meet;
+    /// The facts at the start of the entry block (forward) or at the
+    /// end of the blocks without successors (backward).
+    fn boundary(&self,fun: &Function) -> BTreeSet<Self::Fact>;
+    /// Apply an instruction to the facts, in the direction of the
+    /// analysis.
+    fn transfer(&self,pos: Position,instr: &Instruction,facts: &mut BTreeSet<Self::Fact>);
+    /// Adjust the facts flowing along the control-flow edge from `from`
+    /// to `to`, for example for the operands of phi nodes. The default
+    /// keeps them unchanged.
+    fn edge(&self,_from: &BasicBlock,_to: &BasicBlock,_facts: &mut BTreeSet<Self::Fact>) {}
+}
+
+/// The solution of a dataflow problem.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct DataflowResult<F> {
+    direction: Direction,
+    // The facts before every instruction of a block and after the last
+    // one. `None` for blocks that the analysis does not reach, which
+    // only happens for `Meet::Intersection`.
+    states: Vec<Option<Vec<BTreeSet<F>>>>
+}
+
+impl<F: Ord> DataflowResult<F> {
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+    /// The facts at the start of a block.
+    pub fn block_in(&self,blk: usize) -> Option<&BTreeSet<F>> {
+        self.states[blk].as_ref().and_then(|st| st.first())
+    }
+    /// The facts at the end of a block, after its terminator.
+    pub fn block_out(&self,blk: usize) -> Option<&BTreeSet<F>> {
+        self.states[blk].as_ref().and_then(|st| st.last())
+    }
+    /// The facts right before an instruction.
+    pub fn before(&self,pos: Position) -> Option<&BTreeSet<F>> {
+        self.states[pos.block].as_ref().and_then(|st| st.get(pos.instr))
+    }
+    /// The facts right after an instruction.
+    pub fn after(&self,pos: Position) -> Option<&BTreeSet<F>> {
+        self.states[pos.block].as_ref().and_then(|st| st.get(pos.instr+1))
+    }
+}
+
+/// Solve a dataflow problem for a function body, `None` for
+/// declarations.
+pub fn solve<P: Problem>(problem: &P,fun: &Function) -> Option<DataflowResult<P::Fact>> {
+    let blocks = match fun.body {
+        Some(ref blks) => &blks[..],
+        None => return None
+    };
+    let cfg = ControlFlowGraph::new(blocks);
+    let direction = problem.direction();
+    let forward = direction==Direction::Forward;
+    let union = problem.meet()==Meet::Union;
+    let boundary = problem.boundary(fun);
+    let is_boundary: Vec<bool> = (0..cfg.len()).map(|b| if forward {
+        b==cfg.entry()
+    } else {
+        cfg.successors(b).is_empty()
+    }).collect();
+    let sources = |b: usize| if forward { cfg.predecessors(b) } else { cfg.successors(b) };
+    let targets = |b: usize| if forward { cfg.successors(b) } else { cfg.predecessors(b) };
+
+    // The facts at the end of every block in the direction of the
+    // analysis, `None` is the set of all facts.
+    let bottom = if union { Some(BTreeSet::new()) } else { None };
+    let mut ends: Vec<Option<BTreeSet<P::Fact>>> = vec![bottom; cfg.len()];
+    let start = |b: usize,ends: &[Option<BTreeSet<P::Fact>>]| {
+        let mut incoming = Vec::new();
+        if is_boundary[b] {
+            incoming.push(boundary.clone());
+        }
+        // Unreached sources of an intersection are the set of all facts
+        // and can be skipped
+        for &s in sources(b) {
+            if let Some(ref facts) = ends[s] {
+                let mut facts = facts.clone();
+                if forward {
+                    problem.edge(&blocks[s],&blocks[b],&mut facts);
+                } else {
+                    problem.edge(&blocks[b],&blocks[s],&mut facts);
+                }
+                incoming.push(facts);
+            }
+        }
+        let mut incoming = incoming.into_iter();
+        match incoming.next() {
+            Some(first) => Some(incoming.fold(first,|acc,facts| if union {
+                acc.into_iter().chain(facts).collect()
+            } else {
+                acc.intersection(&facts).cloned().collect()
+            })),
+            None if union => Some(BTreeSet::new()),
+            None => None
+        }
+    };
+
+    // Visit the blocks in reverse postorder (forward) or postorder
+    // (backward) first, then the blocks unreachable from the entry
+    let mut order = cfg.reverse_postorder();
+    if !forward {
+        order.reverse();
+    }
+    let reach = cfg.reachable();
+    order.extend((0..cfg.len()).filter(|&b| !reach[b]));
+    let mut queued = vec![true; cfg.len()];
+    let mut worklist: VecDeque<usize> = order.into_iter().collect();
+    while let Some(b) = worklist.pop_front() {
+        queued[b] = false;
+        let end = start(b,&ends).map(|facts| transfer_block(problem,b,&blocks[b],facts));
+        if end!=ends[b] {
+            ends[b] = end;
+            for &t in targets(b) {
+                if !queued[t] {
+                    queued[t] = true;
+                    worklist.push_back(t);
+                }
+            }
+        }
+    }
+
+    let states = (0..cfg.len()).map(|b| start(b,&ends).map(|mut facts| {
+        let instrs = &blocks[b].instrs;
+        let mut res = Vec::with_capacity(instrs.len()+1);
+        res.push(facts.clone());
+        if forward {
+            for (i,instr) in instrs.iter().enumerate() {
+                problem.transfer(Position { block: b, instr: i },instr,&mut facts);
+                res.push(facts.clone());
+            }
+        } else {
+            for (i,instr) in instrs.iter().enumerate().rev() {
+                problem.transfer(Position { block: b, instr: i },instr,&mut facts);
+                res.push(facts.clone());
+            }
+            res.reverse();
+        }
+        res
+    })).collect();
+    Some(DataflowResult { direction, states })
+}
+
+fn transfer_block<P: Problem>(problem: &P,b: usize,blk: &BasicBlock,mut facts: BTreeSet<P::Fact>)
+                              -> BTreeSet<P::Fact> {
+    let mut apply = |(i,instr): (usize,&Instruction)| {
+        problem.transfer(Position { block: b, instr: i },instr,&mut facts)
+    };
+    match problem.direction() {
+        Direction::Forward => blk.instrs.iter().enumerate().for_each(&mut apply),
+        Direction::Backward => blk.instrs.iter().enumerate().rev().for_each(&mut apply)
+    }
+    facts
+}
+
+fn is_ssa_value(val: &Value) -> bool {
+    matches!(*val,Value::Local(_) | Value::Argument(_))
+}
+
+/// Backward analysis of the locals and arguments whose value is used
+/// later on.
+///
+/// A value used by a phi node is live at the end of the corresponding
+/// predecessor, but not at the start of the phi's block. Values used
+/// inside of metadata do not count.
+#[derive(Debug,Clone,Copy,Default)]
+pub struct Liveness;
+
+impl Problem for Liveness {
+    type Fact = Value;
+    fn direction(&self) -> Direction {
+        Direction::Backward
+    }
+    fn meet(&self) -> Meet {
+        Meet::Union
+    }
+    fn boundary(&self,_: &Function) -> BTreeSet<Value> {
+        BTreeSet::new()
+    }
+    fn transfer(&self,_: Position,instr: &Instruction,facts: &mut BTreeSet<Value>) {
+        if let Some(name) = instr.content.name() {
+            facts.remove(&Value::Local(name.to_string()));
+        }
+        if let InstructionC::Phi(..) = instr.content {
+            return
+        }
+        facts.extend(instr.content.operands().filter(|v| is_ssa_value(v)).cloned());
+    }
+    fn edge(&self,from: &BasicBlock,to: &BasicBlock,facts: &mut BTreeSet<Value>) {
+        for instr in to.instrs.iter() {
+            match instr.content {
+                InstructionC::Phi(_,_,ref incoming) => facts.extend(
+                    incoming.iter()
+                        .filter(|inc| inc.1==from.name && is_ssa_value(&inc.0))
+                        .map(|inc| inc.0.clone())),
+                _ => break
+            }
+        }
+    }
+}
+
+/// The live values of a function, `None` for declarations.
+pub fn liveness(fun: &Function) -> Option<DataflowResult<Value>> {
+    solve(&Liveness,fun)
+}
+
+/// Forward analysis of the stores to allocas that may be the last
+/// write to their alloca. Facts are the positions of store
+/// instructions.
+///
+/// Only stores whose pointer operand is the alloca itself are tracked,
+/// and a store only replaces earlier stores to the same alloca. Memory
+/// written through other pointers (derived or escaped ones) is not
+/// taken into account.
+#[derive(Debug,Clone)]
+pub struct ReachingStores {
+    targets: BTreeMap<Position,String>
+}
+
+impl ReachingStores {
+    pub fn new(fun: &Function) -> ReachingStores {
+        let mut targets = BTreeMap::new();
+        let blocks = match fun.body {
+            Some(ref blks) => blks,
+            None => return ReachingStores { targets }
+        };
+        let allocas: BTreeSet<&str> = blocks.iter()
+            .flat_map(|blk| blk.instrs.iter())
+            .filter_map(|instr| match instr.content {
+                InstructionC::Alloca(ref name,..) => Some(&name[..]),
+                _ => None
+            })
+            .collect();
+        for (b,blk) in blocks.iter().enumerate() {
+            for (i,instr) in blk.instrs.iter().enumerate() {
+                if let InstructionC::Store(_,_,Typed { val: Value::Local(ref ptr), .. },_) = instr.content {
+                    if allocas.contains(&ptr[..]) {
+                        targets.insert(Position { block: b, instr: i },ptr.clone());
+                    }
+                }
+            }
+        }
+        ReachingStores { targets }
+    }
+    /// The alloca written by a tracked store.
+    pub fn target(&self,pos: Position) -> Option<&str> {
+        self.targets.get(&pos).map(|n| &n[..])
+    }
+    /// The tracked stores that may have written the value read by the
+    /// load at `pos`. Empty if the instruction is not a load of an
+    /// alloca.
+    pub fn stores_for_load(&self,fun: &Function,res: &DataflowResult<Position>,pos: Position) -> Vec<Position> {
+        let instr = match fun.body {
+            Some(ref blks) => &blks[pos.block].instrs[pos.instr],
+            None => return Vec::new()
+        };
+        let ptr = match instr.content {
+            InstructionC::Unary(_,Typed { val: Value::Local(ref ptr), .. },UnaryInst::Load(..)) => ptr,
+            _ => return Vec::new()
+        };
+        match res.before(pos) {
+            Some(facts) => facts.iter().cloned().filter(|p| self.targets[p]==*ptr).collect(),
+            None => Vec::new()
+        }
+    }
+}
+
+impl Problem for ReachingStores {
+    type Fact = Position;
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+    fn meet(&self) -> Meet {
+        Meet::Union
+    }
+    fn boundary(&self,_: &Function) -> BTreeSet<Position> {
+        BTreeSet::new()
+    }
+    fn transfer(&self,pos: Position,_: &Instruction,facts: &mut BTreeSet<Position>) {
+        if let Some(alloca) = self.targets.get(&pos) {
+            facts.retain(|p| self.targets[p]!=*alloca);
+            facts.insert(pos);
+        }
+    }
+}
+
+/// The reaching stores of a function, `None` for declarations.
+pub fn reaching_stores(fun: &Function) -> Option<(ReachingStores,DataflowResult<Position>)> {
+    let problem = ReachingStores::new(fun);
+    solve(&problem,fun).map(|res| (problem,res))
+}
+
+/// A computation without side effects, i.e. an instruction without its
+/// result name.
+#[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Hash,Clone)]
+pub enum Expression {
+    Bin(BinOp,Type,Value,Value),
+    ICmp(CmpOp,Type,Value,Value),
+    Cast(CastInst,Typed<Value>,Type),
+    GEP(GEP<Value>),
+    Select(Value,Type,Value,Value)
+}
+
+impl Expression {
+    /// The expression computed by an instruction, if it has no side
+    /// effects and does not read memory.
+    pub fn of(instr: &InstructionC) -> Option<Expression> {
+        Some(match *instr {
+            InstructionC::Bin(_,ref op,ref tp,ref l,ref r) =>
+                Expression::Bin(op.clone(),tp.clone(),l.clone(),r.clone()),
+            InstructionC::ICmp(_,ref op,ref tp,ref l,ref r) =>
+                Expression::ICmp(op.clone(),tp.clone(),l.clone(),r.clone()),
+            InstructionC::Unary(_,ref arg,UnaryInst::Cast(ref tp,op)) =>
+                Expression::Cast(op,arg.clone(),tp.clone()),
+            InstructionC::GEP(_,ref gep) => Expression::GEP(gep.clone()),
+            InstructionC::Select(_,ref c,ref tp,ref l,ref r) =>
+                Expression::Select(c.clone(),tp.clone(),l.clone(),r.clone()),
+            _ => return None
+        })
+    }
+    /// The value operands of the expression.
+    pub fn operands(&self) -> Vec<&Value> {
+        match *self {
+            Expression::Bin(_,_,ref l,ref r) |
+            Expression::ICmp(_,_,ref l,ref r) => vec![l,r],
+            Expression::Cast(_,ref arg,_) => vec![&arg.val],
+            Expression::GEP(ref gep) => {
+                let mut res = vec![&gep.ptr.val];
+                res.extend(gep.indices.iter().map(|idx| &idx.0.val));
+                res
+            },
+            Expression::Select(ref c,_,ref l,ref r) => vec![c,l,r]
+        }
+    }
+    fn uses(&self,name: &str) -> bool {
+        self.operands().into_iter().any(|v| match *v {
+            Value::Local(ref n) => n==name,
+            _ => false
+        })
+    }
+}
+
+/// Forward analysis of the expressions that have been computed on
+/// every path, with none of their operands redefined since.
+///
+/// In SSA form operands are only redefined by the phi nodes of loop
+/// headers (and other instructions in loops), which start a new
+/// iteration.
+#[derive(Debug,Clone,Copy,Default)]
+pub struct AvailableExpressions;
+
+impl AvailableExpressions {
+    /// The instructions computing an expression that is already
+    /// available, together with that expression.
+    pub fn redundant(fun: &Function,res: &DataflowResult<Expression>) -> Vec<(Position,Expression)> {
+        let mut found = Vec::new();
+        let blocks = match fun.body {
+            Some(ref blks) => blks,
+            None => return found
+        };
+        for (b,blk) in blocks.iter().enumerate() {
+            for (i,instr) in blk.instrs.iter().enumerate() {
+                let pos = Position { block: b, instr: i };
+                if let (Some(e),Some(avail)) = (Expression::of(&instr.content),res.before(pos)) {
+                    if avail.contains(&e) {
+                        found.push((pos,e));
+                    }
+                }
+            }
+        }
+        found
+    }
+}
+
+impl Problem for AvailableExpressions {
+    type Fact = Expression;
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+    fn meet(&self) -> Meet {
+        Meet::Intersection
+    }
+    fn boundary(&self,_: &Function) -> BTreeSet<Expression> {
+        BTreeSet::new()
+    }
+    fn transfer(&self,_: Position,instr: &Instruction,facts: &mut BTreeSet<Expression>) {
+        let name = instr.content.name();
+        if let Some(name) = name {
+            facts.retain(|e| !e.uses(name));
+        }
+        if let Some(e) = Expression::of(&instr.content) {
+            if !name.is_some_and(|n| e.uses(n)) {
+                facts.insert(e);
+            }
+        }
+    }
+}
+
+/// The available expressions of a function, `None` for declarations.
+pub fn available_expressions(fun: &Function) -> Option<DataflowResult<Expression>> {
+    solve(&AvailableExpressions,fun)
+}
+
+#[cfg(test)]
+fn pos(block: usize,instr: usize) -> Position {
+    Position { block, instr }
+}
+
+#[test]
+fn test_liveness() {
+    let fun = parse_test_function(b"define i32 @f(i32 %n, i32 %m) {
+entry:
+  %x = add i32 %n, 1
+  br label %loop
+
+loop:
+  %i = phi i32 [ 0, %entry ], [ %i2, %loop ]
+  %s = phi i32 [ %x, %entry ], [ %s2, %loop ]
+  %s2 = add i32 %s, %i
+  %i2 = add i32 %i, 1
+  %c = icmp slt i32 %i2, %n
+  br i1 %c, label %loop, label %exit
+
+exit:
+  ret i32 %s2
+}");
+    let res = liveness(&fun).unwrap();
+    let set = |vals: &[&str]| -> BTreeSet<Value> {
+        vals.iter().map(|v| match *v {
+            "n" => Value::Argument(0),
+            v => Value::Local(v.to_string())
+        }).collect()
+    };
+    assert_eq!(res.direction(),Direction::Backward);
+    assert_eq!(res.block_in(0),Some(&set(&["n"])));
+    assert_eq!(res.block_out(0),Some(&set(&["n","x"])));
+    // Phi operands are live at the end of the predecessors only
+    assert_eq!(res.block_in(1),Some(&set(&["n"])));
+    assert_eq!(res.block_out(1),Some(&set(&["n","i2","s2"])));
+    assert_eq!(res.before(pos(1,2)),Some(&set(&["n","i","s"])));
+    assert_eq!(res.after(pos(1,2)),Some(&set(&["n","i","s2"])));
+    assert_eq!(res.block_in(2),Some(&set(&["s2"])));
+    assert_eq!(res.block_out(2),Some(&set(&[])));
+}
+
+#[test]
+fn test_reaching_stores() {
+    let fun = parse_test_function(b"define i32 @f(i1 %c, i32* %q) {
+entry:
+  %a = alloca i32, align 4
+  %b = alloca i32, align 4
+  store i32 0, i32* %a, align 4
+  store i32 1, i32* %b, align 4
+  br i1 %c, label %then, label %join
+
+then:
+  store i32 2, i32* %a, align 4
+  store i32 3, i32* %q, align 4
+  br label %join
+
+join:
+  %x = load i32* %a, align 4
+  store i32 %x, i32* %a, align 4
+  %y = load i32* %a, align 4
+  %z = load i32* %b, align 4
+  ret i32 %y
+}");
+    let (rs,res) = reaching_stores(&fun).unwrap();
+    assert_eq!(rs.target(pos(0,2)),Some("a"));
+    assert_eq!(rs.target(pos(1,1)),None);
+    assert_eq!(res.block_in(2),Some(&vec![pos(0,2),pos(0,3),pos(1,0)].into_iter().collect()));
+    assert_eq!(rs.stores_for_load(&fun,&res,pos(2,0)),vec![pos(0,2),pos(1,0)]);
+    assert_eq!(rs.stores_for_load(&fun,&res,pos(2,2)),vec![pos(2,1)]);
+    assert_eq!(rs.stores_for_load(&fun,&res,pos(2,3)),vec![pos(0,3)]);
+    assert!(rs.stores_for_load(&fun,&res,pos(2,1)).is_empty());
+}
+
+#[test]
+fn test_available_expressions() {
+    let fun = parse_test_function(b"define i32 @f(i32 %a, i32 %b, i1 %c) {
+entry:
+  %x = add i32 %a, %b
+  br i1 %c, label %then, label %else
+
+then:
+  %y = mul i32 %a, %b
+  %x2 = add i32 %a, %b
+  br label %join
+
+else:
+  %y2 = mul i32 %a, %b
+  br label %join
+
+join:
+  %x3 = add i32 %a, %b
+  %y3 = mul i32 %a, %b
+  br label %loop
+
+loop:
+  %i = phi i32 [ 0, %join ], [ %i2, %loop ]
+  %i2 = add i32 %i, 1
+  %d = icmp slt i32 %i2, %x3
+  br i1 %d, label %loop, label %exit
+
+exit:
+  ret i32 %i2
+}");
+    let res = available_expressions(&fun).unwrap();
+    let add = Expression::Bin(BinOp::Add(false,false),Type::Int(32),Value::Argument(0),Value::Argument(1));
+    let mul = Expression::Bin(BinOp::Mul(false,false),Type::Int(32),Value::Argument(0),Value::Argument(1));
+    let inc = Expression::Bin(BinOp::Add(false,false),Type::Int(32),Value::Local("i".to_string()),
+                              Value::Constant(Constant::Int(1.into())));
+    assert_eq!(res.block_in(3),Some(&vec![add.clone(),mul.clone()].into_iter().collect()));
+    // The phi node redefines %i, so the increment is not available
+    assert!(!res.block_in(4).unwrap().contains(&inc));
+    assert!(res.after(pos(4,1)).unwrap().contains(&inc));
+    assert!(!res.after(pos(4,0)).unwrap().contains(&inc));
+    let redundant: Vec<Position> = AvailableExpressions::redundant(&fun,&res).into_iter().map(|r| r.0).collect();
+    assert_eq!(redundant,vec![pos(1,1),pos(3,0),pos(3,1)]);
+}
+
+#[test]
+fn test_dataflow_minisat() {
+    let m = minisat();
+    for fun in m.functions.values() {
+        let live = match liveness(fun) {
+            Some(res) => res,
+            None => continue
+        };
+        // Only arguments are live at the entry
+        assert!(live.block_in(0).unwrap().iter().all(|v| matches!(*v,Value::Argument(_))),
+                "{}",fun.name);
+        let (_,stores) = reaching_stores(fun).unwrap();
+        assert!(stores.block_in(0).unwrap().is_empty());
+        let avail = available_expressions(fun).unwrap();
+        assert!(avail.block_in(0).unwrap().is_empty());
+    }
+}
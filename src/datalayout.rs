@@ -1,7 +1,11 @@
 use nom::{IResult,ErrorKind};
 use std::collections::HashMap;
+use std::cmp::max;
+use std::fmt;
+use std::str;
 use helper::*;
 use super::types::{Type};
+use ordered::OrderedMap;
 
 #[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord,Hash)]
 pub enum Endian {
@@ -112,45 +116,260 @@ impl DataLayout {
         }
     }
     pub fn object_alignment(&self) -> (u64,u64) {
-        match self.object_alignment {
-            Some(r) => r,
-            None => (0,64)
-        }
+        self.object_alignment.unwrap_or((0,64))
     }
     /// This is a rough estimation without considering alignment
-    pub fn type_size_in_bits(&self,tp: &Type,mp: &HashMap<String,Type>) -> u64 {
-        match tp {
-            &Type::Opaque => 0,
-            &Type::Int(sz) => sz,
-            &Type::Float => 32,
-            &Type::Double => 64,
-            &Type::PPC_FP128 => 128,
-            &Type::FP128 => 128,
-            &Type::X86_FP80 => 80,
-            &Type::Label => 0,
-            &Type::Pointer(_,ref sp) => {
-                let addr_sp = match sp {
-                    &None => 0,
-                    &Some(x) => x
-                };
+    pub fn type_size_in_bits(&self,tp: &Type,mp: &OrderedMap<String,Type>) -> u64 {
+        match *tp {
+            Type::Opaque => 0,
+            Type::Int(sz) => sz,
+            Type::Float => 32,
+            Type::Double => 64,
+            Type::PPC_FP128 => 128,
+            Type::FP128 => 128,
+            Type::X86_FP80 => 80,
+            Type::Label => 0,
+            Type::Pointer(_,ref sp) => {
+                let addr_sp = sp.unwrap_or(0);
                 let (sz,_,_) = self.pointer_alignment(addr_sp);
                 sz
             },
-            &Type::Struct(ref st) => {
+            Type::Struct(ref st) => {
                 let mut acc = 0;
                 for el in st.iter() {
                     acc += self.type_size_in_bits(el,mp);
                 }
                 acc
             },
-            &Type::Array(sz,ref sub_tp) => sz*self.type_size_in_bits(sub_tp,mp),
-            &Type::Function(_,_,_) => 0,
-            &Type::Named(ref name) => match mp.get(name) {
+            Type::Array(sz,ref sub_tp) => sz*self.type_size_in_bits(sub_tp,mp),
+            Type::Function(_,_,_) => 0,
+            Type::Named(ref name) => match mp.get(name) {
                 None => panic!("Named type {} not found",name),
                 Some(rtp) => self.type_size_in_bits(rtp,mp)
             },
-            &Type::Metadata => 0
+            Type::Metadata => 0
+        }
+    }
+    /// The ABI alignment of a type in bytes.
+    ///
+    /// Integer widths without an explicit alignment use the alignment
+    /// of the next larger defined width, or of the largest one.
+    pub fn type_alignment(&self,tp: &Type,mp: &OrderedMap<String,Type>) -> Result<u64,LayoutError> {
+        self.alignment(tp,mp,&mut Vec::new())
+    }
+    /// The number of bytes written when storing a value of the type.
+    pub fn type_store_size(&self,tp: &Type,mp: &OrderedMap<String,Type>) -> Result<u64,LayoutError> {
+        self.store_size(tp,mp,&mut Vec::new())
+    }
+    /// The distance in bytes between consecutive values of the type in
+    /// memory, i.e. the store size rounded up to the alignment.
+    pub fn type_alloc_size(&self,tp: &Type,mp: &OrderedMap<String,Type>) -> Result<u64,LayoutError> {
+        self.alloc_size(tp,mp,&mut Vec::new())
+    }
+    /// Compute the offsets of the elements of a (non-packed) struct.
+    pub fn struct_layout(&self,elems: &[Type],mp: &OrderedMap<String,Type>) -> Result<StructLayout,LayoutError> {
+        self.layout(elems,mp,&mut Vec::new())
+    }
+    // The following take the named types that are being laid out, a
+    // named type that contains itself is among them when it is reached
+    // again.
+    fn alignment(&self,tp: &Type,mp: &OrderedMap<String,Type>,seen: &mut Vec<String>) -> Result<u64,LayoutError> {
+        Ok(match *tp {
+            Type::Int(w) => {
+                let abi = match self.integer_alignment(w) {
+                    Some((abi,_)) => abi,
+                    None => {
+                        let mut widths: Vec<u64> = self.integer_alignment.keys()
+                            .cloned()
+                            .chain([8,16,32,64])
+                            .collect();
+                        widths.sort();
+                        let nw = match widths.iter().find(|&&nw| nw > w) {
+                            Some(nw) => *nw,
+                            None => *widths.last().unwrap()
+                        };
+                        self.integer_alignment(nw).unwrap().0
+                    }
+                };
+                max(abi/8,1)
+            },
+            Type::Float => self.float_alignment(32).unwrap().0/8,
+            Type::Double => self.float_alignment(64).unwrap().0/8,
+            Type::PPC_FP128 |
+            Type::FP128 => self.float_alignment(128).unwrap().0/8,
+            Type::X86_FP80 => match self.float_alignment(80) {
+                Some((abi,_)) => abi/8,
+                None => 16
+            },
+            Type::Pointer(_,ref sp) => {
+                let (_,abi,_) = self.pointer_alignment(sp.unwrap_or(0));
+                abi/8
+            },
+            Type::Struct(ref st) => self.layout(st,mp,seen)?.alignment,
+            Type::Array(_,ref sub_tp) => self.alignment(sub_tp,mp,seen)?,
+            Type::Named(ref name) => {
+                let rtp = named(name,mp,seen)?;
+                seen.push(name.clone());
+                let res = self.alignment(rtp,mp,seen);
+                seen.pop();
+                res?
+            },
+            _ => 1
+        })
+    }
+    fn store_size(&self,tp: &Type,mp: &OrderedMap<String,Type>,seen: &mut Vec<String>) -> Result<u64,LayoutError> {
+        Ok(match *tp {
+            Type::Struct(ref st) => self.layout(st,mp,seen)?.size,
+            Type::Array(sz,ref sub_tp) => sz*self.alloc_size(sub_tp,mp,seen)?,
+            Type::Named(ref name) => {
+                let rtp = named(name,mp,seen)?;
+                seen.push(name.clone());
+                let res = self.store_size(rtp,mp,seen);
+                seen.pop();
+                res?
+            },
+            _ => self.type_size_in_bits(tp,mp).div_ceil(8)
+        })
+    }
+    fn alloc_size(&self,tp: &Type,mp: &OrderedMap<String,Type>,seen: &mut Vec<String>) -> Result<u64,LayoutError> {
+        Ok(align_to(self.store_size(tp,mp,seen)?,self.alignment(tp,mp,seen)?))
+    }
+    fn layout(&self,elems: &[Type],mp: &OrderedMap<String,Type>,seen: &mut Vec<String>) -> Result<StructLayout,LayoutError> {
+        let mut offsets = Vec::with_capacity(elems.len());
+        let mut size = 0;
+        let mut alignment = 1;
+        for el in elems.iter() {
+            let al = self.alignment(el,mp,seen)?;
+            size = align_to(size,al);
+            offsets.push(size);
+            size += self.alloc_size(el,mp,seen)?;
+            alignment = max(alignment,al);
+        }
+        Ok(StructLayout { size: align_to(size,alignment),
+                          alignment,
+                          offsets })
+    }
+}
+
+// The definition of a named type, unless it is already being laid out.
+fn named<'a>(name: &str,mp: &'a OrderedMap<String,Type>,seen: &[String]) -> Result<&'a Type,LayoutError> {
+    if seen.iter().any(|n| n==name) {
+        return Err(LayoutError::Recursive(name.to_string()))
+    }
+    match mp.get(name) {
+        None => panic!("Named type {} not found",name),
+        Some(rtp) => Ok(rtp)
+    }
+}
+
+/// A type that has no memory layout.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum LayoutError {
+    /// The named type contains itself other than through a pointer.
+    Recursive(String)
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LayoutError::Recursive(ref n) => write!(f,"type %{} contains itself",n)
+        }
+    }
+}
+
+impl ::std::error::Error for LayoutError {}
+
+impl Default for DataLayout {
+    fn default() -> DataLayout {
+        DataLayout::new()
+    }
+}
+
+/// Prints the layout specification, as it appears in `target datalayout`.
+impl fmt::Display for DataLayout {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        fn aligns(specs: &mut Vec<String>,prefix: &str,mp: &HashMap<u64,(u64,u64)>) {
+            let mut keys: Vec<&u64> = mp.keys().collect();
+            keys.sort();
+            for w in keys {
+                let (abi,pref) = mp[w];
+                if abi==pref {
+                    specs.push(format!("{}{}:{}",prefix,w,abi));
+                } else {
+                    specs.push(format!("{}{}:{}:{}",prefix,w,abi,pref));
+                }
+            }
+        }
+        let mut specs = Vec::new();
+        match self.endianess {
+            Some(Endian::Little) => specs.push("e".to_string()),
+            Some(Endian::Big) => specs.push("E".to_string()),
+            None => {}
+        }
+        if let Some(ref m) = self.mangling {
+            specs.push(format!("m:{}",match *m {
+                Mangling::ELF => 'e',
+                Mangling::Mips => 'm',
+                Mangling::MachO => 'o',
+                Mangling::Windows => 'w',
+                Mangling::WindowsX86 => 'x'
+            }));
+        }
+        let mut spaces: Vec<&AddressSpace> = self.pointer_alignment.keys().collect();
+        spaces.sort();
+        for sp in spaces {
+            let (size,abi,pref) = self.pointer_alignment[sp];
+            let sp = if *sp==0 { String::new() } else { sp.to_string() };
+            if abi==pref {
+                specs.push(format!("p{}:{}:{}",sp,size,abi));
+            } else {
+                specs.push(format!("p{}:{}:{}:{}",sp,size,abi,pref));
+            }
+        }
+        aligns(&mut specs,"i",&self.integer_alignment);
+        aligns(&mut specs,"v",&self.vector_alignment);
+        aligns(&mut specs,"f",&self.float_alignment);
+        if let Some((abi,pref)) = self.object_alignment {
+            specs.push(if abi==pref { format!("a:{}",abi) } else { format!("a:{}:{}",abi,pref) });
+        }
+        if !self.native_ints.is_empty() {
+            let ints: Vec<String> = self.native_ints.iter().map(|i| i.to_string()).collect();
+            specs.push(format!("n{}",ints.join(":")));
+        }
+        if !self.non_integral_addr_space.is_empty() {
+            let sps: Vec<String> = self.non_integral_addr_space.iter().map(|i| i.to_string()).collect();
+            specs.push(format!("ni:{}",sps.join(":")));
+        }
+        if let Some(n) = self.stack_alignment {
+            specs.push(format!("S{}",n));
+        }
+        if let Some(sp) = self.alloca_addr_space {
+            specs.push(format!("A{}",sp));
         }
+        write!(f,"{}",specs.join("-"))
+    }
+}
+
+/// The memory layout of a struct type, all values are in bytes.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct StructLayout {
+    pub size: u64,
+    pub alignment: u64,
+    pub offsets: Vec<u64>
+}
+
+impl StructLayout {
+    pub fn element_offset(&self,idx: usize) -> u64 {
+        self.offsets[idx]
+    }
+}
+
+/// Round `n` up to a multiple of `align`.
+pub fn align_to(n: u64,align: u64) -> u64 {
+    if align==0 {
+        n
+    } else {
+        n.div_ceil(align)*align
     }
 }
 
@@ -163,10 +382,10 @@ named!(pub datalayout<DataLayout>,
                       char!('"') >>
                       (dl))));
 
-fn datalayout_string(inp: &[u8]) -> IResult<&[u8],DataLayout> {
+pub fn datalayout_string(inp: &[u8]) -> IResult<&[u8],DataLayout> {
     let mut layout = DataLayout::new();
     let mut input = inp;
-    while input.len() > 0 {
+    while !input.is_empty() {
         match input[0] {
             b'E' => {
                 layout.endianess = Some(Endian::Big);
@@ -219,11 +438,11 @@ fn datalayout_string(inp: &[u8]) -> IResult<&[u8],DataLayout> {
             b'i' => match parse_u64(&input[1..]) {
                 IResult::Error(err) => return IResult::Error(err),
                 IResult::Incomplete(need) => return IResult::Incomplete(need),
-                IResult::Done(ninp,bw) => if ninp.len()>0 {
+                IResult::Done(ninp,bw) => if !ninp.is_empty() {
                     match parse_u64(&ninp[1..]) {
                         IResult::Error(err) => return IResult::Error(err),
                         IResult::Incomplete(need) => return IResult::Incomplete(need),
-                        IResult::Done(ninp,abi) => if ninp.len()>0 {
+                        IResult::Done(ninp,abi) => if !ninp.is_empty() {
                             match ninp[0] {
                                 b'-' => {
                                     input = &ninp[1..];
@@ -252,11 +471,11 @@ fn datalayout_string(inp: &[u8]) -> IResult<&[u8],DataLayout> {
             b'f' => match parse_u64(&input[1..]) {
                 IResult::Error(err) => return IResult::Error(err),
                 IResult::Incomplete(need) => return IResult::Incomplete(need),
-                IResult::Done(ninp,bw) => if ninp.len()>0 {
+                IResult::Done(ninp,bw) => if !ninp.is_empty() {
                     match parse_u64(&ninp[1..]) {
                         IResult::Error(err) => return IResult::Error(err),
                         IResult::Incomplete(need) => return IResult::Incomplete(need),
-                        IResult::Done(ninp,abi) => if ninp.len()>0 {
+                        IResult::Done(ninp,abi) => if !ninp.is_empty() {
                             match ninp[0] {
                                 b'-' => {
                                     input = &ninp[1..];
@@ -282,6 +501,22 @@ fn datalayout_string(inp: &[u8]) -> IResult<&[u8],DataLayout> {
                     return IResult::Error(ErrorKind::Custom(5))
                 }
             },
+            b'p' => {
+                // p[n]:<size>:<abi>[:<pref>[:<idx>]]
+                let end = input.iter().position(|&c| c==b'-' || c==b'"').unwrap_or(input.len());
+                let fields: Option<Vec<u64>> = str::from_utf8(&input[1..end]).ok()
+                    .and_then(|spec| spec.split(':')
+                              .map(|f| if f.is_empty() { Some(0) } else { f.parse().ok() })
+                              .collect());
+                match fields {
+                    Some(ref f) if f.len() >= 3 => {
+                        let pref = if f.len() > 3 { f[3] } else { f[2] };
+                        layout.pointer_alignment.insert(f[0],(f[1],f[2],pref));
+                    },
+                    _ => return IResult::Error(ErrorKind::Custom(7))
+                }
+                input = if input.get(end)==Some(&b'-') { &input[end+1..] } else { &input[end..] };
+            },
             b'n' => {
                 let mut vec = Vec::new();
                 input = &input[1..];
@@ -291,7 +526,7 @@ fn datalayout_string(inp: &[u8]) -> IResult<&[u8],DataLayout> {
                         IResult::Incomplete(need) => return IResult::Incomplete(need),
                         IResult::Done(ninp,pref) => {
                             vec.push(pref);
-                            if ninp.len() > 0 {
+                            if !ninp.is_empty() {
                                 match ninp[0] {
                                     b'-' => {
                                         input = &ninp[1..];
@@ -335,5 +570,51 @@ fn test_datalayout() {
                                native_ints: vec![8,16,32,64],
                                non_integral_addr_space: Vec::new() };
     assert_eq!(datalayout(b"target datalayout = \"e-m:o-i64:64-f80:128-n8:16:32:64-S128\""),
-               IResult::Done(&b""[..],layout1));
+               IResult::Done(&b""[..],layout1.clone()));
+    let mut layout2 = layout1;
+    layout2.mangling = Some(Mangling::ELF);
+    layout2.pointer_alignment.insert(270,(32,32,32));
+    layout2.pointer_alignment.insert(272,(64,64,64));
+    assert_eq!(datalayout(b"target datalayout = \"e-m:e-p270:32:32-p272:64:64-i64:64-f80:128-n8:16:32:64-S128\""),
+               IResult::Done(&b""[..],layout2.clone()));
+    assert_eq!(layout2.to_string(),"e-m:e-p270:32:32-p272:64:64-i64:64-f80:128-n8:16:32:64-S128");
+}
+
+#[test]
+fn test_struct_layout() {
+    let layout = match datalayout(b"target datalayout = \"e-m:e-i64:64-f80:128-n8:16:32:64-S128\"") {
+        IResult::Done(_,l) => l,
+        _ => panic!("parse failure")
+    };
+    let mut mp = OrderedMap::new();
+    mp.insert("vec".to_string(),Type::Struct(vec![Type::Int(32),Type::Int(32),
+                                                  Type::ptr(Type::Int(32))]));
+    let vec = Type::Named("vec".to_string());
+    assert_eq!(layout.type_alloc_size(&vec,&mp),Ok(16));
+    assert_eq!(layout.type_alignment(&vec,&mp),Ok(8));
+    let st = layout.struct_layout(&[Type::Int(8),Type::Int(64),Type::Int(1),vec.clone()],&mp).unwrap();
+    assert_eq!(st.offsets,vec![0,8,16,24]);
+    assert_eq!(st.size,40);
+    assert_eq!(layout.type_store_size(&Type::Int(1),&mp),Ok(1));
+    assert_eq!(layout.type_alloc_size(&Type::Array(3,Box::new(Type::Int(24))),&mp),Ok(12));
+    assert_eq!(layout.type_alignment(&Type::Int(128),&mp),Ok(8));
+}
+
+#[test]
+fn test_recursive_layout() {
+    let layout = DataLayout::new();
+    let mut mp = OrderedMap::new();
+    let list = Type::Named("list".to_string());
+    let node = Type::Named("node".to_string());
+    mp.insert("list".to_string(),Type::Struct(vec![Type::Int(32),Type::ptr(list.clone())]));
+    mp.insert("node".to_string(),Type::Struct(vec![Type::Int(32),Type::Array(2,Box::new(node.clone()))]));
+    mp.insert("pair".to_string(),Type::Struct(vec![list.clone(),list.clone()]));
+    // Pointers and repeated elements are not cycles.
+    assert_eq!(layout.type_alloc_size(&list,&mp),Ok(16));
+    assert_eq!(layout.type_alloc_size(&Type::Named("pair".to_string()),&mp),Ok(32));
+    let err = Err(LayoutError::Recursive("node".to_string()));
+    assert_eq!(layout.type_alignment(&node,&mp),err);
+    assert_eq!(layout.type_store_size(&node,&mp),err);
+    assert_eq!(layout.type_alloc_size(&node,&mp),err);
+    assert_eq!(layout.struct_layout(&[Type::Int(8),node],&mp).map(|l| l.size),err);
 }
@@ -0,0 +1,539 @@
+//! Structural comparison of modules.
+//!
+//! `diff_modules` reports the differences between two modules element by
+//! element: functions, globals, named types and attribute groups are
+//! matched by name, and the bodies of functions defined in both modules
+//! are compared block by block.
+//!
+//! Bodies are compared up to a consistent renaming of locals and blocks,
+//! so a body that only differs in value numbering has no differences.
+//! Blocks are paired by walking both control flow graphs in tandem from
+//! the entry block; blocks that are not reached this way are paired by
+//! name. The instructions of paired blocks are aligned with a longest
+//! common subsequence, and every aligned pair binds the names it defines.
+//! A final pass checks the aligned pairs against all bindings and splits
+//! those that disagree into a removal and an addition.
+//!
+//! Metadata attachments are ignored, and attribute groups used by calls
+//! and functions are compared by their attributes, not their number.
+//! Lazy bodies, see `module_lazy`, are not compared; materialize the
+//! modules first.
+use std::collections::{HashMap,HashSet,VecDeque};
+use std::fmt;
+use super::*;
+use visit::VisitorMut;
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum Difference {
+    FunctionAdded(String),
+    FunctionRemoved(String),
+    /// The calling convention, return type, argument types or parameter
+    /// attributes, or whether the function takes variable arguments.
+    SignatureChanged(String),
+    /// The linkage, visibility or DLL storage class.
+    LinkageChanged(String),
+    /// The attributes of the function's attribute groups.
+    FunctionAttributesChanged(String),
+    /// A declared function is defined in the new module.
+    BodyAdded(String),
+    /// A defined function is only declared in the new module.
+    BodyRemoved(String),
+    BodyChanged(String,Vec<BlockDiff>),
+    GlobalAdded(String),
+    GlobalRemoved(String),
+    GlobalChanged(String),
+    TypeAdded(String),
+    TypeRemoved(String),
+    TypeChanged(String),
+    AttributeGroupAdded(u64),
+    AttributeGroupRemoved(u64),
+    AttributeGroupChanged(u64)
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Difference::FunctionAdded(ref n) => write!(f,"function @{} added",n),
+            Difference::FunctionRemoved(ref n) => write!(f,"function @{} removed",n),
+            Difference::SignatureChanged(ref n) => write!(f,"signature of @{} changed",n),
+            Difference::LinkageChanged(ref n) => write!(f,"linkage of @{} changed",n),
+            Difference::FunctionAttributesChanged(ref n) => write!(f,"attributes of @{} changed",n),
+            Difference::BodyAdded(ref n) => write!(f,"function @{} is now defined",n),
+            Difference::BodyRemoved(ref n) => write!(f,"function @{} is now declared",n),
+            Difference::BodyChanged(ref n,ref blks) => write!(f,"body of @{} changed in {} blocks",n,blks.len()),
+            Difference::GlobalAdded(ref n) => write!(f,"global @{} added",n),
+            Difference::GlobalRemoved(ref n) => write!(f,"global @{} removed",n),
+            Difference::GlobalChanged(ref n) => write!(f,"global @{} changed",n),
+            Difference::TypeAdded(ref n) => write!(f,"type %{} added",n),
+            Difference::TypeRemoved(ref n) => write!(f,"type %{} removed",n),
+            Difference::TypeChanged(ref n) => write!(f,"type %{} changed",n),
+            Difference::AttributeGroupAdded(n) => write!(f,"attribute group #{} added",n),
+            Difference::AttributeGroupRemoved(n) => write!(f,"attribute group #{} removed",n),
+            Difference::AttributeGroupChanged(n) => write!(f,"attribute group #{} changed",n)
+        }
+    }
+}
+
+/// A difference between the bodies of a function. Blocks are given by
+/// their position in the old and new body.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum BlockDiff {
+    Added(usize),
+    Removed(usize),
+    /// A pair of blocks whose instructions differ.
+    Changed(usize,usize,Vec<InstrDiff>)
+}
+
+/// An edit of the instructions of a block, by position in the old or new
+/// block. Edits are in the order of the blocks.
+#[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Hash,Clone,Copy)]
+pub enum InstrDiff {
+    Removed(usize),
+    Added(usize)
+}
+
+/// The differences between two modules, in the order of the old module's
+/// elements followed by the elements only in the new module.
+pub fn diff_modules(old: &Module,new: &Module) -> Vec<Difference> {
+    let mut groups = AttrGroups::new();
+    let old_groups = groups.canonical(old);
+    let new_groups = groups.canonical(new);
+    let mut res = Vec::new();
+    for (name,tp) in old.types.iter() {
+        match new.types.get(name) {
+            None => res.push(Difference::TypeRemoved(name.clone())),
+            Some(ntp) => if tp!=ntp {
+                res.push(Difference::TypeChanged(name.clone()))
+            }
+        }
+    }
+    res.extend(new.types.keys()
+               .filter(|n| !old.types.contains_key(*n))
+               .map(|n| Difference::TypeAdded(n.clone())));
+    for (name,g) in old.globals.iter() {
+        match new.globals.get(name) {
+            None => res.push(Difference::GlobalRemoved(name.clone())),
+            Some(ng) => if g!=ng {
+                res.push(Difference::GlobalChanged(name.clone()))
+            }
+        }
+    }
+    res.extend(new.globals.keys()
+               .filter(|n| !old.globals.contains_key(*n))
+               .map(|n| Difference::GlobalAdded(n.clone())));
+    for (name,f) in old.functions.iter() {
+        let nf = match new.functions.get(name) {
+            None => {
+                res.push(Difference::FunctionRemoved(name.clone()));
+                continue
+            },
+            Some(nf) => nf
+        };
+        if signature(f)!=signature(nf) {
+            res.push(Difference::SignatureChanged(name.clone()))
+        }
+        if (f.linkage,f.visibility,f.dll_storage_class)!=(nf.linkage,nf.visibility,nf.dll_storage_class) {
+            res.push(Difference::LinkageChanged(name.clone()))
+        }
+        if function_attributes(old,f)!=function_attributes(new,nf) {
+            res.push(Difference::FunctionAttributesChanged(name.clone()))
+        }
+        match (&f.body,&nf.body) {
+            (Some(b1),Some(b2)) => {
+                let blks = BodyDiff::new(b1,b2,&old_groups,&new_groups).run();
+                if !blks.is_empty() {
+                    res.push(Difference::BodyChanged(name.clone(),blks))
+                }
+            },
+            (None,Some(_)) => res.push(Difference::BodyAdded(name.clone())),
+            (Some(_),None) => res.push(Difference::BodyRemoved(name.clone())),
+            (None,None) => {}
+        }
+    }
+    res.extend(new.functions.keys()
+               .filter(|n| !old.functions.contains_key(*n))
+               .map(|n| Difference::FunctionAdded(n.clone())));
+    for (id,attrs) in old.attr_groups.iter() {
+        match new.attr_groups.get(id) {
+            None => res.push(Difference::AttributeGroupRemoved(*id)),
+            Some(nattrs) => if sorted(attrs)!=sorted(nattrs) {
+                res.push(Difference::AttributeGroupChanged(*id))
+            }
+        }
+    }
+    res.extend(new.attr_groups.keys()
+               .filter(|id| !old.attr_groups.contains_key(*id))
+               .map(|id| Difference::AttributeGroupAdded(*id)));
+    res
+}
+
+/// The differences between two function bodies. Attribute groups used
+/// by calls are compared by number.
+pub fn diff_bodies(old: &[BasicBlock],new: &[BasicBlock]) -> Vec<BlockDiff> {
+    let groups = HashMap::new();
+    BodyDiff::new(old,new,&groups,&groups).run()
+}
+
+type Signature<'a> = (&'a CallingConv,&'a Option<(ParAttrs,Type)>,Vec<&'a Type>,&'a [ParAttrs],bool);
+
+// Argument names are not part of the signature.
+fn signature(f: &Function) -> Signature<'_> {
+    (&f.cconv,&f.return_type,f.arguments.iter().map(|a| &a.1).collect(),&f.argument_attrs,f.var_args)
+}
+
+fn sorted(attrs: &[Attribute]) -> Vec<&Attribute> {
+    let mut res: Vec<&Attribute> = attrs.iter().collect();
+    res.sort();
+    res
+}
+
+fn function_attributes<'a>(m: &'a Module,f: &Function) -> Vec<&'a Attribute> {
+    let mut res: Vec<&Attribute> = f.attribute_groups.iter()
+        .filter_map(|g| m.attr_groups.get(g))
+        .flat_map(|attrs| attrs.iter())
+        .collect();
+    res.sort();
+    res.dedup();
+    res
+}
+
+// Numbers attribute groups by their attributes, across modules.
+struct AttrGroups {
+    ids: HashMap<Vec<Attribute>,u64>
+}
+
+impl AttrGroups {
+    fn new() -> AttrGroups {
+        AttrGroups { ids: HashMap::new() }
+    }
+    fn canonical(&mut self,m: &Module) -> HashMap<u64,u64> {
+        let mut res = HashMap::new();
+        for (id,attrs) in m.attr_groups.iter() {
+            let mut attrs = attrs.clone();
+            attrs.sort();
+            let next = self.ids.len() as u64;
+            res.insert(*id,*self.ids.entry(attrs).or_insert(next));
+        }
+        res
+    }
+}
+
+// Replaces locals and labels by the names they are bound to in the new
+// body and attribute groups by their canonical number. Unbound names
+// become empty, as do the names defined by instructions.
+struct Canon<'a> {
+    names: Option<&'a HashMap<String,String>>,
+    known: &'a HashSet<String>,
+    groups: &'a HashMap<u64,u64>
+}
+
+impl<'a> Canon<'a> {
+    fn rename(&self,name: &mut String) {
+        let new = match self.names {
+            Some(names) => names.get(name).cloned().unwrap_or_default(),
+            None => if self.known.contains(name) { return } else { String::new() }
+        };
+        *name = new;
+    }
+    fn instruction(&mut self,instr: &InstructionC) -> InstructionC {
+        let mut res = instr.clone();
+        self.visit_instruction_c_mut(&mut res);
+        res
+    }
+}
+
+impl<'a> VisitorMut for Canon<'a> {
+    fn visit_value_mut(&mut self,v: &mut Value) {
+        match *v {
+            Value::Local(ref mut name) => self.rename(name),
+            _ => visit::walk_value_mut(self,v)
+        }
+    }
+    fn visit_def_mut(&mut self,name: &mut String) {
+        name.clear()
+    }
+    fn visit_label_mut(&mut self,label: &mut String) {
+        self.rename(label)
+    }
+    fn visit_attribute_group_mut(&mut self,id: &mut AttributeGroup) {
+        if !self.groups.is_empty() {
+            *id = self.groups.get(id).cloned().unwrap_or(u64::MAX)
+        }
+    }
+}
+
+#[derive(Clone,Copy)]
+enum Edit {
+    Keep(usize,usize),
+    Removed(usize),
+    Added(usize)
+}
+
+struct BodyDiff<'a> {
+    old: &'a [BasicBlock],
+    new: &'a [BasicBlock],
+    old_groups: &'a HashMap<u64,u64>,
+    new_groups: &'a HashMap<u64,u64>,
+    // Bindings of old locals and blocks to new ones, and their targets.
+    names: HashMap<String,String>,
+    bound: HashSet<String>
+}
+
+impl<'a> BodyDiff<'a> {
+    fn new(old: &'a [BasicBlock],new: &'a [BasicBlock],
+           old_groups: &'a HashMap<u64,u64>,new_groups: &'a HashMap<u64,u64>) -> BodyDiff<'a> {
+        BodyDiff { old,
+                   new,
+                   old_groups,
+                   new_groups,
+                   names: HashMap::new(),
+                   bound: HashSet::new() }
+    }
+
+    fn bind(&mut self,old: &str,new: &str) -> bool {
+        if self.names.contains_key(old) || self.bound.contains(new) {
+            return false
+        }
+        self.names.insert(old.to_string(),new.to_string());
+        self.bound.insert(new.to_string());
+        true
+    }
+
+    fn pair_blocks(&mut self) -> Vec<(usize,usize)> {
+        let old_idx: HashMap<&str,usize> = self.old.iter().enumerate().map(|(i,b)| (&b.name[..],i)).collect();
+        let new_idx: HashMap<&str,usize> = self.new.iter().enumerate().map(|(i,b)| (&b.name[..],i)).collect();
+        let mut pairs = Vec::new();
+        let mut queue = VecDeque::new();
+        if !self.old.is_empty() && !self.new.is_empty() {
+            queue.push_back((0,0));
+        }
+        while let Some((i,j)) = queue.pop_front() {
+            let (b1,b2) = (&self.old[i],&self.new[j]);
+            if !self.bind(&b1.name,&b2.name) {
+                continue
+            }
+            pairs.push((i,j));
+            if let (Some(t1),Some(t2)) = (b1.terminator(),b2.terminator()) {
+                let (l1,l2) = (t1.targets(),t2.targets());
+                if l1.len()==l2.len() {
+                    for (x,y) in l1.into_iter().zip(l2) {
+                        if let (Some(&i),Some(&j)) = (old_idx.get(x),new_idx.get(y)) {
+                            queue.push_back((i,j))
+                        }
+                    }
+                }
+            }
+        }
+        for (i,b) in self.old.iter().enumerate() {
+            if let Some(&j) = new_idx.get(&b.name[..]) {
+                if self.bind(&b.name,&b.name) {
+                    pairs.push((i,j))
+                }
+            }
+        }
+        pairs
+    }
+
+    fn canon_old(&self,instr: &InstructionC) -> InstructionC {
+        Canon { names: Some(&self.names), known: &self.bound, groups: self.old_groups }.instruction(instr)
+    }
+
+    fn canon_new(&self,instr: &InstructionC) -> InstructionC {
+        Canon { names: None, known: &self.bound, groups: self.new_groups }.instruction(instr)
+    }
+
+    // Aligns two blocks with the current bindings and binds the names
+    // defined by aligned instructions.
+    fn align(&mut self,b1: &BasicBlock,b2: &BasicBlock) -> Vec<Edit> {
+        let c1: Vec<InstructionC> = b1.instrs.iter().map(|i| self.canon_old(&i.content)).collect();
+        let c2: Vec<InstructionC> = b2.instrs.iter().map(|i| self.canon_new(&i.content)).collect();
+        let edits = lcs(&c1,&c2);
+        for e in edits.iter() {
+            if let Edit::Keep(i,j) = *e {
+                if let (Some(n1),Some(n2)) = (b1.instrs[i].content.name(),b2.instrs[j].content.name()) {
+                    self.bind(n1,n2);
+                }
+            }
+        }
+        edits
+    }
+
+    fn run(mut self) -> Vec<BlockDiff> {
+        let (old,new) = (self.old,self.new);
+        let pairs = self.pair_blocks();
+        let aligned: Vec<Vec<Edit>> = pairs.iter()
+            .map(|&(i,j)| self.align(&old[i],&new[j]))
+            .collect();
+        let mut res = Vec::new();
+        for (&(i,j),edits) in pairs.iter().zip(aligned) {
+            let mut instrs = Vec::new();
+            for e in edits {
+                match e {
+                    Edit::Keep(k,l) => {
+                        let c1 = self.canon_old(&old[i].instrs[k].content);
+                        let c2 = self.canon_new(&new[j].instrs[l].content);
+                        if c1!=c2 {
+                            instrs.push(InstrDiff::Removed(k));
+                            instrs.push(InstrDiff::Added(l));
+                        }
+                    },
+                    Edit::Removed(k) => instrs.push(InstrDiff::Removed(k)),
+                    Edit::Added(l) => instrs.push(InstrDiff::Added(l))
+                }
+            }
+            if !instrs.is_empty() {
+                res.push(BlockDiff::Changed(i,j,instrs))
+            }
+        }
+        let old_paired: HashSet<usize> = pairs.iter().map(|p| p.0).collect();
+        let new_paired: HashSet<usize> = pairs.iter().map(|p| p.1).collect();
+        res.extend((0..old.len()).filter(|i| !old_paired.contains(i)).map(BlockDiff::Removed));
+        res.extend((0..new.len()).filter(|j| !new_paired.contains(j)).map(BlockDiff::Added));
+        res
+    }
+}
+
+// The edits turning one sequence into the other with the most kept
+// elements.
+fn lcs<T: PartialEq>(a: &[T],b: &[T]) -> Vec<Edit> {
+    let (n,m) = (a.len(),b.len());
+    // len[i][j] is the length of a common subsequence of a[i..] and b[j..]
+    let mut len = vec![vec![0usize;m+1];n+1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            len[i][j] = if a[i]==b[j] {
+                len[i+1][j+1]+1
+            } else {
+                len[i+1][j].max(len[i][j+1])
+            }
+        }
+    }
+    let mut res = Vec::new();
+    let (mut i,mut j) = (0,0);
+    while i<n && j<m {
+        if a[i]==b[j] {
+            res.push(Edit::Keep(i,j));
+            i += 1;
+            j += 1;
+        } else if len[i+1][j]>=len[i][j+1] {
+            res.push(Edit::Removed(i));
+            i += 1;
+        } else {
+            res.push(Edit::Added(j));
+            j += 1;
+        }
+    }
+    res.extend((i..n).map(Edit::Removed));
+    res.extend((j..m).map(Edit::Added));
+    res
+}
+
+#[test]
+fn test_diff_renamed() {
+    let m1 = minisat();
+    assert_eq!(diff_modules(&m1,&m1),Vec::new());
+    let src1 = b"define i32 @f(i32 %x) {
+entry:
+  %a = add i32 %x, 1
+  %c = icmp sgt i32 %a, 0
+  br i1 %c, label %pos, label %done
+
+pos:
+  %b = mul i32 %a, 2
+  br label %done
+
+done:
+  %r = phi i32 [ %a, %entry ], [ %b, %pos ]
+  ret i32 %r
+}
+";
+    let src2 = b"define i32 @f(i32 %y) {
+0:
+  %1 = add i32 %y, 1
+  %2 = icmp sgt i32 %1, 0
+  br i1 %2, label %3, label %5
+
+3:
+  %4 = mul i32 %1, 2
+  br label %5
+
+5:
+  %6 = phi i32 [ %1, %0 ], [ %4, %3 ]
+  ret i32 %6
+}
+";
+    let (m1,m2) = (parse_test_module(src1),parse_test_module(src2));
+    assert_eq!(diff_modules(&m1,&m2),Vec::new());
+    let src3 = b"define i32 @f(i32 %y) {
+0:
+  %1 = add i32 %y, 1
+  %2 = icmp sgt i32 %1, 0
+  br i1 %2, label %3, label %5
+
+3:
+  %4 = shl i32 %1, 1
+  br label %5
+
+5:
+  %6 = phi i32 [ %1, %0 ], [ %4, %3 ]
+  ret i32 %6
+}
+";
+    let m3 = parse_test_module(src3);
+    assert_eq!(diff_modules(&m1,&m3),
+               vec![Difference::BodyChanged("f".to_string(),
+                                            vec![BlockDiff::Changed(1,1,vec![InstrDiff::Removed(0),
+                                                                             InstrDiff::Added(0)])])]);
+}
+
+#[test]
+fn test_diff_elements() {
+    let src1 = b"%t = type { i32 }
+@g = global i32 1
+@h = global i32 2
+
+declare void @a(i32) #0
+declare void @b()
+define void @c() {
+entry:
+  call void @b() #1
+  ret void
+}
+
+attributes #0 = { nounwind }
+attributes #1 = { cold }
+";
+    let src2 = b"%t = type { i64 }
+%u = type { i8 }
+@g = global i32 3
+
+declare void @a(i64) #1
+define void @b() {
+entry:
+  ret void
+}
+define void @c() {
+entry:
+  call void @b() #0
+  call void @b() #0
+  ret void
+}
+declare void @d()
+
+attributes #0 = { cold }
+attributes #1 = { nounwind }
+";
+    let (m1,m2) = (parse_test_module(src1),parse_test_module(src2));
+    assert_eq!(diff_modules(&m1,&m2),
+               vec![Difference::TypeChanged("t".to_string()),
+                    Difference::TypeAdded("u".to_string()),
+                    Difference::GlobalChanged("g".to_string()),
+                    Difference::GlobalRemoved("h".to_string()),
+                    Difference::SignatureChanged("a".to_string()),
+                    Difference::BodyAdded("b".to_string()),
+                    Difference::BodyChanged("c".to_string(),
+                                            vec![BlockDiff::Changed(0,0,vec![InstrDiff::Added(1)])]),
+                    Difference::FunctionAdded("d".to_string()),
+                    Difference::AttributeGroupChanged(0),
+                    Difference::AttributeGroupChanged(1)]);
+}
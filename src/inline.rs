@@ -0,0 +1,508 @@
+//! Function inlining.
+//!
+//! `inline_call` replaces a single direct call by a copy of the body of
+//! the callee. The block containing the call is split at the call, the
+//! copied blocks are placed in between, and returns become branches to
+//! the second half of the split block, where a phi collects the return
+//! value under the name of the call. Locals and blocks of the callee are
+//! renamed where they collide with names of the caller.
+//!
+//! The `Inliner` pass decides which calls to inline with a simple cost
+//! model: the number of instructions of the callee, not counting debug
+//! intrinsics, compared against a threshold.
+use std::collections::{HashMap,HashSet};
+use std::fmt;
+use super::*;
+use callgraph::CallGraph;
+use passes::{fresh_name,AnalysisManager,Pass,Preserved};
+use ssa::Position;
+use visit::VisitorMut;
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum InlineError {
+    UnknownFunction(String),
+    NotDefined(String),
+    /// There is no call at the position, or the callee is not a
+    /// function name.
+    NotADirectCall(Position),
+    /// The callee is the caller itself.
+    Recursive(String),
+    VarArgs(String),
+    /// The number of arguments does not match the callee.
+    ArgumentMismatch(String)
+}
+
+impl fmt::Display for InlineError {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InlineError::UnknownFunction(ref n) => write!(f,"unknown function @{}",n),
+            InlineError::NotDefined(ref n) => write!(f,"function @{} has no body",n),
+            InlineError::NotADirectCall(ref p) => write!(f,"no direct call at {}:{}",p.block,p.instr),
+            InlineError::Recursive(ref n) => write!(f,"cannot inline @{} into itself",n),
+            InlineError::VarArgs(ref n) => write!(f,"cannot inline variadic function @{}",n),
+            InlineError::ArgumentMismatch(ref n) => write!(f,"wrong number of arguments for @{}",n)
+        }
+    }
+}
+
+impl ::std::error::Error for InlineError {}
+
+/// The callee of a direct call, if the instruction is one.
+pub fn direct_callee(instr: &InstructionC) -> Option<&str> {
+    match *instr {
+        InstructionC::Call(_,_,_,Value::Constant(Constant::Global(ref f)),_,_) => Some(f),
+        _ => None
+    }
+}
+
+fn is_debug_intrinsic(instr: &InstructionC) -> bool {
+    direct_callee(instr).is_some_and(|f| f.starts_with("llvm.dbg."))
+}
+
+/// The cost of inlining a function: its number of instructions, without
+/// debug intrinsics.
+pub fn inline_cost(fun: &Function) -> usize {
+    fun.body.iter()
+        .flat_map(|blks| blks.iter())
+        .flat_map(|blk| blk.instrs.iter())
+        .filter(|i| !is_debug_intrinsic(&i.content))
+        .count()
+}
+
+/// Whether one of the attribute groups of a function contains the
+/// attribute.
+pub fn has_attribute(m: &Module,fun: &Function,name: &str) -> bool {
+    fun.attribute_groups.iter()
+        .filter_map(|g| m.attr_groups.get(g))
+        .any(|attrs| attrs.iter().any(|a| !a.quoted && a.name==name))
+}
+
+// Renames the locals and blocks of the callee and replaces its
+// arguments by the operands of the call.
+struct Rename<'a> {
+    names: &'a HashMap<String,String>,
+    args: &'a [Value]
+}
+
+impl<'a> VisitorMut for Rename<'a> {
+    fn visit_value_mut(&mut self,v: &mut Value) {
+        let new = match *v {
+            Value::Argument(n) => Some(self.args[n].clone()),
+            Value::Local(ref name) => self.names.get(name).map(|n| Value::Local(n.clone())),
+            _ => None
+        };
+        match new {
+            Some(new) => *v = new,
+            None => visit::walk_value_mut(self,v)
+        }
+    }
+    fn visit_def_mut(&mut self,name: &mut String) {
+        if let Some(n) = self.names.get(name) {
+            *name = n.clone();
+        }
+    }
+    fn visit_label_mut(&mut self,label: &mut String) {
+        if let Some(n) = self.names.get(label) {
+            *label = n.clone();
+        }
+    }
+}
+
+struct Replace<'a>(&'a str,&'a Value);
+
+impl<'a> VisitorMut for Replace<'a> {
+    fn visit_value_mut(&mut self,v: &mut Value) {
+        if matches!(*v,Value::Local(ref n) if n==self.0) {
+            *v = self.1.clone();
+        } else {
+            visit::walk_value_mut(self,v)
+        }
+    }
+}
+
+fn is_static_alloca(instr: &InstructionC) -> bool {
+    match *instr {
+        InstructionC::Alloca(_,_,None,_) => true,
+        InstructionC::Alloca(_,_,Some(ref n),_) => matches!(n.val,Value::Constant(Constant::Int(_))),
+        _ => false
+    }
+}
+
+/// Inline the call at a position of a function. Static allocas of the
+/// callee's entry block are moved to the entry block of the caller, so
+/// inlining into a loop does not grow the stack.
+pub fn inline_call(m: &mut Module,caller: &str,pos: Position) -> Result<(),InlineError> {
+    let (ret,callee,args) = {
+        let fun = m.functions.get(caller).ok_or_else(|| InlineError::UnknownFunction(caller.to_string()))?;
+        let blks = fun.body.as_ref().ok_or_else(|| InlineError::NotDefined(caller.to_string()))?;
+        let instr = blks.get(pos.block)
+            .and_then(|b| b.instrs.get(pos.instr))
+            .map(|i| &i.content);
+        match instr {
+            Some(InstructionC::Call(ret,_,_,Value::Constant(Constant::Global(f)),args,_)) =>
+                (ret.clone(),f.clone(),args.iter().map(|a| a.val.clone()).collect::<Vec<_>>()),
+            _ => return Err(InlineError::NotADirectCall(pos))
+        }
+    };
+    if callee==caller {
+        return Err(InlineError::Recursive(callee))
+    }
+    let target = m.functions.get(&callee).ok_or_else(|| InlineError::UnknownFunction(callee.clone()))?;
+    let mut body = target.body.clone().ok_or_else(|| InlineError::NotDefined(callee.clone()))?;
+    if target.var_args {
+        return Err(InlineError::VarArgs(callee))
+    }
+    if target.arguments.len()!=args.len() {
+        return Err(InlineError::ArgumentMismatch(callee))
+    }
+    let ret_type = target.return_type.as_ref().map(|r| r.1.clone());
+
+    let fun = m.functions.get_mut(caller).unwrap();
+    let mut used: HashSet<String> = fun.arguments.iter().filter_map(|a| a.0.clone()).collect();
+    let blks = fun.body.as_mut().unwrap();
+    for blk in blks.iter() {
+        used.insert(blk.name.clone());
+        used.extend(blk.instrs.iter().filter_map(|i| i.content.name().map(|n| n.to_string())));
+    }
+    let mut names = HashMap::new();
+    for blk in body.iter() {
+        names.insert(blk.name.clone(),fresh_name(&mut used,&blk.name));
+        for name in blk.instrs.iter().filter_map(|i| i.content.name()) {
+            names.insert(name.to_string(),fresh_name(&mut used,name));
+        }
+    }
+    let exit = fresh_name(&mut used,&format!("{}.exit",callee));
+
+    // Rename the copy and turn returns into branches
+    let mut returns = Vec::new();
+    {
+        let mut rename = Rename { names: &names, args: &args };
+        for blk in body.iter_mut() {
+            blk.name = names[&blk.name].clone();
+            rename.visit_basic_block_mut(blk);
+            if let Some(last) = blk.instrs.last_mut() {
+                let val = match last.content {
+                    InstructionC::Term(Terminator::Ret(ref v)) => v.as_ref().map(|v| v.val.clone()),
+                    _ => continue
+                };
+                if let Some(v) = val {
+                    returns.push((v,blk.name.clone()));
+                }
+                last.content = InstructionC::Term(Terminator::Br(exit.clone()));
+            }
+        }
+    }
+    let allocas: Vec<Instruction> = {
+        let (allocas,rest) = body[0].instrs.drain(..).partition(|i| is_static_alloca(&i.content));
+        body[0].instrs = rest;
+        allocas
+    };
+
+    // Split the block of the call
+    let mut tail = blks[pos.block].instrs.split_off(pos.instr);
+    tail.remove(0);
+    let from = blks[pos.block].name.clone();
+    blks[pos.block].instrs.push(Instruction { content: InstructionC::Term(Terminator::Br(body[0].name.clone())),
+                                              metadata: OrderedMap::new() });
+    let mut cont = BasicBlock { name: exit.clone(), instrs: tail };
+    if let (Some(ref name),Some(tp)) = (ret.as_ref(),ret_type) {
+        if !returns.is_empty() {
+            cont.instrs.insert(0,Instruction { content: InstructionC::Phi(name.to_string(),tp,returns.clone()),
+                                               metadata: OrderedMap::new() });
+        }
+    }
+    // Successors of the call block are now reached from the exit block
+    let succs: Vec<String> = cont.terminator().map_or(Vec::new(),|t| t.targets().into_iter().map(|t| t.to_string()).collect());
+    let at = pos.block+1;
+    blks.splice(at..at,body.into_iter().chain(Some(cont)));
+    for blk in blks.iter_mut().filter(|b| succs.contains(&b.name)) {
+        for instr in blk.instrs.iter_mut() {
+            if let InstructionC::Phi(_,_,ref mut inc) = instr.content {
+                for i in inc.iter_mut().filter(|i| i.1==from) {
+                    i.1 = exit.clone();
+                }
+            }
+        }
+    }
+    for (i,alloca) in allocas.into_iter().enumerate() {
+        blks[0].instrs.insert(i,alloca);
+    }
+    // Without a return, the result of the call is never available
+    if let (Some(name),true) = (ret,returns.is_empty()) {
+        Replace(&name,&Value::Constant(Constant::Undef)).visit_function_mut(fun);
+    }
+    Ok(())
+}
+
+/// Inlines calls to functions whose cost is at most the threshold.
+/// Functions are processed bottom-up in the call graph, so callees are
+/// flattened before they are inlined. Calls within recursive cycles,
+/// to variadic functions and to functions marked `noinline` are kept,
+/// calls to functions marked `alwaysinline` are always inlined.
+pub struct Inliner {
+    pub threshold: usize,
+    /// The threshold for functions marked `inlinehint`.
+    pub hint_threshold: usize
+}
+
+impl Inliner {
+    pub fn new(threshold: usize) -> Inliner {
+        Inliner { threshold, hint_threshold: threshold*3 }
+    }
+    fn should_inline(&self,m: &Module,cg: &CallGraph,callee: &str) -> bool {
+        let fun = match m.functions.get(callee) {
+            Some(fun) if fun.is_defined() && !fun.var_args => fun,
+            _ => return false
+        };
+        if cg.node(callee).is_some_and(|n| cg.is_recursive(n)) || has_attribute(m,fun,"noinline") {
+            return false
+        }
+        let threshold = if has_attribute(m,fun,"inlinehint") { self.hint_threshold } else { self.threshold };
+        has_attribute(m,fun,"alwaysinline") || inline_cost(fun)<=threshold
+    }
+    /// Inline all calls in a function that pass the cost model, returns
+    /// the number of inlined calls.
+    pub fn run_on_function(&self,m: &mut Module,cg: &CallGraph,caller: &str) -> usize {
+        let mut count = 0;
+        loop {
+            let site = {
+                let blks = match m.functions.get(caller).and_then(|f| f.body.as_ref()) {
+                    Some(blks) => blks,
+                    None => return count
+                };
+                blks.iter().enumerate().find_map(|(b,blk)| {
+                    blk.instrs.iter().position(|i| {
+                        direct_callee(&i.content).is_some_and(|f| f!=caller && self.should_inline(m,cg,f))
+                    }).map(|i| Position { block: b, instr: i })
+                })
+            };
+            match site {
+                Some(pos) => inline_call(m,caller,pos).expect("inlining a checked call"),
+                None => return count
+            }
+            count += 1;
+        }
+    }
+}
+
+impl Default for Inliner {
+    fn default() -> Inliner {
+        Inliner::new(25)
+    }
+}
+
+impl Pass for Inliner {
+    fn name(&self) -> &str {
+        "inline"
+    }
+    fn run(&mut self,m: &mut Module,am: &mut AnalysisManager) -> bool {
+        let cg = CallGraph::new(m);
+        let mut changed = false;
+        for node in cg.bottom_up() {
+            let name = match cg.name(node) {
+                Some(name) => name,
+                None => continue
+            };
+            if self.run_on_function(m,&cg,name)>0 {
+                am.invalidate(name,Preserved::Nothing);
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+fn block_names(fun: &Function) -> Vec<&str> {
+    fun.body.as_ref().unwrap().iter().map(|b| &b.name[..]).collect()
+}
+
+#[test]
+fn test_inline_call() {
+    let src = b"define i32 @abs(i32 %x) {
+entry:
+  %neg = icmp slt i32 %x, 0
+  br i1 %neg, label %then, label %done
+
+then:
+  %y = sub i32 0, %x
+  ret i32 %y
+
+done:
+  ret i32 %x
+}
+
+define void @set(i32* %p, i32 %v) {
+entry:
+  %tmp = alloca i32, align 4
+  store i32 %v, i32* %tmp, align 4
+  %y = load i32* %tmp, align 4
+  store i32 %y, i32* %p, align 4
+  ret void
+}
+
+define i32 @f(i32 %a, i1 %c) {
+entry:
+  %slot = alloca i32, align 4
+  br i1 %c, label %then, label %join
+
+then:
+  %y = call i32 @abs(i32 %a)
+  call void @set(i32* %slot, i32 %y)
+  br label %join
+
+join:
+  %r = phi i32 [ 0, %entry ], [ %y, %then ]
+  ret i32 %r
+}";
+    let orig = parse_test_module(src);
+    let mut m = orig.clone();
+    assert_eq!(inline_call(&mut m,"f",Position { block: 1, instr: 0 }),Ok(()));
+    {
+        let fun = &m.functions["f"];
+        assert_eq!(block_names(fun),vec!["entry","then","entry.0","then.0","done","abs.exit","join"]);
+        let blks = fun.body.as_ref().unwrap();
+        assert_eq!(blks[1].instrs[0].content,InstructionC::Term(Terminator::Br("entry.0".to_string())));
+        assert_eq!(blks[2].instrs[0].content,
+                   InstructionC::ICmp("neg".to_string(),CmpOp::SLt,Type::Int(32),
+                                      Value::Argument(0),Value::Constant(Constant::Int(0.into()))));
+        assert_eq!(blks[5].instrs[0].content,
+                   InstructionC::Phi("y".to_string(),Type::Int(32),
+                                     vec![(Value::Local("y.0".to_string()),"then.0".to_string()),
+                                          (Value::Argument(0),"done".to_string())]));
+        match blks[6].instrs[0].content {
+            InstructionC::Phi(_,_,ref inc) => assert_eq!(inc[1].1,"abs.exit"),
+            ref i => panic!("unexpected instruction {:?}",i)
+        }
+    }
+    let pos = Position { block: 5, instr: 1 };
+    assert_eq!(inline_call(&mut m,"f",pos),Ok(()));
+    let fun = &m.functions["f"];
+    let blks = fun.body.as_ref().unwrap();
+    // The alloca of @set moved to the entry block
+    assert!(matches!(blks[0].instrs[0].content,InstructionC::Alloca(ref n,..) if n=="tmp"));
+    assert_eq!(block_names(fun),vec!["entry","then","entry.0","then.0","done","abs.exit","entry.1","set.exit","join"]);
+    assert_eq!(blks[6].instrs[0].content,
+               InstructionC::Store(false,Typed::new(Type::Int(32),Value::Local("y".to_string())),
+                                   Typed::new(Type::ptr(Type::Int(32)),Value::Local("tmp".to_string())),Some(4)));
+
+    let mut before = interp::Interpreter::new(&orig).unwrap();
+    let mut after = interp::Interpreter::new(&m).unwrap();
+    for &(a,c) in [(5,true),(-7,true),(3,false)].iter() {
+        let args = vec![interp::Val::from_i64(32,a),interp::Val::bool(c)];
+        assert_eq!(before.run_function("f",args.clone()),after.run_function("f",args));
+    }
+
+    assert_eq!(inline_call(&mut m,"f",Position { block: 0, instr: 0 }),
+               Err(InlineError::NotADirectCall(Position { block: 0, instr: 0 })));
+}
+
+#[test]
+fn test_inliner() {
+    let mut m = parse_test_module(b"declare void @ext()
+
+define i32 @fact(i32 %n) {
+entry:
+  %c = icmp eq i32 %n, 0
+  br i1 %c, label %base, label %rec
+
+base:
+  ret i32 1
+
+rec:
+  %m = sub i32 %n, 1
+  %r = call i32 @fact(i32 %m)
+  %p = mul i32 %n, %r
+  ret i32 %p
+}
+
+define i32 @inc(i32 %x) {
+entry:
+  %y = add i32 %x, 1
+  ret i32 %y
+}
+
+define i32 @twice(i32 %x) {
+entry:
+  %a = call i32 @inc(i32 %x)
+  %b = call i32 @inc(i32 %a)
+  ret i32 %b
+}
+
+define void @stop() #0 {
+entry:
+  call void @ext()
+  unreachable
+}
+
+define i32 @main() {
+entry:
+  %a = call i32 @twice(i32 1)
+  %b = call i32 @fact(i32 %a)
+  call void @stop()
+  ret i32 %b
+}
+
+attributes #0 = { noinline nounwind }
+");
+    assert_eq!(inline_call(&mut m.clone(),"fact",Position { block: 2, instr: 1 }),Err(InlineError::Recursive("fact".to_string())));
+    assert_eq!(inline_call(&mut m.clone(),"main",Position { block: 0, instr: 2 }),Ok(()));
+    let mut pm = passes::PassManager::new();
+    pm.add_pass(Inliner::default());
+    assert!(pm.run(&mut m));
+    let calls = |m: &Module,name: &str| -> Vec<String> {
+        m.functions[name].body.as_ref().unwrap().iter()
+            .flat_map(|b| b.instrs.iter())
+            .filter_map(|i| direct_callee(&i.content).map(|f| f.to_string()))
+            .collect()
+    };
+    assert_eq!(calls(&m,"twice"),Vec::<String>::new());
+    assert_eq!(calls(&m,"main"),vec!["fact".to_string(),"stop".to_string()]);
+    assert_eq!(calls(&m,"fact"),vec!["fact".to_string()]);
+    let mut interp = interp::Interpreter::new(&m).unwrap();
+    assert_eq!(interp.run_function("twice",vec![interp::Val::from_i64(32,4)]),
+               Ok(interp::Outcome::Returned(Some(interp::Val::from_i64(32,6)))));
+}
+
+#[test]
+fn test_inline_minisat() {
+    let mut m = minisat();
+    let mut pm = passes::PassManager::new();
+    pm.add_pass(Inliner::default());
+    assert!(pm.run(&mut m));
+    for fun in m.functions.values() {
+        let idx = ssa::SsaIndex::new(fun);
+        let cfg = match cfg::ControlFlowGraph::from_function(fun) {
+            Some(cfg) => cfg,
+            None => continue
+        };
+        let mut seen = HashSet::new();
+        for (b,blk) in fun.body.as_ref().unwrap().iter().enumerate() {
+            assert!(seen.insert(&blk.name[..]),"{}: duplicate block %{}",fun.name,blk.name);
+            for instr in blk.instrs.iter() {
+                if let Some(name) = instr.content.name() {
+                    assert!(seen.insert(name),"{}: duplicate name %{}",fun.name,name);
+                }
+                if let Some(f) = direct_callee(&instr.content) {
+                    assert!(!f.starts_with("veci_") && !f.starts_with("vecp_"),"{} still calls @{}",fun.name,f);
+                }
+                if let InstructionC::Phi(_,_,ref inc) = instr.content {
+                    for i in inc.iter() {
+                        let p = cfg.block_index(&i.1).expect("phi from unknown block");
+                        assert!(cfg.predecessors(b).contains(&p),"{}: %{} is no predecessor of %{}",fun.name,i.1,blk.name);
+                    }
+                }
+                for op in instr.content.operands() {
+                    if let Value::Local(ref l) = *op {
+                        assert!(idx.definition(op).is_some(),"{}: %{} is not defined",fun.name,l);
+                    }
+                }
+            }
+        }
+    }
+    let mut interp = interp::Interpreter::new(&m).unwrap();
+    match interp.run_main(&["minisat","sample.cnf"]) {
+        Err(interp::ExecError::AssertionFailed(ref msg)) => assert!(msg.starts_with("solver.c:721:"),"{}",msg),
+        res => panic!("unexpected result {:?}",res)
+    }
+}
@@ -0,0 +1,1628 @@
+//! A reference interpreter for modules.
+//!
+//! Memory is a set of byte-addressed allocations laid out according to
+//! the module's `DataLayout`. Globals are allocated and initialised when
+//! the interpreter is created, allocas live until their function
+//! returns. Every pointer remembers the allocation it was derived from.
+//!
+//! Calls to functions without a body go to host functions, which get
+//! access to the memory and the standard streams of the `Machine`. A
+//! set of libc functions (`printf`, `malloc`, `exit`, ...) is installed
+//! by default and can be replaced with `add_host_function`.
+use num_bigint::{BigInt,BigUint,Sign};
+use num_traits::{ToPrimitive,Zero};
+use std::cmp::{max,min};
+use std::collections::{BTreeMap,HashMap};
+use std::fmt;
+use super::*;
+use builder::resolve_type;
+use datalayout::LayoutError;
+use ub::{self,UbKind,UbReport};
+
+pub type AllocId = usize;
+
+/// A pointer value: an address and the allocation it was derived
+/// from. Pointers created from integers have no allocation.
+#[derive(Debug,PartialEq,Eq,Hash,Clone,Copy)]
+pub struct Pointer {
+    pub addr: u64,
+    pub alloc: Option<AllocId>
+}
+
+impl Pointer {
+    pub fn null() -> Pointer {
+        Pointer { addr: 0, alloc: None }
+    }
+    pub fn is_null(&self) -> bool {
+        self.addr==0
+    }
+    /// The pointer moved by `off` bytes, keeping its allocation.
+    pub fn offset(&self,off: i64) -> Pointer {
+        Pointer { addr: self.addr.wrapping_add(off as u64), alloc: self.alloc }
+    }
+}
+
+/// A first-class value computed by the interpreter.
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+pub enum Val {
+    /// An integer of the given bit width. The value is always in the
+    /// range `0..2^width`.
+    Int(u64,BigInt),
+    Ptr(Pointer)
+}
+
+impl Val {
+    /// An integer of the given width, `v` is truncated as needed.
+    pub fn int(width: u64,v: BigInt) -> Val {
+        Val::Int(width,wrap(width,v))
+    }
+    pub fn from_i64(width: u64,v: i64) -> Val {
+        Val::int(width,BigInt::from(v))
+    }
+    pub fn bool(b: bool) -> Val {
+        Val::Int(1,BigInt::from(b as u8))
+    }
+    /// The value of an integer interpreted as unsigned.
+    pub fn as_unsigned(&self) -> Option<&BigInt> {
+        match *self {
+            Val::Int(_,ref v) => Some(v),
+            Val::Ptr(_) => None
+        }
+    }
+    /// The value of an integer interpreted as two's complement.
+    pub fn as_signed(&self) -> Option<BigInt> {
+        match *self {
+            Val::Int(w,ref v) => Some(to_signed(w,v)),
+            Val::Ptr(_) => None
+        }
+    }
+    pub fn to_i64(&self) -> Option<i64> {
+        self.as_signed().and_then(|v| v.to_i64())
+    }
+    pub fn to_u64(&self) -> Option<u64> {
+        match *self {
+            Val::Int(_,ref v) => v.to_u64(),
+            Val::Ptr(ref p) => Some(p.addr)
+        }
+    }
+    /// Interpret the value as a pointer. Integers become pointers
+    /// without an allocation.
+    pub fn as_pointer(&self) -> Option<Pointer> {
+        match *self {
+            Val::Ptr(p) => Some(p),
+            Val::Int(_,ref v) => v.to_u64().map(|addr| Pointer { addr, alloc: None })
+        }
+    }
+    pub fn is_true(&self) -> bool {
+        match *self {
+            Val::Int(_,ref v) => !v.is_zero(),
+            Val::Ptr(ref p) => !p.is_null()
+        }
+    }
+}
+
+impl fmt::Display for Val {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Val::Int(w,ref v) => write!(f,"i{} {}",w,to_signed(w,v)),
+            Val::Ptr(ref p) => write!(f,"ptr {:#x}",p.addr)
+        }
+    }
+}
+
+fn modulus(width: u64) -> BigInt {
+    BigInt::from(1) << (width as usize)
+}
+
+/// Reduce an integer modulo `2^width`.
+fn wrap(width: u64,v: BigInt) -> BigInt {
+    let m = modulus(width);
+    let r = v % &m;
+    if r.sign()==Sign::Minus {
+        r + m
+    } else {
+        r
+    }
+}
+
+/// Interpret an integer in the range `0..2^width` as signed.
+pub fn to_signed(width: u64,v: &BigInt) -> BigInt {
+    if width > 0 && *v >= modulus(width-1) {
+        v - modulus(width)
+    } else {
+        v.clone()
+    }
+}
+
+fn to_biguint(v: &BigInt) -> BigUint {
+    v.to_biguint().expect("integer values are non-negative")
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum ExecError {
+    UnknownFunction(String),
+    /// A function without a body was called and no host function is
+    /// installed for it.
+    NoHostFunction(String),
+    UnknownGlobal(String),
+    UnknownValue(String),
+    UnknownBlock(String),
+    /// A load, store or host function accessed memory outside of a
+    /// live allocation.
+    InvalidAccess { addr: u64, size: u64 },
+    /// A pointer that does not point to the start of a heap allocation
+    /// was freed.
+    InvalidFree(u64),
+    /// A pointer that does not point to a function was called.
+    InvalidCall(u64),
+    DivisionByZero,
+    Unreachable,
+    /// The program called `__assert_fail`.
+    AssertionFailed(String),
+    /// The program called `abort`.
+    Aborted,
+    /// The program called `exit`. `run_function` turns this into
+    /// `Outcome::Exited`.
+    Exit(i32),
+    StackOverflow,
+    /// An allocation was larger than `MAX_ALLOCATION` bytes.
+    OutOfMemory,
+    StepLimitReached,
+    /// A type has no memory layout.
+    Layout(LayoutError),
+    Unsupported(String),
+    /// An error reported by a host function.
+    Host(String),
+    /// Undefined behaviour detected by a checked interpreter.
+    UndefinedBehaviour(Box<UbReport>),
+    /// An invalid memory access in a checked interpreter, before it is
+    /// attributed to an instruction. Host functions fail with this,
+    /// the call becomes an `UndefinedBehaviour`.
+    AccessViolation(UbKind)
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecError::UnknownFunction(ref n) => write!(f,"unknown function @{}",n),
+            ExecError::NoHostFunction(ref n) => write!(f,"no host function for @{}",n),
+            ExecError::UnknownGlobal(ref n) => write!(f,"unknown global @{}",n),
+            ExecError::UnknownValue(ref n) => write!(f,"unknown value %{}",n),
+            ExecError::UnknownBlock(ref n) => write!(f,"unknown block %{}",n),
+            ExecError::InvalidAccess { addr, size } =>
+                write!(f,"invalid access of {} bytes at {:#x}",size,addr),
+            ExecError::InvalidFree(addr) => write!(f,"invalid free of {:#x}",addr),
+            ExecError::InvalidCall(addr) => write!(f,"call of non-function {:#x}",addr),
+            ExecError::DivisionByZero => write!(f,"division by zero"),
+            ExecError::Unreachable => write!(f,"unreachable executed"),
+            ExecError::AssertionFailed(ref msg) => write!(f,"{}",msg),
+            ExecError::Aborted => write!(f,"program aborted"),
+            ExecError::Exit(code) => write!(f,"program exited with {}",code),
+            ExecError::StackOverflow => write!(f,"stack overflow"),
+            ExecError::OutOfMemory => write!(f,"out of memory"),
+            ExecError::StepLimitReached => write!(f,"step limit reached"),
+            ExecError::Layout(ref e) => write!(f,"{}",e),
+            ExecError::Unsupported(ref what) => write!(f,"unsupported: {}",what),
+            ExecError::Host(ref msg) => write!(f,"{}",msg),
+            ExecError::UndefinedBehaviour(ref r) => write!(f,"undefined behaviour: {}",r),
+            ExecError::AccessViolation(ref k) => write!(f,"undefined behaviour: {}",k)
+        }
+    }
+}
+
+impl ::std::error::Error for ExecError {}
+
+impl From<LayoutError> for ExecError {
+    fn from(e: LayoutError) -> ExecError {
+        ExecError::Layout(e)
+    }
+}
+
+pub type ExecResult<T> = Result<T,ExecError>;
+
+/// How a program run ended.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum Outcome {
+    Returned(Option<Val>),
+    Exited(i32)
+}
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone,Copy)]
+pub enum Stream {
+    Stdin,Stdout,Stderr
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum AllocKind {
+    Global(String),
+    Function(String),
+    Stack,
+    Heap,
+    /// The `FILE` object behind `stdin`, `stdout` or `stderr`.
+    Stream(Stream)
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Allocation {
+    pub base: u64,
+    pub size: u64,
+    pub kind: AllocKind,
+    pub live: bool,
+    pub read_only: bool,
+    bytes: Vec<u8>,
+    init: Vec<bool>,
+    /// The allocations of pointers stored in this one, by offset.
+    provenance: BTreeMap<u64,AllocId>
+}
+
+impl Allocation {
+    pub fn contains(&self,addr: u64,size: u64) -> bool {
+        addr >= self.base && addr-self.base <= self.size && size <= self.size-(addr-self.base)
+    }
+}
+
+/// The size of the largest allocation in bytes. `malloc` and `calloc`
+/// return null for larger requests, other allocations fail with
+/// `ExecError::OutOfMemory`.
+pub const MAX_ALLOCATION: u64 = 1 << 30;
+
+/// Gap left between two allocations, so that running over the end of
+/// one does not hit the next.
+const ALLOC_GAP: u64 = 16;
+
+/// A byte-addressed memory. Addresses are never reused, so dangling
+/// pointers can always be told apart from new allocations.
+#[derive(Debug,Clone)]
+pub struct Memory {
+    allocations: Vec<Allocation>,
+    by_base: BTreeMap<u64,AllocId>,
+    next_addr: u64,
+    endian: Endian,
+    pointer_size: u64,
+    // Whether accesses are checked against the allocation the pointer
+    // was derived from, see `ub::access_violation`
+    checked: bool
+}
+
+impl Memory {
+    pub fn new(dl: &DataLayout) -> Memory {
+        let (ptr_bits,_,_) = dl.pointer_alignment(0);
+        Memory { allocations: Vec::new(),
+                 by_base: BTreeMap::new(),
+                 next_addr: 0x1000,
+                 endian: dl.endianess(),
+                 pointer_size: ptr_bits/8,
+                 checked: false }
+    }
+    pub fn pointer_size(&self) -> u64 {
+        self.pointer_size
+    }
+    /// Create a new, uninitialised allocation.
+    pub fn allocate(&mut self,size: u64,align: u64,kind: AllocKind) -> ExecResult<Pointer> {
+        if size > MAX_ALLOCATION {
+            return Err(ExecError::OutOfMemory)
+        }
+        let base = align_to(self.next_addr,max(align,1));
+        let id = self.allocations.len();
+        self.allocations.push(Allocation { base,
+                                           size,
+                                           kind,
+                                           live: true,
+                                           read_only: false,
+                                           bytes: vec![0; size as usize],
+                                           init: vec![false; size as usize],
+                                           provenance: BTreeMap::new() });
+        self.by_base.insert(base,id);
+        self.next_addr = base+max(size,1)+ALLOC_GAP;
+        Ok(Pointer { addr: base, alloc: Some(id) })
+    }
+    /// Release an allocation, `ptr` must point to its start.
+    pub fn deallocate(&mut self,ptr: &Pointer) -> ExecResult<AllocKind> {
+        match self.by_base.get(&ptr.addr) {
+            Some(&id) if self.allocations[id].live => {
+                let alloc = &mut self.allocations[id];
+                alloc.live = false;
+                alloc.bytes = Vec::new();
+                alloc.init = Vec::new();
+                alloc.provenance.clear();
+                Ok(alloc.kind.clone())
+            },
+            _ => Err(ExecError::InvalidFree(ptr.addr))
+        }
+    }
+    pub fn allocation(&self,id: AllocId) -> &Allocation {
+        &self.allocations[id]
+    }
+    pub fn allocation_mut(&mut self,id: AllocId) -> &mut Allocation {
+        &mut self.allocations[id]
+    }
+    /// The allocation an address points into (or one past the end of),
+    /// live or not.
+    pub fn allocation_at(&self,addr: u64) -> Option<AllocId> {
+        let (_,&id) = self.by_base.range(..=addr).next_back()?;
+        if self.allocations[id].contains(addr,0) {
+            Some(id)
+        } else {
+            None
+        }
+    }
+    /// Find the live allocation and the offset of an access of `size`
+    /// bytes.
+    fn resolve(&self,ptr: &Pointer,size: u64) -> ExecResult<(AllocId,usize)> {
+        if self.checked {
+            if let Some(kind) = ub::access_violation(self,ptr,size) {
+                return Err(ExecError::AccessViolation(kind))
+            }
+        }
+        let err = ExecError::InvalidAccess { addr: ptr.addr, size };
+        let id = self.allocation_at(ptr.addr).ok_or_else(|| err.clone())?;
+        let alloc = &self.allocations[id];
+        match alloc.kind {
+            AllocKind::Function(_) | AllocKind::Stream(_) => return Err(err),
+            _ => {}
+        }
+        if !alloc.live || !alloc.contains(ptr.addr,size) {
+            return Err(err)
+        }
+        Ok((id,(ptr.addr-alloc.base) as usize))
+    }
+    pub fn read_bytes(&self,ptr: &Pointer,size: u64) -> ExecResult<&[u8]> {
+        let (id,off) = self.resolve(ptr,size)?;
+        Ok(&self.allocations[id].bytes[off..off+size as usize])
+    }
+    pub fn write_bytes(&mut self,ptr: &Pointer,bytes: &[u8]) -> ExecResult<()> {
+        let size = bytes.len() as u64;
+        let (id,off) = self.resolve(ptr,size)?;
+        let ps = self.pointer_size;
+        let alloc = &mut self.allocations[id];
+        if alloc.read_only {
+            return Err(ExecError::InvalidAccess { addr: ptr.addr, size })
+        }
+        alloc.bytes[off..off+bytes.len()].copy_from_slice(bytes);
+        for i in alloc.init[off..off+bytes.len()].iter_mut() {
+            *i = true;
+        }
+        // Stored pointers lose their provenance when overwritten
+        let lo = (off as u64).saturating_sub(ps-1);
+        let hi = off as u64+size;
+        let stale: Vec<u64> = alloc.provenance.range(lo..hi).map(|(o,_)| *o).collect();
+        for o in stale {
+            alloc.provenance.remove(&o);
+        }
+        Ok(())
+    }
+    /// Set `size` bytes to `byte`.
+    pub fn fill(&mut self,ptr: &Pointer,byte: u8,size: u64) -> ExecResult<()> {
+        self.resolve(ptr,size)?;
+        self.write_bytes(ptr,&vec![byte; size as usize])
+    }
+    /// Whether all bytes of an access have been written before.
+    pub fn is_initialized(&self,ptr: &Pointer,size: u64) -> ExecResult<bool> {
+        let (id,off) = self.resolve(ptr,size)?;
+        Ok(self.allocations[id].init[off..off+size as usize].iter().all(|&i| i))
+    }
+    /// Read an unsigned integer of `size` bytes.
+    pub fn read_int(&self,ptr: &Pointer,size: u64) -> ExecResult<BigInt> {
+        let bytes = self.read_bytes(ptr,size)?;
+        Ok(match self.endian {
+            Endian::Little => BigInt::from_bytes_le(Sign::Plus,bytes),
+            Endian::Big => BigInt::from_bytes_be(Sign::Plus,bytes)
+        })
+    }
+    /// Write the lowest `size` bytes of a non-negative integer.
+    pub fn write_int(&mut self,ptr: &Pointer,size: u64,v: &BigInt) -> ExecResult<()> {
+        let (_,mut bytes) = v.to_bytes_le();
+        bytes.resize(size as usize,0);
+        if self.endian==Endian::Big {
+            bytes.reverse();
+        }
+        self.write_bytes(ptr,&bytes)
+    }
+    pub fn read_pointer(&self,ptr: &Pointer) -> ExecResult<Pointer> {
+        let addr = self.read_int(ptr,self.pointer_size)?.to_u64().unwrap();
+        let (id,off) = self.resolve(ptr,self.pointer_size)?;
+        let alloc = self.allocations[id].provenance.get(&(off as u64)).cloned();
+        Ok(Pointer { addr, alloc })
+    }
+    pub fn write_pointer(&mut self,ptr: &Pointer,val: &Pointer) -> ExecResult<()> {
+        let ps = self.pointer_size;
+        self.write_int(ptr,ps,&BigInt::from(val.addr))?;
+        if let Some(a) = val.alloc {
+            let (id,off) = self.resolve(ptr,ps)?;
+            self.allocations[id].provenance.insert(off as u64,a);
+        }
+        Ok(())
+    }
+    /// Copy `size` bytes including initialisation state and stored
+    /// pointers. The ranges may overlap.
+    pub fn copy(&mut self,dst: &Pointer,src: &Pointer,size: u64) -> ExecResult<()> {
+        if size==0 {
+            return Ok(())
+        }
+        let (sid,soff) = self.resolve(src,size)?;
+        let bytes = self.allocations[sid].bytes[soff..soff+size as usize].to_vec();
+        let init = self.allocations[sid].init[soff..soff+size as usize].to_vec();
+        let prov: Vec<(u64,AllocId)> = self.allocations[sid].provenance
+            .range(soff as u64..soff as u64+size)
+            .map(|(o,a)| (*o-soff as u64,*a))
+            .collect();
+        self.write_bytes(dst,&bytes)?;
+        let (did,doff) = self.resolve(dst,size)?;
+        let alloc = &mut self.allocations[did];
+        alloc.init[doff..doff+size as usize].copy_from_slice(&init);
+        for (o,a) in prov {
+            alloc.provenance.insert(o+doff as u64,a);
+        }
+        Ok(())
+    }
+    /// Read a zero-terminated string, without the terminator.
+    pub fn read_c_string(&self,ptr: &Pointer) -> ExecResult<Vec<u8>> {
+        let mut res = Vec::new();
+        let mut cur = *ptr;
+        loop {
+            let b = self.read_bytes(&cur,1)?[0];
+            if b==0 {
+                return Ok(res)
+            }
+            res.push(b);
+            cur = cur.offset(1);
+        }
+    }
+}
+
+/// The state visible to host functions.
+pub struct Machine {
+    pub memory: Memory,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// The data the program can read from `stdin`.
+    pub stdin: Vec<u8>,
+    stdin_pos: usize,
+    streams: HashMap<u64,Stream>
+}
+
+impl Machine {
+    /// The stream a `FILE*` argument refers to.
+    pub fn stream(&self,file: &Val) -> Option<Stream> {
+        file.as_pointer().and_then(|p| self.streams.get(&p.addr).cloned())
+    }
+    pub fn write(&mut self,stream: Stream,bytes: &[u8]) {
+        match stream {
+            Stream::Stdout => self.stdout.extend_from_slice(bytes),
+            Stream::Stderr => self.stderr.extend_from_slice(bytes),
+            Stream::Stdin => {}
+        }
+    }
+    /// Consume up to `size` bytes of `stdin`.
+    pub fn read_stdin(&mut self,size: usize) -> Vec<u8> {
+        let end = min(self.stdin_pos.saturating_add(size),self.stdin.len());
+        let res = self.stdin[self.stdin_pos..end].to_vec();
+        self.stdin_pos = end;
+        res
+    }
+    pub fn stdin_eof(&self) -> bool {
+        self.stdin_pos >= self.stdin.len()
+    }
+    /// Allocate zero-filled heap memory.
+    pub fn malloc(&mut self,size: u64) -> ExecResult<Pointer> {
+        let ptr = self.memory.allocate(size,16,AllocKind::Heap)?;
+        self.memory.write_bytes(&ptr,&vec![0; size as usize])?;
+        if let Some(id) = ptr.alloc {
+            // malloc'ed memory is not initialised, only zero-filled
+            for i in self.memory.allocation_mut(id).init.iter_mut() {
+                *i = false;
+            }
+        }
+        Ok(ptr)
+    }
+    pub fn free(&mut self,ptr: &Pointer) -> ExecResult<()> {
+        if ptr.is_null() {
+            return Ok(())
+        }
+        match self.memory.allocation_at(ptr.addr).map(|id| &self.memory.allocation(id).kind) {
+            Some(&AllocKind::Heap) => self.memory.deallocate(ptr).map(|_| ()),
+            _ => Err(ExecError::InvalidFree(ptr.addr))
+        }
+    }
+    /// Render a `printf` format string with the given arguments.
+    pub fn format(&self,fmt: &Pointer,args: &[Val]) -> ExecResult<Vec<u8>> {
+        let fmt = self.memory.read_c_string(fmt)?;
+        let mut args = args.iter();
+        let mut next_arg = || args.next().ok_or_else(|| ExecError::Host("printf: missing argument".to_string()));
+        let mut res = Vec::new();
+        let mut i = 0;
+        while i < fmt.len() {
+            if fmt[i]!=b'%' {
+                res.push(fmt[i]);
+                i += 1;
+                continue
+            }
+            i += 1;
+            let mut left = false;
+            let mut zero = false;
+            let mut plus = false;
+            let mut space = false;
+            while i < fmt.len() {
+                match fmt[i] {
+                    b'-' => left = true,
+                    b'0' => zero = true,
+                    b'+' => plus = true,
+                    b' ' => space = true,
+                    b'#' => {},
+                    _ => break
+                }
+                i += 1;
+            }
+            let mut width = 0;
+            if i < fmt.len() && fmt[i]==b'*' {
+                width = next_arg()?.to_i64().unwrap_or(0) as usize;
+                i += 1;
+            }
+            while i < fmt.len() && fmt[i].is_ascii_digit() {
+                width = width*10+(fmt[i]-b'0') as usize;
+                i += 1;
+            }
+            let mut precision = None;
+            if i < fmt.len() && fmt[i]==b'.' {
+                i += 1;
+                let mut p = 0;
+                if i < fmt.len() && fmt[i]==b'*' {
+                    p = next_arg()?.to_i64().unwrap_or(0) as usize;
+                    i += 1;
+                }
+                while i < fmt.len() && fmt[i].is_ascii_digit() {
+                    p = p*10+(fmt[i]-b'0') as usize;
+                    i += 1;
+                }
+                precision = Some(p);
+            }
+            let mut length = None;
+            while i < fmt.len() && b"hlqjzt".contains(&fmt[i]) {
+                length = Some(match (length,fmt[i]) {
+                    (Some(8),b'h') => 8,
+                    (None,b'h') => 16,
+                    _ => 64
+                });
+                i += 1;
+            }
+            if i >= fmt.len() {
+                break
+            }
+            let conv = fmt[i];
+            i += 1;
+            let mut body: Vec<u8> = match conv {
+                b'%' => {
+                    res.push(b'%');
+                    continue
+                },
+                b'd' | b'i' | b'u' | b'x' | b'X' | b'o' => {
+                    let arg = next_arg()?;
+                    let (w,v) = match *arg {
+                        Val::Int(w,ref v) => match length {
+                            Some(l) if l < w => (l,wrap(l,v.clone())),
+                            _ => (w,v.clone())
+                        },
+                        Val::Ptr(ref p) => (64,BigInt::from(p.addr))
+                    };
+                    let (neg,digits) = match conv {
+                        b'd' | b'i' => {
+                            let s = to_signed(w,&v);
+                            (s.sign()==Sign::Minus,(if s.sign()==Sign::Minus { -s } else { s }).to_str_radix(10))
+                        },
+                        b'u' => (false,v.to_str_radix(10)),
+                        b'o' => (false,v.to_str_radix(8)),
+                        b'x' => (false,v.to_str_radix(16)),
+                        _ => (false,v.to_str_radix(16).to_uppercase())
+                    };
+                    let mut digits = digits.into_bytes();
+                    if let Some(p) = precision {
+                        while digits.len() < p {
+                            digits.insert(0,b'0');
+                        }
+                    }
+                    let sign: &[u8] = if neg { b"-" } else if plus { b"+" } else if space { b" " } else { b"" };
+                    if zero && !left && precision.is_none() {
+                        while digits.len()+sign.len() < width {
+                            digits.insert(0,b'0');
+                        }
+                    }
+                    let mut r = sign.to_vec();
+                    r.extend(digits);
+                    r
+                },
+                b'c' => vec![next_arg()?.to_u64().unwrap_or(0) as u8],
+                b's' => {
+                    let ptr = next_arg()?.as_pointer().unwrap_or_else(Pointer::null);
+                    let mut s = self.memory.read_c_string(&ptr)?;
+                    if let Some(p) = precision {
+                        s.truncate(p);
+                    }
+                    s
+                },
+                b'p' => format!("{:#x}",next_arg()?.to_u64().unwrap_or(0)).into_bytes(),
+                c => return Err(ExecError::Unsupported(format!("printf conversion %{}",c as char)))
+            };
+            if body.len() < width {
+                let pad = vec![b' '; width-body.len()];
+                if left {
+                    body.extend(pad);
+                } else {
+                    let mut r = pad;
+                    r.extend(body);
+                    body = r;
+                }
+            }
+            res.extend(body);
+        }
+        Ok(res)
+    }
+}
+
+/// A function implemented by the host. It receives the evaluated
+/// arguments of the call and returns the result, if any. Integer
+/// results are truncated or extended to the return type of the call.
+pub type HostFunction = Box<dyn FnMut(&mut Machine,&[Val]) -> ExecResult<Option<Val>>>;
+
+struct Frame<'m> {
+    function: &'m Function,
+    blocks: &'m [BasicBlock],
+    block: usize,
+    instr: usize,
+    locals: HashMap<&'m str,Val>,
+    args: Vec<Val>,
+    allocas: Vec<Pointer>
+}
+
+enum Step<'m> {
+    Continue,
+    Call(&'m Function,Vec<Val>),
+    Return(Option<Val>)
+}
+
+pub struct Interpreter<'m> {
+    module: &'m Module,
+    machine: Machine,
+    globals: HashMap<&'m str,Pointer>,
+    functions: HashMap<u64,&'m str>,
+    labels: HashMap<&'m str,HashMap<&'m str,usize>>,
+    hosts: HashMap<String,HostFunction>,
+    max_depth: usize,
+    step_limit: Option<u64>,
+    steps: u64,
+    checked: bool
+}
+
+impl<'m> Interpreter<'m> {
+    /// Create an interpreter for a module, allocating and initialising
+    /// all of its globals.
+    pub fn new(module: &'m Module) -> ExecResult<Interpreter<'m>> {
+        let mut interp = Interpreter { module,
+                                       machine: Machine { memory: Memory::new(&module.datalayout),
+                                                          stdout: Vec::new(),
+                                                          stderr: Vec::new(),
+                                                          stdin: Vec::new(),
+                                                          stdin_pos: 0,
+                                                          streams: HashMap::new() },
+                                       globals: HashMap::new(),
+                                       functions: HashMap::new(),
+                                       labels: HashMap::new(),
+                                       hosts: HashMap::new(),
+                                       max_depth: 10000,
+                                       step_limit: None,
+                                       steps: 0,
+                                       checked: false };
+        let dl = &module.datalayout;
+        let mut globals: Vec<(&String,&GlobalVariable)> = module.globals.iter().collect();
+        globals.sort_by_key(|&(n,_)| n);
+        for &(name,glob) in globals.iter() {
+            let size = dl.type_alloc_size(&glob.types,&module.types)?;
+            let align = match glob.alignment {
+                Some(a) => a,
+                None => dl.type_alignment(&glob.types,&module.types)?
+            };
+            let ptr = interp.machine.memory.allocate(size,align,AllocKind::Global(name.clone()))?;
+            interp.globals.insert(name,ptr);
+        }
+        let mut funs: Vec<&String> = module.functions.keys().collect();
+        funs.sort();
+        for name in funs {
+            let ptr = interp.machine.memory.allocate(1,1,AllocKind::Function(name.clone()))?;
+            interp.globals.insert(name,ptr);
+            interp.functions.insert(ptr.addr,name);
+            if let Some(ref blks) = module.functions[name].body {
+                interp.labels.insert(name,blks.iter()
+                                     .enumerate()
+                                     .map(|(i,b)| (&b.name[..],i))
+                                     .collect());
+            }
+        }
+        for (name,glob) in globals {
+            let ptr = interp.globals[&name[..]];
+            match glob.initialization {
+                Some(ref c) => interp.store_constant(&ptr,&glob.types,c)?,
+                None => {
+                    let size = dl.type_store_size(&glob.types,&module.types)?;
+                    interp.machine.memory.write_bytes(&ptr,&vec![0; size as usize])?;
+                    let stream = match &name[..] {
+                        "stdin" => Stream::Stdin,
+                        "stdout" => Stream::Stdout,
+                        "stderr" => Stream::Stderr,
+                        _ => continue
+                    };
+                    let file = interp.machine.memory.allocate(1,1,AllocKind::Stream(stream))?;
+                    interp.machine.streams.insert(file.addr,stream);
+                    interp.machine.memory.write_pointer(&ptr,&file)?;
+                }
+            }
+            if glob.global_type==GlobalType::Constant {
+                let id = ptr.alloc.unwrap();
+                interp.machine.memory.allocation_mut(id).read_only = true;
+            }
+        }
+        install_default_host_functions(&mut interp);
+        Ok(interp)
+    }
+    /// Create an interpreter that stops at the first undefined
+    /// behaviour, see the `ub` module.
+    pub fn checked(module: &'m Module) -> ExecResult<Interpreter<'m>> {
+        let mut interp = Interpreter::new(module)?;
+        interp.checked = true;
+        interp.machine.memory.checked = true;
+        Ok(interp)
+    }
+    /// Install (or replace) the implementation of a function without
+    /// a body.
+    pub fn add_host_function<F>(&mut self,name: &str,f: F)
+        where F: FnMut(&mut Machine,&[Val]) -> ExecResult<Option<Val>> + 'static {
+        self.hosts.insert(name.to_string(),Box::new(f));
+    }
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+    pub fn machine_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+    /// The address of a global variable or function.
+    pub fn global(&self,name: &str) -> Option<Pointer> {
+        self.globals.get(name).cloned()
+    }
+    /// The maximal number of nested calls, 10000 by default.
+    pub fn set_max_depth(&mut self,depth: usize) {
+        self.max_depth = depth;
+    }
+    /// Stop execution after the given number of instructions.
+    pub fn set_step_limit(&mut self,limit: Option<u64>) {
+        self.step_limit = limit;
+    }
+    /// The number of instructions executed so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+    /// Run a function to completion.
+    pub fn run_function(&mut self,name: &str,args: Vec<Val>) -> ExecResult<Outcome> {
+        let fun = self.module.functions.get(name)
+            .ok_or_else(|| ExecError::UnknownFunction(name.to_string()))?;
+        match self.execute(fun,args) {
+            Ok(res) => Ok(Outcome::Returned(res)),
+            Err(ExecError::Exit(code)) => Ok(Outcome::Exited(code)),
+            Err(err) => Err(err)
+        }
+    }
+    /// Run `main` with the given command line, whose first element is
+    /// the program name. A `main` returning normally counts as exiting
+    /// with its return value.
+    pub fn run_main(&mut self,args: &[&str]) -> ExecResult<Outcome> {
+        let fun = self.module.functions.get("main")
+            .ok_or_else(|| ExecError::UnknownFunction("main".to_string()))?;
+        let mut vals = Vec::new();
+        if !fun.arguments.is_empty() {
+            let ps = self.machine.memory.pointer_size();
+            let argv = self.machine.malloc(ps*(args.len() as u64+1))?;
+            for (i,arg) in args.iter().enumerate() {
+                let mut bytes = arg.as_bytes().to_vec();
+                bytes.push(0);
+                let s = self.machine.malloc(bytes.len() as u64)?;
+                self.machine.memory.write_bytes(&s,&bytes)?;
+                self.machine.memory.write_pointer(&argv.offset((i as u64*ps) as i64),&s)?;
+            }
+            self.machine.memory.write_pointer(&argv.offset((args.len() as u64*ps) as i64),&Pointer::null())?;
+            vals.push(Val::from_i64(32,args.len() as i64));
+            vals.push(Val::Ptr(argv));
+        }
+        match self.run_function("main",vals)? {
+            Outcome::Returned(Some(v)) => Ok(Outcome::Exited(v.to_i64().unwrap_or(0) as i32)),
+            res => Ok(res)
+        }
+    }
+
+    fn execute(&mut self,fun: &'m Function,args: Vec<Val>) -> ExecResult<Option<Val>> {
+        let mut stack = vec![self.enter(fun,args)?];
+        let res = self.run(&mut stack);
+        while let Some(frame) = stack.pop() {
+            self.leave(frame)?;
+        }
+        res
+    }
+    fn run(&mut self,stack: &mut Vec<Frame<'m>>) -> ExecResult<Option<Val>> {
+        loop {
+            let step = self.step(stack.last_mut().unwrap())?;
+            match step {
+                Step::Continue => {},
+                Step::Call(fun,args) => {
+                    if stack.len() >= self.max_depth {
+                        return Err(ExecError::StackOverflow)
+                    }
+                    let frame = self.enter(fun,args)?;
+                    stack.push(frame);
+                },
+                Step::Return(val) => {
+                    let frame = stack.pop().unwrap();
+                    let ret_tp = frame.function.return_type.as_ref().map(|(_,tp)| tp);
+                    self.leave(frame)?;
+                    let caller = match stack.last_mut() {
+                        None => return Ok(val),
+                        Some(c) => c
+                    };
+                    let instr = &caller.blocks[caller.block].instrs[caller.instr].content;
+                    if let (Some(name),Some(v),Some(tp)) = (instr.name(),val,ret_tp) {
+                        caller.locals.insert(name,self.coerce(v,tp));
+                    }
+                    caller.instr += 1;
+                }
+            }
+        }
+    }
+    fn enter(&self,fun: &'m Function,args: Vec<Val>) -> ExecResult<Frame<'m>> {
+        let blocks = match fun.body {
+            Some(ref blks) if !blks.is_empty() => &blks[..],
+            _ => return Err(ExecError::Unsupported(format!("@{} has no body",fun.name)))
+        };
+        Ok(Frame { function: fun,
+                   blocks,
+                   block: 0,
+                   instr: 0,
+                   locals: HashMap::new(),
+                   args,
+                   allocas: Vec::new() })
+    }
+    fn leave(&mut self,frame: Frame<'m>) -> ExecResult<()> {
+        for ptr in frame.allocas {
+            self.machine.memory.deallocate(&ptr)?;
+        }
+        Ok(())
+    }
+    /// Execute the current instruction of a frame.
+    fn step(&mut self,frame: &mut Frame<'m>) -> ExecResult<Step<'m>> {
+        self.steps += 1;
+        if let Some(limit) = self.step_limit {
+            if self.steps > limit {
+                return Err(ExecError::StepLimitReached)
+            }
+        }
+        let instr = &frame.blocks[frame.block].instrs[frame.instr].content;
+        let res = match *instr {
+            InstructionC::Alloca(_,ref tp,ref count,align) => {
+                let n = match *count {
+                    Some(ref c) => self.value(frame,&c.tp,&c.val)?.to_u64().unwrap_or(0),
+                    None => 1
+                };
+                let dl = &self.module.datalayout;
+                let size = n.checked_mul(dl.type_alloc_size(tp,&self.module.types)?)
+                    .ok_or(ExecError::OutOfMemory)?;
+                let align = match align {
+                    Some(a) => a,
+                    None => dl.type_alignment(tp,&self.module.types)?
+                };
+                let ptr = self.machine.memory.allocate(size,align,AllocKind::Stack)?;
+                frame.allocas.push(ptr);
+                Val::Ptr(ptr)
+            },
+            InstructionC::Call(_,_,ref tp,ref callee,ref args,_) => {
+                let fun = self.callee(frame,callee)?;
+                if fun.name.starts_with("llvm.dbg.") {
+                    frame.instr += 1;
+                    return Ok(Step::Continue)
+                }
+                let mut vals = Vec::with_capacity(args.len());
+                for arg in args.iter() {
+                    vals.push(self.value(frame,&arg.tp,&arg.val)?);
+                }
+                if fun.body.is_some() {
+                    return Ok(Step::Call(fun,vals))
+                }
+                let res = match self.call_host(&fun.name,&vals) {
+                    Err(ExecError::AccessViolation(kind)) => return Err(self.undefined(frame,kind)),
+                    res => res?
+                };
+                let ret_tp = tp.as_ref().and_then(|(tp,_)| builder::call_return_type(tp));
+                match (instr.name(),res,ret_tp) {
+                    (Some(_),Some(v),Some(tp)) => self.coerce(v,&tp),
+                    _ => {
+                        frame.instr += 1;
+                        return Ok(Step::Continue)
+                    }
+                }
+            },
+            InstructionC::ICmp(_,ref op,ref tp,ref lhs,ref rhs) => {
+                let l = self.value(frame,tp,lhs)?;
+                let r = self.value(frame,tp,rhs)?;
+                Val::bool(compare(op,&l,&r))
+            },
+            InstructionC::Unary(_,ref arg,UnaryInst::Load(..)) => {
+                let ptr = self.value(frame,&arg.tp,&arg.val)?;
+                let tp = match *resolve_type(&self.module.types,&arg.tp) {
+                    Type::Pointer(ref el,_) => (**el).clone(),
+                    ref t => return Err(ExecError::Unsupported(format!("load from {:?}",t)))
+                };
+                let ptr = ptr.as_pointer().unwrap();
+                if self.checked {
+                    let size = self.module.datalayout.type_store_size(&tp,&self.module.types)?;
+                    let mem = &self.machine.memory;
+                    if let Some(kind) = ub::access_violation(mem,&ptr,size)
+                        .or_else(|| ub::uninitialized_read(mem,&ptr,size)) {
+                        return Err(self.undefined(frame,kind))
+                    }
+                }
+                self.load(&ptr,&tp)?
+            },
+            InstructionC::Unary(_,ref arg,UnaryInst::Cast(ref tp,op)) => {
+                let v = self.value(frame,&arg.tp,&arg.val)?;
+                self.cast(op,v,tp)?
+            },
+            InstructionC::GEP(_,ref g) => {
+                let base = self.value(frame,&g.ptr.tp,&g.ptr.val)?;
+                let mut idx = Vec::with_capacity(g.indices.len());
+                for (i,_) in g.indices.iter() {
+                    idx.push(self.value(frame,&i.tp,&i.val)?);
+                }
+                let res = self.gep(&g.ptr.tp,base.clone(),&idx)?;
+                if self.checked && g.inbounds {
+                    if let Some(kind) = ub::gep_violation(&self.machine.memory,
+                                                          &base.as_pointer().unwrap(),
+                                                          &res.as_pointer().unwrap()) {
+                        return Err(self.undefined(frame,kind))
+                    }
+                }
+                res
+            },
+            InstructionC::Store(_,ref val,ref ptr,_) => {
+                let v = self.value(frame,&val.tp,&val.val)?;
+                let p = self.value(frame,&ptr.tp,&ptr.val)?.as_pointer().unwrap();
+                if self.checked {
+                    let size = self.module.datalayout.type_store_size(&val.tp,&self.module.types)?;
+                    if let Some(kind) = ub::access_violation(&self.machine.memory,&p,size) {
+                        return Err(self.undefined(frame,kind))
+                    }
+                }
+                self.store(&p,&val.tp,&v)?;
+                frame.instr += 1;
+                return Ok(Step::Continue)
+            },
+            InstructionC::Select(_,ref c,ref tp,ref v1,ref v2) => {
+                if self.value(frame,&Type::Int(1),c)?.is_true() {
+                    self.value(frame,tp,v1)?
+                } else {
+                    self.value(frame,tp,v2)?
+                }
+            },
+            InstructionC::Phi(..) => return Err(ExecError::Unsupported("phi after the start of a block".to_string())),
+            InstructionC::Bin(_,ref op,ref tp,ref lhs,ref rhs) => {
+                let l = self.value(frame,tp,lhs)?;
+                let r = self.value(frame,tp,rhs)?;
+                if self.checked {
+                    if let Some(kind) = ub::arithmetic_violation(op,&l,&r) {
+                        return Err(self.undefined(frame,kind))
+                    }
+                }
+                binary(op,&l,&r)?
+            },
+            InstructionC::Term(ref t) => return self.terminator(frame,t)
+        };
+        if let Some(name) = instr.name() {
+            frame.locals.insert(name,res);
+        }
+        frame.instr += 1;
+        Ok(Step::Continue)
+    }
+    fn terminator(&mut self,frame: &mut Frame<'m>,term: &'m Terminator) -> ExecResult<Step<'m>> {
+        match *term {
+            Terminator::Br(ref trg) => self.jump(frame,trg)?,
+            Terminator::BrC(ref c,ref t,ref f) => {
+                if self.value(frame,&Type::Int(1),c)?.is_true() {
+                    self.jump(frame,t)?
+                } else {
+                    self.jump(frame,f)?
+                }
+            },
+            Terminator::Ret(ref val) => return match *val {
+                None => Ok(Step::Return(None)),
+                Some(ref v) => Ok(Step::Return(Some(self.value(frame,&v.tp,&v.val)?)))
+            },
+            Terminator::Switch(ref tp,ref v,ref def,ref cases) => {
+                let v = self.value(frame,tp,v)?;
+                let mut trg = def;
+                for (c,lbl) in cases.iter() {
+                    if self.constant(tp,c)?==v {
+                        trg = lbl;
+                        break
+                    }
+                }
+                self.jump(frame,trg)?
+            },
+            Terminator::Unreachable => return Err(if self.checked {
+                self.undefined(frame,UbKind::Unreachable)
+            } else {
+                ExecError::Unreachable
+            })
+        }
+        Ok(Step::Continue)
+    }
+    /// Report undefined behaviour at the current instruction.
+    fn undefined(&self,frame: &Frame<'m>,kind: UbKind) -> ExecError {
+        let blk = &frame.blocks[frame.block];
+        let instr = &blk.instrs[frame.instr];
+        ExecError::UndefinedBehaviour(Box::new(UbReport { kind,
+                                                          function: frame.function.name.clone(),
+                                                          block: blk.name.clone(),
+                                                          index: frame.instr,
+                                                          instruction: instr.content.clone(),
+                                                          location: ub::debug_location(self.module,instr) }))
+    }
+    /// Continue execution at the start of a block, evaluating its phi
+    /// nodes simultaneously.
+    fn jump(&mut self,frame: &mut Frame<'m>,label: &str) -> ExecResult<()> {
+        let trg = *self.labels[&frame.function.name[..]].get(label)
+            .ok_or_else(|| ExecError::UnknownBlock(label.to_string()))?;
+        let from = &frame.blocks[frame.block].name;
+        let mut vals = Vec::new();
+        for instr in frame.blocks[trg].instrs.iter() {
+            match instr.content {
+                InstructionC::Phi(ref name,ref tp,ref incoming) => {
+                    let v = match incoming.iter().find(|(_,l)| l==from) {
+                        Some((v,_)) => self.value(frame,tp,v)?,
+                        None => return Err(ExecError::UnknownBlock(from.clone()))
+                    };
+                    vals.push((&name[..],v));
+                },
+                _ => break
+            }
+        }
+        frame.block = trg;
+        frame.instr = vals.len();
+        for (name,v) in vals {
+            frame.locals.insert(name,v);
+        }
+        Ok(())
+    }
+    fn callee(&self,frame: &Frame<'m>,callee: &Value) -> ExecResult<&'m Function> {
+        let name = match *callee {
+            Value::Constant(Constant::Global(ref n)) => &n[..],
+            _ => {
+                let ptr = self.value(frame,&Type::ptr(Type::Int(8)),callee)?.as_pointer().unwrap();
+                *self.functions.get(&ptr.addr).ok_or(ExecError::InvalidCall(ptr.addr))?
+            }
+        };
+        self.module.functions.get(name).ok_or_else(|| ExecError::UnknownFunction(name.to_string()))
+    }
+    fn call_host(&mut self,name: &str,args: &[Val]) -> ExecResult<Option<Val>> {
+        // Overloaded intrinsics are looked up without their type suffixes
+        let mut key = name;
+        while !self.hosts.contains_key(key) {
+            match key.rfind('.') {
+                Some(pos) if key.starts_with("llvm.") && pos > 4 => key = &key[..pos],
+                _ => return Err(ExecError::NoHostFunction(name.to_string()))
+            }
+        }
+        let host = self.hosts.get_mut(key).unwrap();
+        host(&mut self.machine,args)
+    }
+    fn value(&self,frame: &Frame<'m>,tp: &Type,val: &Value) -> ExecResult<Val> {
+        match *val {
+            Value::Constant(ref c) => self.constant(tp,c),
+            Value::Local(ref name) => frame.locals.get(&name[..]).cloned()
+                .ok_or_else(|| ExecError::UnknownValue(name.clone())),
+            Value::Argument(n) => frame.args.get(n).cloned()
+                .ok_or_else(|| ExecError::UnknownValue(format!("{}",n))),
+            Value::Metadata(_) => Err(ExecError::Unsupported("metadata operand".to_string()))
+        }
+    }
+    fn constant(&self,tp: &Type,c: &Constant) -> ExecResult<Val> {
+        match *c {
+            Constant::Int(ref i) => match *resolve_type(&self.module.types,tp) {
+                Type::Int(w) => Ok(Val::int(w,i.clone())),
+                Type::Pointer(..) => Ok(Val::Ptr(Pointer { addr: i.to_u64().unwrap_or(0), alloc: None })),
+                ref t => Err(ExecError::Unsupported(format!("integer constant of type {:?}",t)))
+            },
+            Constant::Global(ref name) => self.globals.get(&name[..])
+                .map(|p| Val::Ptr(*p))
+                .ok_or_else(|| ExecError::UnknownGlobal(name.clone())),
+            Constant::NullPtr => Ok(Val::Ptr(Pointer::null())),
+            // Any value will do, zero is the most predictable one
+            Constant::Undef => match *resolve_type(&self.module.types,tp) {
+                Type::Int(w) => Ok(Val::int(w,BigInt::zero())),
+                Type::Pointer(..) => Ok(Val::Ptr(Pointer::null())),
+                ref t => Err(ExecError::Unsupported(format!("undef of type {:?}",t)))
+            },
+            Constant::GEP(ref g) => {
+                let base = self.constant(&g.ptr.tp,&g.ptr.val)?;
+                let mut idx = Vec::with_capacity(g.indices.len());
+                for (i,_) in g.indices.iter() {
+                    idx.push(self.constant(&i.tp,&i.val)?);
+                }
+                self.gep(&g.ptr.tp,base,&idx)
+            },
+            Constant::Cast(op,ref c,ref to) => {
+                let v = self.constant(&c.tp,&c.val)?;
+                self.cast(op,v,to)
+            },
+            Constant::Array(_) => Err(ExecError::Unsupported("aggregate value".to_string()))
+        }
+    }
+    /// Write the initializer of a global to memory.
+    fn store_constant(&mut self,ptr: &Pointer,tp: &Type,c: &Constant) -> ExecResult<()> {
+        let types = &self.module.types;
+        let dl = &self.module.datalayout;
+        match (resolve_type(types,tp),c) {
+            (Type::Array(_,el),Constant::Array(elems)) => {
+                let sz = dl.type_alloc_size(el,types)? as i64;
+                for (i,e) in elems.iter().enumerate() {
+                    self.store_constant(&ptr.offset(i as i64*sz),el,e)?;
+                }
+                Ok(())
+            },
+            (Type::Struct(els),Constant::Array(elems)) => {
+                let layout = dl.struct_layout(els,types)?;
+                for (i,(el,e)) in els.iter().zip(elems.iter()).enumerate() {
+                    self.store_constant(&ptr.offset(layout.offsets[i] as i64),el,e)?;
+                }
+                Ok(())
+            },
+            _ => {
+                let v = self.constant(tp,c)?;
+                self.store(ptr,tp,&v)
+            }
+        }
+    }
+    fn load(&self,ptr: &Pointer,tp: &Type) -> ExecResult<Val> {
+        let mem = &self.machine.memory;
+        match *resolve_type(&self.module.types,tp) {
+            Type::Int(w) => {
+                let v = mem.read_int(ptr,self.module.datalayout.type_store_size(tp,&self.module.types)?)?;
+                Ok(Val::int(w,v))
+            },
+            Type::Pointer(..) => Ok(Val::Ptr(mem.read_pointer(ptr)?)),
+            ref t => Err(ExecError::Unsupported(format!("load of type {:?}",t)))
+        }
+    }
+    fn store(&mut self,ptr: &Pointer,tp: &Type,val: &Val) -> ExecResult<()> {
+        let size = self.module.datalayout.type_store_size(tp,&self.module.types)?;
+        match *val {
+            Val::Int(_,ref v) => self.machine.memory.write_int(ptr,size,v),
+            Val::Ptr(ref p) => self.machine.memory.write_pointer(ptr,p)
+        }
+    }
+    fn gep(&self,ptr_tp: &Type,base: Val,indices: &[Val]) -> ExecResult<Val> {
+        let types = &self.module.types;
+        let dl = &self.module.datalayout;
+        let base = base.as_pointer().unwrap();
+        let mut cur = match *resolve_type(types,ptr_tp) {
+            Type::Pointer(ref el,_) => &**el,
+            ref t => return Err(ExecError::Unsupported(format!("getelementptr on {:?}",t)))
+        };
+        let mut off: i64 = 0;
+        for (n,idx) in indices.iter().enumerate() {
+            let i = idx.to_i64().unwrap_or(0);
+            if n==0 {
+                off = off.wrapping_add(i.wrapping_mul(dl.type_alloc_size(cur,types)? as i64));
+                continue
+            }
+            cur = match *resolve_type(types,cur) {
+                Type::Array(_,ref el) => {
+                    off = off.wrapping_add(i.wrapping_mul(dl.type_alloc_size(el,types)? as i64));
+                    el
+                },
+                Type::Struct(ref els) => {
+                    let layout = dl.struct_layout(els,types)?;
+                    off = off.wrapping_add(layout.element_offset(i as usize) as i64);
+                    &els[i as usize]
+                },
+                ref t => return Err(ExecError::Unsupported(format!("getelementptr into {:?}",t)))
+            };
+        }
+        Ok(Val::Ptr(base.offset(off)))
+    }
+    fn cast(&self,op: CastInst,v: Val,tp: &Type) -> ExecResult<Val> {
+        let to = resolve_type(&self.module.types,tp);
+        Ok(match (op,v,to) {
+            (CastInst::Trunc,Val::Int(_,v),&Type::Int(w)) |
+            (CastInst::ZExt,Val::Int(_,v),&Type::Int(w)) => Val::int(w,v),
+            (CastInst::SExt,Val::Int(fw,v),&Type::Int(w)) => Val::int(w,to_signed(fw,&v)),
+            (CastInst::PtrToInt,Val::Ptr(p),&Type::Int(w)) => Val::int(w,BigInt::from(p.addr)),
+            (CastInst::IntToPtr,v,&Type::Pointer(..)) => Val::Ptr(Pointer { addr: v.to_u64().unwrap_or(0), alloc: None }),
+            (CastInst::Bitcast,v,_) => v,
+            (op,v,to) => return Err(ExecError::Unsupported(format!("{:?} of {} to {:?}",op,v,to)))
+        })
+    }
+    /// Adapt a value returned by a call to the expected type.
+    fn coerce(&self,v: Val,tp: &Type) -> Val {
+        match (v,resolve_type(&self.module.types,tp)) {
+            (Val::Int(_,v),&Type::Int(w)) => Val::int(w,v),
+            (Val::Int(_,v),&Type::Pointer(..)) => Val::Ptr(Pointer { addr: v.to_u64().unwrap_or(0), alloc: None }),
+            (v,_) => v
+        }
+    }
+}
+
+/// Evaluate a comparison, pointers are compared by their address.
+pub fn compare(op: &CmpOp,l: &Val,r: &Val) -> bool {
+    let (ul,ur,sl,sr) = match (l,r) {
+        (Val::Int(w,l),Val::Int(_,r)) => (l.clone(),r.clone(),to_signed(*w,l),to_signed(*w,r)),
+        _ => {
+            let l = BigInt::from(l.to_u64().unwrap());
+            let r = BigInt::from(r.to_u64().unwrap());
+            (l.clone(),r.clone(),l,r)
+        }
+    };
+    match *op {
+        CmpOp::Eq => ul==ur,
+        CmpOp::Ne => ul!=ur,
+        CmpOp::UGt => ul>ur,
+        CmpOp::UGe => ul>=ur,
+        CmpOp::ULt => ul<ur,
+        CmpOp::ULe => ul<=ur,
+        CmpOp::SGt => sl>sr,
+        CmpOp::SGe => sl>=sr,
+        CmpOp::SLt => sl<sr,
+        CmpOp::SLe => sl<=sr
+    }
+}
+
+/// Evaluate a binary operation on two integers of the same width.
+pub fn binary(op: &BinOp,l: &Val,r: &Val) -> ExecResult<Val> {
+    let (w,l,r) = match (l,r) {
+        (Val::Int(w,l),Val::Int(_,r)) => (*w,l,r),
+        _ => return Err(ExecError::Unsupported(format!("{:?} on pointers",op)))
+    };
+    let shift = || r.to_usize().unwrap_or(usize::MAX);
+    Ok(match *op {
+        BinOp::Add(..) => Val::int(w,l+r),
+        BinOp::Sub(..) => Val::int(w,l-r),
+        BinOp::Mul(..) => Val::int(w,l*r),
+        BinOp::And => Val::Int(w,BigInt::from_biguint(Sign::Plus,to_biguint(l) & to_biguint(r))),
+        BinOp::Or => Val::Int(w,BigInt::from_biguint(Sign::Plus,to_biguint(l) | to_biguint(r))),
+        BinOp::XOr => Val::Int(w,BigInt::from_biguint(Sign::Plus,to_biguint(l) ^ to_biguint(r))),
+        // Shifts by the bit width or more produce poison, which is
+        // treated as zero here.
+        BinOp::Shl => if shift() as u64 >= w {
+            Val::Int(w,BigInt::zero())
+        } else {
+            Val::int(w,l << shift())
+        },
+        BinOp::LShr => if shift() as u64 >= w {
+            Val::Int(w,BigInt::zero())
+        } else {
+            Val::Int(w,l >> shift())
+        },
+        BinOp::AShr => if shift() as u64 >= w {
+            Val::Int(w,BigInt::zero())
+        } else {
+            let s = shift();
+            let mut res = to_biguint(l) >> s;
+            if to_signed(w,l).sign()==Sign::Minus {
+                let fill = (BigUint::from(1u8) << (w as usize)) - (BigUint::from(1u8) << (w as usize-s));
+                res |= fill;
+            }
+            Val::Int(w,BigInt::from_biguint(Sign::Plus,res))
+        },
+        BinOp::SDiv(_) => {
+            if r.is_zero() {
+                return Err(ExecError::DivisionByZero)
+            }
+            Val::int(w,to_signed(w,l)/to_signed(w,r))
+        }
+    })
+}
+
+fn arg(args: &[Val],n: usize) -> ExecResult<&Val> {
+    args.get(n).ok_or_else(|| ExecError::Host(format!("missing argument {}",n)))
+}
+
+fn ptr_arg(args: &[Val],n: usize) -> ExecResult<Pointer> {
+    arg(args,n)?.as_pointer().ok_or_else(|| ExecError::Host(format!("argument {} is not a pointer",n)))
+}
+
+fn int_arg(args: &[Val],n: usize) -> ExecResult<u64> {
+    arg(args,n)?.to_u64().ok_or_else(|| ExecError::Host(format!("argument {} is not an integer",n)))
+}
+
+// Allocate memory for `malloc` and friends, which return null instead
+// of failing. A size of `None` has overflowed.
+fn heap_allocate(m: &mut Machine,size: Option<u64>) -> ExecResult<Pointer> {
+    match size.map(|sz| m.malloc(sz)) {
+        None | Some(Err(ExecError::OutOfMemory)) => Ok(Pointer::null()),
+        Some(res) => res
+    }
+}
+
+fn install_default_host_functions(interp: &mut Interpreter) {
+    interp.add_host_function("printf",|m,args| {
+        let out = m.format(&ptr_arg(args,0)?,&args[1..])?;
+        m.stdout.extend_from_slice(&out);
+        Ok(Some(Val::from_i64(32,out.len() as i64)))
+    });
+    interp.add_host_function("fprintf",|m,args| {
+        let stream = m.stream(arg(args,0)?)
+            .ok_or_else(|| ExecError::Host("fprintf: unknown stream".to_string()))?;
+        let out = m.format(&ptr_arg(args,1)?,&args[2..])?;
+        m.write(stream,&out);
+        Ok(Some(Val::from_i64(32,out.len() as i64)))
+    });
+    interp.add_host_function("puts",|m,args| {
+        let mut s = m.memory.read_c_string(&ptr_arg(args,0)?)?;
+        s.push(b'\n');
+        m.stdout.extend_from_slice(&s);
+        Ok(Some(Val::from_i64(32,s.len() as i64)))
+    });
+    interp.add_host_function("putchar",|m,args| {
+        let c = int_arg(args,0)?;
+        m.stdout.push(c as u8);
+        Ok(Some(Val::from_i64(32,c as i64)))
+    });
+    interp.add_host_function("malloc",|m,args| {
+        Ok(Some(Val::Ptr(heap_allocate(m,Some(int_arg(args,0)?))?)))
+    });
+    interp.add_host_function("calloc",|m,args| {
+        let size = int_arg(args,0)?.checked_mul(int_arg(args,1)?);
+        let ptr = heap_allocate(m,size)?;
+        if let Some(size) = size {
+            if !ptr.is_null() {
+                m.memory.fill(&ptr,0,size)?;
+            }
+        }
+        Ok(Some(Val::Ptr(ptr)))
+    });
+    interp.add_host_function("realloc",|m,args| {
+        let old = ptr_arg(args,0)?;
+        let size = int_arg(args,1)?;
+        let ptr = heap_allocate(m,Some(size))?;
+        if ptr.is_null() {
+            return Ok(Some(Val::Ptr(ptr)))
+        }
+        if !old.is_null() {
+            let old_size = match m.memory.allocation_at(old.addr) {
+                Some(id) => m.memory.allocation(id).size,
+                None => return Err(ExecError::InvalidFree(old.addr))
+            };
+            m.memory.copy(&ptr,&old,min(size,old_size))?;
+            m.free(&old)?;
+        }
+        Ok(Some(Val::Ptr(ptr)))
+    });
+    interp.add_host_function("free",|m,args| {
+        m.free(&ptr_arg(args,0)?)?;
+        Ok(None)
+    });
+    interp.add_host_function("exit",|_,args| Err(ExecError::Exit(arg(args,0)?.to_i64().unwrap_or(0) as i32)));
+    interp.add_host_function("abort",|_,_| Err(ExecError::Aborted));
+    interp.add_host_function("__assert_fail",|m,args| {
+        let expr = m.memory.read_c_string(&ptr_arg(args,0)?)?;
+        let file = m.memory.read_c_string(&ptr_arg(args,1)?)?;
+        let fun = m.memory.read_c_string(&ptr_arg(args,3)?)?;
+        Err(ExecError::AssertionFailed(format!("{}:{}: {}: Assertion `{}' failed.",
+                                               String::from_utf8_lossy(&file),
+                                               int_arg(args,2)?,
+                                               String::from_utf8_lossy(&fun),
+                                               String::from_utf8_lossy(&expr))))
+    });
+    interp.add_host_function("abs",|_,args| {
+        let v = arg(args,0)?.as_signed().unwrap();
+        Ok(Some(Val::from_i64(32,v.to_i64().unwrap_or(0).wrapping_abs())))
+    });
+    // Execution is deterministic, no time passes.
+    interp.add_host_function("clock",|_,_| Ok(Some(Val::from_i64(64,0))));
+    interp.add_host_function("feof",|m,args| {
+        let eof = m.stream(arg(args,0)?)!=Some(Stream::Stdin) || m.stdin_eof();
+        Ok(Some(Val::from_i64(32,eof as i64)))
+    });
+    interp.add_host_function("fread",|m,args| {
+        let ptr = ptr_arg(args,0)?;
+        let size = int_arg(args,1)?;
+        let count = int_arg(args,2)?;
+        if m.stream(arg(args,3)?)!=Some(Stream::Stdin) || size==0 {
+            return Ok(Some(Val::from_i64(64,0)))
+        }
+        let mut data = m.read_stdin(size.saturating_mul(count) as usize);
+        data.truncate(data.len()-data.len()%size as usize);
+        m.memory.write_bytes(&ptr,&data)?;
+        Ok(Some(Val::from_i64(64,(data.len() as u64/size) as i64)))
+    });
+    interp.add_host_function("strlen",|m,args| {
+        let s = m.memory.read_c_string(&ptr_arg(args,0)?)?;
+        Ok(Some(Val::from_i64(64,s.len() as i64)))
+    });
+    for name in ["memcpy","memmove","llvm.memcpy","llvm.memmove"].iter() {
+        interp.add_host_function(name,|m,args| {
+            let dst = ptr_arg(args,0)?;
+            m.memory.copy(&dst,&ptr_arg(args,1)?,int_arg(args,2)?)?;
+            Ok(Some(Val::Ptr(dst)))
+        });
+    }
+    for name in ["memset","llvm.memset"].iter() {
+        interp.add_host_function(name,|m,args| {
+            let dst = ptr_arg(args,0)?;
+            m.memory.fill(&dst,int_arg(args,1)? as u8,int_arg(args,2)?)?;
+            Ok(Some(Val::Ptr(dst)))
+        });
+    }
+    for name in ["llvm.lifetime.start","llvm.lifetime.end"].iter() {
+        interp.add_host_function(name,|_,_| Ok(None));
+    }
+}
+
+/// Remove the `assert`s of a module, as if it was compiled with
+/// `NDEBUG`: conditional branches to blocks calling `__assert_fail`
+/// jump to the other target unconditionally.
+#[cfg(test)]
+fn without_assertions(m: &Module) -> Module {
+    let mut res = m.clone();
+    for fun in res.functions.values_mut() {
+        let blks = match fun.body {
+            Some(ref mut blks) => blks,
+            None => continue
+        };
+        let failing: Vec<String> = blks.iter().filter(|b| match b.instrs[0].content {
+            InstructionC::Call(_,_,_,Value::Constant(Constant::Global(ref n)),_,_) => n=="__assert_fail",
+            _ => false
+        }).map(|b| b.name.clone()).collect();
+        for blk in blks.iter_mut() {
+            let instr = blk.instrs.last_mut().unwrap();
+            let trg = match instr.content {
+                InstructionC::Term(Terminator::BrC(_,ref t,ref f)) if failing.contains(f) => t.clone(),
+                InstructionC::Term(Terminator::BrC(_,ref t,ref f)) if failing.contains(t) => f.clone(),
+                _ => continue
+            };
+            instr.content = InstructionC::Term(Terminator::Br(trg));
+        }
+    }
+    res
+}
+
+#[test]
+fn test_interp_minisat() {
+    let m = minisat();
+    let mut interp = Interpreter::new(&m).unwrap();
+    assert_eq!(interp.run_main(&["minisat"]),Ok(Outcome::Exited(1)));
+    assert_eq!(&interp.machine().stderr[..],&b"ERROR! Not enough command line arguments.\n"[..]);
+
+    // One of the assertions of the solver does not hold on the sample,
+    // natively compiled code fails in the same way.
+    let mut interp = Interpreter::new(&m).unwrap();
+    match interp.run_main(&["minisat","sample.cnf"]) {
+        Err(ExecError::AssertionFailed(ref msg)) => assert!(msg.starts_with("solver.c:721:"),"{}",msg),
+        res => panic!("unexpected result {:?}",res)
+    }
+
+    let m = without_assertions(&m);
+    let mut interp = Interpreter::new(&m).unwrap();
+    assert_eq!(interp.run_main(&["minisat","sample.cnf"]),Ok(Outcome::Exited(0)));
+    let out = String::from_utf8(interp.machine().stdout.clone()).unwrap();
+    assert!(out.contains("\nSATISFIABLE\n"),"unexpected output {}",out);
+    assert!(out.ends_with("Satisfying solution: x0=0 x1=1 x2=0 x3=0 x4=1 x5=0 x6=1 x7=1 x8=1 x9=0 \n"),
+            "unexpected output {}",out);
+}
+
+#[test]
+fn test_interp_arith() {
+    let m = parse_test_module(b"define i32 @f(i32 %n) {
+entry:
+  br label %loop
+loop:
+  %i = phi i32 [ 0, %entry ], [ %i1, %loop ]
+  %acc = phi i32 [ 1, %entry ], [ %acc1, %loop ]
+  %acc1 = mul i32 %acc, 3
+  %i1 = add i32 %i, 1
+  %c = icmp slt i32 %i1, %n
+  br i1 %c, label %loop, label %exit
+exit:
+  %neg = sub i32 0, %acc1
+  %sh = ashr i32 %neg, 1
+  %d = sdiv i32 %sh, -2
+  ret i32 %d
+}
+
+define i8 @g(i8 %x) {
+entry:
+  %p = alloca i32, align 4
+  %w = sext i8 %x to i32
+  store i32 %w, i32* %p, align 4
+  %b = bitcast i32* %p to i8*
+  %hi = getelementptr inbounds i8* %b, i64 3
+  %v = load i8* %hi, align 1
+  ret i8 %v
+}
+");
+    let mut interp = Interpreter::new(&m).unwrap();
+    // 3^4 = 81, -81 >> 1 = -41, -41 / -2 = 20
+    assert_eq!(interp.run_function("f",vec![Val::from_i64(32,4)]),
+               Ok(Outcome::Returned(Some(Val::from_i64(32,20)))));
+    // Without a datalayout memory is big endian
+    assert_eq!(interp.run_function("g",vec![Val::from_i64(8,-5)]),
+               Ok(Outcome::Returned(Some(Val::from_i64(8,-5)))));
+    assert_eq!(interp.run_function("g",vec![Val::from_i64(8,7)]),
+               Ok(Outcome::Returned(Some(Val::from_i64(8,7)))));
+}
+
+#[test]
+fn test_interp_host_functions() {
+    let m = parse_test_module(b"@.fmt = private unnamed_addr constant [9 x i8] c\"%s=%-3d|\\00\", align 1
+@.name = private unnamed_addr constant [2 x i8] c\"x\\00\", align 1
+
+declare i32 @printf(i8*, ...)
+
+declare i32 @get()
+
+define i32 @main() {
+entry:
+  %v = call i32 @get()
+  %r = call i32 (i8*, ...)* @printf(i8* getelementptr inbounds ([9 x i8]* @.fmt, i32 0, i32 0), i8* getelementptr inbounds ([2 x i8]* @.name, i32 0, i32 0), i32 %v)
+  ret i32 %r
+}
+");
+    let mut interp = Interpreter::new(&m).unwrap();
+    assert_eq!(interp.run_main(&[]),Err(ExecError::NoHostFunction("get".to_string())));
+    interp.add_host_function("get",|_,_| Ok(Some(Val::from_i64(32,-7))));
+    assert_eq!(interp.run_main(&[]),Ok(Outcome::Exited(6)));
+    assert_eq!(&interp.machine().stdout[..],&b"x=-7 |"[..]);
+}
+
+
+#[test]
+fn test_interp_allocation_limits() {
+    let m = parse_test_module(b"declare i8* @malloc(i64)
+
+declare i8* @calloc(i64, i64)
+
+define i8* @m(i64 %n) {
+entry:
+  %p = call i8* @malloc(i64 %n)
+  ret i8* %p
+}
+
+define i8* @c(i64 %n) {
+entry:
+  %p = call i8* @calloc(i64 %n, i64 %n)
+  ret i8* %p
+}
+
+define void @a(i64 %n) {
+entry:
+  %p = alloca i64, i64 %n
+  ret void
+}
+");
+    let mut interp = Interpreter::new(&m).unwrap();
+    let null = Ok(Outcome::Returned(Some(Val::Ptr(Pointer::null()))));
+    assert_eq!(interp.run_function("m",vec![Val::from_i64(64,-1)]),null);
+    assert_eq!(interp.run_function("c",vec![Val::from_i64(64,1 << 32)]),null);
+    match interp.run_function("c",vec![Val::from_i64(64,4)]) {
+        Ok(Outcome::Returned(Some(Val::Ptr(p)))) => assert!(!p.is_null()),
+        res => panic!("unexpected result {:?}",res)
+    }
+    assert_eq!(interp.run_function("a",vec![Val::from_i64(64,1 << 62)]),Err(ExecError::OutOfMemory));
+    assert_eq!(interp.run_function("a",vec![Val::from_i64(64,1 << 20)]),Ok(Outcome::Returned(None)));
+}
+
+#[test]
+fn test_interp_recursive_type() {
+    let m = parse_test_module(b"%s = type { i32, %s }
+
+define void @a() {
+entry:
+  %p = alloca %s
+  ret void
+}
+");
+    let mut interp = Interpreter::new(&m).unwrap();
+    assert_eq!(interp.run_function("a",vec![]),
+               Err(ExecError::Layout(LayoutError::Recursive("s".to_string()))));
+}
@@ -6,19 +6,46 @@ extern crate num_traits;
 use nom::*;
 use self::num_bigint::BigInt;
 use std::collections::HashMap;
-use std::collections::hash_map::Entry;
 use std::str;
 use std::str::FromStr;
 use std::fs::File;
-use std::io::Read;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
 use datalayout::*;
 use helper::*;
 use types::*;
+use ordered::OrderedMap;
 use num_traits::cast::FromPrimitive;
 use std::cmp::min;
 
 pub mod datalayout;
 pub mod types;
+pub mod cfg;
+pub mod loops;
+pub mod ssa;
+pub mod visit;
+pub mod builder;
+pub mod interp;
+pub mod ub;
+pub mod smt;
+pub mod symex;
+pub mod bmc;
+pub mod absint;
+pub mod dataflow;
+pub mod alias;
+pub mod callgraph;
+pub mod passes;
+pub mod mem2reg;
+pub mod inline;
+pub mod link;
+pub mod bitstream;
+pub mod bitcode;
+pub mod stream;
+pub mod borrowed;
+pub mod ordered;
+pub mod printer;
+pub mod diff;
 mod helper;
 #[cfg(test)]
 mod tests;
@@ -97,7 +124,11 @@ pub enum Constant {
     Int(BigInt),
     Array(Vec<Constant>),
     GEP(Box<GEP<Constant>>),
-    NullPtr
+    /// A cast constant expression, e.g. `bitcast (i8* @f to void ()*)`.
+    Cast(CastInst,Box<Typed<Constant>>,Type),
+    NullPtr,
+    /// An unspecified value of any type.
+    Undef
 }
 
 #[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Hash,Clone)]
@@ -119,12 +150,146 @@ pub struct Module {
     pub id: Option<String>,
     pub datalayout: DataLayout,
     pub triple: Option<String>,
-    pub functions: HashMap<String,Function>,
-    pub types: HashMap<String,Type>,
-    pub globals: HashMap<String,GlobalVariable>,
-    pub attr_groups: HashMap<u64,Vec<Attribute>>,
-    pub named_md: HashMap<String,Metadata>,
-    pub md: HashMap<u64,Metadata>
+    pub functions: OrderedMap<String,Function>,
+    pub types: OrderedMap<String,Type>,
+    pub globals: OrderedMap<String,GlobalVariable>,
+    pub attr_groups: OrderedMap<u64,Vec<Attribute>>,
+    pub named_md: OrderedMap<String,Metadata>,
+    pub md: OrderedMap<u64,Metadata>,
+    /// Bodies of defined functions that have not been parsed yet, see
+    /// `module_lazy`. The `body` of these functions is `None`.
+    pub lazy_bodies: HashMap<String,LazyBody>,
+    /// Definitions of functions that are not in `functions` because
+    /// an earlier definition of the same name is, in source order.
+    pub duplicate_functions: Vec<Function>
+}
+
+impl Module {
+    pub fn new() -> Module {
+        Module { id: None,
+                 datalayout: DataLayout::new(),
+                 triple: None,
+                 functions: OrderedMap::new(),
+                 types: OrderedMap::new(),
+                 globals: OrderedMap::new(),
+                 attr_groups: OrderedMap::new(),
+                 named_md: OrderedMap::new(),
+                 md: OrderedMap::new(),
+                 lazy_bodies: HashMap::new(),
+                 duplicate_functions: Vec::new() }
+    }
+
+    /// The body of a function, parsed on first access. `None` for
+    /// declarations and unknown functions. A body that cannot be parsed
+    /// stays unparsed.
+    pub fn function_body(&mut self,name: &str) -> Result<Option<&[BasicBlock]>,stream::ParseError> {
+        if let Some(lazy) = self.lazy_bodies.get(name) {
+            let f = self.functions.get_mut(name).expect("lazy body of an unknown function");
+            f.body = Some(lazy.parse(&f.arguments)?);
+            self.lazy_bodies.remove(name);
+        }
+        Ok(self.functions.get(name).and_then(|f| f.body.as_ref()).map(|b| &b[..]))
+    }
+
+    /// Parse all bodies that have not been parsed yet. Stops at the
+    /// first body that cannot be parsed.
+    pub fn materialize(&mut self) -> Result<(),stream::ParseError> {
+        let mut names: Vec<String> = self.lazy_bodies.keys().cloned().collect();
+        names.sort_by_key(|n| self.lazy_bodies[n].range.start);
+        for name in names {
+            self.function_body(&name)?;
+        }
+        Ok(())
+    }
+
+    // Add a function, unless an earlier definition of the same name is
+    // kept, see `add_element`.
+    fn add_function(&mut self,name: String,fun: Function) {
+        if !self.is_defined(&name) {
+            self.functions.insert(name,fun);
+        } else if fun.is_defined() {
+            self.duplicate_functions.push(fun);
+        }
+    }
+
+    /// Whether a function is defined, even if its body is not parsed yet.
+    pub fn is_defined(&self,name: &str) -> bool {
+        self.lazy_bodies.contains_key(name) ||
+            self.functions.get(name).is_some_and(|f| f.is_defined())
+    }
+}
+
+/// The source text of a function body that has not been parsed yet.
+#[derive(PartialEq,Eq,Clone)]
+pub struct LazyBody {
+    source: Arc<[u8]>,
+    range: Range<usize>
+}
+
+impl LazyBody {
+    /// The position of the body in the source.
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    /// The line of the source the body starts at.
+    pub fn line(&self) -> u64 {
+        line_of(&self.source,&self.source[self.range.start..])
+    }
+
+    fn parse(&self,args: &[(Option<String>,Type)]) -> Result<Vec<BasicBlock>,stream::ParseError> {
+        match function_body(&self.source[self.range.clone()],args,false) {
+            IResult::Done(_,blks) => Ok(blks),
+            _ => Err(stream::ParseError::Syntax(self.line()))
+        }
+    }
+}
+
+impl fmt::Debug for LazyBody {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,"LazyBody({:?})",self.range)
+    }
+}
+
+impl Default for Module {
+    fn default() -> Module {
+        Module::new()
+    }
+}
+
+/// A top-level entity of a textual module, in the order of the source.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum ModuleElement {
+    ModuleId(String),
+    DataLayout(DataLayout),
+    Triple(String),
+    TypeDef(String,Type),
+    Global(String,GlobalVariable),
+    /// A function definition or declaration.
+    Function(String,Function),
+    AttributeGroup(u64,Vec<Attribute>),
+    Metadata(u64,Metadata),
+    NamedMetadata(String,Metadata)
+}
+
+impl Module {
+    /// Add a parsed element to the module. A function replaces an
+    /// earlier declaration of the same name but not an earlier
+    /// definition; a definition that is not kept goes to
+    /// `duplicate_functions`.
+    pub fn add_element(&mut self,el: ModuleElement) {
+        match el {
+            ModuleElement::ModuleId(id) => self.id = Some(id),
+            ModuleElement::DataLayout(dl) => self.datalayout = dl,
+            ModuleElement::Triple(tr) => self.triple = Some(tr),
+            ModuleElement::TypeDef(name,tp) => { self.types.insert(name,tp); },
+            ModuleElement::Global(name,def) => { self.globals.insert(name,def); },
+            ModuleElement::Function(name,fun) => self.add_function(name,fun),
+            ModuleElement::AttributeGroup(n,attrs) => { self.attr_groups.insert(n,attrs); },
+            ModuleElement::Metadata(n,md) => { self.md.insert(n,md); },
+            ModuleElement::NamedMetadata(name,md) => { self.named_md.insert(name,md); }
+        }
+    }
 }
 
 #[derive(Debug,PartialEq,Eq,Clone)]
@@ -136,6 +301,9 @@ pub struct Function {
     pub cconv: CallingConv,
     pub return_type: Option<(ParAttrs,Type)>,
     pub arguments: Vec<(Option<String>,Type)>,
+    /// The parameter attributes of the arguments, in the same order
+    /// as `arguments`.
+    pub argument_attrs: Vec<ParAttrs>,
     pub var_args: bool,
     pub attribute_groups: Vec<AttributeGroup>,
     pub body: Option<Vec<BasicBlock>>
@@ -153,10 +321,20 @@ pub struct BasicBlock {
     pub instrs: Vec<Instruction>
 }
 
+impl BasicBlock {
+    /// The terminator of the block, if its last instruction is one.
+    pub fn terminator(&self) -> Option<&Terminator> {
+        match self.instrs.last() {
+            Some(&Instruction { content: InstructionC::Term(ref t), .. }) => Some(t),
+            _ => None
+        }
+    }
+}
+
 #[derive(Debug,PartialEq,Eq,Clone)]
 pub struct Instruction {
     pub content: InstructionC,
-    pub metadata: HashMap<String,u64>
+    pub metadata: OrderedMap<String,u64>
 }
 
 #[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Hash,Clone)]
@@ -240,6 +418,24 @@ pub enum Terminator {
     Unreachable
 }
 
+impl Terminator {
+    /// The labels of all blocks this terminator can jump to, in
+    /// operand order (duplicates are kept).
+    pub fn targets(&self) -> Vec<&str> {
+        match *self {
+            Terminator::Br(ref l) => vec![l],
+            Terminator::BrC(_,ref l1,ref l2) => vec![l1,l2],
+            Terminator::Ret(_) => Vec::new(),
+            Terminator::Switch(_,_,ref def,ref cases) => {
+                let mut res = vec![&def[..]];
+                res.extend(cases.iter().map(|(_,l)| &l[..]));
+                res
+            },
+            Terminator::Unreachable => Vec::new()
+        }
+    }
+}
+
 #[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Hash,Clone)]
 pub enum InstructionC {
     Alloca(String,Type,Option<Typed<Value>>,Option<Alignment>),
@@ -254,6 +450,25 @@ pub enum InstructionC {
     Term(Terminator)
 }
 
+impl InstructionC {
+    /// The name of the local value defined by the instruction, if any.
+    pub fn name(&self) -> Option<&str> {
+        match *self {
+            InstructionC::Alloca(ref n,..) |
+            InstructionC::Call(Some(ref n),..) |
+            InstructionC::ICmp(ref n,..) |
+            InstructionC::Unary(ref n,..) |
+            InstructionC::GEP(ref n,..) |
+            InstructionC::Select(ref n,..) |
+            InstructionC::Phi(ref n,..) |
+            InstructionC::Bin(ref n,..) => Some(n),
+            InstructionC::Call(None,..) |
+            InstructionC::Store(..) |
+            InstructionC::Term(..) => None
+        }
+    }
+}
+
 #[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Hash,Clone)]
 pub enum UnaryInst {
     Cast(Type,CastInst),
@@ -292,18 +507,33 @@ pub enum Metadata {
     Value(Box<Typed<Value>>),
     Struct(Vec<Metadata>),
     Bytes(Vec<u8>),
-    Location(u64,u64,Box<Metadata>)
+    Location(u64,u64,Box<Metadata>),
+    /// A specialized node like `!DIFile(...)`: its kind, whether it is
+    /// distinct, and the fields that are set. Only read from bitcode.
+    Node(String,bool,Vec<(String,MetadataField)>)
+}
+
+/// The value of a field of a specialized metadata node.
+#[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Hash,Clone)]
+pub enum MetadataField {
+    Int(u64),
+    Bool(bool),
+    /// A named constant like `CSK_MD5`.
+    Constant(String),
+    /// The operations of a `DIExpression`.
+    Elements(Vec<u64>),
+    Metadata(Metadata)
 }
 
 const NO_ARGS: [(Option<String>,Type); 0] = [];
 
-named!(module_id<&str>,
+named!(module_id<&'a str>,
        map_res!(delimited!( tag!("; ModuleID = \'"),
                             is_not!("\'"),
                             char!('\'') ),
                 str::from_utf8));
 
-named!(triple<&str>,
+named!(triple<&'a str>,
        map_res!( ws!(do_parse!( tag!("target") >>
                                 tag!("triple") >>
                                 char!('=') >>
@@ -313,14 +543,14 @@ named!(triple<&str>,
                                 (trp) )),
                      str::from_utf8));
 
-named!(type_def<(&str,Type)>,
+named!(type_def<(&'a str,Type)>,
        ws!(do_parse!( name: local_name >>
                       char!('=') >>
                       tag!("type") >>
                       tp: types >>
                       (name,tp))));
 
-named!(global_def<(&str,GlobalVariable)>,
+named!(global_def<(&'a str,GlobalVariable)>,
        do_parse!( name: global_name >>
                   llvm_space >>
                   char!('=') >>
@@ -328,7 +558,7 @@ named!(global_def<(&str,GlobalVariable)>,
                   glob: global_variable >>
                   (name,glob)));
 
-named!(function_definition<(&str,Function)>,
+named!(function_header<(&'a str,Function,bool)>,
        do_parse!(is_defined: alt!(map!(tag!("define"),|_| true) |
                                   map!(tag!("declare"),|_| false)) >>
                  llvm_space >>
@@ -347,11 +577,14 @@ named!(function_definition<(&str,Function)>,
                  llvm_space >>
                  char!('(') >>
                  llvm_space >>
-                 args: separated_list!(delimited!(llvm_space,char!(','),llvm_space),
-                                       do_parse!(tp: types >>
-                                                 n: opt!(preceded!(llvm_space,map!(local_name,|n| n.to_string()))) >>
-                                                 (n,tp))) >>
-                 va: map!( opt!(do_parse!( cond!(!args.is_empty(),
+                 args: map!(separated_list!(delimited!(llvm_space,char!(','),llvm_space),
+                                            do_parse!(tp: types >>
+                                                      llvm_space >>
+                                                      pattrs: par_attrs >>
+                                                      n: opt!(map!(local_name,|n| n.to_string())) >>
+                                                      ((n,tp),pattrs))),
+                            |args: Vec<_>| args.into_iter().unzip::<_,_,Vec<_>,Vec<_>>()) >>
+                 va: map!( opt!(do_parse!( cond!(!args.0.is_empty(),
                                                  terminated!(char!(','),llvm_space)) >>
                                            tag!("...") >>
                                            ())),
@@ -361,35 +594,79 @@ named!(function_definition<(&str,Function)>,
                  llvm_space >>
                  attrs: many0!(delimited!(char!('#'),parse_u64,llvm_space)) >>
                  llvm_space >>
-                 blks: cond!(is_defined,
-                             do_parse!(char!('{') >>
-                                       llvm_nl >>
-                                       blks: many0!(terminated!(call!(basic_block,&args[..]),llvm_nl)) >>
-                                       char!('}') >>
-                                       (blks))) >>
                  (name,Function { name: name.to_string(),
                                   linkage: lnk,
                                   visibility: vis,
                                   dll_storage_class: stcls,
                                   cconv: cc,
                                   return_type: ret,
-                                  arguments: args,
+                                  arguments: args.0,
+                                  argument_attrs: args.1,
                                   var_args: va,
                                   attribute_groups: attrs,
-                                  body: blks })));
+                                  body: None },is_defined)));
+
+// The blocks of a function body. A line that is not an instruction is
+// reported with a panic if `strict`, otherwise the body fails to parse.
+named_args!(function_body<'a>(args: &'a [(Option<String>,Type)],strict: bool)<Vec<BasicBlock>>,
+       do_parse!(char!('{') >>
+                 llvm_nl >>
+                 blks: many0!(terminated!(call!(basic_block,args,strict),llvm_nl)) >>
+                 char!('}') >>
+                 (blks)));
+
+named!(function_definition<(&'a str,Function)>,
+       do_parse!(hd: function_header >>
+                 blks: cond!(hd.2,call!(function_body,&hd.1.arguments[..],true)) >>
+                 (hd.0,Function { body: blks, ..hd.1 })));
+
+// A function body, up to the first line that starts with a closing
+// brace, without parsing it.
+fn skip_function_body(input: &[u8]) -> IResult<&[u8],&[u8]> {
+    if input.is_empty() {
+        return IResult::Incomplete(Needed::Size(1))
+    }
+    if input[0] != b'{' {
+        return IResult::Error(error_position!(ErrorKind::Char,input))
+    }
+    let mut pos = 1;
+    while let Some(nl) = input[pos..].iter().position(|&c| c==b'\n') {
+        pos += nl+1;
+        let indent = input[pos..].iter().take_while(|&&c| c==b' ' || c==b'\t').count();
+        if input.get(pos+indent)==Some(&b'}') {
+            let end = pos+indent+1;
+            return IResult::Done(&input[end..],&input[..end])
+        }
+    }
+    IResult::Incomplete(Needed::Unknown)
+}
+
+// A function header and, for definitions, the unparsed body.
+type Skeleton<'a> = (&'a str,Function,Option<&'a [u8]>);
+
+fn function_skeleton<'a>(input: &'a [u8]) -> IResult<&'a [u8],Skeleton<'a>> {
+    do_parse!(input,
+              hd: function_header >>
+              body: cond!(hd.2,skip_function_body) >>
+              (hd.0,hd.1,body))
+}
 
 named!(comment,
        preceded!(char!(';'),
                  is_not!("\n")));
 
-named_args!(basic_block<'a>(args: &'a [(Option<String>,Type)])<BasicBlock>,
+named_args!(basic_block<'a>(args: &'a [(Option<String>,Type)],strict: bool)<BasicBlock>,
        do_parse!(name: map_res!(is_not!("} \t\n:"),
                                 str::from_utf8) >>
                  char!(':') >>
                  instrs: many0!(preceded!(llvm_nl,alt!(call!(instruction,args) |
                                                        preceded!(not!(alt!(char!('}') | preceded!(is_not!("} \t\n:"),char!(':')))),
-                                                                 map!(map_res!(is_not!("\n"),str::from_utf8),
-                                                                      |s| { panic!("Cannot parse instruction: {}",s) }))))) >>
+                                                                 map_opt!(map_res!(is_not!("\n"),str::from_utf8),
+                                                                          |s| if strict {
+                                                                              panic!("Cannot parse instruction: {}",s)
+                                                                          } else {
+                                                                              None
+                                                                          }))))) >>
                  (BasicBlock { name: name.to_string(),
                                instrs: instrs })));
 
@@ -405,8 +682,8 @@ named_args!(instruction<'a>(args: &'a [(Option<String>,Type)])<Instruction>,
                                              char!('!') >>
                                              id: parse_u64 >>
                                              (name.to_string(),id)),
-                                   HashMap::new(),
-                                   |mut mp: HashMap<String,u64>,(name,id)| {
+                                   OrderedMap::new(),
+                                   |mut mp: OrderedMap<String,u64>,(name,id)| {
                                        mp.insert(name,id);
                                        mp
                                    }) >>
@@ -553,12 +830,7 @@ named_args!(instruction_c<'a>(args: &'a [(Option<String>,Type)])<InstructionC>,
                                            (InstructionC::Unary(name.to_string(),
                                                                 ptr,
                                                                 UnaryInst::Load(vol,align)))) |
-                                 do_parse!(op: alt!(map!(tag!("trunc"),|_| CastInst::Trunc) |
-                                                    map!(tag!("zext"),|_| CastInst::ZExt) |
-                                                    map!(tag!("sext"),|_| CastInst::SExt) |
-                                                    map!(tag!("bitcast"),|_| CastInst::Bitcast) |
-                                                    map!(tag!("inttoptr"),|_| CastInst::IntToPtr) |
-                                                    map!(tag!("ptrtoint"),|_| CastInst::PtrToInt)) >>
+                                 do_parse!(op: cast_inst >>
                                            llvm_space >>
                                            val: call!(typed_value,args) >>
                                            llvm_space >>
@@ -703,7 +975,7 @@ named_args!(metadata<'a>(args: &'a [(Option<String>,Type)])<Metadata>,
                                     map!(delimited!(char!('"'),
                                                     many0!(alt!(map_res!(map_res!(preceded!(char!('\\'),take!(2)),
                                                                                   str::from_utf8),
-                                                                         FromStr::from_str) |
+                                                                         |s| u8::from_str_radix(s,16)) |
                                                                 map_opt!(be_u8,|c| if c==b'"' { None } else { Some(c) }))),
                                                     char!('"')),
                                          Metadata::Bytes) |
@@ -783,50 +1055,27 @@ named_args!(num_metadata<'a>(args: &'a [(Option<String>,Type)])<(u64,Metadata)>,
                  def: call!(metadata,args) >>
                  (name,def)));
 
-named_args!(module_element<'a>(m: &'a mut Module)<()>,
-            alt!( map!(module_id,
-                       |id| {
-                           m.id = Some(id.to_string());
-                       }) |
-                  map!(datalayout,
-                       |dl| {
-                           m.datalayout = dl;
-                       }) |
-                  map!(triple,
-                       |tr| {
-                           m.triple = Some(tr.to_string());
-                       }) |
-                  map!(type_def,
-                       |(name,tp)| {
-                           m.types.insert(name.to_string(),tp);
-                       }) |
-                  map!(global_def,
-                       |(name,def)| {
-                           m.globals.insert(name.to_string(),def);
-                       }) |
-                  map!(function_definition,
-                       |(name,fun)| {
-                           match m.functions.entry(name.to_string()) {
-                               Entry::Occupied(mut e) => if !e.get().is_defined() {
-                                   e.insert(fun);
-                               },
-                               Entry::Vacant(e) => { e.insert(fun); }
-                           }
-                       }) |
-                  map!(attribute_group,
-                       |(n,attrs)| {
-                           m.attr_groups.insert(n,attrs);
-                       }) |
-                  map!(call!(num_metadata,&NO_ARGS),
-                       |(n,md)| {
-                           m.md.insert(n,md);
-                       }) |
-                  map!(call!(named_metadata,&NO_ARGS),
-                       |(n,md)| {
-                           m.named_md.insert(n,md);
-                       }) |
-                  map!(comment,
-                       |_| { })));
+named!(module_element<Option<ModuleElement>>,
+       alt!( map!(module_id,
+                  |id| Some(ModuleElement::ModuleId(id.to_string()))) |
+             map!(datalayout,
+                  |dl| Some(ModuleElement::DataLayout(dl))) |
+             map!(triple,
+                  |tr| Some(ModuleElement::Triple(tr.to_string()))) |
+             map!(type_def,
+                  |(name,tp)| Some(ModuleElement::TypeDef(name.to_string(),tp))) |
+             map!(global_def,
+                  |(name,def)| Some(ModuleElement::Global(name.to_string(),def))) |
+             map!(function_definition,
+                  |(name,fun)| Some(ModuleElement::Function(name.to_string(),fun))) |
+             map!(attribute_group,
+                  |(n,attrs)| Some(ModuleElement::AttributeGroup(n,attrs))) |
+             map!(call!(num_metadata,&NO_ARGS),
+                  |(n,md)| Some(ModuleElement::Metadata(n,md))) |
+             map!(call!(named_metadata,&NO_ARGS),
+                  |(n,md)| Some(ModuleElement::NamedMetadata(n,md))) |
+             map!(comment,
+                  |_| None)));
 
 fn gep<T,F>(input: &[u8],parse: F,paren: bool) -> IResult<&[u8],GEP<T>>
     where F : Fn(&[u8]) -> IResult<&[u8],T> {
@@ -856,6 +1105,8 @@ fn gep<T,F>(input: &[u8],parse: F,paren: bool) -> IResult<&[u8],GEP<T>>
 named!(constant<Constant>,
        alt_complete!( map!(tag!("null"),
                   |_| Constant::NullPtr) |
+             map!(tag!("undef"),
+                  |_| Constant::Undef) |
              map!(tag!("false"),
                   |_| Constant::Int(BigInt::from(0))) |
              map!(tag!("true"),
@@ -879,9 +1130,50 @@ named!(constant<Constant>,
                            |s| { BigInt::parse_bytes(s,10) }),
                   |i| Constant::Int(-i)) |
              map!(call!(gep,constant,true),
-                  |g| Constant::GEP(Box::new(g)))
+                  |g| Constant::GEP(Box::new(g))) |
+             map!(delimited!(terminated!(char!('['),llvm_space),
+                             constant_elements,
+                             char!(']')),
+                  Constant::Array) |
+             map!(delimited!(terminated!(char!('{'),llvm_space),
+                             constant_elements,
+                             char!('}')),
+                  Constant::Array) |
+             do_parse!(op: cast_inst >>
+                       llvm_space >>
+                       char!('(') >>
+                       llvm_space >>
+                       tp: types >>
+                       llvm_space >>
+                       c: constant >>
+                       llvm_space >>
+                       tag!("to") >>
+                       llvm_space >>
+                       trg: types >>
+                       llvm_space >>
+                       char!(')') >>
+                       (Constant::Cast(op,Box::new(Typed::new(tp,c)),trg)))
        ));
 
+// The elements of an array or struct constant, the element types are
+// not kept.
+named!(constant_elements<Vec<Constant> >,
+       terminated!(separated_list!(terminated!(char!(','),llvm_space),
+                                   do_parse!(types >>
+                                             llvm_space >>
+                                             c: constant >>
+                                             llvm_space >>
+                                             (c))),
+                   llvm_space));
+
+named!(cast_inst<CastInst>,
+       alt!(map!(tag!("trunc"),|_| CastInst::Trunc) |
+            map!(tag!("zext"),|_| CastInst::ZExt) |
+            map!(tag!("sext"),|_| CastInst::SExt) |
+            map!(tag!("bitcast"),|_| CastInst::Bitcast) |
+            map!(tag!("inttoptr"),|_| CastInst::IntToPtr) |
+            map!(tag!("ptrtoint"),|_| CastInst::PtrToInt)));
+
 named!(constant_char<BigInt>,
        alt!(map_opt!(preceded!(char!('\\'),
                                take!(2)),
@@ -897,13 +1189,13 @@ named!(linkage<Linkage>,
        alt!( map!(tag!("private"),|_| Linkage::Private) |
              map!(tag!("internal"),|_| Linkage::Internal) |
              map!(tag!("available_externally"),|_| Linkage::AvailableExternally) |
+             map!(tag!("linkonce_odr"),|_| Linkage::LinkOnceODR) |
              map!(tag!("linkonce"),|_| Linkage::LinkOnce) |
+             map!(tag!("weak_odr"),|_| Linkage::WeakODR) |
              map!(tag!("weak"),|_| Linkage::Weak) |
              map!(tag!("common"),|_| Linkage::Common) |
              map!(tag!("appending"),|_| Linkage::Appending) |
              map!(tag!("extern_weak"),|_| Linkage::ExternWeak) |
-             map!(tag!("linkonce_odr"),|_| Linkage::LinkOnceODR) |
-             map!(tag!("weak_odr"),|_| Linkage::WeakODR) |
              map!(tag!("external"),|_| Linkage::External) ));
 
 named!(visibility<Visibility>,
@@ -1017,20 +1309,19 @@ named!(calling_conv<CallingConv>,
 
 named_args!(par_attr<'a>(attrs: &'a mut ParAttrs)<()>,
             alt!( map!(tag!("zeroext"),
-                       |_| { attrs.zeroext = true;
-                             () }) |
+                       |_| { attrs.zeroext = true; }) |
                   map!(tag!("signext"),
-                       |_| { attrs.signext = true;
-                             () }) |
+                       |_| { attrs.signext = true; }) |
                   map!(tag!("inreg"),
-                       |_| { attrs.inreg = true;
-                             () }) |
+                       |_| { attrs.inreg = true; }) |
                   map!(tag!("byval"),
-                       |_| { attrs.byval = true;
-                             () }) |
+                       |_| { attrs.byval = true; }) |
                   map!(tag!("noalias"),
-                       |_| { attrs.noalias = true;
-                             () })
+                       |_| { attrs.noalias = true; }) |
+                  map!(tag!("nocapture"),
+                       |_| { attrs.nocapture = true; }) |
+                  map!(tag!("nonnull"),
+                       |_| { attrs.nonnull = true; })
             ));
 
 fn par_attrs(inp: &[u8]) -> IResult<&[u8],ParAttrs> {
@@ -1052,116 +1343,25 @@ fn par_attrs(inp: &[u8]) -> IResult<&[u8],ParAttrs> {
     IResult::Incomplete(Needed::Unknown)
 }
 
-struct ModuleBuilder {
-    m: Module,
-    st: ConsumerState<(),(),Move>
-}
-
-impl ModuleBuilder {
-    fn new() -> ModuleBuilder {
-        ModuleBuilder { m: Module { id: None,
-                                    datalayout: DataLayout::new(),
-                                    triple: None,
-                                    functions: HashMap::new(),
-                                    types: HashMap::new(),
-                                    globals: HashMap::new(),
-                                    attr_groups: HashMap::new(),
-                                    named_md: HashMap::new(),
-                                    md: HashMap::new() },
-                        st: ConsumerState::Continue(Move::Consume(0)) }
-    }
-}
-
-impl<'a> Consumer<&'a [u8],(),(),Move> for ModuleBuilder {
-    fn handle(&mut self,input: Input<&[u8]>) -> &ConsumerState<(),(),Move> {
-        match input {
-            Input::Eof(None) => {
-                println!("EOF");
-                self.st = ConsumerState::Done(Move::Consume(0),());
-                &self.st
-            },
-            Input::Empty => {
-                println!("Empty");
-                self.st = ConsumerState::Continue(Move::Consume(0));
-                &self.st
-            },
-            Input::Element(sl) | Input::Eof(Some(sl)) => {
-                {
-                    let strs = str::from_utf8(sl).expect("cannot parse utf8");
-                    println!("Handle {}",strs);
-                }
-                match module_element(sl,&mut self.m) {
-                    IResult::Done(rest,()) => {
-                        {
-                            let rest_strs = str::from_utf8(rest).expect("cannot parse utf8");
-                            println!("Done: {}",rest_strs);
-                            println!("Consumed: {}",sl.offset(rest));
-                        }
-                        let mut ninp = rest;
-                        while ninp.len() > 0 && (ninp[0]==b' ' || ninp[0]==b'\t' || ninp[0]==b'\n') {
-                            ninp = &ninp[1..];
-                        }
-                        self.st = ConsumerState::Continue(Move::Consume(sl.offset(ninp)));
-                        &self.st
-                    },
-                    IResult::Incomplete(n) => {
-                        println!("Incomplete");
-                        self.st = ConsumerState::Continue(Move::Await(n));
-                        &self.st
-                    },
-                    IResult::Error(_) => {
-                        println!("Error");
-                        self.st = ConsumerState::Error(());
-                        &self.st
-                    }
-                }
-            }
-        }
-    }
-    fn state(&self) -> &ConsumerState<(),(),Move> {
-        &self.st
-    }
-}
-
+/// Parse a textual module file. The file is read element by element,
+/// see `stream::ElementReader`.
 pub fn parse_module(file: &str) -> Option<Module> {
-    let mut buf = Vec::new();
-    let mut f = match File::open(file) {
+    let f = match File::open(file) {
         Ok(r) => r,
         Err(_) => return None
     };
-    match f.read_to_end(&mut buf) {
-        Ok(_) => {},
-        Err(_) => return None
-    }
-    match module(&buf[..]) {
-        IResult::Done(ninp,m) => if ninp.len()==0 { Some(m) } else { None },
-        _ => None
-    }
-    /*let mut fp = FileProducer::new(file,1024).expect("Cannot open file");
-    let mut builder = ModuleBuilder::new();
-    loop {
-        match fp.apply(&mut builder) {
-            &ConsumerState::Error(_) => return None,
-            &ConsumerState::Done(_,_) => return Some(builder.m),
-            &ConsumerState::Continue(_) => {}
-        }
-    }*/
+    stream::read_module(f).ok()
 }
 
 pub fn module(input: &[u8]) -> IResult<&[u8],Module> {
     let mut inp = input;
-    let mut m = Module { id: None,
-                         datalayout: DataLayout::new(),
-                         triple: None,
-                         functions: HashMap::new(),
-                         types: HashMap::new(),
-                         globals: HashMap::new(),
-                         attr_groups: HashMap::new(),
-                         named_md: HashMap::new(),
-                         md: HashMap::new() };
+    let mut m = Module::new();
     while !inp.is_empty() {
-        match module_element(inp,&mut m) {
-            IResult::Done(ninp,()) => {
+        match module_element(inp) {
+            IResult::Done(ninp,el) => {
+                if let Some(el) = el {
+                    m.add_element(el);
+                }
                 inp = ninp;
                 while inp.len() > 0 && (inp[0]==b' ' || inp[0]==b'\t' || inp[0]==b'\n') {
                     inp = &inp[1..];
@@ -1178,6 +1378,85 @@ pub fn module(input: &[u8]) -> IResult<&[u8],Module> {
     IResult::Done(&b""[..],m)
 }
 
+/// Like `module`, but only the headers of functions are parsed. The
+/// input is copied and the bodies are parsed on demand, with
+/// `Module::function_body` or `Module::materialize`; until then the
+/// `body` of defined functions is `None`. Bodies of definitions that
+/// are not kept are parsed right away.
+pub fn module_lazy(input: &[u8]) -> Result<Module,stream::ParseError> {
+    let source: Arc<[u8]> = Arc::from(input);
+    let mut inp = input;
+    let mut m = Module::new();
+    while !inp.is_empty() {
+        let res = match function_skeleton(inp) {
+            IResult::Done(ninp,(name,fun,body)) => {
+                let lazy = body.map(|body| {
+                    let start = input.offset(body);
+                    LazyBody { source: source.clone(),
+                               range: start..start+body.len() }
+                });
+                match lazy {
+                    // A later definition is not kept, so its body is
+                    // parsed right away for `duplicate_functions`.
+                    Some(lazy) if m.is_defined(name) => {
+                        let body = lazy.parse(&fun.arguments)?;
+                        m.add_function(name.to_string(),Function { body: Some(body), ..fun });
+                    },
+                    Some(lazy) => {
+                        m.add_function(name.to_string(),fun);
+                        m.lazy_bodies.insert(name.to_string(),lazy);
+                    },
+                    None => m.add_function(name.to_string(),fun)
+                }
+                IResult::Done(ninp,None)
+            },
+            _ => module_element(inp)
+        };
+        match res {
+            IResult::Done(ninp,el) => {
+                if let Some(el) = el {
+                    m.add_element(el);
+                }
+                inp = ninp;
+                while !inp.is_empty() && (inp[0]==b' ' || inp[0]==b'\t' || inp[0]==b'\n') {
+                    inp = &inp[1..];
+                }
+            },
+            IResult::Error(_) => return Err(stream::ParseError::Syntax(line_of(input,inp))),
+            IResult::Incomplete(_) => return Err(stream::ParseError::Truncated(line_of(input,inp)))
+        }
+    }
+    Ok(m)
+}
+
+// The line of `input` that `rest`, a suffix of it, starts at.
+fn line_of(input: &[u8],rest: &[u8]) -> u64 {
+    input[..input.offset(rest)].iter().filter(|&&c| c==b'\n').count() as u64+1
+}
+
+/// Parse a whole module in a test.
+#[cfg(test)]
+fn parse_test_module(src: &[u8]) -> Module {
+    match module(src) {
+        IResult::Done(_,m) => m,
+        _ => panic!("parse failure")
+    }
+}
+
+/// Parse a single function definition in a test.
+#[cfg(test)]
+fn parse_test_function(src: &[u8]) -> Function {
+    match function_definition(src) {
+        IResult::Done(_,(_,f)) => f,
+        _ => panic!("parse failure")
+    }
+}
+
+#[cfg(test)]
+fn minisat() -> Module {
+    parse_test_module(include_bytes!("minisat.ll"))
+}
+
 impl Constant {
     pub fn zero_init(tp: &Type) -> Self {
         match tp {
@@ -0,0 +1,582 @@
+//! Linking of several modules into one, like `llvm-link`.
+//!
+//! Modules are linked into the first one, one after the other. For each
+//! linked module:
+//!
+//! * Named types are unified with the types of the destination: a type
+//!   maps to the type of the same name if both have the same structure
+//!   (or one is opaque), otherwise to a structurally identical type of
+//!   another name, otherwise it is added, renamed if its name is taken.
+//! * Symbols with private or internal linkage are renamed if their
+//!   name is used by the other module.
+//! * Declarations resolve to definitions. Of two definitions the
+//!   stronger one is kept: external definitions beat weak and linkonce
+//!   definitions, which beat common symbols (the larger one is kept),
+//!   which beat `available_externally` definitions. Two external
+//!   definitions are a conflict. Appending arrays are concatenated.
+//! * Metadata nodes are renumbered after the ones of the destination,
+//!   identical attribute groups are shared, named metadata lists are
+//!   concatenated.
+use std::collections::{HashMap,HashSet};
+use std::fmt;
+use super::*;
+use datalayout::LayoutError;
+use passes::fresh_name;
+use visit::VisitorMut;
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum LinkError {
+    /// Two modules contain a non-weak definition of the symbol.
+    DuplicateDefinition(String),
+    /// The symbol is a function in one module and a variable in the
+    /// other.
+    KindMismatch(String),
+    /// Only one definition of the symbol has appending linkage, or the
+    /// element types of the arrays differ.
+    AppendingMismatch(String),
+    /// The type of a common symbol has no memory layout.
+    Layout(LayoutError)
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LinkError::DuplicateDefinition(ref n) => write!(f,"symbol @{} is defined more than once",n),
+            LinkError::KindMismatch(ref n) => write!(f,"symbol @{} is both a function and a variable",n),
+            LinkError::AppendingMismatch(ref n) => write!(f,"appending variable @{} cannot be merged",n),
+            LinkError::Layout(ref e) => write!(f,"{}",e)
+        }
+    }
+}
+
+impl ::std::error::Error for LinkError {}
+
+impl From<LayoutError> for LinkError {
+    fn from(e: LayoutError) -> LinkError {
+        LinkError::Layout(e)
+    }
+}
+
+/// Link modules into one. The identifier, data layout and target
+/// triple are those of the first module that has them.
+pub fn link(modules: Vec<Module>) -> Result<Module,LinkError> {
+    let mut it = modules.into_iter();
+    let mut dst = match it.next() {
+        Some(m) => m,
+        None => return Ok(Module::new())
+    };
+    for src in it {
+        link_into(&mut dst,src)?;
+    }
+    Ok(dst)
+}
+
+// How strongly a definition binds its symbol.
+#[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Clone,Copy)]
+enum Strength {
+    Declaration,
+    AvailableExternally,
+    Common,
+    Weak,
+    Strong
+}
+
+fn strength(linkage: Option<Linkage>,defined: bool) -> Strength {
+    match linkage {
+        _ if !defined => Strength::Declaration,
+        Some(Linkage::ExternWeak) => Strength::Declaration,
+        Some(Linkage::AvailableExternally) => Strength::AvailableExternally,
+        Some(Linkage::Common) => Strength::Common,
+        Some(Linkage::LinkOnce) | Some(Linkage::LinkOnceODR) |
+        Some(Linkage::Weak) | Some(Linkage::WeakODR) => Strength::Weak,
+        _ => Strength::Strong
+    }
+}
+
+fn is_local(linkage: Option<Linkage>) -> bool {
+    matches!(linkage,Some(Linkage::Private) | Some(Linkage::Internal))
+}
+
+// The linkage of a symbol of a module.
+fn symbol_linkage(m: &Module,name: &str) -> Option<Option<Linkage>> {
+    m.functions.get(name).map(|f| f.linkage)
+        .or_else(|| m.globals.get(name).map(|g| g.linkage))
+}
+
+// Renames types, symbols and attribute groups and shifts metadata ids.
+#[derive(Default)]
+struct Remap {
+    types: HashMap<String,String>,
+    symbols: HashMap<String,String>,
+    attrs: HashMap<AttributeGroup,AttributeGroup>,
+    md_offset: u64
+}
+
+impl VisitorMut for Remap {
+    fn visit_type_mut(&mut self,tp: &mut Type) {
+        if let Type::Named(ref mut n) = *tp {
+            if let Some(new) = self.types.get(n) {
+                *n = new.clone();
+            }
+        }
+        visit::walk_type_mut(self,tp)
+    }
+    fn visit_constant_mut(&mut self,c: &mut Constant) {
+        if let Constant::Global(ref mut n) = *c {
+            if let Some(new) = self.symbols.get(n) {
+                *n = new.clone();
+            }
+        }
+        visit::walk_constant_mut(self,c)
+    }
+    fn visit_attribute_group_mut(&mut self,id: &mut AttributeGroup) {
+        if let Some(new) = self.attrs.get(id) {
+            *id = *new;
+        }
+    }
+    fn visit_metadata_mut(&mut self,md: &mut Metadata) {
+        if let Metadata::Ref(ref mut id) = *md {
+            *id += self.md_offset;
+        }
+        visit::walk_metadata_mut(self,md)
+    }
+    fn visit_metadata_attachment_mut(&mut self,_kind: &str,id: &mut u64) {
+        *id += self.md_offset;
+    }
+}
+
+// Renamed entries keep their position.
+fn rename_keys<T>(map: &mut OrderedMap<String,T>,names: &HashMap<String,String>) {
+    let renamed: OrderedMap<String,T> = map.drain()
+        .map(|(k,v)| (names.get(&k).cloned().unwrap_or(k),v))
+        .collect();
+    *map = renamed;
+}
+
+// Apply a remapping to a whole module, including the names of the
+// definitions.
+fn remap_module(m: &mut Module,mut remap: Remap) {
+    remap.visit_module_mut(m);
+    rename_keys(&mut m.types,&remap.types);
+    rename_keys(&mut m.globals,&remap.symbols);
+    rename_keys(&mut m.functions,&remap.symbols);
+    for (name,f) in m.functions.iter_mut() {
+        f.name = name.clone();
+    }
+    let attrs: OrderedMap<AttributeGroup,Vec<Attribute>> = m.attr_groups.drain()
+        .map(|(id,a)| (remap.attrs.get(&id).cloned().unwrap_or(id),a))
+        .collect();
+    m.attr_groups = attrs;
+    let md: OrderedMap<u64,Metadata> = m.md.drain().map(|(id,md)| (id+remap.md_offset,md)).collect();
+    m.md = md;
+}
+
+// A type with the named types of the source module replaced by the
+// ones of the destination, `None` if it refers to an unmapped type.
+fn map_type(tp: &Type,types: &HashMap<String,String>) -> Option<Type> {
+    Some(match *tp {
+        Type::Named(ref n) => Type::Named(types.get(n)?.clone()),
+        Type::Pointer(ref t,sp) => Type::Pointer(Box::new(map_type(t,types)?),sp),
+        Type::Array(n,ref t) => Type::Array(n,Box::new(map_type(t,types)?)),
+        Type::Struct(ref ts) => Type::Struct(ts.iter().map(|t| map_type(t,types)).collect::<Option<Vec<_>>>()?),
+        Type::Function(ref ret,ref args,va) => {
+            let ret = match *ret {
+                Some(ref r) => Some(Box::new(map_type(r,types)?)),
+                None => None
+            };
+            Type::Function(ret,args.iter().map(|t| map_type(t,types)).collect::<Option<Vec<_>>>()?,va)
+        },
+        ref t => t.clone()
+    })
+}
+
+fn compatible(src: &Type,dst: &Type,types: &HashMap<String,String>) -> bool {
+    *src==Type::Opaque || *dst==Type::Opaque || map_type(src,types).as_ref()==Some(dst)
+}
+
+// Map the named types of the source module to types of the
+// destination, or to new names for the types to add.
+fn unify_types(dst: &Module,src: &Module) -> HashMap<String,String> {
+    let mut src_names: Vec<&String> = src.types.keys().collect();
+    src_names.sort();
+    let mut dst_names: Vec<&String> = dst.types.keys().collect();
+    dst_names.sort();
+    // Types of the same name, as long as they agree. Removing a type
+    // can make others disagree, which handles recursive types.
+    let mut map: HashMap<String,String> = src_names.iter()
+        .filter(|n| dst.types.contains_key(**n))
+        .map(|n| (n.to_string(),n.to_string()))
+        .collect();
+    loop {
+        let wrong: Vec<String> = map.iter()
+            .filter(|e| !compatible(&src.types[e.0],&dst.types[e.1],&map))
+            .map(|e| e.0.clone())
+            .collect();
+        if wrong.is_empty() {
+            break
+        }
+        for n in wrong.iter() {
+            map.remove(n);
+        }
+    }
+    // Structurally identical types of other names
+    for name in src_names.iter() {
+        if map.contains_key(*name) || src.types[*name]==Type::Opaque {
+            continue
+        }
+        for d in dst_names.iter() {
+            if dst.types[*d]==Type::Opaque {
+                continue
+            }
+            map.insert(name.to_string(),d.to_string());
+            if compatible(&src.types[*name],&dst.types[*d],&map) {
+                break
+            }
+            map.remove(*name);
+        }
+    }
+    let mut used: HashSet<String> = dst.types.keys().cloned().collect();
+    for name in src_names.iter() {
+        if !map.contains_key(*name) {
+            map.insert(name.to_string(),fresh_name(&mut used,name));
+        }
+    }
+    map
+}
+
+// Share identical attribute groups and give the others unused ids.
+fn unify_attribute_groups(dst: &Module,src: &Module) -> HashMap<AttributeGroup,AttributeGroup> {
+    let mut next = dst.attr_groups.keys().max().map_or(0,|m| m+1);
+    let mut ids: Vec<&AttributeGroup> = src.attr_groups.keys().collect();
+    ids.sort();
+    let mut map = HashMap::new();
+    for id in ids {
+        let attrs = &src.attr_groups[id];
+        let mut same: Vec<&AttributeGroup> = dst.attr_groups.iter().filter(|g| g.1==attrs).map(|g| g.0).collect();
+        same.sort();
+        let new = match same.first() {
+            Some(d) => **d,
+            None => {
+                next += 1;
+                next-1
+            }
+        };
+        map.insert(*id,new);
+    }
+    map
+}
+
+fn merge_named_metadata(dst: &mut Metadata,src: Metadata) {
+    match (dst,src) {
+        (&mut Metadata::Struct(ref mut d),Metadata::Struct(s)) => for el in s {
+            if !d.contains(&el) {
+                d.push(el)
+            }
+        },
+        (d,s) => if *d!=s {
+            *d = Metadata::Struct(vec![d.clone(),s])
+        }
+    }
+}
+
+fn merge_appending(name: &str,dst: &mut GlobalVariable,src: GlobalVariable) -> Result<(),LinkError> {
+    let (n,el) = match (&dst.types,&src.types) {
+        (&Type::Array(n,ref d),&Type::Array(m,ref s)) if d==s => (n+m,d.clone()),
+        _ => return Err(LinkError::AppendingMismatch(name.to_string()))
+    };
+    let mut els = match dst.initialization.take() {
+        Some(Constant::Array(els)) => els,
+        _ => Vec::new()
+    };
+    if let Some(Constant::Array(s)) = src.initialization {
+        els.extend(s);
+    }
+    dst.types = Type::Array(n,el);
+    dst.initialization = Some(Constant::Array(els));
+    Ok(())
+}
+
+/// Link a module into another one.
+pub fn link_into(dst: &mut Module,mut src: Module) -> Result<(),LinkError> {
+    // Rename local symbols whose name is used in the other module
+    let mut used: HashSet<String> = dst.functions.keys().chain(dst.globals.keys())
+        .chain(src.functions.keys()).chain(src.globals.keys())
+        .cloned().collect();
+    let mut src_names: Vec<String> = src.functions.keys().chain(src.globals.keys()).cloned().collect();
+    src_names.sort();
+    let mut src_symbols = HashMap::new();
+    let mut dst_symbols = HashMap::new();
+    for name in src_names.iter() {
+        let dst_linkage = match symbol_linkage(dst,name) {
+            Some(l) => l,
+            None => continue
+        };
+        if is_local(symbol_linkage(&src,name).unwrap()) {
+            src_symbols.insert(name.clone(),fresh_name(&mut used,name));
+        } else if is_local(dst_linkage) {
+            dst_symbols.insert(name.clone(),fresh_name(&mut used,name));
+        }
+    }
+    if !dst_symbols.is_empty() {
+        remap_module(dst,Remap { symbols: dst_symbols, ..Remap::default() });
+    }
+    let md_offset = dst.md.keys().max().map_or(0,|m| m+1);
+    let remap = Remap { types: unify_types(dst,&src),
+                        symbols: src_symbols,
+                        attrs: unify_attribute_groups(dst,&src),
+                        md_offset };
+    remap_module(&mut src,remap);
+
+    if dst.id.is_none() {
+        dst.id = src.id;
+    }
+    if dst.triple.is_none() {
+        dst.triple = src.triple;
+    }
+    if dst.datalayout==DataLayout::new() {
+        dst.datalayout = src.datalayout;
+    }
+    for (name,tp) in src.types {
+        let opaque = dst.types.get(&name).is_none_or(|t| *t==Type::Opaque);
+        if opaque {
+            dst.types.insert(name,tp);
+        }
+    }
+    dst.attr_groups.extend(src.attr_groups);
+    dst.md.extend(src.md);
+    for (name,md) in src.named_md {
+        match dst.named_md.get_mut(&name) {
+            Some(d) => merge_named_metadata(d,md),
+            None => {
+                dst.named_md.insert(name,md);
+            }
+        }
+    }
+
+    let mut names: Vec<String> = src.functions.keys().chain(src.globals.keys()).cloned().collect();
+    names.sort();
+    for name in names {
+        if let Some(f) = src.functions.remove(&name) {
+            if dst.globals.contains_key(&name) {
+                return Err(LinkError::KindMismatch(name))
+            }
+            let keep = match dst.functions.get(&name) {
+                None => false,
+                Some(d) => {
+                    let (ds,ss) = (strength(d.linkage,d.is_defined()),strength(f.linkage,f.is_defined()));
+                    if ds==Strength::Strong && ss==Strength::Strong {
+                        return Err(LinkError::DuplicateDefinition(name))
+                    }
+                    ds>=ss
+                }
+            };
+            if !keep {
+                dst.functions.insert(name,f);
+            }
+        } else {
+            let g = src.globals.remove(&name).unwrap();
+            if dst.functions.contains_key(&name) {
+                return Err(LinkError::KindMismatch(name))
+            }
+            let keep = match dst.globals.get_mut(&name) {
+                None => false,
+                Some(d) => {
+                    let appending = Some(Linkage::Appending);
+                    if d.linkage==appending || g.linkage==appending {
+                        if d.linkage!=g.linkage {
+                            return Err(LinkError::AppendingMismatch(name))
+                        }
+                        merge_appending(&name,d,g)?;
+                        continue
+                    }
+                    let (ds,ss) = (strength(d.linkage,d.initialization.is_some()),
+                                   strength(g.linkage,g.initialization.is_some()));
+                    if ds==Strength::Strong && ss==Strength::Strong {
+                        return Err(LinkError::DuplicateDefinition(name))
+                    }
+                    if ds==Strength::Common && ss==Strength::Common {
+                        let dl = &dst.datalayout;
+                        dl.type_alloc_size(&d.types,&dst.types)?>=dl.type_alloc_size(&g.types,&dst.types)?
+                    } else {
+                        ds>=ss
+                    }
+                }
+            };
+            if !keep {
+                dst.globals.insert(name,g);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_link() {
+    let a = parse_test_module(b"%struct.S = type { i32, %struct.S* }
+%struct.P = type { i32, i32 }
+%struct.C = type { i8 }
+
+@counter = common global i32 0, align 4
+@big = common global i16 0, align 2
+@llvm.used = appending global [1 x i8*] [i8* bitcast (i32 ()* @helper to i8*)], section \"llvm.metadata\"
+
+declare i32 @f(%struct.S*)
+
+define weak i32 @g() {
+entry:
+  ret i32 1
+}
+
+define internal i32 @helper() #0 {
+entry:
+  ret i32 10
+}
+
+define i32 @main() #0 {
+entry:
+  %s = alloca %struct.S, align 8
+  %p = alloca %struct.P, align 4
+  %a = call i32 @f(%struct.S* %s), !dbg !1
+  %b = call i32 @g()
+  %c = call i32 @helper()
+  %d = add i32 %a, %b
+  %e = add i32 %d, %c
+  ret i32 %e
+}
+
+attributes #0 = { nounwind }
+
+!llvm.ident = !{!0}
+
+!0 = !{!\"a\"}
+!1 = !{!\"loc\"}
+");
+    let b = parse_test_module(b"%struct.S = type { i32, %struct.S* }
+%struct.Q = type { i32, i32 }
+%struct.C = type { i16 }
+
+@counter = common global i32 0, align 4
+@big = common global i64 0, align 8
+@llvm.used = appending global [1 x i8*] [i8* bitcast (i32 ()* @helper to i8*)], section \"llvm.metadata\"
+
+define i32 @f(%struct.S* %s) #1 {
+entry:
+  %c = alloca %struct.C, align 2
+  %q = alloca %struct.Q, align 4
+  %h = call i32 @helper(), !dbg !1
+  ret i32 %h
+}
+
+define i32 @g() #0 {
+entry:
+  ret i32 2
+}
+
+define internal i32 @helper() #0 {
+entry:
+  ret i32 100
+}
+
+attributes #0 = { nounwind }
+attributes #1 = { noinline }
+
+!llvm.ident = !{!0}
+
+!0 = !{!\"b\"}
+!1 = !{!\"loc\"}
+");
+    let m = link(vec![a,b]).unwrap();
+    let mut names: Vec<&String> = m.functions.keys().collect();
+    names.sort();
+    assert_eq!(names,vec!["f","g","helper","helper.0","main"]);
+    assert!(m.functions["f"].is_defined());
+    // The external definition of @g wins over the weak one
+    let mut interp = interp::Interpreter::new(&m).unwrap();
+    assert_eq!(interp.run_function("main",vec![]),
+               Ok(interp::Outcome::Returned(Some(interp::Val::from_i64(32,112)))));
+
+    let mut types: Vec<&String> = m.types.keys().collect();
+    types.sort();
+    assert_eq!(types,vec!["struct.C","struct.C.0","struct.P","struct.S"]);
+    match m.functions["f"].body.as_ref().unwrap()[0].instrs[1].content {
+        InstructionC::Alloca(_,ref tp,..) => assert_eq!(*tp,Type::Named("struct.P".to_string())),
+        ref i => panic!("unexpected instruction {:?}",i)
+    }
+    assert_eq!(m.globals["big"].types,Type::Int(64));
+    assert_eq!(m.globals["llvm.used"].types,Type::array(2,Type::ptr(Type::Int(8))));
+    match m.globals["llvm.used"].initialization {
+        Some(Constant::Array(ref els)) => assert_eq!(els[1],
+            Constant::Cast(CastInst::Bitcast,
+                           Box::new(Typed::new(Type::ptr(Type::Function(Some(Box::new(Type::Int(32))),vec![],false)),
+                                               Constant::Global("helper.0".to_string()))),
+                           Type::ptr(Type::Int(8)))),
+        ref c => panic!("unexpected initializer {:?}",c)
+    }
+
+    let mut groups: Vec<(&u64,&Vec<Attribute>)> = m.attr_groups.iter().collect();
+    groups.sort();
+    assert_eq!(groups.len(),2);
+    assert_eq!(m.functions["f"].attribute_groups,vec![1]);
+    assert_eq!(m.functions["helper.0"].attribute_groups,vec![0]);
+    assert_eq!(m.md.len(),4);
+    assert_eq!(m.named_md["llvm.ident"],Metadata::Struct(vec![Metadata::Ref(0),Metadata::Ref(2)]));
+    assert_eq!(m.functions["f"].body.as_ref().unwrap()[0].instrs[2].metadata["dbg"],3);
+}
+
+#[test]
+fn test_link_errors() {
+    let a = parse_test_module(b"@x = global i32 0
+
+define i32 @f() {
+entry:
+  ret i32 0
+}");
+    let b = parse_test_module(b"define i32 @f() {
+entry:
+  ret i32 1
+}");
+    let c = parse_test_module(b"declare i32 @x()");
+    let d = parse_test_module(b"define linkonce_odr i32 @f() {
+entry:
+  ret i32 2
+}");
+    assert_eq!(link(vec![a.clone(),b]),Err(LinkError::DuplicateDefinition("f".to_string())));
+    assert_eq!(link(vec![a.clone(),c]),Err(LinkError::KindMismatch("x".to_string())));
+    let m = link(vec![d,a.clone()]).unwrap();
+    assert_eq!(m.functions["f"],a.functions["f"]);
+    assert_eq!(link(vec![]),Ok(Module::new()));
+}
+
+#[test]
+fn test_link_minisat() {
+    let minisat = minisat();
+    assert_eq!(link(vec![minisat.clone()]).as_ref(),Ok(&minisat));
+    // Linking the solver into a copy of itself without its main function
+    // only conflicts on external definitions
+    let mut lib = minisat.clone();
+    lib.functions.remove("main");
+    assert!(matches!(link(vec![minisat.clone(),lib.clone()]),Err(LinkError::DuplicateDefinition(_))));
+    // With only declarations for the external symbols, the internal
+    // ones are duplicated
+    for f in lib.functions.values_mut() {
+        if !is_local(f.linkage) {
+            f.body = None;
+        }
+    }
+    for g in lib.globals.values_mut() {
+        if !is_local(g.linkage) {
+            g.linkage = Some(Linkage::External);
+            g.initialization = None;
+        }
+    }
+    let m = link(vec![minisat.clone(),lib.clone()]).unwrap();
+    let internal = lib.functions.values().filter(|f| is_local(f.linkage)).count();
+    assert_eq!(m.functions.len(),minisat.functions.len()+internal);
+    assert_eq!(m.types,minisat.types);
+    assert_eq!(m.md.len(),minisat.md.len()+lib.md.len());
+    let mut interp = interp::Interpreter::new(&m).unwrap();
+    match interp.run_main(&["minisat","sample.cnf"]) {
+        Err(interp::ExecError::AssertionFailed(ref msg)) => assert!(msg.starts_with("solver.c:721:"),"{}",msg),
+        res => panic!("unexpected result {:?}",res)
+    }
+}
@@ -0,0 +1,310 @@
+//! Natural loop detection and the loop nesting forest of a function.
+//!
+//! Loops are found from the back edges of the control-flow graph, i.e.
+//! edges whose target dominates their source. Natural loops sharing a
+//! header are merged into one loop.
+use super::Function;
+use cfg::{ControlFlowGraph,DominatorTree};
+
+/// Index of a loop in a `LoopInfo`.
+pub type LoopId = usize;
+
+/// A control-flow edge between two block indices.
+pub type Edge = (usize,usize);
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Loop {
+    /// The block all back edges of the loop jump to.
+    pub header: usize,
+    /// All blocks of the loop (including nested loops), sorted.
+    pub blocks: Vec<usize>,
+    /// The sources of the back edges of the loop.
+    pub latches: Vec<usize>,
+    /// Edges leaving the loop as `(exiting block,exit block)`.
+    pub exits: Vec<Edge>,
+    /// The unique block outside the loop that jumps to the header and
+    /// nowhere else, if there is one.
+    pub preheader: Option<usize>,
+    pub parent: Option<LoopId>,
+    pub children: Vec<LoopId>,
+    /// Nesting depth, top level loops have depth 1.
+    pub depth: usize
+}
+
+impl Loop {
+    pub fn contains(&self,blk: usize) -> bool {
+        self.blocks.binary_search(&blk).is_ok()
+    }
+    /// The distinct blocks outside the loop that are jumped to from
+    /// inside of it.
+    pub fn exit_blocks(&self) -> Vec<usize> {
+        let mut res: Vec<usize> = self.exits.iter().map(|&(_,e)| e).collect();
+        res.sort();
+        res.dedup();
+        res
+    }
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct LoopInfo {
+    loops: Vec<Loop>,
+    innermost: Vec<Option<LoopId>>,
+    back_edges: Vec<Edge>,
+    irreducible_edges: Vec<Edge>
+}
+
+impl LoopInfo {
+    pub fn new(cfg: &ControlFlowGraph,dt: &DominatorTree) -> LoopInfo {
+        let reach = cfg.reachable();
+        let (back_edges,irreducible_edges) = classify_retreating_edges(cfg,dt);
+
+        // Collect the natural loops, one per header
+        let mut headers: Vec<usize> = back_edges.iter().map(|&(_,h)| h).collect();
+        headers.sort();
+        headers.dedup();
+        let mut loops: Vec<Loop> = headers.iter().map(|&h| {
+            let latches: Vec<usize> = back_edges.iter()
+                .filter(|&&(_,trg)| trg==h)
+                .map(|&(src,_)| src)
+                .collect();
+            let mut in_loop = vec![false; cfg.len()];
+            in_loop[h] = true;
+            let mut stack = latches.clone();
+            while let Some(b) = stack.pop() {
+                if in_loop[b] || !reach[b] {
+                    continue
+                }
+                in_loop[b] = true;
+                stack.extend_from_slice(cfg.predecessors(b));
+            }
+            let blocks: Vec<usize> = (0..cfg.len()).filter(|&b| in_loop[b]).collect();
+            let mut exits = Vec::new();
+            for &b in blocks.iter() {
+                for &s in cfg.successors(b) {
+                    if !in_loop[s] {
+                        exits.push((b,s));
+                    }
+                }
+            }
+            let outside: Vec<usize> = cfg.predecessors(h).iter()
+                .cloned()
+                .filter(|&p| !in_loop[p] && reach[p])
+                .collect();
+            let preheader = if outside.len()==1 && cfg.successors(outside[0])==[h] {
+                Some(outside[0])
+            } else {
+                None
+            };
+            Loop { header: h,
+                   blocks,
+                   latches,
+                   exits,
+                   preheader,
+                   parent: None,
+                   children: Vec::new(),
+                   depth: 1 }
+        }).collect();
+
+        // Build the nesting forest: the parent of a loop is the
+        // smallest other loop containing its header.
+        let mut by_size: Vec<LoopId> = (0..loops.len()).collect();
+        by_size.sort_by_key(|&l| loops[l].blocks.len());
+        let mut innermost = vec![None; cfg.len()];
+        for (i,&l) in by_size.iter().enumerate() {
+            for &b in loops[l].blocks.iter() {
+                if innermost[b].is_none() {
+                    innermost[b] = Some(l);
+                }
+            }
+            let h = loops[l].header;
+            let parent = by_size[i+1..].iter()
+                .cloned()
+                .find(|&p| loops[p].contains(h));
+            loops[l].parent = parent;
+            if let Some(p) = parent {
+                loops[p].children.push(l);
+            }
+        }
+        for &l in by_size.iter().rev() {
+            if let Some(p) = loops[l].parent {
+                loops[l].depth = loops[p].depth+1;
+            }
+        }
+        LoopInfo { loops,
+                   innermost,
+                   back_edges,
+                   irreducible_edges }
+    }
+    /// Compute the loop information of a function body, `None` for
+    /// declarations.
+    pub fn for_function(fun: &Function) -> Option<LoopInfo> {
+        ControlFlowGraph::from_function(fun).map(|cfg| {
+            let dt = DominatorTree::new(&cfg);
+            LoopInfo::new(&cfg,&dt)
+        })
+    }
+    pub fn loops(&self) -> &[Loop] {
+        &self.loops
+    }
+    pub fn get(&self,l: LoopId) -> &Loop {
+        &self.loops[l]
+    }
+    /// The loops that are not nested in any other loop.
+    pub fn top_level(&self) -> Vec<LoopId> {
+        (0..self.loops.len()).filter(|&l| self.loops[l].parent.is_none()).collect()
+    }
+    /// The innermost loop containing a block.
+    pub fn loop_for(&self,blk: usize) -> Option<LoopId> {
+        self.innermost[blk]
+    }
+    /// The number of loops containing a block, 0 outside of loops.
+    pub fn loop_depth(&self,blk: usize) -> usize {
+        match self.innermost[blk] {
+            None => 0,
+            Some(l) => self.loops[l].depth
+        }
+    }
+    pub fn is_header(&self,blk: usize) -> bool {
+        self.loops.iter().any(|l| l.header==blk)
+    }
+    /// All edges `(latch,header)` whose target dominates their source.
+    pub fn back_edges(&self) -> &[Edge] {
+        &self.back_edges
+    }
+    /// Whether the function contains loops with more than one entry,
+    /// which are not described by the natural loops.
+    pub fn is_irreducible(&self) -> bool {
+        !self.irreducible_edges.is_empty()
+    }
+    /// Retreating edges of a depth-first traversal that are not back
+    /// edges. Each of them enters a cycle that has no single header.
+    pub fn irreducible_edges(&self) -> &[Edge] {
+        &self.irreducible_edges
+    }
+}
+
+/// Split the retreating edges of a depth-first search from the entry
+/// into back edges and edges that witness irreducible control flow.
+fn classify_retreating_edges(cfg: &ControlFlowGraph,dt: &DominatorTree)
+                             -> (Vec<Edge>,Vec<Edge>) {
+    let mut back = Vec::new();
+    let mut irreducible = Vec::new();
+    if cfg.is_empty() {
+        return (back,irreducible)
+    }
+    let mut visited = vec![false; cfg.len()];
+    let mut on_stack = vec![false; cfg.len()];
+    let mut stack = vec![(cfg.entry(),0)];
+    visited[cfg.entry()] = true;
+    on_stack[cfg.entry()] = true;
+    while let Some(&mut (node,ref mut next)) = stack.last_mut() {
+        let succs = cfg.successors(node);
+        if *next < succs.len() {
+            let succ = succs[*next];
+            *next += 1;
+            if on_stack[succ] {
+                if dt.dominates(succ,node) {
+                    back.push((node,succ));
+                } else {
+                    irreducible.push((node,succ));
+                }
+            } else if !visited[succ] {
+                visited[succ] = true;
+                on_stack[succ] = true;
+                stack.push((succ,0));
+            }
+        } else {
+            on_stack[node] = false;
+            stack.pop();
+        }
+    }
+    back.sort();
+    irreducible.sort();
+    (back,irreducible)
+}
+
+#[test]
+fn test_loops_minisat() {
+    let m = ::minisat();
+    let fun = &m.functions["main"];
+    let cfg = ControlFlowGraph::from_function(fun).unwrap();
+    let li = LoopInfo::for_function(fun).unwrap();
+    let blk = |n: &str| cfg.block_index(n).unwrap();
+    assert_eq!(li.loops().len(),1);
+    assert!(!li.is_irreducible());
+    let l = li.get(0);
+    assert_eq!(l.header,blk("for.cond"));
+    assert_eq!(l.latches,vec![blk("for.inc")]);
+    assert_eq!(l.preheader,Some(blk("if.then21")));
+    assert_eq!(l.exit_blocks(),vec![blk("for.end")]);
+    assert_eq!(li.loop_depth(blk("for.body")),1);
+    assert_eq!(li.loop_depth(blk("entry")),0);
+
+    for fun in m.functions.values() {
+        let li = match LoopInfo::for_function(fun) {
+            Some(li) => li,
+            None => continue
+        };
+        assert!(!li.is_irreducible(),"{} is irreducible",fun.name);
+        for l in li.loops() {
+            assert!(l.contains(l.header));
+            for &latch in l.latches.iter() {
+                assert!(l.contains(latch));
+            }
+            if let Some(p) = l.parent {
+                let pl = li.get(p);
+                assert_eq!(l.depth,pl.depth+1);
+                assert!(l.blocks.iter().all(|&b| pl.contains(b)));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_nested_loops() {
+    let fun = ::parse_test_function(b"define void @f(i1 %c) {
+entry:
+  br label %outer
+outer:
+  br label %inner
+inner:
+  br i1 %c, label %inner, label %outer.latch
+outer.latch:
+  br i1 %c, label %outer, label %exit
+exit:
+  ret void
+}");
+    let li = LoopInfo::for_function(&fun).unwrap();
+    assert_eq!(li.loops().len(),2);
+    assert_eq!(li.top_level().len(),1);
+    let outer = li.get(li.top_level()[0]);
+    assert_eq!(outer.header,1);
+    assert_eq!(outer.blocks,vec![1,2,3]);
+    assert_eq!(outer.exits,vec![(3,4)]);
+    assert_eq!(outer.preheader,Some(0));
+    let inner = li.get(outer.children[0]);
+    assert_eq!(inner.header,2);
+    assert_eq!(inner.blocks,vec![2]);
+    assert_eq!(inner.depth,2);
+    assert_eq!(inner.preheader,Some(1));
+    assert_eq!(li.loop_depth(2),2);
+    assert_eq!(li.back_edges(),&[(2,2),(3,1)]);
+}
+
+#[test]
+fn test_irreducible() {
+    let fun = ::parse_test_function(b"define void @f(i1 %c) {
+entry:
+  br i1 %c, label %a, label %b
+a:
+  br i1 %c, label %b, label %exit
+b:
+  br label %a
+exit:
+  ret void
+}");
+    let li = LoopInfo::for_function(&fun).unwrap();
+    assert!(li.is_irreducible());
+    assert!(li.loops().is_empty());
+    assert_eq!(li.irreducible_edges().len(),1);
+}
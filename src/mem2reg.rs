@@ -0,0 +1,480 @@
+//! Promotion of stack slots to SSA values ("mem2reg").
+//!
+//! An `alloca` of a single scalar whose address is only used by
+//! non-volatile loads and stores of the allocated type is replaced by
+//! SSA values. Phi nodes are placed at the iterated dominance frontier
+//! of the stores, restricted to the blocks where the slot is live
+//! (pruned SSA), and values are renamed along the dominator tree.
+//! Loads before the first store read `undef`.
+//!
+//! Calls of `llvm.dbg.declare` describing a promoted slot are removed,
+//! so debug information about promoted variables is lost.
+use std::collections::{HashMap,HashSet};
+use super::*;
+use builder::resolve_type;
+use cfg::{ControlFlowGraph,DominatorTree};
+use passes::{fresh_name,AnalysisManager,CfgAnalysis,DominatorAnalysis,FunctionPass,Preserved};
+use ssa::Position;
+use visit::{Visitor,VisitorMut};
+
+/// The pass promoting all promotable allocas of a function.
+pub struct PromoteAllocas;
+
+impl FunctionPass for PromoteAllocas {
+    fn name(&self) -> &str {
+        "mem2reg"
+    }
+    fn run_on_function(&mut self,types: &OrderedMap<String,Type>,fun: &mut Function,am: &mut AnalysisManager) -> Preserved {
+        if promotable_allocas(types,fun).is_empty() {
+            return Preserved::All
+        }
+        let cfg = am.get::<CfgAnalysis>(fun).clone();
+        let dt = am.get::<DominatorAnalysis>(fun).clone();
+        promote(types,fun,&cfg,&dt);
+        Preserved::Cfg
+    }
+}
+
+fn is_scalar(types: &OrderedMap<String,Type>,tp: &Type) -> bool {
+    matches!(*resolve_type(types,tp),
+             Type::Int(_) | Type::Pointer(..) | Type::Float | Type::Double |
+             Type::PPC_FP128 | Type::FP128 | Type::X86_FP80)
+}
+
+fn is_dbg_declare(instr: &InstructionC) -> bool {
+    matches!(*instr,InstructionC::Call(_,_,_,Value::Constant(Constant::Global(ref f)),_,_) if f=="llvm.dbg.declare")
+}
+
+// The promotable alloca accessed by an instruction and whether it is a
+// load (otherwise a store).
+fn access(slots: &HashMap<&str,usize>,instr: &InstructionC) -> Option<(usize,bool)> {
+    match *instr {
+        InstructionC::Unary(_,ref ptr,UnaryInst::Load(false,_)) => match ptr.val {
+            Value::Local(ref p) => slots.get(&p[..]).map(|s| (*s,true)),
+            _ => None
+        },
+        InstructionC::Store(false,_,ref ptr,_) => match ptr.val {
+            Value::Local(ref p) => slots.get(&p[..]).map(|s| (*s,false)),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+// Collects the locals used by an instruction.
+struct UsedLocals(Vec<String>);
+
+impl Visitor for UsedLocals {
+    fn visit_value(&mut self,v: &Value) {
+        if let Value::Local(ref name) = *v {
+            self.0.push(name.clone());
+        }
+        visit::walk_value(self,v)
+    }
+}
+
+/// The allocas of a function that can be promoted, with the allocated
+/// types, in the order they appear.
+pub fn promotable_allocas(types: &OrderedMap<String,Type>,fun: &Function) -> Vec<(String,Type)> {
+    let blks = match fun.body {
+        Some(ref blks) => blks,
+        None => return Vec::new()
+    };
+    let mut candidates: Vec<(&str,&Type)> = Vec::new();
+    for instr in blks.iter().flat_map(|b| b.instrs.iter()) {
+        if let InstructionC::Alloca(ref name,ref tp,ref num,_) = instr.content {
+            let single = match *num {
+                None => true,
+                Some(ref n) => matches!(n.val,Value::Constant(Constant::Int(ref i)) if *i==1.into())
+            };
+            if single && is_scalar(types,tp) {
+                candidates.push((name,tp));
+            }
+        }
+    }
+    let slots: HashMap<&str,usize> = candidates.iter().enumerate().map(|(i,c)| (c.0,i)).collect();
+    let mut escaped = vec![false; candidates.len()];
+    for instr in blks.iter().flat_map(|b| b.instrs.iter()) {
+        if let InstructionC::Alloca(..) = instr.content {
+            continue
+        }
+        let accessed = match (access(&slots,&instr.content),&instr.content) {
+            (Some((s,true)),InstructionC::Unary(_,ptr,_)) => {
+                let tp = candidates[s].1;
+                if ptr.tp!=Type::ptr(tp.clone()) {
+                    escaped[s] = true;
+                }
+                Some(s)
+            },
+            (Some((s,false)),InstructionC::Store(_,v,_,_)) => {
+                let tp = candidates[s].1;
+                if v.tp!=*tp {
+                    escaped[s] = true;
+                }
+                Some(s)
+            },
+            _ => None
+        };
+        if is_dbg_declare(&instr.content) {
+            continue
+        }
+        // Every other use, including storing the address, escapes
+        let mut used = UsedLocals(Vec::new());
+        match instr.content {
+            InstructionC::Store(_,ref v,_,_) if accessed.is_some() => used.visit_typed_value(v),
+            _ if accessed.is_some() => {},
+            ref c => used.visit_instruction_c(c)
+        }
+        for name in used.0.iter() {
+            if let Some(s) = slots.get(&name[..]) {
+                escaped[*s] = true;
+            }
+        }
+    }
+    candidates.into_iter()
+        .zip(escaped)
+        .filter(|c| !c.1)
+        .map(|c| (c.0 .0.to_string(),c.0 .1.clone()))
+        .collect()
+}
+
+struct Rename<'a>(&'a HashMap<String,Value>);
+
+impl<'a> VisitorMut for Rename<'a> {
+    fn visit_value_mut(&mut self,v: &mut Value) {
+        let new = match *v {
+            Value::Local(ref name) => self.0.get(name).cloned(),
+            _ => None
+        };
+        match new {
+            Some(new) => *v = new,
+            None => visit::walk_value_mut(self,v)
+        }
+    }
+}
+
+// A phi placed for a slot: its name and incoming values.
+type Phi = (String,Vec<(Value,String)>);
+
+// Follow replaced loads to the value they stand for.
+fn resolve(loads: &HashMap<String,Value>,v: &Value) -> Value {
+    let mut cur = v;
+    for _ in 0..=loads.len() {
+        match *cur {
+            Value::Local(ref name) if loads.contains_key(name) => cur = &loads[name],
+            _ => break
+        }
+    }
+    cur.clone()
+}
+
+/// Promote the promotable allocas of a function, given its control-flow
+/// graph and dominator tree. Returns the number of promoted allocas.
+pub fn promote(types: &OrderedMap<String,Type>,fun: &mut Function,cfg: &ControlFlowGraph,dt: &DominatorTree) -> usize {
+    let allocas = promotable_allocas(types,fun);
+    if allocas.is_empty() {
+        return 0
+    }
+    let mut names: HashSet<String> = fun.arguments.iter().filter_map(|a| a.0.clone()).collect();
+    let blks = fun.body.as_mut().unwrap();
+    names.extend(blks.iter().flat_map(|b| b.instrs.iter()).filter_map(|i| i.content.name().map(|n| n.to_string())));
+    let slots: HashMap<&str,usize> = allocas.iter().enumerate().map(|(i,a)| (&a.0[..],i)).collect();
+    let n = cfg.len();
+
+    // Blocks storing to a slot and blocks where it is live on entry
+    let mut defs = vec![Vec::new(); allocas.len()];
+    let mut live = vec![vec![false; n]; allocas.len()];
+    for (b,blk) in blks.iter().enumerate() {
+        let mut stored = HashSet::new();
+        for instr in blk.instrs.iter() {
+            match access(&slots,&instr.content) {
+                Some((s,true)) if !stored.contains(&s) => live[s][b] = true,
+                Some((s,false)) if stored.insert(s) => defs[s].push(b),
+                _ => {}
+            }
+        }
+    }
+    for (s,live) in live.iter_mut().enumerate() {
+        let mut work: Vec<usize> = (0..n).filter(|b| live[*b]).collect();
+        while let Some(b) = work.pop() {
+            for &p in cfg.predecessors(b) {
+                if !live[p] && !defs[s].contains(&p) {
+                    live[p] = true;
+                    work.push(p);
+                }
+            }
+        }
+    }
+
+    // Phi placement at the iterated dominance frontiers
+    let frontiers = dt.dominance_frontiers(cfg);
+    let mut phis: HashMap<(usize,usize),Phi> = HashMap::new();
+    for (s,defs) in defs.iter().enumerate() {
+        let mut work = defs.clone();
+        let mut placed = HashSet::new();
+        while let Some(b) = work.pop() {
+            for &f in frontiers[b].iter() {
+                if live[s][f] && placed.insert(f) {
+                    phis.insert((f,s),(fresh_name(&mut names,&allocas[s].0),Vec::new()));
+                    if !defs.contains(&f) {
+                        work.push(f);
+                    }
+                }
+            }
+        }
+    }
+
+    // Renaming along the dominator tree. Unreachable blocks start from
+    // undef values.
+    let undef = vec![Value::Constant(Constant::Undef); allocas.len()];
+    let mut loads: HashMap<String,Value> = HashMap::new();
+    let mut removed: HashSet<Position> = HashSet::new();
+    let mut work: Vec<(usize,Vec<Value>)> = dt.roots().iter().map(|r| (*r,undef.clone())).collect();
+    work.extend((0..n).filter(|b| !dt.contains(*b)).map(|b| (b,undef.clone())));
+    while let Some((b,mut vals)) = work.pop() {
+        for (s,val) in vals.iter_mut().enumerate() {
+            if let Some(phi) = phis.get(&(b,s)) {
+                *val = Value::Local(phi.0.clone());
+            }
+        }
+        for (i,instr) in blks[b].instrs.iter().enumerate() {
+            match (access(&slots,&instr.content),&instr.content) {
+                (Some((s,true)),InstructionC::Unary(name,_,_)) => {
+                    loads.insert(name.clone(),vals[s].clone());
+                },
+                (Some((s,false)),InstructionC::Store(_,v,_,_)) => {
+                    vals[s] = resolve(&loads,&v.val);
+                },
+                (_,InstructionC::Alloca(name,..)) if slots.contains_key(&name[..]) => {},
+                (_,c) if is_dbg_declare(c) => {
+                    let mut used = UsedLocals(Vec::new());
+                    used.visit_instruction_c(c);
+                    if !used.0.iter().any(|u| slots.contains_key(&u[..])) {
+                        continue
+                    }
+                },
+                _ => continue
+            }
+            removed.insert(Position { block: b, instr: i });
+        }
+        let from = &blks[b].name;
+        if let Some(term) = blks[b].terminator() {
+            for trg in term.targets() {
+                let t = match cfg.block_index(trg) {
+                    Some(t) => t,
+                    None => continue
+                };
+                for (s,val) in vals.iter().enumerate() {
+                    if let Some(phi) = phis.get_mut(&(t,s)) {
+                        phi.1.push((val.clone(),from.clone()));
+                    }
+                }
+            }
+        }
+        if dt.contains(b) {
+            for &c in dt.children(b) {
+                work.push((c,vals.clone()));
+            }
+        }
+    }
+
+    for (b,blk) in blks.iter_mut().enumerate() {
+        let mut i = 0;
+        blk.instrs.retain(|_| {
+            i += 1;
+            !removed.contains(&Position { block: b, instr: i-1 })
+        });
+        for s in (0..allocas.len()).rev() {
+            if let Some((name,inc)) = phis.remove(&(b,s)) {
+                let mut inc: Vec<(Value,String)> = inc.into_iter().map(|(v,l)| (resolve(&loads,&v),l)).collect();
+                inc.sort_by_key(|i| cfg.block_index(&i.1));
+                blk.instrs.insert(0,Instruction { content: InstructionC::Phi(name,allocas[s].1.clone(),inc),
+                                                  metadata: OrderedMap::new() });
+            }
+        }
+    }
+    let loads: HashMap<String,Value> = loads.keys().map(|k| (k.clone(),resolve(&loads,&loads[k]))).collect();
+    Rename(&loads).visit_function_mut(fun);
+    allocas.len()
+}
+
+#[cfg(test)]
+fn run_pass(m: &mut Module,name: &str) -> Preserved {
+    let fun = m.functions.get_mut(name).unwrap();
+    PromoteAllocas.run_on_function(&m.types,fun,&mut AnalysisManager::new())
+}
+
+#[test]
+fn test_promote_loop() {
+    let src = b"define i32 @sum(i32 %n) {
+entry:
+  %n.addr = alloca i32, align 4
+  %s = alloca i32, align 4
+  %i = alloca i32, align 4
+  %unused = alloca i32, align 4
+  store i32 %n, i32* %n.addr, align 4
+  call void @llvm.dbg.declare(metadata i32* %s, metadata !1, metadata !2)
+  store i32 0, i32* %s, align 4
+  store i32 0, i32* %i, align 4
+  br label %for.cond
+
+for.cond:
+  %0 = load i32* %i, align 4
+  %1 = load i32* %n.addr, align 4
+  %cmp = icmp slt i32 %0, %1
+  br i1 %cmp, label %for.body, label %for.end
+
+for.body:
+  %2 = load i32* %i, align 4
+  %3 = load i32* %s, align 4
+  %add = add nsw i32 %3, %2
+  store i32 %add, i32* %s, align 4
+  %inc = add nsw i32 %2, 1
+  store i32 %inc, i32* %i, align 4
+  br label %for.cond
+
+for.end:
+  %4 = load i32* %s, align 4
+  ret i32 %4
+}
+
+declare void @llvm.dbg.declare(metadata, metadata, metadata)
+";
+    let orig = parse_test_module(src);
+    let mut m = orig.clone();
+    assert_eq!(promotable_allocas(&m.types,&m.functions["sum"]).len(),4);
+    assert_eq!(run_pass(&mut m,"sum"),Preserved::Cfg);
+    let fun = &m.functions["sum"];
+    let blks = fun.body.as_ref().unwrap();
+    for instr in blks.iter().flat_map(|b| b.instrs.iter()) {
+        match instr.content {
+            InstructionC::Alloca(..) | InstructionC::Store(..) |
+            InstructionC::Unary(_,_,UnaryInst::Load(..)) => panic!("unpromoted access {:?}",instr),
+            InstructionC::Call(..) => panic!("debug declaration kept"),
+            _ => {}
+        }
+    }
+    // %n is never stored inside the loop and needs no phi
+    assert_eq!(blks[1].instrs[0].content,
+               InstructionC::Phi("s.0".to_string(),Type::Int(32),
+                                 vec![(Value::Constant(Constant::Int(0.into())),"entry".to_string()),
+                                      (Value::Local("add".to_string()),"for.body".to_string())]));
+    assert_eq!(blks[1].instrs[1].content,
+               InstructionC::Phi("i.0".to_string(),Type::Int(32),
+                                 vec![(Value::Constant(Constant::Int(0.into())),"entry".to_string()),
+                                      (Value::Local("inc".to_string()),"for.body".to_string())]));
+    assert_eq!(blks[1].instrs[2].content,
+               InstructionC::ICmp("cmp".to_string(),CmpOp::SLt,Type::Int(32),
+                                  Value::Local("i.0".to_string()),Value::Argument(0)));
+    assert_eq!(blks[3].instrs[0].content,
+               InstructionC::Term(Terminator::Ret(Some(Typed { tp: Type::Int(32), val: Value::Local("s.0".to_string()) }))));
+    let mut before = interp::Interpreter::new(&orig).unwrap();
+    let mut after = interp::Interpreter::new(&m).unwrap();
+    for n in [0,1,5,10].iter() {
+        assert_eq!(before.run_function("sum",vec![interp::Val::from_i64(32,*n)]),
+                   after.run_function("sum",vec![interp::Val::from_i64(32,*n)]));
+    }
+}
+
+#[test]
+fn test_promote_escaping() {
+    let mut m = parse_test_module(b"declare void @use(i32*)
+
+define i32 @f(i1 %c) {
+entry:
+  %passed = alloca i32, align 4
+  %stored = alloca i32*, align 8
+  %cast = alloca i32, align 4
+  %volatile = alloca i32, align 4
+  %array = alloca i32, i32 2, align 4
+  %agg = alloca [2 x i32], align 4
+  %cond = alloca i32, align 4
+  call void @use(i32* %passed)
+  store i32* %passed, i32** %stored, align 8
+  %p = bitcast i32* %cast to i8*
+  store volatile i32 1, i32* %volatile, align 4
+  br i1 %c, label %then, label %join
+
+then:
+  store i32 1, i32* %cond, align 4
+  br label %join
+
+join:
+  %v = load i32* %cond, align 4
+  ret i32 %v
+}");
+    let promotable: Vec<String> = promotable_allocas(&m.types,&m.functions["f"]).into_iter().map(|a| a.0).collect();
+    assert_eq!(promotable,vec!["stored".to_string(),"cond".to_string()]);
+    assert_eq!(run_pass(&mut m,"f"),Preserved::Cfg);
+    let blks = m.functions["f"].body.as_ref().unwrap();
+    // Without a store on the path from the entry, the slot is undef
+    assert_eq!(blks[2].instrs[0].content,
+               InstructionC::Phi("cond.0".to_string(),Type::Int(32),
+                                 vec![(Value::Constant(Constant::Undef),"entry".to_string()),
+                                      (Value::Constant(Constant::Int(1.into())),"then".to_string())]));
+    assert_eq!(blks[0].instrs.iter().filter(|i| matches!(i.content,InstructionC::Alloca(..))).count(),5);
+    assert_eq!(run_pass(&mut m,"f"),Preserved::All);
+}
+
+#[test]
+fn test_promote_switch() {
+    let src = b"define i32 @f(i32 %x) {
+entry:
+  %r = alloca i32, align 4
+  store i32 10, i32* %r, align 4
+  switch i32 %x, label %other [ i32 0, label %zero
+                                i32 1, label %join
+                                i32 2, label %join ]
+
+zero:
+  store i32 20, i32* %r, align 4
+  br label %join
+
+other:
+  %y = add i32 %x, 1
+  store i32 %y, i32* %r, align 4
+  br label %join
+
+dead:
+  store i32 40, i32* %r, align 4
+  br label %join
+
+join:
+  %v = load i32* %r, align 4
+  ret i32 %v
+}";
+    let orig = parse_test_module(src);
+    let mut m = orig.clone();
+    assert_eq!(run_pass(&mut m,"f"),Preserved::Cfg);
+    let blks = m.functions["f"].body.as_ref().unwrap();
+    // One incoming value per edge, including the one from the
+    // unreachable block
+    assert_eq!(blks[4].instrs[0].content,
+               InstructionC::Phi("r.0".to_string(),Type::Int(32),
+                                 vec![(Value::Constant(Constant::Int(10.into())),"entry".to_string()),
+                                      (Value::Constant(Constant::Int(10.into())),"entry".to_string()),
+                                      (Value::Constant(Constant::Int(20.into())),"zero".to_string()),
+                                      (Value::Local("y".to_string()),"other".to_string()),
+                                      (Value::Constant(Constant::Int(40.into())),"dead".to_string())]));
+    let mut before = interp::Interpreter::new(&orig).unwrap();
+    let mut after = interp::Interpreter::new(&m).unwrap();
+    for x in 0..4 {
+        assert_eq!(before.run_function("f",vec![interp::Val::from_i64(32,x)]),
+                   after.run_function("f",vec![interp::Val::from_i64(32,x)]));
+    }
+}
+
+#[test]
+fn test_promote_minisat() {
+    // The fixture is already in SSA form, its few remaining allocas
+    // have their address taken
+    let mut m = minisat();
+    let orig = m.clone();
+    for fun in m.functions.values() {
+        assert_eq!(promotable_allocas(&m.types,fun),vec![],"{}",fun.name);
+    }
+    let mut pm = passes::PassManager::new();
+    pm.add_function_pass(PromoteAllocas);
+    assert!(!pm.run(&mut m));
+    assert!(m==orig);
+}
@@ -0,0 +1,213 @@
+//! A map that keeps the order in which keys were first inserted.
+//!
+//! The containers of a `Module` use it so that iterating over them
+//! follows the source and the output of the printer, the bitcode writer
+//! and anything that walks a module is the same from run to run.
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::iter::{FromIterator,Zip};
+use std::ops::Index;
+use std::slice;
+use std::vec;
+
+/// Entries are stored in insertion order, with a hash index from keys to
+/// positions. Replacing the value of a key keeps its position; removing
+/// a key shifts the later entries. Two maps are equal if they hold the
+/// same entries, in any order.
+#[derive(Clone)]
+pub struct OrderedMap<K,V> {
+    keys: Vec<K>,
+    values: Vec<V>,
+    index: HashMap<K,usize>
+}
+
+pub type Iter<'a,K,V> = Zip<slice::Iter<'a,K>,slice::Iter<'a,V>>;
+pub type IterMut<'a,K,V> = Zip<slice::Iter<'a,K>,slice::IterMut<'a,V>>;
+pub type IntoIter<K,V> = Zip<vec::IntoIter<K>,vec::IntoIter<V>>;
+pub type Drain<'a,K,V> = Zip<vec::Drain<'a,K>,vec::Drain<'a,V>>;
+
+impl<K: Hash+Eq+Clone,V> OrderedMap<K,V> {
+    pub fn new() -> OrderedMap<K,V> {
+        OrderedMap { keys: Vec::new(),
+                     values: Vec::new(),
+                     index: HashMap::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// The position of a key in insertion order.
+    pub fn position<Q: ?Sized+Hash+Eq>(&self,key: &Q) -> Option<usize> where K: Borrow<Q> {
+        self.index.get(key).cloned()
+    }
+
+    pub fn get<Q: ?Sized+Hash+Eq>(&self,key: &Q) -> Option<&V> where K: Borrow<Q> {
+        self.position(key).map(|i| &self.values[i])
+    }
+
+    pub fn get_mut<Q: ?Sized+Hash+Eq>(&mut self,key: &Q) -> Option<&mut V> where K: Borrow<Q> {
+        match self.position(key) {
+            Some(i) => Some(&mut self.values[i]),
+            None => None
+        }
+    }
+
+    /// The entry at a position.
+    pub fn get_index(&self,i: usize) -> Option<(&K,&V)> {
+        self.keys.get(i).map(|k| (k,&self.values[i]))
+    }
+
+    pub fn contains_key<Q: ?Sized+Hash+Eq>(&self,key: &Q) -> bool where K: Borrow<Q> {
+        self.index.contains_key(key)
+    }
+
+    /// Insert a value, returning the one it replaces. A new key is added
+    /// at the end.
+    pub fn insert(&mut self,key: K,value: V) -> Option<V> {
+        match self.index.get(&key) {
+            Some(&i) => Some(::std::mem::replace(&mut self.values[i],value)),
+            None => {
+                self.index.insert(key.clone(),self.keys.len());
+                self.keys.push(key);
+                self.values.push(value);
+                None
+            }
+        }
+    }
+
+    pub fn remove<Q: ?Sized+Hash+Eq>(&mut self,key: &Q) -> Option<V> where K: Borrow<Q> {
+        let i = self.index.remove(key)?;
+        self.keys.remove(i);
+        for k in &self.keys[i..] {
+            *self.index.get_mut::<K>(k).unwrap() -= 1;
+        }
+        Some(self.values.remove(i))
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+        self.values.clear();
+        self.index.clear();
+    }
+
+    pub fn iter(&self) -> Iter<'_,K,V> {
+        self.keys.iter().zip(self.values.iter())
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_,K,V> {
+        self.keys.iter().zip(self.values.iter_mut())
+    }
+
+    pub fn keys(&self) -> slice::Iter<'_,K> {
+        self.keys.iter()
+    }
+
+    pub fn values(&self) -> slice::Iter<'_,V> {
+        self.values.iter()
+    }
+
+    pub fn values_mut(&mut self) -> slice::IterMut<'_,V> {
+        self.values.iter_mut()
+    }
+
+    /// Remove all entries, in order.
+    pub fn drain(&mut self) -> Drain<'_,K,V> {
+        self.index.clear();
+        self.keys.drain(..).zip(self.values.drain(..))
+    }
+}
+
+impl<K: Hash+Eq+Clone,V> Default for OrderedMap<K,V> {
+    fn default() -> OrderedMap<K,V> {
+        OrderedMap::new()
+    }
+}
+
+impl<K: Hash+Eq+Clone,V: PartialEq> PartialEq for OrderedMap<K,V> {
+    fn eq(&self,other: &OrderedMap<K,V>) -> bool {
+        self.len()==other.len() &&
+            self.iter().all(|(k,v)| other.get(k)==Some(v))
+    }
+}
+
+impl<K: Hash+Eq+Clone,V: Eq> Eq for OrderedMap<K,V> {}
+
+impl<K: fmt::Debug,V: fmt::Debug> fmt::Debug for OrderedMap<K,V> {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.keys.iter().zip(self.values.iter())).finish()
+    }
+}
+
+impl<K: Hash+Eq+Clone+Borrow<Q>,V,Q: ?Sized+Hash+Eq> Index<&Q> for OrderedMap<K,V> {
+    type Output = V;
+    fn index(&self,key: &Q) -> &V {
+        self.get(key).expect("key not in map")
+    }
+}
+
+impl<K: Hash+Eq+Clone,V> FromIterator<(K,V)> for OrderedMap<K,V> {
+    fn from_iter<I: IntoIterator<Item=(K,V)>>(iter: I) -> OrderedMap<K,V> {
+        let mut res = OrderedMap::new();
+        res.extend(iter);
+        res
+    }
+}
+
+impl<K: Hash+Eq+Clone,V> Extend<(K,V)> for OrderedMap<K,V> {
+    fn extend<I: IntoIterator<Item=(K,V)>>(&mut self,iter: I) {
+        for (k,v) in iter {
+            self.insert(k,v);
+        }
+    }
+}
+
+impl<K,V> IntoIterator for OrderedMap<K,V> {
+    type Item = (K,V);
+    type IntoIter = IntoIter<K,V>;
+    fn into_iter(self) -> IntoIter<K,V> {
+        self.keys.into_iter().zip(self.values)
+    }
+}
+
+impl<'a,K: Hash+Eq+Clone,V> IntoIterator for &'a OrderedMap<K,V> {
+    type Item = (&'a K,&'a V);
+    type IntoIter = Iter<'a,K,V>;
+    fn into_iter(self) -> Iter<'a,K,V> {
+        self.iter()
+    }
+}
+
+impl<'a,K: Hash+Eq+Clone,V> IntoIterator for &'a mut OrderedMap<K,V> {
+    type Item = (&'a K,&'a mut V);
+    type IntoIter = IterMut<'a,K,V>;
+    fn into_iter(self) -> IterMut<'a,K,V> {
+        self.iter_mut()
+    }
+}
+
+#[test]
+fn test_ordered_map() {
+    let mut mp = OrderedMap::new();
+    for k in &["c","a","d","b"] {
+        mp.insert(k.to_string(),k.len());
+    }
+    assert_eq!(mp.insert("a".to_string(),5),Some(1));
+    assert_eq!(mp.keys().map(|k| &k[..]).collect::<Vec<_>>(),vec!["c","a","d","b"]);
+    assert_eq!(mp["a"],5);
+    assert_eq!(mp.remove("a"),Some(5));
+    assert_eq!(mp.remove("a"),None);
+    assert_eq!(mp.position("b"),Some(2));
+    assert_eq!(mp.get_index(1),Some((&"d".to_string(),&1)));
+    let rev: OrderedMap<String,usize> = mp.clone().into_iter().rev().collect();
+    assert_eq!(rev,mp);
+    assert_eq!(rev.keys().map(|k| &k[..]).collect::<Vec<_>>(),vec!["b","d","c"]);
+    assert_eq!(mp.drain().map(|(k,_)| k).collect::<Vec<_>>(),vec!["c","d","b"]);
+    assert!(mp.is_empty() && !mp.contains_key("c"));
+}
@@ -0,0 +1,750 @@
+//! Printing of modules in the textual format.
+//!
+//! The output is what `module` parses. Elements are printed in the
+//! order LLVM prints them, types, globals, functions, attribute groups
+//! and metadata, each kind in the order of its container, so a module
+//! read from `llvm-dis` output is printed in its original order. The
+//! syntax is that of the parser, e.g. `load` and `getelementptr` take
+//! the pointer type only.
+//!
+//! The module does not record how elements of different kinds were
+//! interleaved in the source, so a hand-written module with, say, a
+//! global between two functions is printed with the global first.
+//! Functions in `duplicate_functions` are not printed either, printing
+//! such a module loses them.
+//!
+//! Bodies that are not parsed yet, see `module_lazy`, are printed as
+//! they appear in the source.
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use num_traits::ToPrimitive;
+use super::*;
+
+type Args = [(Option<String>,Type)];
+
+/// Write a module to a textual file.
+pub fn write_file(m: &Module,path: &str) -> io::Result<()> {
+    File::create(path).and_then(|mut f| write!(f,"{}",m))
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Type::Opaque => write!(f,"opaque"),
+            Type::Int(w) => write!(f,"i{}",w),
+            Type::Float => write!(f,"float"),
+            Type::Double => write!(f,"double"),
+            Type::PPC_FP128 => write!(f,"ppc_fp128"),
+            Type::FP128 => write!(f,"fp128"),
+            Type::X86_FP80 => write!(f,"x86_fp80"),
+            Type::Label => write!(f,"label"),
+            Type::Pointer(ref tp,None) => write!(f,"{}*",tp),
+            Type::Pointer(ref tp,Some(sp)) => write!(f,"{} addrspace({})*",tp,sp),
+            Type::Struct(ref els) if els.is_empty() => write!(f,"{{}}"),
+            Type::Struct(ref els) => {
+                write!(f,"{{ ")?;
+                list(f,els,|f,el| write!(f,"{}",el))?;
+                write!(f," }}")
+            },
+            Type::Array(n,ref el) => write!(f,"[{} x {}]",n,el),
+            Type::Function(ref ret,ref args,va) => {
+                match *ret {
+                    Some(ref ret) => write!(f,"{} (",ret)?,
+                    None => write!(f,"void (")?
+                }
+                list(f,args,|f,arg| write!(f,"{}",arg))?;
+                var_args(f,!args.is_empty(),va)?;
+                write!(f,")")
+            },
+            Type::Named(ref n) => write!(f,"%{}",n),
+            Type::Metadata => write!(f,"metadata")
+        }
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        Printer { m: self }.module(f)
+    }
+}
+
+/// An instruction of a function, displayed as in the printed module but
+/// without its metadata attachments.
+pub struct InstructionDisplay<'a> {
+    pub module: &'a Module,
+    pub function: &'a Function,
+    pub instruction: &'a InstructionC
+}
+
+impl<'a> fmt::Display for InstructionDisplay<'a> {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        Printer { m: self.module }.instruction(f,self.instruction,&self.function.arguments)
+    }
+}
+
+// Writes the elements separated by commas.
+fn list<T,F>(f: &mut fmt::Formatter,els: &[T],mut el: F) -> fmt::Result
+    where F: FnMut(&mut fmt::Formatter,&T) -> fmt::Result {
+    for (i,x) in els.iter().enumerate() {
+        if i>0 {
+            write!(f,", ")?;
+        }
+        el(f,x)?;
+    }
+    Ok(())
+}
+
+fn var_args(f: &mut fmt::Formatter,after_args: bool,va: bool) -> fmt::Result {
+    match (va,after_args) {
+        (false,_) => Ok(()),
+        (true,true) => write!(f,", ..."),
+        (true,false) => write!(f,"...")
+    }
+}
+
+// Writes a string with the escapes of LLVM.
+fn escaped(f: &mut fmt::Formatter,bytes: &[u8]) -> fmt::Result {
+    for &c in bytes {
+        if c.is_ascii_graphic() && c!=b'"' && c!=b'\\' || c==b' ' {
+            write!(f,"{}",c as char)?;
+        } else {
+            write!(f,"\\{:02X}",c)?;
+        }
+    }
+    Ok(())
+}
+
+fn linkage_name(l: Linkage) -> &'static str {
+    match l {
+        Linkage::Private => "private",
+        Linkage::Internal => "internal",
+        Linkage::AvailableExternally => "available_externally",
+        Linkage::LinkOnce => "linkonce",
+        Linkage::Weak => "weak",
+        Linkage::Common => "common",
+        Linkage::Appending => "appending",
+        Linkage::ExternWeak => "extern_weak",
+        Linkage::LinkOnceODR => "linkonce_odr",
+        Linkage::WeakODR => "weak_odr",
+        Linkage::External => "external"
+    }
+}
+
+// The keywords that precede a global or function name, each followed
+// by a space.
+fn linkage_attrs(f: &mut fmt::Formatter,l: Option<Linkage>,vis: Visibility,dll: DLLStorageClass) -> fmt::Result {
+    if let Some(l) = l {
+        write!(f,"{} ",linkage_name(l))?;
+    }
+    match vis {
+        Visibility::Default => {},
+        Visibility::Hidden => write!(f,"hidden ")?,
+        Visibility::Protected => write!(f,"protected ")?
+    }
+    match dll {
+        DLLStorageClass::Default => Ok(()),
+        DLLStorageClass::DLLImport => write!(f,"dllimport "),
+        DLLStorageClass::DLLExport => write!(f,"dllexport ")
+    }
+}
+
+fn calling_conv_name(f: &mut fmt::Formatter,cc: &CallingConv) -> fmt::Result {
+    match *cc {
+        CallingConv::C => Ok(()),
+        CallingConv::Fast => write!(f,"fastcc "),
+        CallingConv::Cold => write!(f,"coldcc "),
+        CallingConv::WebKitJS => write!(f,"webkit_jscc "),
+        CallingConv::AnyReg => write!(f,"anyregcc "),
+        CallingConv::PreserveMost => write!(f,"preserve_mostcc "),
+        CallingConv::PreserveAll => write!(f,"preserve_allcc "),
+        CallingConv::CxxFastTLS => write!(f,"cxx_fast_tlscc "),
+        CallingConv::Swift => write!(f,"swiftcc "),
+        CallingConv::Numbered(n) => write!(f,"cc {} ",n)
+    }
+}
+
+fn par_attrs(attrs: &ParAttrs) -> Vec<String> {
+    let flags = [(attrs.zeroext,"zeroext"),(attrs.signext,"signext"),(attrs.inreg,"inreg"),
+                 (attrs.byval,"byval"),(attrs.inalloca,"inalloca"),(attrs.sret,"sret"),
+                 (attrs.noalias,"noalias"),(attrs.nocapture,"nocapture"),(attrs.nest,"nest"),
+                 (attrs.returned,"returned"),(attrs.nonnull,"nonnull"),
+                 (attrs.swiftself,"swiftself"),(attrs.swifterror,"swifterror")];
+    let mut res: Vec<String> = flags.iter().filter(|f| f.0).map(|f| f.1.to_string()).collect();
+    if let Some(n) = attrs.align {
+        res.push(format!("align {}",n));
+    }
+    if let Some(n) = attrs.dereferenceable {
+        res.push(format!("dereferenceable({})",n));
+    }
+    if let Some(n) = attrs.dereferenceable_or_null {
+        res.push(format!("dereferenceable_or_null({})",n));
+    }
+    res
+}
+
+fn alignment(f: &mut fmt::Formatter,align: Option<Alignment>) -> fmt::Result {
+    match align {
+        Some(n) => write!(f,", align {}",n),
+        None => Ok(())
+    }
+}
+
+fn cast_name(op: CastInst) -> &'static str {
+    match op {
+        CastInst::Trunc => "trunc",
+        CastInst::ZExt => "zext",
+        CastInst::SExt => "sext",
+        CastInst::Bitcast => "bitcast",
+        CastInst::IntToPtr => "inttoptr",
+        CastInst::PtrToInt => "ptrtoint"
+    }
+}
+
+fn cmp_name(op: &CmpOp) -> &'static str {
+    match *op {
+        CmpOp::Eq => "eq",
+        CmpOp::Ne => "ne",
+        CmpOp::UGt => "ugt",
+        CmpOp::UGe => "uge",
+        CmpOp::ULt => "ult",
+        CmpOp::ULe => "ule",
+        CmpOp::SGt => "sgt",
+        CmpOp::SGe => "sge",
+        CmpOp::SLt => "slt",
+        CmpOp::SLe => "sle"
+    }
+}
+
+fn bin_op(f: &mut fmt::Formatter,op: &BinOp) -> fmt::Result {
+    let wrap = |f: &mut fmt::Formatter,name: &str,nuw: bool,nsw: bool| {
+        write!(f,"{}{}{}",name,if nuw { " nuw" } else { "" },if nsw { " nsw" } else { "" })
+    };
+    match *op {
+        BinOp::Add(nuw,nsw) => wrap(f,"add",nuw,nsw),
+        BinOp::Sub(nuw,nsw) => wrap(f,"sub",nuw,nsw),
+        BinOp::Mul(nuw,nsw) => wrap(f,"mul",nuw,nsw),
+        BinOp::And => write!(f,"and"),
+        BinOp::Or => write!(f,"or"),
+        BinOp::XOr => write!(f,"xor"),
+        BinOp::AShr => write!(f,"ashr"),
+        BinOp::LShr => write!(f,"lshr"),
+        BinOp::Shl => write!(f,"shl"),
+        BinOp::SDiv(exact) => write!(f,"sdiv{}",if exact { " exact" } else { "" })
+    }
+}
+
+struct Printer<'m> {
+    m: &'m Module
+}
+
+impl<'m> Printer<'m> {
+    fn module(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        let m = self.m;
+        if let Some(ref id) = m.id {
+            writeln!(f,"; ModuleID = '{}'",id)?;
+        }
+        if m.datalayout!=DataLayout::new() {
+            writeln!(f,"target datalayout = \"{}\"",m.datalayout)?;
+        }
+        if let Some(ref tr) = m.triple {
+            writeln!(f,"target triple = \"{}\"",tr)?;
+        }
+        if !m.types.is_empty() {
+            writeln!(f)?;
+            for (name,tp) in m.types.iter() {
+                writeln!(f,"%{} = type {}",name,tp)?;
+            }
+        }
+        if !m.globals.is_empty() {
+            writeln!(f)?;
+            for (name,g) in m.globals.iter() {
+                self.global(f,name,g)?;
+            }
+        }
+        for fun in m.functions.values() {
+            writeln!(f)?;
+            self.function(f,fun)?;
+        }
+        if !m.attr_groups.is_empty() {
+            writeln!(f)?;
+            for (n,attrs) in m.attr_groups.iter() {
+                write!(f,"attributes #{} = {{ ",n)?;
+                for attr in attrs {
+                    if attr.quoted {
+                        write!(f,"\"{}\"",attr.name)?;
+                    } else {
+                        write!(f,"{}",attr.name)?;
+                    }
+                    if let Some(ref v) = attr.value {
+                        write!(f,"=\"{}\"",v)?;
+                    }
+                    write!(f," ")?;
+                }
+                writeln!(f,"}}")?;
+            }
+        }
+        if !m.named_md.is_empty() || !m.md.is_empty() {
+            writeln!(f)?;
+        }
+        for (name,md) in m.named_md.iter() {
+            write!(f,"!{} = ",name)?;
+            self.metadata(f,md,&[])?;
+            writeln!(f)?;
+        }
+        for (n,md) in m.md.iter() {
+            write!(f,"!{} = ",n)?;
+            self.metadata(f,md,&[])?;
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+
+    fn global(&self,f: &mut fmt::Formatter,name: &str,g: &GlobalVariable) -> fmt::Result {
+        write!(f,"@{} = ",name)?;
+        linkage_attrs(f,g.linkage,g.visibility,g.dll_storage_class)?;
+        match g.thread_local {
+            None => {},
+            Some(ThreadLocal::ThreadLocal) => write!(f,"thread_local ")?,
+            Some(ThreadLocal::LocalDynamic) => write!(f,"thread_local(localdynamic) ")?,
+            Some(ThreadLocal::InitialExec) => write!(f,"thread_local(initialexec) ")?,
+            Some(ThreadLocal::LocalExec) => write!(f,"thread_local(localexec) ")?
+        }
+        match g.unnamed_addr {
+            None => {},
+            Some(UnnamedAddr::UnnamedAddr) => write!(f,"unnamed_addr ")?,
+            Some(UnnamedAddr::LocalUnnamedAddr) => write!(f,"local_unnamed_addr ")?
+        }
+        if let Some(sp) = g.addr_space {
+            write!(f,"addrspace({}) ",sp)?;
+        }
+        if g.externally_initialized {
+            write!(f,"externally_initialized ")?;
+        }
+        match g.global_type {
+            GlobalType::Global => write!(f,"global ")?,
+            GlobalType::Constant => write!(f,"constant ")?
+        }
+        write!(f,"{}",g.types)?;
+        if let Some(ref init) = g.initialization {
+            write!(f," ")?;
+            self.constant(f,init,&g.types)?;
+        }
+        if let Some(ref sec) = g.section {
+            write!(f,", section \"{}\"",sec)?;
+        }
+        alignment(f,g.alignment)?;
+        writeln!(f)
+    }
+
+    fn function(&self,f: &mut fmt::Formatter,fun: &Function) -> fmt::Result {
+        let lazy = self.m.lazy_bodies.get(&fun.name);
+        let defined = fun.body.is_some() || lazy.is_some();
+        write!(f,"{} ",if defined { "define" } else { "declare" })?;
+        linkage_attrs(f,fun.linkage,fun.visibility,fun.dll_storage_class)?;
+        calling_conv_name(f,&fun.cconv)?;
+        match fun.return_type {
+            Some((ref attrs,ref tp)) => {
+                for attr in par_attrs(attrs) {
+                    write!(f,"{} ",attr)?;
+                }
+                write!(f,"{}",tp)?;
+            },
+            None => write!(f,"void")?
+        }
+        write!(f," @{}(",fun.name)?;
+        for (i,(name,tp)) in fun.arguments.iter().enumerate() {
+            if i>0 {
+                write!(f,", ")?;
+            }
+            write!(f,"{}",tp)?;
+            if let Some(attrs) = fun.argument_attrs.get(i) {
+                for attr in par_attrs(attrs) {
+                    write!(f," {}",attr)?;
+                }
+            }
+            if let Some(ref name) = *name {
+                write!(f," %{}",name)?;
+            }
+        }
+        var_args(f,!fun.arguments.is_empty(),fun.var_args)?;
+        write!(f,")")?;
+        for n in &fun.attribute_groups {
+            write!(f," #{}",n)?;
+        }
+        if let Some(lazy) = lazy {
+            let body = &lazy.source[lazy.range()];
+            return writeln!(f," {}",String::from_utf8_lossy(body))
+        }
+        let blks = match fun.body {
+            Some(ref blks) => blks,
+            None => return writeln!(f)
+        };
+        writeln!(f," {{")?;
+        for (i,blk) in blks.iter().enumerate() {
+            if i>0 {
+                writeln!(f)?;
+            }
+            writeln!(f,"{}:",blk.name)?;
+            for instr in &blk.instrs {
+                write!(f,"  ")?;
+                self.instruction(f,&instr.content,&fun.arguments)?;
+                for (kind,n) in instr.metadata.iter() {
+                    write!(f,", !{} !{}",kind,n)?;
+                }
+                writeln!(f)?;
+            }
+        }
+        writeln!(f,"}}")
+    }
+
+    fn instruction(&self,f: &mut fmt::Formatter,instr: &InstructionC,args: &Args) -> fmt::Result {
+        if let Some(name) = instr.name() {
+            write!(f,"%{} = ",name)?;
+        }
+        match *instr {
+            InstructionC::Alloca(_,ref tp,ref n,align) => {
+                write!(f,"alloca {}",tp)?;
+                if let Some(ref n) = *n {
+                    write!(f,", ")?;
+                    self.typed_value(f,n,args)?;
+                }
+                alignment(f,align)
+            },
+            InstructionC::Call(_,ref cc,ref ret,ref fun,ref call_args,ref attrs) => {
+                write!(f,"call ")?;
+                calling_conv_name(f,cc)?;
+                match *ret {
+                    Some((ref tp,ref pattrs)) => {
+                        for attr in par_attrs(pattrs) {
+                            write!(f,"{} ",attr)?;
+                        }
+                        write!(f,"{} ",tp)?;
+                    },
+                    None => write!(f,"void ")?
+                }
+                self.value(f,fun,&Type::Opaque,args)?;
+                write!(f,"(")?;
+                list(f,call_args,|f,arg| self.typed_value(f,arg,args))?;
+                write!(f,")")?;
+                for n in attrs {
+                    write!(f," #{}",n)?;
+                }
+                Ok(())
+            },
+            InstructionC::ICmp(_,ref op,ref tp,ref v1,ref v2) => {
+                write!(f,"icmp {} {} ",cmp_name(op),tp)?;
+                self.value(f,v1,tp,args)?;
+                write!(f,", ")?;
+                self.value(f,v2,tp,args)
+            },
+            InstructionC::Unary(_,ref v,UnaryInst::Load(vol,align)) => {
+                write!(f,"load {}",if vol { "volatile " } else { "" })?;
+                self.typed_value(f,v,args)?;
+                alignment(f,align)
+            },
+            InstructionC::Unary(_,ref v,UnaryInst::Cast(ref tp,op)) => {
+                write!(f,"{} ",cast_name(op))?;
+                self.typed_value(f,v,args)?;
+                write!(f," to {}",tp)
+            },
+            InstructionC::GEP(_,ref gep) => {
+                write!(f,"getelementptr {}",if gep.inbounds { "inbounds " } else { "" })?;
+                self.typed_value(f,&gep.ptr,args)?;
+                for &(ref idx,inrange) in &gep.indices {
+                    write!(f,", {}",if inrange { "inrange " } else { "" })?;
+                    self.typed_value(f,idx,args)?;
+                }
+                Ok(())
+            },
+            InstructionC::Store(vol,ref v,ref ptr,align) => {
+                write!(f,"store {}",if vol { "volatile " } else { "" })?;
+                self.typed_value(f,v,args)?;
+                write!(f,", ")?;
+                self.typed_value(f,ptr,args)?;
+                alignment(f,align)
+            },
+            InstructionC::Select(_,ref c,ref tp,ref v1,ref v2) => {
+                write!(f,"select i1 ")?;
+                self.value(f,c,&Type::Int(1),args)?;
+                write!(f,", {} ",tp)?;
+                self.value(f,v1,tp,args)?;
+                write!(f,", {} ",tp)?;
+                self.value(f,v2,tp,args)
+            },
+            InstructionC::Phi(_,ref tp,ref incoming) => {
+                write!(f,"phi {} ",tp)?;
+                list(f,incoming,|f,(v,lbl)| {
+                    write!(f,"[ ")?;
+                    self.value(f,v,tp,args)?;
+                    write!(f,", %{} ]",lbl)
+                })
+            },
+            InstructionC::Bin(_,ref op,ref tp,ref v1,ref v2) => {
+                bin_op(f,op)?;
+                write!(f," {} ",tp)?;
+                self.value(f,v1,tp,args)?;
+                write!(f,", ")?;
+                self.value(f,v2,tp,args)
+            },
+            InstructionC::Term(Terminator::Br(ref lbl)) => write!(f,"br label %{}",lbl),
+            InstructionC::Term(Terminator::BrC(ref c,ref l1,ref l2)) => {
+                write!(f,"br i1 ")?;
+                self.value(f,c,&Type::Int(1),args)?;
+                write!(f,", label %{}, label %{}",l1,l2)
+            },
+            InstructionC::Term(Terminator::Ret(None)) => write!(f,"ret void"),
+            InstructionC::Term(Terminator::Ret(Some(ref v))) => {
+                write!(f,"ret ")?;
+                self.typed_value(f,v,args)
+            },
+            InstructionC::Term(Terminator::Switch(ref tp,ref v,ref def,ref cases)) => {
+                write!(f,"switch {} ",tp)?;
+                self.value(f,v,tp,args)?;
+                writeln!(f,", label %{} [",def)?;
+                for (c,lbl) in cases {
+                    write!(f,"    {} ",tp)?;
+                    self.constant(f,c,tp)?;
+                    writeln!(f,", label %{}",lbl)?;
+                }
+                write!(f,"  ]")
+            },
+            InstructionC::Term(Terminator::Unreachable) => write!(f,"unreachable")
+        }
+    }
+
+    fn typed_value(&self,f: &mut fmt::Formatter,v: &Typed<Value>,args: &Args) -> fmt::Result {
+        write!(f,"{} ",v.tp)?;
+        self.value(f,&v.val,&v.tp,args)
+    }
+
+    fn value(&self,f: &mut fmt::Formatter,v: &Value,tp: &Type,args: &Args) -> fmt::Result {
+        match *v {
+            Value::Constant(ref c) => self.constant(f,c,tp),
+            Value::Local(ref n) => write!(f,"%{}",n),
+            Value::Argument(i) => match args.get(i) {
+                Some(&(Some(ref n),_)) => write!(f,"%{}",n),
+                _ => write!(f,"%{}",i)
+            },
+            Value::Metadata(ref md) => self.metadata(f,md,args)
+        }
+    }
+
+    // Named types are resolved to print the elements of aggregates.
+    fn resolve<'a>(&'a self,tp: &'a Type) -> &'a Type {
+        match *tp {
+            Type::Named(ref n) => match self.m.types.get(n) {
+                Some(tp) => self.resolve(tp),
+                None => tp
+            },
+            _ => tp
+        }
+    }
+
+    fn constant(&self,f: &mut fmt::Formatter,c: &Constant,tp: &Type) -> fmt::Result {
+        match *c {
+            Constant::Global(ref n) => write!(f,"@{}",n),
+            Constant::Int(ref i) => match *self.resolve(tp) {
+                Type::Int(1) if *i==BigInt::from(0) => write!(f,"false"),
+                Type::Int(1) if *i==BigInt::from(1) => write!(f,"true"),
+                _ => write!(f,"{}",i)
+            },
+            Constant::Array(ref els) => match *self.resolve(tp) {
+                Type::Struct(ref tps) if els.is_empty() && tps.is_empty() => write!(f,"{{}}"),
+                Type::Struct(ref tps) => {
+                    write!(f,"{{ ")?;
+                    for (i,el) in els.iter().enumerate() {
+                        if i>0 {
+                            write!(f,", ")?;
+                        }
+                        let tp = tps.get(i).unwrap_or(&Type::Opaque);
+                        write!(f,"{} ",tp)?;
+                        self.constant(f,el,tp)?;
+                    }
+                    write!(f," }}")
+                },
+                Type::Array(_,ref el_tp) => match bytes(els) {
+                    Some(ref bytes) if **el_tp==Type::Int(8) => {
+                        write!(f,"c\"")?;
+                        escaped(f,bytes)?;
+                        write!(f,"\"")
+                    },
+                    _ => {
+                        write!(f,"[")?;
+                        list(f,els,|f,el| {
+                            write!(f,"{} ",el_tp)?;
+                            self.constant(f,el,el_tp)
+                        })?;
+                        write!(f,"]")
+                    }
+                },
+                _ => {
+                    write!(f,"[")?;
+                    list(f,els,|f,el| {
+                        write!(f,"{} ",tp)?;
+                        self.constant(f,el,&Type::Opaque)
+                    })?;
+                    write!(f,"]")
+                }
+            },
+            Constant::GEP(ref gep) => {
+                write!(f,"getelementptr {}(",if gep.inbounds { "inbounds " } else { "" })?;
+                write!(f,"{} ",gep.ptr.tp)?;
+                self.constant(f,&gep.ptr.val,&gep.ptr.tp)?;
+                for &(ref idx,inrange) in &gep.indices {
+                    write!(f,", {}{} ",if inrange { "inrange " } else { "" },idx.tp)?;
+                    self.constant(f,&idx.val,&idx.tp)?;
+                }
+                write!(f,")")
+            },
+            Constant::Cast(op,ref v,ref tp) => {
+                write!(f,"{} ({} ",cast_name(op),v.tp)?;
+                self.constant(f,&v.val,&v.tp)?;
+                write!(f," to {})",tp)
+            },
+            Constant::NullPtr => write!(f,"null"),
+            Constant::Undef => write!(f,"undef")
+        }
+    }
+
+    fn metadata(&self,f: &mut fmt::Formatter,md: &Metadata,args: &Args) -> fmt::Result {
+        match *md {
+            Metadata::Null => write!(f,"null"),
+            Metadata::Ref(n) => write!(f,"!{}",n),
+            Metadata::Value(ref v) => self.typed_value(f,v,args),
+            Metadata::Struct(ref els) => {
+                write!(f,"!{{")?;
+                list(f,els,|f,el| self.metadata(f,el,args))?;
+                write!(f,"}}")
+            },
+            Metadata::Bytes(ref bytes) => {
+                write!(f,"!\"")?;
+                escaped(f,bytes)?;
+                write!(f,"\"")
+            },
+            Metadata::Location(l,c,ref scope) => {
+                write!(f,"!MDLocation(line: {}, column: {}, scope: ",l,c)?;
+                self.metadata(f,scope,args)?;
+                write!(f,")")
+            },
+            Metadata::Node(ref kind,distinct,ref fields) => {
+                if distinct {
+                    write!(f,"distinct ")?;
+                }
+                write!(f,"!{}(",kind)?;
+                list(f,fields,|f,(name,field)| match *field {
+                    MetadataField::Int(n) => write!(f,"{}: {}",name,n),
+                    MetadataField::Bool(b) => write!(f,"{}: {}",name,b),
+                    MetadataField::Constant(ref c) => write!(f,"{}: {}",name,c),
+                    MetadataField::Elements(ref ops) => list(f,ops,|f,op| write!(f,"{}",op)),
+                    MetadataField::Metadata(Metadata::Bytes(ref bytes)) => {
+                        write!(f,"{}: \"",name)?;
+                        escaped(f,bytes)?;
+                        write!(f,"\"")
+                    },
+                    MetadataField::Metadata(ref md) => {
+                        write!(f,"{}: ",name)?;
+                        self.metadata(f,md,args)
+                    }
+                })?;
+                write!(f,")")
+            }
+        }
+    }
+}
+
+// The elements of an array as bytes, if they all are integers that fit.
+fn bytes(els: &[Constant]) -> Option<Vec<u8>> {
+    els.iter().map(|el| match *el {
+        Constant::Int(ref i) => i.to_u8(),
+        _ => None
+    }).collect()
+}
+
+#[test]
+fn test_print_roundtrip() {
+    let m = minisat();
+    let txt = m.to_string();
+    let m2 = parse_test_module(txt.as_bytes());
+    assert_eq!(m2,m);
+    assert!(m2.functions.keys().eq(m.functions.keys()));
+    assert!(m2.globals.keys().eq(m.globals.keys()));
+    assert!(m2.md.keys().eq(m.md.keys()));
+    assert_eq!(m2.to_string(),txt);
+    let mut lazy = module_lazy(include_bytes!("minisat.ll")).unwrap();
+    assert_eq!(parse_test_module(lazy.to_string().as_bytes()),m);
+    lazy.materialize().unwrap();
+    assert_eq!(lazy.to_string(),txt);
+}
+
+#[test]
+fn test_print_elements() {
+    let src = b"%pair = type { i32, i8* }
+@s = private constant [4 x i8] c\"a\\22\\0A\\00\", align 1
+@p = global %pair { i32 -1, i8* getelementptr inbounds ([4 x i8]* @s, i32 0, i32 0) }
+
+declare i32 @puts(i8* nocapture) #0
+
+define i1 @f(i32 %x, ...) {
+entry:
+  %c = icmp slt i32 %x, 0
+  br i1 %c, label %neg, label %done
+
+neg:
+  %y = sub nsw i32 0, %x
+  switch i32 %y, label %done [
+    i32 1, label %done
+  ]
+
+done:
+  %r = phi i1 [ true, %entry ], [ false, %neg ], [ false, %neg ]
+  ret i1 %r
+}
+
+declare void @f()
+
+attributes #0 = { nounwind \"frame\"=\"none\" }
+
+!0 = !{!\"x\\0A\", null, i32 1}
+";
+    let m = parse_test_module(src);
+    assert!(m.duplicate_functions.is_empty());
+    let txt = m.to_string();
+    assert!(txt.contains("c\"a\\22\\0A\\00\""),"{}",txt);
+    assert!(txt.contains("declare i32 @puts(i8* nocapture) #0\n"),"{}",txt);
+    assert!(txt.contains("phi i1 [ true, %entry ], [ false, %neg ]"),"{}",txt);
+    assert!(txt.contains("!0 = !{!\"x\\0A\", null, i32 1}"),"{}",txt);
+    assert_eq!(parse_test_module(txt.as_bytes()),m);
+}
+
+#[test]
+fn test_print_order() {
+    let src = b"define void @f() {
+entry:
+  ret void
+}
+
+@g = global i32 0
+
+define void @f() {
+entry:
+  unreachable
+}
+
+%t = type { i32 }
+";
+    let m = parse_test_module(src);
+    assert_eq!(m.duplicate_functions.len(),1);
+    // Elements are grouped by kind and the duplicate is dropped.
+    assert_eq!(m.to_string(),"
+%t = type { i32 }
+
+@g = global i32 0
+
+define void @f() {
+entry:
+  ret void
+}
+");
+}
@@ -0,0 +1,111 @@
+; Source of sample.bc, assembled with llvm-as (LLVM 14).
+target datalayout = "e-m:e-i64:64-f80:128-n8:16:32:64-S128"
+target triple = "x86_64-unknown-linux-gnu"
+
+%struct.pair = type { i32, %struct.pair* }
+%struct.opaque = type opaque
+
+@counter = internal global i32 0, align 4
+@table = constant [3 x i16] [i16 1, i16 -2, i16 3], align 2
+@big = global i128 -170141183460469231731687303715884105728
+@tls = thread_local(initialexec) global i64 0
+@pairs = global [2 x %struct.pair] zeroinitializer
+@ptr = global i32* getelementptr inbounds ([2 x %struct.pair], [2 x %struct.pair]* @pairs, i64 0, i64 1, i32 0)
+@fptr = global i8* bitcast (i32 (i32, i32)* @classify to i8*)
+@0 = private unnamed_addr constant [4 x i8] c"abc\00"
+@ext = extern_weak global %struct.opaque
+@sec = global i32 5, section "mysec", align 8
+
+define i32 @classify(i32 %x, i32 signext %y) #0 !dbg !6 {
+entry:
+  %buf = alloca [4 x i32], align 16
+  %n = alloca i32, i32 %y, align 4
+  call void @llvm.dbg.value(metadata i32 %x, metadata !11, metadata !DIExpression()), !dbg !9
+  %cmp = icmp slt i32 %x, 0, !dbg !9
+  br i1 %cmp, label %neg, label %sw, !prof !10
+
+neg:
+  %0 = sub nsw i32 0, %x, !dbg !13
+  br label %done, !dbg !13
+
+sw:
+  switch i32 %x, label %done [
+    i32 1, label %one
+    i32 2, label %one
+  ]
+
+one:
+  %sel = select i1 %cmp, i32 %y, i32 7
+  %p = getelementptr inbounds [4 x i32], [4 x i32]* %buf, i64 0, i64 2
+  store volatile i32 %sel, i32* %p, align 4
+  %l = load i32, i32* %p, align 4
+  %sh = ashr exact i32 %l, 1
+  br label %done
+
+done:
+  %r = phi i32 [ %0, %neg ], [ %sh, %one ], [ %x, %sw ]
+  ret i32 %r
+}
+
+define i64 @loop(i64 %n) {
+entry:
+  br label %head
+
+head:
+  %i = phi i64 [ 0, %entry ], [ %next, %head ]
+  %next = add nuw i64 %i, 1
+  %c = icmp ult i64 %next, %n
+  br i1 %c, label %head, label %exit
+
+exit:
+  %t = trunc i64 %next to i8
+  %z = zext i8 %t to i64
+  %r = call i64 (i64, ...) @vf(i64 %z, i32 3)
+  ret i64 %r
+}
+
+define i64 @fwd(i64 %a) {
+entry:
+  br label %def
+
+use:
+  %u = add i64 %d, 1
+  ret i64 %u
+
+def:
+  %d = mul i64 %a, %a
+  br label %use
+}
+
+define void @anon(i32 %0) {
+  %2 = add i32 %0, 1
+  br label %3
+
+3:
+  ret void
+}
+
+declare i64 @vf(i64 zeroext, ...)
+
+declare void @llvm.dbg.value(metadata, metadata, metadata) #1
+
+attributes #0 = { noinline nounwind uwtable "frame-pointer"="all" }
+attributes #1 = { nofree nosync nounwind readnone speculatable willreturn }
+
+!llvm.dbg.cu = !{!0}
+!llvm.module.flags = !{!3, !4}
+!llvm.ident = !{!5}
+
+!0 = distinct !DICompileUnit(language: DW_LANG_C99, file: !1, producer: "clang", isOptimized: false, runtimeVersion: 0, emissionKind: FullDebug)
+!1 = !DIFile(filename: "sample.c", directory: "/tmp")
+!3 = !{i32 7, !"Dwarf Version", i32 4}
+!4 = !{i32 2, !"Debug Info Version", i32 3}
+!5 = !{!"sample compiler"}
+!6 = distinct !DISubprogram(name: "classify", scope: !1, file: !1, line: 3, type: !7, scopeLine: 3, unit: !0, spFlags: DISPFlagDefinition)
+!7 = !DISubroutineType(types: !8)
+!8 = !{null}
+!9 = !DILocation(line: 4, column: 7, scope: !6)
+!10 = !{!"branch_weights", i32 1, i32 2}
+!11 = !DILocalVariable(name: "x", arg: 1, scope: !6, file: !1, line: 3, type: !12)
+!12 = !DIBasicType(name: "int", size: 32, encoding: DW_ATE_signed)
+!13 = !DILocation(line: 5, column: 3, scope: !6)
@@ -0,0 +1,740 @@
+//! SMT terms over bit-vectors, booleans and arrays, their SMT-LIB2
+//! output and an interface to external solvers.
+//!
+//! Terms are immutable and shared. The constructors fold constants and
+//! apply a few cheap simplifications (most importantly reads from
+//! arrays at addresses that are known to differ from a write), so that
+//! concrete parts of a program stay concrete.
+use num_bigint::{BigInt,BigUint,Sign};
+use num_traits::{Zero,One,ToPrimitive};
+use std::collections::{HashMap,HashSet,BTreeMap};
+use std::env;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::ops;
+use std::process::{Command,Stdio};
+use std::rc::Rc;
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+pub enum Sort {
+    Bool,
+    BitVec(u64),
+    Array(Box<Sort>,Box<Sort>)
+}
+
+impl fmt::Display for Sort {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Sort::Bool => write!(f,"Bool"),
+            Sort::BitVec(w) => write!(f,"(_ BitVec {})",w),
+            Sort::Array(ref i,ref e) => write!(f,"(Array {} {})",i,e)
+        }
+    }
+}
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone,Copy)]
+pub enum Op {
+    Not,And,Or,Implies,Eq,Ite,
+    BvNot,BvNeg,
+    BvAdd,BvSub,BvMul,
+    BvUDiv,BvSDiv,BvURem,BvSRem,
+    BvAnd,BvOr,BvXor,
+    BvShl,BvLShr,BvAShr,
+    BvUlt,BvUle,BvSlt,BvSle,
+    Concat,
+    Extract(u64,u64),
+    ZeroExtend(u64),
+    SignExtend(u64),
+    Select,Store
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Op::Not => "not", Op::And => "and", Op::Or => "or", Op::Implies => "=>",
+            Op::Eq => "=", Op::Ite => "ite",
+            Op::BvNot => "bvnot", Op::BvNeg => "bvneg",
+            Op::BvAdd => "bvadd", Op::BvSub => "bvsub", Op::BvMul => "bvmul",
+            Op::BvUDiv => "bvudiv", Op::BvSDiv => "bvsdiv", Op::BvURem => "bvurem", Op::BvSRem => "bvsrem",
+            Op::BvAnd => "bvand", Op::BvOr => "bvor", Op::BvXor => "bvxor",
+            Op::BvShl => "bvshl", Op::BvLShr => "bvlshr", Op::BvAShr => "bvashr",
+            Op::BvUlt => "bvult", Op::BvUle => "bvule", Op::BvSlt => "bvslt", Op::BvSle => "bvsle",
+            Op::Concat => "concat",
+            Op::Extract(h,l) => return write!(f,"(_ extract {} {})",h,l),
+            Op::ZeroExtend(n) => return write!(f,"(_ zero_extend {})",n),
+            Op::SignExtend(n) => return write!(f,"(_ sign_extend {})",n),
+            Op::Select => "select", Op::Store => "store"
+        };
+        write!(f,"{}",name)
+    }
+}
+
+#[derive(Debug,PartialEq,Eq,Hash)]
+pub enum TermNode {
+    Var(String,Sort),
+    Bool(bool),
+    /// A bit-vector constant of the given width, the value is in the
+    /// range `0..2^width`.
+    BitVec(u64,BigInt),
+    App(Op,Vec<Term>)
+}
+
+#[derive(Debug,PartialEq,Eq,Hash,Clone)]
+pub struct Term(Rc<TermNode>);
+
+fn modulus(w: u64) -> BigInt {
+    BigInt::from(1) << (w as usize)
+}
+
+fn wrap(w: u64,v: BigInt) -> BigInt {
+    let m = modulus(w);
+    let r = v % &m;
+    if r.sign()==Sign::Minus { r + m } else { r }
+}
+
+fn signed(w: u64,v: &BigInt) -> BigInt {
+    if w > 0 && *v >= modulus(w-1) { v - modulus(w) } else { v.clone() }
+}
+
+fn bits(v: &BigInt) -> BigUint {
+    v.to_biguint().unwrap()
+}
+
+fn from_bits(v: BigUint) -> BigInt {
+    BigInt::from_biguint(Sign::Plus,v)
+}
+
+impl Term {
+    fn mk(node: TermNode) -> Term {
+        Term(Rc::new(node))
+    }
+    pub fn node(&self) -> &TermNode {
+        &self.0
+    }
+    pub fn var(name: &str,sort: Sort) -> Term {
+        Term::mk(TermNode::Var(name.to_string(),sort))
+    }
+    pub fn bool(b: bool) -> Term {
+        Term::mk(TermNode::Bool(b))
+    }
+    /// A bit-vector constant, `v` is truncated to the width.
+    pub fn bv(width: u64,v: BigInt) -> Term {
+        Term::mk(TermNode::BitVec(width,wrap(width,v)))
+    }
+    pub fn bv_i64(width: u64,v: i64) -> Term {
+        Term::bv(width,BigInt::from(v))
+    }
+    pub fn sort(&self) -> Sort {
+        // Iterative, array terms can be long chains of stores
+        let mut t = self;
+        loop {
+            match *t.node() {
+                TermNode::Var(_,ref s) => return s.clone(),
+                TermNode::Bool(_) => return Sort::Bool,
+                TermNode::BitVec(w,_) => return Sort::BitVec(w),
+                TermNode::App(op,ref args) => match op {
+                    Op::Not | Op::And | Op::Or | Op::Implies | Op::Eq |
+                    Op::BvUlt | Op::BvUle | Op::BvSlt | Op::BvSle => return Sort::Bool,
+                    Op::Ite => t = &args[1],
+                    Op::Concat => return Sort::BitVec(args[0].width()+args[1].width()),
+                    Op::Extract(h,l) => return Sort::BitVec(h-l+1),
+                    Op::ZeroExtend(n) | Op::SignExtend(n) => return Sort::BitVec(args[0].width()+n),
+                    Op::Select => return match args[0].sort() {
+                        Sort::Array(_,e) => *e,
+                        s => panic!("select on {}",s)
+                    },
+                    _ => t = &args[0]
+                }
+            }
+        }
+    }
+    /// The width of a bit-vector term.
+    pub fn width(&self) -> u64 {
+        match self.sort() {
+            Sort::BitVec(w) => w,
+            s => panic!("{} is not a bit-vector sort",s)
+        }
+    }
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self.node() {
+            TermNode::Bool(b) => Some(b),
+            _ => None
+        }
+    }
+    /// The value of a bit-vector constant.
+    pub fn as_bv(&self) -> Option<&BigInt> {
+        match *self.node() {
+            TermNode::BitVec(_,ref v) => Some(v),
+            _ => None
+        }
+    }
+    pub fn is_const(&self) -> bool {
+        matches!(*self.node(),TermNode::Bool(_) | TermNode::BitVec(..))
+    }
+    /// Apply an operator, simplifying where possible.
+    pub fn app(op: Op,args: Vec<Term>) -> Term {
+        if let Some(t) = simplify(op,&args) {
+            return t
+        }
+        Term::mk(TermNode::App(op,args))
+    }
+    pub fn and(ts: Vec<Term>) -> Term {
+        Term::app(Op::And,ts)
+    }
+    pub fn or(ts: Vec<Term>) -> Term {
+        Term::app(Op::Or,ts)
+    }
+    pub fn implies(a: Term,b: Term) -> Term {
+        Term::app(Op::Implies,vec![a,b])
+    }
+    pub fn eq(a: Term,b: Term) -> Term {
+        Term::app(Op::Eq,vec![a,b])
+    }
+    pub fn ite(c: Term,a: Term,b: Term) -> Term {
+        Term::app(Op::Ite,vec![c,a,b])
+    }
+    pub fn bin(op: Op,a: Term,b: Term) -> Term {
+        Term::app(op,vec![a,b])
+    }
+    pub fn extract(hi: u64,lo: u64,t: Term) -> Term {
+        Term::app(Op::Extract(hi,lo),vec![t])
+    }
+    pub fn zero_extend(n: u64,t: Term) -> Term {
+        Term::app(Op::ZeroExtend(n),vec![t])
+    }
+    pub fn sign_extend(n: u64,t: Term) -> Term {
+        Term::app(Op::SignExtend(n),vec![t])
+    }
+    /// Truncate or zero/sign-extend a bit-vector to a width.
+    pub fn resize(t: Term,width: u64,signed: bool) -> Term {
+        let w = t.width();
+        if width < w {
+            Term::extract(width-1,0,t)
+        } else if width==w {
+            t
+        } else if signed {
+            Term::sign_extend(width-w,t)
+        } else {
+            Term::zero_extend(width-w,t)
+        }
+    }
+    pub fn concat(hi: Term,lo: Term) -> Term {
+        Term::app(Op::Concat,vec![hi,lo])
+    }
+    pub fn select(arr: Term,idx: Term) -> Term {
+        Term::app(Op::Select,vec![arr,idx])
+    }
+    pub fn store(arr: Term,idx: Term,val: Term) -> Term {
+        Term::app(Op::Store,vec![arr,idx,val])
+    }
+    /// The names and sorts of all variables in the term.
+    pub fn variables(&self) -> BTreeMap<String,Sort> {
+        let mut res = BTreeMap::new();
+        collect_variables(self,&mut HashSet::new(),&mut res);
+        res
+    }
+}
+
+impl ops::Not for Term {
+    type Output = Term;
+    fn not(self) -> Term {
+        Term::app(Op::Not,vec![self])
+    }
+}
+
+impl Drop for Term {
+    fn drop(&mut self) {
+        // Take apart uniquely owned subterms without recursion, so that
+        // dropping a long chain of stores cannot overflow the stack
+        let mut stack = Vec::new();
+        if let Some(&mut TermNode::App(_,ref mut args)) = Rc::get_mut(&mut self.0) {
+            stack.append(args);
+        }
+        while let Some(mut t) = stack.pop() {
+            if let Some(&mut TermNode::App(_,ref mut args)) = Rc::get_mut(&mut t.0) {
+                stack.append(args);
+            }
+        }
+    }
+}
+
+fn collect_variables(t: &Term,seen: &mut HashSet<*const TermNode>,res: &mut BTreeMap<String,Sort>) {
+    let mut stack = vec![t];
+    while let Some(t) = stack.pop() {
+        if !seen.insert(&*t.0 as *const TermNode) {
+            continue
+        }
+        match *t.node() {
+            TermNode::Var(ref n,ref s) => {
+                res.insert(n.clone(),s.clone());
+            },
+            TermNode::App(_,ref args) => stack.extend(args.iter()),
+            _ => {}
+        }
+    }
+}
+
+/// Split a bit-vector into a symbolic base and a constant offset.
+fn split_offset(t: &Term) -> (Option<&Term>,BigInt) {
+    match *t.node() {
+        TermNode::BitVec(_,ref v) => (None,v.clone()),
+        TermNode::App(Op::BvAdd,ref args) => match args[1].as_bv() {
+            Some(v) => (Some(&args[0]),v.clone()),
+            None => (Some(t),BigInt::zero())
+        },
+        _ => (Some(t),BigInt::zero())
+    }
+}
+
+/// Whether two bit-vector terms certainly have different values.
+fn distinct(a: &Term,b: &Term) -> bool {
+    let (ba,oa) = split_offset(a);
+    let (bb,ob) = split_offset(b);
+    ba==bb && oa!=ob
+}
+
+fn simplify(op: Op,args: &[Term]) -> Option<Term> {
+    if !args.is_empty() && args.iter().all(|a| a.is_const()) {
+        if let Some(t) = fold(op,args) {
+            return Some(t)
+        }
+    }
+    match op {
+        Op::Not => match *args[0].node() {
+            TermNode::App(Op::Not,ref inner) => Some(inner[0].clone()),
+            _ => None
+        },
+        Op::And | Op::Or => {
+            let neutral = op==Op::And;
+            // Flatten nested conjunctions/disjunctions
+            if args.iter().any(|a| match *a.node() { TermNode::App(ref o,_) => *o==op, _ => false }) {
+                let mut flat = Vec::new();
+                for a in args {
+                    match *a.node() {
+                        TermNode::App(ref o,ref inner) if *o==op => flat.extend(inner.iter().cloned()),
+                        _ => flat.push(a.clone())
+                    }
+                }
+                return Some(Term::app(op,flat))
+            }
+            if args.iter().any(|a| a.as_bool()==Some(!neutral)) {
+                return Some(Term::bool(!neutral))
+            }
+            // A condition together with its negation
+            if args.iter().any(|a| match *a.node() {
+                TermNode::App(Op::Not,ref inner) => args.contains(&inner[0]),
+                _ => false
+            }) {
+                return Some(Term::bool(!neutral))
+            }
+            let rest: Vec<Term> = args.iter().filter(|a| a.as_bool()!=Some(neutral)).cloned().collect();
+            match rest.len() {
+                0 => Some(Term::bool(neutral)),
+                1 => Some(rest[0].clone()),
+                n if n < args.len() => Some(Term::mk(TermNode::App(op,rest))),
+                _ => None
+            }
+        },
+        Op::Implies => match (args[0].as_bool(),args[1].as_bool()) {
+            (Some(false),_) | (_,Some(true)) => Some(Term::bool(true)),
+            (Some(true),_) => Some(args[1].clone()),
+            (_,Some(false)) => Some(!args[0].clone()),
+            _ => None
+        },
+        Op::Eq => {
+            if args[0]==args[1] {
+                return Some(Term::bool(true))
+            }
+            if args[0].sort()!=Sort::Bool && distinct(&args[0],&args[1]) {
+                return Some(Term::bool(false))
+            }
+            // (= (ite c k1 k2) k) for constants
+            let (ite,k) = if args[1].is_const() { (&args[0],&args[1]) } else { (&args[1],&args[0]) };
+            if let TermNode::App(Op::Ite,ref ia) = *ite.node() {
+                if k.is_const() && ia[1].is_const() && ia[2].is_const() && ia[1]!=ia[2] {
+                    if ia[1]==*k {
+                        return Some(ia[0].clone())
+                    } else if ia[2]==*k {
+                        return Some(!ia[0].clone())
+                    } else {
+                        return Some(Term::bool(false))
+                    }
+                }
+            }
+            match (args[0].as_bool(),args[1].as_bool()) {
+                (Some(true),_) => Some(args[1].clone()),
+                (_,Some(true)) => Some(args[0].clone()),
+                (Some(false),_) => Some(!args[1].clone()),
+                (_,Some(false)) => Some(!args[0].clone()),
+                _ => None
+            }
+        },
+        Op::Ite => match args[0].as_bool() {
+            Some(true) => Some(args[1].clone()),
+            Some(false) => Some(args[2].clone()),
+            None => if args[1]==args[2] {
+                Some(args[1].clone())
+            } else {
+                None
+            }
+        },
+        Op::BvAdd => {
+            if args[0].is_const() {
+                return Some(Term::bin(Op::BvAdd,args[1].clone(),args[0].clone()))
+            }
+            match args[1].as_bv() {
+                Some(v) if v.is_zero() => Some(args[0].clone()),
+                Some(v) => match *args[0].node() {
+                    TermNode::App(Op::BvAdd,ref inner) => inner[1].as_bv().map(|v2| {
+                        Term::bin(Op::BvAdd,inner[0].clone(),Term::bv(args[0].width(),v+v2))
+                    }),
+                    _ => None
+                },
+                None => None
+            }
+        },
+        Op::BvSub => args[1].as_bv().map(|v| {
+            let w = args[0].width();
+            Term::bin(Op::BvAdd,args[0].clone(),Term::bv(w,-v.clone()))
+        }),
+        Op::Extract(h,l) => {
+            let t = &args[0];
+            if l==0 && h+1==t.width() {
+                return Some(t.clone())
+            }
+            match *t.node() {
+                TermNode::App(Op::Extract(_,l2),ref inner) =>
+                    Some(Term::extract(h+l2,l+l2,inner[0].clone())),
+                TermNode::App(Op::Concat,ref inner) => {
+                    let lw = inner[1].width();
+                    if h < lw {
+                        Some(Term::extract(h,l,inner[1].clone()))
+                    } else if l >= lw {
+                        Some(Term::extract(h-lw,l-lw,inner[0].clone()))
+                    } else {
+                        None
+                    }
+                },
+                TermNode::App(Op::ZeroExtend(_),ref inner) |
+                TermNode::App(Op::SignExtend(_),ref inner) if h < inner[0].width() =>
+                    Some(Term::extract(h,l,inner[0].clone())),
+                _ => None
+            }
+        },
+        Op::Concat => match (args[0].node(),args[1].node()) {
+            (&TermNode::App(Op::Extract(h1,l1),ref a1),&TermNode::App(Op::Extract(h2,l2),ref a2))
+                if a1[0]==a2[0] && l1==h2+1 => Some(Term::extract(h1,l2,a1[0].clone())),
+            _ => None
+        },
+        Op::Select => {
+            // Skip writes to addresses that differ from the read
+            let mut arr = &args[0];
+            let idx = &args[1];
+            while let TermNode::App(Op::Store,ref sargs) = *arr.node() {
+                if sargs[1]==*idx {
+                    return Some(sargs[2].clone())
+                } else if distinct(&sargs[1],idx) {
+                    arr = &sargs[0];
+                } else {
+                    break
+                }
+            }
+            if arr!=&args[0] {
+                Some(Term::mk(TermNode::App(Op::Select,vec![arr.clone(),idx.clone()])))
+            } else {
+                None
+            }
+        },
+        _ => None
+    }
+}
+
+/// Evaluate an operator on constants.
+fn fold(op: Op,args: &[Term]) -> Option<Term> {
+    if let Some(b) = args[0].as_bool() {
+        let bs: Vec<bool> = args.iter().filter_map(|a| a.as_bool()).collect();
+        return match op {
+            Op::Not => Some(Term::bool(!b)),
+            Op::And => Some(Term::bool(bs.iter().all(|&b| b))),
+            Op::Or => Some(Term::bool(bs.iter().any(|&b| b))),
+            Op::Implies => Some(Term::bool(!b || bs[1])),
+            Op::Eq => Some(Term::bool(args[0]==args[1])),
+            Op::Ite => Some(if b { args[1].clone() } else { args[2].clone() }),
+            _ => None
+        }
+    }
+    let w = args[0].width();
+    let a = args[0].as_bv()?;
+    let ones = modulus(w)-BigInt::one();
+    if args.len()==1 {
+        return match op {
+            Op::BvNot => Some(Term::bv(w,&ones-a)),
+            Op::BvNeg => Some(Term::bv(w,-a.clone())),
+            Op::Extract(h,l) => Some(Term::bv(h-l+1,a >> (l as usize))),
+            Op::ZeroExtend(n) => Some(Term::bv(w+n,a.clone())),
+            Op::SignExtend(n) => Some(Term::bv(w+n,signed(w,a))),
+            _ => None
+        }
+    }
+    let b = args[1].as_bv()?;
+    let (sa,sb) = (signed(w,a),signed(w,b));
+    let shift = b.to_usize().unwrap_or(usize::MAX);
+    Some(match op {
+        Op::Eq => Term::bool(a==b),
+        Op::BvAdd => Term::bv(w,a+b),
+        Op::BvSub => Term::bv(w,a-b),
+        Op::BvMul => Term::bv(w,a*b),
+        // Division by zero as defined by SMT-LIB
+        Op::BvUDiv => if b.is_zero() { Term::bv(w,ones) } else { Term::bv(w,a/b) },
+        Op::BvURem => if b.is_zero() { Term::bv(w,a.clone()) } else { Term::bv(w,a%b) },
+        Op::BvSDiv => if b.is_zero() {
+            Term::bv_i64(w,if sa.sign()==Sign::Minus { 1 } else { -1 })
+        } else {
+            Term::bv(w,sa/sb)
+        },
+        Op::BvSRem => if b.is_zero() { Term::bv(w,a.clone()) } else { Term::bv(w,sa%sb) },
+        Op::BvAnd => Term::bv(w,from_bits(bits(a) & bits(b))),
+        Op::BvOr => Term::bv(w,from_bits(bits(a) | bits(b))),
+        Op::BvXor => Term::bv(w,from_bits(bits(a) ^ bits(b))),
+        Op::BvShl => if shift as u64 >= w { Term::bv_i64(w,0) } else { Term::bv(w,a << shift) },
+        Op::BvLShr => if shift as u64 >= w { Term::bv_i64(w,0) } else { Term::bv(w,a >> shift) },
+        Op::BvAShr => if shift as u64 >= w {
+            Term::bv_i64(w,if sa.sign()==Sign::Minus { -1 } else { 0 })
+        } else {
+            let mut r = bits(a) >> shift;
+            if sa.sign()==Sign::Minus {
+                r |= bits(&ones) ^ (bits(&ones) >> shift);
+            }
+            Term::bv(w,from_bits(r))
+        },
+        Op::BvUlt => Term::bool(a<b),
+        Op::BvUle => Term::bool(a<=b),
+        Op::BvSlt => Term::bool(sa<sb),
+        Op::BvSle => Term::bool(sa<=sb),
+        Op::Concat => Term::bv(w+args[1].width(),(a << (args[1].width() as usize))+b),
+        _ => return None
+    })
+}
+
+/// Quote a symbol if it is not a simple SMT-LIB symbol.
+pub fn symbol(name: &str) -> String {
+    let simple = !name.is_empty() &&
+        !name.as_bytes()[0].is_ascii_digit() &&
+        name.bytes().all(|c| c.is_ascii_alphanumeric() || b"~!@$%^&*_-+=<>.?/".contains(&c));
+    if simple {
+        name.to_string()
+    } else {
+        format!("|{}|",name.replace(['|','\\'],"_"))
+    }
+}
+
+fn write_node(out: &mut String,t: &Term,names: &HashMap<*const TermNode,String>,top: bool) {
+    let key = &*t.0 as *const TermNode;
+    if !top {
+        if let Some(n) = names.get(&key) {
+            out.push_str(n);
+            return
+        }
+    }
+    match *t.node() {
+        TermNode::Var(ref n,_) => out.push_str(&symbol(n)),
+        TermNode::Bool(b) => out.push_str(if b { "true" } else { "false" }),
+        TermNode::BitVec(w,ref v) => out.push_str(&format!("(_ bv{} {})",v,w)),
+        TermNode::App(op,ref args) => {
+            out.push_str(&format!("({}",op));
+            for a in args.iter() {
+                out.push(' ');
+                write_node(out,a,names,false);
+            }
+            out.push(')');
+        }
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::new();
+        write_node(&mut out,self,&HashMap::new(),true);
+        write!(f,"{}",out)
+    }
+}
+
+/// Render a complete SMT-LIB2 script asserting all terms, followed by
+/// `(check-sat)` and `(get-model)`.
+///
+/// Subterms that occur more than once, and all array writes, are
+/// introduced with `define-fun` so that the output stays linear in the
+/// size of the term graph.
+pub fn script(logic: &str,assertions: &[Term]) -> String {
+    let mut out = String::new();
+    out.push_str("(set-option :produce-models true)\n");
+    out.push_str(&format!("(set-logic {})\n",logic));
+    let mut vars = BTreeMap::new();
+    let mut seen = HashSet::new();
+    for a in assertions.iter() {
+        collect_variables(a,&mut seen,&mut vars);
+    }
+    for (name,sort) in vars.iter() {
+        out.push_str(&format!("(declare-fun {} () {})\n",symbol(name),sort));
+    }
+    // Count references and find a post-order of the term graph
+    let mut refs: HashMap<*const TermNode,usize> = HashMap::new();
+    let mut order: Vec<&Term> = Vec::new();
+    for a in assertions.iter() {
+        let mut stack = vec![(a,false)];
+        while let Some((t,done)) = stack.pop() {
+            let key = &*t.0 as *const TermNode;
+            if done {
+                order.push(t);
+                continue
+            }
+            let cnt = refs.entry(key).or_insert(0);
+            *cnt += 1;
+            if *cnt > 1 {
+                continue
+            }
+            stack.push((t,true));
+            if let TermNode::App(_,ref args) = *t.node() {
+                for arg in args.iter().rev() {
+                    stack.push((arg,false));
+                }
+            }
+        }
+    }
+    let mut names = HashMap::new();
+    for t in order {
+        let key = &*t.0 as *const TermNode;
+        let shared = match *t.node() {
+            TermNode::App(Op::Store,_) => true,
+            TermNode::App(..) => refs[&key] > 1,
+            _ => false
+        };
+        if shared {
+            let name = format!("_t{}",names.len());
+            out.push_str(&format!("(define-fun {} () {} ",name,t.sort()));
+            write_node(&mut out,t,&names,true);
+            out.push_str(")\n");
+            names.insert(key,name);
+        }
+    }
+    for a in assertions.iter() {
+        out.push_str("(assert ");
+        write_node(&mut out,a,&names,false);
+        out.push_str(")\n");
+    }
+    out.push_str("(check-sat)\n(get-model)\n");
+    out
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum SatResult {
+    /// Satisfiable, with the model printed by the solver.
+    Sat(String),
+    Unsat,
+    Unknown(String)
+}
+
+/// An SMT solver binary that reads SMT-LIB2 from its standard input.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Solver {
+    pub program: String,
+    pub args: Vec<String>
+}
+
+const KNOWN_SOLVERS: [(&str,&[&str]); 5] = [("z3",&["-in"]),
+                                            ("cvc5",&["--lang=smt2"]),
+                                            ("cvc4",&["--lang=smt2"]),
+                                            ("yices-smt2",&[]),
+                                            ("bitwuzla",&[])];
+
+impl Solver {
+    pub fn new(program: &str,args: &[&str]) -> Solver {
+        Solver { program: program.to_string(),
+                 args: args.iter().map(|a| a.to_string()).collect() }
+    }
+    /// Look for a known solver in the `PATH`.
+    pub fn find() -> Option<Solver> {
+        let path = env::var_os("PATH")?;
+        for &(name,args) in KNOWN_SOLVERS.iter() {
+            if env::split_paths(&path).any(|dir| dir.join(name).is_file()) {
+                return Some(Solver::new(name,args))
+            }
+        }
+        None
+    }
+    /// Run the solver on a script.
+    pub fn check(&self,script: &str) -> io::Result<SatResult> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(script.as_bytes())?;
+        let out = child.wait_with_output()?;
+        let text = String::from_utf8_lossy(&out.stdout).into_owned();
+        let mut lines = text.splitn(2,'\n');
+        Ok(match lines.next().map(|l| l.trim()) {
+            Some("sat") => SatResult::Sat(lines.next().unwrap_or("").to_string()),
+            Some("unsat") => SatResult::Unsat,
+            _ => SatResult::Unknown(text.clone())
+        })
+    }
+}
+
+#[test]
+fn test_simplify() {
+    let x = Term::var("x",Sort::BitVec(32));
+    let c = |v| Term::bv_i64(32,v);
+    assert_eq!(Term::bin(Op::BvAdd,c(3),c(-5)),c(-2));
+    assert_eq!(Term::bin(Op::BvAdd,Term::bin(Op::BvAdd,x.clone(),c(4)),c(4)),
+               Term::bin(Op::BvAdd,x.clone(),c(8)));
+    assert_eq!(Term::bin(Op::BvSlt,c(-1),c(0)),Term::bool(true));
+    assert_eq!(Term::bin(Op::BvUlt,c(-1),c(0)),Term::bool(false));
+    assert_eq!(Term::bin(Op::BvAShr,c(-8),c(1)),c(-4));
+    assert_eq!(Term::extract(31,0,x.clone()),x);
+    // Little endian load after store
+    let mem = Term::var("mem",Sort::Array(Box::new(Sort::BitVec(32)),Box::new(Sort::BitVec(8))));
+    let addr = Term::bin(Op::BvAdd,Term::var("p",Sort::BitVec(32)),c(8));
+    let mut m = mem.clone();
+    for i in 0..4 {
+        m = Term::store(m,Term::bin(Op::BvAdd,addr.clone(),c(i)),Term::extract(8*i as u64+7,8*i as u64,x.clone()));
+    }
+    let mut v = Term::select(m.clone(),addr.clone());
+    for i in 1..4 {
+        v = Term::concat(Term::select(m.clone(),Term::bin(Op::BvAdd,addr.clone(),c(i))),v);
+    }
+    assert_eq!(v,x);
+    // A read from a different offset skips all writes
+    assert_eq!(Term::select(m,Term::bin(Op::BvAdd,addr.clone(),c(4))),
+               Term::select(mem,Term::bin(Op::BvAdd,addr,c(4))));
+    let b = Term::var("b",Sort::BitVec(1));
+    let cond = Term::bin(Op::BvUlt,x.clone(),c(3));
+    assert_eq!(Term::eq(Term::ite(cond.clone(),Term::bv_i64(1,1),Term::bv_i64(1,0)),Term::bv_i64(1,1)),cond);
+    assert_eq!(Term::and(vec![Term::bool(true),Term::eq(b.clone(),b)]),Term::bool(true));
+}
+
+#[test]
+fn test_script() {
+    let x = Term::var("x",Sort::BitVec(8));
+    let y = Term::var("in put",Sort::BitVec(8));
+    let s = Term::bin(Op::BvAdd,x.clone(),y.clone());
+    let a1 = Term::bin(Op::BvUlt,s.clone(),Term::bv_i64(8,10));
+    let a2 = Term::eq(Term::bin(Op::BvMul,s,Term::bv_i64(8,2)),x);
+    let out = script("QF_BV",&[a1,a2]);
+    assert_eq!(out,"(set-option :produce-models true)
+(set-logic QF_BV)
+(declare-fun |in put| () (_ BitVec 8))
+(declare-fun x () (_ BitVec 8))
+(define-fun _t0 () (_ BitVec 8) (bvadd x |in put|))
+(assert (bvult _t0 (_ bv10 8)))
+(assert (= (bvmul _t0 (_ bv2 8)) x))
+(check-sat)
+(get-model)
+");
+    if cfg!(unix) {
+        let fake = Solver::new("sh",&["-c","cat >/dev/null; echo unsat"]);
+        assert_eq!(fake.check(&out).unwrap(),SatResult::Unsat);
+    }
+}
@@ -0,0 +1,228 @@
+//! An index of the SSA values of a function: where each local is
+//! defined and where locals and arguments are used.
+//!
+//! Only direct operands count as uses. Values wrapped in metadata (for
+//! example the arguments of `llvm.dbg.value`) are not indexed.
+use std::collections::HashMap;
+use super::{Function,Instruction,Value};
+#[cfg(test)]
+use super::InstructionC;
+
+/// The location of an instruction in a function body.
+#[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Hash,Clone,Copy)]
+pub struct Position {
+    pub block: usize,
+    pub instr: usize
+}
+
+#[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Hash,Clone,Copy)]
+pub enum Definition {
+    Argument(usize),
+    Instruction(Position)
+}
+
+/// A use of a value as the `operand`-th operand of an instruction.
+#[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Hash,Clone,Copy)]
+pub struct Use {
+    pub position: Position,
+    pub operand: usize
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct SsaIndex {
+    defs: HashMap<String,Position>,
+    uses: HashMap<Value,Vec<Use>>,
+    shape: Vec<usize>
+}
+
+const NO_USES: [Use; 0] = [];
+
+impl SsaIndex {
+    /// Index a function. Declarations produce an empty index.
+    pub fn new(fun: &Function) -> SsaIndex {
+        let mut idx = SsaIndex { defs: HashMap::new(),
+                                 uses: HashMap::new(),
+                                 shape: Vec::new() };
+        let blks = match fun.body {
+            Some(ref blks) => blks,
+            None => return idx
+        };
+        for (b,blk) in blks.iter().enumerate() {
+            idx.shape.push(blk.instrs.len());
+            for (i,instr) in blk.instrs.iter().enumerate() {
+                let pos = Position { block: b, instr: i };
+                if let Some(name) = instr.content.name() {
+                    idx.defs.insert(name.to_string(),pos);
+                }
+                for (op,val) in instr.content.operands().enumerate() {
+                    if is_indexed(val) {
+                        idx.uses.entry(val.clone())
+                            .or_default()
+                            .push(Use { position: pos, operand: op });
+                    }
+                }
+            }
+        }
+        idx
+    }
+    /// Where a local or argument is defined.
+    pub fn definition(&self,val: &Value) -> Option<Definition> {
+        match *val {
+            Value::Argument(n) => Some(Definition::Argument(n)),
+            Value::Local(ref name) => self.defs.get(name).map(|p| Definition::Instruction(*p)),
+            _ => None
+        }
+    }
+    /// The instruction defining a local.
+    pub fn defining_instruction<'a>(&self,fun: &'a Function,name: &str) -> Option<&'a Instruction> {
+        let pos = self.defs.get(name)?;
+        fun.body.as_ref().map(|blks| &blks[pos.block].instrs[pos.instr])
+    }
+    /// All uses of a local or argument, in program order.
+    pub fn uses(&self,val: &Value) -> &[Use] {
+        match self.uses.get(val) {
+            Some(u) => &u[..],
+            None => &NO_USES
+        }
+    }
+    /// The names of all locals defined in the function.
+    pub fn locals(&self) -> Vec<&str> {
+        self.defs.keys().map(|n| &n[..]).collect()
+    }
+    /// Whether the function was changed in a way that the index does
+    /// not reflect anymore. Only edits made through the index itself
+    /// keep it up to date, everything else requires a new index.
+    ///
+    /// This only compares the shape of the body and the definitions,
+    /// an operand changed in place is not detected.
+    pub fn is_stale(&self,fun: &Function) -> bool {
+        let blks = match fun.body {
+            Some(ref blks) => blks,
+            None => return !self.shape.is_empty()
+        };
+        if blks.len()!=self.shape.len() ||
+            blks.iter().zip(self.shape.iter()).any(|(b,&l)| b.instrs.len()!=l) {
+            return true
+        }
+        self.defs.iter().any(|(name,pos)| {
+            blks[pos.block].instrs[pos.instr].content.name()!=Some(name)
+        })
+    }
+    /// Replace every use of `old` by `new` and update the index
+    /// accordingly. Returns the number of replaced operands.
+    ///
+    /// Panics if the index is stale, including when a recorded use no
+    /// longer refers to `old`.
+    pub fn replace_all_uses_with(&mut self,fun: &mut Function,old: &Value,new: Value) -> usize {
+        assert!(!self.is_stale(fun),"SSA index is stale");
+        if *old==new {
+            return 0
+        }
+        let blks = fun.body.as_mut().unwrap();
+        if let Some(old_uses) = self.uses.get(old) {
+            for u in old_uses.iter() {
+                let instr = &blks[u.position.block].instrs[u.position.instr].content;
+                assert!(instr.operands().nth(u.operand)==Some(old),"SSA index is stale");
+            }
+        }
+        let old_uses = match self.uses.remove(old) {
+            Some(u) => u,
+            None => return 0
+        };
+        for u in old_uses.iter() {
+            let instr = &mut blks[u.position.block].instrs[u.position.instr].content;
+            if let Some(op) = instr.operands_mut().nth(u.operand) {
+                *op = new.clone();
+            }
+        }
+        if is_indexed(&new) {
+            let new_uses = self.uses.entry(new).or_default();
+            new_uses.extend_from_slice(&old_uses);
+            new_uses.sort();
+        }
+        old_uses.len()
+    }
+}
+
+fn is_indexed(val: &Value) -> bool {
+    matches!(*val,Value::Local(_) | Value::Argument(_))
+}
+
+#[test]
+fn test_ssa_index_minisat() {
+    let m = ::minisat();
+    let fun = &m.functions["main"];
+    let idx = SsaIndex::new(fun);
+    let call = Value::Local("call".to_string());
+    assert_eq!(idx.definition(&call),
+               Some(Definition::Instruction(Position { block: 0, instr: 2 })));
+    match idx.defining_instruction(fun,"k.0").unwrap().content {
+        InstructionC::Phi(..) => {},
+        ref i => panic!("unexpected definition {:?}",i)
+    }
+    // solver_delete is called on %call twice, and its fields are
+    // accessed through several GEPs.
+    let uses = idx.uses(&call);
+    assert!(uses.len() > 4);
+    for u in uses {
+        let instr = &fun.body.as_ref().unwrap()[u.position.block].instrs[u.position.instr];
+        assert_eq!(instr.content.operands().nth(u.operand),Some(&call));
+    }
+    // %argc is only used by the comparison, the debug intrinsic refers
+    // to it through metadata.
+    assert_eq!(idx.uses(&Value::Argument(0)),
+               &[Use { position: Position { block: 0, instr: 5 }, operand: 0 }]);
+    assert!(!idx.is_stale(fun));
+
+    for fun in m.functions.values() {
+        let idx = SsaIndex::new(fun);
+        for name in idx.locals() {
+            assert_eq!(idx.defining_instruction(fun,name).unwrap().content.name(),Some(name));
+        }
+    }
+}
+
+#[test]
+fn test_replace_all_uses_with() {
+    let mut fun = ::parse_test_function(b"define i32 @f(i32 %a) {
+entry:
+  %x = add i32 %a, 1
+  %y = mul i32 %x, %x
+  ret i32 %y
+}");
+    let mut idx = SsaIndex::new(&fun);
+    let x = Value::Local("x".to_string());
+    assert_eq!(idx.uses(&x).len(),2);
+    assert_eq!(idx.replace_all_uses_with(&mut fun,&x,Value::Argument(0)),2);
+    assert!(idx.uses(&x).is_empty());
+    assert_eq!(idx.uses(&Value::Argument(0)).len(),3);
+    assert!(!idx.is_stale(&fun));
+    assert_eq!(idx,SsaIndex::new(&fun));
+    match fun.body.as_ref().unwrap()[0].instrs[1].content {
+        InstructionC::Bin(_,_,_,ref v1,ref v2) => {
+            assert_eq!(v1,&Value::Argument(0));
+            assert_eq!(v2,&Value::Argument(0));
+        },
+        ref i => panic!("unexpected instruction {:?}",i)
+    }
+    fun.body.as_mut().unwrap()[0].instrs.remove(0);
+    assert!(idx.is_stale(&fun));
+}
+
+#[test]
+#[should_panic(expected = "SSA index is stale")]
+fn test_replace_all_uses_with_edited_operand() {
+    let mut fun = ::parse_test_function(b"define i32 @f(i32 %a) {
+entry:
+  %x = add i32 %a, 1
+  %y = mul i32 %x, %a
+  ret i32 %y
+}");
+    let mut idx = SsaIndex::new(&fun);
+    // Swap the operands of %y behind the index's back
+    if let InstructionC::Bin(_,_,_,ref mut v1,ref mut v2) = fun.body.as_mut().unwrap()[0].instrs[1].content {
+        ::std::mem::swap(v1,v2);
+    }
+    assert!(!idx.is_stale(&fun));
+    idx.replace_all_uses_with(&mut fun,&Value::Local("x".to_string()),Value::Argument(0));
+}
@@ -0,0 +1,354 @@
+//! Incremental parsing of textual modules.
+//!
+//! `ElementReader` reads a module from any `io::Read` and yields its
+//! top-level elements one at a time, so only the element being parsed
+//! is held in memory. Elements are cut at line boundaries: a function
+//! definition extends to the first line starting with `}`, all other
+//! elements to the end of their line, unless the parser asks for more.
+//!
+//! `parse_parallel` splits an input held in memory the same way and
+//! parses the elements on several threads. The module is assembled in
+//! the order of the source, so the result is the same as that of
+//! `module`.
+use nom::IResult;
+use std::fmt;
+use std::io::{BufRead,BufReader,Read};
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::thread;
+use super::*;
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum ParseError {
+    Io(String),
+    /// The element starting at the given line cannot be parsed.
+    Syntax(u64),
+    /// The input ends inside the element starting at the given line.
+    Truncated(u64)
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Io(ref e) => write!(f,"cannot read module: {}",e),
+            ParseError::Syntax(l) => write!(f,"syntax error in the element at line {}",l),
+            ParseError::Truncated(l) => write!(f,"unexpected end of input in the element at line {}",l)
+        }
+    }
+}
+
+impl ::std::error::Error for ParseError {}
+
+// Whether a line ends a function definition.
+fn closes_function(line: &[u8]) -> bool {
+    line.iter().find(|c| !c.is_ascii_whitespace())==Some(&b'}')
+}
+
+/// An iterator over the elements of a textual module. Iteration stops
+/// after the first error.
+pub struct ElementReader<R> {
+    input: BufReader<R>,
+    /// Unparsed input, starting at line `next_line`.
+    buf: Vec<u8>,
+    next_line: u64,
+    /// The line of the last element returned.
+    line: u64,
+    eof: bool,
+    failed: bool
+}
+
+impl<R: Read> ElementReader<R> {
+    pub fn new(input: R) -> ElementReader<R> {
+        ElementReader { input: BufReader::new(input),
+                        buf: Vec::new(),
+                        next_line: 1,
+                        line: 0,
+                        eof: false,
+                        failed: false }
+    }
+
+    /// The line at which the element last returned starts.
+    pub fn line(&self) -> u64 {
+        self.line
+    }
+
+    // Appends a line to the buffer, returns the start of the line or
+    // `None` at the end of the input.
+    fn read_line(&mut self) -> Result<Option<usize>,ParseError> {
+        let start = self.buf.len();
+        match self.input.read_until(b'\n',&mut self.buf) {
+            Ok(0) => {
+                self.eof = true;
+                Ok(None)
+            },
+            Ok(_) => Ok(Some(start)),
+            Err(e) => Err(ParseError::Io(e.to_string()))
+        }
+    }
+
+    // Makes sure the buffer holds the first line of the next element and,
+    // for a function definition, the lines up to its closing brace.
+    fn fill(&mut self) -> Result<(),ParseError> {
+        if !self.buf.contains(&b'\n') && self.read_line()?.is_none() {
+            return Ok(())
+        }
+        if !self.buf.starts_with(b"define") {
+            return Ok(())
+        }
+        if self.buf.split(|&c| c==b'\n').skip(1).any(closes_function) {
+            return Ok(())
+        }
+        while let Some(start) = self.read_line()? {
+            if closes_function(&self.buf[start..]) {
+                return Ok(())
+            }
+        }
+        Err(ParseError::Truncated(self.next_line))
+    }
+
+    fn consume(&mut self,len: usize) {
+        self.next_line += self.buf[..len].iter().filter(|&&c| c==b'\n').count() as u64;
+        self.buf.drain(..len);
+    }
+
+    fn next_element(&mut self) -> Result<Option<ModuleElement>,ParseError> {
+        loop {
+            let ws = self.buf.iter().take_while(|c| c.is_ascii_whitespace()).count();
+            self.consume(ws);
+            if self.buf.is_empty() && self.eof {
+                return Ok(None)
+            }
+            self.fill()?;
+            if self.buf.iter().all(|c| c.is_ascii_whitespace()) {
+                continue
+            }
+            match module_element(&self.buf) {
+                IResult::Done(rest,el) => {
+                    let line = self.next_line;
+                    let len = self.buf.len()-rest.len();
+                    self.consume(len);
+                    if el.is_some() {
+                        self.line = line;
+                        return Ok(el)
+                    }
+                },
+                IResult::Incomplete(_) => if self.eof || self.read_line()?.is_none() {
+                    return Err(ParseError::Truncated(self.next_line))
+                },
+                IResult::Error(_) => return Err(ParseError::Syntax(self.next_line))
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for ElementReader<R> {
+    type Item = Result<ModuleElement,ParseError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None
+        }
+        match self.next_element() {
+            Ok(el) => el.map(Ok),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Call `f` for each element of a textual module.
+pub fn for_each_element<R: Read,F: FnMut(ModuleElement)>(input: R,mut f: F) -> Result<(),ParseError> {
+    for el in ElementReader::new(input) {
+        f(el?);
+    }
+    Ok(())
+}
+
+/// Read a whole textual module.
+pub fn read_module<R: Read>(input: R) -> Result<Module,ParseError> {
+    let mut m = Module::new();
+    for_each_element(input,|el| m.add_element(el))?;
+    Ok(m)
+}
+
+// The elements of a module with the lines they start at.
+fn split_elements(input: &[u8]) -> Result<Vec<(u64,&[u8])>,ParseError> {
+    let mut res = Vec::new();
+    let mut lines = input.split_inclusive(|&c| c==b'\n');
+    let mut line = 0;
+    let mut pos = 0;
+    while let Some(first) = lines.next() {
+        line += 1;
+        let start = line;
+        let begin = pos+first.iter().take_while(|c| c.is_ascii_whitespace()).count();
+        pos += first.len();
+        if begin==pos {
+            continue
+        }
+        if input[begin..].starts_with(b"define") {
+            loop {
+                match lines.next() {
+                    Some(l) => {
+                        line += 1;
+                        pos += l.len();
+                        if closes_function(l) {
+                            break
+                        }
+                    },
+                    None => return Err(ParseError::Truncated(start))
+                }
+            }
+        }
+        res.push((start,&input[begin..pos]));
+    }
+    Ok(res)
+}
+
+// Parses the elements of a chunk, which may be followed by comments.
+fn parse_elements(line: u64,input: &[u8]) -> Result<Vec<ModuleElement>,ParseError> {
+    let mut res = Vec::new();
+    let mut inp = input;
+    loop {
+        inp = &inp[inp.iter().take_while(|c| c.is_ascii_whitespace()).count()..];
+        if inp.is_empty() {
+            return Ok(res)
+        }
+        match module_element(inp) {
+            IResult::Done(rest,el) => {
+                res.extend(el);
+                inp = rest;
+            },
+            IResult::Incomplete(_) => return Err(ParseError::Truncated(line)),
+            IResult::Error(_) => return Err(ParseError::Syntax(line))
+        }
+    }
+}
+
+// The number of elements parsed per thread before they are added to
+// the module, which bounds the number of parsed elements held at once.
+const WINDOW: usize = 1024;
+
+// Parses elements on `threads` threads, returning the results in order.
+// A single thread parses on the calling one.
+fn parse_window(elements: &[(u64,&[u8])],threads: usize) -> Vec<Result<Vec<ModuleElement>,ParseError>> {
+    if threads==1 {
+        return elements.iter().map(|&(line,el)| parse_elements(line,el)).collect()
+    }
+    let next = AtomicUsize::new(0);
+    let mut parsed: Vec<(usize,Result<Vec<ModuleElement>,ParseError>)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|_| scope.spawn(|| {
+            let mut res = Vec::new();
+            loop {
+                let i = next.fetch_add(1,Ordering::Relaxed);
+                match elements.get(i) {
+                    Some(&(line,el)) => res.push((i,parse_elements(line,el))),
+                    None => return res
+                }
+            }
+        })).collect();
+        workers.into_iter().flat_map(|w| w.join().unwrap()).collect()
+    });
+    parsed.sort_by_key(|p| p.0);
+    parsed.into_iter().map(|p| p.1).collect()
+}
+
+/// Parse a textual module on `threads` threads, or as many as there are
+/// cores if `threads` is 0. Of several syntax errors, the first one in
+/// the input is returned; an unterminated function definition is found
+/// before any parsing starts.
+pub fn parse_parallel(input: &[u8],threads: usize) -> Result<Module,ParseError> {
+    let elements = split_elements(input)?;
+    let threads = match threads {
+        0 => thread::available_parallelism().map_or(1,|n| n.get()),
+        n => n
+    };
+    let mut m = Module::new();
+    for window in elements.chunks(WINDOW*threads) {
+        for els in parse_window(window,threads.min(window.len())) {
+            for el in els? {
+                m.add_element(el);
+            }
+        }
+    }
+    Ok(m)
+}
+
+// Hands out the input in small pieces, to exercise the buffering.
+#[cfg(test)]
+struct Trickle<'a>(&'a [u8]);
+
+#[cfg(test)]
+impl<'a> Read for Trickle<'a> {
+    fn read(&mut self,out: &mut [u8]) -> ::std::io::Result<usize> {
+        let n = self.0.len().min(out.len()).min(7);
+        out[..n].copy_from_slice(&self.0[..n]);
+        self.0 = &self.0[n..];
+        Ok(n)
+    }
+}
+
+#[test]
+fn test_read_module() {
+    let src = include_bytes!("minisat.ll");
+    assert_eq!(read_module(Trickle(src)).unwrap(),parse_test_module(src));
+    assert_eq!(Some(parse_test_module(src)),::parse_module("src/minisat.ll"));
+}
+
+#[test]
+fn test_elements() {
+    let src = b"; ModuleID = 'm.bc'
+target triple = \"x86_64-unknown-linux-gnu\"
+
+%pair = type { i32, i32 }
+@g = global i32 0, align 4
+
+define i32 @f(i32 %x) {
+entry:
+  br label %exit
+
+exit:
+  ret i32 %x
+}
+
+declare void @h()
+!0 = !{}
+";
+    let mut names = Vec::new();
+    let mut reader = ElementReader::new(&src[..]);
+    while let Some(el) = reader.next() {
+        names.push((reader.line(),match el.unwrap() {
+            ModuleElement::ModuleId(id) => id,
+            ModuleElement::Triple(tr) => tr,
+            ModuleElement::TypeDef(n,_) | ModuleElement::Global(n,_) | ModuleElement::Function(n,_) => n,
+            ModuleElement::Metadata(n,_) => n.to_string(),
+            el => panic!("unexpected element {:?}",el)
+        }));
+    }
+    assert_eq!(names,vec![(1,"m.bc".to_string()),(2,"x86_64-unknown-linux-gnu".to_string()),
+                          (4,"pair".to_string()),(5,"g".to_string()),(7,"f".to_string()),
+                          (15,"h".to_string()),(16,"0".to_string())]);
+}
+
+#[test]
+fn test_read_errors() {
+    let src = b"@g = global i32 0\n\n@h = bogus\n@i = global i32 0\n";
+    let res: Vec<_> = ElementReader::new(&src[..]).collect();
+    assert_eq!(res.len(),2);
+    assert_eq!(res[1],Err(ParseError::Syntax(3)));
+    let src = b"@g = global i32 0\ndefine void @f() {\nentry:\n  ret void\n";
+    assert_eq!(read_module(&src[..]).err(),Some(ParseError::Truncated(2)));
+}
+
+#[test]
+fn test_parse_parallel() {
+    let src = include_bytes!("minisat.ll");
+    let m = parse_test_module(src);
+    for threads in 0..4 {
+        assert_eq!(parse_parallel(src,threads).as_ref(),Ok(&m));
+    }
+    let src = b"@g = global i32 0\n\n@h = bogus\ndefine void @f() {\n  ret void\n";
+    assert_eq!(parse_parallel(src,2).err(),Some(ParseError::Truncated(4)));
+    let src = b"@g = global i32 0\n\n@h = bogus\n@i = bogus\n";
+    assert_eq!(parse_parallel(src,2).err(),Some(ParseError::Syntax(3)));
+}
+
@@ -0,0 +1,1027 @@
+//! Symbolic execution of functions.
+//!
+//! The arguments of the explored function, the results of calls to
+//! functions without a body and the values of selected loads are
+//! symbolic. Every conditional branch or switch whose condition is not
+//! decided by the path so far forks the execution, and each finished
+//! path carries the constraints under which it is taken. These can be
+//! written as SMT-LIB2 scripts (logic `QF_ABV`) or checked with an
+//! external solver, see `smt::Solver`.
+//!
+//! Memory is a single array from addresses to bytes. Globals, allocas
+//! and heap blocks get concrete addresses, so only pointers loaded from
+//! unknown memory or computed from symbolic integers are symbolic.
+//! Calls to defined functions are inlined.
+//!
+//! The translation of single instructions is done by `Lowering`, which
+//! is shared with the bounded model checker in `bmc`.
+use num_bigint::BigInt;
+use num_traits::ToPrimitive;
+use std::cmp::max;
+use std::collections::{HashMap,HashSet};
+use std::fmt;
+use std::io;
+use std::io::Write;
+use super::*;
+use builder::{resolve_type,call_return_type};
+use datalayout::LayoutError;
+use smt::{self,Term,Sort,Op,Solver,SatResult};
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum SymError {
+    UnknownFunction(String),
+    UnknownGlobal(String),
+    UnknownValue(String),
+    UnknownBlock(String),
+    Unsupported(String),
+    /// A type has no memory layout.
+    Layout(LayoutError),
+    /// Running the solver failed.
+    Solver(String)
+}
+
+impl fmt::Display for SymError {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SymError::UnknownFunction(ref n) => write!(f,"unknown function @{}",n),
+            SymError::UnknownGlobal(ref n) => write!(f,"unknown global @{}",n),
+            SymError::UnknownValue(ref n) => write!(f,"unknown value %{}",n),
+            SymError::UnknownBlock(ref n) => write!(f,"unknown block %{}",n),
+            SymError::Unsupported(ref what) => write!(f,"unsupported: {}",what),
+            SymError::Layout(ref e) => write!(f,"{}",e),
+            SymError::Solver(ref msg) => write!(f,"solver failed: {}",msg)
+        }
+    }
+}
+
+impl ::std::error::Error for SymError {}
+
+impl From<LayoutError> for SymError {
+    fn from(e: LayoutError) -> SymError {
+        SymError::Layout(e)
+    }
+}
+
+pub type SymResult<T> = Result<T,SymError>;
+
+/// How a path ends.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum PathEnd {
+    /// The explored function returned.
+    Returned(Option<Term>),
+    /// `exit` was called with the given code.
+    Exited(Term),
+    /// `__assert_fail` or `__VERIFIER_error` was called.
+    AssertionFailed,
+    Aborted,
+    Unreachable,
+    /// The path exceeded the step or call depth limit.
+    BoundReached
+}
+
+/// A finished execution path.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Path {
+    /// The conditions of all branches taken on the path.
+    pub constraints: Vec<Term>,
+    pub end: PathEnd,
+    /// The visited blocks as function and block name.
+    pub blocks: Vec<(String,String)>
+}
+
+impl Path {
+    /// An SMT-LIB2 script that is satisfiable iff the path is feasible.
+    pub fn smtlib(&self) -> String {
+        smt::script("QF_ABV",&self.constraints)
+    }
+    /// Write the script of `smtlib` to a file, stdout, ...
+    pub fn write_smtlib<W: Write>(&self,out: &mut W) -> io::Result<()> {
+        out.write_all(self.smtlib().as_bytes())
+    }
+    /// Decide whether the path is feasible, a model gives inputs that
+    /// take it.
+    pub fn check(&self,solver: &Solver) -> io::Result<SatResult> {
+        solver.check(&self.smtlib())
+    }
+}
+
+/// Memory as an SMT array from addresses to bytes. The bytes written
+/// at concrete addresses since the last write to a symbolic address
+/// are cached, so that reading them does not search the array.
+#[derive(Debug,Clone)]
+pub struct Memory {
+    array: Term,
+    bytes: HashMap<u64,Term>
+}
+
+impl Memory {
+    pub fn new(array: Term) -> Memory {
+        Memory { array, bytes: HashMap::new() }
+    }
+    pub fn array(&self) -> &Term {
+        &self.array
+    }
+    pub fn read(&self,addr: Term) -> Term {
+        match addr.as_bv().and_then(|a| a.to_u64()).and_then(|a| self.bytes.get(&a)) {
+            Some(b) => b.clone(),
+            None => Term::select(self.array.clone(),addr)
+        }
+    }
+    pub fn write(&mut self,addr: Term,byte: Term) {
+        match addr.as_bv().and_then(|a| a.to_u64()) {
+            Some(a) => {
+                self.bytes.insert(a,byte.clone());
+            },
+            None => self.bytes.clear()
+        }
+        self.array = Term::store(self.array.clone(),addr,byte);
+    }
+    /// The memory that is `m` for the first case whose condition holds,
+    /// the last memory is used if none holds.
+    pub fn merge(cases: &[(Term,Memory)]) -> Memory {
+        let mut res = cases.last().expect("no memory to merge").1.clone();
+        for (c,m) in cases.iter().rev().skip(1) {
+            res.array = Term::ite(c.clone(),m.array.clone(),res.array);
+            let bytes = res.bytes.into_iter()
+                .filter_map(|(a,b)| m.bytes.get(&a).map(|mb| (a,Term::ite(c.clone(),mb.clone(),b))))
+                .collect();
+            res.bytes = bytes;
+        }
+        res
+    }
+}
+
+/// Hands out concrete addresses for allocations and names for fresh
+/// variables.
+#[derive(Debug,Clone)]
+pub struct Allocator {
+    next: u64,
+    /// The sizes of heap blocks by address.
+    heap: HashMap<u64,u64>,
+    fresh: usize
+}
+
+impl Allocator {
+    pub fn allocate(&mut self,size: u64,align: u64) -> u64 {
+        let addr = align_to(self.next,max(align,1));
+        self.next = addr+max(size,1)+16;
+        addr
+    }
+    /// A new bit-vector variable `<prefix>!<n>`.
+    pub fn fresh(&mut self,prefix: &str,width: u64) -> Term {
+        self.fresh += 1;
+        Term::var(&format!("{}!{}",prefix,self.fresh),Sort::BitVec(width))
+    }
+}
+
+/// The effect of an instruction, see `Lowering::instruction`.
+pub enum Effect<'m> {
+    /// The instruction was executed, with the value it defines.
+    Value(Option<Term>),
+    /// A call to a function with a body.
+    Call(&'m Function,Vec<Term>),
+    /// The instruction ends the path.
+    End(PathEnd),
+    /// The instruction is the terminator of the block.
+    Terminator(&'m Terminator)
+}
+
+/// Default heap block size for `malloc` with a symbolic size.
+const SYMBOLIC_ALLOC_SIZE: u64 = 1 << 20;
+
+/// The translation of values, memory accesses and instructions of a
+/// module to terms. Globals and functions are placed at fixed
+/// addresses starting at `0x1000`.
+pub struct Lowering<'m> {
+    module: &'m Module,
+    globals: HashMap<&'m str,u64>,
+    functions: HashMap<u64,&'m str>,
+    heap_start: u64,
+    pointer_bits: u64,
+    little_endian: bool
+}
+
+impl<'m> Lowering<'m> {
+    pub fn new(module: &'m Module) -> SymResult<Lowering<'m>> {
+        let dl = &module.datalayout;
+        let mut res = Lowering { module,
+                                 globals: HashMap::new(),
+                                 functions: HashMap::new(),
+                                 heap_start: 0,
+                                 pointer_bits: dl.pointer_alignment(0).0,
+                                 little_endian: dl.endianess()==Endian::Little };
+        let mut next = 0x1000;
+        let mut globals: Vec<(&String,&GlobalVariable)> = module.globals.iter().collect();
+        globals.sort_by_key(|&(n,_)| n);
+        for (name,glob) in globals {
+            let size = dl.type_alloc_size(&glob.types,&module.types)?;
+            let align = match glob.alignment {
+                Some(a) => a,
+                None => dl.type_alignment(&glob.types,&module.types)?
+            };
+            let addr = align_to(next,max(align,1));
+            res.globals.insert(name,addr);
+            next = addr+max(size,1)+16;
+        }
+        let mut funs: Vec<&String> = module.functions.keys().collect();
+        funs.sort();
+        for name in funs {
+            res.globals.insert(name,next);
+            res.functions.insert(next,name);
+            next += 16;
+        }
+        res.heap_start = next;
+        Ok(res)
+    }
+    pub fn module(&self) -> &'m Module {
+        self.module
+    }
+    /// The address of a global variable or function.
+    pub fn global(&self,name: &str) -> Option<u64> {
+        self.globals.get(name).cloned()
+    }
+    /// The unknown array `mem0` with the initializers of all globals
+    /// written to it.
+    pub fn initial_memory(&self) -> SymResult<Memory> {
+        let sort = Sort::Array(Box::new(Sort::BitVec(self.pointer_bits)),Box::new(Sort::BitVec(8)));
+        let mut mem = Memory::new(Term::var("mem0",sort));
+        let mut globals: Vec<(&String,&GlobalVariable)> = self.module.globals.iter().collect();
+        globals.sort_by_key(|&(n,_)| n);
+        for (name,glob) in globals {
+            if let Some(ref c) = glob.initialization {
+                let addr = self.address(self.globals[&name[..]]);
+                self.store_constant(&mut mem,addr,&glob.types,c)?;
+            }
+        }
+        Ok(mem)
+    }
+    /// An allocator for addresses after all globals and functions.
+    pub fn allocator(&self) -> Allocator {
+        Allocator { next: self.heap_start, heap: HashMap::new(), fresh: 0 }
+    }
+    /// The variables `arg.<name>` (or `arg.<index>` for unnamed ones)
+    /// for the arguments of a function.
+    pub fn arguments(&self,fun: &Function) -> SymResult<Vec<Term>> {
+        let mut args = Vec::with_capacity(fun.arguments.len());
+        for (i,(n,tp)) in fun.arguments.iter().enumerate() {
+            let var = match *n {
+                Some(ref n) => format!("arg.{}",n),
+                None => format!("arg.{}",i)
+            };
+            args.push(Term::var(&var,Sort::BitVec(self.width(tp)?)));
+        }
+        Ok(args)
+    }
+    /// Execute an instruction other than a phi node.
+    pub fn instruction(&self,locals: &HashMap<&'m str,Term>,args: &[Term],instr: &'m InstructionC,
+                       mem: &mut Memory,alloc: &mut Allocator) -> SymResult<Effect<'m>> {
+        let value = |tp: &Type,v: &Value| self.value(locals,args,tp,v);
+        let res = match *instr {
+            InstructionC::Alloca(_,ref tp,ref count,align) => {
+                let n = match *count {
+                    Some(ref c) => match value(&c.tp,&c.val)?.as_bv().and_then(|n| n.to_u64()) {
+                        Some(n) => n,
+                        None => return Err(SymError::Unsupported("alloca of symbolic size".to_string()))
+                    },
+                    None => 1
+                };
+                let dl = &self.module.datalayout;
+                let size = n*dl.type_alloc_size(tp,&self.module.types)?;
+                let align = match align {
+                    Some(a) => a,
+                    None => dl.type_alignment(tp,&self.module.types)?
+                };
+                self.address(alloc.allocate(size,align))
+            },
+            InstructionC::Call(_,_,ref tp,ref callee,ref call_args,_) => {
+                let fun = self.callee(locals,args,callee)?;
+                if fun.name.starts_with("llvm.dbg.") || fun.name.starts_with("llvm.lifetime.") {
+                    return Ok(Effect::Value(None))
+                }
+                let mut vals = Vec::with_capacity(call_args.len());
+                for arg in call_args.iter() {
+                    vals.push(value(&arg.tp,&arg.val)?);
+                }
+                if fun.body.is_some() {
+                    return Ok(Effect::Call(fun,vals))
+                }
+                let ret_tp = tp.as_ref().and_then(|(tp,_)| call_return_type(tp));
+                return match self.call_external(mem,alloc,&fun.name,&vals,ret_tp.as_ref())? {
+                    Err(end) => Ok(Effect::End(end)),
+                    Ok(v) => Ok(Effect::Value(if instr.name().is_some() { v } else { None }))
+                }
+            },
+            InstructionC::ICmp(_,ref op,ref tp,ref lhs,ref rhs) =>
+                from_bool(compare(op,value(tp,lhs)?,value(tp,rhs)?)),
+            InstructionC::Unary(_,ref arg,UnaryInst::Load(..)) => {
+                let ptr = value(&arg.tp,&arg.val)?;
+                self.load(mem,ptr,&self.loaded_type(&arg.tp)?)?
+            },
+            InstructionC::Unary(_,ref arg,UnaryInst::Cast(ref tp,op)) => {
+                let v = value(&arg.tp,&arg.val)?;
+                Term::resize(v,self.width(tp)?,op==CastInst::SExt)
+            },
+            InstructionC::GEP(_,ref g) => {
+                let base = value(&g.ptr.tp,&g.ptr.val)?;
+                let mut idx = Vec::with_capacity(g.indices.len());
+                for (i,_) in g.indices.iter() {
+                    idx.push(value(&i.tp,&i.val)?);
+                }
+                self.gep(&g.ptr.tp,base,idx)?
+            },
+            InstructionC::Store(_,ref val,ref ptr,_) => {
+                let v = value(&val.tp,&val.val)?;
+                let p = value(&ptr.tp,&ptr.val)?;
+                self.store(mem,p,&val.tp,v)?;
+                return Ok(Effect::Value(None))
+            },
+            InstructionC::Select(_,ref c,ref tp,ref v1,ref v2) =>
+                Term::ite(is_true(value(&Type::Int(1),c)?),value(tp,v1)?,value(tp,v2)?),
+            InstructionC::Phi(..) => return Err(SymError::Unsupported("phi after the start of a block".to_string())),
+            InstructionC::Bin(_,ref op,ref tp,ref lhs,ref rhs) =>
+                Term::bin(binary_op(op),value(tp,lhs)?,value(tp,rhs)?),
+            InstructionC::Term(ref t) => return Ok(Effect::Terminator(t))
+        };
+        Ok(Effect::Value(Some(res)))
+    }
+    /// The outgoing edges of a conditional terminator as conditions and
+    /// target labels. `None` for `ret` and `unreachable`.
+    pub fn branches(&self,locals: &HashMap<&'m str,Term>,args: &[Term],term: &'m Terminator)
+                    -> SymResult<Option<Vec<(Term,&'m str)>>> {
+        Ok(Some(match *term {
+            Terminator::Br(ref trg) => vec![(Term::bool(true),&trg[..])],
+            Terminator::BrC(ref c,ref t,ref f) => {
+                let c = is_true(self.value(locals,args,&Type::Int(1),c)?);
+                vec![(c.clone(),&t[..]),(!c,&f[..])]
+            },
+            Terminator::Switch(ref tp,ref v,ref def,ref cases) => {
+                let v = self.value(locals,args,tp,v)?;
+                let mut targets = Vec::with_capacity(cases.len()+1);
+                let mut others = Vec::with_capacity(cases.len());
+                for (c,lbl) in cases.iter() {
+                    let eq = Term::eq(v.clone(),self.constant(tp,c)?);
+                    others.push(!eq.clone());
+                    targets.push((eq,&lbl[..]));
+                }
+                targets.push((Term::and(others),&def[..]));
+                targets
+            },
+            Terminator::Ret(_) | Terminator::Unreachable => return Ok(None)
+        }))
+    }
+    /// Model a call to a function without a body. Returns the result of
+    /// the call or how the path ends. Unknown functions return a fresh
+    /// value and do not change memory.
+    pub fn call_external(&self,mem: &mut Memory,alloc: &mut Allocator,name: &str,args: &[Term],
+                         ret_tp: Option<&Type>) -> SymResult<Result<Option<Term>,PathEnd>> {
+        let arg = |n: usize| args.get(n).cloned()
+            .ok_or_else(|| SymError::Unsupported(format!("too few arguments for @{}",name)));
+        let base = match name.find(".p0") {
+            Some(pos) if name.starts_with("llvm.") => &name[..pos],
+            _ => name
+        };
+        match base {
+            "__assert_fail" | "__VERIFIER_error" => return Ok(Err(PathEnd::AssertionFailed)),
+            "abort" => return Ok(Err(PathEnd::Aborted)),
+            "exit" => return Ok(Err(PathEnd::Exited(arg(0)?))),
+            "malloc" | "calloc" | "realloc" => {
+                let size = match base {
+                    "malloc" => arg(0)?,
+                    "calloc" => Term::bin(Op::BvMul,arg(0)?,arg(1)?),
+                    _ => arg(1)?
+                };
+                let n = size.as_bv().and_then(|n| n.to_u64());
+                let size = n.unwrap_or(SYMBOLIC_ALLOC_SIZE);
+                let addr = alloc.allocate(size,16);
+                alloc.heap.insert(addr,size);
+                if base=="calloc" && n.is_some() {
+                    for i in 0..size {
+                        mem.write(self.address(addr+i),Term::bv_i64(8,0));
+                    }
+                } else if base=="realloc" {
+                    // Only blocks at known addresses can be copied
+                    let old = arg(0)?;
+                    if let Some(old_size) = old.as_bv().and_then(|a| a.to_u64()).and_then(|a| alloc.heap.get(&a)) {
+                        for i in 0..min(*old_size,size) {
+                            let byte = mem.read(self.offset(&old,i));
+                            mem.write(self.address(addr+i),byte);
+                        }
+                    }
+                }
+                return Ok(Ok(Some(self.address(addr))))
+            },
+            "free" => return Ok(Ok(None)),
+            "memset" | "llvm.memset" => {
+                if let Some(n) = arg(2)?.as_bv().and_then(|n| n.to_u64()) {
+                    let byte = Term::resize(arg(1)?,8,false);
+                    let dst = arg(0)?;
+                    for i in 0..n {
+                        mem.write(self.offset(&dst,i),byte.clone());
+                    }
+                    return Ok(Ok(if base=="memset" { Some(dst) } else { None }))
+                }
+            },
+            "memcpy" | "memmove" | "llvm.memcpy" | "llvm.memmove" => {
+                if let Some(n) = arg(2)?.as_bv().and_then(|n| n.to_u64()) {
+                    let (dst,src) = (arg(0)?,arg(1)?);
+                    let bytes: Vec<Term> = (0..n).map(|i| mem.read(self.offset(&src,i))).collect();
+                    for (i,b) in bytes.into_iter().enumerate() {
+                        mem.write(self.offset(&dst,i as u64),b);
+                    }
+                    return Ok(Ok(if base.starts_with("llvm.") { None } else { Some(dst) }))
+                }
+            },
+            _ => {}
+        }
+        match ret_tp {
+            Some(tp) => {
+                let w = self.width(tp)?;
+                Ok(Ok(Some(alloc.fresh(&format!("ret.{}",name),w))))
+            },
+            None => Ok(Ok(None))
+        }
+    }
+    pub fn callee(&self,locals: &HashMap<&'m str,Term>,args: &[Term],callee: &Value) -> SymResult<&'m Function> {
+        let name = match *callee {
+            Value::Constant(Constant::Global(ref n)) => &n[..],
+            _ => {
+                let ptr = self.value(locals,args,&Type::ptr(Type::Int(8)),callee)?;
+                match ptr.as_bv().and_then(|a| a.to_u64()).and_then(|a| self.functions.get(&a)) {
+                    Some(n) => *n,
+                    None => return Err(SymError::Unsupported(format!("call of {}",ptr)))
+                }
+            }
+        };
+        self.module.functions.get(name).ok_or_else(|| SymError::UnknownFunction(name.to_string()))
+    }
+    pub fn value(&self,locals: &HashMap<&'m str,Term>,args: &[Term],tp: &Type,val: &Value) -> SymResult<Term> {
+        match *val {
+            Value::Constant(ref c) => self.constant(tp,c),
+            Value::Local(ref name) => locals.get(&name[..]).cloned()
+                .ok_or_else(|| SymError::UnknownValue(name.clone())),
+            Value::Argument(n) => args.get(n).cloned()
+                .ok_or_else(|| SymError::UnknownValue(format!("{}",n))),
+            Value::Metadata(_) => Err(SymError::Unsupported("metadata operand".to_string()))
+        }
+    }
+    pub fn constant(&self,tp: &Type,c: &Constant) -> SymResult<Term> {
+        match *c {
+            Constant::Int(ref i) => Ok(Term::bv(self.width(tp)?,i.clone())),
+            Constant::Global(ref name) => self.globals.get(&name[..])
+                .map(|a| self.address(*a))
+                .ok_or_else(|| SymError::UnknownGlobal(name.clone())),
+            Constant::NullPtr => Ok(self.address(0)),
+            // Picking one value for undef, like the interpreter, only
+            // loses paths
+            Constant::Undef => Ok(Term::bv(self.width(tp)?,BigInt::from(0))),
+            Constant::GEP(ref g) => {
+                let base = self.constant(&g.ptr.tp,&g.ptr.val)?;
+                let mut idx = Vec::with_capacity(g.indices.len());
+                for (i,_) in g.indices.iter() {
+                    idx.push(self.constant(&i.tp,&i.val)?);
+                }
+                self.gep(&g.ptr.tp,base,idx)
+            },
+            Constant::Cast(op,ref c,ref to) => {
+                let v = self.constant(&c.tp,&c.val)?;
+                Ok(Term::resize(v,self.width(to)?,op==CastInst::SExt))
+            },
+            Constant::Array(_) => Err(SymError::Unsupported("aggregate value".to_string()))
+        }
+    }
+    /// The width of the bit-vectors representing values of a type.
+    pub fn width(&self,tp: &Type) -> SymResult<u64> {
+        match *resolve_type(&self.module.types,tp) {
+            Type::Int(w) => Ok(w),
+            Type::Pointer(..) => Ok(self.pointer_bits),
+            ref t => Err(SymError::Unsupported(format!("value of type {:?}",t)))
+        }
+    }
+    /// The type loaded through a pointer type.
+    pub fn loaded_type(&self,ptr_tp: &Type) -> SymResult<Type> {
+        match *resolve_type(&self.module.types,ptr_tp) {
+            Type::Pointer(ref el,_) => Ok((**el).clone()),
+            ref t => Err(SymError::Unsupported(format!("load from {:?}",t)))
+        }
+    }
+    pub fn address(&self,addr: u64) -> Term {
+        Term::bv(self.pointer_bits,BigInt::from(addr))
+    }
+    pub fn offset(&self,ptr: &Term,off: u64) -> Term {
+        Term::bin(Op::BvAdd,ptr.clone(),self.address(off))
+    }
+    pub fn store_constant(&self,mem: &mut Memory,ptr: Term,tp: &Type,c: &Constant) -> SymResult<()> {
+        let types = &self.module.types;
+        let dl = &self.module.datalayout;
+        match (resolve_type(types,tp),c) {
+            (Type::Array(_,el),Constant::Array(elems)) => {
+                let sz = dl.type_alloc_size(el,types)?;
+                for (i,e) in elems.iter().enumerate() {
+                    self.store_constant(mem,self.offset(&ptr,i as u64*sz),el,e)?;
+                }
+                Ok(())
+            },
+            (Type::Struct(els),Constant::Array(elems)) => {
+                let layout = dl.struct_layout(els,types)?;
+                for (i,(el,e)) in els.iter().zip(elems.iter()).enumerate() {
+                    self.store_constant(mem,self.offset(&ptr,layout.offsets[i]),el,e)?;
+                }
+                Ok(())
+            },
+            _ => {
+                let v = self.constant(tp,c)?;
+                self.store(mem,ptr,tp,v)
+            }
+        }
+    }
+    pub fn load(&self,mem: &Memory,ptr: Term,tp: &Type) -> SymResult<Term> {
+        let w = self.width(tp)?;
+        let size = self.module.datalayout.type_store_size(tp,&self.module.types)?;
+        let mut res: Option<Term> = None;
+        for i in 0..size {
+            let byte = mem.read(self.offset(&ptr,i));
+            res = Some(match res {
+                None => byte,
+                Some(r) => if self.little_endian {
+                    Term::concat(byte,r)
+                } else {
+                    Term::concat(r,byte)
+                }
+            });
+        }
+        Ok(Term::resize(res.unwrap(),w,false))
+    }
+    pub fn store(&self,mem: &mut Memory,ptr: Term,tp: &Type,val: Term) -> SymResult<()> {
+        let size = self.module.datalayout.type_store_size(tp,&self.module.types)?;
+        let val = Term::resize(val,size*8,false);
+        for i in 0..size {
+            let byte = if self.little_endian { i } else { size-1-i };
+            mem.write(self.offset(&ptr,i),Term::extract(8*byte+7,8*byte,val.clone()));
+        }
+        Ok(())
+    }
+    pub fn gep(&self,ptr_tp: &Type,base: Term,indices: Vec<Term>) -> SymResult<Term> {
+        let types = &self.module.types;
+        let dl = &self.module.datalayout;
+        let pw = self.pointer_bits;
+        let mut cur = match *resolve_type(types,ptr_tp) {
+            Type::Pointer(ref el,_) => &**el,
+            ref t => return Err(SymError::Unsupported(format!("getelementptr on {:?}",t)))
+        };
+        let mut res = base;
+        let scaled = |idx: Term,size: u64| Term::bin(Op::BvMul,Term::resize(idx,pw,true),
+                                                    Term::bv(pw,BigInt::from(size)));
+        for (n,idx) in indices.into_iter().enumerate() {
+            if n==0 {
+                res = Term::bin(Op::BvAdd,res,scaled(idx,dl.type_alloc_size(cur,types)?));
+                continue
+            }
+            cur = match *resolve_type(types,cur) {
+                Type::Array(_,ref el) => {
+                    res = Term::bin(Op::BvAdd,res,scaled(idx,dl.type_alloc_size(el,types)?));
+                    el
+                },
+                Type::Struct(ref els) => {
+                    let i = match idx.as_bv().and_then(|i| i.to_usize()) {
+                        Some(i) if i < els.len() => i,
+                        _ => return Err(SymError::Unsupported("symbolic struct index".to_string()))
+                    };
+                    let layout = dl.struct_layout(els,types)?;
+                    res = self.offset(&res,layout.element_offset(i));
+                    &els[i]
+                },
+                ref t => return Err(SymError::Unsupported(format!("getelementptr into {:?}",t)))
+            };
+        }
+        Ok(res)
+    }
+}
+
+/// An `i1` value as a boolean.
+fn is_true(v: Term) -> Term {
+    Term::eq(v,Term::bv_i64(1,1))
+}
+
+fn from_bool(b: Term) -> Term {
+    Term::ite(b,Term::bv_i64(1,1),Term::bv_i64(1,0))
+}
+
+fn compare(op: &CmpOp,l: Term,r: Term) -> Term {
+    match *op {
+        CmpOp::Eq => Term::eq(l,r),
+        CmpOp::Ne => !Term::eq(l,r),
+        CmpOp::ULt => Term::bin(Op::BvUlt,l,r),
+        CmpOp::ULe => Term::bin(Op::BvUle,l,r),
+        CmpOp::UGt => Term::bin(Op::BvUlt,r,l),
+        CmpOp::UGe => Term::bin(Op::BvUle,r,l),
+        CmpOp::SLt => Term::bin(Op::BvSlt,l,r),
+        CmpOp::SLe => Term::bin(Op::BvSle,l,r),
+        CmpOp::SGt => Term::bin(Op::BvSlt,r,l),
+        CmpOp::SGe => Term::bin(Op::BvSle,r,l)
+    }
+}
+
+/// The bit-vector operation of a binary instruction. Results that
+/// would be poison (overflowing shifts, division by zero) get the
+/// SMT-LIB semantics instead.
+fn binary_op(op: &BinOp) -> Op {
+    match *op {
+        BinOp::Add(..) => Op::BvAdd,
+        BinOp::Sub(..) => Op::BvSub,
+        BinOp::Mul(..) => Op::BvMul,
+        BinOp::And => Op::BvAnd,
+        BinOp::Or => Op::BvOr,
+        BinOp::XOr => Op::BvXor,
+        BinOp::AShr => Op::BvAShr,
+        BinOp::LShr => Op::BvLShr,
+        BinOp::Shl => Op::BvShl,
+        BinOp::SDiv(_) => Op::BvSDiv
+    }
+}
+
+
+#[derive(Clone)]
+struct Frame<'m> {
+    function: &'m Function,
+    blocks: &'m [BasicBlock],
+    block: usize,
+    instr: usize,
+    locals: HashMap<&'m str,Term>,
+    args: Vec<Term>
+}
+
+#[derive(Clone)]
+struct State<'m> {
+    frames: Vec<Frame<'m>>,
+    memory: Memory,
+    alloc: Allocator,
+    constraints: Vec<Term>,
+    blocks: Vec<(String,String)>,
+    steps: usize
+}
+
+impl<'m> State<'m> {
+    fn frame(&self) -> &Frame<'m> {
+        self.frames.last().unwrap()
+    }
+    fn frame_mut(&mut self) -> &mut Frame<'m> {
+        self.frames.last_mut().unwrap()
+    }
+    fn enter_block(&mut self) {
+        let fr = self.frame();
+        let name = (fr.function.name.clone(),fr.blocks[fr.block].name.clone());
+        self.blocks.push(name);
+    }
+}
+
+enum Step<'m> {
+    Continue,
+    Call(&'m Function,Vec<Term>),
+    Return(Option<Term>),
+    /// Continue at one of the labels under the respective condition.
+    Branch(Vec<(Term,&'m str)>),
+    End(PathEnd)
+}
+
+pub struct SymbolicExecutor<'m> {
+    lowering: Lowering<'m>,
+    labels: HashMap<&'m str,HashMap<&'m str,usize>>,
+    symbolic_loads: HashSet<(String,String)>,
+    initial_memory: Memory,
+    solver: Option<Solver>,
+    max_paths: usize,
+    max_steps: usize,
+    max_depth: usize
+}
+
+impl<'m> SymbolicExecutor<'m> {
+    /// Create an executor for a module. Globals are placed in memory
+    /// and initialised, the rest of memory is the unknown array `mem0`.
+    pub fn new(module: &'m Module) -> SymResult<SymbolicExecutor<'m>> {
+        let lowering = Lowering::new(module)?;
+        let initial_memory = lowering.initial_memory()?;
+        let labels = module.functions.iter()
+            .filter_map(|(name,f)| f.body.as_ref().map(|blks| {
+                (&name[..],blks.iter().enumerate().map(|(i,b)| (&b.name[..],i)).collect())
+            }))
+            .collect();
+        Ok(SymbolicExecutor { lowering,
+                              labels,
+                              symbolic_loads: HashSet::new(),
+                              initial_memory,
+                              solver: None,
+                              max_paths: 1000,
+                              max_steps: 100000,
+                              max_depth: 64 })
+    }
+    /// Make loads defining the local `name` in `function` return a
+    /// fresh symbolic value instead of reading memory.
+    pub fn symbolic_load(&mut self,function: &str,name: &str) {
+        self.symbolic_loads.insert((function.to_string(),name.to_string()));
+    }
+    /// Use a solver to drop infeasible paths when forking. Without one,
+    /// only conditions that simplify to `false` are pruned.
+    pub fn set_solver(&mut self,solver: Option<Solver>) {
+        self.solver = solver;
+    }
+    /// Stop after this many paths, 1000 by default.
+    pub fn set_max_paths(&mut self,n: usize) {
+        self.max_paths = n;
+    }
+    /// The maximal number of instructions per path, 100000 by default.
+    pub fn set_max_steps(&mut self,n: usize) {
+        self.max_steps = n;
+    }
+    /// The maximal number of nested inlined calls, 64 by default.
+    pub fn set_max_depth(&mut self,n: usize) {
+        self.max_depth = n;
+    }
+    /// The address of a global variable or function.
+    pub fn global(&self,name: &str) -> Option<u64> {
+        self.lowering.global(name)
+    }
+    /// Explore the paths through a function, depth first. Its
+    /// arguments are the variables `arg.<name>` (or `arg.<index>` for
+    /// unnamed ones).
+    pub fn explore(&self,name: &str) -> SymResult<Vec<Path>> {
+        let fun = self.lowering.module().functions.get(name)
+            .ok_or_else(|| SymError::UnknownFunction(name.to_string()))?;
+        let args = self.lowering.arguments(fun)?;
+        let mut init = State { frames: vec![self.enter(fun,args)?],
+                               memory: self.initial_memory.clone(),
+                               alloc: self.lowering.allocator(),
+                               constraints: Vec::new(),
+                               blocks: Vec::new(),
+                               steps: 0 };
+        init.enter_block();
+        let mut work = vec![init];
+        let mut paths = Vec::new();
+        while let Some(mut st) = work.pop() {
+            if paths.len() >= self.max_paths {
+                break
+            }
+            // `None` if the state forked or turned out to be infeasible
+            let end = loop {
+                if st.steps >= self.max_steps {
+                    break Some(PathEnd::BoundReached)
+                }
+                st.steps += 1;
+                match self.step(&mut st)? {
+                    Step::Continue => {},
+                    Step::Call(fun,args) => {
+                        if st.frames.len() >= self.max_depth {
+                            break Some(PathEnd::BoundReached)
+                        }
+                        let frame = self.enter(fun,args)?;
+                        st.frames.push(frame);
+                        st.enter_block();
+                    },
+                    Step::Return(val) => {
+                        st.frames.pop();
+                        let caller = match st.frames.last_mut() {
+                            None => break Some(PathEnd::Returned(val)),
+                            Some(c) => c
+                        };
+                        let instr = &caller.blocks[caller.block].instrs[caller.instr].content;
+                        if let (Some(name),Some(v)) = (instr.name(),val) {
+                            caller.locals.insert(name,v);
+                        }
+                        caller.instr += 1;
+                    },
+                    Step::Branch(targets) => {
+                        let mut feasible = Vec::new();
+                        for (cond,lbl) in targets {
+                            let keep = match cond.as_bool() {
+                                Some(b) => b,
+                                None => self.feasible(&st,&cond)?
+                            };
+                            if keep {
+                                feasible.push((cond,lbl));
+                            }
+                        }
+                        if feasible.len()!=1 {
+                            // Explore the first target first
+                            for (cond,lbl) in feasible.into_iter().rev() {
+                                let mut next = st.clone();
+                                next.constraints.push(cond);
+                                self.jump(&mut next,lbl)?;
+                                work.push(next);
+                            }
+                            break None
+                        }
+                        let (cond,lbl) = feasible.pop().unwrap();
+                        if cond.as_bool().is_none() {
+                            st.constraints.push(cond);
+                        }
+                        self.jump(&mut st,lbl)?;
+                    },
+                    Step::End(end) => break Some(end)
+                }
+            };
+            if let Some(end) = end {
+                paths.push(Path { constraints: st.constraints,
+                                  end,
+                                  blocks: st.blocks });
+            }
+        }
+        Ok(paths)
+    }
+
+    fn feasible(&self,st: &State<'m>,cond: &Term) -> SymResult<bool> {
+        let solver = match self.solver {
+            None => return Ok(true),
+            Some(ref s) => s
+        };
+        let mut cs = st.constraints.clone();
+        cs.push(cond.clone());
+        match solver.check(&smt::script("QF_ABV",&cs)) {
+            Ok(SatResult::Unsat) => Ok(false),
+            Ok(_) => Ok(true),
+            Err(err) => Err(SymError::Solver(err.to_string()))
+        }
+    }
+    fn enter(&self,fun: &'m Function,args: Vec<Term>) -> SymResult<Frame<'m>> {
+        let blocks = match fun.body {
+            Some(ref blks) if !blks.is_empty() => &blks[..],
+            _ => return Err(SymError::Unsupported(format!("@{} has no body",fun.name)))
+        };
+        Ok(Frame { function: fun,
+                   blocks,
+                   block: 0,
+                   instr: 0,
+                   locals: HashMap::new(),
+                   args })
+    }
+    fn step(&self,st: &mut State<'m>) -> SymResult<Step<'m>> {
+        let State { ref mut frames,ref mut memory,ref mut alloc,.. } = *st;
+        let frame = frames.last_mut().unwrap();
+        let blocks: &'m [BasicBlock] = frame.blocks;
+        let instr = &blocks[frame.block].instrs[frame.instr].content;
+        if let InstructionC::Unary(ref name,ref arg,UnaryInst::Load(..)) = *instr {
+            if self.symbolic_loads.contains(&(frame.function.name.clone(),name.clone())) {
+                let w = self.lowering.width(&self.lowering.loaded_type(&arg.tp)?)?;
+                let v = alloc.fresh(&format!("{}.{}",frame.function.name,name),w);
+                frame.locals.insert(name,v);
+                frame.instr += 1;
+                return Ok(Step::Continue)
+            }
+        }
+        match self.lowering.instruction(&frame.locals,&frame.args,instr,memory,alloc)? {
+            Effect::Value(v) => {
+                if let (Some(name),Some(v)) = (instr.name(),v) {
+                    frame.locals.insert(name,v);
+                }
+                frame.instr += 1;
+                Ok(Step::Continue)
+            },
+            Effect::Call(fun,args) => Ok(Step::Call(fun,args)),
+            Effect::End(end) => Ok(Step::End(end)),
+            Effect::Terminator(term) => Ok(match *term {
+                Terminator::Ret(ref val) => match *val {
+                    None => Step::Return(None),
+                    Some(ref v) => Step::Return(Some(self.lowering.value(&frame.locals,&frame.args,&v.tp,&v.val)?))
+                },
+                Terminator::Unreachable => Step::End(PathEnd::Unreachable),
+                _ => Step::Branch(self.lowering.branches(&frame.locals,&frame.args,term)?.unwrap())
+            })
+        }
+    }
+    /// Continue at the start of a block, evaluating its phi nodes
+    /// simultaneously.
+    fn jump(&self,st: &mut State<'m>,label: &str) -> SymResult<()> {
+        {
+            let frame = st.frame_mut();
+            let trg = *self.labels[&frame.function.name[..]].get(label)
+                .ok_or_else(|| SymError::UnknownBlock(label.to_string()))?;
+            let from = &frame.blocks[frame.block].name;
+            let mut vals = Vec::new();
+            for instr in frame.blocks[trg].instrs.iter() {
+                match instr.content {
+                    InstructionC::Phi(ref name,ref tp,ref incoming) => {
+                        let v = match incoming.iter().find(|(_,l)| l==from) {
+                            Some((v,_)) => self.lowering.value(&frame.locals,&frame.args,tp,v)?,
+                            None => return Err(SymError::UnknownBlock(from.clone()))
+                        };
+                        vals.push((&name[..],v));
+                    },
+                    _ => break
+                }
+            }
+            frame.block = trg;
+            frame.instr = vals.len();
+            for (name,v) in vals {
+                frame.locals.insert(name,v);
+            }
+        }
+        st.enter_block();
+        Ok(())
+    }
+}
+
+#[test]
+fn test_symex_paths() {
+    let m = parse_test_module(b"@limit = global i32 10, align 4
+
+declare void @__assert_fail(i8*, i8*, i32, i8*)
+
+define i32 @check(i32 %x, i32 %y) {
+entry:
+  %p = alloca i32, align 4
+  store i32 %x, i32* %p, align 4
+  %l = load i32* @limit, align 4
+  %c1 = icmp slt i32 %x, %l
+  br i1 %c1, label %small, label %large
+
+small:
+  %x2 = load i32* %p, align 4
+  %s = add nsw i32 %x2, %y
+  %c2 = icmp eq i32 %s, 7
+  br i1 %c2, label %fail, label %done
+
+fail:
+  call void @__assert_fail(i8* null, i8* null, i32 0, i8* null)
+  unreachable
+
+large:
+  %c3 = icmp sgt i32 %x, 5
+  br i1 %c3, label %done, label %dead
+
+dead:
+  unreachable
+
+done:
+  %r = phi i32 [ %s, %small ], [ 1, %large ]
+  ret i32 %r
+}
+");
+    let exec = SymbolicExecutor::new(&m).unwrap();
+    let paths = exec.explore("check").unwrap();
+    let ends: Vec<&PathEnd> = paths.iter().map(|p| &p.end).collect();
+    // x >= 10 implies x > 5, but without a solver the dead block is
+    // still explored
+    assert_eq!(paths.len(),4);
+    assert_eq!(ends[0],&PathEnd::AssertionFailed);
+    assert!(matches!(*ends[1],PathEnd::Returned(Some(_))));
+    assert_eq!(ends[2],&PathEnd::Returned(Some(Term::bv_i64(32,1))));
+    assert_eq!(ends[3],&PathEnd::Unreachable);
+    let x = Term::var("arg.x",Sort::BitVec(32));
+    let y = Term::var("arg.y",Sort::BitVec(32));
+    // The load from the alloca sees the stored argument
+    assert_eq!(paths[0].constraints,
+               vec![Term::bin(Op::BvSlt,x.clone(),Term::bv_i64(32,10)),
+                    Term::eq(Term::bin(Op::BvAdd,x,y),Term::bv_i64(32,7))]);
+    assert_eq!(paths[0].blocks.iter().map(|b| &b.1[..]).collect::<Vec<_>>(),
+               vec!["entry","small","fail"]);
+    let script = paths[0].smtlib();
+    assert!(script.starts_with("(set-option :produce-models true)\n(set-logic QF_ABV)\n"));
+    assert!(script.contains("(declare-fun arg.x () (_ BitVec 32))\n"));
+    assert!(script.contains("(assert (bvslt arg.x (_ bv10 32)))\n"));
+    assert!(script.ends_with("(check-sat)\n(get-model)\n"));
+    let mut out = Vec::new();
+    paths[0].write_smtlib(&mut out).unwrap();
+    assert_eq!(out,script.into_bytes());
+    if let Some(solver) = Solver::find() {
+        let mut exec = SymbolicExecutor::new(&m).unwrap();
+        exec.set_solver(Some(solver.clone()));
+        let paths = exec.explore("check").unwrap();
+        assert_eq!(paths.len(),3);
+        assert!(matches!(paths[0].check(&solver).unwrap(),SatResult::Sat(_)));
+    }
+}
+
+#[test]
+fn test_symex_loads() {
+    let m = parse_test_module(b"define i32 @f(i32* %p) {
+entry:
+  %v = load i32* %p, align 4
+  %w = load i32* %p, align 4
+  %c = icmp eq i32 %v, %w
+  br i1 %c, label %same, label %different
+
+same:
+  ret i32 0
+
+different:
+  ret i32 1
+}
+");
+    let mut exec = SymbolicExecutor::new(&m).unwrap();
+    assert_eq!(exec.explore("f").unwrap().len(),1);
+    exec.symbolic_load("f","w");
+    let paths = exec.explore("f").unwrap();
+    assert_eq!(paths.len(),2);
+    assert!(paths[1].smtlib().contains("(declare-fun f.w!1 () (_ BitVec 32))"));
+}
+
+#[test]
+fn test_symex_minisat() {
+    let m = minisat();
+    let mut exec = SymbolicExecutor::new(&m).unwrap();
+    exec.set_max_paths(4);
+    exec.set_max_steps(20000);
+    let paths = exec.explore("main").unwrap();
+    // The usage message is printed unless argc is 2
+    let argc = Term::var("arg.argc",Sort::BitVec(32));
+    assert_eq!(paths[0].end,PathEnd::Exited(Term::bv_i64(32,1)));
+    assert_eq!(paths[0].constraints,vec![!Term::eq(argc,Term::bv_i64(32,2))]);
+}
@@ -52,15 +52,26 @@ fn test_function_definition() {
                          cconv: CallingConv::C,
                          return_type: Some((ParAttrs::new(),Type::Int(32))),
                          arguments: args,
+                         argument_attrs: vec![ParAttrs::new(),ParAttrs::new()],
                          var_args: false,
                          attribute_groups: Vec::new(),
                          body: Some(vec![BasicBlock { name: "entry".to_string(),
                                                       instrs: vec![Instruction { content: InstructionC::Term(Terminator::Ret(Some(Typed { tp: Type::Int(32),
                                                                                                                                           val: Value::Constant(Constant::Int(BigInt::from(0))) }))),
-                                                                                 metadata: HashMap::new() }] }])
+                                                                                 metadata: OrderedMap::new() }] }])
     };
     assert_eq!(function_definition(b"define i32 @main(i32 %argc, i8** %argv) {\nentry:\n  ret i32 0\n}"),
                IResult::Done(&b""[..],("main",fun)));
+    match function_definition(b"declare void @f(i8* noalias nocapture %p, i32 zeroext, i8*)") {
+        IResult::Done(_,(_,f)) => {
+            assert_eq!(f.arguments.len(),3);
+            assert_eq!(f.arguments[0].0,Some("p".to_string()));
+            assert!(f.argument_attrs[0].noalias && f.argument_attrs[0].nocapture);
+            assert!(f.argument_attrs[1].zeroext);
+            assert_eq!(f.argument_attrs[2],ParAttrs::new());
+        },
+        _ => panic!("parse failure")
+    }
 }
 
 #[test]
@@ -97,6 +108,14 @@ fn test_global_def() {
         alignment: None };
     assert_eq!(global_variable(txt1),
                IResult::Done(&b"\n"[..],glob1));
+    match global_variable(b"linkonce_odr constant [2 x i32] [i32 1, i32 -2], align 4\n") {
+        IResult::Done(_,g) => {
+            assert_eq!(g.linkage,Some(Linkage::LinkOnceODR));
+            assert_eq!(g.initialization,Some(Constant::Array(vec![Constant::Int(BigInt::from(1)),
+                                                                  Constant::Int(BigInt::from(-2))])));
+        },
+        _ => panic!("parse failure")
+    }
 }
 
 /*#[test]
@@ -124,6 +143,10 @@ fn test_value() {
                         Value::Metadata(Metadata::Ref(431)));
     assert_eq!(typed_value(txt3,&NO_ARGS),
                IResult::Done(&b""[..],v3));
+    let v4 = Typed::new(Type::Int(32),
+                        Value::Constant(Constant::Undef));
+    assert_eq!(typed_value(b"i32 undef",&NO_ARGS),
+               IResult::Done(&b""[..],v4));
 }
 
 #[test]
@@ -141,7 +164,7 @@ fn test_instruction() {
     let c = InstructionC::Call(None,CallingConv::C,None,
                                Value::Constant(Constant::Global("llvm.dbg.value".to_string())),
                                vec![a1,a2,a3,a4],Vec::new());
-    let mut dbg = HashMap::new();
+    let mut dbg = OrderedMap::new();
     dbg.insert("dbg".to_string(),433);
     let instr = Instruction { content: c,
                               metadata: dbg };
@@ -155,7 +178,7 @@ fn test_instruction() {
                                 Type::Int(32),
                                 Value::Argument(0),
                                 Value::Constant(Constant::Int(BigInt::from(2))));
-    let mut dbg2 = HashMap::new();
+    let mut dbg2 = OrderedMap::new();
     dbg2.insert("dbg".to_string(),440);
     let instr2 = Instruction { content: c2,
                                metadata: dbg2 };
@@ -167,7 +190,7 @@ fn test_instruction() {
                                                 "if.then21".to_string(),
                                                 "if.end30".to_string()));
     let instr3 = Instruction { content: c3,
-                               metadata: HashMap::new() };
+                               metadata: OrderedMap::new() };
     assert_eq!(instruction(txt3,&NO_ARGS),
                IResult::Done(&b""[..],instr3));
 
@@ -185,8 +208,103 @@ fn test_instruction() {
                                                   Value::Constant(ptr)),
                                        Some(4));
         Instruction { content: cont,
-                      metadata: HashMap::new() }
+                      metadata: OrderedMap::new() }
     };
     assert_eq!(instruction(txt4,&NO_ARGS),
                IResult::Done(&b""[..],instr4));
 }
+
+#[test]
+fn test_module_lazy() {
+    let src = include_bytes!("minisat.ll");
+    let eager = parse_test_module(src);
+    let mut lazy = module_lazy(src).unwrap();
+    assert_eq!(lazy.globals,eager.globals);
+    assert!(lazy.functions.values().all(|f| f.body.is_none()));
+    assert_eq!(lazy.lazy_bodies.len(),eager.functions.values().filter(|f| f.is_defined()).count());
+    for (name,f) in eager.functions.iter() {
+        assert_eq!(lazy.is_defined(name),f.is_defined());
+        assert_eq!(Function { body: None, ..f.clone() },lazy.functions[name]);
+    }
+    let range = lazy.lazy_bodies["main"].range();
+    assert!(src[range.start..].starts_with(b"{") && src[..range.end].ends_with(b"}"));
+    assert_eq!(lazy.function_body("main"),Ok(eager.functions["main"].body.as_ref().map(|b| &b[..])));
+    assert!(!lazy.lazy_bodies.contains_key("main"));
+    assert_eq!(lazy.function_body("no_such_function"),Ok(None));
+    assert_eq!(lazy.materialize(),Ok(()));
+    assert_eq!(lazy,eager);
+}
+
+#[test]
+fn test_module_lazy_duplicates() {
+    let src = b"define i32 @f() {
+entry:
+  ret i32 0
+}
+
+declare i32 @f()
+
+define i32 @f() {
+entry:
+  ret i32 1
+}
+";
+    let eager = parse_test_module(src);
+    let mut lazy = module_lazy(src).unwrap();
+    assert_eq!(lazy.materialize(),Ok(()));
+    assert_eq!(lazy,eager);
+    // The first definition is kept, only the dropped definition is
+    // recorded.
+    let ret = |f: &Function| match f.body.as_ref().unwrap()[0].instrs[0].content {
+        InstructionC::Term(Terminator::Ret(Some(ref v))) => v.val.clone(),
+        ref i => panic!("unexpected instruction {:?}",i)
+    };
+    assert_eq!(ret(&eager.functions["f"]),Value::Constant(Constant::Int(BigInt::from(0))));
+    assert_eq!(eager.duplicate_functions.len(),1);
+    assert_eq!(ret(&eager.duplicate_functions[0]),Value::Constant(Constant::Int(BigInt::from(1))));
+}
+
+#[test]
+fn test_module_lazy_malformed() {
+    let src = b"define i32 @f() {
+entry:
+  ret i32 0
+}
+
+define i32 @g() {
+entry:
+  %x = frobnicate i32 0
+  ret i32 %x
+}
+";
+    let mut lazy = module_lazy(src).unwrap();
+    assert_eq!(lazy.function_body("g"),Err(::stream::ParseError::Syntax(6)));
+    assert!(lazy.is_defined("g"));
+    assert_eq!(lazy.materialize(),Err(::stream::ParseError::Syntax(6)));
+    assert!(lazy.functions["f"].body.is_some());
+}
+
+#[test]
+fn test_module_lazy_errors() {
+    // The body of a dropped definition is parsed right away.
+    let src = b"define i32 @f() {
+entry:
+  ret i32 0
+}
+
+define i32 @f() {
+entry:
+  %x = frobnicate i32 0
+  ret i32 %x
+}
+";
+    assert_eq!(module_lazy(src),Err(::stream::ParseError::Syntax(6)));
+    let src = b"define i32 @f() {
+entry:
+  ret i32 0
+}
+
+@g = frobnicate i32 0
+";
+    assert_eq!(module_lazy(src),Err(::stream::ParseError::Syntax(6)));
+}
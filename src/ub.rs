@@ -0,0 +1,446 @@
+//! Detection of undefined behaviour during interpretation.
+//!
+//! An `Interpreter` created with `Interpreter::checked` validates every
+//! memory access, including those of host functions like `memset`,
+//! against the allocation the pointer was derived from,
+//! rejects reads of uninitialised memory and checks the arithmetic and
+//! `getelementptr` instructions for poison-producing inputs. The first
+//! violation aborts execution with an `ExecError::UndefinedBehaviour`
+//! describing the offending instruction.
+use num_bigint::{BigInt,Sign};
+use num_traits::Zero;
+use std::fmt;
+use super::{Module,Instruction,InstructionC,Metadata,MetadataField,BinOp};
+use interp::{Memory,Pointer,Val,AllocKind};
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum UbKind {
+    /// A null pointer was dereferenced.
+    NullDereference,
+    /// A pointer that belongs to no allocation was dereferenced.
+    InvalidPointer { addr: u64 },
+    /// A pointer to a freed allocation (or a returned stack frame) was
+    /// dereferenced.
+    UseAfterFree { addr: u64, alloc: AllocKind },
+    /// An access of `size` bytes at `offset` leaves the allocation the
+    /// pointer was derived from.
+    OutOfBounds { alloc: AllocKind, offset: i64, size: u64, alloc_size: u64 },
+    UninitializedRead { addr: u64, size: u64 },
+    /// An arithmetic instruction with a `nuw` (`signed==false`) or `nsw`
+    /// flag overflowed.
+    Overflow { op: BinOp, signed: bool },
+    ShiftOutOfRange { amount: BigInt, width: u64 },
+    DivisionByZero,
+    /// Division of the smallest signed value by -1.
+    DivisionOverflow,
+    /// An `sdiv exact` had a remainder.
+    InexactDivision,
+    /// An `inbounds` GEP computed an address outside of its base
+    /// allocation.
+    GepOutOfBounds { offset: i64, alloc_size: u64 },
+    Unreachable
+}
+
+fn describe(alloc: &AllocKind) -> String {
+    match *alloc {
+        AllocKind::Global(ref n) => format!("global @{}",n),
+        AllocKind::Function(ref n) => format!("function @{}",n),
+        AllocKind::Stack => "stack allocation".to_string(),
+        AllocKind::Heap => "heap allocation".to_string(),
+        AllocKind::Stream(s) => format!("{:?} stream",s)
+    }
+}
+
+impl fmt::Display for UbKind {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UbKind::NullDereference => write!(f,"null pointer dereference"),
+            UbKind::InvalidPointer { addr } => write!(f,"dereference of invalid pointer {:#x}",addr),
+            UbKind::UseAfterFree { addr, ref alloc } =>
+                write!(f,"access to {:#x} in freed {}",addr,describe(alloc)),
+            UbKind::OutOfBounds { ref alloc, offset, size, alloc_size } =>
+                write!(f,"out-of-bounds access of {} bytes at offset {} of {} of size {}",
+                       size,offset,describe(alloc),alloc_size),
+            UbKind::UninitializedRead { addr, size } =>
+                write!(f,"read of {} uninitialised bytes at {:#x}",size,addr),
+            UbKind::Overflow { ref op, signed } =>
+                write!(f,"{} overflow in {:?}",if signed { "signed" } else { "unsigned" },op),
+            UbKind::ShiftOutOfRange { ref amount, width } =>
+                write!(f,"shift by {} of a {}-bit value",amount,width),
+            UbKind::DivisionByZero => write!(f,"division by zero"),
+            UbKind::DivisionOverflow => write!(f,"signed division overflow"),
+            UbKind::InexactDivision => write!(f,"exact division with remainder"),
+            UbKind::GepOutOfBounds { offset, alloc_size } =>
+                write!(f,"inbounds getelementptr to offset {} of an allocation of size {}",offset,alloc_size),
+            UbKind::Unreachable => write!(f,"unreachable executed")
+        }
+    }
+}
+
+/// A source location taken from the `!dbg` attachment of an
+/// instruction.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct DebugLocation {
+    pub line: u64,
+    pub column: u64,
+    pub file: Option<String>
+}
+
+impl fmt::Display for DebugLocation {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match self.file {
+            Some(ref file) => write!(f,"{}:{}:{}",file,self.line,self.column),
+            None => write!(f,"line {}, column {}",self.line,self.column)
+        }
+    }
+}
+
+/// The source location of an instruction.
+pub fn debug_location(m: &Module,instr: &Instruction) -> Option<DebugLocation> {
+    let id = instr.metadata.get("dbg")?;
+    match *m.md.get(id)? {
+        Metadata::Location(line,column,ref scope) => Some(DebugLocation { line,
+                                                                          column,
+                                                                          file: scope_file(m,scope) }),
+        _ => None
+    }
+}
+
+/// The file name of a scope, which refers to its file descriptor as
+/// its second element, or as its `file` field if it is a specialized
+/// node.
+fn scope_file(m: &Module,scope: &Metadata) -> Option<String> {
+    let resolve = |md: &Metadata| match *md {
+        Metadata::Ref(id) => m.md.get(&id),
+        _ => None
+    };
+    let field = |fields: &[(String,MetadataField)],name: &str| match fields.iter().find(|f| f.0==name)?.1 {
+        MetadataField::Metadata(ref md) => Some(md.clone()),
+        _ => None
+    };
+    let file = match *resolve(scope)? {
+        Metadata::Struct(ref els) => resolve(els.get(1)?)?,
+        Metadata::Node(_,_,ref fields) => resolve(&field(fields,"file")?)?,
+        _ => return None
+    };
+    let name = match *file {
+        Metadata::Struct(ref els) => els.first()?.clone(),
+        Metadata::Node(_,_,ref fields) => field(fields,"filename")?,
+        _ => return None
+    };
+    match name {
+        Metadata::Bytes(ref name) => Some(String::from_utf8_lossy(name).into_owned()),
+        _ => None
+    }
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct UbReport {
+    pub kind: UbKind,
+    pub function: String,
+    pub block: String,
+    /// The index of the offending instruction in its block.
+    pub index: usize,
+    pub instruction: InstructionC,
+    pub location: Option<DebugLocation>
+}
+
+impl fmt::Display for UbReport {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref loc) = self.location {
+            write!(f,"{}: ",loc)?;
+        }
+        write!(f,"{} in @{}, block %{}, instruction {}",self.kind,self.function,self.block,self.index)
+    }
+}
+
+/// Check a memory access of `size` bytes against the allocation the
+/// pointer was derived from. Pointers without one (e.g. created by
+/// `inttoptr`) may access whatever allocation they point into.
+pub fn access_violation(mem: &Memory,ptr: &Pointer,size: u64) -> Option<UbKind> {
+    if ptr.is_null() {
+        return Some(UbKind::NullDereference)
+    }
+    let id = match ptr.alloc.or_else(|| mem.allocation_at(ptr.addr)) {
+        Some(id) => id,
+        None => return Some(UbKind::InvalidPointer { addr: ptr.addr })
+    };
+    let alloc = mem.allocation(id);
+    if !alloc.live {
+        return Some(UbKind::UseAfterFree { addr: ptr.addr, alloc: alloc.kind.clone() })
+    }
+    if !alloc.contains(ptr.addr,size) {
+        return Some(UbKind::OutOfBounds { alloc: alloc.kind.clone(),
+                                          offset: ptr.addr.wrapping_sub(alloc.base) as i64,
+                                          size,
+                                          alloc_size: alloc.size })
+    }
+    None
+}
+
+/// Check that a load only reads initialised memory. The access has to
+/// be valid.
+pub fn uninitialized_read(mem: &Memory,ptr: &Pointer,size: u64) -> Option<UbKind> {
+    match mem.is_initialized(ptr,size) {
+        Ok(false) => Some(UbKind::UninitializedRead { addr: ptr.addr, size }),
+        _ => None
+    }
+}
+
+/// Check that the result of an `inbounds` GEP stays inside (or one past
+/// the end of) the allocation of its base.
+pub fn gep_violation(mem: &Memory,base: &Pointer,res: &Pointer) -> Option<UbKind> {
+    let offset = res.addr.wrapping_sub(base.addr) as i64;
+    let id = match base.alloc.or_else(|| mem.allocation_at(base.addr)) {
+        Some(id) => id,
+        None => return if offset==0 {
+            None
+        } else {
+            Some(UbKind::GepOutOfBounds { offset, alloc_size: 0 })
+        }
+    };
+    let alloc = mem.allocation(id);
+    if alloc.contains(base.addr,0) && alloc.contains(res.addr,0) {
+        None
+    } else {
+        Some(UbKind::GepOutOfBounds { offset: res.addr.wrapping_sub(alloc.base) as i64,
+                                      alloc_size: alloc.size })
+    }
+}
+
+/// Check a binary operation for overflow with `nuw`/`nsw` flags,
+/// oversized shifts and invalid divisions.
+pub fn arithmetic_violation(op: &BinOp,l: &Val,r: &Val) -> Option<UbKind> {
+    let (w,ul,ur) = match (l,r) {
+        (Val::Int(w,l),Val::Int(_,r)) => (*w,l,r),
+        _ => return None
+    };
+    let (sl,sr) = (l.as_signed().unwrap(),r.as_signed().unwrap());
+    let umax = BigInt::from(1) << (w as usize);
+    let smin = -(BigInt::from(1) << (w as usize-1));
+    let smax = (BigInt::from(1) << (w as usize-1)) - BigInt::from(1);
+    let overflow = |nuw: bool,nsw: bool,ures: BigInt,sres: BigInt| {
+        if nuw && (ures.sign()==Sign::Minus || ures >= umax) {
+            Some(UbKind::Overflow { op: op.clone(), signed: false })
+        } else if nsw && (sres < smin || sres > smax) {
+            Some(UbKind::Overflow { op: op.clone(), signed: true })
+        } else {
+            None
+        }
+    };
+    match *op {
+        BinOp::Add(nuw,nsw) => overflow(nuw,nsw,ul+ur,&sl+&sr),
+        BinOp::Sub(nuw,nsw) => overflow(nuw,nsw,ul-ur,&sl-&sr),
+        BinOp::Mul(nuw,nsw) => overflow(nuw,nsw,ul*ur,&sl*&sr),
+        BinOp::Shl | BinOp::LShr | BinOp::AShr => if *ur >= BigInt::from(w) {
+            Some(UbKind::ShiftOutOfRange { amount: ur.clone(), width: w })
+        } else {
+            None
+        },
+        BinOp::SDiv(exact) => if ur.is_zero() {
+            Some(UbKind::DivisionByZero)
+        } else if sl==smin && sr==BigInt::from(-1) {
+            Some(UbKind::DivisionOverflow)
+        } else if exact && !(&sl % &sr).is_zero() {
+            Some(UbKind::InexactDivision)
+        } else {
+            None
+        },
+        BinOp::And | BinOp::Or | BinOp::XOr => None
+    }
+}
+
+#[cfg(test)]
+fn check(m: &Module,fun: &str,args: Vec<Val>) -> Result<Option<Val>,Box<UbReport>> {
+    let mut interp = ::interp::Interpreter::checked(m).unwrap();
+    match interp.run_function(fun,args) {
+        Ok(::interp::Outcome::Returned(v)) => Ok(v),
+        Err(::interp::ExecError::UndefinedBehaviour(r)) => Err(r),
+        res => panic!("unexpected result {:?}",res)
+    }
+}
+
+#[test]
+fn test_ub_memory() {
+    let m = ::parse_test_module(b"@g = global [2 x i8] c\"ab\", align 1
+
+define i32 @oob(i64 %i) {
+entry:
+  %p = alloca [4 x i32], align 4
+  %q = getelementptr [4 x i32]* %p, i64 0, i64 %i
+  store i32 1, i32* %q, align 4
+  %v = load i32* %q, align 4
+  ret i32 %v
+}
+
+define i8 @global(i64 %i) {
+entry:
+  %q = getelementptr [2 x i8]* @g, i64 0, i64 %i
+  %v = load i8* %q, align 1
+  ret i8 %v
+}
+
+define i32 @uninit(i1 %c) {
+entry:
+  %p = alloca i32, align 4
+  br i1 %c, label %init, label %read
+init:
+  store i32 5, i32* %p, align 4
+  br label %read
+read:
+  %v = load i32* %p, align 4, !dbg !0
+  ret i32 %v
+}
+
+define i32* @escape() {
+entry:
+  %p = alloca i32, align 4
+  store i32 0, i32* %p, align 4
+  ret i32* %p
+}
+
+define i32 @dangling() {
+entry:
+  %p = call i32* @escape()
+  %v = load i32* %p, align 4
+  ret i32 %v
+}
+
+define i32 @gep(i64 %i) {
+entry:
+  %p = alloca [4 x i32], align 4
+  %q = getelementptr inbounds [4 x i32]* %p, i64 0, i64 %i
+  ret i32 0
+}
+
+!0 = !MDLocation(line: 12, column: 7, scope: !1)
+!1 = !{!\"0x2e\\00uninit\", !2}
+!2 = !{!\"test.c\", !\"/tmp\"}
+");
+    let i64v = |v| vec![Val::from_i64(64,v)];
+    assert_eq!(check(&m,"oob",i64v(3)),Ok(Some(Val::from_i64(32,1))));
+    let r = check(&m,"oob",i64v(4)).unwrap_err();
+    assert_eq!(r.kind,UbKind::OutOfBounds { alloc: AllocKind::Stack, offset: 16, size: 4, alloc_size: 16 });
+    assert_eq!((&r.function[..],&r.block[..],r.index),("oob","entry",2));
+    assert_eq!(check(&m,"global",i64v(-1)).unwrap_err().kind,
+               UbKind::OutOfBounds { alloc: AllocKind::Global("g".to_string()), offset: -1, size: 1, alloc_size: 2 });
+    assert_eq!(check(&m,"global",i64v(1)),Ok(Some(Val::from_i64(8,98))));
+
+    assert_eq!(check(&m,"uninit",vec![Val::bool(true)]),Ok(Some(Val::from_i64(32,5))));
+    let r = check(&m,"uninit",vec![Val::bool(false)]).unwrap_err();
+    match r.kind {
+        UbKind::UninitializedRead { size: 4, .. } => {},
+        ref k => panic!("unexpected {:?}",k)
+    }
+    assert_eq!(r.location,Some(DebugLocation { line: 12, column: 7, file: Some("test.c".to_string()) }));
+    let msg = format!("{}",r);
+    assert!(msg.starts_with("test.c:12:7: read of 4 uninitialised bytes"),"{}",msg);
+    assert!(msg.ends_with("in @uninit, block %read, instruction 0"),"{}",msg);
+
+    match check(&m,"dangling",vec![]).unwrap_err().kind {
+        UbKind::UseAfterFree { alloc: AllocKind::Stack, .. } => {},
+        k => panic!("unexpected {:?}",k)
+    }
+    assert_eq!(check(&m,"gep",i64v(4)),Ok(Some(Val::from_i64(32,0))));
+    assert_eq!(check(&m,"gep",i64v(5)).unwrap_err().kind,
+               UbKind::GepOutOfBounds { offset: 20, alloc_size: 16 });
+}
+
+#[test]
+fn test_ub_host_functions() {
+    let m = ::parse_test_module(b"declare void @llvm.memset.p0i8.i64(i8*, i8, i64, i32, i1)
+
+declare i8* @memcpy(i8*, i8*, i64)
+
+define void @set(i64 %n) {
+entry:
+  %p = alloca [4 x i8], align 1
+  %q = bitcast [4 x i8]* %p to i8*
+  call void @llvm.memset.p0i8.i64(i8* %q, i8 0, i64 %n, i32 1, i1 false), !dbg !0
+  ret void
+}
+
+define void @copy(i64 %n) {
+entry:
+  %p = alloca [4 x i8], align 1
+  %q = alloca [8 x i8], align 1
+  %p8 = bitcast [4 x i8]* %p to i8*
+  %q8 = bitcast [8 x i8]* %q to i8*
+  %r = call i8* @memcpy(i8* %q8, i8* %p8, i64 %n)
+  ret void
+}
+
+!0 = !MDLocation(line: 3, column: 5, scope: !1)
+!1 = !{!\"0x2e\\00set\", !2}
+!2 = !{!\"test.c\", !\"/tmp\"}
+");
+    let i64v = |v| vec![Val::from_i64(64,v)];
+    assert_eq!(check(&m,"set",i64v(4)),Ok(None));
+    let r = check(&m,"set",i64v(16)).unwrap_err();
+    assert_eq!(r.kind,UbKind::OutOfBounds { alloc: AllocKind::Stack, offset: 0, size: 16, alloc_size: 4 });
+    assert_eq!((&r.function[..],&r.block[..],r.index),("set","entry",2));
+    assert_eq!(r.location,Some(DebugLocation { line: 3, column: 5, file: Some("test.c".to_string()) }));
+    assert_eq!(check(&m,"copy",i64v(4)),Ok(None));
+    let r = check(&m,"copy",i64v(8)).unwrap_err();
+    assert_eq!(r.kind,UbKind::OutOfBounds { alloc: AllocKind::Stack, offset: 0, size: 8, alloc_size: 4 });
+    assert_eq!(r.index,4);
+}
+
+#[test]
+fn test_ub_arithmetic() {
+    let m = ::parse_test_module(b"define i8 @add(i8 %a, i8 %b) {
+entry:
+  %r = add nsw i8 %a, %b
+  ret i8 %r
+}
+
+define i8 @addu(i8 %a, i8 %b) {
+entry:
+  %r = add nuw i8 %a, %b
+  ret i8 %r
+}
+
+define i8 @shl(i8 %a, i8 %b) {
+entry:
+  %r = shl i8 %a, %b
+  ret i8 %r
+}
+
+define i8 @div(i8 %a, i8 %b) {
+entry:
+  %r = sdiv i8 %a, %b
+  ret i8 %r
+}
+
+define void @unreach() {
+entry:
+  unreachable
+}
+");
+    let args = |a,b| vec![Val::from_i64(8,a),Val::from_i64(8,b)];
+    assert_eq!(check(&m,"add",args(100,27)),Ok(Some(Val::from_i64(8,127))));
+    assert_eq!(check(&m,"add",args(100,28)).unwrap_err().kind,
+               UbKind::Overflow { op: BinOp::Add(false,true), signed: true });
+    assert_eq!(check(&m,"add",args(-100,-28)),Ok(Some(Val::from_i64(8,-128))));
+    assert_eq!(check(&m,"addu",args(-1,-1)).unwrap_err().kind,
+               UbKind::Overflow { op: BinOp::Add(true,false), signed: false });
+    assert_eq!(check(&m,"shl",args(1,7)),Ok(Some(Val::from_i64(8,-128))));
+    assert_eq!(check(&m,"shl",args(1,8)).unwrap_err().kind,
+               UbKind::ShiftOutOfRange { amount: BigInt::from(8), width: 8 });
+    assert_eq!(check(&m,"div",args(-128,2)),Ok(Some(Val::from_i64(8,-64))));
+    assert_eq!(check(&m,"div",args(5,0)).unwrap_err().kind,UbKind::DivisionByZero);
+    assert_eq!(check(&m,"div",args(-128,-1)).unwrap_err().kind,UbKind::DivisionOverflow);
+    assert_eq!(check(&m,"unreach",vec![]).unwrap_err().kind,UbKind::Unreachable);
+}
+
+#[test]
+fn test_ub_minisat() {
+    // The solver runs into its failing assertion without undefined
+    // behaviour on the way.
+    let m = ::minisat();
+    let mut interp = ::interp::Interpreter::checked(&m).unwrap();
+    match interp.run_main(&["minisat","sample.cnf"]) {
+        Err(::interp::ExecError::AssertionFailed(_)) => {},
+        res => panic!("unexpected result {:?}",res)
+    }
+}
@@ -0,0 +1,725 @@
+//! Generic traversal of the IR.
+//!
+//! `Visitor` and `VisitorMut` have one method per node type. The
+//! default implementations call the corresponding `walk_*` function,
+//! which visits all children of the node. An implementation overrides
+//! the methods for the nodes it is interested in and calls the `walk_*`
+//! function itself if it wants to descend further.
+use std::vec;
+use super::*;
+
+/// Iterator over the value operands of an instruction.
+pub type Operands<'a> = vec::IntoIter<&'a Value>;
+/// Iterator over mutable references to the value operands of an
+/// instruction.
+pub type OperandsMut<'a> = vec::IntoIter<&'a mut Value>;
+
+impl InstructionC {
+    /// The direct value operands of the instruction, in textual order.
+    /// Values wrapped in metadata are a single `Value::Metadata`
+    /// operand.
+    pub fn operands(&self) -> Operands<'_> {
+        let res: Vec<&Value> = match *self {
+            InstructionC::Alloca(_,_,ref num,_) => num.iter().map(|n| &n.val).collect(),
+            InstructionC::Call(_,_,_,ref f,ref args,_) => {
+                let mut res = vec![f];
+                res.extend(args.iter().map(|a| &a.val));
+                res
+            },
+            InstructionC::ICmp(_,_,_,ref v1,ref v2) |
+            InstructionC::Bin(_,_,_,ref v1,ref v2) => vec![v1,v2],
+            InstructionC::Unary(_,ref v,_) => vec![&v.val],
+            InstructionC::GEP(_,ref g) => {
+                let mut res = vec![&g.ptr.val];
+                res.extend(g.indices.iter().map(|(i,_)| &i.val));
+                res
+            },
+            InstructionC::Store(_,ref v,ref p,_) => vec![&v.val,&p.val],
+            InstructionC::Select(_,ref c,_,ref v1,ref v2) => vec![c,v1,v2],
+            InstructionC::Phi(_,_,ref inc) => inc.iter().map(|(v,_)| v).collect(),
+            InstructionC::Term(ref t) => match *t {
+                Terminator::BrC(ref c,_,_) => vec![c],
+                Terminator::Ret(Some(ref v)) => vec![&v.val],
+                Terminator::Switch(_,ref v,_,_) => vec![v],
+                Terminator::Br(_) | Terminator::Ret(None) | Terminator::Unreachable => Vec::new()
+            }
+        };
+        res.into_iter()
+    }
+    /// Mutable version of `operands`, with the same order.
+    pub fn operands_mut(&mut self) -> OperandsMut<'_> {
+        let res: Vec<&mut Value> = match *self {
+            InstructionC::Alloca(_,_,ref mut num,_) => num.iter_mut().map(|n| &mut n.val).collect(),
+            InstructionC::Call(_,_,_,ref mut f,ref mut args,_) => {
+                let mut res = vec![f];
+                res.extend(args.iter_mut().map(|a| &mut a.val));
+                res
+            },
+            InstructionC::ICmp(_,_,_,ref mut v1,ref mut v2) |
+            InstructionC::Bin(_,_,_,ref mut v1,ref mut v2) => vec![v1,v2],
+            InstructionC::Unary(_,ref mut v,_) => vec![&mut v.val],
+            InstructionC::GEP(_,ref mut g) => {
+                let mut res = vec![&mut g.ptr.val];
+                res.extend(g.indices.iter_mut().map(|(i,_)| &mut i.val));
+                res
+            },
+            InstructionC::Store(_,ref mut v,ref mut p,_) => vec![&mut v.val,&mut p.val],
+            InstructionC::Select(_,ref mut c,_,ref mut v1,ref mut v2) => vec![c,v1,v2],
+            InstructionC::Phi(_,_,ref mut inc) => inc.iter_mut().map(|(v,_)| v).collect(),
+            InstructionC::Term(ref mut t) => match *t {
+                Terminator::BrC(ref mut c,_,_) => vec![c],
+                Terminator::Ret(Some(ref mut v)) => vec![&mut v.val],
+                Terminator::Switch(_,ref mut v,_,_) => vec![v],
+                Terminator::Br(_) | Terminator::Ret(None) | Terminator::Unreachable => Vec::new()
+            }
+        };
+        res.into_iter()
+    }
+}
+
+pub trait Visitor {
+    fn visit_module(&mut self,m: &Module) {
+        walk_module(self,m)
+    }
+    fn visit_type_def(&mut self,_name: &str,tp: &Type) {
+        self.visit_type(tp)
+    }
+    fn visit_global(&mut self,_name: &str,g: &GlobalVariable) {
+        walk_global(self,g)
+    }
+    fn visit_function(&mut self,f: &Function) {
+        walk_function(self,f)
+    }
+    fn visit_attribute_group_def(&mut self,_id: AttributeGroup,_attrs: &[Attribute]) {}
+    fn visit_named_metadata(&mut self,_name: &str,md: &Metadata) {
+        self.visit_metadata(md)
+    }
+    fn visit_metadata_def(&mut self,_id: u64,md: &Metadata) {
+        self.visit_metadata(md)
+    }
+    fn visit_basic_block(&mut self,blk: &BasicBlock) {
+        walk_basic_block(self,blk)
+    }
+    fn visit_instruction(&mut self,instr: &Instruction) {
+        walk_instruction(self,instr)
+    }
+    fn visit_instruction_c(&mut self,instr: &InstructionC) {
+        walk_instruction_c(self,instr)
+    }
+    fn visit_terminator(&mut self,term: &Terminator) {
+        walk_terminator(self,term)
+    }
+    /// The name of a local defined by an instruction.
+    fn visit_def(&mut self,_name: &str) {}
+    /// A reference to a basic block in a terminator or phi node.
+    fn visit_label(&mut self,_label: &str) {}
+    /// A metadata attachment `!kind !id` of an instruction.
+    fn visit_metadata_attachment(&mut self,_kind: &str,_id: u64) {}
+    /// A reference to an attribute group `#id`.
+    fn visit_attribute_group(&mut self,_id: AttributeGroup) {}
+    fn visit_typed_value(&mut self,v: &Typed<Value>) {
+        self.visit_type(&v.tp);
+        self.visit_value(&v.val)
+    }
+    fn visit_typed_constant(&mut self,c: &Typed<Constant>) {
+        self.visit_type(&c.tp);
+        self.visit_constant(&c.val)
+    }
+    fn visit_gep_value(&mut self,g: &GEP<Value>) {
+        self.visit_typed_value(&g.ptr);
+        for (idx,_) in g.indices.iter() {
+            self.visit_typed_value(idx)
+        }
+    }
+    fn visit_gep_constant(&mut self,g: &GEP<Constant>) {
+        self.visit_typed_constant(&g.ptr);
+        for (idx,_) in g.indices.iter() {
+            self.visit_typed_constant(idx)
+        }
+    }
+    fn visit_value(&mut self,v: &Value) {
+        walk_value(self,v)
+    }
+    fn visit_constant(&mut self,c: &Constant) {
+        walk_constant(self,c)
+    }
+    fn visit_metadata(&mut self,md: &Metadata) {
+        walk_metadata(self,md)
+    }
+    fn visit_type(&mut self,tp: &Type) {
+        walk_type(self,tp)
+    }
+}
+
+pub fn walk_module<V: Visitor + ?Sized>(v: &mut V,m: &Module) {
+    for (name,tp) in m.types.iter() {
+        v.visit_type_def(name,tp)
+    }
+    for (name,g) in m.globals.iter() {
+        v.visit_global(name,g)
+    }
+    for f in m.functions.values() {
+        v.visit_function(f)
+    }
+    for (id,attrs) in m.attr_groups.iter() {
+        v.visit_attribute_group_def(*id,attrs)
+    }
+    for (name,md) in m.named_md.iter() {
+        v.visit_named_metadata(name,md)
+    }
+    for (id,md) in m.md.iter() {
+        v.visit_metadata_def(*id,md)
+    }
+}
+
+pub fn walk_global<V: Visitor + ?Sized>(v: &mut V,g: &GlobalVariable) {
+    v.visit_type(&g.types);
+    if let Some(ref init) = g.initialization {
+        v.visit_constant(init)
+    }
+}
+
+pub fn walk_function<V: Visitor + ?Sized>(v: &mut V,f: &Function) {
+    if let Some((_,ref tp)) = f.return_type {
+        v.visit_type(tp)
+    }
+    for (_,tp) in f.arguments.iter() {
+        v.visit_type(tp)
+    }
+    for attr in f.attribute_groups.iter() {
+        v.visit_attribute_group(*attr)
+    }
+    if let Some(ref blks) = f.body {
+        for blk in blks.iter() {
+            v.visit_basic_block(blk)
+        }
+    }
+}
+
+pub fn walk_basic_block<V: Visitor + ?Sized>(v: &mut V,blk: &BasicBlock) {
+    for instr in blk.instrs.iter() {
+        v.visit_instruction(instr)
+    }
+}
+
+pub fn walk_instruction<V: Visitor + ?Sized>(v: &mut V,instr: &Instruction) {
+    v.visit_instruction_c(&instr.content);
+    for (kind,id) in instr.metadata.iter() {
+        v.visit_metadata_attachment(kind,*id)
+    }
+}
+
+pub fn walk_instruction_c<V: Visitor + ?Sized>(v: &mut V,instr: &InstructionC) {
+    if let Some(name) = instr.name() {
+        v.visit_def(name)
+    }
+    match *instr {
+        InstructionC::Alloca(_,ref tp,ref num,_) => {
+            v.visit_type(tp);
+            if let Some(ref n) = *num {
+                v.visit_typed_value(n)
+            }
+        },
+        InstructionC::Call(_,_,ref ret,ref f,ref args,ref attrs) => {
+            if let Some((ref tp,_)) = *ret {
+                v.visit_type(tp)
+            }
+            v.visit_value(f);
+            for arg in args.iter() {
+                v.visit_typed_value(arg)
+            }
+            for attr in attrs.iter() {
+                v.visit_attribute_group(*attr)
+            }
+        },
+        InstructionC::ICmp(_,_,ref tp,ref v1,ref v2) |
+        InstructionC::Bin(_,_,ref tp,ref v1,ref v2) => {
+            v.visit_type(tp);
+            v.visit_value(v1);
+            v.visit_value(v2)
+        },
+        InstructionC::Unary(_,ref val,ref op) => {
+            v.visit_typed_value(val);
+            if let UnaryInst::Cast(ref tp,_) = *op {
+                v.visit_type(tp)
+            }
+        },
+        InstructionC::GEP(_,ref g) => v.visit_gep_value(g),
+        InstructionC::Store(_,ref val,ref ptr,_) => {
+            v.visit_typed_value(val);
+            v.visit_typed_value(ptr)
+        },
+        InstructionC::Select(_,ref c,ref tp,ref v1,ref v2) => {
+            v.visit_value(c);
+            v.visit_type(tp);
+            v.visit_value(v1);
+            v.visit_value(v2)
+        },
+        InstructionC::Phi(_,ref tp,ref inc) => {
+            v.visit_type(tp);
+            for (val,lbl) in inc.iter() {
+                v.visit_value(val);
+                v.visit_label(lbl)
+            }
+        },
+        InstructionC::Term(ref t) => v.visit_terminator(t)
+    }
+}
+
+pub fn walk_terminator<V: Visitor + ?Sized>(v: &mut V,term: &Terminator) {
+    match *term {
+        Terminator::Br(ref l) => v.visit_label(l),
+        Terminator::BrC(ref c,ref l1,ref l2) => {
+            v.visit_value(c);
+            v.visit_label(l1);
+            v.visit_label(l2)
+        },
+        Terminator::Ret(ref r) => if let Some(ref r) = *r {
+            v.visit_typed_value(r)
+        },
+        Terminator::Switch(ref tp,ref val,ref def,ref cases) => {
+            v.visit_type(tp);
+            v.visit_value(val);
+            v.visit_label(def);
+            for (c,l) in cases.iter() {
+                v.visit_constant(c);
+                v.visit_label(l)
+            }
+        },
+        Terminator::Unreachable => {}
+    }
+}
+
+pub fn walk_value<V: Visitor + ?Sized>(v: &mut V,val: &Value) {
+    match *val {
+        Value::Constant(ref c) => v.visit_constant(c),
+        Value::Metadata(ref md) => v.visit_metadata(md),
+        Value::Local(_) | Value::Argument(_) => {}
+    }
+}
+
+pub fn walk_constant<V: Visitor + ?Sized>(v: &mut V,c: &Constant) {
+    match *c {
+        Constant::Array(ref els) => for el in els.iter() {
+            v.visit_constant(el)
+        },
+        Constant::GEP(ref g) => v.visit_gep_constant(g),
+        Constant::Cast(_,ref c,ref tp) => {
+            v.visit_typed_constant(c);
+            v.visit_type(tp)
+        },
+        Constant::Global(_) | Constant::Int(_) | Constant::NullPtr | Constant::Undef => {}
+    }
+}
+
+pub fn walk_metadata<V: Visitor + ?Sized>(v: &mut V,md: &Metadata) {
+    match *md {
+        Metadata::Value(ref val) => v.visit_typed_value(val),
+        Metadata::Struct(ref els) => for el in els.iter() {
+            v.visit_metadata(el)
+        },
+        Metadata::Location(_,_,ref scope) => v.visit_metadata(scope),
+        Metadata::Node(_,_,ref fields) => for field in fields.iter() {
+            if let MetadataField::Metadata(ref el) = field.1 {
+                v.visit_metadata(el)
+            }
+        },
+        Metadata::Null | Metadata::Ref(_) | Metadata::Bytes(_) => {}
+    }
+}
+
+pub fn walk_type<V: Visitor + ?Sized>(v: &mut V,tp: &Type) {
+    match *tp {
+        Type::Pointer(ref el,_) | Type::Array(_,ref el) => v.visit_type(el),
+        Type::Struct(ref els) => for el in els.iter() {
+            v.visit_type(el)
+        },
+        Type::Function(ref ret,ref args,_) => {
+            if let Some(ref ret) = *ret {
+                v.visit_type(ret)
+            }
+            for arg in args.iter() {
+                v.visit_type(arg)
+            }
+        },
+        _ => {}
+    }
+}
+
+pub trait VisitorMut {
+    fn visit_module_mut(&mut self,m: &mut Module) {
+        walk_module_mut(self,m)
+    }
+    fn visit_type_def_mut(&mut self,_name: &str,tp: &mut Type) {
+        self.visit_type_mut(tp)
+    }
+    fn visit_global_mut(&mut self,_name: &str,g: &mut GlobalVariable) {
+        walk_global_mut(self,g)
+    }
+    fn visit_function_mut(&mut self,f: &mut Function) {
+        walk_function_mut(self,f)
+    }
+    fn visit_attribute_group_def_mut(&mut self,_id: AttributeGroup,_attrs: &mut Vec<Attribute>) {}
+    fn visit_named_metadata_mut(&mut self,_name: &str,md: &mut Metadata) {
+        self.visit_metadata_mut(md)
+    }
+    fn visit_metadata_def_mut(&mut self,_id: u64,md: &mut Metadata) {
+        self.visit_metadata_mut(md)
+    }
+    fn visit_basic_block_mut(&mut self,blk: &mut BasicBlock) {
+        walk_basic_block_mut(self,blk)
+    }
+    fn visit_instruction_mut(&mut self,instr: &mut Instruction) {
+        walk_instruction_mut(self,instr)
+    }
+    fn visit_instruction_c_mut(&mut self,instr: &mut InstructionC) {
+        walk_instruction_c_mut(self,instr)
+    }
+    fn visit_terminator_mut(&mut self,term: &mut Terminator) {
+        walk_terminator_mut(self,term)
+    }
+    /// The name of a local defined by an instruction.
+    fn visit_def_mut(&mut self,_name: &mut String) {}
+    /// A reference to a basic block in a terminator or phi node. Block
+    /// names themselves are reached through `visit_basic_block_mut`.
+    fn visit_label_mut(&mut self,_label: &mut String) {}
+    /// A metadata attachment `!kind !id` of an instruction.
+    fn visit_metadata_attachment_mut(&mut self,_kind: &str,_id: &mut u64) {}
+    /// A reference to an attribute group `#id`.
+    fn visit_attribute_group_mut(&mut self,_id: &mut AttributeGroup) {}
+    fn visit_typed_value_mut(&mut self,v: &mut Typed<Value>) {
+        self.visit_type_mut(&mut v.tp);
+        self.visit_value_mut(&mut v.val)
+    }
+    fn visit_typed_constant_mut(&mut self,c: &mut Typed<Constant>) {
+        self.visit_type_mut(&mut c.tp);
+        self.visit_constant_mut(&mut c.val)
+    }
+    fn visit_gep_value_mut(&mut self,g: &mut GEP<Value>) {
+        self.visit_typed_value_mut(&mut g.ptr);
+        for (idx,_) in g.indices.iter_mut() {
+            self.visit_typed_value_mut(idx)
+        }
+    }
+    fn visit_gep_constant_mut(&mut self,g: &mut GEP<Constant>) {
+        self.visit_typed_constant_mut(&mut g.ptr);
+        for (idx,_) in g.indices.iter_mut() {
+            self.visit_typed_constant_mut(idx)
+        }
+    }
+    fn visit_value_mut(&mut self,v: &mut Value) {
+        walk_value_mut(self,v)
+    }
+    fn visit_constant_mut(&mut self,c: &mut Constant) {
+        walk_constant_mut(self,c)
+    }
+    fn visit_metadata_mut(&mut self,md: &mut Metadata) {
+        walk_metadata_mut(self,md)
+    }
+    fn visit_type_mut(&mut self,tp: &mut Type) {
+        walk_type_mut(self,tp)
+    }
+}
+
+pub fn walk_module_mut<V: VisitorMut + ?Sized>(v: &mut V,m: &mut Module) {
+    for (name,tp) in m.types.iter_mut() {
+        v.visit_type_def_mut(name,tp)
+    }
+    for (name,g) in m.globals.iter_mut() {
+        v.visit_global_mut(name,g)
+    }
+    for f in m.functions.values_mut() {
+        v.visit_function_mut(f)
+    }
+    for (id,attrs) in m.attr_groups.iter_mut() {
+        v.visit_attribute_group_def_mut(*id,attrs)
+    }
+    for (name,md) in m.named_md.iter_mut() {
+        v.visit_named_metadata_mut(name,md)
+    }
+    for (id,md) in m.md.iter_mut() {
+        v.visit_metadata_def_mut(*id,md)
+    }
+}
+
+pub fn walk_global_mut<V: VisitorMut + ?Sized>(v: &mut V,g: &mut GlobalVariable) {
+    v.visit_type_mut(&mut g.types);
+    if let Some(ref mut init) = g.initialization {
+        v.visit_constant_mut(init)
+    }
+}
+
+pub fn walk_function_mut<V: VisitorMut + ?Sized>(v: &mut V,f: &mut Function) {
+    if let Some((_,ref mut tp)) = f.return_type {
+        v.visit_type_mut(tp)
+    }
+    for (_,tp) in f.arguments.iter_mut() {
+        v.visit_type_mut(tp)
+    }
+    for attr in f.attribute_groups.iter_mut() {
+        v.visit_attribute_group_mut(attr)
+    }
+    if let Some(ref mut blks) = f.body {
+        for blk in blks.iter_mut() {
+            v.visit_basic_block_mut(blk)
+        }
+    }
+}
+
+pub fn walk_basic_block_mut<V: VisitorMut + ?Sized>(v: &mut V,blk: &mut BasicBlock) {
+    for instr in blk.instrs.iter_mut() {
+        v.visit_instruction_mut(instr)
+    }
+}
+
+pub fn walk_instruction_mut<V: VisitorMut + ?Sized>(v: &mut V,instr: &mut Instruction) {
+    v.visit_instruction_c_mut(&mut instr.content);
+    for (kind,id) in instr.metadata.iter_mut() {
+        v.visit_metadata_attachment_mut(kind,id)
+    }
+}
+
+pub fn walk_instruction_c_mut<V: VisitorMut + ?Sized>(v: &mut V,instr: &mut InstructionC) {
+    match *instr {
+        InstructionC::Alloca(ref mut name,ref mut tp,ref mut num,_) => {
+            v.visit_def_mut(name);
+            v.visit_type_mut(tp);
+            if let Some(ref mut n) = *num {
+                v.visit_typed_value_mut(n)
+            }
+        },
+        InstructionC::Call(ref mut name,_,ref mut ret,ref mut f,ref mut args,ref mut attrs) => {
+            if let Some(ref mut name) = *name {
+                v.visit_def_mut(name)
+            }
+            if let Some((ref mut tp,_)) = *ret {
+                v.visit_type_mut(tp)
+            }
+            v.visit_value_mut(f);
+            for arg in args.iter_mut() {
+                v.visit_typed_value_mut(arg)
+            }
+            for attr in attrs.iter_mut() {
+                v.visit_attribute_group_mut(attr)
+            }
+        },
+        InstructionC::ICmp(ref mut name,_,ref mut tp,ref mut v1,ref mut v2) |
+        InstructionC::Bin(ref mut name,_,ref mut tp,ref mut v1,ref mut v2) => {
+            v.visit_def_mut(name);
+            v.visit_type_mut(tp);
+            v.visit_value_mut(v1);
+            v.visit_value_mut(v2)
+        },
+        InstructionC::Unary(ref mut name,ref mut val,ref mut op) => {
+            v.visit_def_mut(name);
+            v.visit_typed_value_mut(val);
+            if let UnaryInst::Cast(ref mut tp,_) = *op {
+                v.visit_type_mut(tp)
+            }
+        },
+        InstructionC::GEP(ref mut name,ref mut g) => {
+            v.visit_def_mut(name);
+            v.visit_gep_value_mut(g)
+        },
+        InstructionC::Store(_,ref mut val,ref mut ptr,_) => {
+            v.visit_typed_value_mut(val);
+            v.visit_typed_value_mut(ptr)
+        },
+        InstructionC::Select(ref mut name,ref mut c,ref mut tp,ref mut v1,ref mut v2) => {
+            v.visit_def_mut(name);
+            v.visit_value_mut(c);
+            v.visit_type_mut(tp);
+            v.visit_value_mut(v1);
+            v.visit_value_mut(v2)
+        },
+        InstructionC::Phi(ref mut name,ref mut tp,ref mut inc) => {
+            v.visit_def_mut(name);
+            v.visit_type_mut(tp);
+            for (val,lbl) in inc.iter_mut() {
+                v.visit_value_mut(val);
+                v.visit_label_mut(lbl)
+            }
+        },
+        InstructionC::Term(ref mut t) => v.visit_terminator_mut(t)
+    }
+}
+
+pub fn walk_terminator_mut<V: VisitorMut + ?Sized>(v: &mut V,term: &mut Terminator) {
+    match *term {
+        Terminator::Br(ref mut l) => v.visit_label_mut(l),
+        Terminator::BrC(ref mut c,ref mut l1,ref mut l2) => {
+            v.visit_value_mut(c);
+            v.visit_label_mut(l1);
+            v.visit_label_mut(l2)
+        },
+        Terminator::Ret(ref mut r) => if let Some(ref mut r) = *r {
+            v.visit_typed_value_mut(r)
+        },
+        Terminator::Switch(ref mut tp,ref mut val,ref mut def,ref mut cases) => {
+            v.visit_type_mut(tp);
+            v.visit_value_mut(val);
+            v.visit_label_mut(def);
+            for (c,l) in cases.iter_mut() {
+                v.visit_constant_mut(c);
+                v.visit_label_mut(l)
+            }
+        },
+        Terminator::Unreachable => {}
+    }
+}
+
+pub fn walk_value_mut<V: VisitorMut + ?Sized>(v: &mut V,val: &mut Value) {
+    match *val {
+        Value::Constant(ref mut c) => v.visit_constant_mut(c),
+        Value::Metadata(ref mut md) => v.visit_metadata_mut(md),
+        Value::Local(_) | Value::Argument(_) => {}
+    }
+}
+
+pub fn walk_constant_mut<V: VisitorMut + ?Sized>(v: &mut V,c: &mut Constant) {
+    match *c {
+        Constant::Array(ref mut els) => for el in els.iter_mut() {
+            v.visit_constant_mut(el)
+        },
+        Constant::GEP(ref mut g) => v.visit_gep_constant_mut(g),
+        Constant::Cast(_,ref mut c,ref mut tp) => {
+            v.visit_typed_constant_mut(c);
+            v.visit_type_mut(tp)
+        },
+        Constant::Global(_) | Constant::Int(_) | Constant::NullPtr | Constant::Undef => {}
+    }
+}
+
+pub fn walk_metadata_mut<V: VisitorMut + ?Sized>(v: &mut V,md: &mut Metadata) {
+    match *md {
+        Metadata::Value(ref mut val) => v.visit_typed_value_mut(val),
+        Metadata::Struct(ref mut els) => for el in els.iter_mut() {
+            v.visit_metadata_mut(el)
+        },
+        Metadata::Location(_,_,ref mut scope) => v.visit_metadata_mut(scope),
+        Metadata::Node(_,_,ref mut fields) => for field in fields.iter_mut() {
+            if let MetadataField::Metadata(ref mut el) = field.1 {
+                v.visit_metadata_mut(el)
+            }
+        },
+        Metadata::Null | Metadata::Ref(_) | Metadata::Bytes(_) => {}
+    }
+}
+
+pub fn walk_type_mut<V: VisitorMut + ?Sized>(v: &mut V,tp: &mut Type) {
+    match *tp {
+        Type::Pointer(ref mut el,_) | Type::Array(_,ref mut el) => v.visit_type_mut(el),
+        Type::Struct(ref mut els) => for el in els.iter_mut() {
+            v.visit_type_mut(el)
+        },
+        Type::Function(ref mut ret,ref mut args,_) => {
+            if let Some(ref mut ret) = *ret {
+                v.visit_type_mut(ret)
+            }
+            for arg in args.iter_mut() {
+                v.visit_type_mut(arg)
+            }
+        },
+        _ => {}
+    }
+}
+
+#[test]
+fn test_visitor() {
+    // Count the references to globals and the uses of named types
+    struct Counter {
+        globals: HashMap<String,usize>,
+        named: usize,
+        labels: usize
+    }
+    impl Visitor for Counter {
+        fn visit_constant(&mut self,c: &Constant) {
+            if let Constant::Global(ref n) = *c {
+                *self.globals.entry(n.clone()).or_insert(0) += 1;
+            }
+            walk_constant(self,c)
+        }
+        fn visit_type(&mut self,tp: &Type) {
+            if let Type::Named(_) = *tp {
+                self.named += 1;
+            }
+            walk_type(self,tp)
+        }
+        fn visit_label(&mut self,_: &str) {
+            self.labels += 1;
+        }
+    }
+    let m = minisat();
+    let mut c = Counter { globals: HashMap::new(), named: 0, labels: 0 };
+    c.visit_function(&m.functions["main"]);
+    assert_eq!(c.globals["stderr"],1);
+    assert_eq!(c.globals["solver_delete"],2);
+    assert_eq!(c.globals[".str2"],2);
+    assert!(c.named > 0);
+    // 4 conditional branches, 4 unconditional, 1 phi with 2 incomings
+    assert_eq!(c.labels,4*2+4+2);
+}
+
+#[test]
+fn test_visitor_mut() {
+    // Rename all locals and labels of a function
+    struct Rename;
+    impl VisitorMut for Rename {
+        fn visit_basic_block_mut(&mut self,blk: &mut BasicBlock) {
+            blk.name.push_str(".r");
+            walk_basic_block_mut(self,blk)
+        }
+        fn visit_def_mut(&mut self,name: &mut String) {
+            name.push_str(".r")
+        }
+        fn visit_label_mut(&mut self,lbl: &mut String) {
+            lbl.push_str(".r")
+        }
+        fn visit_value_mut(&mut self,v: &mut Value) {
+            if let Value::Local(ref mut n) = *v {
+                n.push_str(".r")
+            }
+            walk_value_mut(self,v)
+        }
+    }
+    let m = minisat();
+    let mut f = m.functions["main"].clone();
+    Rename.visit_function_mut(&mut f);
+    let blks = f.body.as_ref().unwrap();
+    assert_eq!(blks[0].name,"entry.r");
+    assert_eq!(blks[0].instrs[2].content.name(),Some("call.r"));
+    // The renamed function still has a consistent control-flow graph
+    // and def-use structure.
+    ::cfg::ControlFlowGraph::from_function(&f).unwrap();
+    let idx = ::ssa::SsaIndex::new(&f);
+    assert!(idx.definition(&Value::Local("call.r".to_string())).is_some());
+    assert!(idx.uses(&Value::Local("call".to_string())).is_empty());
+    // Metadata arguments of debug intrinsics are renamed as well
+    match blks[0].instrs[3].content {
+        InstructionC::Call(_,_,_,_,ref args,_) => match args[0].val {
+            Value::Metadata(Metadata::Value(ref v)) => assert_eq!(v.val,Value::Local("call.r".to_string())),
+            ref v => panic!("unexpected argument {:?}",v)
+        },
+        ref i => panic!("unexpected instruction {:?}",i)
+    }
+}
+
+#[test]
+fn test_operands() {
+    let m = minisat();
+    for f in m.functions.values() {
+        if let Some(ref blks) = f.body {
+            for blk in blks.iter() {
+                for instr in blk.instrs.iter() {
+                    let mut c = instr.content.clone();
+                    let ops: Vec<Value> = c.operands().cloned().collect();
+                    for op in c.operands_mut() {
+                        *op = Value::Local("x".to_string());
+                    }
+                    assert!(c.operands().all(|v| *v==Value::Local("x".to_string())));
+                    assert_eq!(c.operands().count(),ops.len());
+                }
+            }
+        }
+    }
+}